@@ -0,0 +1,60 @@
+//! Benchmarks of the memory hot paths: single byte accesses through the
+//! memory map, whole frames of a program accessing memory in a loop and
+//! restoring saved states.
+//!
+//! Run with `cargo bench -p mahboi`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use mahboi::{
+    BiosKind, Emulator, HardwareModel,
+    cartridge::Cartridge,
+    gym::Gym,
+    machine::input::Keys,
+    primitives::{Byte, Word},
+};
+
+
+/// A ROM that copies 128 bytes from WRAM to `$FF80..` over and over:
+///
+/// ```text
+/// 0x0150: ld hl, $C000; ld c, $80
+/// 0x0155: ld a, [hl+]; ldh [c], a; inc c; jr nz, -5; jr -12
+/// ```
+fn rom() -> Cartridge {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    rom[0x150..0x15D].copy_from_slice(&[
+        0x21, 0x00, 0xC0, 0x0E, 0x80, 0x2A, 0xE2, 0x0C, 0x20, 0xFB, 0x18, 0xF4, 0x00,
+    ]);
+    Cartridge::from_bytes(&rom).unwrap()
+}
+
+fn byte_access(c: &mut Criterion) {
+    let mut emulator = Emulator::new(rom(), BiosKind::Minimal, HardwareModel::Dmg);
+    let machine = emulator.machine_mut();
+
+    for &(name, start) in &[("wram", 0xC000), ("hram", 0xFF80), ("vram", 0x8000)] {
+        c.bench_function(&format!("load_byte {}", name), |b| b.iter(|| {
+            (start..start + 0x7F).fold(0u8, |acc, addr| {
+                acc.wrapping_add(machine.load_byte(black_box(Word::new(addr))).get())
+            })
+        }));
+        c.bench_function(&format!("store_byte {}", name), |b| b.iter(|| {
+            for addr in start..start + 0x7F {
+                machine.store_byte(black_box(Word::new(addr)), Byte::new(addr as u8));
+            }
+        }));
+    }
+}
+
+fn frames(c: &mut Criterion) {
+    let mut gym = Gym::new(rom(), BiosKind::Minimal, vec![]);
+    c.bench_function("frame", |b| b.iter(|| gym.step(Keys::none()).ok().unwrap()));
+
+    let state = gym.save();
+    c.bench_function("restore", |b| b.iter(|| gym.restore(black_box(&state))));
+}
+
+criterion_group!(benches, byte_access, frames);
+criterion_main!(benches);
@@ -0,0 +1,18 @@
+use mahboi::{
+    SCREEN_WIDTH,
+    env::Peripherals,
+    machine::input::Keys,
+    primitives::PixelColor,
+};
+
+
+/// Peripherals that ignore all output and never press any key.
+pub struct Dummy;
+
+impl Peripherals for Dummy {
+    fn write_lcd_line(&mut self, _: u8, _: &[PixelColor; SCREEN_WIDTH]) {}
+    fn get_pressed_keys(&self) -> Keys {
+        Keys::none()
+    }
+    fn offer_sound_sample(&mut self, _: impl FnOnce(f32) -> f32) {}
+}
@@ -0,0 +1,42 @@
+//! Executes the input as instruction stream (starting at `0x0150`, where the
+//! entry point of the ROM-only cartridge jumps to) and checks that the
+//! interpreter neither panics nor violates invariants of the CPU state.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use mahboi::{BiosKind, Emulator, HardwareModel, cartridge::Cartridge};
+
+mod common;
+
+
+/// Maximum number of executed instructions per input.
+const MAX_STEPS: usize = 10_000;
+
+fuzz_target!(|data: &[u8]| {
+    if data.is_empty() {
+        return;
+    }
+
+    // Repeat the input to fill the whole ROM after the header. The zeroed
+    // header describes a ROM-only cartridge.
+    let mut rom = vec![0; 0x8000];
+    rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    for (dst, &byte) in rom[0x0150..].iter_mut().zip(data.iter().cycle()) {
+        *dst = byte;
+    }
+    let cartridge = Cartridge::from_bytes(&rom).expect("invalid cartridge");
+
+    let mut emulator = Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+    let machine = emulator.machine_mut();
+    for _ in 0..MAX_STEPS {
+        // Invalid instructions terminate the emulation.
+        if machine.execute_step(&mut common::Dummy).is_err() {
+            break;
+        }
+
+        // The lower four bits of F don't exist.
+        assert_eq!(machine.cpu.f.get() & 0x0F, 0, "low nibble of F is not zero");
+    }
+});
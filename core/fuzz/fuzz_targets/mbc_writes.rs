@@ -0,0 +1,46 @@
+//! Creates a cartridge with the MBC, ROM size and RAM size given by the first
+//! three bytes of the input and performs the memory accesses described by the
+//! rest of it, checking that nothing panics.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use mahboi::{
+    BiosKind, Emulator, HardwareModel,
+    cartridge::Cartridge,
+    primitives::{Byte, Word},
+};
+
+
+fuzz_target!(|data: &[u8]| {
+    let (header, accesses) = match data {
+        [ty, rom_size, ram_size, rest @ ..] => ([*ty, *rom_size % 6, *ram_size % 6], rest),
+        _ => return,
+    };
+
+    let mut rom = vec![0; 0x8000 << header[1]];
+    rom[0x0147..0x014A].copy_from_slice(&header);
+    let cartridge = match Cartridge::from_bytes(&rom) {
+        Ok(cartridge) => cartridge,
+        Err(_) => return,
+    };
+
+    // Every access is four bytes: the kind (even: write, odd: read), the
+    // address (most significant byte first) and the value to write. Only the
+    // ROM area (i.e. the MBC registers) and external RAM are accessed.
+    let mut emulator = Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+    let machine = emulator.machine_mut();
+    for access in accesses.chunks_exact(4) {
+        let addr = u16::from_be_bytes([access[1], access[2]]);
+        let addr = if addr < 0x8000 { addr } else { 0xA000 | (addr & 0x1FFF) };
+        if access[0] % 2 == 0 {
+            machine.store_byte(Word::new(addr), Byte::new(access[3]));
+        } else {
+            machine.load_byte(Word::new(addr));
+        }
+
+        // The mapped bank has to exist.
+        assert!(machine.cartridge.rom_bank() * 0x4000 < machine.cartridge.rom().len());
+    }
+});
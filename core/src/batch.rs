@@ -0,0 +1,126 @@
+//! Running many emulators in parallel, each with its own input script.
+//!
+//! This is useful to search for glitches, to brute force RNG manipulation or
+//! for rollouts in reinforcement learning: all runs start at the same saved
+//! state (see `Gym::save`) and the end states of all of them are collected.
+
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+use crate::{
+    Disruption,
+    gym::{Gym, GymState, Observation},
+    machine::input::Keys,
+    primitives::Word,
+};
+
+
+/// The end of one run of `run_batch`.
+pub struct RunResult {
+    /// The observation after the last frame of the script.
+    pub observation: Observation,
+
+    /// The state after the last frame, e.g. to continue from there.
+    pub state: GymState,
+}
+
+/// Runs one emulator per input script, starting at `start`, on `threads`
+/// threads. A script contains the keys pressed in each frame. The
+/// observations contain the values at the `watched` addresses.
+///
+/// Returns the results in the order of `scripts`. If an emulator terminated,
+/// the result is the `Disruption` instead.
+pub fn run_batch(
+    start: &GymState,
+    watched: &[Word],
+    scripts: &[Vec<Keys>],
+    threads: usize,
+) -> Vec<Result<RunResult, Disruption>> {
+    // Scripts can take very different amounts of time (e.g. when an emulator
+    // terminates early), so the threads pick the next script when they are
+    // done instead of getting a fixed share.
+    let next = AtomicUsize::new(0);
+    let mut results = thread::scope(|scope| {
+        let workers = (0..threads.max(1)).map(|_| {
+            // A machine is not `Sync`, so every thread gets its own copy.
+            let start = start.clone();
+            let next = &next;
+            scope.spawn(move || {
+                let mut results = Vec::new();
+                loop {
+                    let idx = next.fetch_add(1, Ordering::Relaxed);
+                    let script = match scripts.get(idx) {
+                        Some(script) => script,
+                        None => break,
+                    };
+
+                    results.push((idx, run(&start, watched, script)));
+                }
+
+                results
+            })
+        }).collect::<Vec<_>>();
+
+        workers.into_iter()
+            .flat_map(|worker| worker.join().expect("batch worker panicked"))
+            .collect::<Vec<_>>()
+    });
+
+    results.sort_by_key(|&(idx, _)| idx);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Executes one script starting at `start`.
+fn run(start: &GymState, watched: &[Word], script: &[Keys]) -> Result<RunResult, Disruption> {
+    let mut gym = Gym::from_state(start, watched.to_vec());
+    for &keys in script {
+        gym.step(keys)?;
+    }
+
+    Ok(RunResult {
+        observation: gym.observe(),
+        state: gym.save(),
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        BiosKind,
+        machine::input::JoypadKey,
+        test_util::cartridge_with_code,
+    };
+    use super::*;
+
+    #[test]
+    fn test_run_batch() {
+        // 0x0150: select buttons, then store the inverted button state in
+        // $C000 forever:
+        //   ld a, $10; ldh [$00], a; ldh a, [$00]; cpl; ld [$C000], a; jr -8
+        let cartridge = cartridge_with_code(&[
+            0x3E, 0x10, 0xE0, 0x00, 0xF0, 0x00, 0x2F, 0xEA, 0x00, 0xC0, 0x18, 0xF8,
+        ]);
+        let watched = [Word::new(0xC000)];
+        let start = Gym::new(cartridge, BiosKind::Minimal, watched.to_vec()).save();
+
+        let keys = |key| Keys::none().set_key(key, true);
+        let scripts = (0..10)
+            .map(|i| {
+                let last = if i % 2 == 0 { keys(JoypadKey::A) } else { keys(JoypadKey::Start) };
+                vec![Keys::none(), last]
+            })
+            .collect::<Vec<_>>();
+
+        let results = run_batch(&start, &watched, &scripts, 3);
+        assert_eq!(results.len(), 10);
+        for (i, result) in results.into_iter().enumerate() {
+            let result = result.ok().unwrap();
+            let expected = if i % 2 == 0 { 0b0001 } else { 0b1000 };
+            assert_eq!(result.observation.frame, 2);
+            assert_eq!(result.observation.ram[0].get() & 0x0F, expected);
+        }
+    }
+}
@@ -1,16 +1,25 @@
 //! Everything related to the cartridge and its header.
 
 use std::{
+    error::Error,
     fmt,
     cmp::{PartialOrd, Ord, Ordering},
 };
 
 use crate::{
     log::*,
-    mbc::{Mbc, NoMbc, Mbc1, Mbc3, Mbc5},
+    mbc::{Mbc, NoMbc, Mbc1, Mbc3, Mbc5, PocketCamera, Tama5, HuC3, WisdomTree},
+    primitives::Byte,
 };
 
 
+/// The first address after the cartridge header. Every valid ROM is at least
+/// this long.
+const HEADER_END: usize = 0x0150;
+
+type MbcResult = Result<Box<dyn Mbc>, CartridgeError>;
+
+
 /// Specifies how this ROM works with the CGB. Stored at `0x0143`.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CgbMode {
@@ -31,16 +40,16 @@ pub enum CgbMode {
 
 impl CgbMode {
     /// Parses the CGB mode from the given byte.
-    pub fn from_byte(byte: u8) -> Self {
+    pub fn from_byte(byte: u8) -> Result<Self, CartridgeError> {
         match byte {
             // Bit 7 not set
-            0x00..=0x7F => CgbMode::NonCgb,
-            0xC0 => CgbMode::CgbOnly,
-            0x80 => CgbMode::BothSupported,
+            0x00..=0x7F => Ok(CgbMode::NonCgb),
+            0xC0 => Ok(CgbMode::CgbOnly),
+            0x80 => Ok(CgbMode::BothSupported),
 
             // Bit 7 and bit 2 or 3 set
-            b if (b & 0b0000_0110) != 0 => CgbMode::NonCgbSpecial,
-            _ => panic!("Unsupported cartridge CGB mode!"),
+            b if (b & 0b0000_0110) != 0 => Ok(CgbMode::NonCgbSpecial),
+            _ => Err(CartridgeError::InvalidCgbMode(byte)),
         }
     }
 }
@@ -81,10 +90,10 @@ pub enum CartridgeType {
 
 impl CartridgeType {
     /// Parses the cartridge type from the given byte.
-    pub fn from_byte(byte: u8) -> Self {
+    pub fn from_byte(byte: u8) -> Result<Self, CartridgeError> {
         use self::CartridgeType::*;
 
-        match byte {
+        let ty = match byte {
             0x00 => RomOnly,
             0x01 => Mbc1,
             0x02 => Mbc1Ram,
@@ -113,8 +122,35 @@ impl CartridgeType {
             0xFD => BandaiTama5,
             0xFE => HuC3,
             0xFF => HuC1RamBattery,
-            _ => panic!("Unsupported cartridge type {:02x}!", byte)
-        }
+            _ => return Err(CartridgeError::InvalidCartridgeType(byte)),
+        };
+
+        Ok(ty)
+    }
+
+    /// Returns whether the cartridge keeps its RAM while the Game Boy is
+    /// turned off, i.e. whether it has a battery (or an EEPROM, like the
+    /// TAMA5). Games use this RAM to store their save data.
+    pub fn has_battery(self) -> bool {
+        use self::CartridgeType::*;
+
+        matches!(
+            self,
+            Mbc1RamBattery
+                | Mbc2Battery
+                | RomRamBattery
+                | Mmm01RamBattery
+                | Mbc3TimerBattery
+                | Mbc3TimerRamBattery
+                | Mbc3RamBattery
+                | Mbc5RamBattery
+                | Mbc5RumbleRamBattery
+                | Mbc7SensorRumbleRamBattery
+                | PocketCamera
+                | BandaiTama5
+                | HuC3
+                | HuC1RamBattery
+        )
     }
 }
 
@@ -137,8 +173,8 @@ pub enum RomSize {
 
 impl RomSize {
     /// Parses the ROM size from the given byte.
-    pub fn from_byte(byte: u8) -> Self {
-        match byte {
+    pub fn from_byte(byte: u8) -> Result<Self, CartridgeError> {
+        let size = match byte {
             0x00 => RomSize::NoBanking,
             0x01 => RomSize::Banks4,
             0x02 => RomSize::Banks8,
@@ -151,8 +187,10 @@ impl RomSize {
             0x52 => RomSize::Banks72,
             0x53 => RomSize::Banks80,
             0x54 => RomSize::Banks96,
-            _ => panic!("Invalid ROM size in cartridge: {:02x}!", byte)
-        }
+            _ => return Err(CartridgeError::InvalidRomSize(byte)),
+        };
+
+        Ok(size)
     }
 
     /// Returns the number of bytes of the ROM.
@@ -212,16 +250,18 @@ pub enum RamSize {
 
 impl RamSize {
     /// Parses the RAM size from the given byte.
-    pub fn from_byte(byte: u8) -> Self {
-        match byte {
+    pub fn from_byte(byte: u8) -> Result<Self, CartridgeError> {
+        let size = match byte {
             0x00 => RamSize::None,
             0x01 => RamSize::Kb2,
             0x02 => RamSize::Kb8,
             0x03 => RamSize::Kb32,
             0x04 => RamSize::Kb128,
             0x05 => RamSize::Kb64,
-            _ => panic!("Invalid RAM size in cartridge: {:02x}!", byte)
-        }
+            _ => return Err(CartridgeError::InvalidRamSize(byte)),
+        };
+
+        Ok(size)
     }
 
     /// Returns the number of bytes of the RAM.
@@ -253,6 +293,7 @@ impl PartialOrd for RamSize {
 ///
 /// This contains the full cartridge data and a number of fields for specific
 /// header values.
+#[derive(Clone)]
 pub struct Cartridge {
     title: String,
     cgb_mode: CgbMode,
@@ -263,7 +304,40 @@ pub struct Cartridge {
 }
 
 impl Cartridge {
-    pub fn from_bytes(bytes: &[u8]) -> Self {
+    /// Parses the cartridge header and creates the matching MBC for the given
+    /// ROM data.
+    ///
+    /// All errors returned by this function describe why the given data is
+    /// not a (supported) Game Boy ROM. Their `Display` output is meant to be
+    /// shown to the user as is.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CartridgeError> {
+        Self::new(bytes, |cartridge_type, rom_size, ram_size| {
+            // The header of unlicensed games with this mapper is wrong.
+            if WisdomTree::detect(bytes) {
+                return Ok(Box::new(WisdomTree::new(bytes, rom_size)?) as Box<dyn Mbc>);
+            }
+
+            Self::get_mbc_impl(cartridge_type)(bytes, rom_size, ram_size)
+        })
+    }
+
+    /// Creates a cartridge with a custom MBC. The header is parsed from the
+    /// ROM returned by `mbc.rom()`, so it has to be valid. The cartridge type
+    /// and sizes in the header are not checked against the MBC, though.
+    pub fn with_mbc(mbc: Box<dyn Mbc>) -> Result<Self, CartridgeError> {
+        let bytes = mbc.rom().iter().map(|b| b.get()).collect::<Vec<_>>();
+        Self::new(&bytes, |_, _, _| Ok(mbc))
+    }
+
+    /// Parses the header of `bytes` and creates the MBC with `create_mbc`.
+    fn new(
+        bytes: &[u8],
+        create_mbc: impl FnOnce(CartridgeType, RomSize, RamSize) -> MbcResult,
+    ) -> Result<Self, CartridgeError> {
+        if bytes.len() < HEADER_END {
+            return Err(CartridgeError::TooShort(bytes.len()));
+        }
+
         // Parse header fields
 
         // Detect the name length by testing if the last 4 bytes contain a 0
@@ -282,41 +356,85 @@ impl Cartridge {
         let title = String::from_utf8_lossy(&bytes[0x0134..0x0134 + title_len]);
 
         // Read a couple of one byte values
-        let cgb_mode = CgbMode::from_byte(bytes[0x0143]);
-        let cartridge_type = CartridgeType::from_byte(bytes[0x0147]);
-        let rom_size = RomSize::from_byte(bytes[0x0148]);
-        let ram_size = RamSize::from_byte(bytes[0x0149]);
+        let cgb_mode = CgbMode::from_byte(bytes[0x0143])?;
+        let cartridge_type = CartridgeType::from_byte(bytes[0x0147])?;
+        let rom_size = RomSize::from_byte(bytes[0x0148])?;
+        let ram_size = RamSize::from_byte(bytes[0x0149])?;
         info!("{:?}, {:?}", cartridge_type, rom_size);
 
         // TODO checksum and nintendo logo check
 
-        let mbc = Self::get_mbc_impl(cartridge_type)(bytes, rom_size, ram_size);
+        let mbc = create_mbc(cartridge_type, rom_size, ram_size)?;
 
-        Self {
+        Ok(Self {
             title: title.into_owned(),
             cgb_mode,
             mbc,
             rom_size,
             ram_size,
             cartridge_type,
-        }
+        })
+    }
+
+    /// Returns the title from the cartridge header.
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    /// Returns the ROM bank currently mapped to `0x4000..0x8000`.
+    pub fn rom_bank(&self) -> usize {
+        self.mbc.rom_bank()
+    }
+
+    /// Returns the RAM bank currently mapped to `0xA000..0xC000`.
+    pub fn ram_bank(&self) -> usize {
+        self.mbc.ram_bank()
+    }
+
+    /// Returns the cartridge type from the header.
+    pub fn cartridge_type(&self) -> CartridgeType {
+        self.cartridge_type
+    }
+
+    /// Returns the memory bank controller, e.g. to inspect its state.
+    pub fn mbc(&self) -> &dyn Mbc {
+        &*self.mbc
+    }
+
+    /// Returns the full ROM with all banks.
+    pub fn rom(&self) -> &[Byte] {
+        self.mbc.rom()
+    }
+
+    /// Returns the cartridge RAM with all banks, e.g. to load a battery save.
+    pub fn ram_mut(&mut self) -> &mut [Byte] {
+        self.mbc.ram_mut()
     }
 
     /// Returns a function that creates the MBC implementation matching the
     /// given cartridge type.
-    fn get_mbc_impl(ty: CartridgeType) -> impl FnOnce(&[u8], RomSize, RamSize) -> Box<dyn Mbc> {
+    fn get_mbc_impl(ty: CartridgeType) -> impl FnOnce(&[u8], RomSize, RamSize) -> MbcResult {
         move |data, rom_size, ram_size| {
             use self::CartridgeType as Ct;
 
-            match ty {
-                Ct::RomOnly => Box::new(NoMbc::new(data, rom_size, ram_size)),
+            // Cartridge types without RAM must not specify a RAM size.
+            let check_no_ram = || {
+                if ram_size == RamSize::None {
+                    Ok(())
+                } else {
+                    Err(CartridgeError::UnexpectedRam { ty, ram_size })
+                }
+            };
+
+            let mbc: Box<dyn Mbc> = match ty {
+                Ct::RomOnly => Box::new(NoMbc::new(data, rom_size, ram_size)?),
 
                 Ct::Mbc1 | Ct::Mbc1Ram | Ct::Mbc1RamBattery => {
                     if ty == Ct::Mbc1 {
-                        assert!(ram_size == RamSize::None);
+                        check_no_ram()?;
                     }
 
-                    Box::new(Mbc1::new(data, rom_size, ram_size))
+                    Box::new(Mbc1::new(data, rom_size, ram_size)?)
                 }
 
                 Ct::Mbc5
@@ -326,10 +444,10 @@ impl Cartridge {
                 | Ct::Mbc5RumbleRam
                 | Ct::Mbc5RumbleRamBattery => {
                     if ty == Ct::Mbc5 || ty == Ct::Mbc5Rumble {
-                        assert!(ram_size == RamSize::None);
+                        check_no_ram()?;
                     }
 
-                    Box::new(Mbc5::new(data, rom_size, ram_size))
+                    Box::new(Mbc5::new(data, rom_size, ram_size)?)
                 }
 
                 Ct::Mbc3TimerBattery
@@ -338,28 +456,35 @@ impl Cartridge {
                 | Ct::Mbc3Ram
                 | Ct::Mbc3RamBattery => {
                     if ty == Ct::Mbc3TimerBattery || ty == Ct::Mbc3 {
-                        assert!(ram_size == RamSize::None);
+                        check_no_ram()?;
                     }
 
                     // TODO: maybe check something with the clock?
 
-                    Box::new(Mbc3::new(data, rom_size, ram_size))
+                    Box::new(Mbc3::new(data, rom_size, ram_size)?)
                 }
 
-                Ct::Mbc2 => unimplemented!(),
-                Ct::Mbc2Battery => unimplemented!(),
-                Ct::RomRam => unimplemented!(),
-                Ct::RomRamBattery => unimplemented!(),
-                Ct::Mmm01 => unimplemented!(),
-                Ct::Mmm01Ram => unimplemented!(),
-                Ct::Mmm01RamBattery => unimplemented!(),
-                Ct::Mbc6 => unimplemented!(),
-                Ct::Mbc7SensorRumbleRamBattery => unimplemented!(),
-                Ct::PocketCamera => unimplemented!(),
-                Ct::BandaiTama5 => unimplemented!(),
-                Ct::HuC3 => unimplemented!(),
-                Ct::HuC1RamBattery => unimplemented!(),
-            }
+                Ct::PocketCamera => Box::new(PocketCamera::new(data, rom_size, ram_size)?),
+
+                // The RAM size in the header is ignored as the EEPROM always
+                // has the same size.
+                Ct::BandaiTama5 => Box::new(Tama5::new(data, rom_size)?),
+
+                Ct::HuC3 => Box::new(HuC3::new(data, rom_size, ram_size)?),
+
+                Ct::Mbc2
+                | Ct::Mbc2Battery
+                | Ct::RomRam
+                | Ct::RomRamBattery
+                | Ct::Mmm01
+                | Ct::Mmm01Ram
+                | Ct::Mmm01RamBattery
+                | Ct::Mbc6
+                | Ct::Mbc7SensorRumbleRamBattery
+                | Ct::HuC1RamBattery => return Err(CartridgeError::UnsupportedCartridgeType(ty)),
+            };
+
+            Ok(mbc)
         }
     }
 }
@@ -376,3 +501,159 @@ impl fmt::Debug for Cartridge {
             .finish()
     }
 }
+
+/// Reasons why some data cannot be loaded as cartridge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CartridgeError {
+    /// The data is too short to even contain the cartridge header. Contains
+    /// the actual length.
+    TooShort(usize),
+
+    /// The CGB flag (`0x0143`) has an invalid value.
+    InvalidCgbMode(u8),
+
+    /// The cartridge type (`0x0147`) has an invalid value.
+    InvalidCartridgeType(u8),
+
+    /// The ROM size (`0x0148`) has an invalid value.
+    InvalidRomSize(u8),
+
+    /// The RAM size (`0x0149`) has an invalid value.
+    InvalidRamSize(u8),
+
+    /// The cartridge type is valid, but not supported by this emulator (yet).
+    UnsupportedCartridgeType(CartridgeType),
+
+    /// The length of the data does not match the ROM size from the header.
+    LengthMismatch {
+        rom_size: RomSize,
+        actual: usize,
+    },
+
+    /// The cartridge type says "no RAM", but the header specifies a RAM size.
+    UnexpectedRam {
+        ty: CartridgeType,
+        ram_size: RamSize,
+    },
+
+    /// The ROM size is too large for the memory bank controller.
+    UnsupportedRomSize {
+        rom_size: RomSize,
+        mbc: &'static str,
+    },
+
+    /// The RAM size cannot be used with the memory bank controller.
+    UnsupportedRamSize {
+        ram_size: RamSize,
+        mbc: &'static str,
+    },
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::CartridgeError::*;
+
+        match self {
+            TooShort(len) => write!(
+                f,
+                "file is too short to be a Game Boy ROM ({} bytes, but the header alone \
+                    is {} bytes long)",
+                len,
+                HEADER_END,
+            ),
+            InvalidCgbMode(b) => write!(f, "invalid CGB flag {:#04x} in cartridge header", b),
+            InvalidCartridgeType(b) => write!(
+                f,
+                "unknown cartridge type {:#04x} in cartridge header (is this really a \
+                    Game Boy ROM?)",
+                b,
+            ),
+            InvalidRomSize(b) => write!(f, "invalid ROM size {:#04x} in cartridge header", b),
+            InvalidRamSize(b) => write!(f, "invalid RAM size {:#04x} in cartridge header", b),
+            UnsupportedCartridgeType(ty) => {
+                write!(f, "cartridge type {:?} is not supported yet", ty)
+            }
+            LengthMismatch { rom_size, actual } => write!(
+                f,
+                "file is {} bytes long, but the cartridge header says the ROM has {} bytes \
+                    (the file is probably truncated or corrupted)",
+                actual,
+                rom_size.len(),
+            ),
+            UnexpectedRam { ty, ram_size } => write!(
+                f,
+                "cartridge type {:?} has no RAM, but the header specifies {} KiB of RAM",
+                ty,
+                ram_size.len() / 1024,
+            ),
+            UnsupportedRomSize { rom_size, mbc } => write!(
+                f,
+                "ROM size of {} KiB is not supported with {}",
+                rom_size.len() / 1024,
+                mbc,
+            ),
+            UnsupportedRamSize { ram_size, mbc } => write!(
+                f,
+                "RAM size of {} KiB is not supported with {}",
+                ram_size.len() / 1024,
+                mbc,
+            ),
+        }
+    }
+}
+
+impl Error for CartridgeError {}
+
+
+#[cfg(test)]
+mod test {
+    use crate::test_util::rom_with_code;
+    use super::*;
+
+    /// Returns the error of loading a 32 KiB ROM with the given type, ROM
+    /// size and RAM size in the header.
+    fn error(ty: u8, rom_size: u8, ram_size: u8) -> CartridgeError {
+        let mut rom = rom_with_code(&[]);
+        rom[0x0147..=0x0149].copy_from_slice(&[ty, rom_size, ram_size]);
+        Cartridge::from_bytes(&rom).unwrap_err()
+    }
+
+    #[test]
+    fn test_errors() {
+        let e = Cartridge::from_bytes(&[0; 0x100]).unwrap_err();
+        assert_eq!(e, CartridgeError::TooShort(0x100));
+        assert_eq!(
+            e.to_string(),
+            "file is too short to be a Game Boy ROM (256 bytes, but the header alone is 336 \
+                bytes long)",
+        );
+
+        let e = error(0x04, 0x00, 0x00);
+        assert_eq!(e, CartridgeError::InvalidCartridgeType(0x04));
+        assert_eq!(
+            e.to_string(),
+            "unknown cartridge type 0x04 in cartridge header (is this really a Game Boy ROM?)",
+        );
+
+        let e = error(0x01, 0x01, 0x00);
+        assert_eq!(e, CartridgeError::LengthMismatch { rom_size: RomSize::Banks4, actual: 0x8000 });
+        assert_eq!(
+            e.to_string(),
+            "file is 32768 bytes long, but the cartridge header says the ROM has 65536 bytes \
+                (the file is probably truncated or corrupted)",
+        );
+
+        let e = error(0x01, 0x00, 0x02);
+        let expected = CartridgeError::UnexpectedRam {
+            ty: CartridgeType::Mbc1,
+            ram_size: RamSize::Kb8,
+        };
+        assert_eq!(e, expected);
+        assert_eq!(
+            e.to_string(),
+            "cartridge type Mbc1 has no RAM, but the header specifies 8 KiB of RAM",
+        );
+
+        assert!(Cartridge::from_bytes(&rom_with_code(&[])).is_ok());
+    }
+}
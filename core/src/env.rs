@@ -1,14 +1,33 @@
 use crate::{
-    SCREEN_WIDTH,
+    SCREEN_HEIGHT, SCREEN_WIDTH,
     primitives::PixelColor,
     machine::input::Keys,
 };
 
+
+/// A complete frame of the LCD, row by row.
+pub type Frame = [[PixelColor; SCREEN_WIDTH]; SCREEN_HEIGHT];
+
 pub trait Peripherals {
+    /// If `true`, the emulator hands over complete frames via `present_frame`
+    /// instead of single lines via `write_lcd_line`. Frontends that show
+    /// whole frames anyway only have to copy once per frame then, and never
+    /// see a half drawn frame (e.g. when the LCD is disabled mid-frame). The
+    /// default is `false`.
+    const FULL_FRAMES: bool = false;
+
     /// Write one line of pixels to the Gameboy's LCD. The `line_idx` parameter
-    /// determines the line (from 0 to 159 inclusive).
+    /// determines the line (from 0 to 159 inclusive). Not called if
+    /// `FULL_FRAMES` is `true`.
     fn write_lcd_line(&mut self, line_idx: u8, pixels: &[PixelColor; SCREEN_WIDTH]);
 
+    /// Is called with the finished frame at the start of each V-Blank if
+    /// `FULL_FRAMES` is `true`. When the LCD is disabled, a blank frame is
+    /// presented once instead.
+    fn present_frame(&mut self, frame: &Frame) {
+        let _ = frame;
+    }
+
     /// Returns all currently pressed keys. The emulator calls this method
     /// frequently, so the implementing type should "cache" key presses in some
     /// way to allow fast access.
@@ -19,4 +38,23 @@ pub trait Peripherals {
     /// can call `f` at its own sample rate. It has to provide the sample rate
     /// to the function for certain audio filters within the emulator.
     fn offer_sound_sample(&mut self, f: impl FnOnce(f32) -> f32);
+
+    /// Is called when the cartridge (e.g. the Pocket Camera) captures an
+    /// image. The returned frame has to be `width` x `height` pixels large.
+    /// Returns `None` if no image source is available, which is the default.
+    fn capture_image(&mut self, width: usize, height: usize) -> Option<GreyFrame> {
+        let _ = (width, height);
+        None
+    }
+}
+
+/// A greyscale image, e.g. from a camera.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GreyFrame {
+    pub width: usize,
+    pub height: usize,
+
+    /// The brightness of all pixels, row by row. `0` is black and `255` is
+    /// white.
+    pub pixels: Vec<u8>,
 }
@@ -0,0 +1,181 @@
+//! A headless interface to drive the emulator frame by frame, e.g. from bots
+//! or for reinforcement learning.
+//!
+//! Each `Gym::step` executes one frame with the given keys pressed and returns
+//! an `Observation` of the result. States can be saved and restored cheaply,
+//! as the cartridge ROM is shared between all copies of a machine.
+
+use crate::{
+    BiosKind, Disruption, Emulator, HardwareModel, InputPolling, SCREEN_HEIGHT, SCREEN_WIDTH,
+    cartridge::Cartridge,
+    env::Peripherals,
+    machine::{Machine, input::Keys},
+    primitives::{Byte, PixelColor, Word},
+};
+
+
+/// An emulator without any frontend, driven one frame at a time.
+pub struct Gym {
+    emulator: Emulator,
+    screen: Screen,
+
+    /// The addresses whose values are part of every observation.
+    watched: Vec<Word>,
+
+    /// Number of frames executed.
+    frame: u64,
+}
+
+/// The state of the emulator after a `Gym::step`.
+#[derive(Debug, Clone)]
+pub struct Observation {
+    /// Number of frames executed since the gym was created.
+    pub frame: u64,
+
+    /// The content of the screen, row by row.
+    pub framebuffer: Vec<PixelColor>,
+
+    /// The values of the watched addresses, in the order passed to
+    /// `Gym::new`.
+    pub ram: Vec<Byte>,
+}
+
+/// A saved state of a `Gym` (see `Gym::save`).
+#[derive(Clone)]
+pub struct GymState {
+    machine: Machine,
+    frame: u64,
+    framebuffer: Vec<PixelColor>,
+}
+
+impl Gym {
+    /// Creates a gym running the given cartridge on a DMG. The values of the
+    /// memory at the `watched` addresses are part of every observation.
+    pub fn new(cartridge: Cartridge, bios: BiosKind, watched: Vec<Word>) -> Self {
+        // The keys only change between frames anyway.
+        let mut emulator = Emulator::new(cartridge, bios, HardwareModel::Dmg);
+        emulator.set_input_polling(InputPolling::PerFrame);
+
+        Self {
+            emulator,
+            screen: Screen {
+                framebuffer: vec![PixelColor::new(0, 0, 0); SCREEN_WIDTH * SCREEN_HEIGHT],
+                keys: Keys::none(),
+            },
+            watched,
+            frame: 0,
+        }
+    }
+
+    /// Executes one frame with `keys` pressed and returns the resulting
+    /// observation.
+    pub fn step(&mut self, keys: Keys) -> Result<Observation, Disruption> {
+        self.screen.keys = keys;
+        self.emulator.execute_frame(&mut self.screen, |_| false)?;
+        self.frame += 1;
+
+        Ok(self.observe())
+    }
+
+    /// Returns the observation of the current state, without executing
+    /// anything.
+    pub fn observe(&self) -> Observation {
+        let machine = self.emulator.machine();
+        Observation {
+            frame: self.frame,
+            framebuffer: self.screen.framebuffer.clone(),
+            ram: self.watched.iter().map(|&addr| machine.load_byte_bypass_dma(addr)).collect(),
+        }
+    }
+
+    /// Creates a gym starting at a state saved by another gym.
+    pub fn from_state(state: &GymState, watched: Vec<Word>) -> Self {
+        let mut machine = state.machine.clone();
+        machine.trace_stream = None;
+
+        Self {
+            emulator: Emulator {
+                machine,
+                input_polling: InputPolling::PerFrame,
+                trace_writer: None,
+            },
+            screen: Screen {
+                framebuffer: state.framebuffer.clone(),
+                keys: Keys::none(),
+            },
+            watched,
+            frame: state.frame,
+        }
+    }
+
+    /// Returns the machine, e.g. to read memory that is not watched.
+    pub fn machine(&self) -> &Machine {
+        self.emulator.machine()
+    }
+
+    /// Saves the current state, which can be restored with `restore`.
+    pub fn save(&self) -> GymState {
+        GymState {
+            machine: self.emulator.machine().clone(),
+            frame: self.frame,
+            framebuffer: self.screen.framebuffer.clone(),
+        }
+    }
+
+    /// Restores a state created by `save`.
+    pub fn restore(&mut self, state: &GymState) {
+        self.emulator.machine_mut().restore(&state.machine);
+        self.frame = state.frame;
+        self.screen.framebuffer.copy_from_slice(&state.framebuffer);
+    }
+}
+
+/// The peripherals of a gym: a framebuffer and the keys of the current step.
+struct Screen {
+    framebuffer: Vec<PixelColor>,
+    keys: Keys,
+}
+
+impl Peripherals for Screen {
+    fn write_lcd_line(&mut self, line_idx: u8, pixels: &[PixelColor; SCREEN_WIDTH]) {
+        let start = line_idx as usize * SCREEN_WIDTH;
+        self.framebuffer[start..start + SCREEN_WIDTH].copy_from_slice(pixels);
+    }
+
+    fn get_pressed_keys(&self) -> Keys {
+        self.keys
+    }
+
+    fn offer_sound_sample(&mut self, _: impl FnOnce(f32) -> f32) {}
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{machine::input::JoypadKey, test_util::cartridge_with_code};
+    use super::*;
+
+    #[test]
+    fn test_save_restore() {
+        // 0x0150: ld hl, $C000; inc [hl]; jr -3
+        let cartridge = cartridge_with_code(&[0x21, 0x00, 0xC0, 0x34, 0x18, 0xFD]);
+        let mut gym = Gym::new(cartridge, BiosKind::Minimal, vec![Word::new(0xC000)]);
+
+        let a = Keys::none().set_key(JoypadKey::A, true);
+        let first = gym.step(a).ok().unwrap();
+        assert_eq!(first.frame, 1);
+        assert_eq!(first.framebuffer.len(), SCREEN_WIDTH * SCREEN_HEIGHT);
+        assert_eq!(gym.machine().pressed_keys(), a);
+
+        let state = gym.save();
+        let second = gym.step(Keys::none()).ok().unwrap();
+        assert_eq!(second.frame, 2);
+        assert_ne!(first.ram, second.ram);
+
+        gym.restore(&state);
+        assert_eq!(gym.observe().ram, first.ram);
+        let again = gym.step(Keys::none()).ok().unwrap();
+        assert_eq!(again.frame, 2);
+        assert_eq!(again.ram, second.ram);
+    }
+}
@@ -2,11 +2,15 @@
 //!
 //! This module contains some basic information like size and clock count for
 //! all instructions. It is stored in two 256-element long arrays -- one for
-//! the main instructions and one for all PREFIX CB instructions.
+//! the main instructions and one for all PREFIX CB instructions. Based on
+//! that, `decode` disassembles and `assemble` assembles instructions.
 
-use std::ops::Index;
+use std::{
+    fmt,
+    ops::Index,
+};
 
-use crate::primitives::Byte;
+use crate::primitives::{Byte, Word};
 
 /// The information we store per instruction.
 #[derive(Debug, Clone, Copy)]
@@ -27,6 +31,13 @@ pub struct Instr {
     /// - `r8`: 8 bit signed value which is added to PC
     pub mnemonic: &'static str,
 
+    /// The mnemonic without operands, e.g. `LD` or `JR`.
+    pub name: &'static str,
+
+    /// The operands in the order of the mnemonic (destination first). Parsed
+    /// from the mnemonic at compile time.
+    pub operands: [Option<Operand>; 2],
+
     /// Length in bytes
     pub len: u8,
 
@@ -49,13 +60,7 @@ impl Instr {
         clocks: u8,
         clocks_taken: Option<u8>,
     ) -> Option<Self> {
-        Some(Instr {
-            opcode: Byte::new(opcode),
-            mnemonic,
-            len,
-            clocks,
-            clocks_taken,
-        })
+        Some(Self::new(opcode, mnemonic, len, clocks, clocks_taken))
     }
 
     const fn new(
@@ -65,19 +70,599 @@ impl Instr {
         clocks: u8,
         clocks_taken: Option<u8>,
     ) -> Self {
+        let (name, operands) = parse_mnemonic(mnemonic);
         Instr {
             opcode: Byte::new(opcode),
             mnemonic,
+            name,
+            operands,
             len,
             clocks,
             clocks_taken,
         }
     }
+
+    /// Returns the number of operands (0 to 2).
+    pub fn operand_count(&self) -> usize {
+        self.operands.iter().filter(|op| op.is_some()).count()
+    }
+}
+
+/// An 8 bit register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg8 {
+    A,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+}
+
+/// A 16 bit register or register pair.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg16 {
+    Af,
+    Bc,
+    De,
+    Hl,
+    Sp,
+}
+
+/// The condition of a conditional jump, call or return.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    /// `NZ`: zero flag not set.
+    NotZero,
+    /// `Z`: zero flag set.
+    Zero,
+    /// `NC`: carry flag not set.
+    NotCarry,
+    /// `C`: carry flag set.
+    Carry,
+}
+
+/// An operand of an instruction, as written in the mnemonic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    /// An 8 bit register, e.g. `B`.
+    Reg8(Reg8),
+
+    /// A 16 bit register, e.g. `BC` or `SP`.
+    Reg16(Reg16),
+
+    /// The memory at the address in a register pair: `(BC)`, `(DE)` or
+    /// `(HL)`.
+    Mem(Reg16),
+
+    /// `(HL+)`: the memory at HL, which is incremented afterwards.
+    MemHlInc,
+
+    /// `(HL-)`: the memory at HL, which is decremented afterwards.
+    MemHlDec,
+
+    /// `(C)`: the memory at `$FF00 + C`.
+    MemHighC,
+
+    /// `d8`: 8 bit immediate data.
+    Imm8,
+
+    /// `d16`: 16 bit immediate data.
+    Imm16,
+
+    /// `a16`: 16 bit immediate address as target of a jump or call.
+    Addr16,
+
+    /// `(a16)`: the memory at a 16 bit immediate address.
+    MemAddr16,
+
+    /// `(a8)`: the memory at `$FF00` plus an 8 bit immediate.
+    MemHighAddr8,
+
+    /// `r8` of `JR`: a signed 8 bit immediate offset to the address of the
+    /// next instruction.
+    Rel8,
+
+    /// `r8` of `ADD SP, r8`: a signed 8 bit immediate.
+    SignedImm8,
+
+    /// `SP+r8`: SP plus a signed 8 bit immediate.
+    SpRel8,
+
+    /// The condition of a conditional jump, call or return.
+    Cond(Condition),
+
+    /// The bit index of `BIT`, `RES` and `SET` (0 to 7).
+    Bit(u8),
+
+    /// The fixed call target of `RST`, e.g. `0x38` for `RST 38H`.
+    RstTarget(u8),
+}
+
+impl Operand {
+    /// Returns the number of bytes this operand occupies after the opcode.
+    pub fn immediate_len(&self) -> u8 {
+        match self {
+            Operand::Imm8
+            | Operand::MemHighAddr8
+            | Operand::Rel8
+            | Operand::SignedImm8
+            | Operand::SpRel8 => 1,
+            Operand::Imm16 | Operand::Addr16 | Operand::MemAddr16 => 2,
+            _ => 0,
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    /// Formats the operand as in the mnemonic.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reg16 = |r: &Reg16| match r {
+            Reg16::Af => "AF",
+            Reg16::Bc => "BC",
+            Reg16::De => "DE",
+            Reg16::Hl => "HL",
+            Reg16::Sp => "SP",
+        };
+
+        match self {
+            Operand::Reg8(r) => write!(f, "{:?}", r),
+            Operand::Reg16(r) => write!(f, "{}", reg16(r)),
+            Operand::Mem(r) => write!(f, "({})", reg16(r)),
+            Operand::MemHlInc => write!(f, "(HL+)"),
+            Operand::MemHlDec => write!(f, "(HL-)"),
+            Operand::MemHighC => write!(f, "(C)"),
+            Operand::Imm8 => write!(f, "d8"),
+            Operand::Imm16 => write!(f, "d16"),
+            Operand::Addr16 => write!(f, "a16"),
+            Operand::MemAddr16 => write!(f, "(a16)"),
+            Operand::MemHighAddr8 => write!(f, "(a8)"),
+            Operand::Rel8 | Operand::SignedImm8 => write!(f, "r8"),
+            Operand::SpRel8 => write!(f, "SP+r8"),
+            Operand::Cond(Condition::NotZero) => write!(f, "NZ"),
+            Operand::Cond(Condition::Zero) => write!(f, "Z"),
+            Operand::Cond(Condition::NotCarry) => write!(f, "NC"),
+            Operand::Cond(Condition::Carry) => write!(f, "C"),
+            Operand::Bit(bit) => write!(f, "{}", bit),
+            Operand::RstTarget(target) => write!(f, "{:02X}H", target),
+        }
+    }
+}
+
+/// The value of an immediate operand of a disassembled instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Immediate {
+    /// The data of `d8`.
+    Data8(Byte),
+
+    /// The data of `d16`.
+    Data16(Word),
+
+    /// The address of `a16`, `(a16)` and `(a8)` (with `$FF00` already
+    /// added) and the jump target of `JR`.
+    Addr(Word),
+
+    /// The signed value of `SP+r8` and `ADD SP, r8`.
+    Signed(i8),
+}
+
+/// An operand of a disassembled instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResolvedOperand {
+    pub operand: Operand,
+
+    /// The value, if the operand is an immediate.
+    pub value: Option<Immediate>,
+}
+
+impl fmt::Display for ResolvedOperand {
+    /// Formats the operand as in the mnemonic, but with the placeholder
+    /// replaced by the value.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let signed = |v: i8| {
+            let sign = if v < 0 { '-' } else { '+' };
+            format!("{}0x{:02x}", sign, v.unsigned_abs())
+        };
+
+        match (self.operand, self.value) {
+            (Operand::SpRel8, Some(Immediate::Signed(v))) => write!(f, "SP{}", signed(v)),
+            (_, Some(Immediate::Signed(v))) => write!(f, "{}", signed(v)),
+            (Operand::MemAddr16, Some(Immediate::Addr(addr)))
+            | (Operand::MemHighAddr8, Some(Immediate::Addr(addr))) => write!(f, "({})", addr),
+            (_, Some(Immediate::Addr(addr))) => write!(f, "{}", addr),
+            (_, Some(Immediate::Data8(b))) => write!(f, "{}", b),
+            (_, Some(Immediate::Data16(w))) => write!(f, "{}", w),
+            (operand, None) => write!(f, "{}", operand),
+        }
+    }
+}
+
+/// An instruction decoded from memory, with its immediate operands resolved
+/// (see `decode`).
+#[derive(Debug, Clone, Copy)]
+pub struct Disassembled {
+    /// The address of the instruction.
+    pub addr: Word,
+
+    /// The instruction. For `PREFIX CB` instructions, this is the entry of
+    /// `PREFIXED_INSTRUCTIONS`.
+    pub instr: Instr,
+
+    /// Whether this is a `PREFIX CB` instruction.
+    pub prefixed: bool,
+
+    pub operands: [Option<ResolvedOperand>; 2],
+}
+
+impl fmt::Display for Disassembled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.instr.name)?;
+        for (i, operand) in self.operands.iter().flatten().enumerate() {
+            let sep = if i == 0 { " " } else { ", " };
+            write!(f, "{}{}", sep, operand)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Decodes the instruction at the start of `bytes`, which is located at
+/// `addr`. Returns `None` if the opcode is invalid or `bytes` is too short
+/// for the instruction.
+pub fn decode(addr: Word, bytes: &[Byte]) -> Option<Disassembled> {
+    let (instr, prefixed) = match INSTRUCTIONS[*bytes.first()?]? {
+        instr if instr.name == "PREFIX CB" => (PREFIXED_INSTRUCTIONS[*bytes.get(1)?], true),
+        instr => (instr, false),
+    };
+
+    let args = bytes.get(1..instr.len as usize)?;
+    let resolve = |operand: Operand| {
+        let value = match operand {
+            Operand::Imm8 => Immediate::Data8(args[0]),
+            Operand::Imm16 => Immediate::Data16(Word::from_bytes(args[0], args[1])),
+            Operand::Addr16 | Operand::MemAddr16 => {
+                Immediate::Addr(Word::from_bytes(args[0], args[1]))
+            }
+            Operand::MemHighAddr8 => Immediate::Addr(Word::new(0xFF00) + args[0]),
+            Operand::Rel8 => Immediate::Addr(addr + instr.len as u16 + args[0].get() as i8),
+            Operand::SignedImm8 | Operand::SpRel8 => Immediate::Signed(args[0].get() as i8),
+            _ => return ResolvedOperand { operand, value: None },
+        };
+
+        ResolvedOperand { operand, value: Some(value) }
+    };
+
+    Some(Disassembled {
+        addr,
+        instr,
+        prefixed,
+        operands: [instr.operands[0].map(resolve), instr.operands[1].map(resolve)],
+    })
+}
+
+/// Disassembles the instruction at the start of `bytes`, which is located at
+/// `addr`, e.g. `LD A, 0x3f` or `JR NZ, 0x0150` (with the jump target
+/// already computed). Invalid opcodes and truncated instructions are shown
+/// as `<invalid>`.
+pub fn disassemble(addr: Word, bytes: &[Byte]) -> String {
+    match decode(addr, bytes) {
+        Some(instr) => instr.to_string(),
+        None => "<invalid>".to_string(),
+    }
+}
+
+/// An error while assembling code (see `assemble`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmError {
+    /// There is no instruction with this name and these operands. Contains
+    /// the instruction as written.
+    UnknownInstruction(String),
+
+    /// A value does not fit into its operand, e.g. `LD A, 300` or a `JR`
+    /// target that is too far away. Contains the operand.
+    OutOfRange(String),
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AsmError::UnknownInstruction(instr) => write!(f, "unknown instruction '{}'", instr),
+            AsmError::OutOfRange(operand) => write!(f, "operand '{}' out of range", operand),
+        }
+    }
+}
+
+/// Assembles one or more instructions, separated by `;` or newlines, e.g.
+/// `LD A, $3F; LDH [$80], A`.
+///
+/// The syntax is the one of the mnemonics and of `disassemble`, but case
+/// insensitive and a bit more liberal:
+/// - numbers can be written as `$3F`, `0x3F`, `3FH`, `%111111` or `63`,
+/// - memory operands can be written with brackets, e.g. `[HL]`,
+/// - `(HLI)`, `(HLD)`, `($FF00+C)`, `LDH A, (C)` and `JP (HL)` are accepted,
+/// - the `A` of 8 bit arithmetic can be omitted or added (`SUB A, B`).
+///
+/// The operand of `JR` is the jump target, like in the disassembly, or an
+/// offset with explicit sign (e.g. `JR -3`). As the code is assumed to be
+/// located at `0x0000`, use `assemble_at` for jump targets.
+pub fn assemble(src: &str) -> Result<Vec<u8>, AsmError> {
+    assemble_at(Word::new(0), src)
+}
+
+/// Like `assemble`, but for code located at `addr`.
+pub fn assemble_at(addr: Word, src: &str) -> Result<Vec<u8>, AsmError> {
+    let mut out = Vec::new();
+    for instr in src.split([';', '\n']).map(str::trim).filter(|s| !s.is_empty()) {
+        assemble_instr(addr + out.len() as u16, instr, &mut out)?;
+    }
+
+    Ok(out)
+}
+
+/// Assembles one instruction located at `addr` and appends it to `out`.
+fn assemble_instr(addr: Word, src: &str, out: &mut Vec<u8>) -> Result<(), AsmError> {
+    let (name, rest) = src.split_once(char::is_whitespace).unwrap_or((src, ""));
+    let name = name.to_ascii_uppercase();
+    let mut operands = match rest.trim() {
+        "" => vec![],
+        rest => rest.split(',').map(canonical_operand).collect::<Vec<_>>(),
+    };
+
+    let name = match name.as_str() {
+        "LDH" if operands.iter().any(|op| op == "(C)") => "LD",
+        "JP" if operands == ["(HL)"] => {
+            operands[0] = "HL".into();
+            "JP"
+        }
+        name => name,
+    };
+
+    let mut variants = vec![operands.clone()];
+    if matches!(name, "ADD" | "ADC" | "SUB" | "SBC" | "AND" | "XOR" | "OR" | "CP") {
+        match operands.as_slice() {
+            [a, b] if a == "A" => variants.push(vec![b.clone()]),
+            [b] => variants.push(vec!["A".into(), b.clone()]),
+            _ => {}
+        }
+    }
+
+    let instrs = INSTRUCTIONS.0.iter().flatten().map(|instr| (false, instr))
+        .chain(PREFIXED_INSTRUCTIONS.0.iter().map(|instr| (true, instr)))
+        .filter(|(_, instr)| instr.name == name);
+    for (prefixed, instr) in instrs {
+        let end = addr + instr.len as u16;
+        for operands in variants.iter().filter(|ops| ops.len() == instr.operand_count()) {
+            let mut bytes = if prefixed {
+                vec![0xCB, instr.opcode.get()]
+            } else {
+                vec![instr.opcode.get()]
+            };
+
+            let mut matches = true;
+            for (&operand, text) in instr.operands.iter().flatten().zip(operands) {
+                if !encode_operand(operand, text, end, &mut bytes)? {
+                    matches = false;
+                    break;
+                }
+            }
+
+            if matches {
+                // The second byte of `STOP` is not an operand.
+                bytes.resize(instr.len as usize, 0);
+                out.extend(bytes);
+                return Ok(());
+            }
+        }
+    }
+
+    Err(AsmError::UnknownInstruction(src.to_string()))
+}
+
+/// Normalizes an operand: uppercase, without whitespace, with parentheses
+/// for memory operands and in the form used by the mnemonics.
+fn canonical_operand(s: &str) -> String {
+    let s = s.chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| match c {
+            '[' => '(',
+            ']' => ')',
+            c => c.to_ascii_uppercase(),
+        })
+        .collect::<String>();
+
+    let high_c = s.strip_prefix('(')
+        .and_then(|s| s.strip_suffix("+C)"))
+        .and_then(parse_number)
+        == Some(0xFF00);
+    match s.as_str() {
+        "(HLI)" => "(HL+)".into(),
+        "(HLD)" => "(HL-)".into(),
+        _ if high_c => "(C)".into(),
+        _ => s,
+    }
+}
+
+/// Checks whether the operand `text` (see `canonical_operand`) fits
+/// `operand` and if so, appends its immediate bytes to `out`. `end` is the
+/// address after the instruction.
+fn encode_operand(
+    operand: Operand,
+    text: &str,
+    end: Word,
+    out: &mut Vec<u8>,
+) -> Result<bool, AsmError> {
+    let value = match operand {
+        Operand::MemAddr16 | Operand::MemHighAddr8 => text.strip_prefix('(')
+            .and_then(|s| s.strip_suffix(')'))
+            .and_then(parse_number),
+        Operand::SpRel8 => text.strip_prefix("SP")
+            .filter(|s| s.starts_with(['+', '-']))
+            .and_then(parse_number),
+        Operand::Imm8
+        | Operand::Imm16
+        | Operand::Addr16
+        | Operand::Rel8
+        | Operand::SignedImm8
+        | Operand::Bit(_)
+        | Operand::RstTarget(_) => parse_number(text),
+        _ => return Ok(text == operand.to_string()),
+    };
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(false),
+    };
+
+    let in_range = match operand {
+        Operand::Bit(bit) => return Ok(value == bit as i64),
+        Operand::RstTarget(target) => return Ok(value == target as i64),
+        Operand::Imm8 => (-0x80..=0xFF).contains(&value),
+        Operand::Imm16 => (-0x8000..=0xFFFF).contains(&value),
+        Operand::Addr16 | Operand::MemAddr16 => (0..=0xFFFF).contains(&value),
+        Operand::MemHighAddr8 => {
+            (0..=0xFF).contains(&value) || (0xFF00..=0xFFFF).contains(&value)
+        }
+        _ => true,
+    };
+    if !in_range {
+        return Err(AsmError::OutOfRange(text.to_string()));
+    }
+
+    match operand {
+        Operand::Imm16 | Operand::Addr16 | Operand::MemAddr16 => {
+            out.extend_from_slice(&(value as u16).to_le_bytes());
+        }
+        Operand::Rel8 | Operand::SignedImm8 | Operand::SpRel8 => {
+            // Without a sign, the operand of `JR` is the jump target.
+            let offset = if operand == Operand::Rel8 && !text.starts_with(['+', '-']) {
+                value - end.get() as i64
+            } else {
+                value
+            };
+            if !(-0x80..=0x7F).contains(&offset) {
+                return Err(AsmError::OutOfRange(text.to_string()));
+            }
+            out.push(offset as u8);
+        }
+        _ => out.push(value as u8),
+    }
+
+    Ok(true)
+}
+
+/// Parses a number in one of the syntaxes accepted by `assemble`, optionally
+/// with a sign.
+fn parse_number(s: &str) -> Option<i64> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+
+    let digits = |digits: &str, radix| {
+        if !digits.is_empty() && digits.chars().all(|c| c.is_digit(radix)) {
+            i64::from_str_radix(digits, radix).ok()
+        } else {
+            None
+        }
+    };
+    let value = if let Some(hex) = s.strip_prefix('$').or_else(|| s.strip_prefix("0X")) {
+        digits(hex, 16)?
+    } else if let Some(bin) = s.strip_prefix('%') {
+        digits(bin, 2)?
+    } else if let Some(hex) = s.strip_suffix('H') {
+        // `FFH` would be ambiguous with names, so a leading digit is required.
+        if !hex.starts_with(|c: char| c.is_ascii_digit()) {
+            return None;
+        }
+        digits(hex, 16)?
+    } else {
+        digits(s, 10)?
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+/// Splits the mnemonic into the name and the operands.
+const fn parse_mnemonic(mnemonic: &'static str) -> (&'static str, [Option<Operand>; 2]) {
+    let bytes = mnemonic.as_bytes();
+    let mut name_end = 0;
+    while name_end < bytes.len() && bytes[name_end] != b' ' {
+        name_end += 1;
+    }
+
+    // `PREFIX CB` is not an instruction with an operand.
+    let name = mnemonic.split_at(name_end).0;
+    if name_end == bytes.len() || matches!(name.as_bytes(), b"PREFIX") {
+        return (mnemonic, [None, None]);
+    }
+
+    let name_bytes = name.as_bytes();
+
+    let rest = bytes.split_at(name_end + 1).1;
+    let mut comma = 0;
+    while comma < rest.len() && rest[comma] != b',' {
+        comma += 1;
+    }
+    if comma == rest.len() {
+        (name, [Some(parse_operand(rest, name_bytes)), None])
+    } else {
+        let (first, second) = rest.split_at(comma);
+        let second = second.split_at(2).1;
+        (name, [
+            Some(parse_operand(first, name_bytes)),
+            Some(parse_operand(second, name_bytes)),
+        ])
+    }
+}
+
+/// Parses one operand of an instruction with the given name.
+const fn parse_operand(s: &[u8], name: &[u8]) -> Operand {
+    // In jumps, calls and returns, `C` is the carry condition, not the
+    // register.
+    let has_condition = matches!(name, b"JP" | b"JR" | b"CALL" | b"RET");
+
+    match s {
+        b"A" => Operand::Reg8(Reg8::A),
+        b"B" => Operand::Reg8(Reg8::B),
+        b"C" if has_condition => Operand::Cond(Condition::Carry),
+        b"C" => Operand::Reg8(Reg8::C),
+        b"D" => Operand::Reg8(Reg8::D),
+        b"E" => Operand::Reg8(Reg8::E),
+        b"H" => Operand::Reg8(Reg8::H),
+        b"L" => Operand::Reg8(Reg8::L),
+        b"AF" => Operand::Reg16(Reg16::Af),
+        b"BC" => Operand::Reg16(Reg16::Bc),
+        b"DE" => Operand::Reg16(Reg16::De),
+        b"HL" => Operand::Reg16(Reg16::Hl),
+        b"SP" => Operand::Reg16(Reg16::Sp),
+        b"(BC)" => Operand::Mem(Reg16::Bc),
+        b"(DE)" => Operand::Mem(Reg16::De),
+        b"(HL)" => Operand::Mem(Reg16::Hl),
+        b"(HL+)" => Operand::MemHlInc,
+        b"(HL-)" => Operand::MemHlDec,
+        b"(C)" => Operand::MemHighC,
+        b"d8" => Operand::Imm8,
+        b"d16" => Operand::Imm16,
+        b"a16" => Operand::Addr16,
+        b"(a16)" => Operand::MemAddr16,
+        b"(a8)" => Operand::MemHighAddr8,
+        b"r8" if matches!(name, b"JR") => Operand::Rel8,
+        b"r8" => Operand::SignedImm8,
+        b"SP+r8" => Operand::SpRel8,
+        b"NZ" => Operand::Cond(Condition::NotZero),
+        b"Z" => Operand::Cond(Condition::Zero),
+        b"NC" => Operand::Cond(Condition::NotCarry),
+        &[bit @ b'0'..=b'7'] => Operand::Bit(bit - b'0'),
+        &[hi @ b'0'..=b'3', lo @ (b'0' | b'8'), b'H'] => {
+            Operand::RstTarget((hi - b'0') * 0x10 + (lo - b'0'))
+        }
+        _ => panic!("unknown operand in mnemonic"),
+    }
 }
 
 /// Simple wrapper to make the static array indexable with `Byte` instead of
 /// `usize`.
-pub struct InstrDb<T>([T; 256]);
+pub struct InstrDb<T>(pub(crate) [T; 256]);
 
 impl<T> Index<Byte> for InstrDb<T> {
     type Output = T;
@@ -1182,3 +1767,141 @@ macro_rules! prefixed_opcode {
     ("SET 7, (HL)") => { 0xfe };
     ("SET 7, A") => { 0xff };
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_operands() {
+        let ops = |op: u8| INSTRUCTIONS[Byte::new(op)].unwrap().operands;
+        assert_eq!(ops(0x00), [None, None]);
+        assert_eq!(ops(0x06), [Some(Operand::Reg8(Reg8::B)), Some(Operand::Imm8)]);
+        assert_eq!(ops(0x08), [Some(Operand::MemAddr16), Some(Operand::Reg16(Reg16::Sp))]);
+        assert_eq!(ops(0x22), [Some(Operand::MemHlInc), Some(Operand::Reg8(Reg8::A))]);
+        assert_eq!(ops(0x38), [Some(Operand::Cond(Condition::Carry)), Some(Operand::Rel8)]);
+        assert_eq!(ops(0xD8), [Some(Operand::Cond(Condition::Carry)), None]);
+        assert_eq!(ops(0xE2), [Some(Operand::MemHighC), Some(Operand::Reg8(Reg8::A))]);
+        assert_eq!(ops(0xE8), [Some(Operand::Reg16(Reg16::Sp)), Some(Operand::SignedImm8)]);
+        assert_eq!(ops(0xF8), [Some(Operand::Reg16(Reg16::Hl)), Some(Operand::SpRel8)]);
+        assert_eq!(ops(0xFF), [Some(Operand::RstTarget(0x38)), None]);
+        assert_eq!(INSTRUCTIONS[Byte::new(0xCB)].unwrap().name, "PREFIX CB");
+        assert_eq!(INSTRUCTIONS[Byte::new(0xF8)].unwrap().name, "LD");
+
+        let bit = PREFIXED_INSTRUCTIONS[Byte::new(0x7E)];
+        assert_eq!(bit.name, "BIT");
+        assert_eq!(bit.operands, [Some(Operand::Bit(7)), Some(Operand::Mem(Reg16::Hl))]);
+    }
+
+    #[test]
+    fn test_operand_lengths() {
+        // The immediates have to fill up the instruction (except for `STOP`
+        // and `PREFIX CB`, whose second byte is not an operand).
+        for instr in INSTRUCTIONS.0.iter().flatten() {
+            let imm_len: u8 = instr.operands.iter().flatten().map(|op| op.immediate_len()).sum();
+            match instr.name {
+                "STOP" => assert_eq!(instr.len, 2),
+                "PREFIX CB" => assert_eq!(instr.len, 0),
+                _ => assert_eq!(1 + imm_len, instr.len, "{}", instr.mnemonic),
+            }
+        }
+        for instr in PREFIXED_INSTRUCTIONS.0.iter() {
+            assert_eq!(instr.operands.iter().flatten().map(|op| op.immediate_len()).sum::<u8>(), 0);
+        }
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let dis = |addr: u16, bytes: &[u8]| {
+            let bytes = bytes.iter().map(|&b| Byte::new(b)).collect::<Vec<_>>();
+            disassemble(Word::new(addr), &bytes)
+        };
+
+        assert_eq!(dis(0x0100, &[0x00]), "NOP");
+        assert_eq!(dis(0x0100, &[0x3E, 0x3F]), "LD A, 0x3f");
+        assert_eq!(dis(0x0100, &[0x21, 0x34, 0x12]), "LD HL, 0x1234");
+        assert_eq!(dis(0x0100, &[0xC3, 0x50, 0x01]), "JP 0x0150");
+        assert_eq!(dis(0x0100, &[0xFA, 0x00, 0xC0]), "LD A, (0xc000)");
+        assert_eq!(dis(0x0100, &[0xE0, 0x80]), "LDH (0xff80), A");
+        assert_eq!(dis(0x0150, &[0x20, 0xFE]), "JR NZ, 0x0150");
+        assert_eq!(dis(0x0150, &[0x18, 0x10]), "JR 0x0162");
+        assert_eq!(dis(0x0100, &[0xE8, 0xFD]), "ADD SP, -0x03");
+        assert_eq!(dis(0x0100, &[0xF8, 0x05]), "LD HL, SP+0x05");
+        assert_eq!(dis(0x0100, &[0xFF]), "RST 38H");
+        assert_eq!(dis(0x0100, &[0xCB, 0x7E]), "BIT 7, (HL)");
+        assert_eq!(dis(0x0100, &[0x22]), "LD (HL+), A");
+        assert_eq!(dis(0x0100, &[0xD3]), "<invalid>");
+        assert_eq!(dis(0x0100, &[0xC3, 0x50]), "<invalid>");
+        assert_eq!(dis(0x0100, &[0xCB]), "<invalid>");
+
+        let instr = decode(Word::new(0x0100), &[Byte::new(0xCB), Byte::new(0x11)]).unwrap();
+        assert!(instr.prefixed);
+        assert_eq!(instr.instr.mnemonic, "RL C");
+        assert_eq!(instr.operands[0].unwrap().value, None);
+    }
+
+    #[test]
+    fn test_assemble() {
+        assert_eq!(assemble("LD A, $3F"), Ok(vec![0x3E, 0x3F]));
+        assert_eq!(assemble("ld a, 0x3f"), Ok(vec![0x3E, 0x3F]));
+        assert_eq!(assemble("NOP"), Ok(vec![0x00]));
+        assert_eq!(assemble("stop"), Ok(vec![0x10, 0x00]));
+        assert_eq!(assemble("LD HL, 4660"), Ok(vec![0x21, 0x34, 0x12]));
+        assert_eq!(assemble("LD [$C000], A"), Ok(vec![0xEA, 0x00, 0xC0]));
+        assert_eq!(assemble("LDH A, [$80]"), Ok(vec![0xF0, 0x80]));
+        assert_eq!(assemble("LDH ($FF80), A"), Ok(vec![0xE0, 0x80]));
+        assert_eq!(assemble("LDH A, (C)"), Ok(vec![0xF2]));
+        assert_eq!(assemble("LD ($FF00+C), A"), Ok(vec![0xE2]));
+        assert_eq!(assemble("LD [HLI], A"), Ok(vec![0x22]));
+        assert_eq!(assemble("LD A, (HL-)"), Ok(vec![0x3A]));
+        assert_eq!(assemble("LD HL, SP-3"), Ok(vec![0xF8, 0xFD]));
+        assert_eq!(assemble("ADD SP, -3"), Ok(vec![0xE8, 0xFD]));
+        assert_eq!(assemble("SUB A, B"), Ok(vec![0x90]));
+        assert_eq!(assemble("ADD B"), Ok(vec![0x80]));
+        assert_eq!(assemble("JP C, $0150"), Ok(vec![0xDA, 0x50, 0x01]));
+        assert_eq!(assemble("JP (HL)"), Ok(vec![0xE9]));
+        assert_eq!(assemble("RET NC"), Ok(vec![0xD0]));
+        assert_eq!(assemble("RST 38H"), Ok(vec![0xFF]));
+        assert_eq!(assemble("RST $08"), Ok(vec![0xCF]));
+        assert_eq!(assemble("BIT 7, [HL]"), Ok(vec![0xCB, 0x7E]));
+        assert_eq!(assemble("RES 0, %1"), Err(AsmError::UnknownInstruction("RES 0, %1".into())));
+        assert_eq!(assemble("JR -3"), Ok(vec![0x18, 0xFD]));
+        assert_eq!(
+            assemble("ld hl, $C000; inc [hl]\njr -3"),
+            Ok(vec![0x21, 0x00, 0xC0, 0x34, 0x18, 0xFD]),
+        );
+        assert_eq!(assemble_at(Word::new(0x0150), "JR NZ, $0150"), Ok(vec![0x20, 0xFE]));
+        assert_eq!(assemble_at(Word::new(0x0150), "NOP; JR $0150"), Ok(vec![0x00, 0x18, 0xFD]));
+
+        assert_eq!(assemble("LD A, 300"), Err(AsmError::OutOfRange("300".into())));
+        assert_eq!(assemble("JR $0150"), Err(AsmError::OutOfRange("$0150".into())));
+        assert_eq!(assemble("LDH ($1234), A"), Err(AsmError::OutOfRange("($1234)".into())));
+        assert_eq!(assemble("LD A, Q"), Err(AsmError::UnknownInstruction("LD A, Q".into())));
+        assert_eq!(assemble("FOO"), Err(AsmError::UnknownInstruction("FOO".into())));
+        assert_eq!(assemble(" ; "), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_assemble_disassembly() {
+        // Assembling the disassembly of every instruction has to result in
+        // the same bytes.
+        let addr = Word::new(0x0150);
+        let prefixed = (0..=0xFF).map(|op| vec![0xCB, op]);
+        let unprefixed = INSTRUCTIONS.0.iter()
+            .flatten()
+            .filter(|instr| instr.name != "PREFIX CB")
+            .map(|instr| {
+                // The second byte of `STOP` is always assembled as 0.
+                let arg = if instr.name == "STOP" { 0x00 } else { 0x12 };
+                let bytes = [instr.opcode.get(), arg, 0x34];
+                bytes[..instr.len as usize].to_vec()
+            });
+        for bytes in unprefixed.chain(prefixed) {
+            let bytes = bytes.iter().map(|&b| Byte::new(b)).collect::<Vec<_>>();
+            let code = disassemble(addr, &bytes);
+            let expected = bytes.iter().map(|b| b.get()).collect::<Vec<_>>();
+            assert_eq!(assemble_at(addr, &code), Ok(expected), "{}", code);
+        }
+    }
+}
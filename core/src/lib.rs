@@ -1,14 +1,17 @@
 //! Mahboi!
 
 
+use std::io::Write;
+
 use crate::{
     env::Peripherals,
     cartridge::{Cartridge},
     machine::{
         Machine,
         ppu::Mode,
+        trace_stream::{TraceFormat, TraceStream},
     },
-    primitives::CYCLES_PER_FRAME,
+    primitives::{Byte, CYCLES_PER_FRAME},
     log::*,
 };
 
@@ -22,6 +25,15 @@ pub mod primitives;
 pub mod env;
 pub mod cartridge;
 pub mod machine;
+pub mod gym;
+pub mod batch;
+pub mod link;
+pub mod rollback;
+pub mod save_state;
+
+#[cfg(any(test, feature = "test-util"))]
+#[doc(hidden)]
+pub mod test_util;
 
 
 /// Width of the Game Boy screen in pixels.
@@ -34,6 +46,20 @@ pub const MACHINE_CYCLES_PER_SECOND: u32 = 1_048_576;
 
 pub const FRAME_RATE: f64 = 59.727500569606;
 
+/// Whether faster approximations are used instead of the cycle-exact paths.
+/// This is selected at compile time with the `fast` feature (which requires
+/// disabling the default `accuracy` feature), so it costs nothing at runtime.
+/// With `fast`:
+///
+/// - the pixel transfer always takes 172 dots, ignoring scrolling, the window
+///   and sprites,
+/// - OAM DMA does not block the bus, so reads return the real values and
+///   writes are not lost,
+/// - the PPU is not advanced cycle by cycle while an OAM DMA is active.
+///
+/// Most games don't notice, but timing sensitive ones and test ROMs do.
+pub const FAST: bool = cfg!(all(feature = "fast", not(feature = "accuracy")));
+
 
 /// Different kinds of BIOS (boot ROMs) that can be loaded.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -46,16 +72,124 @@ pub enum BiosKind {
 }
 
 
+/// The Game Boy model that is emulated. It determines the register values
+/// after the boot ROM and a few hardware quirks. Note that CGB features are
+/// not emulated: CGB games only work if they support the DMG as well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HardwareModel {
+    /// The first revision of the original Game Boy.
+    Dmg0,
+
+    /// The original Game Boy.
+    Dmg,
+
+    /// The Game Boy Pocket.
+    Mgb,
+
+    /// The Super Game Boy.
+    Sgb,
+
+    /// The Game Boy Color running a DMG only game.
+    CgbDmgMode,
+
+    /// The Game Boy Color.
+    Cgb,
+}
+
+impl HardwareModel {
+    /// Returns whether this is a Game Boy Color (in any mode).
+    pub fn is_cgb(&self) -> bool {
+        matches!(self, HardwareModel::CgbDmgMode | HardwareModel::Cgb)
+    }
+}
+
+
+/// How often the emulator asks the peripherals for the pressed keys (see
+/// `Peripherals::get_pressed_keys`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputPolling {
+    /// After every instruction. This is the most expensive option, but sub
+    /// frame inputs (used in speedruns and TAS) are only possible with it.
+    PerInstruction,
+
+    /// Once per scanline, i.e. 154 times per frame.
+    PerScanline,
+
+    /// Once per frame, at the start of `Emulator::execute_frame`. Good enough
+    /// for normal playing.
+    PerFrame,
+}
+
+
 pub struct Emulator {
     machine: Machine,
+    input_polling: InputPolling,
+
+    /// The writer of the trace stream, if it was started.
+    trace_writer: Option<Box<dyn Write + Send>>,
 }
 
 impl Emulator {
-    pub fn new(cartridge: Cartridge, bios: BiosKind) -> Self {
-        info!("Creating emulator");
+    pub fn new(cartridge: Cartridge, bios: BiosKind, model: HardwareModel) -> Self {
+        info!("Creating emulator ({:?})", model);
+
+        Self {
+            machine: Machine::new(cartridge, bios, model),
+            input_polling: InputPolling::PerInstruction,
+            trace_writer: None,
+        }
+    }
+
+    /// Sets how often input is polled in `execute_frame`. The default is
+    /// `InputPolling::PerInstruction`.
+    pub fn set_input_polling(&mut self, input_polling: InputPolling) {
+        self.input_polling = input_polling;
+    }
+
+    /// Starts streaming all executed instructions, interrupt dispatches and
+    /// bank switches to `writer` (see `machine::trace_stream` for the
+    /// formats). The records are written at the end of each
+    /// `execute_frame`, so a buffered writer is not necessary. If writing
+    /// fails, the stream is stopped. A running stream is replaced.
+    pub fn start_trace(&mut self, writer: impl Write + Send + 'static, format: TraceFormat) {
+        self.stop_trace();
+        let mbc = &self.machine.cartridge.mbc;
+        self.machine.trace_stream = Some(TraceStream::new(format, mbc.rom_bank(), mbc.ram_bank()));
+        self.trace_writer = Some(Box::new(writer));
+    }
+
+    /// Writes the remaining records and stops the trace stream.
+    pub fn stop_trace(&mut self) {
+        self.flush_trace();
+        self.machine.trace_stream = None;
+        self.trace_writer = None;
+    }
+
+    /// Writes the records collected since the last call to the writer.
+    fn flush_trace(&mut self) {
+        let stream = &mut self.machine.trace_stream;
+        if let (Some(stream), Some(writer)) = (stream, &mut self.trace_writer) {
+            if let Err(e) = stream.flush_to(writer).and_then(|_| writer.flush()) {
+                error!("failed to write trace stream, stopping: {}", e);
+                self.machine.trace_stream = None;
+                self.trace_writer = None;
+            }
+        }
+    }
+
+    /// Creates an independent copy of this emulator, e.g. for bots and TAS
+    /// tools exploring different inputs from the same state. This is much
+    /// cheaper than a save state round trip: the cartridge ROM is shared
+    /// until one of the copies modifies it. The input polling mode is kept,
+    /// but the copy does not continue the trace stream.
+    pub fn fork(&self) -> Self {
+        let mut machine = self.machine.clone();
+        machine.trace_stream = None;
 
         Self {
-            machine: Machine::new(cartridge, bios),
+            machine,
+            input_polling: self.input_polling,
+            trace_writer: None,
         }
     }
 
@@ -63,6 +197,12 @@ impl Emulator {
         &self.machine
     }
 
+    /// Returns the machine mutably. This is mainly useful for debuggers that
+    /// want to modify memory, registers or memory hooks.
+    pub fn machine_mut(&mut self) -> &mut Machine {
+        &mut self.machine
+    }
+
     /// Executes until the end of one frame (in most cases exactly 17,556 cycles)
     ///
     /// After executing this once, the emulator has written a new frame via the display
@@ -73,65 +213,68 @@ impl Emulator {
         peripherals: &mut impl Peripherals,
         mut should_pause: impl FnMut(&Machine) -> bool,
     ) -> Result<(), Disruption> {
-        let mut cycles = 0;
-        loop {
+        let mut progress = FrameProgress::default();
+        let result = loop {
             if should_pause(&self.machine) {
-                return Err(Disruption::Paused);
+                break Err(Disruption::Paused);
             }
 
-            // Let the CPU execute one instruction
-            let cycles_spent = self.machine.step()?;
-
-            // Let other subsystems run for the same number of cycles as the
-            // CPU did.
-            let vblank_before = self.machine.ppu.regs().mode() == Mode::VBlank;
-            for _ in 0..cycles_spent {
-                // Timer
-                self.machine.timer.step(&mut self.machine.interrupt_controller);
-
-                // PPU
-                self.machine.ppu.step(peripherals, &mut self.machine.interrupt_controller);
+            match self.step_in_frame(&mut progress, peripherals) {
+                Ok(false) => {}
+                Ok(true) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
 
-                // OAM DMA
-                self.machine.dma_step();
+        self.flush_trace();
+        result
+    }
 
-                self.machine.sound_controller.step();
-                peripherals.offer_sound_sample(|sample_rate| {
-                    self.machine.sound_controller.output(sample_rate)
-                });
-            }
+    /// Executes one step as part of the frame described by `progress`.
+    /// Returns `true` if the frame is finished.
+    #[inline(always)]
+    fn step_in_frame(
+        &mut self,
+        progress: &mut FrameProgress,
+        peripherals: &mut impl Peripherals,
+    ) -> Result<bool, Disruption> {
+        let line = self.machine.ppu.regs().current_line;
+        let poll_input = match self.input_polling {
+            InputPolling::PerInstruction => true,
+            InputPolling::PerScanline => progress.polled_line != Some(line),
+            InputPolling::PerFrame => progress.polled_line.is_none(),
+        };
+        if poll_input {
+            progress.polled_line = Some(line);
+        }
 
-            // Handle input
-            //
-            // TODO: It's a bit wasteful to check this every cycle. Normal
-            // users probably wouldn't notice any difference if we would check
-            // this only once per frame. However, sub frame inputs are a thing
-            // in speed running. We could make this configurable.
-            self.machine.input_controller.handle_input(
-                peripherals,
-                &mut self.machine.interrupt_controller,
-            );
-
-            // If we just entered V-Blank, we will return. This is here to get
-            // the PPU and real Display synchronized.
-            if !vblank_before && self.machine.ppu.regs().mode() == Mode::VBlank {
-                break;
-            }
+        let vblank_before = self.machine.ppu.regs().mode() == Mode::VBlank;
+        let cycles_spent = self.machine.execute_step_polling(peripherals, poll_input)?;
 
-            // This is just a fallback for the case that the LCD is disabled
-            // the whole time or repeatedly which would mean no V-Blank is ever
-            // entered. To avoid spending too many cycles in this method, we
-            // return after a fixed number of cycles regardless.
-            cycles += cycles_spent as u64;
-            if cycles >= CYCLES_PER_FRAME {
-                break;
-            }
+        // If we just entered V-Blank, we will return. This is here to get
+        // the PPU and real Display synchronized.
+        if !vblank_before && self.machine.ppu.regs().mode() == Mode::VBlank {
+            return Ok(true);
         }
 
-        Ok(())
+        // This is just a fallback for the case that the LCD is disabled
+        // the whole time or repeatedly which would mean no V-Blank is ever
+        // entered. To avoid spending too many cycles in this method, we
+        // return after a fixed number of cycles regardless.
+        progress.cycles += cycles_spent as u64;
+        Ok(progress.cycles >= CYCLES_PER_FRAME)
     }
 }
 
+/// The progress of a frame executed step by step.
+#[derive(Default)]
+struct FrameProgress {
+    cycles: u64,
+
+    /// The line in which the input was last polled.
+    polled_line: Option<Byte>,
+}
+
 
 /// Describes the special situation when the emulator stops unexpectedly.
 pub enum Disruption {
@@ -143,3 +286,134 @@ pub enum Disruption {
     /// means that the emulator probably can't be resumed in any useful way.
     Terminated,
 }
+
+
+#[cfg(test)]
+mod test {
+    use std::{cell::Cell, io, sync::{Arc, Mutex}};
+    use crate::{
+        machine::input::{JoypadKey, Keys},
+        primitives::{PixelColor, Word},
+        test_util::rom_with_code,
+    };
+    use super::*;
+
+    /// Counts how often the keys are requested.
+    #[derive(Default)]
+    struct CountPolls(Cell<u32>);
+
+    impl Peripherals for CountPolls {
+        fn get_pressed_keys(&self) -> Keys {
+            self.0.set(self.0.get() + 1);
+            Keys::none()
+        }
+
+        fn write_lcd_line(&mut self, _: u8, _: &[PixelColor; SCREEN_WIDTH]) {}
+        fn offer_sound_sample(&mut self, _: impl FnOnce(f32) -> f32) {}
+    }
+
+    #[test]
+    fn test_input_polling() {
+        let polls = |polling| {
+            let cartridge = Cartridge::from_bytes(&vec![0; 0x8000]).unwrap();
+            let mut emulator = Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+            emulator.set_input_polling(polling);
+            let mut peripherals = CountPolls::default();
+
+            // The first frame is not complete, as the BIOS does not start at
+            // the beginning of a frame.
+            let _ = emulator.execute_frame(&mut peripherals, |_| false);
+            peripherals.0.set(0);
+            let _ = emulator.execute_frame(&mut peripherals, |_| false);
+            peripherals.0.get()
+        };
+
+        assert_eq!(polls(InputPolling::PerFrame), 1);
+        // Once per line, plus possibly once more at the line on which the
+        // frame started and ended.
+        assert!((154..=155).contains(&polls(InputPolling::PerScanline)));
+        assert!(polls(InputPolling::PerInstruction) > 10_000);
+    }
+
+    #[test]
+    fn test_fork() {
+        // 0150: LDH A, (00); LD (C000), A; JR -7
+        let mut rom = vec![0; 0x8000];
+        rom[0x150..0x157].copy_from_slice(&[0xF0, 0x00, 0xEA, 0x00, 0xC0, 0x18, 0xF9]);
+        let cartridge = Cartridge::from_bytes(&rom).unwrap();
+        let mut emulator = Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        emulator.machine_mut().cpu.pc = Word::new(0x150);
+        emulator.machine_mut().store_byte(Word::new(0xFF00), Byte::new(0x10));
+
+        struct Press(Keys);
+        impl Peripherals for Press {
+            fn get_pressed_keys(&self) -> Keys {
+                self.0
+            }
+            fn write_lcd_line(&mut self, _: u8, _: &[PixelColor; SCREEN_WIDTH]) {}
+            fn offer_sound_sample(&mut self, _: impl FnOnce(f32) -> f32) {}
+        }
+
+        // Both branches continue from the same state with different inputs.
+        let mut fork = emulator.fork();
+        let _ = emulator.execute_frame(&mut Press(Keys::none()), |_| false);
+        let _ = fork.execute_frame(&mut Press(Keys::none().set_key(JoypadKey::A, true)), |_| false);
+
+        let read = |e: &Emulator| e.machine().load_byte(Word::new(0xC000)).get() & 0x0F;
+        assert_eq!(read(&emulator), 0x0F);
+        assert_eq!(read(&fork), 0x0E);
+        assert_eq!(emulator.machine().cycle_count(), fork.machine().cycle_count());
+    }
+
+    /// A writer whose output can still be inspected after it was moved into
+    /// the emulator.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trace_stream() {
+        // An MBC1 cartridge. 0x0150: ld a, $01; ldh [$FF], a; ei; ld a, $02;
+        // ld [$2000], a; jr -2. The VBlank handler only returns.
+        let mut rom = rom_with_code(&[
+            0x3E, 0x01, 0xE0, 0xFF, 0xFB, 0x3E, 0x02, 0xEA, 0x00, 0x20, 0x18, 0xFE,
+        ]);
+        rom.resize(0x10000, 0);
+        rom[0x40] = 0xD9;
+        rom[0x147..0x149].copy_from_slice(&[0x01, 0x01]);
+
+        let run = |format| {
+            let cartridge = Cartridge::from_bytes(&rom).unwrap();
+            let mut emulator = Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+            let out = SharedBuf::default();
+            emulator.start_trace(out.clone(), format);
+            for _ in 0..3 {
+                let _ = emulator.execute_frame(&mut CountPolls::default(), |_| false);
+            }
+            emulator.stop_trace();
+            let bytes = out.0.lock().unwrap().clone();
+            bytes
+        };
+
+        let text = String::from_utf8(run(TraceFormat::Text)).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "BANK 01 00");
+        assert!(lines.iter().any(|l| l.starts_with("0150 3E AF:")));
+        assert!(lines.contains(&"BANK 02 00"));
+        assert!(lines.contains(&"INT 0040 FROM 015A"));
+        assert!(lines.iter().any(|l| l.starts_with("0040 D9 ")));
+        assert_eq!(lines.iter().filter(|l| l.starts_with("BANK")).count(), 2);
+
+        let binary = run(TraceFormat::Binary);
+        assert_eq!(binary[..4], [0x03, 0x01, 0x00, 0x00]);
+        assert!(binary.len() < text.len() / 2);
+    }
+}
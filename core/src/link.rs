@@ -0,0 +1,93 @@
+//! Connecting the serial ports of two emulators with a link cable, in memory.
+//!
+//! Both emulators are executed in lockstep: the one that executed fewer
+//! cycles makes the next step. As soon as one of them finished a transfer
+//! with its internal clock, the bytes are exchanged with the other one (if it
+//! waits for a transfer with the external clock).
+
+use crate::{
+    Disruption, Emulator, FrameProgress,
+    env::Peripherals,
+    machine::Machine,
+    primitives::Byte,
+};
+
+
+/// Executes one frame of both emulators with their serial ports connected.
+/// Once connected, transfers of the emulators wait for the other side, so
+/// they should only be executed with this function afterwards.
+pub fn execute_linked_frame(
+    (left, left_peripherals): (&mut Emulator, &mut impl Peripherals),
+    (right, right_peripherals): (&mut Emulator, &mut impl Peripherals),
+) -> Result<(), Disruption> {
+    left.machine.serial.linked = true;
+    right.machine.serial.linked = true;
+
+    let mut left_progress = FrameProgress::default();
+    let mut right_progress = FrameProgress::default();
+    let mut left_done = false;
+    let mut right_done = false;
+    while !left_done || !right_done {
+        let left_next = !left_done
+            && (right_done || left.machine.cycle_count() <= right.machine.cycle_count());
+        if left_next {
+            left_done = left.step_in_frame(&mut left_progress, left_peripherals)?;
+        } else {
+            right_done = right.step_in_frame(&mut right_progress, right_peripherals)?;
+        }
+
+        exchange(&mut left.machine, &mut right.machine);
+        exchange(&mut right.machine, &mut left.machine);
+    }
+
+    Ok(())
+}
+
+/// Finishes the transfer of `master` if it shifted all bits.
+fn exchange(master: &mut Machine, slave: &mut Machine) {
+    if let Some(byte) = master.serial.outgoing() {
+        let received = slave.serial.receive(byte, &mut slave.interrupt_controller)
+            .unwrap_or(Byte::new(0xFF));
+        master.serial.finish(received, &mut master.interrupt_controller);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        BiosKind, HardwareModel,
+        primitives::Word,
+        test_util::{NullPeripherals, cartridge_with_code},
+    };
+    use super::*;
+
+    /// 0x0150: ld a, <data>; ldh [$01], a; ld a, <control>; ldh [$02], a; jr -2
+    fn emulator(data: u8, control: u8) -> Emulator {
+        let cartridge = cartridge_with_code(&[
+            0x3E, data, 0xE0, 0x01, 0x3E, control, 0xE0, 0x02, 0x18, 0xFE,
+        ]);
+        Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg)
+    }
+
+    #[test]
+    fn test_linked_transfer() {
+        let sb = Word::new(0xFF01);
+        let mut master = emulator(0x12, 0x81);
+        let mut slave = emulator(0x34, 0x80);
+        execute_linked_frame(
+            (&mut master, &mut NullPeripherals),
+            (&mut slave, &mut NullPeripherals),
+        ).ok().unwrap();
+        assert_eq!(master.machine().load_byte(sb), Byte::new(0x34));
+        assert_eq!(slave.machine().load_byte(sb), Byte::new(0x12));
+
+        // Without a partner, the master receives 0xFF and the slave waits.
+        let mut master = emulator(0x12, 0x81);
+        let mut slave = emulator(0x34, 0x80);
+        master.execute_frame(&mut NullPeripherals, |_| false).ok().unwrap();
+        slave.execute_frame(&mut NullPeripherals, |_| false).ok().unwrap();
+        assert_eq!(master.machine().load_byte(sb), Byte::new(0xFF));
+        assert_eq!(slave.machine().load_byte(sb), Byte::new(0x34));
+    }
+}
@@ -0,0 +1,387 @@
+//! Export and import of save states in the Best Effort Save State format
+//! (BESS), which is understood by several other emulators (e.g. SameBoy).
+//!
+//! A BESS file starts with the raw memory buffers, followed by a sequence of
+//! blocks (4 ASCII characters, the length as `u32` and the data) and a footer
+//! of 8 bytes: the offset of the first block as `u32` and the magic `BESS`.
+//! All numbers are little endian. Only the `CORE` block is required; unknown
+//! blocks are ignored. The `CORE` block contains the CPU registers, all IO
+//! registers and the size and offset of each memory buffer. MBC registers are
+//! stored as writes that restore them (`MBC ` block).
+//!
+//! BESS does not contain the internal state of the PPU, timer or sound, so
+//! importing is not exact: the PPU restarts the current line, for example.
+//! Use `Machine::save_state` for exact states.
+
+use std::convert::TryInto;
+
+use crate::{
+    HardwareModel,
+    log::*,
+    primitives::{Byte, Word},
+    save_state::SaveStateError,
+};
+use super::{Machine, State};
+
+
+/// The version of the `CORE` block written by `export_bess`.
+const MAJOR_VERSION: u16 = 1;
+const MINOR_VERSION: u16 = 1;
+
+/// Size of the `CORE` block.
+const CORE_LEN: usize = 0xD0;
+
+/// Offset of the IO registers in the `CORE` block.
+const CORE_IO_OFFSET: usize = 0x18;
+
+/// Offset of the memory buffer sizes and offsets in the `CORE` block.
+const CORE_BUFFERS_OFFSET: usize = CORE_IO_OFFSET + 0x80;
+
+/// The registers of the sound channels with the trigger bit (bit 7).
+const TRIGGER_REGISTERS: [u16; 4] = [0xFF14, 0xFF19, 0xFF1E, 0xFF23];
+
+
+impl Machine {
+    /// Serializes the state of this machine into a BESS save state (see the
+    /// module documentation).
+    pub fn export_bess(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        // Raw memory buffers. The CGB palettes don't exist on the DMG and are
+        // stored with a size of 0.
+        let mut buffers = Vec::new();
+        let ram = self.cartridge.mbc.ram();
+        for buffer in &[
+            self.wram.as_slice(),
+            self.ppu.vram.as_slice(),
+            ram,
+            self.ppu.oam.as_slice(),
+            self.hram.as_slice(),
+            &[],
+            &[],
+        ] {
+            buffers.push((buffer.len() as u32, out.len() as u32));
+            out.extend(buffer.iter().map(|b| b.get()));
+        }
+        let first_block = out.len() as u32;
+
+        let name = format!("mahboi {}", env!("CARGO_PKG_VERSION"));
+        write_block(&mut out, b"NAME", name.as_bytes());
+
+        write_block(&mut out, b"INFO", &rom_info(self));
+
+        let mut core = Vec::with_capacity(CORE_LEN);
+        core.extend_from_slice(&MAJOR_VERSION.to_le_bytes());
+        core.extend_from_slice(&MINOR_VERSION.to_le_bytes());
+        core.extend_from_slice(model_name(self.model));
+        let cpu = &self.cpu;
+        for reg in &[cpu.pc, cpu.af(), cpu.bc(), cpu.de(), cpu.hl(), cpu.sp] {
+            core.extend_from_slice(&reg.get().to_le_bytes());
+        }
+        core.push(self.interrupt_controller.ime as u8);
+        core.push(self.interrupt_controller.interrupt_enable.get());
+        core.push(match self.state {
+            State::Normal => 0,
+            State::Halted => 1,
+            State::Stopped => 2,
+        });
+        core.push(0);
+        for addr in 0xFF00..0xFF80 {
+            core.push(self.load_byte_bypass_dma(Word::new(addr)).get());
+        }
+        for (size, offset) in buffers {
+            core.extend_from_slice(&size.to_le_bytes());
+            core.extend_from_slice(&offset.to_le_bytes());
+        }
+        write_block(&mut out, b"CORE", &core);
+
+        let mbc = self.cartridge.mbc.register_writes().into_iter()
+            .flat_map(|(addr, byte)| {
+                let [lo, hi] = addr.get().to_le_bytes();
+                vec![lo, hi, byte.get()]
+            })
+            .collect::<Vec<_>>();
+        if !mbc.is_empty() {
+            write_block(&mut out, b"MBC ", &mbc);
+        }
+
+        write_block(&mut out, b"END ", &[]);
+        out.extend_from_slice(&first_block.to_le_bytes());
+        out.extend_from_slice(b"BESS");
+        out
+    }
+
+    /// Restores a BESS save state (e.g. created by another emulator) with the
+    /// same ROM. If an error is returned, this machine is not changed.
+    pub fn import_bess(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        let blocks = parse_blocks(data)?;
+        let block = |tag: &[u8; 4]| blocks.iter().find(|(t, _)| t == tag).map(|(_, d)| *d);
+
+        // If the ROM is specified, it has to match ours.
+        if let Some(info) = block(b"INFO") {
+            if info != &rom_info(self)[..] {
+                let title = String::from_utf8_lossy(&info[..info.len().min(0x10)]);
+                return Err(SaveStateError::RomMismatch(title.trim_end_matches('\0').into()));
+            }
+        }
+
+        let core = block(b"CORE").ok_or_else(|| SaveStateError::MissingChunk("CORE".into()))?;
+        if core.len() < CORE_LEN {
+            return Err(SaveStateError::Corrupted("CORE block is too short".into()));
+        }
+        let major = u16_at(core, 0);
+        if major != MAJOR_VERSION {
+            return Err(SaveStateError::UnsupportedVersion(major));
+        }
+        if core[4] != model_name(self.model)[0] {
+            warn!(
+                "[bess] save state was created for model '{}', but {:?} is emulated",
+                String::from_utf8_lossy(&core[4..8]),
+                self.model,
+            );
+        }
+
+        // Find the memory buffers and check their sizes.
+        let buffer = |idx: usize, expected_len: usize| {
+            let pos = CORE_BUFFERS_OFFSET + idx * 8;
+            let size = u32_at(core, pos) as usize;
+            let offset = u32_at(core, pos + 4) as usize;
+            if size != expected_len {
+                return Err(SaveStateError::Corrupted(format!(
+                    "buffer {} has size {:#x}, expected {:#x}",
+                    idx,
+                    size,
+                    expected_len,
+                )));
+            }
+            data.get(offset..offset + size)
+                .ok_or_else(|| SaveStateError::Corrupted(format!("buffer {} is truncated", idx)))
+        };
+        let wram = buffer(0, 0x2000)?;
+        let vram = buffer(1, 0x2000)?;
+        let ram = buffer(2, self.cartridge.mbc.ram().len())?;
+        let oam = buffer(3, 0xA0)?;
+        let hram = buffer(4, 0x7F)?;
+
+        let mut machine = self.clone();
+        let copy = |dst: &mut [Byte], src: &[u8]| {
+            for (d, &s) in dst.iter_mut().zip(src) {
+                *d = Byte::new(s);
+            }
+        };
+
+        // IO registers. Registers with side effects on writes are restored
+        // without those side effects.
+        let io = &core[CORE_IO_OFFSET..CORE_IO_OFFSET + 0x80];
+        for (i, &value) in io.iter().enumerate() {
+            let addr = Word::new(0xFF00 + i as u16);
+            let byte = Byte::new(value);
+            match addr.get() {
+                0xFF00 => machine.input_controller.store_register(byte),
+                0xFF01..=0xFF02 => machine.serial.store_byte(addr, byte),
+                0xFF04..=0xFF07 => machine.timer.store_byte(addr, byte),
+                0xFF0F => machine.interrupt_controller.store_if(byte),
+                a if TRIGGER_REGISTERS.contains(&a) => {
+                    machine.sound_controller.store_byte(addr - 0xFF10, byte.map(|b| b & 0x7F));
+                }
+                0xFF10..=0xFF3F => machine.sound_controller.store_byte(addr - 0xFF10, byte),
+                0xFF40..=0xFF4B => {}
+                _ => machine.io[addr - 0xFF00] = byte,
+            }
+        }
+        let ppu_regs = io[0x40..0x4C].iter().map(|&b| Byte::new(b)).collect::<Vec<_>>();
+        machine.ppu.restore_registers(&ppu_regs);
+
+        // CPU
+        machine.cpu.pc = Word::new(u16_at(core, 0x08));
+        machine.cpu.set_af(Word::new(u16_at(core, 0x0A)));
+        machine.cpu.set_bc(Word::new(u16_at(core, 0x0C)));
+        machine.cpu.set_de(Word::new(u16_at(core, 0x0E)));
+        machine.cpu.set_hl(Word::new(u16_at(core, 0x10)));
+        machine.cpu.sp = Word::new(u16_at(core, 0x12));
+        machine.interrupt_controller.ime = core[0x14] != 0;
+        machine.interrupt_controller.interrupt_enable = Byte::new(core[0x15]);
+        machine.state = match core[0x16] {
+            0 => State::Normal,
+            1 => State::Halted,
+            2 => State::Stopped,
+            other => {
+                return Err(SaveStateError::Corrupted(format!("invalid CPU state {}", other)));
+            }
+        };
+        machine.enable_interrupts_next_step = false;
+
+        // Memory
+        copy(machine.wram.as_mut_slice(), wram);
+        copy(machine.ppu.vram.as_mut_slice(), vram);
+        copy(machine.cartridge.mbc.ram_mut(), ram);
+        copy(machine.ppu.oam.as_mut_slice(), oam);
+        copy(machine.hram.as_mut_slice(), hram);
+
+        // MBC registers
+        if let Some(mbc) = block(b"MBC ") {
+            let writes = mbc.chunks_exact(3);
+            if !writes.remainder().is_empty() {
+                return Err(SaveStateError::Corrupted("invalid MBC block".into()));
+            }
+            for write in writes {
+                let addr = Word::new(u16_at(write, 0));
+                if addr.get() < 0x8000 {
+                    machine.cartridge.mbc.store_rom_byte(addr, Byte::new(write[2]));
+                } else {
+                    warn!("[bess] ignoring MBC write to {}", addr);
+                }
+            }
+        }
+
+        *self = machine;
+        self.update_trace_banks();
+        Ok(())
+    }
+}
+
+/// Returns the title and the global checksum of the ROM, as stored in the
+/// `INFO` block.
+fn rom_info(machine: &Machine) -> Vec<u8> {
+    let rom = machine.cartridge.rom();
+    rom[0x134..0x144].iter().chain(&rom[0x14E..0x150]).map(|b| b.get()).collect()
+}
+
+fn model_name(model: HardwareModel) -> &'static [u8; 4] {
+    match model {
+        HardwareModel::Dmg0 | HardwareModel::Dmg => b"GD  ",
+        HardwareModel::Mgb => b"GM  ",
+        HardwareModel::Sgb => b"SN  ",
+        HardwareModel::CgbDmgMode | HardwareModel::Cgb => b"CC  ",
+    }
+}
+
+fn write_block(out: &mut Vec<u8>, tag: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(tag);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+}
+
+fn u16_at(data: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes(data[pos..pos + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+}
+
+/// The tag and data of each block.
+type Blocks<'a> = Vec<([u8; 4], &'a [u8])>;
+
+/// Checks the footer and returns all blocks up to the `END ` block.
+fn parse_blocks(data: &[u8]) -> Result<Blocks<'_>, SaveStateError> {
+    if data.len() < 8 || &data[data.len() - 4..] != b"BESS" {
+        return Err(SaveStateError::NotASaveState);
+    }
+    let footer = data.len() - 8;
+
+    let mut blocks = Vec::new();
+    let mut pos = u32_at(data, footer) as usize;
+    loop {
+        if pos + 8 > footer {
+            return Err(SaveStateError::Corrupted("truncated block header".into()));
+        }
+        let tag: [u8; 4] = data[pos..pos + 4].try_into().unwrap();
+        let len = u32_at(data, pos + 4) as usize;
+        let start = pos + 8;
+        if len > footer - start {
+            return Err(SaveStateError::Corrupted("truncated block".into()));
+        }
+        if &tag == b"END " {
+            return Ok(blocks);
+        }
+        blocks.push((tag, &data[start..start + len]));
+        pos = start + len;
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        BiosKind, Emulator,
+        cartridge::Cartridge,
+        test_util::{NullPeripherals, rom_with_code},
+    };
+    use super::*;
+
+    /// An MBC1 cartridge with RAM. 0x0150: ld hl, $C000; inc [hl]; jr -3
+    fn emulator(title: &[u8]) -> Emulator {
+        let mut rom = rom_with_code(&[0x21, 0x00, 0xC0, 0x34, 0x18, 0xFD]);
+        rom.resize(0x10000, 0);
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        rom[0x147..0x14A].copy_from_slice(&[0x03, 0x01, 0x02]);
+        let cartridge = Cartridge::from_bytes(&rom).unwrap();
+        Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg)
+    }
+
+    fn run_frames(emulator: &mut Emulator, frames: usize) {
+        for _ in 0..frames {
+            emulator.execute_frame(&mut NullPeripherals, |_| false).ok().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut emulator = emulator(b"COUNTER");
+        run_frames(&mut emulator, 5);
+        let machine = emulator.machine_mut();
+        machine.store_byte(Word::new(0x0000), Byte::new(0x0A));
+        machine.store_byte(Word::new(0x2000), Byte::new(0x03));
+        machine.store_byte(Word::new(0xA123), Byte::new(0x42));
+        let bess = machine.export_bess();
+        assert_eq!(&bess[bess.len() - 4..], b"BESS");
+
+        let counter = machine.load_byte(Word::new(0xC000));
+        let cpu = machine.cpu;
+        let timer_and_ppu = (0xFF04..0xFF08).chain(0xFF40..0xFF4C)
+            .map(|addr| machine.load_byte(Word::new(addr)))
+            .collect::<Vec<_>>();
+        run_frames(&mut emulator, 5);
+        let machine = emulator.machine_mut();
+        machine.store_byte(Word::new(0x2000), Byte::new(0x01));
+        machine.store_byte(Word::new(0xA123), Byte::new(0x00));
+        assert_ne!(machine.load_byte(Word::new(0xC000)), counter);
+
+        machine.import_bess(&bess).unwrap();
+        assert_eq!(machine.load_byte(Word::new(0xC000)), counter);
+        assert_eq!(machine.load_byte(Word::new(0xA123)), Byte::new(0x42));
+        assert_eq!(machine.cartridge.mbc.rom_bank(), 3);
+        assert_eq!(machine.cpu.pc, cpu.pc);
+        assert_eq!(machine.cpu.af(), cpu.af());
+        let restored = (0xFF04..0xFF08).chain(0xFF40..0xFF4C)
+            .map(|addr| machine.load_byte(Word::new(addr)))
+            .collect::<Vec<_>>();
+        assert_eq!(restored, timer_and_ppu);
+
+        // The machine keeps running after importing.
+        run_frames(&mut emulator, 5);
+        assert_ne!(emulator.machine().load_byte(Word::new(0xC000)), counter);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut counter = emulator(b"COUNTER");
+        let bess = counter.machine().export_bess();
+        let machine = counter.machine_mut();
+
+        let truncated = &bess[..bess.len() - 1];
+        assert_eq!(machine.import_bess(truncated), Err(SaveStateError::NotASaveState));
+        assert_eq!(machine.import_bess(&[0; 64]), Err(SaveStateError::NotASaveState));
+        assert!(matches!(
+            machine.import_bess(&bess[0x2000..]),
+            Err(SaveStateError::Corrupted(_)),
+        ));
+
+        let other = emulator(b"OTHER").machine().export_bess();
+        assert_eq!(
+            counter.machine_mut().import_bess(&other),
+            Err(SaveStateError::RomMismatch("OTHER".into())),
+        );
+    }
+}
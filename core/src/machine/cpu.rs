@@ -1,8 +1,11 @@
 use crate::{
+    HardwareModel,
     primitives::{Byte, Word},
+    save_state::{SaveStateError, StateReader, StateWriter},
 };
 
 
+#[derive(Debug, Clone, Copy)]
 pub struct Cpu {
     /// Accumulator
     pub a: Byte,
@@ -47,6 +50,50 @@ impl Cpu {
         }
     }
 
+    /// Returns the registers right after the boot ROM of the given model
+    /// finished (i.e. at PC `0x0100`). Some values depend on the cartridge
+    /// header, which is read from `rom`.
+    pub(crate) fn after_boot(model: HardwareModel, rom: &[Byte]) -> Self {
+        let header_checksum = rom[0x014D].get();
+        let dmg_flags = if header_checksum == 0 { 0x80 } else { 0xB0 };
+
+        let [a, f, b, c, d, e, h, l] = match model {
+            HardwareModel::Dmg0 => [0x01, 0x00, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03],
+            HardwareModel::Dmg => [0x01, dmg_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            HardwareModel::Mgb => [0xFF, dmg_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            HardwareModel::Sgb => [0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+            HardwareModel::CgbDmgMode => {
+                // The CGB boot ROM checks the title of games by Nintendo to
+                // select a color palette, leaving the checksum in B.
+                let old_licensee = rom[0x014B].get();
+                let new_licensee = [rom[0x0144].get(), rom[0x0145].get()];
+                let nintendo = old_licensee == 0x01
+                    || (old_licensee == 0x33 && new_licensee == *b"01");
+                let b = if nintendo {
+                    rom[0x0134..0x0144].iter().fold(0u8, |sum, b| sum.wrapping_add(b.get()))
+                } else {
+                    0x00
+                };
+                let [h, l] = if b == 0x43 || b == 0x58 { [0x99, 0x1A] } else { [0x00, 0x7C] };
+                [0x11, 0x80, b, 0x00, 0x00, 0x08, h, l]
+            }
+            HardwareModel::Cgb => [0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+        };
+
+        Self {
+            a: Byte::new(a),
+            f: Byte::new(f),
+            b: Byte::new(b),
+            c: Byte::new(c),
+            d: Byte::new(d),
+            e: Byte::new(e),
+            h: Byte::new(h),
+            l: Byte::new(l),
+            sp: Word::new(0xFFFE),
+            pc: Word::new(0x0100),
+        }
+    }
+
     pub fn hl(&self) -> Word {
         Word::from_bytes(self.l, self.h)
     }
@@ -152,6 +199,26 @@ impl Cpu {
 
         carry
     }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        for &reg in &[self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l] {
+            w.byte(reg);
+        }
+        w.word(self.sp);
+        w.word(self.pc);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        for reg in [
+            &mut self.a, &mut self.f, &mut self.b, &mut self.c,
+            &mut self.d, &mut self.e, &mut self.h, &mut self.l,
+        ] {
+            *reg = r.byte()?;
+        }
+        self.sp = r.word()?;
+        self.pc = r.word()?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -249,4 +316,35 @@ mod test {
         assert_eq!(run(true, true, true, 0xF6), (-0x66, true));
         assert_eq!(run(true, true, true, 0xFF), (-0x66, true));
     }
+
+    #[test]
+    fn test_boot_registers() {
+        use crate::{BiosKind, cartridge::Cartridge, machine::Machine};
+
+        let boot = |model, rom: &[u8]| {
+            let cartridge = Cartridge::from_bytes(rom).unwrap();
+            let mut machine = Machine::new(cartridge, BiosKind::Minimal, model);
+            while machine.bios_mounted() {
+                machine.step().ok().unwrap();
+            }
+            let cpu = machine.cpu;
+            assert_eq!((cpu.pc.get(), cpu.sp.get()), (0x0100, 0xFFFE));
+            [cpu.af().get(), cpu.bc().get(), cpu.de().get(), cpu.hl().get()]
+        };
+
+        let mut rom = vec![0; 0x8000];
+        assert_eq!(boot(HardwareModel::Dmg, &rom), [0x0180, 0x0013, 0x00D8, 0x014D]);
+        rom[0x014D] = 0x42;
+        assert_eq!(boot(HardwareModel::Dmg0, &rom), [0x0100, 0xFF13, 0x00C1, 0x8403]);
+        assert_eq!(boot(HardwareModel::Dmg, &rom), [0x01B0, 0x0013, 0x00D8, 0x014D]);
+        assert_eq!(boot(HardwareModel::Mgb, &rom), [0xFFB0, 0x0013, 0x00D8, 0x014D]);
+        assert_eq!(boot(HardwareModel::Sgb, &rom), [0x0100, 0x0014, 0x0000, 0xC060]);
+        assert_eq!(boot(HardwareModel::Cgb, &rom), [0x1180, 0x0000, 0xFF56, 0x000D]);
+        assert_eq!(boot(HardwareModel::CgbDmgMode, &rom), [0x1180, 0x0000, 0x0008, 0x007C]);
+
+        // Games by Nintendo get the checksum of their title in B.
+        rom[0x014B] = 0x01;
+        rom[0x0134] = 0x43;
+        assert_eq!(boot(HardwareModel::CgbDmgMode, &rom), [0x1180, 0x4300, 0x0008, 0x991A]);
+    }
 }
@@ -0,0 +1,144 @@
+//! A cache of decoded instructions in the cartridge ROM.
+//!
+//! Fetching an instruction reads three bytes through the memory map and the
+//! MBC. For code in ROM, these bytes only depend on the ROM bank and the
+//! address, so they are stored per ROM byte and can be looked up with one
+//! index operation in hot loops. Switching banks does not require any
+//! invalidation, as the bank is part of the key. Code outside of ROM (e.g.
+//! routines copied to HRAM) is never cached, so normal writes cannot make
+//! an entry stale; only writes to ROM by debuggers invalidate entries.
+//!
+//! The cache is disabled by default, since it needs four bytes per ROM byte.
+
+use super::Machine;
+use crate::primitives::{Byte, Word};
+
+
+/// The opcode (which selects the handler of the instruction) and the two
+/// bytes following it. Depending on the length of the instruction, these
+/// are operands or unused.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Decoded {
+    pub(crate) op_code: Byte,
+    pub(crate) arg_byte: Byte,
+    pub(crate) last_byte: Byte,
+}
+
+/// The cache stored inside of `Machine`.
+#[derive(Clone)]
+pub(crate) struct DecodeCache {
+    /// One entry per ROM byte. Empty if the cache is disabled.
+    entries: Vec<Option<Decoded>>,
+}
+
+impl DecodeCache {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn is_enabled(&self) -> bool {
+        !self.entries.is_empty()
+    }
+
+    /// Returns the index into the ROM of the instruction at `addr` while
+    /// `bank0` is mapped to `0x0000..0x4000` and `bank` to `0x4000..0x8000`.
+    /// Returns `None` for addresses
+    /// outside of ROM and for the last two bytes of both halves, as the
+    /// operands of those come from a different bank.
+    fn index(addr: Word, bank0: usize, bank: usize) -> Option<usize> {
+        match addr.get() {
+            0x0000..=0x3FFD => Some(bank0 * 0x4000 + addr.get() as usize),
+            0x4000..=0x7FFD => Some(bank * 0x4000 + (addr.get() - 0x4000) as usize),
+            _ => None,
+        }
+    }
+
+    /// Removes all entries that contain the ROM byte at `idx`.
+    pub(crate) fn invalidate(&mut self, idx: usize) {
+        let end = (idx + 1).min(self.entries.len());
+        for entry in &mut self.entries[idx.saturating_sub(2).min(end)..end] {
+            *entry = None;
+        }
+    }
+}
+
+impl Machine {
+    /// Enables or disables the decoded instruction cache (see the module
+    /// documentation). Disabled by default.
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.decode_cache.entries = if enabled {
+            vec![None; self.cartridge.rom().len()]
+        } else {
+            Vec::new()
+        };
+    }
+
+    /// Reads the instruction at `addr`, from the decode cache if possible.
+    pub(crate) fn fetch(&mut self, addr: Word) -> Decoded {
+        // While the BIOS is mounted, its instructions shadow the ROM and
+        // during DMA, reads from ROM can return the byte the DMA copies.
+        let cacheable = self.decode_cache.is_enabled()
+            && self.ppu.oam_dma_status.is_none()
+            && !(addr.get() < 0x100 && self.bios_mounted());
+        let idx = if cacheable {
+            let mbc = &self.cartridge.mbc;
+            DecodeCache::index(addr, mbc.rom_bank0(), mbc.rom_bank())
+        } else {
+            None
+        };
+
+        if let Some(&Some(decoded)) = idx.and_then(|idx| self.decode_cache.entries.get(idx)) {
+            return decoded;
+        }
+
+        let decoded = Decoded {
+            op_code: self.load_byte_silent(addr),
+            arg_byte: self.load_byte_silent(addr + 1u16),
+            last_byte: self.load_byte_silent(addr + 2u16),
+        };
+        if let Some(entry) = idx.and_then(|idx| self.decode_cache.entries.get_mut(idx)) {
+            *entry = Some(decoded);
+        }
+
+        decoded
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{BiosKind, HardwareModel, test_util::cartridge_with_code};
+    use super::*;
+
+    #[test]
+    fn test_cached_execution() {
+        // 0x0150: ld hl, $C000; inc [hl]; jr -3
+        let cartridge = cartridge_with_code(&[0x21, 0x00, 0xC0, 0x34, 0x18, 0xFD]);
+        let mut uncached = Machine::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        let mut cached = uncached.clone();
+        cached.set_decode_cache(true);
+
+        let counter = Word::new(0xC000);
+        let run = |machine: &mut Machine| {
+            for _ in 0..1000 {
+                machine.step().ok().unwrap();
+            }
+            machine.load_byte(counter)
+        };
+        assert_eq!(run(&mut cached), run(&mut uncached));
+        assert_eq!(cached.cpu.pc, uncached.cpu.pc);
+
+        // Patching the ROM replaces `inc [hl]` by `dec [hl]`, which has to
+        // invalidate the cached instruction.
+        let mut unpatched = cached.clone();
+        for machine in &mut [&mut cached, &mut uncached] {
+            machine.store_byte_raw(Word::new(0x0153), Byte::new(0x35));
+        }
+        let patched = run(&mut cached);
+        assert_eq!(patched, run(&mut uncached));
+        assert_ne!(patched, run(&mut unpatched));
+    }
+}
@@ -0,0 +1,251 @@
+//! Comparing two machine states (see `Machine::diff`), e.g. to find out where
+//! two recordings, rewind snapshots or emulator versions desynced. Save
+//! states can be compared by loading them into two machines first.
+//!
+//! Only the state visible to the game is compared: CPU registers, IO
+//! registers and memory, plus the cycle count and the position of the PPU in
+//! the frame. Internal state of the components (e.g. the timer's divider
+//! counter) shows up as soon as it affects a register.
+
+use std::{fmt, ops::Range};
+
+use super::Machine;
+use crate::primitives::{Byte, Word};
+
+
+/// Differing bytes closer together than this are reported as one range.
+const MERGE_GAP: usize = 8;
+
+/// A register (or other single value) that differs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegisterDiff {
+    pub name: &'static str,
+    pub left: String,
+    pub right: String,
+}
+
+/// An IO register (`0xFF00..0xFF80` or IE) that differs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoDiff {
+    pub addr: Word,
+    pub left: Byte,
+    pub right: Byte,
+}
+
+/// A memory region compared by `Machine::diff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryRegion {
+    Vram,
+    Wram,
+    Oam,
+    Hram,
+
+    /// All banks of the cartridge RAM.
+    CartridgeRam,
+}
+
+impl MemoryRegion {
+    /// Formats the offset into this region as address. For the cartridge
+    /// RAM, the bank is prepended.
+    fn format_offset(self, offset: usize) -> String {
+        let base = match self {
+            MemoryRegion::Vram => 0x8000,
+            MemoryRegion::Wram => 0xC000,
+            MemoryRegion::Oam => 0xFE00,
+            MemoryRegion::Hram => 0xFF80,
+            MemoryRegion::CartridgeRam => {
+                return format!("{:02x}:{:04x}", offset / 0x2000, 0xA000 + offset % 0x2000);
+            }
+        };
+
+        format!("{:04x}", base + offset)
+    }
+}
+
+impl fmt::Display for MemoryRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            MemoryRegion::Vram => "VRAM",
+            MemoryRegion::Wram => "WRAM",
+            MemoryRegion::Oam => "OAM",
+            MemoryRegion::Hram => "HRAM",
+            MemoryRegion::CartridgeRam => "cartridge RAM",
+        };
+        name.fmt(f)
+    }
+}
+
+/// A range of memory in which some bytes differ.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeDiff {
+    pub region: MemoryRegion,
+
+    /// The offsets into the region. The first and last byte of the range
+    /// differ.
+    pub range: Range<usize>,
+
+    /// The number of differing bytes in the range.
+    pub count: usize,
+}
+
+/// All differences between two machines, returned by `Machine::diff`. The
+/// `Display` output lists them in a human readable form.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Diff {
+    pub registers: Vec<RegisterDiff>,
+    pub io: Vec<IoDiff>,
+    pub memory: Vec<RangeDiff>,
+}
+
+impl Diff {
+    /// Returns `true` if no differences were found.
+    pub fn is_empty(&self) -> bool {
+        self.registers.is_empty() && self.io.is_empty() && self.memory.is_empty()
+    }
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "no differences");
+        }
+
+        for reg in &self.registers {
+            writeln!(f, "{:<16} {} != {}", reg.name, reg.left, reg.right)?;
+        }
+        for io in &self.io {
+            writeln!(f, "{:<16} {} != {}", format!("IO {}", io.addr), io.left, io.right)?;
+        }
+        for mem in &self.memory {
+            writeln!(
+                f,
+                "{} {}-{}: {} of {} bytes differ",
+                mem.region,
+                mem.region.format_offset(mem.range.start),
+                mem.region.format_offset(mem.range.end - 1),
+                mem.count,
+                mem.range.len(),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Machine {
+    /// Compares this machine with `other` (see the module documentation).
+    /// `self` is the left and `other` the right side of the returned diff.
+    pub fn diff(&self, other: &Machine) -> Diff {
+        let mut registers = Vec::new();
+        let mut reg = |name, left: String, right: String| {
+            if left != right {
+                registers.push(RegisterDiff { name, left, right });
+            }
+        };
+        let (l, r) = (&self.cpu, &other.cpu);
+        for &(name, left, right) in &[
+            ("A", l.a, r.a), ("F", l.f, r.f), ("B", l.b, r.b), ("C", l.c, r.c),
+            ("D", l.d, r.d), ("E", l.e, r.e), ("H", l.h, r.h), ("L", l.l, r.l),
+        ] {
+            reg(name, left.to_string(), right.to_string());
+        }
+        reg("SP", l.sp.to_string(), r.sp.to_string());
+        reg("PC", l.pc.to_string(), r.pc.to_string());
+        reg(
+            "IME",
+            self.interrupt_controller.ime.to_string(),
+            other.interrupt_controller.ime.to_string(),
+        );
+        reg("CPU state", format!("{:?}", self.state), format!("{:?}", other.state));
+        reg("cycle count", self.cycle_count.to_string(), other.cycle_count.to_string());
+        reg(
+            "PPU cycle",
+            self.ppu.cycle_in_frame().to_string(),
+            other.ppu.cycle_in_frame().to_string(),
+        );
+
+        let io = (0xFF00..0xFF80).chain(Some(0xFFFF))
+            .map(Word::new)
+            .filter_map(|addr| {
+                let left = self.load_byte_bypass_dma(addr);
+                let right = other.load_byte_bypass_dma(addr);
+                if left != right {
+                    Some(IoDiff { addr, left, right })
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        let mut memory = Vec::new();
+        for &(region, left, right) in &[
+            (MemoryRegion::Vram, self.ppu.vram.as_slice(), other.ppu.vram.as_slice()),
+            (MemoryRegion::Wram, self.wram.as_slice(), other.wram.as_slice()),
+            (MemoryRegion::Oam, self.ppu.oam.as_slice(), other.ppu.oam.as_slice()),
+            (MemoryRegion::Hram, self.hram.as_slice(), other.hram.as_slice()),
+            (MemoryRegion::CartridgeRam, self.cartridge.mbc.ram(), other.cartridge.mbc.ram()),
+        ] {
+            diff_memory(region, left, right, &mut memory);
+        }
+
+        Diff { registers, io, memory }
+    }
+}
+
+/// Adds the ranges in which `left` and `right` differ to `out`. If the
+/// lengths differ (different cartridges), the additional bytes count as
+/// different.
+fn diff_memory(region: MemoryRegion, left: &[Byte], right: &[Byte], out: &mut Vec<RangeDiff>) {
+    let len = left.len().max(right.len());
+    let differs = |i: usize| left.get(i) != right.get(i);
+
+    let mut current: Option<RangeDiff> = None;
+    for i in (0..len).filter(|&i| differs(i)) {
+        match &mut current {
+            Some(diff) if i - diff.range.end < MERGE_GAP => {
+                diff.range.end = i + 1;
+                diff.count += 1;
+            }
+            _ => {
+                out.extend(current.take());
+                current = Some(RangeDiff { region, range: i..i + 1, count: 1 });
+            }
+        }
+    }
+    out.extend(current);
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{BiosKind, HardwareModel, cartridge::Cartridge};
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let cartridge = Cartridge::from_bytes(&[0; 0x8000]).unwrap();
+        let left = Machine::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        let mut right = left.clone();
+        assert!(left.diff(&right).is_empty());
+
+        right.cpu.a = Byte::new(0x42);
+        right.store_byte(Word::new(0xFF42), Byte::new(3));
+        for &addr in &[0xC010, 0xC012, 0xC017, 0xC100] {
+            right.store_byte(Word::new(addr), Byte::new(1));
+        }
+
+        let diff = left.diff(&right);
+        assert_eq!(diff.registers.len(), 1);
+        assert_eq!(diff.registers[0].name, "A");
+        assert_eq!(diff.io, [IoDiff {
+            addr: Word::new(0xFF42),
+            left: Byte::zero(),
+            right: Byte::new(3),
+        }]);
+        assert_eq!(diff.memory, [
+            RangeDiff { region: MemoryRegion::Wram, range: 0x10..0x18, count: 3 },
+            RangeDiff { region: MemoryRegion::Wram, range: 0x100..0x101, count: 1 },
+        ]);
+        assert!(diff.to_string().contains("WRAM c010-c017: 3 of 8 bytes differ"));
+    }
+}
@@ -1,12 +1,32 @@
 //! Contains code to actually execute instructions.
 
-use super::Machine;
+use super::{Machine, mm::Region};
 use crate::{
     primitives::{Byte, Word},
     log::*,
 };
 
 
+/// The DMG has two memory buses the DMA can read from. While the DMA uses
+/// one of them, the CPU cannot access anything on that bus.
+#[derive(Debug, PartialEq, Eq)]
+enum Bus {
+    /// VRAM.
+    Video,
+
+    /// Cartridge ROM and RAM and WRAM.
+    External,
+}
+
+impl Bus {
+    fn of(addr: Word) -> Self {
+        match Region::of(addr).0 {
+            Region::Vram => Bus::Video,
+            _ => Bus::External,
+        }
+    }
+}
+
 impl Machine {
     /// Executes one DMA step if any DMA operations are currently ongoing.
     pub(crate) fn dma_step(&mut self) {
@@ -18,6 +38,7 @@ impl Machine {
                 let dst_addr = Word::new(0xFE00) + lsb;
                 let b = self.load_byte_bypass_dma(src_addr);
                 self.ppu.store_oam_byte(dst_addr, b);
+                self.ppu.oam_dma_byte = b;
             }
 
             // Advance the source address. If we reached 0xXXF1, we copied the
@@ -30,4 +51,74 @@ impl Machine {
             }
         }
     }
+
+    /// Returns the byte the CPU reads from `addr` instead of the real value,
+    /// if the access conflicts with an ongoing OAM DMA. Writes are lost in
+    /// that case.
+    ///
+    /// OAM is not accessible at all during the DMA. Reads from the bus the
+    /// DMA reads from return the byte the DMA copied last. The other bus, IO
+    /// registers and HRAM can be accessed normally. The first cycle (in which
+    /// the DMA only prepares) does not block anything. With `FAST`, there
+    /// are no conflicts.
+    pub(crate) fn oam_dma_conflict(&self, addr: Word) -> Option<Byte> {
+        if crate::FAST {
+            return None;
+        }
+
+        let src_addr = match self.ppu.oam_dma_status {
+            Some(src_addr) if src_addr.into_bytes().0 != Byte::new(0xFF) => src_addr,
+            _ => return None,
+        };
+
+        match Region::of(addr).0 {
+            Region::Oam | Region::Unusable => Some(Byte::new(0xFF)),
+            Region::Io | Region::Hram | Region::Ie => None,
+            _ if Bus::of(addr) == Bus::of(src_addr) => Some(self.ppu.oam_dma_byte),
+            _ => None,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{BiosKind, HardwareModel, cartridge::Cartridge};
+    use super::*;
+
+    #[test]
+    #[cfg_attr(all(feature = "fast", not(feature = "accuracy")), ignore)]
+    fn test_bus_conflicts() {
+        let cartridge = Cartridge::from_bytes(&[0; 0x8000]).unwrap();
+        let mut machine = Machine::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        for i in 0..0xA0 {
+            machine.store_byte(Word::new(0xC000 + i), Byte::new(i as u8 + 1));
+        }
+        machine.store_byte(Word::new(0x8000), Byte::new(0x42));
+        machine.store_byte(Word::new(0xFF80), Byte::new(0x13));
+
+        // Nothing is blocked in the setup cycle.
+        machine.store_byte(Word::new(0xFF46), Byte::new(0xC0));
+        assert_eq!(machine.load_byte(Word::new(0xC050)), 0x51);
+        machine.dma_step();
+        machine.dma_step();
+        machine.dma_step();
+
+        // Two bytes were copied.
+        assert_eq!(machine.load_byte(Word::new(0xC050)), 0x02);
+        assert_eq!(machine.load_byte(Word::new(0x0100)), 0x02);
+        assert_eq!(machine.load_byte(Word::new(0xFE00)), 0xFF);
+        assert_eq!(machine.load_byte(Word::new(0x8000)), 0x42);
+        assert_eq!(machine.load_byte(Word::new(0xFF80)), 0x13);
+        assert_eq!(machine.load_byte(Word::new(0xFF46)), 0xC0);
+
+        // Writes to the blocked bus are lost.
+        machine.store_byte(Word::new(0xC000), Byte::new(0xAA));
+        for _ in 0..0xA0 {
+            machine.dma_step();
+        }
+        assert_eq!(machine.ppu.oam_dma_status, None);
+        assert_eq!(machine.load_byte(Word::new(0xC000)), 0x01);
+        assert_eq!(machine.load_byte(Word::new(0xFE9F)), 0xA0);
+    }
 }
@@ -0,0 +1,971 @@
+//! The handlers of all instructions.
+//!
+//! Every opcode has its own handler function. They are stored in two tables
+//! indexed by the opcode, like `INSTRUCTIONS` and `PREFIXED_INSTRUCTIONS`.
+//! Fetching the instruction, advancing PC and counting the cycles is done by
+//! `Machine::step`, so single instructions can be tested by calling their
+//! handler directly.
+
+use super::{Machine, State};
+use crate::{
+    instr::InstrDb,
+    log::*,
+    primitives::{Byte, Word},
+};
+
+
+/// The fetched instruction, as passed to its handler.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Operands {
+    /// The opcode. For prefixed instructions, this is the byte after `0xCB`.
+    pub(crate) op_code: Byte,
+
+    /// The byte following the opcode (`d8`, `a8` or `r8`).
+    pub(crate) byte: Byte,
+
+    /// The two bytes following the opcode (`d16` or `a16`).
+    pub(crate) word: Word,
+}
+
+/// Executes one instruction. When it is called, PC already points to the
+/// next instruction. Returns whether the branch was taken for conditional
+/// instructions (those with `clocks_taken`) and `None` for all others.
+pub(crate) type Handler = fn(&mut Machine, Operands) -> Option<bool>;
+
+/// The result of a handler body: conditional instructions evaluate to
+/// whether the branch was taken, all others to `()`.
+trait Branch {
+    fn taken(self) -> Option<bool>;
+}
+
+impl Branch for () {
+    fn taken(self) -> Option<bool> {
+        None
+    }
+}
+
+impl Branch for bool {
+    fn taken(self) -> Option<bool> {
+        Some(self)
+    }
+}
+
+/// Builds a handler table from `"MNEMONIC" => |m, args| body,` entries. The
+/// given macro (`opcode` or `prefixed_opcode`) maps the mnemonics to
+/// opcodes. All opcodes without an entry get the `invalid` handler.
+macro_rules! handlers {
+    ($opcode:ident; $( $name:tt => |$m:pat_param, $args:pat_param| $body:expr, )*) => {{
+        let mut table = [invalid as Handler; 256];
+        $(
+            table[$opcode!($name)] = {
+                fn handler($m: &mut Machine, $args: Operands) -> Option<bool> {
+                    Branch::taken($body)
+                }
+                handler
+            };
+        )*
+        table
+    }};
+}
+
+/// This is a template macro for all DEC instructions. Which can be used by passing
+/// the register in which should be decremented.
+macro_rules! dec {
+    ($m:ident, $x:expr) => {{
+        let (_, half_carry) = $x.sub_with_carries(Byte::new(1));
+        let zero = $x == 0;
+        set_flags!($m.cpu.f => zero 1 half_carry -);
+    }}
+}
+
+/// This is a template macro for all INC instructions. Which can be used by passing
+/// the register in which should be incremented.
+macro_rules! inc {
+    ($m:ident, $x:expr) => {{
+        let (_, half_carry) = $x.add_with_carries(Byte::new(1));
+        let zero = $x == 0;
+        set_flags!($m.cpu.f => zero 0 half_carry -);
+    }}
+}
+
+/// This is a template macro for all SUB instructions. Input should be a [`Byte`].
+macro_rules! sub {
+    ($m:ident, $x:expr) => {{
+        let (carry, half_carry) = $m.cpu.a.sub_with_carries($x);
+        let zero = $m.cpu.a == Byte::zero();
+        set_flags!($m.cpu.f => zero 1 half_carry carry);
+    }}
+}
+
+/// This is a template macro for all SBC instructions. Input should be a [`Byte`].
+macro_rules! sbc {
+    ($m:ident, $x:expr) => {{
+        // let val = $x - ($m.cpu.carry() as u8);
+        // sub!(val);
+        let (carry, half_carry) = $m.cpu.a.full_sub_with_carries($x, $m.cpu.carry());
+        let zero = $m.cpu.a == Byte::zero();
+        set_flags!($m.cpu.f => zero 1 half_carry carry);
+    }}
+}
+
+/// This is a template macro for all ADD A, b instructions (where `b` should be a [`Byte`]).
+macro_rules! add {
+    ($m:ident, $x:expr) => {{
+        let (carry, half_carry) = $m.cpu.a.add_with_carries($x);
+        let zero = $m.cpu.a == Byte::zero();
+        set_flags!($m.cpu.f => zero 0 half_carry carry);
+    }}
+}
+
+/// This is a template macro for all ADD HL, w instructions (where `w` should
+/// be a [`Word`]).
+macro_rules! add_hl {
+    ($m:ident, $x:expr) => {{
+        let mut val = $m.cpu.hl();
+        let (carry, half_carry) = val.add_with_carries($x);
+        set_flags!($m.cpu.f => - 0 half_carry carry);
+        $m.cpu.set_hl(val);
+    }}
+}
+
+/// This is a template macro for all ADC A, b instructions (where `b` should be a [`Byte`]).
+macro_rules! adc {
+    ($m:ident, $x:expr) => {{
+        let (carry, half_carry) = $m.cpu.a.full_add_with_carries($x, $m.cpu.carry());
+        let zero = $m.cpu.a == Byte::zero();
+        set_flags!($m.cpu.f => zero 0 half_carry carry);
+    }}
+}
+
+/// This is a template macro for all AND b instructions (where `b` should be a [`Byte`]).
+macro_rules! and {
+    ($m:ident, $x:expr) => {{
+        $m.cpu.a &= $x;
+        let zero = $m.cpu.a == Byte::zero();
+        set_flags!($m.cpu.f => zero 0 1 0);
+    }}
+}
+
+/// This is a template macro for all XOR b instructions (where `b` should be a [`Byte`]).
+macro_rules! xor {
+    ($m:ident, $x:expr) => {{
+        $m.cpu.a ^= $x;
+        let zero = $m.cpu.a == Byte::zero();
+        set_flags!($m.cpu.f => zero 0 0 0);
+    }}
+}
+
+/// This is a template macro for all OR b instructions (where `b` should be a [`Byte`]).
+macro_rules! or {
+    ($m:ident, $x:expr) => {{
+        $m.cpu.a |= $x;
+        let zero = $m.cpu.a == Byte::zero();
+        set_flags!($m.cpu.f => zero 0 0 0);
+    }}
+}
+
+/// This is a template macro for all CP b instructions (where `b` should be a [`Byte`]).
+macro_rules! cp {
+    ($m:ident, $x:expr) => {{
+        // Subtract the value in $x from A and set flags accordingly, but don't store
+        // the result.
+        let mut copy = $m.cpu.a;
+        let (carry, half_carry) = copy.sub_with_carries($x);
+        let zero = copy == Byte::zero();
+        set_flags!($m.cpu.f => zero 1 half_carry carry);
+    }}
+}
+
+/// This is a template macro for all LD r, s instructions (where `r` and `s` can be one of:
+/// B, C, A, E, L, D, H). Which can be used by passing the registers in
+/// (e.g.: `ld!(m.cpu.a, m.cpu.b);`). Loading a register into itself (e.g. `LD B, B`) only
+/// takes time, so these instructions have an empty handler instead.
+macro_rules! ld {
+    ($lhs:expr, $rhs:expr) => {
+        $lhs = $rhs
+    }
+}
+
+/// This is a template macro for all RLC b instructions (where `b` should be a [`Byte`]).
+macro_rules! rlc {
+    ($m:ident, $x:expr) => {{
+        let carry = $x.rotate_left();
+        let zero = $x == Byte::zero();
+        set_flags!($m.cpu.f => zero 0 0 carry);
+    }}
+}
+
+/// This is a template macro for all RRC b instructions (where `b` should be a [`Byte`]).
+macro_rules! rrc {
+    ($m:ident, $x:expr) => {{
+        let carry = $x.rotate_right();
+        let zero = $x == Byte::zero();
+        set_flags!($m.cpu.f => zero 0 0 carry);
+    }}
+}
+
+/// This is a template macro for all RL b instructions (where `b` should be a [`Byte`]).
+macro_rules! rl {
+    ($m:ident, $x:expr) => {{
+        let carry = $x.rotate_left_through_carry($m.cpu.carry());
+        let zero = $x == Byte::zero();
+        set_flags!($m.cpu.f => zero 0 0 carry);
+    }}
+}
+
+/// This is a template macro for all RR b instructions (where `b` should be a [`Byte`]).
+macro_rules! rr {
+    ($m:ident, $x:expr) => {{
+        let carry = $x.rotate_right_through_carry($m.cpu.carry());
+        let zero = $x == Byte::zero();
+        set_flags!($m.cpu.f => zero 0 0 carry);
+    }}
+}
+
+/// This is a template macro for all SLA b instructions (where `b` should be a [`Byte`]).
+macro_rules! sla {
+    ($m:ident, $x:expr) => {{
+        let carry = $x.shift_left();
+        let zero = $x == Byte::zero();
+        set_flags!($m.cpu.f => zero 0 0 carry);
+    }}
+}
+
+/// This is a template macro for all SRL b instructions (where `b` should be a [`Byte`]).
+macro_rules! srl {
+    ($m:ident, $x:expr) => {{
+        let carry = $x.shift_right();
+        let zero = $x == Byte::zero();
+        set_flags!($m.cpu.f => zero 0 0 carry);
+    }}
+}
+
+/// This is a template macro for all SRA b instructions (where `b` should be a [`Byte`]).
+macro_rules! sra {
+    ($m:ident, $x:expr) => {{
+        let carry = $x.arithmetic_shift_right();
+        let zero = $x == Byte::zero();
+        set_flags!($m.cpu.f => zero 0 0 carry);
+    }}
+}
+
+/// This is a template macro for all SWAP b instructions (where `b` should be a [`Byte`]).
+macro_rules! swap {
+    ($m:ident, $x:expr) => {{
+        $x = $x.swap_nybbles();
+        let zero = $x == Byte::zero();
+        set_flags!($m.cpu.f => zero 0 0 0);
+    }}
+}
+
+/// This is a convenience macro for all RET-like instructions to reduce duplicate code.
+macro_rules! ret {
+    ($m:ident) => {{
+        $m.cpu.pc = $m.pop();
+    }}
+}
+
+/// This is a convenience macro for all CALL-like instructions to reduce duplicate code.
+macro_rules! call {
+    ($m:ident, $x:expr) => {{
+        $m.push($m.cpu.pc);
+        $m.cpu.pc = $x;
+    }}
+}
+
+
+/// Handlers of all main instructions. `PREFIX CB` is handled by
+/// `Machine::step`, as it selects the handler of the next byte in
+/// `PREFIXED_HANDLERS`.
+pub(crate) static HANDLERS: InstrDb<Handler> = InstrDb(handlers! { opcode;
+    // ========== LD ==========
+    "LD B, d8" => |m, args| ld!(m.cpu.b, args.byte),
+    "LD C, d8" => |m, args| ld!(m.cpu.c, args.byte),
+    "LD A, d8" => |m, args| ld!(m.cpu.a, args.byte),
+    "LD E, d8" => |m, args| ld!(m.cpu.e, args.byte),
+    "LD L, d8" => |m, args| ld!(m.cpu.l, args.byte),
+    "LD D, d8" => |m, args| ld!(m.cpu.d, args.byte),
+    "LD H, d8" => |m, args| ld!(m.cpu.h, args.byte),
+
+    "LD B, B"      => |_, _| {},
+    "LD B, C"      => |m, _| ld!(m.cpu.b, m.cpu.c),
+    "LD B, D"      => |m, _| ld!(m.cpu.b, m.cpu.d),
+    "LD B, E"      => |m, _| ld!(m.cpu.b, m.cpu.e),
+    "LD B, H"      => |m, _| ld!(m.cpu.b, m.cpu.h),
+    "LD B, L"      => |m, _| ld!(m.cpu.b, m.cpu.l),
+    "LD B, (HL)"   => |m, _| ld!(m.cpu.b, m.load_hl()),
+    "LD B, A"      => |m, _| ld!(m.cpu.b, m.cpu.a),
+
+    "LD C, B"      => |m, _| ld!(m.cpu.c, m.cpu.b),
+    "LD C, C"      => |_, _| {},
+    "LD C, D"      => |m, _| ld!(m.cpu.c, m.cpu.d),
+    "LD C, E"      => |m, _| ld!(m.cpu.c, m.cpu.e),
+    "LD C, H"      => |m, _| ld!(m.cpu.c, m.cpu.h),
+    "LD C, L"      => |m, _| ld!(m.cpu.c, m.cpu.l),
+    "LD C, (HL)"   => |m, _| ld!(m.cpu.c, m.load_hl()),
+    "LD C, A"      => |m, _| ld!(m.cpu.c, m.cpu.a),
+
+    "LD D, B"      => |m, _| ld!(m.cpu.d, m.cpu.b),
+    "LD D, C"      => |m, _| ld!(m.cpu.d, m.cpu.c),
+    "LD D, D"      => |_, _| {},
+    "LD D, E"      => |m, _| ld!(m.cpu.d, m.cpu.e),
+    "LD D, H"      => |m, _| ld!(m.cpu.d, m.cpu.h),
+    "LD D, L"      => |m, _| ld!(m.cpu.d, m.cpu.l),
+    "LD D, (HL)"   => |m, _| ld!(m.cpu.d, m.load_hl()),
+    "LD D, A"      => |m, _| ld!(m.cpu.d, m.cpu.a),
+
+    "LD E, B"      => |m, _| ld!(m.cpu.e, m.cpu.b),
+    "LD E, C"      => |m, _| ld!(m.cpu.e, m.cpu.c),
+    "LD E, D"      => |m, _| ld!(m.cpu.e, m.cpu.d),
+    "LD E, E"      => |_, _| {},
+    "LD E, H"      => |m, _| ld!(m.cpu.e, m.cpu.h),
+    "LD E, L"      => |m, _| ld!(m.cpu.e, m.cpu.l),
+    "LD E, (HL)"   => |m, _| ld!(m.cpu.e, m.load_hl()),
+    "LD E, A"      => |m, _| ld!(m.cpu.e, m.cpu.a),
+
+    "LD H, B"      => |m, _| ld!(m.cpu.h, m.cpu.b),
+    "LD H, C"      => |m, _| ld!(m.cpu.h, m.cpu.c),
+    "LD H, D"      => |m, _| ld!(m.cpu.h, m.cpu.d),
+    "LD H, E"      => |m, _| ld!(m.cpu.h, m.cpu.e),
+    "LD H, H"      => |_, _| {},
+    "LD H, L"      => |m, _| ld!(m.cpu.h, m.cpu.l),
+    "LD H, (HL)"   => |m, _| ld!(m.cpu.h, m.load_hl()),
+    "LD H, A"      => |m, _| ld!(m.cpu.h, m.cpu.a),
+
+    "LD L, B"      => |m, _| ld!(m.cpu.l, m.cpu.b),
+    "LD L, C"      => |m, _| ld!(m.cpu.l, m.cpu.c),
+    "LD L, D"      => |m, _| ld!(m.cpu.l, m.cpu.d),
+    "LD L, E"      => |m, _| ld!(m.cpu.l, m.cpu.e),
+    "LD L, H"      => |m, _| ld!(m.cpu.l, m.cpu.h),
+    "LD L, L"      => |_, _| {},
+    "LD L, (HL)"   => |m, _| ld!(m.cpu.l, m.load_hl()),
+    "LD L, A"      => |m, _| ld!(m.cpu.l, m.cpu.a),
+
+    "LD A, B"      => |m, _| ld!(m.cpu.a, m.cpu.b),
+    "LD A, C"      => |m, _| ld!(m.cpu.a, m.cpu.c),
+    "LD A, D"      => |m, _| ld!(m.cpu.a, m.cpu.d),
+    "LD A, E"      => |m, _| ld!(m.cpu.a, m.cpu.e),
+    "LD A, H"      => |m, _| ld!(m.cpu.a, m.cpu.h),
+    "LD A, L"      => |m, _| ld!(m.cpu.a, m.cpu.l),
+    "LD A, (HL)"   => |m, _| ld!(m.cpu.a, m.load_hl()),
+    "LD A, A"      => |_, _| {},
+
+    "LD (HL), B" => |m, _| m.store_hl(m.cpu.b),
+    "LD (HL), C" => |m, _| m.store_hl(m.cpu.c),
+    "LD (HL), D" => |m, _| m.store_hl(m.cpu.d),
+    "LD (HL), E" => |m, _| m.store_hl(m.cpu.e),
+    "LD (HL), H" => |m, _| m.store_hl(m.cpu.h),
+    "LD (HL), L" => |m, _| m.store_hl(m.cpu.l),
+    "LD (HL), A" => |m, _| m.store_hl(m.cpu.a),
+    "LD (HL), d8" => |m, args| m.store_hl(args.byte),
+
+    "LD BC, d16" => |m, args| m.cpu.set_bc(args.word),
+    "LD DE, d16" => |m, args| m.cpu.set_de(args.word),
+    "LD HL, d16" => |m, args| m.cpu.set_hl(args.word),
+    "LD SP, d16" => |m, args| m.cpu.sp = args.word,
+    "LD SP, HL" => |m, _| m.cpu.sp = m.cpu.hl(),
+    "LD HL, SP+r8" => |m, args| {
+        let mut src = m.cpu.sp;
+        let (carry, half_carry) = src.add_i8_with_carries(args.byte.get() as i8);
+        set_flags!(m.cpu.f => 0 0 half_carry carry);
+        m.cpu.set_hl(src);
+    },
+    "LD (a16), SP" => |m, args| m.store_word(args.word, m.cpu.sp),
+
+    "LD (C), A" => |m, _| {
+        let dst = Word::new(0xFF00) + m.cpu.c;
+        m.store_byte(dst, m.cpu.a);
+    },
+    "LD A, (C)" => |m, _| {
+        m.cpu.a = m.load_byte(Word::new(0xFF00) + m.cpu.c);
+    },
+    "LDH (a8), A" => |m, args| {
+        let dst = Word::new(0xFF00) + args.byte;
+        m.store_byte(dst, m.cpu.a);
+    },
+    "LDH A, (a8)" => |m, args| {
+        let src = Word::new(0xFF00) + args.byte;
+        m.cpu.a = m.load_byte(src);
+    },
+    "LD (HL+), A" => |m, _| {
+        let dst = m.cpu.hl();
+        m.store_byte(dst, m.cpu.a);
+        m.cpu.set_hl(dst + 1u16);
+    },
+    "LD (HL-), A" => |m, _| {
+        let dst = m.cpu.hl();
+        m.store_byte(dst, m.cpu.a);
+        m.cpu.set_hl(dst - 1);
+    },
+    "LD A, (HL+)" => |m, _| {
+        let dst = m.cpu.hl();
+        m.cpu.a = m.load_byte(dst);
+        m.cpu.set_hl(dst + 1u16);
+    },
+    "LD A, (HL-)" => |m, _| {
+        let dst = m.cpu.hl();
+        m.cpu.a = m.load_byte(dst);
+        m.cpu.set_hl(dst - 1u16);
+    },
+    "LD A, (DE)" => |m, _| m.cpu.a = m.load_byte(m.cpu.de()),
+    "LD A, (BC)" => |m, _| m.cpu.a = m.load_byte(m.cpu.bc()),
+    "LD A, (a16)" => |m, args| m.cpu.a = m.load_byte(args.word),
+    "LD (DE), A" => |m, _| m.store_byte(m.cpu.de(), m.cpu.a),
+    "LD (BC), A" => |m, _| m.store_byte(m.cpu.bc(), m.cpu.a),
+    "LD (a16), A" => |m, args| m.store_byte(args.word, m.cpu.a),
+
+    // ========== DEC ==========
+    "DEC B" => |m, _| dec!(m, m.cpu.b),
+    "DEC D" => |m, _| dec!(m, m.cpu.d),
+    "DEC H" => |m, _| dec!(m, m.cpu.h),
+    "DEC C" => |m, _| dec!(m, m.cpu.c),
+    "DEC E" => |m, _| dec!(m, m.cpu.e),
+    "DEC L" => |m, _| dec!(m, m.cpu.l),
+    "DEC A" => |m, _| dec!(m, m.cpu.a),
+
+    "DEC BC" => |m, _| m.cpu.set_bc(m.cpu.bc() - 1u16),
+    "DEC DE" => |m, _| m.cpu.set_de(m.cpu.de() - 1u16),
+    "DEC HL" => |m, _| m.cpu.set_hl(m.cpu.hl() - 1u16),
+    "DEC SP" => |m, _| m.cpu.sp -= 1u16,
+    "DEC (HL)" => |m, _| {
+        let mut val = m.load_hl();
+        dec!(m, val);
+        m.store_hl(val);
+    },
+
+    // ========== INC ==========
+    "INC B" => |m, _| inc!(m, m.cpu.b),
+    "INC D" => |m, _| inc!(m, m.cpu.d),
+    "INC H" => |m, _| inc!(m, m.cpu.h),
+    "INC C" => |m, _| inc!(m, m.cpu.c),
+    "INC E" => |m, _| inc!(m, m.cpu.e),
+    "INC L" => |m, _| inc!(m, m.cpu.l),
+    "INC A" => |m, _| inc!(m, m.cpu.a),
+
+    "INC BC" => |m, _| m.cpu.set_bc(m.cpu.bc() + 1u16),
+    "INC DE" => |m, _| m.cpu.set_de(m.cpu.de() + 1u16),
+    "INC HL" => |m, _| m.cpu.set_hl(m.cpu.hl() + 1u16),
+    "INC SP" => |m, _| m.cpu.sp += 1u16,
+    "INC (HL)" => |m, _| {
+        let mut val = m.load_hl();
+        inc!(m, val);
+        m.store_hl(val);
+    },
+
+    // ========== ADD ==========
+    "ADD A, B"     => |m, _| add!(m, m.cpu.b),
+    "ADD A, C"     => |m, _| add!(m, m.cpu.c),
+    "ADD A, D"     => |m, _| add!(m, m.cpu.d),
+    "ADD A, E"     => |m, _| add!(m, m.cpu.e),
+    "ADD A, H"     => |m, _| add!(m, m.cpu.h),
+    "ADD A, L"     => |m, _| add!(m, m.cpu.l),
+    "ADD A, (HL)"  => |m, _| add!(m, m.load_hl()),
+    "ADD A, A"     => |m, _| add!(m, m.cpu.a),
+    "ADD A, d8"    => |m, args| add!(m, args.byte),
+
+    "ADD HL, BC" => |m, _| add_hl!(m, m.cpu.bc()),
+    "ADD HL, DE" => |m, _| add_hl!(m, m.cpu.de()),
+    "ADD HL, HL" => |m, _| add_hl!(m, m.cpu.hl()),
+    "ADD HL, SP" => |m, _| add_hl!(m, m.cpu.sp),
+
+    "ADD SP, r8" => |m, args| {
+        let (carry, half_carry) = m.cpu.sp.add_i8_with_carries(args.byte.get() as i8);
+        set_flags!(m.cpu.f => 0 0 half_carry carry);
+    },
+
+    // ========== ADC ==========
+    "ADC A, B"     => |m, _| adc!(m, m.cpu.b),
+    "ADC A, C"     => |m, _| adc!(m, m.cpu.c),
+    "ADC A, D"     => |m, _| adc!(m, m.cpu.d),
+    "ADC A, E"     => |m, _| adc!(m, m.cpu.e),
+    "ADC A, H"     => |m, _| adc!(m, m.cpu.h),
+    "ADC A, L"     => |m, _| adc!(m, m.cpu.l),
+    "ADC A, (HL)"  => |m, _| adc!(m, m.load_hl()),
+    "ADC A, A"     => |m, _| adc!(m, m.cpu.a),
+    "ADC A, d8"    => |m, args| adc!(m, args.byte),
+
+    // ========== SUB ==========
+    "SUB B"    => |m, _| sub!(m, m.cpu.b),
+    "SUB C"    => |m, _| sub!(m, m.cpu.c),
+    "SUB D"    => |m, _| sub!(m, m.cpu.d),
+    "SUB E"    => |m, _| sub!(m, m.cpu.e),
+    "SUB H"    => |m, _| sub!(m, m.cpu.h),
+    "SUB L"    => |m, _| sub!(m, m.cpu.l),
+    "SUB (HL)" => |m, _| sub!(m, m.load_hl()),
+    "SUB A"    => |m, _| sub!(m, m.cpu.a),
+    "SUB d8"   => |m, args| sub!(m, args.byte),
+
+    // ========== SBC ==========
+    "SBC A, B"    => |m, _| sbc!(m, m.cpu.b),
+    "SBC A, C"    => |m, _| sbc!(m, m.cpu.c),
+    "SBC A, D"    => |m, _| sbc!(m, m.cpu.d),
+    "SBC A, E"    => |m, _| sbc!(m, m.cpu.e),
+    "SBC A, H"    => |m, _| sbc!(m, m.cpu.h),
+    "SBC A, L"    => |m, _| sbc!(m, m.cpu.l),
+    "SBC A, (HL)" => |m, _| sbc!(m, m.load_hl()),
+    "SBC A, A"    => |m, _| sbc!(m, m.cpu.a),
+    "SBC A, d8"   => |m, args| sbc!(m, args.byte),
+
+    // ========== AND ==========
+    "AND B"    => |m, _| and!(m, m.cpu.b),
+    "AND C"    => |m, _| and!(m, m.cpu.c),
+    "AND D"    => |m, _| and!(m, m.cpu.d),
+    "AND E"    => |m, _| and!(m, m.cpu.e),
+    "AND H"    => |m, _| and!(m, m.cpu.h),
+    "AND L"    => |m, _| and!(m, m.cpu.l),
+    "AND (HL)" => |m, _| and!(m, m.load_hl()),
+    "AND A"    => |m, _| and!(m, m.cpu.a),
+    "AND d8"   => |m, args| and!(m, args.byte),
+
+    // ========== XOR ==========
+    "XOR B"    => |m, _| xor!(m, m.cpu.b),
+    "XOR C"    => |m, _| xor!(m, m.cpu.c),
+    "XOR D"    => |m, _| xor!(m, m.cpu.d),
+    "XOR E"    => |m, _| xor!(m, m.cpu.e),
+    "XOR H"    => |m, _| xor!(m, m.cpu.h),
+    "XOR L"    => |m, _| xor!(m, m.cpu.l),
+    "XOR (HL)" => |m, _| xor!(m, m.load_hl()),
+    "XOR A"    => |m, _| xor!(m, m.cpu.a),
+    "XOR d8"   => |m, args| xor!(m, args.byte),
+
+    // ========== OR ==========
+    "OR B"    => |m, _| or!(m, m.cpu.b),
+    "OR C"    => |m, _| or!(m, m.cpu.c),
+    "OR D"    => |m, _| or!(m, m.cpu.d),
+    "OR E"    => |m, _| or!(m, m.cpu.e),
+    "OR H"    => |m, _| or!(m, m.cpu.h),
+    "OR L"    => |m, _| or!(m, m.cpu.l),
+    "OR (HL)" => |m, _| or!(m, m.load_hl()),
+    "OR A"    => |m, _| or!(m, m.cpu.a),
+    "OR d8"   => |m, args| or!(m, args.byte),
+
+    // ========== CP ==========
+    "CP B"    => |m, _| cp!(m, m.cpu.b),
+    "CP C"    => |m, _| cp!(m, m.cpu.c),
+    "CP D"    => |m, _| cp!(m, m.cpu.d),
+    "CP E"    => |m, _| cp!(m, m.cpu.e),
+    "CP H"    => |m, _| cp!(m, m.cpu.h),
+    "CP L"    => |m, _| cp!(m, m.cpu.l),
+    "CP (HL)" => |m, _| cp!(m, m.load_hl()),
+    "CP A"    => |m, _| cp!(m, m.cpu.a),
+    "CP d8"   => |m, args| cp!(m, args.byte),
+
+    // ========== RST ==========
+    "RST 00H" => |m, _| call!(m, Word::new(0x00)),
+    "RST 08H" => |m, _| call!(m, Word::new(0x08)),
+    "RST 10H" => |m, _| call!(m, Word::new(0x10)),
+    "RST 18H" => |m, _| call!(m, Word::new(0x18)),
+    "RST 20H" => |m, _| call!(m, Word::new(0x20)),
+    "RST 28H" => |m, _| call!(m, Word::new(0x28)),
+    "RST 30H" => |m, _| call!(m, Word::new(0x30)),
+    "RST 38H" => |m, _| call!(m, Word::new(0x38)),
+
+    // ========== JR ==========
+    "JR r8" => |m, args| m.cpu.pc += args.byte.get() as i8,
+    "JR NZ, r8" => |m, args| m.jr_if(!m.cpu.zero(), args.byte),
+    "JR Z, r8" => |m, args| m.jr_if(m.cpu.zero(), args.byte),
+    "JR NC, r8" => |m, args| m.jr_if(!m.cpu.carry(), args.byte),
+    "JR C, r8" => |m, args| m.jr_if(m.cpu.carry(), args.byte),
+
+    // ========== JP ==========
+    "JP a16" => |m, args| m.cpu.pc = args.word,
+    "JP HL" => |m, _| m.cpu.pc = m.cpu.hl(),
+    "JP Z, a16" => |m, args| m.jp_if(m.cpu.zero(), args.word),
+    "JP C, a16" => |m, args| m.jp_if(m.cpu.carry(), args.word),
+    "JP NZ, a16" => |m, args| m.jp_if(!m.cpu.zero(), args.word),
+    "JP NC, a16" => |m, args| m.jp_if(!m.cpu.carry(), args.word),
+
+    // ========== POP/PUSH ==========
+    "POP BC" => |m, _| {
+        let val = m.pop();
+        m.cpu.set_bc(val);
+    },
+    "POP DE" => |m, _| {
+        let val = m.pop();
+        m.cpu.set_de(val);
+    },
+    "POP HL" => |m, _| {
+        let val = m.pop();
+        m.cpu.set_hl(val);
+    },
+    "POP AF" => |m, _| {
+        let val = m.pop();
+        m.cpu.set_af(val);
+    },
+    "PUSH BC" => |m, _| m.push(m.cpu.bc()),
+    "PUSH DE" => |m, _| m.push(m.cpu.de()),
+    "PUSH HL" => |m, _| m.push(m.cpu.hl()),
+    "PUSH AF" => |m, _| m.push(m.cpu.af()),
+
+    // ========== CALL ==========
+    "CALL a16" => |m, args| call!(m, args.word),
+    "CALL NZ, a16" => |m, args| m.call_if(!m.cpu.zero(), args.word),
+    "CALL Z, a16" => |m, args| m.call_if(m.cpu.zero(), args.word),
+    "CALL NC, a16" => |m, args| m.call_if(!m.cpu.carry(), args.word),
+    "CALL C, a16" => |m, args| m.call_if(m.cpu.carry(), args.word),
+
+    // ========== RET ==========
+    "RET" => |m, _| ret!(m),
+    "RET NZ" => |m, _| m.ret_if(!m.cpu.zero()),
+    "RET NC" => |m, _| m.ret_if(!m.cpu.carry()),
+    "RET Z" => |m, _| m.ret_if(m.cpu.zero()),
+    "RET C" => |m, _| m.ret_if(m.cpu.carry()),
+    "RETI" => |m, _| {
+        ret!(m);
+        // Enable interrupts
+        m.interrupt_controller.ime = true;
+    },
+
+    // ========== Non-prefix rotate instructions ==========
+    "RLA" => |m, _| {
+        let carry = m.cpu.a.rotate_left_through_carry(m.cpu.carry());
+        set_flags!(m.cpu.f => 0 0 0 carry);
+    },
+    "RRA" => |m, _| {
+        let carry = m.cpu.a.rotate_right_through_carry(m.cpu.carry());
+        set_flags!(m.cpu.f => 0 0 0 carry);
+    },
+    "RLCA" => |m, _| {
+        let carry = m.cpu.a.rotate_left();
+        set_flags!(m.cpu.f => 0 0 0 carry);
+    },
+    "RRCA" => |m, _| {
+        let carry = m.cpu.a.rotate_right();
+        set_flags!(m.cpu.f => 0 0 0 carry);
+    },
+
+    // ========== miscellaneous ==========
+    "SCF" => |m, _| {
+        set_flags!(m.cpu.f => - 0 0 1);
+    },
+    "CCF" => |m, _| {
+        let carry = !m.cpu.carry();
+        set_flags!(m.cpu.f => - 0 0 carry);
+    },
+    "DAA" => |m, _| {
+        let carry = m.cpu.daa();
+        let zero = m.cpu.a == 0;
+        set_flags!(m.cpu.f => zero - 0 carry);
+    },
+    "DI" => |m, _| m.interrupt_controller.ime = false,
+    "EI" => |m, _| m.enable_interrupts_next_step = true,
+    "HALT" => |m, _| {
+        debug!("Executed HALT: CPU entering HALT mode");
+        m.state = State::Halted;
+    },
+    "STOP" => |m, _| {
+        debug!("Executed STOP: CPU entering ultra-low power mode");
+
+        let any_buttons_select = m.input_controller.is_button_selected()
+            || m.input_controller.is_direction_selected();
+        if !any_buttons_select {
+            error!("STOP instruction executed, but no buttons are selected, meaning \
+                that there is no way to exit this STOP mode");
+        }
+
+        // TODO: this is most likely still incorrect in some ways
+        m.ppu.disable();
+        m.state = State::Stopped;
+    },
+    "NOP" => |_, _| {}, // Just do nothing _(:3」∠)_
+    "CPL" => |m, _| {
+        m.cpu.a = !m.cpu.a;
+        set_flags!(m.cpu.f => - 1 1 -);
+    },
+});
+
+/// Handlers of all PREFIX CB instructions.
+pub(crate) static PREFIXED_HANDLERS: InstrDb<Handler> = InstrDb({
+    let mut table = handlers! { prefixed_opcode;
+        // ========== RLC ==========
+        "RLC B" => |m, _| rlc!(m, m.cpu.b),
+        "RLC C" => |m, _| rlc!(m, m.cpu.c),
+        "RLC D" => |m, _| rlc!(m, m.cpu.d),
+        "RLC E" => |m, _| rlc!(m, m.cpu.e),
+        "RLC H" => |m, _| rlc!(m, m.cpu.h),
+        "RLC L" => |m, _| rlc!(m, m.cpu.l),
+        "RLC (HL)" => |m, _| {
+            let mut val = m.load_hl();
+            rlc!(m, val);
+            m.store_hl(val);
+        },
+        "RLC A" => |m, _| rlc!(m, m.cpu.a),
+
+        // ========== RRC ==========
+        "RRC B" => |m, _| rrc!(m, m.cpu.b),
+        "RRC C" => |m, _| rrc!(m, m.cpu.c),
+        "RRC D" => |m, _| rrc!(m, m.cpu.d),
+        "RRC E" => |m, _| rrc!(m, m.cpu.e),
+        "RRC H" => |m, _| rrc!(m, m.cpu.h),
+        "RRC L" => |m, _| rrc!(m, m.cpu.l),
+        "RRC (HL)" => |m, _| {
+            let mut val = m.load_hl();
+            rrc!(m, val);
+            m.store_hl(val);
+        },
+        "RRC A" => |m, _| rrc!(m, m.cpu.a),
+
+        // ========== RL ==========
+        "RL B" => |m, _| rl!(m, m.cpu.b),
+        "RL C" => |m, _| rl!(m, m.cpu.c),
+        "RL D" => |m, _| rl!(m, m.cpu.d),
+        "RL E" => |m, _| rl!(m, m.cpu.e),
+        "RL H" => |m, _| rl!(m, m.cpu.h),
+        "RL L" => |m, _| rl!(m, m.cpu.l),
+        "RL (HL)" => |m, _| {
+            let mut val = m.load_hl();
+            rl!(m, val);
+            m.store_hl(val);
+        },
+        "RL A" => |m, _| rl!(m, m.cpu.a),
+
+        // ========== RR ==========
+        "RR B" => |m, _| rr!(m, m.cpu.b),
+        "RR C" => |m, _| rr!(m, m.cpu.c),
+        "RR D" => |m, _| rr!(m, m.cpu.d),
+        "RR E" => |m, _| rr!(m, m.cpu.e),
+        "RR H" => |m, _| rr!(m, m.cpu.h),
+        "RR L" => |m, _| rr!(m, m.cpu.l),
+        "RR (HL)" => |m, _| {
+            let mut val = m.load_hl();
+            rr!(m, val);
+            m.store_hl(val);
+        },
+        "RR A" => |m, _| rr!(m, m.cpu.a),
+
+        // ========== SLA ==========
+        "SLA B" => |m, _| sla!(m, m.cpu.b),
+        "SLA C" => |m, _| sla!(m, m.cpu.c),
+        "SLA D" => |m, _| sla!(m, m.cpu.d),
+        "SLA E" => |m, _| sla!(m, m.cpu.e),
+        "SLA H" => |m, _| sla!(m, m.cpu.h),
+        "SLA L" => |m, _| sla!(m, m.cpu.l),
+        "SLA (HL)" => |m, _| {
+            let mut val = m.load_hl();
+            sla!(m, val);
+            m.store_hl(val);
+        },
+        "SLA A" => |m, _| sla!(m, m.cpu.a),
+
+        // ========== SRL ==========
+        "SRL B" => |m, _| srl!(m, m.cpu.b),
+        "SRL C" => |m, _| srl!(m, m.cpu.c),
+        "SRL D" => |m, _| srl!(m, m.cpu.d),
+        "SRL E" => |m, _| srl!(m, m.cpu.e),
+        "SRL H" => |m, _| srl!(m, m.cpu.h),
+        "SRL L" => |m, _| srl!(m, m.cpu.l),
+        "SRL (HL)" => |m, _| {
+            let mut val = m.load_hl();
+            srl!(m, val);
+            m.store_hl(val);
+        },
+        "SRL A" => |m, _| srl!(m, m.cpu.a),
+
+        // ========== SRA ==========
+        "SRA B" => |m, _| sra!(m, m.cpu.b),
+        "SRA C" => |m, _| sra!(m, m.cpu.c),
+        "SRA D" => |m, _| sra!(m, m.cpu.d),
+        "SRA E" => |m, _| sra!(m, m.cpu.e),
+        "SRA H" => |m, _| sra!(m, m.cpu.h),
+        "SRA L" => |m, _| sra!(m, m.cpu.l),
+        "SRA (HL)" => |m, _| {
+            let mut val = m.load_hl();
+            sra!(m, val);
+            m.store_hl(val);
+        },
+        "SRA A" => |m, _| sra!(m, m.cpu.a),
+
+        // ========== SWAP ==========
+        "SWAP B" => |m, _| swap!(m, m.cpu.b),
+        "SWAP C" => |m, _| swap!(m, m.cpu.c),
+        "SWAP D" => |m, _| swap!(m, m.cpu.d),
+        "SWAP E" => |m, _| swap!(m, m.cpu.e),
+        "SWAP H" => |m, _| swap!(m, m.cpu.h),
+        "SWAP L" => |m, _| swap!(m, m.cpu.l),
+        "SWAP (HL)" => |m, _| {
+            let mut val = m.load_hl();
+            swap!(m, val);
+            m.store_hl(val);
+        },
+        "SWAP A" => |m, _| swap!(m, m.cpu.a),
+    };
+
+    // ========== BIT/RES/SET ==========
+    let mut op_code = 0x40;
+    while op_code <= 0xFF {
+        table[op_code] = bit_res_set;
+        op_code += 1;
+    }
+
+    table
+});
+
+/// Handler of all BIT, RES and SET instructions.
+fn bit_res_set(m: &mut Machine, args: Operands) -> Option<bool> {
+    let op_code = args.op_code.get();
+
+    // All BIT/RES/SET instructions follow the same structure. Because of this
+    // all three instructions are handled by this function to reduce
+    // duplicate code.
+    //
+    // The opcode structure is the following:
+    // 00 000 000
+    // ^^ ^^^ ^^^
+    // || ||| |||
+    // || ||| --------> The first three bits encode the register which is
+    // || |||           used (0: B, 1: C, 2: D, 3: E, 4: H, 5: L, 6: (HL), 7: A)
+    // ||  -----------> The next three bits encode the bit which should be
+    // ||               passed to the instruction (0: LSB, up to 7: MSB)
+    //  --------------> The last two bits encode the instruction which should
+    //                  be executed (1: BIT, 2: RES, 3: SET)
+
+    // Select register
+    let register_code = op_code & 0b0000_0111;
+
+    // Select instruction
+    let instr_code = (op_code & 0b1100_0000) >> 6;
+
+    // Select bit
+    let bit = (op_code & 0b0011_1000) >> 3;
+
+    // Get bit mask
+    let mask = Byte::new(0b0000_0001 << bit);
+
+    // Handle (HL) in a special way, because we can't create a mutable borrow
+    // of it
+    if register_code == 6 {
+        let byte = m.load_hl();
+        match instr_code {
+            1 => {
+                let zero = (byte & mask) == 0;
+                set_flags!(m.cpu.f => zero 0 1 -);
+            }
+            2 => m.store_hl(byte & !mask),
+            3 => m.store_hl(byte | mask),
+            _ => unreachable!(),
+        }
+    } else {
+        // Create a mutable borrow of the selected register and apply the
+        // instruction on it
+        let reg = match register_code {
+            0 => &mut m.cpu.b,
+            1 => &mut m.cpu.c,
+            2 => &mut m.cpu.d,
+            3 => &mut m.cpu.e,
+            4 => &mut m.cpu.h,
+            5 => &mut m.cpu.l,
+            7 => &mut m.cpu.a,
+            _ => unreachable!(),
+        };
+        match instr_code {
+            1 => {
+                let zero = (*reg & mask) == 0;
+                set_flags!(m.cpu.f => zero 0 1 -);
+            }
+            2 => *reg &= !mask,
+            3 => *reg |= mask,
+            _ => unreachable!(),
+        }
+    }
+
+    None
+}
+
+/// Handler of all invalid opcodes. These are already rejected when the
+/// instruction is decoded, so this is never called.
+fn invalid(_: &mut Machine, _: Operands) -> Option<bool> {
+    unreachable!()
+}
+
+impl Machine {
+    /// Jumps relative to PC by the signed `offset` if `cond` is true.
+    /// Returns `cond`.
+    fn jr_if(&mut self, cond: bool, offset: Byte) -> bool {
+        if cond {
+            self.cpu.pc += offset.get() as i8;
+        }
+        cond
+    }
+
+    /// Jumps to `target` if `cond` is true. Returns `cond`.
+    fn jp_if(&mut self, cond: bool, target: Word) -> bool {
+        if cond {
+            self.cpu.pc = target;
+        }
+        cond
+    }
+
+    /// Calls `target` if `cond` is true. Returns `cond`.
+    fn call_if(&mut self, cond: bool, target: Word) -> bool {
+        if cond {
+            call!(self, target);
+        }
+        cond
+    }
+
+    /// Returns from the current function if `cond` is true. Returns `cond`.
+    fn ret_if(&mut self, cond: bool) -> bool {
+        if cond {
+            ret!(self);
+        }
+        cond
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{BiosKind, HardwareModel, cartridge::Cartridge};
+    use super::*;
+
+    fn machine() -> Machine {
+        let cartridge = Cartridge::from_bytes(&[0; 0x8000]).unwrap();
+        Machine::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg)
+    }
+
+    fn args(op_code: u8, word: u16) -> Operands {
+        let word = Word::new(word);
+        Operands { op_code: Byte::new(op_code), byte: word.into_bytes().0, word }
+    }
+
+    #[test]
+    fn test_add() {
+        let mut m = machine();
+        m.cpu.a = Byte::new(0x3A);
+        m.cpu.b = Byte::new(0xC6);
+        let op = opcode!("ADD A, B");
+        assert_eq!(HANDLERS[Byte::new(op)](&mut m, args(op, 0)), None);
+        assert_eq!(m.cpu.a, 0x00);
+        assert_eq!(m.cpu.f, 0b1011_0000);
+    }
+
+    #[test]
+    fn test_conditional_jump() {
+        let mut m = machine();
+        m.cpu.pc = Word::new(0x0200);
+        m.cpu.f = Byte::new(0b1000_0000);
+        let op = opcode!("JR NZ, r8");
+        assert_eq!(HANDLERS[Byte::new(op)](&mut m, args(op, 0xFE)), Some(false));
+        assert_eq!(m.cpu.pc, Word::new(0x0200));
+
+        m.cpu.f = Byte::zero();
+        assert_eq!(HANDLERS[Byte::new(op)](&mut m, args(op, 0xFE)), Some(true));
+        assert_eq!(m.cpu.pc, Word::new(0x01FE));
+    }
+
+    #[test]
+    fn test_bit_res_set() {
+        let mut m = machine();
+        m.cpu.c = Byte::new(0b0000_1000);
+        let run = |m: &mut Machine, op| PREFIXED_HANDLERS[Byte::new(op)](m, args(op, 0));
+
+        run(&mut m, prefixed_opcode!("BIT 3, C"));
+        assert!(!m.cpu.zero());
+        run(&mut m, prefixed_opcode!("RES 3, C"));
+        assert_eq!(m.cpu.c, 0);
+        run(&mut m, prefixed_opcode!("BIT 3, C"));
+        assert!(m.cpu.zero());
+        run(&mut m, prefixed_opcode!("SET 7, C"));
+        assert_eq!(m.cpu.c, 0b1000_0000);
+    }
+}
@@ -0,0 +1,301 @@
+//! Hooks into the memory accesses of the emulated CPU.
+//!
+//! These are not needed for emulation itself, but are used by debuggers (e.g.
+//! to implement watchpoints or to count accesses per address). Only accesses
+//! done while executing an instruction are observed: loading bytes from
+//! outside (e.g. to show memory in a debugger) never triggers anything.
+//!
+//! Frozen addresses (see `Machine::freeze`) are also stored here: their value
+//! is written again after every instruction, which is how "infinite lives"
+//! cheats work.
+
+use std::{
+    cell::Cell,
+    ops::RangeInclusive,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+};
+
+use super::Machine;
+use crate::{
+    primitives::{Byte, Word},
+};
+
+
+/// The kind of a memory access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// A watchpoint on an address range.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    /// The watched addresses (inclusive).
+    pub range: RangeInclusive<Word>,
+
+    /// Whether reading from the range triggers the watchpoint.
+    pub on_read: bool,
+
+    /// Whether writing to the range triggers the watchpoint.
+    pub on_write: bool,
+}
+
+impl Watchpoint {
+    /// Returns whether an access of the given kind to `addr` triggers this
+    /// watchpoint.
+    pub fn matches(&self, addr: Word, kind: AccessKind) -> bool {
+        let kind_matches = match kind {
+            AccessKind::Read => self.on_read,
+            AccessKind::Write => self.on_write,
+        };
+
+        kind_matches && self.range.contains(&addr)
+    }
+}
+
+/// Describes one memory access that triggered a watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    /// The accessed address.
+    pub addr: Word,
+
+    /// The byte that was read or written.
+    pub value: Byte,
+
+    pub kind: AccessKind,
+
+    /// Address of the instruction that performed the access.
+    pub pc: Word,
+}
+
+/// The number of reads and writes per address, see
+/// `Machine::set_access_counting`.
+pub struct AccessCounts {
+    reads: Box<[AtomicU32]>,
+    writes: Box<[AtomicU32]>,
+}
+
+impl AccessCounts {
+    fn new() -> Self {
+        let zeroed = || (0..0x10000).map(|_| AtomicU32::new(0)).collect();
+        Self {
+            reads: zeroed(),
+            writes: zeroed(),
+        }
+    }
+
+    /// Returns how often `addr` was read.
+    pub fn reads(&self, addr: Word) -> u32 {
+        self.reads[addr.get() as usize].load(Ordering::Relaxed)
+    }
+
+    /// Returns how often `addr` was written.
+    pub fn writes(&self, addr: Word) -> u32 {
+        self.writes[addr.get() as usize].load(Ordering::Relaxed)
+    }
+
+    /// Sets all counts to 0.
+    pub fn reset(&self) {
+        for count in self.reads.iter().chain(self.writes.iter()) {
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+
+    #[inline(always)]
+    fn record(&self, addr: Word, kind: AccessKind) {
+        let counts = match kind {
+            AccessKind::Read => &self.reads,
+            AccessKind::Write => &self.writes,
+        };
+        counts[addr.get() as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// State of all memory hooks. Stored inside of `Machine`.
+#[derive(Clone)]
+pub(crate) struct MemoryHooks {
+    watchpoints: Vec<Watchpoint>,
+
+    /// Access counts, if enabled. Shared between clones of the machine, so
+    /// that snapshots (e.g. for rewinding) stay cheap.
+    counts: Option<Arc<AccessCounts>>,
+
+    /// The first watchpoint hit during the current or last instruction. A
+    /// `Cell`, because loading bytes only requires `&self`.
+    hit: Cell<Option<MemoryAccess>>,
+
+    /// Whether an instruction is currently executed. Accesses are only
+    /// observed while this is `true`.
+    active: bool,
+
+    /// Start address of the instruction currently executed.
+    instr_start: Word,
+
+    /// Frozen addresses and their values, sorted by address.
+    frozen: Vec<(Word, Byte)>,
+}
+
+impl MemoryHooks {
+    pub(crate) fn new() -> Self {
+        Self {
+            watchpoints: Vec::new(),
+            counts: None,
+            hit: Cell::new(None),
+            active: false,
+            instr_start: Word::new(0),
+            frozen: Vec::new(),
+        }
+    }
+
+    /// Called right before an instruction (or interrupt dispatch) starts.
+    pub(crate) fn begin_step(&mut self, pc: Word) {
+        self.hit.set(None);
+        self.instr_start = pc;
+        self.active = true;
+    }
+
+    /// Called right after an instruction finished.
+    pub(crate) fn end_step(&mut self) {
+        self.active = false;
+    }
+
+    /// Notifies the hooks about a memory access.
+    #[inline(always)]
+    pub(crate) fn on_access(&self, addr: Word, value: Byte, kind: AccessKind) {
+        if !self.active {
+            return;
+        }
+        if let Some(counts) = &self.counts {
+            counts.record(addr, kind);
+        }
+        if self.watchpoints.is_empty() {
+            return;
+        }
+
+        let already_hit = self.hit.get().is_some();
+        if !already_hit && self.watchpoints.iter().any(|w| w.matches(addr, kind)) {
+            self.hit.set(Some(MemoryAccess {
+                addr,
+                value,
+                kind,
+                pc: self.instr_start,
+            }));
+        }
+    }
+}
+
+impl Machine {
+    /// Returns all watchpoints currently set.
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.hooks.watchpoints
+    }
+
+    /// Adds a watchpoint. Adding a watchpoint that already exists does
+    /// nothing.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.hooks.watchpoints.contains(&watchpoint) {
+            self.hooks.watchpoints.push(watchpoint);
+        }
+    }
+
+    /// Removes the given watchpoint. Returns `false` if it did not exist.
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        let len_before = self.hooks.watchpoints.len();
+        self.hooks.watchpoints.retain(|w| w != watchpoint);
+        self.hooks.watchpoints.len() != len_before
+    }
+
+    /// Starts or stops counting the reads and writes of each address (see
+    /// `access_counts`). Stopping discards all counts. Like watchpoints,
+    /// only accesses while executing instructions are counted. Fetching the
+    /// instructions themselves is not counted.
+    pub fn set_access_counting(&mut self, enabled: bool) {
+        match (enabled, &self.hooks.counts) {
+            (true, None) => self.hooks.counts = Some(Arc::new(AccessCounts::new())),
+            (false, _) => self.hooks.counts = None,
+            (true, Some(_)) => {}
+        }
+    }
+
+    /// Returns the access counts since counting was started or the counts
+    /// were last reset, or `None` if counting is disabled.
+    pub fn access_counts(&self) -> Option<&AccessCounts> {
+        self.hooks.counts.as_deref()
+    }
+
+    /// Returns all frozen addresses with their values, sorted by address.
+    pub fn frozen(&self) -> &[(Word, Byte)] {
+        &self.hooks.frozen
+    }
+
+    /// Freezes `addr` to `value`: the value is written right away and again
+    /// after every instruction, so the game can never change it for longer
+    /// than one instruction. Freezing an address again replaces the value.
+    ///
+    /// The value is written with `store_byte_raw`, so for cartridge RAM it
+    /// ends up in the bank mapped at that moment.
+    pub fn freeze(&mut self, addr: Word, value: Byte) {
+        match self.hooks.frozen.binary_search_by_key(&addr, |&(a, _)| a) {
+            Ok(idx) => self.hooks.frozen[idx].1 = value,
+            Err(idx) => self.hooks.frozen.insert(idx, (addr, value)),
+        }
+        self.store_byte_raw(addr, value);
+    }
+
+    /// Unfreezes `addr`. Returns `false` if it was not frozen.
+    pub fn unfreeze(&mut self, addr: Word) -> bool {
+        let len_before = self.hooks.frozen.len();
+        self.hooks.frozen.retain(|&(a, _)| a != addr);
+        self.hooks.frozen.len() != len_before
+    }
+
+    /// Writes the values of all frozen addresses.
+    pub(crate) fn apply_frozen(&mut self) {
+        for i in 0..self.hooks.frozen.len() {
+            let (addr, value) = self.hooks.frozen[i];
+            self.store_byte_raw(addr, value);
+        }
+    }
+
+    /// Returns the first memory access of the last executed instruction that
+    /// triggered a watchpoint.
+    pub fn watchpoint_hit(&self) -> Option<MemoryAccess> {
+        self.hooks.hit.get()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{BiosKind, HardwareModel, test_util::cartridge_with_code};
+    use super::*;
+
+    #[test]
+    fn test_fetches_not_reported() {
+        // 0x0150: swap a; ld a, [hl]
+        let cartridge = cartridge_with_code(&[0xCB, 0x37, 0x7E]);
+        let mut machine = Machine::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        machine.cpu.pc = Word::new(0x150);
+        machine.cpu.set_hl(Word::new(0xC000));
+        for addr in [0x150, 0x151, 0x152, 0xC000] {
+            let addr = Word::new(addr);
+            machine.add_watchpoint(Watchpoint {
+                range: addr..=addr,
+                on_read: true,
+                on_write: false,
+            });
+        }
+
+        // Neither the prefix nor the opcode after it trigger a watchpoint.
+        machine.step().ok().unwrap();
+        assert!(machine.watchpoint_hit().is_none());
+
+        machine.step().ok().unwrap();
+        let hit = machine.watchpoint_hit().unwrap();
+        assert_eq!((hit.addr, hit.kind), (Word::new(0xC000), AccessKind::Read));
+    }
+}
@@ -2,13 +2,39 @@ use crate::{
     primitives::Byte,
     env::Peripherals,
     machine::interrupt::{Interrupt, InterruptController},
+    save_state::{SaveStateError, StateReader, StateWriter},
 };
 
 
 /// Manages the input from the Joypad. This is mapped to 0xFF00 in the Memory.
+#[derive(Clone)]
 pub(crate) struct InputController {
-    // TODO: Implement Joypad Interrupt
     register: Byte,
+
+    /// The keys pressed when `handle_input` was last called (including the
+    /// injected ones).
+    pressed: Keys,
+
+    /// Keys pressed via `set_injected_key`, in addition to the keys pressed
+    /// according to the peripherals.
+    injected: Keys,
+
+    /// This is a setting, not part of the save state.
+    opposing_directions: OpposingDirections,
+}
+
+/// What the game sees when opposing directions (Left and Right or Up and
+/// Down) are pressed at the same time. This is impossible on the D-pad of a
+/// real Game Boy, but easy on a keyboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpposingDirections {
+    /// Neither of the two directions is pressed, as the game never sees both
+    /// on real hardware. Some games misbehave if they do. This is the
+    /// default.
+    Filter,
+
+    /// Both directions are pressed. This is useful to hunt for glitches.
+    Allow,
 }
 
 impl InputController {
@@ -16,9 +42,21 @@ impl InputController {
     pub(crate) fn new() -> Self {
         Self {
             register: Byte::new(0xFF),
+            pressed: Keys::none(),
+            injected: Keys::none(),
+            opposing_directions: OpposingDirections::Filter,
         }
     }
 
+    pub(crate) fn set_opposing_directions(
+        &mut self,
+        mode: OpposingDirections,
+        interrupt_controller: &mut InterruptController,
+    ) {
+        self.opposing_directions = mode;
+        self.update_register(interrupt_controller);
+    }
+
     /// Loads the input register.
     ///
     /// This function behaves like the real input register. Meaning: Bits 6 and 7 always return
@@ -42,7 +80,34 @@ impl InputController {
         peripherals: &impl Peripherals,
         interrupt_controller: &mut InterruptController,
     ) {
-        let pressed = peripherals.get_pressed_keys();
+        self.pressed = Keys(peripherals.get_pressed_keys().0 | self.injected.0);
+        self.update_register(interrupt_controller);
+    }
+
+    /// Presses or releases a key independent of the peripherals. The register
+    /// is updated immediately (possibly requesting the joypad interrupt).
+    pub(crate) fn set_injected_key(
+        &mut self,
+        key: JoypadKey,
+        is_pressed: bool,
+        interrupt_controller: &mut InterruptController,
+    ) {
+        self.injected = self.injected.set_key(key, is_pressed);
+
+        // If the key is still pressed according to the peripherals, this is
+        // corrected the next time they are polled.
+        self.pressed = self.pressed.set_key(key, is_pressed);
+        self.update_register(interrupt_controller);
+    }
+
+    /// Updates the register with the keys pressed when the input was last
+    /// handled. This is necessary when the game selects other keys, without
+    /// polling the peripherals again.
+    pub(crate) fn update_register(&mut self, interrupt_controller: &mut InterruptController) {
+        let pressed = match self.opposing_directions {
+            OpposingDirections::Filter => self.pressed.without_opposing_directions(),
+            OpposingDirections::Allow => self.pressed,
+        };
         let keys = match (self.is_direction_selected(), self.is_button_selected()) {
             (false, false) => 0,
             (false, true) => pressed.get_button_keys(),
@@ -67,6 +132,11 @@ impl InputController {
         self.register = new_state;
     }
 
+    /// Returns the keys pressed when the input was last handled.
+    pub(crate) fn pressed_keys(&self) -> Keys {
+        self.pressed
+    }
+
     /// Returns true, if the button keys are selected, false otherwise.
     #[inline(always)]
     pub(crate) fn is_button_selected(&self) -> bool {
@@ -78,6 +148,19 @@ impl InputController {
     pub(crate) fn is_direction_selected(&self) -> bool {
         (self.register.get() & 0b0001_0000) == 0
     }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.byte(self.register);
+        w.u8(self.pressed.0);
+        w.u8(self.injected.0);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.register = r.byte()?;
+        self.pressed = Keys(r.u8()?);
+        self.injected = Keys(r.u8()?);
+        Ok(())
+    }
 }
 
 /// Represents the buttons pressed on the Joypad in an easy and convenient way (some people say,
@@ -91,7 +174,7 @@ impl InputController {
 /// - 5: Left
 /// - 6: Up
 /// - 7: Down (MSB)
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Keys(u8);
 
 impl Keys {
@@ -106,11 +189,41 @@ impl Keys {
     pub fn set_key(mut self, key: JoypadKey, is_pressed: bool) -> Self {
         if is_pressed {
             self.0 |= key as u8;
+        } else {
+            self.0 &= !(key as u8);
         }
 
         self
     }
 
+    /// Creates an instance from the bits described in the type documentation.
+    pub fn from_bits(bits: u8) -> Self {
+        Keys(bits)
+    }
+
+    /// Returns the bits described in the type documentation.
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    /// Returns whether the given key is pressed.
+    #[inline(always)]
+    pub fn is_pressed(&self, key: JoypadKey) -> bool {
+        self.0 & key as u8 != 0
+    }
+
+    /// Releases Left and Right if both are pressed, and the same for Up and
+    /// Down.
+    pub fn without_opposing_directions(self) -> Self {
+        let mut out = self;
+        for &(a, b) in &[(JoypadKey::Left, JoypadKey::Right), (JoypadKey::Up, JoypadKey::Down)] {
+            if self.is_pressed(a) && self.is_pressed(b) {
+                out = out.set_key(a, false).set_key(b, false);
+            }
+        }
+        out
+    }
+
     /// Returns the direction keys in the low nybble (the high nybble is 0).
     #[inline(always)]
     pub(crate) fn get_direction_keys(&self) -> u8 {
@@ -225,4 +338,79 @@ mod test {
             0b1100_0000,
         );
     }
+
+    #[test]
+    fn test_injected_keys() {
+        let mut ic = InputController::new();
+        let mut ih = InterruptController::new();
+        let no_keys = DummyInput { keys: vec![] };
+        let start = DummyInput { keys: vec![JoypadKey::Start] };
+
+        // Select buttons
+        ic.store_register(Byte::new(0b0001_0000));
+        ic.handle_input(&no_keys, &mut ih);
+        assert_eq!(ic.load_register(), 0b1101_1111);
+
+        // Pressing a key requests the joypad interrupt immediately
+        ic.set_injected_key(JoypadKey::A, true, &mut ih);
+        assert_eq!(ic.load_register(), 0b1101_1110);
+        assert_eq!(ih.load_if().get() & 0b0001_0000, 0b0001_0000);
+
+        // Injected keys stay pressed when polling the peripherals
+        ic.handle_input(&start, &mut ih);
+        assert_eq!(ic.load_register(), 0b1101_0110);
+        ic.set_injected_key(JoypadKey::A, false, &mut ih);
+        assert_eq!(ic.load_register(), 0b1101_0111);
+        ic.handle_input(&no_keys, &mut ih);
+        assert_eq!(ic.load_register(), 0b1101_1111);
+    }
+
+    #[test]
+    fn test_opposing_directions() {
+        let mut ic = InputController::new();
+        let mut ih = InterruptController::new();
+        let left_right_up = DummyInput {
+            keys: vec![JoypadKey::Left, JoypadKey::Right, JoypadKey::Up],
+        };
+
+        // Select directions
+        ic.store_register(Byte::new(0b0010_0000));
+        ic.handle_input(&left_right_up, &mut ih);
+        assert_eq!(ic.load_register(), 0b1110_1011);
+
+        ic.set_opposing_directions(OpposingDirections::Allow, &mut ih);
+        assert_eq!(ic.load_register(), 0b1110_1000);
+        ic.set_opposing_directions(OpposingDirections::Filter, &mut ih);
+        assert_eq!(ic.load_register(), 0b1110_1011);
+    }
+
+    #[test]
+    fn test_joypad_interrupt() {
+        let mut ic = InputController::new();
+        let mut ih = InterruptController::new();
+        let mut requested = |ic: &mut InputController, keys: Vec<JoypadKey>| {
+            ih.store_if(Byte::zero());
+            ic.handle_input(&DummyInput { keys }, &mut ih);
+            ih.load_if().get() & 0b0001_0000 != 0
+        };
+
+        // Select buttons
+        ic.store_register(Byte::new(0b0001_0000));
+        assert!(!requested(&mut ic, vec![]));
+
+        // Only pressing a selected key requests the interrupt (a high to low
+        // transition), holding or releasing it does not.
+        assert!(!requested(&mut ic, vec![JoypadKey::Up]));
+        assert!(requested(&mut ic, vec![JoypadKey::Up, JoypadKey::B]));
+        assert!(!requested(&mut ic, vec![JoypadKey::Up, JoypadKey::B]));
+        assert!(requested(&mut ic, vec![JoypadKey::Up, JoypadKey::B, JoypadKey::A]));
+        assert!(!requested(&mut ic, vec![JoypadKey::Up]));
+
+        // Selecting the directions while Up is held is a transition, too.
+        ic.store_register(Byte::new(0b0010_0000));
+        assert!(requested(&mut ic, vec![JoypadKey::Up]));
+
+        // Filtered opposing directions are no key press.
+        assert!(!requested(&mut ic, vec![JoypadKey::Up, JoypadKey::Down]));
+    }
 }
@@ -1,8 +1,12 @@
-use crate::primitives::{Byte, Word};
+use crate::{
+    primitives::{Byte, Word},
+    save_state::{SaveStateError, StateReader, StateWriter},
+};
 
 
 /// Manages the IE and IF register as well as the IME flag. This type is also responsible for
 /// requesting interrupts and giving information about when an interrupt should be executed.
+#[derive(Clone)]
 pub struct InterruptController {
     /// Register to enable certain interrupts. The bits in the register belong to the following
     /// interrupts:
@@ -16,6 +20,8 @@ pub struct InterruptController {
     ///                   ↑   +---- Timer
     ///               ↑   +---- Serial
     ///               +---- Joypad
+    ///
+    /// The upper three bits have no function, but are stored and read back as written.
     pub interrupt_enable: Byte,
 
     /// Register to request certain interrupts. The bit <-> interrupt relation in this register
@@ -115,6 +121,19 @@ impl InterruptController {
             Interrupt::Joypad => set_bit(0b0001_0000),
         };
     }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.byte(self.interrupt_enable);
+        w.byte(self.interrupt_flag);
+        w.bool(self.ime);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.interrupt_enable = r.byte()?;
+        self.interrupt_flag = r.byte()?;
+        self.ime = r.bool()?;
+        Ok(())
+    }
 }
 
 /// This represents all interrupts which can occur.
@@ -139,3 +158,68 @@ impl Interrupt {
         }
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use crate::{BiosKind, HardwareModel, cartridge::Cartridge, machine::Machine};
+    use super::*;
+
+    fn dispatch(pc: u16, sp: u16, ie: u8, interrupt_flag: u8) -> Machine {
+        let cartridge = Cartridge::from_bytes(&[0; 0x8000]).unwrap();
+        let mut machine = Machine::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        machine.cpu.pc = Word::new(pc);
+        machine.cpu.sp = Word::new(sp);
+        machine.store_byte(Word::new(0xFFFF), Byte::new(ie));
+        machine.store_byte(Word::new(0xFF0F), Byte::new(interrupt_flag));
+        machine.interrupt_controller.ime = true;
+        machine.step().ok().unwrap();
+        machine
+    }
+
+    #[test]
+    fn test_ie_upper_bits() {
+        // The upper bits of IE are stored, but don't enable anything.
+        let machine = dispatch(0x1234, 0xFFFE, 0b1110_0000, 0b0000_0100);
+        assert_eq!(machine.load_byte(Word::new(0xFFFF)), 0b1110_0000);
+        assert_eq!(machine.cpu.pc, 0x1235);
+
+        let machine = dispatch(0x1234, 0xFFFE, 0b1110_0100, 0b0000_0100);
+        assert_eq!(machine.load_byte(Word::new(0xFFFF)), 0b1110_0100);
+        assert_eq!(machine.cpu.pc, 0x50);
+
+        // The upper byte of PC (0xE0) is pushed to IE before the interrupt is
+        // selected. Only the upper bits are left, so the dispatch is
+        // cancelled. The lower byte is pushed after that.
+        let machine = dispatch(0xE034, 0x0000, 0b0000_0100, 0b0000_0100);
+        assert_eq!(machine.cpu.pc, 0x0000);
+        assert_eq!(machine.load_byte(Word::new(0xFFFF)), 0xE0);
+        assert_eq!(machine.load_byte(Word::new(0xFFFE)), 0x34);
+        assert_eq!(machine.interrupt_controller.interrupt_flag, 0b0000_0100);
+    }
+
+    #[test]
+    fn test_dispatch_cancelled_by_push() {
+        // Normal dispatch of the timer interrupt.
+        let machine = dispatch(0x1234, 0xD000, 0b0000_0100, 0b0000_0100);
+        assert_eq!(machine.cpu.pc, 0x50);
+        assert_eq!(machine.load_word(Word::new(0xCFFE)), 0x1234);
+
+        // The upper byte of PC (0x12) is pushed to IE and disables the timer
+        // interrupt, but enables the joypad interrupt.
+        let machine = dispatch(0x1234, 0x0000, 0b0000_0100, 0b0001_0100);
+        assert_eq!(machine.cpu.pc, 0x60);
+        assert_eq!(machine.interrupt_controller.interrupt_flag, 0b0000_0100);
+
+        // No interrupt is left: the dispatch is cancelled and jumps to 0.
+        let machine = dispatch(0x1234, 0x0000, 0b0000_0100, 0b0000_0100);
+        assert_eq!(machine.cpu.pc, 0x0000);
+        assert_eq!(machine.load_byte(Word::new(0xFFFF)), 0x12);
+        assert_eq!(machine.interrupt_controller.interrupt_flag, 0b0000_0100);
+        assert!(!machine.interrupt_controller.ime);
+
+        // Overwriting IE with the lower byte does not cancel anything.
+        let machine = dispatch(0x1234, 0x0001, 0b0000_0100, 0b0000_0100);
+        assert_eq!(machine.cpu.pc, 0x50);
+    }
+}
@@ -1,91 +1,405 @@
 //! Everything related to memory mapping.
 
-use super::Machine;
+use super::{
+    Machine,
+    hooks::AccessKind,
+    interrupt::Interrupt,
+};
 use crate::{
     primitives::{Word, Byte},
     log::*,
 };
 
 
+/// The regions of the address space. Every access is dispatched to the
+/// component handling the region (see `Region::of`), so special behavior
+/// (like DMA restrictions or banking) can be added per region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Region {
+    /// `0x0000..0x8000`: the cartridge ROM, overlayed by the boot ROM while
+    /// it is mounted.
+    Rom,
+
+    /// `0x8000..0xA000`: video RAM.
+    Vram,
+
+    /// `0xA000..0xC000`: the cartridge RAM.
+    ExternalRam,
+
+    /// `0xC000..0xE000`: work RAM. Its echo at `0xE000..0xFE00` is mapped
+    /// to this region as well.
+    Wram,
+
+    /// `0xFE00..0xFEA0`: object attribute memory.
+    Oam,
+
+    /// `0xFEA0..0xFF00`: not usable.
+    Unusable,
+
+    /// `0xFF00..0xFF80`: IO registers.
+    Io,
+
+    /// `0xFF80..0xFFFF`: high RAM.
+    Hram,
+
+    /// `0xFFFF`: the interrupt enable register.
+    Ie,
+}
+
+impl Region {
+    /// Returns the region of `addr` and the offset of `addr` in it.
+    #[inline(always)]
+    pub(crate) fn of(addr: Word) -> (Self, Word) {
+        let (region, start) = match addr.get() {
+            0x0000..=0x7FFF => (Region::Rom, 0x0000),
+            0x8000..=0x9FFF => (Region::Vram, 0x8000),
+            0xA000..=0xBFFF => (Region::ExternalRam, 0xA000),
+            0xC000..=0xDFFF => (Region::Wram, 0xC000),
+            0xE000..=0xFDFF => (Region::Wram, 0xE000),
+            0xFE00..=0xFE9F => (Region::Oam, 0xFE00),
+            0xFEA0..=0xFEFF => (Region::Unusable, 0xFEA0),
+            0xFF00..=0xFF7F => (Region::Io, 0xFF00),
+            0xFF80..=0xFFFE => (Region::Hram, 0xFF80),
+            0xFFFF => (Region::Ie, 0xFFFF),
+        };
+
+        (region, addr - start)
+    }
+}
+
+
 impl Machine {
     /// Loads a byte from the given address.
     pub fn load_byte(&self, addr: Word) -> Byte {
-        // If DMA is ongoing, only HRAM can be accessed.
-        if self.ppu.oam_dma_status.is_some() && !(0xFF80..0xFFFF).contains(&addr.get()) {
-            Byte::new(0xFF) // TODO: is it really FF?
-        } else {
-            self.load_byte_bypass_dma(addr)
-        }
+        let byte = self.load_byte_silent(addr);
+        self.hooks.on_access(addr, byte, AccessKind::Read);
+        byte
+    }
+
+    /// Like `load_byte`, but the access is not reported to the memory hooks.
+    /// This is used for instruction fetches.
+    pub(crate) fn load_byte_silent(&self, addr: Word) -> Byte {
+        self.oam_dma_conflict(addr).unwrap_or_else(|| self.load_byte_bypass_dma(addr))
     }
 
     /// Loads a byte from the given address, even if DMA is active (this is
     /// mainly used by the DMA precedure itself).
     pub fn load_byte_bypass_dma(&self, addr: Word) -> Byte {
-        match addr.get() {
+        #[cfg(test)]
+        {
+            if let Some(ram) = &self.flat_ram {
+                return ram[addr.get() as usize];
+            }
+        }
+
+        let (region, offset) = Region::of(addr);
+        match region {
             // ROM mounted switch
-            0x0000..=0x00FF if self.bios_mounted() => self.bios[addr],
-
-            0x0000..=0x7FFF => self.cartridge.mbc.load_rom_byte(addr), // Cartridge
-            0x8000..=0x9FFF => self.ppu.load_vram_byte(addr),
-            0xA000..=0xBFFF => self.cartridge.mbc.load_ram_byte(addr - 0xA000), // exram
-            0xC000..=0xDFFF => self.wram[addr - 0xC000], // wram
-            0xE000..=0xFDFF => self.wram[addr - 0xE000], // wram echo
-            0xFE00..=0xFE9F => self.ppu.load_oam_byte(addr), // oam
-            0xFEA0..=0xFEFF => {
+            Region::Rom if addr.get() < 0x100 && self.bios_mounted() => self.bios[addr],
+
+            Region::Rom => self.cartridge.mbc.load_rom_byte(addr),
+            Region::Vram => self.ppu.load_vram_byte(addr),
+            Region::ExternalRam => self.cartridge.mbc.load_ram_byte(offset),
+            Region::Wram => self.wram[offset],
+            Region::Oam => self.ppu.load_oam_byte(addr),
+            Region::Unusable => {
                 // On DMG this returns 0x00
                 // TODO: Add correct CGB behavior
                 Byte::zero()
             }
+            Region::Io => self.load_io_byte(addr),
+            Region::Hram => self.hram[offset],
+            Region::Ie => self.interrupt_controller.interrupt_enable,
+        }
+    }
 
-            // IF register
+    /// Loads a byte from the IO registers (`0xFF00..0xFF80`).
+    fn load_io_byte(&self, addr: Word) -> Byte {
+        match addr.get() {
             0xFF00 => self.input_controller.load_register(),
+            0xFF01..=0xFF02 => self.serial.load_byte(addr),
             0xFF04..=0xFF07 => self.timer.load_byte(addr),
-            0xFF0F => self.interrupt_controller.load_if(),
+            0xFF0F => self.interrupt_controller.load_if(), // IF register
             0xFF10..=0xFF3F => self.sound_controller.load_byte(addr - 0xFF10),
             0xFF40..=0xFF4B => self.ppu.load_io_byte(addr),
-            0xFF01..=0xFF7F => self.io[addr - 0xFF00], // IO registers
-            0xFF80..=0xFFFE => self.hram[addr - 0xFF80], // hram
-            0xFFFF => self.interrupt_controller.interrupt_enable, // IE register
+            _ => self.io[addr - 0xFF00],
         }
     }
 
     /// Stores the given byte at the given address.
-    pub(crate) fn store_byte(&mut self, addr: Word, byte: Byte) {
-        // If DMA is ongoing, only HRAM can be accessed.
-        if self.ppu.oam_dma_status.is_some() && !(0xFF80..0xFFFF).contains(&addr.get()) {
+    ///
+    /// This behaves exactly like a write by the CPU, i.e. writes to MBC or IO
+    /// registers have the usual side effects.
+    pub fn store_byte(&mut self, addr: Word, byte: Byte) {
+        self.hooks.on_access(addr, byte, AccessKind::Write);
+
+        #[cfg(test)]
+        {
+            if let Some(ram) = &mut self.flat_ram {
+                ram[addr.get() as usize] = byte;
+                return;
+            }
+        }
+
+        if self.oam_dma_conflict(addr).is_some() {
             return;
         }
 
-        match addr.get() {
+        let (region, offset) = Region::of(addr);
+        match region {
             // ROM mounted switch
-            0x0000..=0x00FF if self.bios_mounted() => warn!("Wrote to BIOS ROM!"),
-
-            0x0000..=0x7FFF => self.cartridge.mbc.store_rom_byte(addr, byte), // Cartridge
-            0x8000..=0x9FFF => self.ppu.store_vram_byte(addr, byte),
-            0xA000..=0xBFFF => self.cartridge.mbc.store_ram_byte(addr - 0xA000, byte), // exram
-            0xC000..=0xDFFF => self.wram[addr - 0xC000] = byte, // wram
-            0xE000..=0xFDFF => self.wram[addr - 0xE000] = byte, // wram echo
-            0xFE00..=0xFE9F => self.ppu.store_oam_byte(addr, byte), // oam
-            0xFEA0..=0xFEFF => {
+            Region::Rom if addr.get() < 0x100 && self.bios_mounted() => {
+                warn!("Wrote to BIOS ROM!");
+            }
+
+            Region::Rom => {
+                self.cartridge.mbc.store_rom_byte(addr, byte);
+                self.update_trace_banks();
+            }
+            Region::Vram => self.ppu.store_vram_byte(addr, byte),
+            Region::ExternalRam => self.cartridge.mbc.store_ram_byte(offset, byte),
+            Region::Wram => self.wram[offset] = byte,
+            Region::Oam => self.ppu.store_oam_byte(addr, byte),
+            Region::Unusable => {
                 // On DMG writes to this are ignored
                 // TODO: Add correct CGB behavior
                 trace!("Wrote to {} which is in not writable range: 0xFEA0..0xFF00!", addr);
-            },
+            }
+            Region::Io => self.store_io_byte(addr, byte),
+            Region::Hram => self.hram[offset] = byte,
+            Region::Ie => self.interrupt_controller.interrupt_enable = byte,
+        }
+    }
 
+    /// Stores a byte in the IO registers (`0xFF00..0xFF80`).
+    fn store_io_byte(&mut self, addr: Word, byte: Byte) {
+        match addr.get() {
             // Register with flag for mounting/unmounting the BIOS (this is an IO register). To
             // this register may only be written, if the BIOS is mounted. When the BIOS is
             // unmounted, the write access is denied. We assume the Gameboy hardware does the same.
             0xFF50 if !self.bios_mounted() => warn!("Tried to re-mount BIOS!"),
+            0xFF50 => {
+                self.io[addr - 0xFF00] = byte;
+                if !self.bios_mounted() {
+                    self.finish_boot();
+                }
+            }
 
-            // IF register
             0xFF00 => self.input_controller.store_register(byte),
+            0xFF01..=0xFF02 => self.serial.store_byte(addr, byte),
             0xFF04..=0xFF07 => self.timer.store_byte(addr, byte),
-            0xFF0F => self.interrupt_controller.store_if(byte),
+            0xFF0F => self.interrupt_controller.store_if(byte), // IF register
             0xFF10..=0xFF3F => self.sound_controller.store_byte(addr - 0xFF10, byte),
+            0xFF41 => {
+                // The STAT write bug only exists on the DMG and its variants.
+                if !self.model().is_cgb() && self.ppu.stat_write_interrupt() {
+                    self.interrupt_controller.request_interrupt(Interrupt::LcdStat);
+                }
+                self.ppu.store_io_byte(addr, byte);
+            }
             0xFF40..=0xFF4B => self.ppu.store_io_byte(addr, byte),
-            0xFF01..=0xFF7F => self.io[addr - 0xFF00] = byte, // IO registers
-            0xFF80..=0xFFFE => self.hram[addr - 0xFF80] = byte, // hram
-            0xFFFF => self.interrupt_controller.interrupt_enable = byte, // IE register
+            _ => self.io[addr - 0xFF00] = byte,
+        }
+    }
+
+    /// Writes the byte directly into the memory backing `addr`, without any
+    /// side effects. Writes to the cartridge ROM and RAM modify the currently
+    /// mapped bank instead of MBC registers, and VRAM/OAM can be written even
+    /// if the PPU or DMA would block the access. This is meant for debuggers.
+    ///
+    /// IO registers (and IE) have no backing memory; for those, this is the
+    /// same as `store_byte`.
+    pub fn store_byte_raw(&mut self, addr: Word, byte: Byte) {
+        let (region, offset) = Region::of(addr);
+        match region {
+            Region::Rom if addr.get() < 0x100 && self.bios_mounted() => self.bios[addr] = byte,
+            Region::Rom => {
+                let mbc = &self.cartridge.mbc;
+                let idx = match addr.get() {
+                    0x0000..=0x3FFF => mbc.rom_bank0() * 0x4000 + addr.get() as usize,
+                    _ => mbc.rom_bank() * 0x4000 + (addr.get() - 0x4000) as usize,
+                };
+                if let Some(b) = self.cartridge.mbc.rom_mut().get_mut(idx) {
+                    *b = byte;
+                    self.decode_cache.invalidate(idx);
+                }
+            }
+            Region::Vram => {
+                self.ppu.vram[offset] = byte;
+                self.ppu.invalidate_line_cache();
+            }
+            Region::ExternalRam => {
+                let idx = self.cartridge.mbc.ram_bank() * 0x2000 + offset.get() as usize;
+                if let Some(b) = self.cartridge.mbc.ram_mut().get_mut(idx) {
+                    *b = byte;
+                }
+            }
+            Region::Wram => self.wram[offset] = byte,
+            Region::Oam => {
+                self.ppu.oam[offset] = byte;
+                self.ppu.invalidate_line_cache();
+            }
+            Region::Hram => self.hram[offset] = byte,
+            Region::Unusable | Region::Io | Region::Ie => self.store_byte(addr, byte),
+        }
+    }
+
+    /// Reads the byte backing `addr` without any side effects. Unlike
+    /// `load_byte`, memory hooks (watchpoints, access counting) are not
+    /// notified and VRAM/OAM are readable even if the PPU or DMA would block
+    /// the access. Cartridge RAM is read from the currently mapped bank, even
+    /// if it is disabled. This is the counterpart of `store_byte_raw` and
+    /// meant for debuggers and other tools.
+    pub fn peek_byte(&self, addr: Word) -> Byte {
+        #[cfg(test)]
+        {
+            if let Some(ram) = &self.flat_ram {
+                return ram[addr.get() as usize];
+            }
+        }
+
+        let (region, offset) = Region::of(addr);
+        match region {
+            Region::Rom if addr.get() < 0x100 && self.bios_mounted() => self.bios[addr],
+            Region::Rom => self.cartridge.mbc.load_rom_byte(addr),
+            Region::Vram => self.ppu.vram[offset],
+            Region::ExternalRam => {
+                let idx = self.cartridge.mbc.ram_bank() * 0x2000 + offset.get() as usize;
+                self.cartridge.mbc.ram().get(idx).copied().unwrap_or(Byte::new(0xFF))
+            }
+            Region::Wram => self.wram[offset],
+            Region::Oam => self.ppu.oam[offset],
+            Region::Hram => self.hram[offset],
+            Region::Unusable | Region::Io | Region::Ie => self.load_byte_bypass_dma(addr),
+        }
+    }
+
+    /// Reads `len` bytes starting at `start` like `peek_byte`. Addresses wrap
+    /// around after `0xFFFF`.
+    pub fn peek_range(&self, start: Word, len: usize) -> Vec<Byte> {
+        (0..len).map(|i| self.peek_byte(Word::new(start.get().wrapping_add(i as u16)))).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        BiosKind, HardwareModel,
+        cartridge::Cartridge,
+        machine::{hooks::Watchpoint, ppu::Mode},
+        test_util::{NullPeripherals, cartridge_with_code},
+    };
+    use super::*;
+
+    #[test]
+    fn test_regions() {
+        assert_eq!(Region::of(Word::new(0x0150)), (Region::Rom, Word::new(0x0150)));
+        assert_eq!(Region::of(Word::new(0xA123)), (Region::ExternalRam, Word::new(0x0123)));
+        assert_eq!(Region::of(Word::new(0xC010)), (Region::Wram, Word::new(0x0010)));
+        assert_eq!(Region::of(Word::new(0xE010)), (Region::Wram, Word::new(0x0010)));
+        assert_eq!(Region::of(Word::new(0xFEA0)), (Region::Unusable, Word::new(0x0000)));
+        assert_eq!(Region::of(Word::new(0xFFFF)), (Region::Ie, Word::new(0x0000)));
+
+        let cartridge = Cartridge::from_bytes(&[0; 0x8000]).unwrap();
+        let mut machine = Machine::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        machine.store_byte(Word::new(0xE010), Byte::new(0x42));
+        assert_eq!(machine.load_byte(Word::new(0xC010)), Byte::new(0x42));
+
+        // Checking whether the boot ROM is mounted is not an access to the
+        // BIOS register.
+        machine.add_watchpoint(Watchpoint {
+            range: Word::new(0xFF50)..=Word::new(0xFF50),
+            on_read: true,
+            on_write: true,
+        });
+        machine.load_byte(Word::new(0x0000));
+        assert!(machine.watchpoint_hit().is_none());
+    }
+
+    #[test]
+    fn test_access_counts() {
+        let cartridge = Cartridge::from_bytes(&[0; 0x8000]).unwrap();
+        let mut machine = Machine::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        assert!(machine.access_counts().is_none());
+        machine.set_access_counting(true);
+
+        let addr = Word::new(0xC000);
+        machine.hooks.begin_step(Word::new(0x0150));
+        machine.store_byte(addr, Byte::new(1));
+        machine.load_byte(addr);
+        machine.load_byte(addr);
+        machine.hooks.end_step();
+
+        // Accesses from outside of an instruction are not counted.
+        machine.load_byte(addr);
+
+        let counts = machine.access_counts().unwrap();
+        assert_eq!((counts.reads(addr), counts.writes(addr)), (2, 1));
+        counts.reset();
+        assert_eq!(counts.reads(addr), 0);
+    }
+
+    #[test]
+    fn test_peek() {
+        // 0150: JR -2
+        let cartridge = cartridge_with_code(&[0x18, 0xFE]);
+        let mut machine = Machine::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        machine.cpu.pc = Word::new(0x150);
+
+        let (vram, oam) = (Word::new(0x8010), Word::new(0xFE00));
+        machine.store_byte_raw(vram, Byte::new(0x42));
+        machine.store_byte_raw(oam, Byte::new(0x17));
+        machine.store_byte(Word::new(0xFF40), Byte::new(0b1001_0001));
+        for _ in 0..1000 {
+            if machine.ppu.regs().mode() == Mode::PixelTransfer {
+                break;
+            }
+            machine.execute_step(&mut NullPeripherals).ok().unwrap();
+        }
+        assert_eq!(machine.ppu.regs().mode(), Mode::PixelTransfer);
+
+        // The CPU can't access VRAM and OAM during pixel transfer, but
+        // peeking can.
+        assert_eq!(machine.load_byte(vram), Byte::new(0xFF));
+        assert_eq!(machine.peek_byte(vram), Byte::new(0x42));
+        assert_eq!(machine.peek_byte(oam), Byte::new(0x17));
+        assert_eq!(machine.peek_byte(Word::new(0xFF40)), machine.load_byte(Word::new(0xFF40)));
+
+        // Peeking is not reported to the hooks.
+        machine.set_access_counting(true);
+        machine.hooks.begin_step(Word::new(0x0150));
+        machine.peek_range(Word::new(0x8000), 0x2000);
+        machine.load_byte(vram);
+        machine.hooks.end_step();
+        assert_eq!(machine.access_counts().unwrap().reads(vram), 1);
+
+        machine.store_byte(Word::new(0xFFFE), Byte::new(1));
+        machine.store_byte(Word::new(0xFFFF), Byte::new(2));
+        let bytes = machine.peek_range(Word::new(0xFFFE), 3);
+        assert_eq!(bytes, [Byte::new(1), Byte::new(2), machine.load_byte(Word::new(0x0000))]);
+    }
+
+    #[test]
+    fn test_freeze() {
+        // 0150: LD HL, C000; INC (HL); JR -3
+        let cartridge = cartridge_with_code(&[0x21, 0x00, 0xC0, 0x34, 0x18, 0xFD]);
+        let mut machine = Machine::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        machine.cpu.pc = Word::new(0x150);
+        let addr = Word::new(0xC000);
+
+        machine.freeze(addr, Byte::new(9));
+        assert_eq!(machine.frozen(), &[(addr, Byte::new(9))]);
+        for _ in 0..10 {
+            machine.step().ok().unwrap();
+            assert_eq!(machine.load_byte(addr), Byte::new(9));
+        }
+
+        assert!(machine.unfreeze(addr));
+        assert!(!machine.unfreeze(addr));
+        for _ in 0..4 {
+            machine.step().ok().unwrap();
         }
+        assert_eq!(machine.load_byte(addr), Byte::new(11));
     }
 }
@@ -1,14 +1,21 @@
 use crate::{
-    BiosKind,
+    BiosKind, HardwareModel,
     primitives::{Byte, Word, Memory},
     cartridge::{Cartridge},
+    log::*,
+    save_state::{Compression, Metadata, ParsedState, SaveStateError, StateBuilder},
 };
 use self::{
     cpu::Cpu,
+    decode_cache::DecodeCache,
+    hooks::MemoryHooks,
+    trace::Trace,
+    trace_stream::TraceStream,
     ppu::Ppu,
-    interrupt::{InterruptController, Interrupt},
-    input::InputController,
+    interrupt::InterruptController,
+    input::{InputController, JoypadKey, Keys, OpposingDirections},
     timer::Timer,
+    serial::SerialController,
     sound::SoundController,
 };
 
@@ -16,49 +23,102 @@ use self::{
 #[macro_use]
 mod macros;
 
+mod bess;
 pub mod cpu;
+mod decode_cache;
+pub mod diff;
 mod dma;
+mod handlers;
+pub mod hooks;
 mod mm;
 pub mod ppu;
 mod step;
 mod interrupt;
 pub mod input;
-mod sound;
+mod serial;
+pub mod sound;
 mod timer;
+pub mod timeline;
+pub mod trace;
+pub mod trace_stream;
 
+#[cfg(test)]
+mod single_step_tests;
 
+
+/// The complete state of the emulated Game Boy.
+///
+/// Cloning a machine creates an independent snapshot (e.g. for rewinding in
+/// debuggers or exploring inputs, see `Emulator::fork`). The cartridge ROM is
+/// shared between clones and only copied if it is modified.
+#[derive(Clone)]
 pub struct Machine {
     pub cpu: Cpu,
 
     pub cartridge: Cartridge,
 
-    // TODO These should be arrays!
-    pub bios: Memory,
-    pub wram: Memory,
+    /// The emulated Game Boy model.
+    model: HardwareModel,
+
+    /// The boot ROM mounted at power on.
+    bios_kind: BiosKind,
+
+    pub bios: Memory<0x100>,
+    pub wram: Memory<0x2000>,
 
     // TODO: Remove this, if all IO registers are implemented as their one types
-    pub io: Memory,
+    pub io: Memory<0x80>,
 
     pub ppu: Ppu,
     pub(crate) timer: Timer,
 
-    pub hram: Memory,
+    pub hram: Memory<0x7F>,
 
 
     pub(crate) interrupt_controller: InterruptController,
     pub(crate) input_controller: InputController,
     pub(crate) sound_controller: SoundController,
+    pub(crate) serial: SerialController,
 
     /// Because the EI instruction enables the interrupts during the next cycle we have to store
     /// the request for doing this. This is the purpose of this variable.
     pub enable_interrupts_next_step: bool,
 
+    /// Memory hooks used by debuggers (e.g. watchpoints).
+    pub(crate) hooks: MemoryHooks,
+
+    /// Trace of the last executed instructions. Disabled by default.
+    pub(crate) trace: Trace,
+
+    /// Decoded instructions in ROM. Disabled by default.
+    pub(crate) decode_cache: DecodeCache,
+
+    /// Records for the trace stream of the emulator. `None` if disabled.
+    pub(crate) trace_stream: Option<TraceStream>,
+
+    /// Number of steps (instructions, interrupt dispatches and cycles spent
+    /// in HALT or STOP) executed since power on.
+    step_count: u64,
+
+    /// Number of 1MHz cycles executed since power on.
+    cycle_count: u64,
 
     state: State,
+
+    /// If set, all memory accesses go to this 64KiB of RAM instead of the
+    /// memory map. Used to run CPU test vectors.
+    #[cfg(test)]
+    pub(crate) flat_ram: Option<Box<[Byte]>>,
 }
 
 impl Machine {
-    pub(crate) fn new(cartridge: Cartridge, bios_kind: BiosKind) -> Self {
+    pub(crate) fn new(cartridge: Cartridge, bios_kind: BiosKind, model: HardwareModel) -> Self {
+        // Only the boot ROM of the DMG is bundled. The registers are fixed
+        // when it finishes (see `finish_boot`).
+        if bios_kind == BiosKind::Original && model != HardwareModel::Dmg {
+            warn!("[machine] no original boot ROM for {:?}, using the one of the DMG", model);
+        }
+
         let bios_bytes = match bios_kind {
             BiosKind::Original => include_bytes!(
                 concat!(env!("CARGO_MANIFEST_DIR"), "/data/DMG_BIOS_ROM.bin")
@@ -71,24 +131,205 @@ impl Machine {
         Self {
             cpu: Cpu::new(),
             cartridge,
+            model,
+            bios_kind,
             bios: Memory::from_bytes(bios_bytes),
-            wram: Memory::zeroed(Word::new(0x2000)),
+            wram: Memory::zeroed(),
             ppu: Ppu::new(),
             timer: Timer::new(),
-            io: Memory::zeroed(Word::new(0x80)),
-            hram: Memory::zeroed(Word::new(0x7F)),
+            io: Memory::zeroed(),
+            hram: Memory::zeroed(),
             interrupt_controller: InterruptController::new(),
             input_controller: InputController::new(),
             sound_controller: SoundController::new(),
+            serial: SerialController::new(),
             enable_interrupts_next_step: false,
+            hooks: MemoryHooks::new(),
+            trace: Trace::new(),
+            decode_cache: DecodeCache::new(),
+            trace_stream: None,
+            step_count: 0,
+            cycle_count: 0,
             state: State::Normal,
+            #[cfg(test)]
+            flat_ram: None,
+        }
+    }
+
+    /// Returns the emulated Game Boy model.
+    pub fn model(&self) -> HardwareModel {
+        self.model
+    }
+
+    /// Called when the BIOS unmounts itself. Only the original DMG boot ROM
+    /// is available, which leaves the registers as on a DMG. For all other
+    /// combinations, the registers are set to the values the boot ROM of
+    /// the emulated model leaves.
+    pub(crate) fn finish_boot(&mut self) {
+        if self.bios_kind == BiosKind::Original && self.model == HardwareModel::Dmg {
+            return;
         }
+
+        let pc = self.cpu.pc;
+        self.cpu = Cpu::after_boot(self.model, self.cartridge.rom());
+        self.cpu.pc = pc;
     }
 
     pub fn interrupt_controller(&self) -> &InterruptController {
         &self.interrupt_controller
     }
 
+    pub fn timer(&self) -> &Timer {
+        &self.timer
+    }
+
+    pub fn sound_controller(&self) -> &SoundController {
+        &self.sound_controller
+    }
+
+    /// Returns the number of steps executed since power on. A step is either
+    /// one instruction, an interrupt dispatch or one cycle in HALT/STOP mode.
+    pub fn step_count(&self) -> u64 {
+        self.step_count
+    }
+
+    /// Returns the number of 1MHz cycles (machine cycles) executed since
+    /// power on. One machine cycle is four clock cycles.
+    pub fn cycle_count(&self) -> u64 {
+        self.cycle_count
+    }
+
+    /// Returns `true` if the next step executes the instruction at PC, i.e.
+    /// the CPU is neither in HALT/STOP mode nor about to dispatch an
+    /// interrupt.
+    pub fn executes_instruction_next(&self) -> bool {
+        self.state == State::Normal && self.interrupt_controller.should_interrupt().is_none()
+    }
+
+    /// Returns the address of the interrupt service routine that is entered
+    /// in the next step, if an interrupt is about to be dispatched.
+    pub fn next_interrupt_vector(&self) -> Option<Word> {
+        self.interrupt_controller.should_interrupt().map(|i| i.addr())
+    }
+
+    /// Returns the keys that were pressed when the input was last polled.
+    pub fn pressed_keys(&self) -> Keys {
+        self.input_controller.pressed_keys()
+    }
+
+    /// Presses or releases a key, in addition to the keys pressed according
+    /// to `Peripherals::get_pressed_keys`. The key stays in this state until
+    /// it is changed again with this method. This is useful to control the
+    /// emulator from scripts or tests. Like a real button press, this
+    /// requests the joypad interrupt if the key is selected in the input
+    /// register.
+    pub fn set_key(&mut self, key: JoypadKey, is_pressed: bool) {
+        self.input_controller.set_injected_key(key, is_pressed, &mut self.interrupt_controller);
+    }
+
+    /// Sets what the game sees when opposing directions are pressed at the
+    /// same time (see `OpposingDirections`).
+    pub fn set_opposing_directions(&mut self, mode: OpposingDirections) {
+        let ic = &mut self.interrupt_controller;
+        self.input_controller.set_opposing_directions(mode, ic);
+    }
+
+    /// Sets the state of this machine to the state of `snapshot`, which was
+    /// created by cloning a machine. Watchpoints are not restored but kept as
+    /// they are.
+    pub fn restore(&mut self, snapshot: &Machine) {
+        let hooks = std::mem::replace(&mut self.hooks, MemoryHooks::new());
+        let trace_stream = self.trace_stream.take();
+        *self = snapshot.clone();
+        self.hooks = hooks;
+        self.trace_stream = trace_stream;
+        self.update_trace_banks();
+    }
+
+    /// Writes a bank marker into the trace stream if the banks changed.
+    pub(crate) fn update_trace_banks(&mut self) {
+        if let Some(stream) = &mut self.trace_stream {
+            stream.banks(self.cartridge.mbc.rom_bank(), self.cartridge.mbc.ram_bank());
+        }
+    }
+
+    /// Serializes the state of this machine into the format described in
+    /// `save_state`. Memory hooks, the instruction trace and the connection
+    /// of a link cable are not part of the state, and neither are the
+    /// hardware model and the boot ROM.
+    pub fn save_state(&self, compression: Compression) -> Vec<u8> {
+        self.save_state_with_metadata(compression, &Metadata::default())
+    }
+
+    /// Like `save_state`, but additionally stores the given metadata (e.g. a
+    /// thumbnail), which can be read with `save_state::read_info`.
+    pub fn save_state_with_metadata(
+        &self,
+        compression: Compression,
+        metadata: &Metadata,
+    ) -> Vec<u8> {
+        let mut state = StateBuilder::new();
+        state.chunk(b"MACH", |w| {
+            w.u64(self.step_count);
+            w.u64(self.cycle_count);
+            w.u8(match self.state {
+                State::Normal => 0,
+                State::Halted => 1,
+                State::Stopped => 2,
+            });
+            w.bool(self.enable_interrupts_next_step);
+        });
+        state.chunk(b"CPU ", |w| self.cpu.save_state(w));
+        state.chunk(b"INTR", |w| self.interrupt_controller.save_state(w));
+        state.chunk(b"WRAM", |w| w.bytes(self.wram.as_slice()));
+        state.chunk(b"HRAM", |w| w.bytes(self.hram.as_slice()));
+        state.chunk(b"IO  ", |w| w.bytes(self.io.as_slice()));
+        state.chunk(b"PPU ", |w| self.ppu.save_state(w));
+        state.chunk(b"TIMR", |w| self.timer.save_state(w));
+        state.chunk(b"JOYP", |w| self.input_controller.save_state(w));
+        state.chunk(b"APU ", |w| self.sound_controller.save_state(w));
+        state.chunk(b"SERL", |w| self.serial.save_state(w));
+        state.chunk(b"MBC ", |w| self.cartridge.mbc.save_state(w));
+        state.chunk(b"META", |w| metadata.save_state(w));
+        state.finish(&self.cartridge, compression)
+    }
+
+    /// Restores a state created by `save_state` with the same ROM. If an
+    /// error is returned, this machine is not changed.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        let state = ParsedState::parse(data, &self.cartridge)?;
+        let mut machine = self.clone();
+
+        let mut r = state.chunk(b"MACH")?;
+        machine.step_count = r.u64()?;
+        machine.cycle_count = r.u64()?;
+        machine.state = match r.u8()? {
+            0 => State::Normal,
+            1 => State::Halted,
+            2 => State::Stopped,
+            other => {
+                return Err(SaveStateError::Corrupted(format!("invalid CPU state {}", other)));
+            }
+        };
+        machine.enable_interrupts_next_step = r.bool()?;
+
+        machine.cpu.load_state(&mut state.chunk(b"CPU ")?)?;
+        machine.interrupt_controller.load_state(&mut state.chunk(b"INTR")?)?;
+        state.chunk(b"WRAM")?.bytes(machine.wram.as_mut_slice())?;
+        state.chunk(b"HRAM")?.bytes(machine.hram.as_mut_slice())?;
+        state.chunk(b"IO  ")?.bytes(machine.io.as_mut_slice())?;
+        machine.ppu.load_state(&mut state.chunk(b"PPU ")?)?;
+        machine.timer.load_state(&mut state.chunk(b"TIMR")?)?;
+        machine.input_controller.load_state(&mut state.chunk(b"JOYP")?)?;
+        machine.sound_controller.load_state(&mut state.chunk(b"APU ")?)?;
+        machine.serial.load_state(&mut state.chunk(b"SERL")?)?;
+        machine.cartridge.mbc.load_state(&mut state.chunk(b"MBC ")?)?;
+
+        *self = machine;
+        self.update_trace_banks();
+        Ok(())
+    }
+
     pub fn load_word(&self, addr: Word) -> Word {
         // TODO: Check what happens on DMG hardware in this case
         if addr.get() == 0xffff {
@@ -113,7 +354,8 @@ impl Machine {
     }
 
     pub fn bios_mounted(&self) -> bool {
-        (self.load_byte(Word::new(0xFF50)).get() & 0b0000_0001) == 0
+        // Read directly, as this is not an access by the emulated program.
+        (self.io[Word::new(0x50)].get() & 0b0000_0001) == 0
     }
 
     /// Convenience method to load the value, which is stored behind the adress in HL.
@@ -139,18 +381,29 @@ impl Machine {
         val
     }
 
-    /// Jumps to the interrupt service routine of the given interrupt and returns the number
-    /// of clocks used for the jump.
-    pub(crate) fn isr(&mut self, interrupt: Interrupt) -> u8 {
-        // push pc onto stack
-        self.push(self.cpu.pc);
+    /// Jumps to the interrupt service routine of the highest priority interrupt that is
+    /// requested and returns the number of clocks used for the jump.
+    pub(crate) fn isr(&mut self) -> u8 {
+        // Push pc onto the stack, upper byte first.
+        let (lo, hi) = self.cpu.pc.into_bytes();
+        self.cpu.sp -= 1u16;
+        self.store_byte(self.cpu.sp, hi);
+
+        // The interrupt is only selected after pushing the upper byte. If that push overwrote
+        // IE (SP was 0), the interrupt might not be enabled anymore: then the next one is
+        // dispatched instead or, if there is none, the CPU jumps to 0x0000.
+        let interrupt = self.interrupt_controller.requested_interrupt();
+        self.cpu.sp -= 1u16;
+        self.store_byte(self.cpu.sp, lo);
 
         // jump to address
-        self.cpu.pc = interrupt.addr();
+        self.cpu.pc = interrupt.map(|i| i.addr()).unwrap_or(Word::new(0x0000));
 
         // reset interrupts
         self.interrupt_controller.ime = false;
-        self.interrupt_controller.reset_interrupt_flag(interrupt);
+        if let Some(interrupt) = interrupt {
+            self.interrupt_controller.reset_interrupt_flag(interrupt);
+        }
 
         // It takes 20 clocks to dispatch a normal interrupt + 4 clocks when returning
         // from HALT mode.
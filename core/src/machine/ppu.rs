@@ -10,13 +10,17 @@ use crate::{
     env::Peripherals,
     log::*,
     primitives::{Byte, Word, Memory, PixelColor},
+    save_state::{SaveStateError, StateReader, StateWriter},
+};
+use super::{
+    interrupt::{InterruptController, Interrupt},
+    timeline::{EventKind, Timeline},
 };
-use super::interrupt::{InterruptController, Interrupt};
 
 
 
 /// Number of 1MHz cycles per line.
-const CYCLES_PER_LINE: u8 = 114;
+pub const CYCLES_PER_LINE: u8 = 114;
 
 /// Number of lines including the "V-Blank lines". After drawing the 144 lines
 /// on the LCD, the PPU has a V-Blank phase which lasts exactly
@@ -34,6 +38,7 @@ const SPRITE_WIDTH: u8 = 8;
 
 
 /// The (public) registers inside of the PPU.
+#[derive(Clone)]
 pub struct PpuRegisters {
     /// `0xFF40`: LCD control. All bits can be written.
     ///
@@ -294,7 +299,7 @@ impl TileDataArea {
     /// This implements the difference between the two addressing modes. If
     /// `self` is `High`, the given byte is used as signed offset from `0x9000`
     /// as base pointer.
-    fn index(&self, idx: Byte) -> Word {
+    pub fn index(&self, idx: Byte) -> Word {
         match self {
             TileDataArea::Low => {
                 // Simple indexing: we start at the very beginning of the VRAM
@@ -320,19 +325,52 @@ impl fmt::Display for TileDataArea {
 }
 
 /// Pixel processing unit.
+#[derive(Clone)]
 pub struct Ppu {
-    pub vram: Memory,
-    pub oam: Memory,
+    pub vram: Memory<0x2000>,
+    pub oam: Memory<0xA0>,
 
     /// How many cycles did we already spent in this line?
     cycle_in_line: u8,
 
+    /// Number of frames completed since power on (i.e. how often the PPU
+    /// wrapped around from the last line to line 0).
+    frame_count: u64,
+
     /// The cycle of the line in which hblank starts. This is updated for each
     /// line after the pixel transfer mode.
     hblank_trigger: u8,
 
+    /// Whether LY was equal to WY at the start of a line in this frame. The
+    /// window can only be drawn after that, even if WY changes later.
+    window_y_triggered: bool,
+
+    /// The internal line counter of the window: the line of the window drawn
+    /// next. It is only incremented on lines where the window was actually
+    /// drawn and reset at the start of each frame.
+    window_line: u8,
+
     sprites_on_line: [Sprite; 10],
 
+    /// Set when the LCD is disabled: the screen is cleared with the next
+    /// `advance`.
+    clear_screen: bool,
+
+    /// Set when the LCD is enabled: the first frame afterwards is not shown
+    /// on the screen, it stays blank instead.
+    skip_frame: bool,
+
+    /// The greyscale colors of each line as drawn last, and the number of
+    /// lines started since the last change of VRAM, OAM or a register that
+    /// influences the drawn pixels. If nothing changed for a whole frame,
+    /// the pixel transfer is skipped and the line is taken from this cache
+    /// (which is a big win for static screens like menus).
+    line_cache: Box<[[u8; SCREEN_WIDTH]; SCREEN_HEIGHT]>,
+    unchanged_lines: u16,
+
+    /// The timing of events, if enabled (see `Machine::set_timeline_enabled`).
+    pub(crate) timeline: Option<Timeline>,
+
     /// If an DMA is ongoing, this stores the address of the next source byte.
     /// The DMA copies from 0xXX00 to 0xXXF1. The first cycle of the DMA
     /// procedure is spent preparing. Starting with the second cycles, one byte
@@ -341,6 +379,10 @@ pub struct Ppu {
     /// for the setup time.
     pub(crate) oam_dma_status: Option<Word>,
 
+    /// The byte the OAM DMA copied last, i.e. the value on the bus it reads
+    /// from.
+    pub(crate) oam_dma_byte: Byte,
+
     /// All registers. If you want to read registers, use the `regs()` method
     /// instead. That way, we can avoid accidental mutation of any registers.
     registers: PpuRegisters,
@@ -350,17 +392,26 @@ pub struct Ppu {
 impl Ppu {
     pub(crate) fn new() -> Self {
         Self {
-            vram: Memory::zeroed(Word::new(0x2000)),
-            oam: Memory::zeroed(Word::new(0xA0)),
+            vram: Memory::zeroed(),
+            oam: Memory::zeroed(),
 
             cycle_in_line: 0,
+            frame_count: 0,
 
             // It will be overwritten with a smaller number before becoming
             // relevant.
             hblank_trigger: 255,
+            window_y_triggered: false,
+            window_line: 0,
             sprites_on_line: [Sprite::invisible(); 10],
+            clear_screen: false,
+            skip_frame: false,
+            line_cache: Box::new([[0; SCREEN_WIDTH]; SCREEN_HEIGHT]),
+            unchanged_lines: 0,
+            timeline: None,
 
             oam_dma_status: None,
+            oam_dma_byte: Byte::new(0xFF),
             registers: PpuRegisters::new(),
         }
     }
@@ -389,7 +440,11 @@ impl Ppu {
     pub(crate) fn store_vram_byte(&mut self, addr: Word, byte: Byte) {
         match self.regs().mode() {
             Mode::PixelTransfer if self.regs().is_lcd_enabled() => {},
-            _ => self.vram[addr - 0x8000] = byte,
+            _ if self.vram[addr - 0x8000] != byte => {
+                self.vram[addr - 0x8000] = byte;
+                self.invalidate_line_cache();
+            }
+            _ => {}
         }
     }
 
@@ -418,7 +473,11 @@ impl Ppu {
     pub(crate) fn store_oam_byte(&mut self, addr: Word, byte: Byte) {
         match self.regs().mode() {
             Mode::PixelTransfer | Mode::OamSearch if self.regs().is_lcd_enabled() => {},
-            _ => self.oam[addr - 0xFE00] = byte,
+            _ if self.oam[addr - 0xFE00] != byte => {
+                self.oam[addr - 0xFE00] = byte;
+                self.invalidate_line_cache();
+            }
+            _ => {}
         }
     }
 
@@ -459,6 +518,13 @@ impl Ppu {
     /// The given address has to be in `0xFF40..0xFF4B`, otherwise this
     /// function panics!
     pub(crate) fn store_io_byte(&mut self, addr: Word, byte: Byte) {
+        // Only STAT, LY, LYC and the DMA register don't influence the drawn
+        // pixels.
+        let affects_pixels = !matches!(addr.get(), 0xFF41 | 0xFF44 | 0xFF45 | 0xFF46);
+        if affects_pixels && self.load_io_byte(addr) != byte {
+            self.invalidate_line_cache();
+        }
+
         match addr.get() {
             0xFF40 => {
                 let was_enabled = self.regs().is_lcd_enabled();
@@ -466,13 +532,23 @@ impl Ppu {
                 match (was_enabled, self.regs().is_lcd_enabled()) {
                     (false, true) => {
                         info!("[ppu] LCD was enabled");
+                        // The PPU starts at the beginning of line 0, but the
+                        // first frame is not displayed.
                         self.registers.set_mode(Mode::OamSearch);
+                        self.registers.current_line = Byte::new(0);
                         self.cycle_in_line = 0;
-                        // TODO: also reset other stuff?
+                        self.hblank_trigger = 255;
+                        self.reset_window();
+                        self.skip_frame = true;
                     }
                     (true, false) => {
                         info!("[ppu] LCD was disabled");
+                        // LY and the mode read as 0 while the LCD is off and
+                        // the screen turns white.
+                        self.registers.set_mode(Mode::HBlank);
                         self.registers.current_line = Byte::new(0);
+                        self.cycle_in_line = 0;
+                        self.clear_screen = true;
                     }
                     _ => {}
                 }
@@ -491,6 +567,7 @@ impl Ppu {
                 let src_addr = Word::new((byte.get() as u16) * 0x100) - 1;
                 self.oam_dma_status = Some(src_addr);
                 trace!("DMA started from {:?}", src_addr);
+                self.record(EventKind::Dma(byte));
             },
             0xFF47 => self.registers.background_palette = byte,
             0xFF48 => self.registers.sprite_palette_0 = byte,
@@ -501,6 +578,23 @@ impl Ppu {
         }
     }
 
+    /// Returns whether a write to STAT requests an LCD STAT interrupt. On the
+    /// DMG, STAT briefly reads as if all interrupt sources were enabled
+    /// during a write, so the interrupt is requested if the PPU is in H-Blank
+    /// or V-Blank or LY equals LYC. Some games (e.g. Road Rash) rely on this.
+    pub(crate) fn stat_write_interrupt(&self) -> bool {
+        let regs = self.regs();
+        regs.is_lcd_enabled()
+            && (regs.status.get() & 0b0000_0100 != 0
+                || matches!(regs.mode(), Mode::HBlank | Mode::VBlank))
+    }
+
+    /// Has to be called when VRAM, OAM or the registers are modified without
+    /// the `store_*` methods, so that the next frame is drawn completely.
+    pub(crate) fn invalidate_line_cache(&mut self) {
+        self.unchanged_lines = 0;
+    }
+
     /// Disables the LCD by writing 0 to `FF40.7`.
     pub fn disable(&mut self) {
         let new_val = self.regs().lcd_control.map(|b| b & 0b0111_1111);
@@ -518,8 +612,80 @@ impl Ppu {
         &self.registers
     }
 
+    /// Returns the number of 1MHz cycles already spent in the current line
+    /// (`0..CYCLES_PER_LINE`). Multiply by 4 to get the dot position.
+    pub fn cycle_in_line(&self) -> u8 {
+        self.cycle_in_line
+    }
+
+    /// Returns the number of 1MHz cycles since the start of the current frame
+    /// (i.e. since line 0 started).
+    pub fn cycle_in_frame(&self) -> u32 {
+        self.regs().current_line.get() as u32 * CYCLES_PER_LINE as u32
+            + self.cycle_in_line as u32
+    }
+
+    /// Returns the number of frames completed since power on. Frames are not
+    /// counted while the LCD is disabled.
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// Executes `cycles` machine cycles (1 MHz). Cycles in which the PPU does
+    /// nothing but count (e.g. during pixel transfer or H-Blank) are skipped
+    /// all at once.
+    pub(crate) fn advance(
+        &mut self,
+        cycles: u8,
+        peripherals: &mut impl Peripherals,
+        interrupt_controller: &mut InterruptController,
+    ) {
+        // If the whole LCD is disabled, the PPU does nothing but clearing the
+        // screen once.
+        if !self.regs().is_lcd_enabled() {
+            if self.clear_screen {
+                self.clear_screen = false;
+                let blank = [[0; SCREEN_WIDTH]; SCREEN_HEIGHT];
+                for line in 0..SCREEN_HEIGHT as u8 {
+                    write_line(peripherals, line, &blank[line as usize]);
+                }
+                present_frame(peripherals, &blank);
+            }
+            return;
+        }
+
+        let mut remaining = cycles;
+        while remaining > 0 {
+            let idle = self.idle_cycles().min(remaining);
+            if idle > 0 {
+                self.cycle_in_line += idle;
+                remaining -= idle;
+            } else {
+                self.step(peripherals, interrupt_controller);
+                remaining -= 1;
+            }
+        }
+    }
+
+    /// Returns the number of cycles from now in which `step` would only
+    /// increment `cycle_in_line`. This is conservative: the events are
+    /// treated the same in all lines, even if `step` ignores them in V-Blank.
+    fn idle_cycles(&self) -> u8 {
+        // The first cycle of a line starts a mode, the last one starts the
+        // next line.
+        if self.cycle_in_line == 0 {
+            return 0;
+        }
+
+        [20, self.hblank_trigger, CYCLES_PER_LINE - 1].iter()
+            .filter(|&&event| event >= self.cycle_in_line)
+            .min()
+            .map(|&event| event - self.cycle_in_line)
+            .unwrap_or(0)
+    }
+
     /// Executes one machine cycle (1 Mhz).
-    pub(crate) fn step(
+    fn step(
         &mut self,
         peripherals: &mut impl Peripherals,
         interrupt_controller: &mut InterruptController,
@@ -533,7 +699,11 @@ impl Ppu {
         match self.cycle_in_line {
             // ===== Start of OAM search =====================================
             0 if line < SCREEN_HEIGHT as u8 => {
-                self.registers.set_mode(Mode::OamSearch);
+                self.enter_mode(Mode::OamSearch);
+
+                if self.regs().current_line == self.regs().scroll_win_y {
+                    self.window_y_triggered = true;
+                }
 
                 // Potentially trigger LCD stat interrupt. TODO: this
                 // might be only correct for line 0. This might happen
@@ -547,6 +717,7 @@ impl Ppu {
                 // number as LYC.
                 if self.regs().current_line == self.regs().lyc {
                     self.registers.set_coincidence_flag(true);
+                    self.record(EventKind::LycMatch);
 
                     // Potentially trigger interrupt. TODO: this might
                     // be only correct for line 0. This might happen
@@ -570,14 +741,17 @@ impl Ppu {
             // ===== Start of pixel transfer =================================
             20 if line < SCREEN_HEIGHT as u8 => {
                 // TODO: trigger STAT interrupt here?
-                self.registers.set_mode(Mode::PixelTransfer);
+                self.enter_mode(Mode::PixelTransfer);
                 let cycles = self.do_pixel_transfer(peripherals);
                 self.hblank_trigger = 20 + cycles;
+                if self.is_window_on_line() {
+                    self.window_line += 1;
+                }
             }
 
             // ===== Start of H-Blank ========================================
             _ if line < SCREEN_HEIGHT as u8 && self.cycle_in_line == self.hblank_trigger => {
-                self.registers.set_mode(Mode::HBlank);
+                self.enter_mode(Mode::HBlank);
 
                 // Trigger H-Blank interrupt if enabled.
                 if self.regs().hblank_interrupt() {
@@ -587,7 +761,10 @@ impl Ppu {
 
             // ===== Start of V-Blank ========================================
             0 if line == SCREEN_HEIGHT as u8 => {
-                self.registers.set_mode(Mode::VBlank);
+                self.enter_mode(Mode::VBlank);
+
+                // All lines of the frame are in the line cache now.
+                present_frame(peripherals, &self.line_cache);
 
                 // The V-Blank interrupt is always triggered now
                 interrupt_controller.request_interrupt(Interrupt::Vblank);
@@ -611,19 +788,61 @@ impl Ppu {
             // Bump the line and reset a bunch of values.
             self.registers.current_line += 1;
             self.cycle_in_line = 0;
+            self.unchanged_lines = self.unchanged_lines.saturating_add(1);
 
             // Reset line if we reached the last one.
             if self.regs().current_line == NUM_LINES {
                 self.registers.current_line = Byte::new(0);
+                self.frame_count += 1;
+                if let Some(timeline) = &mut self.timeline {
+                    timeline.end_frame();
+                }
+                self.reset_window();
+
+                // The next frame is shown, so it differs from the blank one.
+                if self.skip_frame {
+                    self.skip_frame = false;
+                    self.invalidate_line_cache();
+                }
             }
         }
     }
 
+    /// Sets the mode and records the change in the timeline.
+    fn enter_mode(&mut self, mode: Mode) {
+        self.registers.set_mode(mode);
+        self.record(EventKind::Mode(mode));
+    }
+
+    /// Records an event at the current cycle, if the timeline is enabled.
+    pub(crate) fn record(&mut self, kind: EventKind) {
+        let cycle = self.cycle_in_frame();
+        if let Some(timeline) = &mut self.timeline {
+            timeline.record(cycle, kind);
+        }
+    }
+
+    /// Resets the window state at the start of a frame.
+    fn reset_window(&mut self) {
+        self.window_y_triggered = false;
+        self.window_line = 0;
+    }
+
+    /// Returns whether the window is drawn on the current line: it has to be
+    /// enabled, WY has to be triggered and WX has to be on screen.
+    fn is_window_on_line(&self) -> bool {
+        self.regs().is_window_enabled()
+            && self.window_y_triggered
+            && self.regs().scroll_win_x.get() < SCREEN_WIDTH as u8 + 7
+    }
+
     /// Performs the OAM search.
     ///
     /// Looks through all 40 sprites in the OAM and extracts the first (up to)
-    /// 10 that are drawn on the current line. These are stored in the
-    /// `sprites_on_line` array. If there are fewer than 10 sprites on the
+    /// 10 that are on the current line, in OAM order. Only the y coordinate
+    /// matters for this, so sprites at x = 0 or off screen on the right count
+    /// towards the limit, although they are not visible. These are stored in
+    /// the `sprites_on_line` array. If there are fewer than 10 sprites on the
     /// current line, the remaining entries are `Sprite::invisible`.
     fn do_oam_search(&mut self) {
         let mut next_idx = 0;
@@ -637,7 +856,7 @@ impl Ppu {
             };
 
             let line = self.regs().current_line + 16;
-            if sprite.x != 0 && line >= sprite.y && line < sprite.y + self.regs().sprite_height() {
+            if line >= sprite.y && line < sprite.y + self.regs().sprite_height() {
                 self.sprites_on_line[next_idx] = sprite;
                 next_idx += 1;
 
@@ -654,11 +873,11 @@ impl Ppu {
             self.sprites_on_line[idx] = Sprite::invisible();
         }
 
-        // We sort them here to make drawing them easier. It has to be stable
-        // sort to retain the original order of sprites with the same x
-        // coordinate. We also have to sort them backwards so that sprites that
-        // are more left are drawn on top of others.
-        self.sprites_on_line.sort_by(|sa, sb| sa.x.cmp(&sb.x).reverse());
+        // We sort them by priority here to make drawing them easier: on the
+        // DMG, the sprite more to the left is drawn on top and for equal x
+        // coordinates, the one first in OAM. As the sort is stable, the
+        // latter is already given by the order of the array.
+        self.sprites_on_line.sort_by_key(|sprite| sprite.x);
     }
 
     /// Performs the whole pixel transfer step at once.
@@ -669,12 +888,19 @@ impl Ppu {
     /// graphical effects based on changing some PPU registers during a line
     /// won't work.
     ///
-    /// Returns the number of 1MHz cycles this phase took. This varies
-    /// depending on the `scroll_x % 8`, on the window position and on the
-    /// number of sprites. This number is only an approximation as apparently
-    /// no one exactly knows how to determine the number of cycles. It's
-    /// between 43 and 72 cycles.
-    fn do_pixel_transfer(&self, peripherals: &mut impl Peripherals) -> u8 {
+    /// Returns the number of 1MHz cycles this phase took (see
+    /// `pixel_transfer_cycles`).
+    fn do_pixel_transfer(&mut self, peripherals: &mut impl Peripherals) -> u8 {
+        let line_idx = self.regs().current_line.get();
+
+        // If nothing changed for a whole frame (and a bit, as the change
+        // might have happened after the pixel transfer of its line), this
+        // line looks exactly like in the last frame.
+        if self.unchanged_lines > NUM_LINES as u16 {
+            write_line(peripherals, line_idx, &self.line_cache[line_idx as usize]);
+            return self.pixel_transfer_cycles();
+        }
+
         // ===== Preparations ================================================
 
         /// Helper to fetch background and window tiles.
@@ -765,26 +991,24 @@ impl Ppu {
             ]
         }
 
-        /// Converts the color number to a real color depending on the given
-        /// palette.
+        /// Converts the color number to a greyscale color depending on the
+        /// given palette.
         #[inline(always)]
-        fn pattern_to_color(pattern: u8, palette: Byte) -> PixelColor {
+        fn pattern_to_color(pattern: u8, palette: Byte) -> u8 {
             // The palette contains four color values. Bit0 and bit1 define the
             // color for the color number 0, bit2 and bit3 for color number 1
             // and so on.
-            let color = (palette.get() >> (pattern * 2)) & 0b11;
-            PixelColor::from_greyscale(color)
+            (palette.get() >> (pattern * 2)) & 0b11
         }
 
 
         // ===== Draw ========================================================
-        let mut line = [PixelColor::from_greyscale(0); SCREEN_WIDTH];
+        let mut line = [0; SCREEN_WIDTH];
         let mut background_zero = [true; SCREEN_WIDTH]; // TODO: maybe use bit array
 
 
         // ----- Draw the background and window ------------------------------
-        let window_visible = self.regs().is_window_enabled()
-            && self.regs().scroll_win_y <= self.regs().current_line;
+        let window_visible = self.is_window_on_line();
         let win_scroll_x = self.regs().scroll_win_x.get();
 
         // Create and prime the prefetcher to fetch background tiles
@@ -809,7 +1033,7 @@ impl Ppu {
                 fetcher.prime(
                     self.regs().window_tile_map_address().start(),
                     0,
-                    (self.regs().current_line - self.regs().scroll_win_y).get(),
+                    self.window_line,
                 );
                 needs_update = true;
             }
@@ -833,6 +1057,11 @@ impl Ppu {
         }
 
         // ----- Draw sprites ------------------------------------------------
+        // The sprites are sorted by priority. For each pixel, only the first
+        // sprite with a non-transparent pixel there is considered: if that
+        // one is behind the background, lower priority sprites don't show
+        // up either.
+        let mut sprite_drawn = [false; SCREEN_WIDTH];
         let sprite_height = self.regs().sprite_height();
         for sprite in &self.sprites_on_line {
             let x = sprite.x.get();
@@ -900,7 +1129,12 @@ impl Ppu {
 
                 // If the pattern is 0, the pixel is translucent and is not
                 // drawn.
-                if pattern != 0 && (sprite.is_always_at_top() || background_zero[screen_col]) {
+                if pattern == 0 || sprite_drawn[screen_col] {
+                    continue;
+                }
+
+                sprite_drawn[screen_col] = true;
+                if sprite.is_always_at_top() || background_zero[screen_col] {
                     let color = pattern_to_color(pattern, palette);
                     line[screen_col] = color;
                 }
@@ -909,10 +1143,191 @@ impl Ppu {
 
 
         // ===== Send the line to the actual display =========================
-        peripherals.write_lcd_line(self.regs().current_line.get(), &line);
+        if self.skip_frame {
+            line = [0; SCREEN_WIDTH];
+        }
+        self.line_cache[line_idx as usize] = line;
+        write_line(peripherals, line_idx, &line);
+
+        self.pixel_transfer_cycles()
+    }
+
+    /// Returns the length of the pixel transfer of the current line in 1MHz
+    /// cycles. This varies depending on the `scroll_x % 8`, on the window and
+    /// on the sprites on the line, and is between 43 and 74 cycles.
+    ///
+    /// This uses the dot (4MHz) counts documented in the Pan Docs: 172 dots,
+    /// plus `scroll_x % 8` dots for the pixels discarded at the start of the
+    /// line, 6 dots if the window is drawn and 6 to 11 dots per sprite. This
+    /// is still only an approximation, e.g. the penalties of sprites on top
+    /// of the window are calculated as if they were on the background.
+    ///
+    /// With `FAST`, this always returns 43.
+    fn pixel_transfer_cycles(&self) -> u8 {
+        if crate::FAST {
+            return 43;
+        }
 
-        // TODO: make more precise
-        43
+        let fine_scroll = self.regs().scroll_bg_x.get() % 8;
+        let mut dots = 172 + fine_scroll as u16;
+        if self.is_window_on_line() {
+            dots += 6;
+        }
+
+        // Each sprite stalls the pixel pipeline for 6 dots. Additionally, the
+        // first sprite on each background tile has to wait until that tile
+        // is fetched, which takes longer the further left the sprite starts
+        // in the tile. Sprites at x = 0 always wait the maximum of 5 dots.
+        let mut tiles_with_sprite = 0u32;
+        for sprite in self.sprites_on_line.iter().filter(|s| s.x.get() < 168) {
+            dots += 6;
+
+            let pos = sprite.x.get() + fine_scroll;
+            let tile = 1 << (pos / 8);
+            if tiles_with_sprite & tile == 0 {
+                tiles_with_sprite |= tile;
+                let in_tile = if sprite.x == 0 { 0 } else { pos % 8 };
+                dots += 5u16.saturating_sub(in_tile as u16);
+            }
+        }
+
+        // The pixel transfer ends in the middle of a cycle in most cases.
+        dots.div_ceil(4) as u8
+    }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(self.vram.as_slice());
+        w.bytes(self.oam.as_slice());
+        w.u8(self.cycle_in_line);
+        w.u64(self.frame_count);
+        w.u8(self.hblank_trigger);
+        w.bool(self.window_y_triggered);
+        w.u8(self.window_line);
+        for sprite in &self.sprites_on_line {
+            for &b in &[sprite.y, sprite.x, sprite.tile_idx, sprite.flags] {
+                w.byte(b);
+            }
+        }
+        w.bool(self.clear_screen);
+        w.bool(self.skip_frame);
+        w.bool(self.oam_dma_status.is_some());
+        w.word(self.oam_dma_status.unwrap_or(Word::new(0)));
+        w.byte(self.oam_dma_byte);
+
+        let regs = &self.registers;
+        for &b in &[
+            regs.lcd_control, regs.status, regs.scroll_bg_y, regs.scroll_bg_x,
+            regs.current_line, regs.lyc, regs.oam_dma_start, regs.background_palette,
+            regs.sprite_palette_0, regs.sprite_palette_1, regs.scroll_win_y, regs.scroll_win_x,
+        ] {
+            w.byte(b);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        r.bytes(self.vram.as_mut_slice())?;
+        r.bytes(self.oam.as_mut_slice())?;
+        self.cycle_in_line = r.u8()?;
+        if self.cycle_in_line >= CYCLES_PER_LINE {
+            return Err(SaveStateError::Corrupted("invalid PPU cycle".into()));
+        }
+        self.frame_count = r.u64()?;
+        self.hblank_trigger = r.u8()?;
+        self.window_y_triggered = r.bool()?;
+        self.window_line = r.u8()?;
+        for sprite in &mut self.sprites_on_line {
+            for b in [&mut sprite.y, &mut sprite.x, &mut sprite.tile_idx, &mut sprite.flags] {
+                *b = r.byte()?;
+            }
+        }
+        self.clear_screen = r.bool()?;
+        self.skip_frame = r.bool()?;
+        let dma_ongoing = r.bool()?;
+        let dma_addr = r.word()?;
+        self.oam_dma_status = if dma_ongoing { Some(dma_addr) } else { None };
+        self.oam_dma_byte = r.byte()?;
+        self.invalidate_line_cache();
+
+        let regs = &mut self.registers;
+        for b in [
+            &mut regs.lcd_control, &mut regs.status, &mut regs.scroll_bg_y,
+            &mut regs.scroll_bg_x, &mut regs.current_line, &mut regs.lyc,
+            &mut regs.oam_dma_start, &mut regs.background_palette, &mut regs.sprite_palette_0,
+            &mut regs.sprite_palette_1, &mut regs.scroll_win_y, &mut regs.scroll_win_x,
+        ] {
+            *b = r.byte()?;
+        }
+        if regs.current_line.get() >= NUM_LINES {
+            return Err(SaveStateError::Corrupted("invalid LY".into()));
+        }
+
+        // The window line counter is incremented at most once per line,
+        // including the current one once its pixel transfer is done. While
+        // the LCD is off, LY is 0 and the counter is reset when it's enabled.
+        let line = regs.current_line.get();
+        if regs.is_lcd_enabled() && self.window_line > line + 1 {
+            return Err(SaveStateError::Corrupted("invalid window line".into()));
+        }
+
+        // Between the OAM search and the pixel transfer, the found sprites
+        // have to be on the current line (or be unused entries), otherwise
+        // drawing them fails.
+        if line < SCREEN_HEIGHT as u8 && (1..=20).contains(&self.cycle_in_line) {
+            let on_line = |s: &Sprite| {
+                (line + 1..=line + 16).contains(&s.y.get()) || s.y == 0 && s.x == 255
+            };
+            if !self.sprites_on_line.iter().all(on_line) {
+                return Err(SaveStateError::Corrupted("invalid sprite on line".into()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Restores the registers `FF40` to `FF4B` from `values` without any side
+    /// effects of writes (e.g. starting a DMA). This is used for save states
+    /// that don't contain the internal PPU state: the current line is
+    /// restarted and the window is assumed to be triggered if `WY` was
+    /// already passed.
+    pub(crate) fn restore_registers(&mut self, values: &[Byte]) {
+        let regs = &mut self.registers;
+        for (b, &v) in [
+            &mut regs.lcd_control, &mut regs.status, &mut regs.scroll_bg_y,
+            &mut regs.scroll_bg_x, &mut regs.current_line, &mut regs.lyc,
+            &mut regs.oam_dma_start, &mut regs.background_palette, &mut regs.sprite_palette_0,
+            &mut regs.sprite_palette_1, &mut regs.scroll_win_y, &mut regs.scroll_win_x,
+        ].iter_mut().zip(values) {
+            **b = v;
+        }
+        regs.status = regs.status.map(|b| b & 0b0111_1111);
+
+        self.cycle_in_line = 0;
+        self.hblank_trigger = 255;
+        self.window_y_triggered = self.registers.current_line > self.registers.scroll_win_y;
+        self.window_line = 0;
+        self.clear_screen = false;
+        self.skip_frame = false;
+        self.oam_dma_status = None;
+        self.invalidate_line_cache();
+    }
+}
+
+/// Hands a finished line (in greyscale) to the peripherals, unless they take
+/// full frames.
+fn write_line<P: Peripherals>(peripherals: &mut P, line_idx: u8, line: &[u8; SCREEN_WIDTH]) {
+    if !P::FULL_FRAMES {
+        peripherals.write_lcd_line(line_idx, &line.map(PixelColor::from_greyscale));
+    }
+}
+
+/// Hands a finished frame (in greyscale) to the peripherals, if they take
+/// full frames.
+fn present_frame<P: Peripherals>(
+    peripherals: &mut P,
+    lines: &[[u8; SCREEN_WIDTH]; SCREEN_HEIGHT],
+) {
+    if P::FULL_FRAMES {
+        peripherals.present_frame(&lines.map(|line| line.map(PixelColor::from_greyscale)));
     }
 }
 
@@ -1013,3 +1428,398 @@ impl Sprite {
         (self.flags.get() & 0b1000_0000) == 0
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        BiosKind, HardwareModel,
+        cartridge::Cartridge,
+        env::Frame,
+        machine::{Machine, input::Keys},
+        save_state::Compression,
+        test_util::NullPeripherals,
+    };
+
+    /// Stores the greyscale colors of line 0.
+    struct Capture([u8; SCREEN_WIDTH]);
+
+    impl Peripherals for Capture {
+        fn write_lcd_line(&mut self, line_idx: u8, pixels: &[PixelColor; SCREEN_WIDTH]) {
+            if line_idx == 0 {
+                for (out, pixel) in self.0.iter_mut().zip(pixels.iter()) {
+                    *out = (0..4)
+                        .find(|&c| PixelColor::from_greyscale(c).to_srgb() == pixel.to_srgb())
+                        .unwrap();
+                }
+            }
+        }
+        fn get_pressed_keys(&self) -> Keys {
+            Keys::none()
+        }
+        fn offer_sound_sample(&mut self, _: impl FnOnce(f32) -> f32) {}
+    }
+
+    /// Draws line 0 with the given sprites (y, x, tile, flags) in OAM. Tile
+    /// 0 (the background) has color 2 in its left half, tile 1 has color 3
+    /// and tile 2 color 1.
+    fn draw_sprites(sprites: &[[u8; 4]]) -> [u8; SCREEN_WIDTH] {
+        let mut ppu = Ppu::new();
+        for (i, &(lo, hi)) in [(0x00, 0xF0), (0xFF, 0xFF), (0xFF, 0x00)].iter().enumerate() {
+            ppu.vram[Word::new(i as u16 * 16)] = Byte::new(lo);
+            ppu.vram[Word::new(i as u16 * 16 + 1)] = Byte::new(hi);
+        }
+        let oam = sprites.iter().flatten().map(|&b| Byte::new(b)).collect::<Vec<_>>();
+        ppu.oam.copy_from_slice(Word::new(0), &oam);
+
+        // LCD on, tile data at 0x8000, identity palettes.
+        ppu.store_io_byte(Word::new(0xFF40), Byte::new(0b1001_0000));
+        for &addr in &[0xFF47, 0xFF48, 0xFF49] {
+            ppu.store_io_byte(Word::new(addr), Byte::new(0b1110_0100));
+        }
+
+        // The first frame after enabling the LCD is not shown.
+        let mut capture = Capture([0; SCREEN_WIDTH]);
+        let mut ic = InterruptController::new();
+        for _ in 0..=NUM_LINES {
+            ppu.advance(CYCLES_PER_LINE, &mut capture, &mut ic);
+        }
+        capture.0
+    }
+
+    #[test]
+    fn test_advance_in_chunks() {
+        let mut chunked = Ppu::new();
+        chunked.enable();
+        // Enable all STAT interrupts and the LYC coincidence for line 5.
+        chunked.store_io_byte(Word::new(0xFF41), Byte::new(0b0111_1000));
+        chunked.store_io_byte(Word::new(0xFF45), Byte::new(5));
+        let mut single = chunked.clone();
+        let mut chunked_ic = InterruptController::new();
+        let mut single_ic = InterruptController::new();
+
+        // After some iterations: the cycle in the frame, LY, STAT and the
+        // interrupts requested in that iteration.
+        let checkpoints = [
+            (5, 21, 0, 0x7B, 0x00),
+            (18, 64, 0, 0x78, 0x02),
+            (164, 573, 5, 0x7E, 0x02),
+            (4691, 16422, 144, 0x79, 0x03),
+            (5016, 1, 0, 0x7A, 0x02),
+            (11_999, 6888, 60, 0x7B, 0x00),
+        ];
+
+        // A bit more than two frames
+        for i in 0..12_000 {
+            let cycles = (i % 6) as u8 + 1;
+            chunked.advance(cycles, &mut NullPeripherals, &mut chunked_ic);
+            for _ in 0..cycles {
+                single.advance(1, &mut NullPeripherals, &mut single_ic);
+            }
+
+            assert_eq!(chunked.cycle_in_frame(), single.cycle_in_frame());
+            assert_eq!(chunked.regs().status, single.regs().status);
+            assert_eq!(chunked.frame_count(), single.frame_count());
+            assert_eq!(chunked_ic.interrupt_flag, single_ic.interrupt_flag);
+
+            if let Some(&(_, cycle, line, status, flags)) =
+                checkpoints.iter().find(|c| c.0 == i)
+            {
+                assert_eq!(chunked.cycle_in_frame(), cycle);
+                assert_eq!(chunked.regs().current_line, Byte::new(line));
+                assert_eq!(chunked.regs().status, Byte::new(status));
+                assert_eq!(chunked_ic.interrupt_flag, Byte::new(flags));
+            }
+
+            // Clear the flags to detect the next request as well.
+            chunked_ic.interrupt_flag = Byte::zero();
+            single_ic.interrupt_flag = Byte::zero();
+        }
+        assert_eq!(chunked.frame_count(), 2);
+    }
+
+    #[test]
+    fn test_lcd_disable_enable() {
+        let mut ppu = Ppu::new();
+        let mut ic = InterruptController::new();
+        let mut capture = Capture([0; SCREEN_WIDTH]);
+        let mut run_frame = |ppu: &mut Ppu| {
+            for _ in 0..NUM_LINES {
+                ppu.advance(CYCLES_PER_LINE, &mut capture, &mut ic);
+            }
+            capture.0[0]
+        };
+
+        // The background is black everywhere, but the first frame stays
+        // white.
+        ppu.store_io_byte(Word::new(0xFF47), Byte::new(0xFF));
+        ppu.enable();
+        assert_eq!(run_frame(&mut ppu), 0);
+        assert_eq!(run_frame(&mut ppu), 3);
+
+        // Disabling in the middle of a frame resets LY and the mode and
+        // clears the screen.
+        ppu.advance(CYCLES_PER_LINE, &mut NullPeripherals, &mut InterruptController::new());
+        ppu.advance(30, &mut NullPeripherals, &mut InterruptController::new());
+        ppu.disable();
+        assert_eq!(ppu.load_io_byte(Word::new(0xFF44)), 0);
+        assert_eq!(ppu.load_io_byte(Word::new(0xFF41)).get() & 0b11, 0);
+        assert_eq!(run_frame(&mut ppu), 0);
+        assert_eq!(ppu.regs().current_line, 0);
+
+        // Enabling restarts at the beginning of line 0.
+        ppu.enable();
+        assert_eq!(ppu.cycle_in_frame(), 0);
+        assert_eq!(run_frame(&mut ppu), 0);
+        assert_eq!(run_frame(&mut ppu), 3);
+    }
+
+    /// Counts the presented frames and remembers whether the last one was
+    /// blank.
+    #[derive(Default)]
+    struct Frames {
+        count: usize,
+        blank: bool,
+    }
+
+    impl Peripherals for Frames {
+        const FULL_FRAMES: bool = true;
+
+        fn write_lcd_line(&mut self, _: u8, _: &[PixelColor; SCREEN_WIDTH]) {
+            panic!("line written although full frames are requested");
+        }
+        fn present_frame(&mut self, frame: &Frame) {
+            let white = PixelColor::from_greyscale(0).to_srgb();
+            self.count += 1;
+            self.blank = frame.iter().flatten().all(|p| p.to_srgb() == white);
+        }
+        fn get_pressed_keys(&self) -> Keys {
+            Keys::none()
+        }
+        fn offer_sound_sample(&mut self, _: impl FnOnce(f32) -> f32) {}
+    }
+
+    #[test]
+    fn test_full_frames() {
+        // LCD on with a black background.
+        let mut ppu = Ppu::new();
+        for i in 0..16 {
+            ppu.vram[Word::new(i)] = Byte::new(0xFF);
+        }
+        ppu.store_io_byte(Word::new(0xFF47), Byte::new(0xFF));
+        ppu.store_io_byte(Word::new(0xFF40), Byte::new(0b1001_0001));
+
+        let mut frames = Frames::default();
+        let mut ic = InterruptController::new();
+        let mut run_lines = |ppu: &mut Ppu, frames: &mut Frames, lines: u8| {
+            for _ in 0..lines {
+                ppu.advance(CYCLES_PER_LINE, frames, &mut ic);
+            }
+        };
+
+        // The first frame after enabling the LCD is blank.
+        run_lines(&mut ppu, &mut frames, NUM_LINES);
+        assert_eq!(frames.count, 1);
+        assert!(frames.blank);
+        run_lines(&mut ppu, &mut frames, NUM_LINES);
+        assert_eq!(frames.count, 2);
+        assert!(!frames.blank);
+
+        // Disabling the LCD mid-frame presents one blank frame, but not the
+        // half drawn one.
+        run_lines(&mut ppu, &mut frames, 50);
+        ppu.store_io_byte(Word::new(0xFF40), Byte::new(0b0001_0001));
+        run_lines(&mut ppu, &mut frames, NUM_LINES);
+        assert_eq!(frames.count, 3);
+        assert!(frames.blank);
+    }
+
+    #[test]
+    fn test_line_cache() {
+        let mut ppu = Ppu::new();
+        let mut ic = InterruptController::new();
+        let mut capture = Capture([0; SCREEN_WIDTH]);
+        let mut run_frame = |ppu: &mut Ppu| {
+            for _ in 0..NUM_LINES {
+                ppu.advance(CYCLES_PER_LINE, &mut capture, &mut ic);
+            }
+            capture.0[0]
+        };
+
+        // Tile 0 has color 1 everywhere.
+        for i in 0..8 {
+            ppu.store_vram_byte(Word::new(0x8000 + i * 2), Byte::new(0xFF));
+        }
+        ppu.store_io_byte(Word::new(0xFF40), Byte::new(0b1001_0001));
+        ppu.store_io_byte(Word::new(0xFF47), Byte::new(0b1110_0100));
+        for _ in 0..3 {
+            run_frame(&mut ppu);
+        }
+        assert_eq!(run_frame(&mut ppu), 1);
+        assert!(ppu.unchanged_lines > NUM_LINES as u16);
+
+        // Modifying VRAM without `store_vram_byte` is not noticed...
+        ppu.vram[Word::new(1)] = Byte::new(0xFF);
+        assert_eq!(run_frame(&mut ppu), 1);
+        ppu.invalidate_line_cache();
+        assert_eq!(run_frame(&mut ppu), 3);
+
+        // ... but stores and register changes are. Writing the same value is
+        // no change.
+        ppu.store_io_byte(Word::new(0xFF47), Byte::new(0b1110_0100));
+        assert!(ppu.unchanged_lines > 0);
+        ppu.store_io_byte(Word::new(0xFF47), Byte::new(0b0001_1011));
+        assert_eq!(ppu.unchanged_lines, 0);
+        assert_eq!(run_frame(&mut ppu), 0);
+        ppu.store_vram_byte(Word::new(0x8000), Byte::new(0x00));
+        assert_eq!(run_frame(&mut ppu), 1);
+        assert_eq!(run_frame(&mut ppu), 1);
+    }
+
+    #[test]
+    fn test_stat_write_interrupt() {
+        let mut ppu = Ppu::new();
+        let mut ic = InterruptController::new();
+        assert!(!ppu.stat_write_interrupt());
+
+        ppu.store_io_byte(Word::new(0xFF45), Byte::new(100));
+        ppu.enable();
+        ppu.advance(1, &mut NullPeripherals, &mut ic);
+        assert_eq!(ppu.regs().mode(), Mode::OamSearch);
+        assert!(!ppu.stat_write_interrupt());
+        ppu.advance(20, &mut NullPeripherals, &mut ic);
+        assert_eq!(ppu.regs().mode(), Mode::PixelTransfer);
+        assert!(!ppu.stat_write_interrupt());
+        ppu.advance(50, &mut NullPeripherals, &mut ic);
+        assert_eq!(ppu.regs().mode(), Mode::HBlank);
+        assert!(ppu.stat_write_interrupt());
+
+        // LY = LYC triggers in all modes.
+        ppu.store_io_byte(Word::new(0xFF45), Byte::new(1));
+        ppu.advance(CYCLES_PER_LINE - 70, &mut NullPeripherals, &mut ic);
+        assert_eq!(ppu.regs().mode(), Mode::OamSearch);
+        assert!(ppu.stat_write_interrupt());
+    }
+
+    #[test]
+    fn test_window_line_counter() {
+        let mut ppu = Ppu::new();
+        let mut ic = InterruptController::new();
+        let mut run_lines = |ppu: &mut Ppu, lines| {
+            for _ in 0..lines {
+                ppu.advance(CYCLES_PER_LINE, &mut NullPeripherals, &mut ic);
+            }
+        };
+
+        // LCD and window on, window starts at line 10 on the left border.
+        ppu.store_io_byte(Word::new(0xFF40), Byte::new(0b1010_0000));
+        ppu.store_io_byte(Word::new(0xFF4A), Byte::new(10));
+        ppu.store_io_byte(Word::new(0xFF4B), Byte::new(7));
+        run_lines(&mut ppu, 20);
+        assert_eq!(ppu.window_line, 10);
+
+        // Lines without window don't count.
+        ppu.store_io_byte(Word::new(0xFF4B), Byte::new(200));
+        run_lines(&mut ppu, 10);
+        assert_eq!(ppu.window_line, 10);
+
+        // Changing WY after it was triggered neither hides nor resets the
+        // window.
+        ppu.store_io_byte(Word::new(0xFF4A), Byte::new(100));
+        ppu.store_io_byte(Word::new(0xFF4B), Byte::new(7));
+        run_lines(&mut ppu, 10);
+        assert_eq!(ppu.window_line, 20);
+
+        // The next frame starts at the top of the window, but WY is not
+        // reached until line 100.
+        run_lines(&mut ppu, NUM_LINES as usize - 40 + 50);
+        assert_eq!(ppu.regs().current_line, 50);
+        assert_eq!(ppu.window_line, 0);
+    }
+
+    #[test]
+    fn test_sprite_priority() {
+        // Equal x: the first sprite in OAM wins.
+        let line = draw_sprites(&[[16, 20, 1, 0], [16, 20, 2, 0]]);
+        assert_eq!(line[12..20], [3; 8]);
+        let line = draw_sprites(&[[16, 20, 2, 0], [16, 20, 1, 0]]);
+        assert_eq!(line[12..20], [1; 8]);
+
+        // Different x: the left one wins, regardless of the OAM order.
+        let line = draw_sprites(&[[16, 24, 2, 0], [16, 20, 1, 0]]);
+        assert_eq!(line[12..20], [3; 8]);
+        assert_eq!(line[20..24], [1; 4]);
+
+        // The winning sprite is behind the background, so the background is
+        // visible where it is not 0, even though the other sprite is not.
+        let line = draw_sprites(&[[16, 8, 1, 0b1000_0000], [16, 8, 2, 0]]);
+        assert_eq!(line[0..8], [2, 2, 2, 2, 3, 3, 3, 3]);
+    }
+
+    #[test]
+    fn test_sprite_limit() {
+        // Ten sprites on the line, but hidden at x = 0. The eleventh is not
+        // drawn.
+        let mut sprites = vec![[16, 0, 1, 0]; 10];
+        sprites.push([16, 50, 1, 0]);
+        assert_eq!(draw_sprites(&sprites)[..], draw_sprites(&sprites[..10])[..]);
+
+        // Sprites on other lines don't count.
+        sprites[0][0] = 100;
+        let line = draw_sprites(&sprites);
+        assert_eq!(line[42..50], [3; 8]);
+    }
+
+    #[test]
+    #[cfg_attr(all(feature = "fast", not(feature = "accuracy")), ignore)]
+    fn test_pixel_transfer_cycles() {
+        let cycles = |scroll_x: u8, window: bool, sprite_xs: &[u8]| {
+            let mut ppu = Ppu::new();
+            ppu.store_io_byte(Word::new(0xFF40), Byte::new(0b1010_0000));
+            ppu.store_io_byte(Word::new(0xFF43), Byte::new(scroll_x));
+            ppu.window_y_triggered = window;
+            for (i, &x) in sprite_xs.iter().enumerate() {
+                ppu.sprites_on_line[i].x = Byte::new(x);
+            }
+            ppu.pixel_transfer_cycles()
+        };
+
+        assert_eq!(cycles(0, false, &[]), 43);
+        assert_eq!(cycles(3, false, &[]), 44);
+        assert_eq!(cycles(0, true, &[]), 45);
+
+        // 172 + 6 + 5 dots, the second sprite on the same tile only adds 6.
+        assert_eq!(cycles(0, false, &[8]), 46);
+        assert_eq!(cycles(0, false, &[8, 9]), 48);
+        assert_eq!(cycles(0, false, &[13]), 45);
+        assert_eq!(cycles(3, false, &[10]), 46);
+        assert_eq!(cycles(3, false, &[0]), 47);
+
+        // Sprites off screen on the right don't stall.
+        assert_eq!(cycles(0, false, &[168]), 43);
+
+        // The maximum: window, scrolling and ten sprites at the start of
+        // different tiles.
+        let sprite_xs = [1, 9, 17, 25, 33, 41, 49, 57, 65, 73];
+        assert_eq!(cycles(7, true, &sprite_xs), 74);
+    }
+
+    #[test]
+    fn test_load_invalid_state() {
+        let cartridge = Cartridge::from_bytes(&[0; 0x8000]).unwrap();
+        let machine = Machine::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+
+        let mut cycle = machine.clone();
+        cycle.ppu.cycle_in_line = CYCLES_PER_LINE;
+        let mut line = machine.clone();
+        line.ppu.registers.current_line = Byte::new(NUM_LINES);
+        for corrupted in [cycle, line] {
+            let state = corrupted.save_state(Compression::None);
+            assert!(matches!(
+                machine.clone().load_state(&state),
+                Err(SaveStateError::Corrupted(_)),
+            ));
+        }
+    }
+}
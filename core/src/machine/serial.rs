@@ -0,0 +1,179 @@
+use crate::{
+    primitives::{Byte, Word},
+    machine::interrupt::{InterruptController, Interrupt},
+    save_state::{SaveStateError, StateReader, StateWriter},
+};
+
+
+/// Number of machine cycles a transfer with the internal clock (8192Hz, one
+/// bit per clock) takes.
+const TRANSFER_CYCLES: u16 = 8 * 128;
+
+/// Manages the serial port (link cable), mapped to FF01 and FF02.
+///
+/// Without a connected link cable (see `link`), transfers with the internal
+/// clock receive `0xFF` and transfers with the external clock never finish.
+/// With a cable, the byte is exchanged with the other machine when a transfer
+/// with the internal clock is finished.
+#[derive(Clone)]
+pub(crate) struct SerialController {
+    /// FF01 SB: the byte to send and, after the transfer, the received byte.
+    data: Byte,
+
+    /// FF02 SC: control register
+    ///
+    /// - Bit 7: transfer in progress
+    /// - Bit 0: use the internal clock
+    control: Byte,
+
+    /// Remaining cycles of the current transfer with the internal clock.
+    cycles_left: u16,
+
+    /// Whether a link cable is connected.
+    pub(crate) linked: bool,
+}
+
+impl SerialController {
+    pub(crate) fn new() -> Self {
+        Self {
+            data: Byte::zero(),
+            control: Byte::zero(),
+            cycles_left: 0,
+            linked: false,
+        }
+    }
+
+    /// Loads one of the serial registers. `addr` has to be 0xFF01 or 0xFF02.
+    pub(crate) fn load_byte(&self, addr: Word) -> Byte {
+        match addr.get() {
+            0xFF01 => self.data,
+            0xFF02 => self.control.map(|b| b | 0b0111_1110),
+            _ => panic!("called `SerialController::load_byte` with invalid address"),
+        }
+    }
+
+    /// Stores one of the serial registers. `addr` has to be 0xFF01 or 0xFF02.
+    pub(crate) fn store_byte(&mut self, addr: Word, byte: Byte) {
+        match addr.get() {
+            0xFF01 => self.data = byte,
+            0xFF02 => {
+                self.control = byte.map(|b| b & 0b1000_0001);
+                if self.is_master() {
+                    self.cycles_left = TRANSFER_CYCLES;
+                }
+            }
+            _ => panic!("called `SerialController::store_byte` with invalid address"),
+        }
+    }
+
+    /// Returns `true` if a transfer with the internal clock is in progress.
+    fn is_master(&self) -> bool {
+        self.control.get() == 0b1000_0001
+    }
+
+    /// Advances a running transfer by the given number of cycles. Without a
+    /// link cable, the transfer is finished as soon as all bits are shifted.
+    pub(crate) fn advance(&mut self, cycles: u8, interrupt_controller: &mut InterruptController) {
+        if !self.is_master() || self.cycles_left == 0 {
+            return;
+        }
+
+        self.cycles_left = self.cycles_left.saturating_sub(cycles as u16);
+        if self.cycles_left == 0 && !self.linked {
+            self.finish(Byte::new(0xFF), interrupt_controller);
+        }
+    }
+
+    /// Returns the byte to send if this side drives the clock and shifted all
+    /// bits. The transfer then has to be finished with `finish`.
+    pub(crate) fn outgoing(&self) -> Option<Byte> {
+        if self.is_master() && self.cycles_left == 0 {
+            Some(self.data)
+        } else {
+            None
+        }
+    }
+
+    /// Exchanges the byte with the other side, which drives the clock.
+    /// Returns the byte sent by this side or `None` if this side is not
+    /// waiting for a transfer with the external clock.
+    pub(crate) fn receive(
+        &mut self,
+        byte: Byte,
+        interrupt_controller: &mut InterruptController,
+    ) -> Option<Byte> {
+        if self.control.get() != 0b1000_0000 {
+            return None;
+        }
+
+        let sent = self.data;
+        self.finish(byte, interrupt_controller);
+        Some(sent)
+    }
+
+    /// Finishes the current transfer with the received byte.
+    pub(crate) fn finish(&mut self, byte: Byte, interrupt_controller: &mut InterruptController) {
+        self.data = byte;
+        self.control = self.control.map(|b| b & 0b0111_1111);
+        interrupt_controller.request_interrupt(Interrupt::Serial);
+    }
+
+    /// The link cable is not part of the state: `linked` stays as it is.
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.byte(self.data);
+        w.byte(self.control);
+        w.u16(self.cycles_left);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.data = r.byte()?;
+        self.control = r.byte()?;
+        self.cycles_left = r.u16()?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_transfer() {
+        let mut ic = InterruptController::new();
+        let mut master = SerialController::new();
+        let mut slave = SerialController::new();
+        master.linked = true;
+        slave.linked = true;
+
+        master.store_byte(Word::new(0xFF01), Byte::new(0x12));
+        slave.store_byte(Word::new(0xFF01), Byte::new(0x34));
+        slave.store_byte(Word::new(0xFF02), Byte::new(0x80));
+        master.store_byte(Word::new(0xFF02), Byte::new(0x81));
+        assert_eq!(master.load_byte(Word::new(0xFF02)), Byte::new(0xFF));
+
+        master.advance(255, &mut ic);
+        assert_eq!(master.outgoing(), None);
+        for _ in 0..4 {
+            master.advance(255, &mut ic);
+        }
+        assert_eq!(master.outgoing(), Some(Byte::new(0x12)));
+
+        let received = slave.receive(Byte::new(0x12), &mut ic).unwrap();
+        master.finish(received, &mut ic);
+        assert_eq!(master.load_byte(Word::new(0xFF01)), Byte::new(0x34));
+        assert_eq!(slave.load_byte(Word::new(0xFF01)), Byte::new(0x12));
+        assert_eq!(master.load_byte(Word::new(0xFF02)), Byte::new(0x7F));
+        assert_eq!(slave.load_byte(Word::new(0xFF02)), Byte::new(0x7E));
+        assert_eq!(ic.interrupt_flag.get() & 0b1000, 0b1000);
+
+        // Without a cable, nobody answers.
+        let mut single = SerialController::new();
+        single.store_byte(Word::new(0xFF02), Byte::new(0x81));
+        for _ in 0..5 {
+            single.advance(255, &mut ic);
+        }
+        assert_eq!(single.outgoing(), None);
+        assert_eq!(single.load_byte(Word::new(0xFF01)), Byte::new(0xFF));
+    }
+}
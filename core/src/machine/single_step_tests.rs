@@ -0,0 +1,184 @@
+//! Runs the SM83 single step test vectors (https://github.com/SingleStepTests/sm83)
+//! against `Machine::step`.
+//!
+//! Each vector describes the CPU registers and the relevant memory before and
+//! after executing one instruction. The memory map is replaced by flat RAM for
+//! this. The vectors are not part of this repository: download the `v1`
+//! folder and run `SM83_TESTS=<path to v1> cargo test -p mahboi -- --ignored`.
+
+use std::{env, fs};
+
+use serde_json::Value;
+
+use crate::{
+    BiosKind, HardwareModel,
+    cartridge::Cartridge,
+    primitives::{Byte, Word},
+};
+use super::Machine;
+
+
+#[test]
+#[ignore = "requires the SM83 test vectors (see module documentation)"]
+fn test_sm83() {
+    let dir = env::var("SM83_TESTS").expect("`SM83_TESTS` has to point to the test vectors");
+    let mut paths = fs::read_dir(&dir)
+        .expect("failed to read test vector directory")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert!(!paths.is_empty(), "no test vectors found in '{}'", dir);
+
+    let mut failures = Vec::new();
+    for path in &paths {
+        let json = fs::read_to_string(path).unwrap();
+        let vectors = serde_json::from_str::<Value>(&json).unwrap();
+
+        // Only report the first failure per file (i.e. per opcode).
+        let failure = vectors.as_array().unwrap().iter().find_map(|v| run_vector(v).err());
+        if let Some(failure) = failure {
+            failures.push(failure);
+        }
+    }
+
+    for failure in &failures {
+        println!("{}", failure);
+    }
+    assert!(failures.is_empty(), "{} of {} opcodes failed", failures.len(), paths.len());
+}
+
+#[test]
+fn test_harness() {
+    let vectors = serde_json::json!([
+        {
+            "name": "00 0000",
+            "initial": {
+                "pc": 4097, "sp": 65534, "a": 1, "b": 2, "c": 3, "d": 4, "e": 5, "f": 176,
+                "h": 192, "l": 0, "ime": 0, "ie": 0, "ram": [[4096, 0], [4097, 0]],
+            },
+            "final": {
+                "pc": 4098, "sp": 65534, "a": 1, "b": 2, "c": 3, "d": 4, "e": 5, "f": 176,
+                "h": 192, "l": 0, "ime": 0, "ram": [[4096, 0], [4097, 0]],
+            },
+            "cycles": [[4097, 0, "r-m"]],
+        },
+        {
+            "name": "77 0000",
+            "initial": {
+                "pc": 4097, "sp": 65534, "a": 66, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0,
+                "h": 192, "l": 0, "ime": 0, "ie": 0,
+                "ram": [[4096, 119], [4097, 0], [49152, 0]],
+            },
+            "final": {
+                "pc": 4098, "sp": 65534, "a": 66, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0,
+                "h": 192, "l": 0, "ime": 0, "ram": [[4096, 119], [4097, 0], [49152, 66]],
+            },
+            "cycles": [[49152, 66, "-wm"], [4097, 0, "r-m"]],
+        },
+        {
+            // The immediate equals the opcode.
+            "name": "3E 0000",
+            "initial": {
+                "pc": 4097, "sp": 65534, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0,
+                "h": 0, "l": 0, "ime": 0, "ie": 0, "ram": [[4096, 62], [4097, 62], [4098, 0]],
+            },
+            "final": {
+                "pc": 4099, "sp": 65534, "a": 62, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0,
+                "h": 0, "l": 0, "ime": 0, "ram": [[4096, 62], [4097, 62], [4098, 0]],
+            },
+            "cycles": [[4097, 62, "r-m"], [4098, 0, "r-m"]],
+        },
+    ]);
+
+    assert_eq!(run_vector(&vectors[0]), Ok(()));
+    assert_eq!(run_vector(&vectors[1]), Ok(()));
+    assert_eq!(run_vector(&vectors[2]), Ok(()));
+
+    let mut wrong = vectors[1].clone();
+    wrong["final"]["ram"][2][1] = 67.into();
+    assert!(run_vector(&wrong).is_err());
+}
+
+/// Runs a single test vector. Returns a description of all differences to
+/// the expected final state as error.
+fn run_vector(vector: &Value) -> Result<(), String> {
+    let name = vector["name"].as_str().unwrap();
+    let initial = &vector["initial"];
+    let expected = &vector["final"];
+
+    let cartridge = Cartridge::from_bytes(&[0; 0x8000]).unwrap();
+    let mut machine = Machine::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+    machine.flat_ram = Some(vec![Byte::zero(); 0x10000].into_boxed_slice());
+    load_state(&mut machine, initial);
+
+    // The vectors model the prefetch of the SM83: the opcode was already
+    // fetched (from `pc - 1`) and the next one is fetched at the end.
+    machine.cpu.pc -= 1u16;
+    let cycles = machine.step().map_err(|_| format!("{}: emulator terminated", name))?;
+    machine.cpu.pc += 1u16;
+
+    let mut diffs = Vec::new();
+    let cpu = &machine.cpu;
+    let actual = [
+        ("pc", cpu.pc.get()),
+        ("sp", cpu.sp.get()),
+        ("a", cpu.a.get().into()),
+        ("b", cpu.b.get().into()),
+        ("c", cpu.c.get().into()),
+        ("d", cpu.d.get().into()),
+        ("e", cpu.e.get().into()),
+        ("f", cpu.f.get().into()),
+        ("h", cpu.h.get().into()),
+        ("l", cpu.l.get().into()),
+        ("ime", machine.interrupt_controller.ime.into()),
+    ];
+    for &(reg, value) in &actual {
+        let expected = expected[reg].as_u64().unwrap() as u16;
+        if value != expected {
+            diffs.push(format!("{} = {:#x} (expected {:#x})", reg, value, expected));
+        }
+    }
+    for entry in expected["ram"].as_array().unwrap() {
+        let addr = Word::new(entry[0].as_u64().unwrap() as u16);
+        let value = machine.load_byte(addr);
+        if value != entry[1].as_u64().unwrap() as u8 {
+            diffs.push(format!("[{}] = {} (expected {})", addr, value, entry[1]));
+        }
+    }
+    let expected_cycles = vector["cycles"].as_array().unwrap().len();
+    if cycles as usize != expected_cycles {
+        diffs.push(format!("took {} cycles (expected {})", cycles, expected_cycles));
+    }
+
+    if diffs.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{}: {}", name, diffs.join(", ")))
+    }
+}
+
+/// Sets the registers and memory to the given state of a test vector.
+fn load_state(machine: &mut Machine, state: &Value) {
+    let word = |key: &str| Word::new(state[key].as_u64().unwrap() as u16);
+    let byte = |key: &str| Byte::new(state[key].as_u64().unwrap() as u8);
+
+    machine.cpu.pc = word("pc");
+    machine.cpu.sp = word("sp");
+    machine.cpu.a = byte("a");
+    machine.cpu.b = byte("b");
+    machine.cpu.c = byte("c");
+    machine.cpu.d = byte("d");
+    machine.cpu.e = byte("e");
+    machine.cpu.f = byte("f");
+    machine.cpu.h = byte("h");
+    machine.cpu.l = byte("l");
+    machine.interrupt_controller.ime = state["ime"].as_u64().unwrap() != 0;
+
+    // The IE and IF registers are replaced by flat RAM as well, so no
+    // interrupt is ever dispatched.
+    for entry in state["ram"].as_array().unwrap() {
+        let addr = Word::new(entry[0].as_u64().unwrap() as u16);
+        machine.store_byte(addr, Byte::new(entry[1].as_u64().unwrap() as u8));
+    }
+}
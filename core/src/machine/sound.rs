@@ -1,4 +1,7 @@
-use crate::primitives::{Byte, Memory, Word};
+use crate::{
+    primitives::{Byte, Memory, Word},
+    save_state::{SaveStateError, StateReader, StateWriter},
+};
 
 
 // TODO: Because of the lack of information some assumptions has been made which need proove:
@@ -9,7 +12,8 @@ use crate::primitives::{Byte, Memory, Word};
 /// Unused bits read 1 and writes are ignored. In our implementation we maintain
 /// that unused bits in our stored `Byte`s are indeed 1. So on read, we just
 /// return them; on write we `|` the input value.
-pub(crate) struct SoundController {
+#[derive(Clone)]
+pub struct SoundController {
     channel1_sweep: Byte,
     channel1_length: Byte,
     channel1_volume: Byte,
@@ -90,8 +94,8 @@ impl SoundController {
             0x06..=0x09 => self.square2.load_byte(addr),
             0x0A..=0x0E | 0x20..=0x2F => self.wave.load_byte(addr),
 
-            0x17..=0x1F => todo!(),
-            0x05 | 0x0F => todo!(),
+            // Unused registers always read as FF.
+            0x05 | 0x0F | 0x17..=0x1F => Byte::new(0xFF),
             0x30..=0xFFFF => panic!("`Sound::load_byte` called with out of bounds address"),
         }
     }
@@ -158,6 +162,37 @@ impl SoundController {
         self.wave.step();
     }
 
+    /// Returns the internal state of the given channel (1 to 4). The
+    /// registers of the channels can simply be read from memory.
+    ///
+    /// Panics if `channel` is not between 1 and 4.
+    pub fn channel_status(&self, channel: u8) -> ChannelStatus {
+        match channel {
+            // TODO: update once channel 1 and 4 are emulated
+            1 => ChannelStatus {
+                dac_enabled: self.channel1_volume.get() & 0b1111_1000 != 0,
+                ..ChannelStatus::default()
+            },
+            2 => ChannelStatus {
+                dac_enabled: self.square2.volume_envelope.get() & 0b1111_1000 != 0,
+                volume: Some(self.square2.volume),
+                length_counter: None,
+                position: Some(self.square2.position),
+            },
+            3 => ChannelStatus {
+                dac_enabled: self.wave.dac_enabled(),
+                volume: None,
+                length_counter: Some(self.wave.length_counter),
+                position: Some(self.wave.position),
+            },
+            4 => ChannelStatus {
+                dac_enabled: self.channel4_volume.get() & 0b1111_1000 != 0,
+                ..ChannelStatus::default()
+            },
+            _ => panic!("sound channel {} does not exist", channel),
+        }
+    }
+
     pub(crate) fn output(&mut self, sample_rate: f32) -> f32 {
         // The high-pass filter needs a parameter alpha which determines how
         // quickly the existing signal decays. This can be calculated from the
@@ -176,6 +211,108 @@ impl SoundController {
 
         self.last_filtered_out
     }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        for &b in &[
+            self.channel1_sweep, self.channel1_length, self.channel1_volume,
+            self.channel1_frequency_lo, self.channel1_frequency_hi, self.channel4_length,
+            self.channel4_volume, self.channel4_polynomial_counter, self.channel4_counter,
+            self.channel_control, self.selection_output, self.sound_on_off,
+        ] {
+            w.byte(b);
+        }
+        w.u32(self.frame_sequencer);
+        w.u32(self.last_filtered_out.to_bits());
+        w.u32(self.last_unfiltered_out.to_bits());
+
+        let square2 = &self.square2;
+        for &b in &[
+            square2.duty_and_length, square2.volume_envelope,
+            square2.freq_lo, square2.control_and_freq,
+        ] {
+            w.byte(b);
+        }
+        w.u16(square2.timer);
+        w.u8(square2.position);
+        w.u8(square2.volume);
+        w.u8(square2.volume_counter);
+
+        let wave = &self.wave;
+        for &b in &[wave.enable, wave.length, wave.volume, wave.freq_lo, wave.control_freq] {
+            w.byte(b);
+        }
+        w.bytes(wave.wave_table.as_slice());
+        w.u8(wave.position);
+        w.u16(wave.timer);
+        w.u16(wave.length_counter);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        for b in [
+            &mut self.channel1_sweep, &mut self.channel1_length, &mut self.channel1_volume,
+            &mut self.channel1_frequency_lo, &mut self.channel1_frequency_hi,
+            &mut self.channel4_length, &mut self.channel4_volume,
+            &mut self.channel4_polynomial_counter, &mut self.channel4_counter,
+            &mut self.channel_control, &mut self.selection_output, &mut self.sound_on_off,
+        ] {
+            *b = r.byte()?;
+        }
+        self.frame_sequencer = r.u32()?;
+        self.last_filtered_out = f32::from_bits(r.u32()?);
+        self.last_unfiltered_out = f32::from_bits(r.u32()?);
+
+        let square2 = &mut self.square2;
+        for b in [
+            &mut square2.duty_and_length, &mut square2.volume_envelope,
+            &mut square2.freq_lo, &mut square2.control_and_freq,
+        ] {
+            *b = r.byte()?;
+        }
+        square2.timer = r.u16()?;
+        square2.position = r.u8()?;
+        square2.volume = r.u8()?;
+        square2.volume_counter = r.u8()?;
+        if square2.position >= 8 || square2.volume > 15 {
+            return Err(SaveStateError::Corrupted("invalid square channel state".into()));
+        }
+
+        let wave = &mut self.wave;
+        for b in [
+            &mut wave.enable, &mut wave.length, &mut wave.volume,
+            &mut wave.freq_lo, &mut wave.control_freq,
+        ] {
+            *b = r.byte()?;
+        }
+        r.bytes(wave.wave_table.as_mut_slice())?;
+        wave.position = r.u8()?;
+        wave.timer = r.u16()?;
+        wave.length_counter = r.u16()?;
+        if wave.position >= 32 {
+            return Err(SaveStateError::Corrupted("invalid wave channel state".into()));
+        }
+        Ok(())
+    }
+}
+
+
+/// Internal state of one sound channel, mainly useful for debuggers. Values
+/// that are not emulated (yet) for a channel are `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChannelStatus {
+    /// Whether the DAC of the channel is powered. If not, the channel is
+    /// silent.
+    pub dac_enabled: bool,
+
+    /// The current volume of the volume envelope (0 to 15).
+    pub volume: Option<u8>,
+
+    /// The length counter. The channel is silenced when it reaches 0 (if
+    /// the length is enabled).
+    pub length_counter: Option<u16>,
+
+    /// The position within the waveform (8 steps for square channels, 32
+    /// samples for the wave channel).
+    pub position: Option<u8>,
 }
 
 
@@ -186,6 +323,7 @@ impl SoundController {
 /// - TODO: Make sure the envelop operation is over once it
 ///   overflows/underflows. (Is that even correct, only have one source).
 /// - TODO: length timer and stuff
+#[derive(Clone)]
 struct SquareChannel2 {
     // Raw registers
     duty_and_length: Byte,  // FF16   DDLL_LLLL
@@ -346,13 +484,14 @@ impl SquareChannel2 {
 ///   and that works out somehow, OR the value is indeed shifted and we rely on
 ///   the high-pass filter to make sure the DC offset of "25% volume" is
 ///   removed.
+#[derive(Clone)]
 struct WaveChannel {
     enable: Byte,       // FF1A  E111_1111
     length: Byte,       // FF1B
     volume: Byte,       // FF1C  1VV1_1111
     freq_lo: Byte,      // FF1D  FFFF_FFFF
     control_freq: Byte, // FF1E  TL11_1FFF
-    wave_table: Memory, // FF30 - FF3F
+    wave_table: Memory<0x10>, // FF30 - FF3F
 
     /// Internal position counter that wraps at 32.
     position: u8,
@@ -372,7 +511,7 @@ impl WaveChannel {
             volume: Byte::zero(),
             freq_lo: Byte::zero(),
             control_freq: Byte::zero(),
-            wave_table: Memory::zeroed(Word::new(0x10)),
+            wave_table: Memory::zeroed(),
             position: 0,
             timer: 0,
             length_counter: 0,
@@ -501,3 +640,33 @@ impl WaveChannel {
 fn dac(input: u8) -> f32 {
     (input as f32 / 7.5) - 1.0
 }
+
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        BiosKind, HardwareModel,
+        cartridge::Cartridge,
+        machine::Machine,
+        save_state::Compression,
+    };
+    use super::*;
+
+    #[test]
+    fn test_load_invalid_state() {
+        let cartridge = Cartridge::from_bytes(&[0; 0x8000]).unwrap();
+        let machine = Machine::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+
+        let mut square = machine.clone();
+        square.sound_controller.square2.position = 8;
+        let mut wave = machine.clone();
+        wave.sound_controller.wave.position = 32;
+        for corrupted in [square, wave] {
+            let state = corrupted.save_state(Compression::None);
+            assert!(matches!(
+                machine.clone().load_state(&state),
+                Err(SaveStateError::Corrupted(_)),
+            ));
+        }
+    }
+}
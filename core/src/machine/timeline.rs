@@ -0,0 +1,148 @@
+//! A record of the timing of PPU mode changes, LYC matches, interrupts and
+//! DMA transfers within a frame, for timing views in debuggers (see
+//! `Machine::set_timeline_enabled`).
+//!
+//! Events are recorded by the PPU with the cycle in the frame they happened
+//! in, so they are exact even though debuggers only see the machine between
+//! instructions. While the LCD is disabled, no events are recorded and no
+//! frame is completed.
+
+use super::{
+    Machine,
+    ppu::{Mode, CYCLES_PER_LINE},
+};
+use crate::primitives::{Byte, Word};
+
+
+/// What happened in an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// The PPU entered this mode.
+    Mode(Mode),
+
+    /// A line with the number in LYC started.
+    LycMatch,
+
+    /// The interrupt with this vector address was dispatched.
+    Interrupt(Word),
+
+    /// An OAM DMA transfer from this page was started.
+    Dma(Byte),
+}
+
+/// One event in a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    /// The number of 1MHz cycles since the start of the frame, see
+    /// `Ppu::cycle_in_frame`.
+    pub cycle: u32,
+
+    pub kind: EventKind,
+}
+
+impl Event {
+    /// Returns the line the event happened in.
+    pub fn line(&self) -> u8 {
+        (self.cycle / CYCLES_PER_LINE as u32) as u8
+    }
+
+    /// Returns the cycle in the line the event happened in.
+    pub fn cycle_in_line(&self) -> u8 {
+        (self.cycle % CYCLES_PER_LINE as u32) as u8
+    }
+}
+
+/// The events of the current and of the last complete frame. Stored inside
+/// of the PPU.
+#[derive(Debug, Clone, Default)]
+pub struct Timeline {
+    current: Vec<Event>,
+    last: Vec<Event>,
+}
+
+impl Timeline {
+    /// Returns the events of the last complete frame in the order they
+    /// happened.
+    pub fn last_frame(&self) -> &[Event] {
+        &self.last
+    }
+
+    /// Returns the events of the current frame so far.
+    pub fn current_frame(&self) -> &[Event] {
+        &self.current
+    }
+
+    pub(crate) fn record(&mut self, cycle: u32, kind: EventKind) {
+        self.current.push(Event { cycle, kind });
+    }
+
+    pub(crate) fn end_frame(&mut self) {
+        std::mem::swap(&mut self.current, &mut self.last);
+        self.current.clear();
+    }
+}
+
+impl Machine {
+    /// Starts or stops recording the timeline of events (see `timeline`).
+    /// Stopping discards all recorded events.
+    pub fn set_timeline_enabled(&mut self, enabled: bool) {
+        match (enabled, &self.ppu.timeline) {
+            (true, None) => self.ppu.timeline = Some(Timeline::default()),
+            (false, _) => self.ppu.timeline = None,
+            (true, Some(_)) => {}
+        }
+    }
+
+    /// Returns the recorded timeline or `None` if recording is disabled.
+    pub fn timeline(&self) -> Option<&Timeline> {
+        self.ppu.timeline.as_ref()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        BiosKind, HardwareModel,
+        cartridge::Cartridge,
+        test_util::{NullPeripherals, rom_with_code},
+    };
+    use super::*;
+
+    #[test]
+    fn test_timeline() {
+        // 0040: RETI
+        // 0150: LD A, 1; LDH (FF), A; EI; JR -2
+        let mut rom = rom_with_code(&[0x3E, 0x01, 0xE0, 0xFF, 0xFB, 0x18, 0xFE]);
+        rom[0x40] = 0xD9;
+        let cartridge = Cartridge::from_bytes(&rom).unwrap();
+        let mut machine = Machine::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        machine.cpu.pc = Word::new(0x150);
+        machine.store_byte(Word::new(0xFF50), Byte::new(1));
+        machine.store_byte(Word::new(0xFF40), Byte::new(0x91));
+        machine.store_byte(Word::new(0xFF45), Byte::new(10));
+        machine.set_timeline_enabled(true);
+
+        let frame = machine.ppu.frame_count();
+        while machine.ppu.frame_count() < frame + 2 {
+            machine.execute_step(&mut NullPeripherals).ok().unwrap();
+        }
+
+        let events = machine.timeline().unwrap().last_frame();
+        let count = |kind| events.iter().filter(|e| e.kind == kind).count();
+        assert_eq!(count(EventKind::Mode(Mode::OamSearch)), 144);
+        assert_eq!(count(EventKind::Mode(Mode::HBlank)), 144);
+        assert_eq!(count(EventKind::LycMatch), 1);
+
+        let vblank = events.iter().find(|e| e.kind == EventKind::Mode(Mode::VBlank)).unwrap();
+        assert_eq!((vblank.line(), vblank.cycle_in_line()), (144, 0));
+        let lyc = events.iter().find(|e| e.kind == EventKind::LycMatch).unwrap();
+        assert_eq!((lyc.line(), lyc.cycle_in_line()), (10, 0));
+
+        // The interrupt is dispatched after the current instruction.
+        let vector = EventKind::Interrupt(Word::new(0x40));
+        let int = events.iter().find(|e| e.kind == vector).unwrap();
+        assert_eq!(int.line(), 144);
+        assert!(int.cycle_in_line() < 4);
+    }
+}
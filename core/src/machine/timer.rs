@@ -1,12 +1,14 @@
 use crate::{
     primitives::{Byte, Word},
     machine::interrupt::{InterruptController, Interrupt},
+    save_state::{SaveStateError, StateReader, StateWriter},
 };
 
 
 /// Manages four timer registers and is responsible for triggering the timer
 /// interrupt.
-pub(crate) struct Timer {
+#[derive(Clone)]
+pub struct Timer {
     /// FF04 DIV: Counting up at a rate of 16384Hz.
     divider: Byte,
 
@@ -67,29 +69,82 @@ impl Timer {
         }
     }
 
-    pub(crate) fn is_enabled(&self) -> bool {
+    pub fn is_enabled(&self) -> bool {
         (self.control.get() & 0b100) == 0b100
     }
 
-    pub(crate) fn step(&mut self, interrupt_controller: &mut InterruptController) {
-        // This counter counts 4Mhz cycles, but this method is only called with
-        // 1Mhz.
-        self.cycle_count += 4;
+    /// Returns the value of TIMA (FF05).
+    pub fn counter(&self) -> Byte {
+        self.counter
+    }
+
+    /// Returns the value of TMA (FF06).
+    pub fn modulo(&self) -> Byte {
+        self.modulo
+    }
+
+    /// Returns the value of TAC (FF07).
+    pub fn control(&self) -> Byte {
+        self.control
+    }
+
+    /// Returns the internal 16 bit counter (counting at 4MHz). DIV is the
+    /// upper byte of this counter.
+    pub fn internal_counter(&self) -> Word {
+        Word::from_bytes(Byte::new(self.cycle_count as u8), self.divider)
+    }
+
+    /// Returns the number of 4MHz clocks between two increments of TIMA, as
+    /// selected by TAC.
+    pub fn increment_period(&self) -> u64 {
+        self.increment_mask() + 1
+    }
+
+    fn increment_mask(&self) -> u64 {
+        match self.control.get() & 0b11 {
+            0b01 => 0b1111, // divider 16
+            0b10 => 0b111111, // divider 64
+            0b11 => 0b11111111, // divider 256
+            0b00 => 0b1111111111, // divider 1024
+            _ => unreachable!(),
+        }
+    }
 
-        if self.cycle_count % 256 == 0 {
-            self.divider += 1;
+    /// Returns the number of 1MHz cycles until TIMA is incremented the next
+    /// time or `None` if the timer is disabled.
+    pub fn cycles_until_increment(&self) -> Option<u64> {
+        if !self.is_enabled() {
+            return None;
         }
 
+        let clocks = self.increment_period() - (self.cycle_count & self.increment_mask());
+        Some(clocks / 4)
+    }
+
+    /// Returns the number of 1MHz cycles until TIMA overflows and the timer
+    /// interrupt is requested (assuming no registers are written until then)
+    /// or `None` if the timer is disabled.
+    pub fn cycles_until_interrupt(&self) -> Option<u64> {
+        let next = self.cycles_until_increment()?;
+        let remaining_increments = 0xFF - self.counter.get() as u64;
+        Some(next + remaining_increments * self.increment_period() / 4)
+    }
+
+    /// Runs the timer for `cycles` 1MHz cycles. This is equivalent to
+    /// stepping it cycle by cycle, but only does work for the increments of
+    /// DIV and TIMA that happen in between.
+    pub(crate) fn advance(&mut self, cycles: u8, interrupt_controller: &mut InterruptController) {
+        // This counter counts 4Mhz cycles. All periods are multiples of 4, so
+        // a register is incremented for every multiple of its period passed.
+        let before = self.cycle_count;
+        self.cycle_count += 4 * cycles as u64;
+        let after = self.cycle_count;
+        let passed = |period: u64| after / period - before / period;
+
+        self.divider += passed(256) as u8;
+
         if self.is_enabled() {
-            let mask = match self.control.get() & 0b11 {
-                0b01 => 0b1111, // divider 16
-                0b10 => 0b111111, // divider 64
-                0b11 => 0b11111111, // divider 256
-                0b00 => 0b1111111111, // divider 1024
-                _ => unreachable!(),
-            };
-
-            if (self.cycle_count & mask) == 0 {
+            for _ in 0..passed(self.increment_period()) {
                 self.counter += 1;
 
                 // TIMA overflowed
@@ -100,4 +155,73 @@ impl Timer {
             }
         }
     }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.byte(self.divider);
+        w.byte(self.counter);
+        w.byte(self.modulo);
+        w.byte(self.control);
+        w.u64(self.cycle_count);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.divider = r.byte()?;
+        self.counter = r.byte()?;
+        self.modulo = r.byte()?;
+        self.control = r.byte()?;
+        self.cycle_count = r.u64()?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_advance_in_chunks() {
+        // The internal counter, TIMA and IF after the iterations 10, 30, 100
+        // and 4999 (36, 106, 351 and 17496 cycles) for each TAC value.
+        let expected = [
+            (0b100, [(0x0090, 0xF0, 0x00), (0x01A8, 0xF0, 0x00),
+                     (0x057C, 0xF1, 0x00), (0x1160, 0xF4, 0x04)]),
+            (0b101, [(0x0090, 0xF9, 0x00), (0x01A8, 0xFA, 0x04),
+                     (0x057C, 0xF7, 0x04), (0x1160, 0xF6, 0x04)]),
+            (0b110, [(0x0090, 0xF2, 0x00), (0x01A8, 0xF6, 0x00),
+                     (0x057C, 0xF5, 0x04), (0x1160, 0xF5, 0x04)]),
+            (0b111, [(0x0090, 0xF0, 0x00), (0x01A8, 0xF1, 0x00),
+                     (0x057C, 0xF5, 0x00), (0x1160, 0xF1, 0x04)]),
+        ];
+
+        for &(control, checkpoints) in &expected {
+            let mut chunked = Timer::new();
+            chunked.store_byte(Word::new(0xFF05), Byte::new(0xF0));
+            chunked.store_byte(Word::new(0xFF06), Byte::new(0xF0));
+            chunked.store_byte(Word::new(0xFF07), Byte::new(control));
+            let mut single = chunked.clone();
+            let mut chunked_ic = InterruptController::new();
+            let mut single_ic = InterruptController::new();
+
+            for i in 0..5000 {
+                let cycles = (i % 6) as u8 + 1;
+                chunked.advance(cycles, &mut chunked_ic);
+                for _ in 0..cycles {
+                    single.advance(1, &mut single_ic);
+                }
+
+                assert_eq!(chunked.internal_counter(), single.internal_counter());
+                assert_eq!(chunked.counter(), single.counter());
+                assert_eq!(chunked_ic.interrupt_flag, single_ic.interrupt_flag);
+
+                if let Some(idx) = [10, 30, 100, 4999].iter().position(|&c| c == i) {
+                    let (internal, tima, flags) = checkpoints[idx];
+                    assert_eq!(chunked.internal_counter(), Word::new(internal));
+                    assert_eq!(chunked.counter(), Byte::new(tima));
+                    assert_eq!(chunked_ic.interrupt_flag, Byte::new(flags));
+                }
+            }
+            assert_ne!(chunked_ic.interrupt_flag, 0);
+        }
+    }
 }
@@ -0,0 +1,75 @@
+//! A ring buffer of the last executed instructions.
+//!
+//! This is only useful for debuggers (e.g. to see how execution arrived at a
+//! crash or breakpoint) and is disabled by default, since it costs some
+//! performance.
+
+use std::collections::VecDeque;
+
+use super::{Machine, cpu::Cpu};
+use crate::{
+    primitives::{Byte, Word},
+};
+
+
+/// One executed instruction.
+#[derive(Debug, Clone, Copy)]
+pub struct TraceEntry {
+    /// Address of the instruction.
+    pub pc: Word,
+
+    /// The opcode and the two bytes following it. Depending on the length of
+    /// the instruction, not all of these bytes are part of the instruction.
+    pub bytes: [Byte; 3],
+
+    /// All registers right before the instruction was executed.
+    pub cpu: Cpu,
+}
+
+/// The trace buffer stored inside of `Machine`.
+#[derive(Clone)]
+pub(crate) struct Trace {
+    entries: VecDeque<TraceEntry>,
+
+    /// Maximum number of entries. `0` means tracing is disabled.
+    capacity: usize,
+}
+
+impl Trace {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: VecDeque::new(),
+            capacity: 0,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.capacity != 0
+    }
+
+    pub(crate) fn push(&mut self, entry: TraceEntry) {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+}
+
+impl Machine {
+    /// Sets the number of executed instructions kept in the trace buffer. A
+    /// capacity of `0` (the default) disables tracing. If the buffer holds
+    /// more entries than the new capacity, the oldest ones are removed.
+    pub fn set_trace_capacity(&mut self, capacity: usize) {
+        let trace = &mut self.trace;
+        trace.capacity = capacity;
+        while trace.entries.len() > capacity {
+            trace.entries.pop_front();
+        }
+    }
+
+    /// Returns the last executed instructions, oldest first.
+    pub fn trace(&self) -> &VecDeque<TraceEntry> {
+        &self.trace.entries
+    }
+}
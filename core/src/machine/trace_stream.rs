@@ -0,0 +1,137 @@
+//! A stream of all executed instructions for offline analysis of long
+//! sessions (see `Emulator::start_trace`).
+//!
+//! In contrast to the trace buffer (`Machine::trace`), the stream is not
+//! limited in size. Records are collected in a buffer inside of the machine
+//! and written to the writer at the end of each frame. Besides instructions,
+//! the stream contains markers for interrupt dispatches and bank switches:
+//! instructions in `0x4000..0x8000` belong to the ROM bank of the last bank
+//! marker. The stream starts with a bank marker.
+//!
+//! In the text format, there is one record per line:
+//!
+//! ```text
+//! 0150 21 AF:01B0 BC:0013 DE:00D8 HL:014D SP:FFFE
+//! INT 0040 FROM 0153
+//! BANK 03 00
+//! ```
+//!
+//! In the binary format, each record starts with a tag byte, followed by
+//! little endian numbers:
+//!
+//! - `0x01` instruction: PC (`u16`), opcode (`u8`), AF, BC, DE, HL, SP (`u16`)
+//! - `0x02` interrupt: vector (`u16`), interrupted PC (`u16`)
+//! - `0x03` bank switch: ROM bank (`u16`), RAM bank (`u8`)
+
+use std::io::{self, Write};
+
+use super::cpu::Cpu;
+use crate::primitives::{Byte, Word};
+
+
+/// The format of the trace stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// One line of text per record. Easy to read and to search, but about
+    /// three times as large.
+    Text,
+
+    /// Compact binary records.
+    Binary,
+}
+
+const TAG_INSTRUCTION: u8 = 0x01;
+const TAG_INTERRUPT: u8 = 0x02;
+const TAG_BANK: u8 = 0x03;
+
+/// The records not yet written to the writer, stored inside of `Machine`.
+#[derive(Clone)]
+pub(crate) struct TraceStream {
+    format: TraceFormat,
+    buf: Vec<u8>,
+
+    /// The banks of the last bank marker.
+    banks: (usize, usize),
+}
+
+impl TraceStream {
+    pub(crate) fn new(format: TraceFormat, rom_bank: usize, ram_bank: usize) -> Self {
+        let mut out = Self {
+            format,
+            buf: Vec::new(),
+            banks: (rom_bank, ram_bank),
+        };
+        out.write_banks();
+        out
+    }
+
+    pub(crate) fn instruction(&mut self, pc: Word, op_code: Byte, cpu: &Cpu) {
+        let regs = [cpu.af(), cpu.bc(), cpu.de(), cpu.hl(), cpu.sp];
+        match self.format {
+            TraceFormat::Text => {
+                // Writing into a `Vec` never fails.
+                let _ = writeln!(
+                    self.buf,
+                    "{:04X} {:02X} AF:{:04X} BC:{:04X} DE:{:04X} HL:{:04X} SP:{:04X}",
+                    pc.get(),
+                    op_code.get(),
+                    regs[0].get(),
+                    regs[1].get(),
+                    regs[2].get(),
+                    regs[3].get(),
+                    regs[4].get(),
+                );
+            }
+            TraceFormat::Binary => {
+                self.buf.push(TAG_INSTRUCTION);
+                self.buf.extend_from_slice(&pc.get().to_le_bytes());
+                self.buf.push(op_code.get());
+                for reg in &regs {
+                    self.buf.extend_from_slice(&reg.get().to_le_bytes());
+                }
+            }
+        }
+    }
+
+    pub(crate) fn interrupt(&mut self, vector: Word, from: Word) {
+        match self.format {
+            TraceFormat::Text => {
+                let _ = writeln!(self.buf, "INT {:04X} FROM {:04X}", vector.get(), from.get());
+            }
+            TraceFormat::Binary => {
+                self.buf.push(TAG_INTERRUPT);
+                self.buf.extend_from_slice(&vector.get().to_le_bytes());
+                self.buf.extend_from_slice(&from.get().to_le_bytes());
+            }
+        }
+    }
+
+    /// Writes a bank marker if the banks changed since the last one.
+    pub(crate) fn banks(&mut self, rom_bank: usize, ram_bank: usize) {
+        if self.banks != (rom_bank, ram_bank) {
+            self.banks = (rom_bank, ram_bank);
+            self.write_banks();
+        }
+    }
+
+    fn write_banks(&mut self) {
+        let (rom_bank, ram_bank) = self.banks;
+        match self.format {
+            TraceFormat::Text => {
+                let _ = writeln!(self.buf, "BANK {:02X} {:02X}", rom_bank, ram_bank);
+            }
+            TraceFormat::Binary => {
+                self.buf.push(TAG_BANK);
+                self.buf.extend_from_slice(&(rom_bank as u16).to_le_bytes());
+                self.buf.push(ram_bank as u8);
+            }
+        }
+    }
+
+    /// Writes all buffered records to `out` and clears the buffer.
+    pub(crate) fn flush_to(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        let result = out.write_all(&self.buf);
+        self.buf.clear();
+        result
+    }
+}
@@ -0,0 +1,415 @@
+use std::{
+    cmp::max,
+    sync::Arc,
+};
+
+use crate::{
+    MACHINE_CYCLES_PER_SECOND,
+    log::*,
+    cartridge::{CartridgeError, RamSize, RomSize},
+    primitives::{Byte, Word},
+    save_state::{SaveStateError, StateReader, StateWriter},
+};
+use super::Mbc;
+
+/// Number of bytes of the clock stored after the RAM: the minute of the day
+/// and the day, both as little endian `u16`.
+const CLOCK_LEN: usize = 4;
+
+/// Number of minutes per day.
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// Number of cycles per minute.
+const CYCLES_PER_MINUTE: u32 = 60 * MACHINE_CYCLES_PER_SECOND;
+
+// Values of the mode register (written to `0x0000..0x2000`) that select what
+// is mapped to `0xA000..0xC000`. All other values map nothing.
+const MODE_RAM_READ: u8 = 0x0;
+const MODE_RAM: u8 = 0xA;
+const MODE_RTC_COMMAND: u8 = 0xB;
+const MODE_RTC_RESPONSE: u8 = 0xC;
+const MODE_RTC_READY: u8 = 0xD;
+const MODE_IR: u8 = 0xE;
+
+/// The HuC3 controller by Hudson Soft, e.g. used by Robopon.
+///
+/// Banking works like with MBC3, but instead of a RAM enable register, a mode
+/// register selects whether RAM (read only or read/write), the clock or the
+/// infrared port is mapped to `0xA000..0xC000`.
+///
+/// The clock is controlled by commands written in the RTC command mode (the
+/// upper nibble is the command, the lower one the argument):
+///
+/// - `1`: read the nibble at the access index, then increment the index
+/// - `2`: write the argument to the nibble at the access index
+/// - `3`: like `2`, but increments the index afterwards
+/// - `4`/`5`: set the low/high nibble of the access index
+///
+/// Nibbles 0 to 2 are the minute of the day, 3 to 6 the day counter. Commands
+/// are executed immediately and the response (command and result nibble) can
+/// be read in the RTC response mode. The clock runs with the emulated time and
+/// is stored after the RAM (see `Mbc::ram`), so it is persisted with it.
+///
+/// The IR port is only a stub: the LED state can be read with `Mbc::ir_led`
+/// and whether light is received is set with `Mbc::set_ir_light`.
+#[derive(Clone)]
+pub(crate) struct HuC3 {
+    rom: Arc<[Byte]>,
+
+    /// The RAM banks followed by `CLOCK_LEN` bytes of the clock.
+    ram: Box<[Byte]>,
+
+    /// The ROM bank, 7 bits. Bank 0 is mapped as bank 1.
+    rom_bank: u8,
+
+    /// The RAM bank, 2 bits.
+    ram_bank: u8,
+
+    /// Selects what is mapped to `0xA000..0xC000`, see the `MODE_*`
+    /// constants.
+    mode: u8,
+
+    /// The nibble of the clock accessed by the next RTC command.
+    access_index: u8,
+
+    /// The response to the last RTC command.
+    response: u8,
+
+    /// Cycles since the clock last advanced by one minute.
+    clock_cycles: u32,
+
+    ir_led: bool,
+    ir_light: bool,
+}
+
+
+impl HuC3 {
+    pub(crate) fn new(
+        data: &[u8],
+        rom_size: RomSize,
+        ram_size: RamSize,
+    ) -> Result<Self, CartridgeError> {
+        if rom_size > RomSize::Banks128 {
+            return Err(CartridgeError::UnsupportedRomSize { rom_size, mbc: "HuC3" });
+        }
+        if ram_size > RamSize::Kb32 {
+            return Err(CartridgeError::UnsupportedRamSize { ram_size, mbc: "HuC3" });
+        }
+        super::check_rom_len(data, rom_size)?;
+
+        let rom = data.iter().cloned().map(Byte::new).collect();
+        let ram = vec![Byte::zero(); ram_size.len() + CLOCK_LEN];
+
+        Ok(Self {
+            rom,
+            ram: ram.into_boxed_slice(),
+            rom_bank: 0,
+            ram_bank: 0,
+            mode: MODE_RAM_READ,
+            access_index: 0,
+            response: 0,
+            clock_cycles: 0,
+            ir_led: false,
+            ir_light: false,
+        })
+    }
+
+    /// Returns the length of the RAM without the clock.
+    fn ram_len(&self) -> usize {
+        self.ram.len() - CLOCK_LEN
+    }
+
+    /// Returns the minute of the day and the day. The minute is normalized,
+    /// as the RAM might come from a corrupted save.
+    fn clock(&self) -> (u16, u16) {
+        let clock = &self.ram[self.ram_len()..];
+        let value = |i: usize| u16::from_le_bytes([clock[i].get(), clock[i + 1].get()]);
+        (value(0) % MINUTES_PER_DAY, value(2))
+    }
+
+    fn set_clock(&mut self, minutes: u16, days: u16) {
+        let start = self.ram_len();
+        let bytes = [minutes.to_le_bytes(), days.to_le_bytes()].concat();
+        for (dst, b) in self.ram[start..].iter_mut().zip(bytes) {
+            *dst = Byte::new(b);
+        }
+    }
+
+    /// Returns the clock as nibbles (see the type documentation).
+    fn clock_nibbles(&self) -> u32 {
+        let (minutes, days) = self.clock();
+        minutes as u32 | (days as u32) << 12
+    }
+
+    /// Executes an RTC command.
+    fn execute(&mut self, byte: u8) {
+        let (command, arg) = (byte >> 4, byte & 0xF);
+        let index = self.access_index as u32;
+        let mut result = 0;
+
+        match command {
+            1 => {
+                if index < 7 {
+                    result = (self.clock_nibbles() >> (index * 4)) as u8 & 0xF;
+                }
+                self.access_index = self.access_index.wrapping_add(1);
+            }
+            2 | 3 => {
+                if index < 7 {
+                    let shift = index * 4;
+                    let nibbles = (self.clock_nibbles() & !(0xF << shift))
+                        | (arg as u32) << shift;
+                    let minutes = (nibbles & 0xFFF) as u16 % MINUTES_PER_DAY;
+                    self.set_clock(minutes, (nibbles >> 12) as u16);
+                } else {
+                    debug!("[huc3] ignoring RTC write to index {:02x}", index);
+                }
+                if command == 3 {
+                    self.access_index = self.access_index.wrapping_add(1);
+                }
+            }
+            4 => self.access_index = (self.access_index & 0xF0) | arg,
+            5 => self.access_index = (self.access_index & 0x0F) | (arg << 4),
+            _ => warn!("[huc3] unknown RTC command {:02x}", byte),
+        }
+
+        self.response = (command << 4) | result;
+    }
+}
+
+impl Mbc for HuC3 {
+    fn load_rom_byte(&self, addr: Word) -> Byte {
+        match addr.get() {
+            // Always bank 0
+            0x0000..=0x3FFF => self.rom[addr.get() as usize],
+
+            // Bank 1 to N
+            0x4000..=0x7FFF => {
+                let bank_offset = self.rom_bank() * 0x4000;
+                let relative_addr = addr.get() as usize - 0x4000;
+
+                // The game might enable a bank higher than specified in the
+                // header. In that case we return FF.
+                self.rom.get(bank_offset + relative_addr)
+                    .cloned()
+                    .unwrap_or(Byte::new(0xFF))
+            }
+
+            _ => unreachable!(),
+        }
+    }
+
+    fn store_rom_byte(&mut self, addr: Word, byte: Byte) {
+        match addr.get() {
+            0x0000..=0x1FFF => self.mode = byte.get() & 0x0F,
+            0x2000..=0x3FFF => self.rom_bank = byte.get() & 0b0111_1111,
+            0x4000..=0x5FFF => self.ram_bank = byte.get() & 0b11,
+            0x6000..=0x7FFF => {}
+            _ => unreachable!(),
+        }
+    }
+
+    fn load_ram_byte(&self, addr: Word) -> Byte {
+        match self.mode {
+            MODE_RAM_READ | MODE_RAM => {
+                // If a value outside of the usable RAM is requested, we return FF.
+                let idx = self.ram_bank as usize * 0x2000 + addr.get() as usize;
+                self.ram[..self.ram_len()].get(idx).cloned().unwrap_or(Byte::new(0xFF))
+            }
+            MODE_RTC_RESPONSE => Byte::new(self.response),
+
+            // Commands are executed immediately, so the clock is always ready.
+            MODE_RTC_READY => Byte::new(0x01),
+            MODE_IR => Byte::new(0xC0 | self.ir_light as u8),
+            _ => Byte::new(0xFF),
+        }
+    }
+
+    fn store_ram_byte(&mut self, addr: Word, byte: Byte) {
+        match self.mode {
+            MODE_RAM => {
+                // Writes outside of the valid RAM are ignored.
+                let idx = self.ram_bank as usize * 0x2000 + addr.get() as usize;
+                if idx < self.ram_len() {
+                    self.ram[idx] = byte;
+                } else {
+                    warn!(
+                        "[huc3] write outside of valid RAM (bank {}, address {})",
+                        self.ram_bank,
+                        addr,
+                    );
+                }
+            }
+            MODE_RTC_COMMAND => self.execute(byte.get()),
+            MODE_IR => self.ir_led = byte.get() & 1 != 0,
+            _ => {}
+        }
+    }
+
+    fn advance(&mut self, cycles: u8) {
+        self.clock_cycles += cycles as u32;
+        if self.clock_cycles >= CYCLES_PER_MINUTE {
+            self.clock_cycles -= CYCLES_PER_MINUTE;
+            let (minutes, days) = self.clock();
+            if minutes + 1 >= MINUTES_PER_DAY {
+                self.set_clock(0, days.wrapping_add(1));
+            } else {
+                self.set_clock(minutes + 1, days);
+            }
+        }
+    }
+
+    fn rom_bank(&self) -> usize {
+        // Bank 0 cannot be mapped, see `load_rom_byte`.
+        max(self.rom_bank, 1) as usize
+    }
+
+    fn ram_bank(&self) -> usize {
+        self.ram_bank as usize
+    }
+
+    fn name(&self) -> &'static str {
+        "HuC3"
+    }
+
+    fn ram_enabled(&self) -> Option<bool> {
+        Some(self.mode == MODE_RAM)
+    }
+
+    fn banking_mode(&self) -> Option<&'static str> {
+        let mode = match self.mode {
+            MODE_RAM_READ => "RAM (read only)",
+            MODE_RAM => "RAM",
+            MODE_RTC_COMMAND => "RTC command",
+            MODE_RTC_RESPONSE => "RTC response",
+            MODE_RTC_READY => "RTC ready",
+            MODE_IR => "IR",
+            _ => "nothing mapped",
+        };
+        Some(mode)
+    }
+
+    fn rom(&self) -> &[Byte] {
+        &self.rom
+    }
+
+    fn rom_mut(&mut self) -> &mut [Byte] {
+        Arc::make_mut(&mut self.rom)
+    }
+
+    fn ram(&self) -> &[Byte] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [Byte] {
+        &mut self.ram
+    }
+
+    fn box_clone(&self) -> Box<dyn Mbc> {
+        Box::new(self.clone())
+    }
+
+    fn ir_led(&self) -> Option<bool> {
+        Some(self.ir_led)
+    }
+
+    fn set_ir_light(&mut self, received: bool) {
+        self.ir_light = received;
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+        w.u8(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.u8(self.mode);
+        w.u8(self.access_index);
+        w.u8(self.response);
+        w.u32(self.clock_cycles);
+        w.bool(self.ir_led);
+        w.bool(self.ir_light);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        r.bytes(&mut self.ram)?;
+        self.rom_bank = r.u8()?;
+        self.ram_bank = r.u8()?;
+        self.mode = r.u8()?;
+        self.access_index = r.u8()?;
+        self.response = r.u8()?;
+        self.clock_cycles = r.u32()?;
+        self.ir_led = r.bool()?;
+        self.ir_light = r.bool()?;
+        Ok(())
+    }
+
+    fn register_writes(&self) -> Vec<(Word, Byte)> {
+        vec![
+            (Word::new(0x0000), Byte::new(self.mode)),
+            (Word::new(0x2000), Byte::new(self.rom_bank)),
+            (Word::new(0x4000), Byte::new(self.ram_bank)),
+        ]
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_huc3() {
+        let data = vec![0; RomSize::Banks4.len()];
+        let mut huc = HuC3::new(&data, RomSize::Banks4, RamSize::Kb32).unwrap();
+        assert_eq!(huc.ram().len(), 0x8000 + CLOCK_LEN);
+        let rtc = |huc: &mut HuC3, command: u8| {
+            huc.store_rom_byte(Word::new(0x0000), Byte::new(MODE_RTC_COMMAND));
+            huc.store_ram_byte(Word::zero(), Byte::new(command));
+            huc.store_rom_byte(Word::new(0x0000), Byte::new(MODE_RTC_RESPONSE));
+            huc.load_ram_byte(Word::zero()).get()
+        };
+
+        // RAM is only writable in RAM mode.
+        huc.store_rom_byte(Word::new(0x4000), Byte::new(3));
+        huc.store_ram_byte(Word::new(0x10), Byte::new(0x42));
+        huc.store_rom_byte(Word::new(0x0000), Byte::new(MODE_RAM));
+        huc.store_ram_byte(Word::new(0x20), Byte::new(0x43));
+        huc.store_rom_byte(Word::new(0x0000), Byte::new(MODE_RAM_READ));
+        assert_eq!(huc.load_ram_byte(Word::new(0x10)), Byte::zero());
+        assert_eq!(huc.load_ram_byte(Word::new(0x20)), Byte::new(0x43));
+
+        // Set the clock to minute 1439 (0x59F) of day 2, then let a minute
+        // pass.
+        rtc(&mut huc, 0x40);
+        rtc(&mut huc, 0x50);
+        for &nibble in &[0xF, 0x9, 0x5, 0x2, 0x0, 0x0, 0x0] {
+            assert_eq!(rtc(&mut huc, 0x30 | nibble), 0x30);
+        }
+        assert_eq!(huc.clock(), (1439, 2));
+        for _ in 0..CYCLES_PER_MINUTE / 16 {
+            huc.advance(16);
+        }
+        assert_eq!(huc.clock(), (0, 3));
+
+        rtc(&mut huc, 0x43);
+        assert_eq!(rtc(&mut huc, 0x10), 0x13);
+        assert_eq!(rtc(&mut huc, 0x10), 0x10);
+
+        // A corrupted clock doesn't overflow.
+        let start = huc.ram_len();
+        for b in &mut huc.ram_mut()[start..] {
+            *b = Byte::new(0xFF);
+        }
+        for _ in 0..CYCLES_PER_MINUTE / 16 {
+            huc.advance(16);
+        }
+        assert_eq!(huc.clock(), (0xFFFF % MINUTES_PER_DAY + 1, 0xFFFF));
+
+        // IR
+        huc.store_rom_byte(Word::new(0x0000), Byte::new(MODE_IR));
+        huc.store_ram_byte(Word::zero(), Byte::new(0x01));
+        assert_eq!(huc.ir_led(), Some(true));
+        assert_eq!(huc.load_ram_byte(Word::zero()), Byte::new(0xC0));
+        huc.set_ir_light(true);
+        assert_eq!(huc.load_ram_byte(Word::zero()), Byte::new(0xC1));
+    }
+}
@@ -1,9 +1,13 @@
-use std::cmp::max;
+use std::{
+    cmp::max,
+    sync::Arc,
+};
 
 use crate::{
     log::*,
-    cartridge::{RamSize, RomSize},
+    cartridge::{CartridgeError, RamSize, RomSize},
     primitives::{Byte, Word},
+    save_state::{SaveStateError, StateReader, StateWriter},
 };
 use super::Mbc;
 
@@ -12,8 +16,9 @@ use super::Mbc;
 ///
 /// With this controller, the cartridge can have up to 2MiB of ROM and up to
 /// 32KiB of external RAM.
+#[derive(Clone)]
 pub(crate) struct Mbc1 {
-    rom: Box<[Byte]>,
+    rom: Arc<[Byte]>,
     ram: Box<[Byte]>,
 
     /// This register is used both for ROM and RAM banking. Bits 0--4 are
@@ -34,46 +39,29 @@ pub(crate) struct Mbc1 {
 
 
 impl Mbc1 {
-    pub(crate) fn new(data: &[u8], rom_size: RomSize, ram_size: RamSize) -> Self {
-        assert!(rom_size <= RomSize::Banks128, "More than 128 banks, but only MBC1!");
-        assert!(
-            rom_size.len() == data.len(),
-            "Length of cartridge doesn't match length specified in ROM size header",
-        );
-        assert!(
-            ram_size <= RamSize::Kb32,
-            "Illegal ram size {:?} for MBC1",
-            ram_size,
-        );
-
-        let rom: Vec<_> = data.iter().cloned().map(Byte::new).collect();
+    pub(crate) fn new(
+        data: &[u8],
+        rom_size: RomSize,
+        ram_size: RamSize,
+    ) -> Result<Self, CartridgeError> {
+        if rom_size > RomSize::Banks128 {
+            return Err(CartridgeError::UnsupportedRomSize { rom_size, mbc: "MBC1" });
+        }
+        if ram_size > RamSize::Kb32 {
+            return Err(CartridgeError::UnsupportedRamSize { ram_size, mbc: "MBC1" });
+        }
+        super::check_rom_len(data, rom_size)?;
+
+        let rom = data.iter().cloned().map(Byte::new).collect();
         let ram = vec![Byte::zero(); ram_size.len()];
 
-        Self {
-            rom: rom.into_boxed_slice(),
+        Ok(Self {
+            rom,
             ram: ram.into_boxed_slice(),
             current_bank: 1,
             ram_mode: false,
             ram_enabled: false, // TODO: is that the correct initial value?
-        }
-    }
-
-    /// Returns the real ROM bank number (with respect to `ram_mode`)
-    fn rom_bank(&self) -> usize {
-        if self.ram_mode {
-            (self.current_bank & 0b0001_1111) as usize
-        } else {
-            (self.current_bank & 0b0111_1111) as usize
-        }
-    }
-
-    /// Returns the real RAM bank number (with respect to `ram_mode`)
-    fn ram_bank(&self) -> usize {
-        if self.ram_mode {
-            ((self.current_bank & 0b0110_0000) >> 5) as usize
-        } else {
-            0
-        }
+        })
     }
 }
 
@@ -115,7 +103,7 @@ impl Mbc for Mbc1 {
 
             // 2 Bits of ROM or RAM bank
             0x4000..=0x5FFF => {
-                let new = byte.get() & 0b0110_0000;
+                let new = (byte.get() & 0b11) << 5;
                 self.current_bank = (self.current_bank & 0b1001_1111) | new;
             }
 
@@ -154,4 +142,78 @@ impl Mbc for Mbc1 {
             );
         }
     }
+
+    /// Returns the real ROM bank number (with respect to `ram_mode`)
+    fn rom_bank(&self) -> usize {
+        if self.ram_mode {
+            (self.current_bank & 0b0001_1111) as usize
+        } else {
+            (self.current_bank & 0b0111_1111) as usize
+        }
+    }
+
+    /// Returns the real RAM bank number (with respect to `ram_mode`)
+    fn ram_bank(&self) -> usize {
+        if self.ram_mode {
+            ((self.current_bank & 0b0110_0000) >> 5) as usize
+        } else {
+            0
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "MBC1"
+    }
+
+    fn ram_enabled(&self) -> Option<bool> {
+        Some(self.ram_enabled)
+    }
+
+    fn banking_mode(&self) -> Option<&'static str> {
+        Some(if self.ram_mode { "RAM banking" } else { "ROM banking" })
+    }
+
+    fn rom(&self) -> &[Byte] {
+        &self.rom
+    }
+
+    fn rom_mut(&mut self) -> &mut [Byte] {
+        Arc::make_mut(&mut self.rom)
+    }
+
+    fn ram(&self) -> &[Byte] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [Byte] {
+        &mut self.ram
+    }
+
+    fn box_clone(&self) -> Box<dyn Mbc> {
+        Box::new(self.clone())
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+        w.u8(self.current_bank);
+        w.bool(self.ram_mode);
+        w.bool(self.ram_enabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        r.bytes(&mut self.ram)?;
+        self.current_bank = r.u8()?;
+        self.ram_mode = r.bool()?;
+        self.ram_enabled = r.bool()?;
+        Ok(())
+    }
+
+    fn register_writes(&self) -> Vec<(Word, Byte)> {
+        vec![
+            (Word::new(0x0000), Byte::new(if self.ram_enabled { 0x0A } else { 0x00 })),
+            (Word::new(0x2000), Byte::new(self.current_bank & 0b0001_1111)),
+            (Word::new(0x4000), Byte::new((self.current_bank >> 5) & 0b11)),
+            (Word::new(0x6000), Byte::new(self.ram_mode as u8)),
+        ]
+    }
 }
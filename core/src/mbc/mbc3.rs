@@ -1,16 +1,21 @@
-use std::cmp::max;
+use std::{
+    cmp::max,
+    sync::Arc,
+};
 
 use crate::{
     log::*,
-    cartridge::{RamSize, RomSize},
+    cartridge::{CartridgeError, RamSize, RomSize},
     primitives::{Byte, Word},
+    save_state::{SaveStateError, StateReader, StateWriter},
 };
 use super::Mbc;
 
 /// Third version of the memory bank controller. In contrast to all other MBCs,
 /// this one can have a real time clock (RTC).
+#[derive(Clone)]
 pub(crate) struct Mbc3 {
-    rom: Box<[Byte]>,
+    rom: Arc<[Byte]>,
     ram: Box<[Byte]>,
 
     /// Stores the current ROM bank. 7 bits are usable, the MSB is always 0.
@@ -47,31 +52,32 @@ pub(crate) struct Mbc3 {
 
 
 impl Mbc3 {
-    pub(crate) fn new(data: &[u8], rom_size: RomSize, ram_size: RamSize) -> Self {
-        assert!(rom_size <= RomSize::Banks128, "More than 128 banks, but only MBC3!");
-        assert!(
-            rom_size.len() == data.len(),
-            "Length of cartridge doesn't match length specified in ROM size header",
-        );
-        assert!(
-            ram_size <= RamSize::Kb32,
-            "Illegal ram size {:?} for MBC3",
-            ram_size,
-        );
-
-        let rom: Vec<_> = data.iter().cloned().map(Byte::new).collect();
+    pub(crate) fn new(
+        data: &[u8],
+        rom_size: RomSize,
+        ram_size: RamSize,
+    ) -> Result<Self, CartridgeError> {
+        if rom_size > RomSize::Banks128 {
+            return Err(CartridgeError::UnsupportedRomSize { rom_size, mbc: "MBC3" });
+        }
+        if ram_size > RamSize::Kb32 {
+            return Err(CartridgeError::UnsupportedRamSize { ram_size, mbc: "MBC3" });
+        }
+        super::check_rom_len(data, rom_size)?;
+
+        let rom = data.iter().cloned().map(Byte::new).collect();
         let ram = vec![Byte::zero(); ram_size.len()];
 
         // TODO: are these all the correct initial values?
-        Self {
-            rom: rom.into_boxed_slice(),
+        Ok(Self {
+            rom,
             ram: ram.into_boxed_slice(),
             rom_bank: 0,
             ram_bank: 0,
             ram_enabled: false,
             rtc_regs: RtcRegisters::new(),
             latch_rtc: Byte::zero(),
-        }
+        })
     }
 }
 
@@ -209,10 +215,82 @@ impl Mbc for Mbc3 {
             _ => unreachable!(),
         }
     }
+
+    fn rom_bank(&self) -> usize {
+        // Bank 0 cannot be mapped, see `load_rom_byte`.
+        max(self.rom_bank, 1) as usize
+    }
+
+    fn ram_bank(&self) -> usize {
+        self.ram_bank as usize
+    }
+
+    fn name(&self) -> &'static str {
+        "MBC3"
+    }
+
+    fn ram_enabled(&self) -> Option<bool> {
+        Some(self.ram_enabled)
+    }
+
+    fn rom(&self) -> &[Byte] {
+        &self.rom
+    }
+
+    fn rom_mut(&mut self) -> &mut [Byte] {
+        Arc::make_mut(&mut self.rom)
+    }
+
+    fn ram(&self) -> &[Byte] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [Byte] {
+        &mut self.ram
+    }
+
+    fn box_clone(&self) -> Box<dyn Mbc> {
+        Box::new(self.clone())
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+        w.u8(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.bool(self.ram_enabled);
+        let rtc = &self.rtc_regs;
+        for &b in &[rtc.secs, rtc.mins, rtc.hours, rtc.days_low, rtc.extra, self.latch_rtc] {
+            w.byte(b);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        r.bytes(&mut self.ram)?;
+        self.rom_bank = r.u8()?;
+        self.ram_bank = r.u8()?;
+        self.ram_enabled = r.bool()?;
+        let rtc = &mut self.rtc_regs;
+        for b in [
+            &mut rtc.secs, &mut rtc.mins, &mut rtc.hours,
+            &mut rtc.days_low, &mut rtc.extra, &mut self.latch_rtc,
+        ] {
+            *b = r.byte()?;
+        }
+        Ok(())
+    }
+
+    fn register_writes(&self) -> Vec<(Word, Byte)> {
+        vec![
+            (Word::new(0x0000), Byte::new(if self.ram_enabled { 0x0A } else { 0x00 })),
+            (Word::new(0x2000), Byte::new(self.rom_bank)),
+            (Word::new(0x4000), Byte::new(self.ram_bank)),
+        ]
+    }
 }
 
 
 /// Everything related to the real time clock (RTC).
+#[derive(Clone)]
 struct RtcRegisters {
     /// Range 0 -- 59
     secs: Byte,
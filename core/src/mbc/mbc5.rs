@@ -1,7 +1,10 @@
+use std::sync::Arc;
+
 use crate::{
     log::*,
-    cartridge::{RamSize, RomSize},
+    cartridge::{CartridgeError, RamSize, RomSize},
     primitives::{Byte, Word},
+    save_state::{SaveStateError, StateReader, StateWriter},
 };
 use super::Mbc;
 
@@ -9,8 +12,9 @@ use super::Mbc;
 ///
 /// With this controller, the cartridge can have up to 8MiB of ROM and up to
 /// 128KiB of external RAM.
+#[derive(Clone)]
 pub(crate) struct Mbc5 {
-    rom: Box<[Byte]>,
+    rom: Arc<[Byte]>,
     ram: Box<[Byte]>,
 
     /// A 9 bit number to select the bank mapped to 0x4000 -- 0x8000. Values 0
@@ -27,28 +31,29 @@ pub(crate) struct Mbc5 {
 
 
 impl Mbc5 {
-    pub(crate) fn new(data: &[u8], rom_size: RomSize, ram_size: RamSize) -> Self {
-        assert!(rom_size <= RomSize::Banks512, "More than 128 banks, but only MBC5!");
-        assert!(
-            rom_size.len() == data.len(),
-            "Length of cartridge doesn't match length specified in ROM size header",
-        );
-        assert!(
-            [RamSize::None, RamSize::Kb8, RamSize::Kb32, RamSize::Kb128].contains(&ram_size),
-            "Illegal ram size {:?} for MBC5",
-            ram_size,
-        );
-
-        let rom: Vec<_> = data.iter().cloned().map(Byte::new).collect();
+    pub(crate) fn new(
+        data: &[u8],
+        rom_size: RomSize,
+        ram_size: RamSize,
+    ) -> Result<Self, CartridgeError> {
+        if rom_size > RomSize::Banks512 {
+            return Err(CartridgeError::UnsupportedRomSize { rom_size, mbc: "MBC5" });
+        }
+        if ![RamSize::None, RamSize::Kb8, RamSize::Kb32, RamSize::Kb128].contains(&ram_size) {
+            return Err(CartridgeError::UnsupportedRamSize { ram_size, mbc: "MBC5" });
+        }
+        super::check_rom_len(data, rom_size)?;
+
+        let rom = data.iter().cloned().map(Byte::new).collect();
         let ram = vec![Byte::zero(); ram_size.len()];
 
-        Self {
-            rom: rom.into_boxed_slice(),
+        Ok(Self {
+            rom,
             ram: ram.into_boxed_slice(),
             rom_bank: 0,
             ram_bank: 0,
             ram_enabled: false, // TODO: is that the correct initial value?
-        }
+        })
     }
 }
 
@@ -88,7 +93,7 @@ impl Mbc for Mbc5 {
 
             // Bit 9 of ROM bank number
             0x3000..=0x3FFF => {
-                self.rom_bank = (self.rom_bank & 0xFF) | (byte.get() as u16 & 1);
+                self.rom_bank = (self.rom_bank & 0xFF) | ((byte.get() as u16 & 1) << 8);
             }
 
             // RAM bank number
@@ -131,4 +136,64 @@ impl Mbc for Mbc5 {
             );
         }
     }
+
+    fn rom_bank(&self) -> usize {
+        self.rom_bank as usize
+    }
+
+    fn ram_bank(&self) -> usize {
+        self.ram_bank as usize
+    }
+
+    fn name(&self) -> &'static str {
+        "MBC5"
+    }
+
+    fn ram_enabled(&self) -> Option<bool> {
+        Some(self.ram_enabled)
+    }
+
+    fn rom(&self) -> &[Byte] {
+        &self.rom
+    }
+
+    fn rom_mut(&mut self) -> &mut [Byte] {
+        Arc::make_mut(&mut self.rom)
+    }
+
+    fn ram(&self) -> &[Byte] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [Byte] {
+        &mut self.ram
+    }
+
+    fn box_clone(&self) -> Box<dyn Mbc> {
+        Box::new(self.clone())
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+        w.u16(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.bool(self.ram_enabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        r.bytes(&mut self.ram)?;
+        self.rom_bank = r.u16()?;
+        self.ram_bank = r.u8()?;
+        self.ram_enabled = r.bool()?;
+        Ok(())
+    }
+
+    fn register_writes(&self) -> Vec<(Word, Byte)> {
+        vec![
+            (Word::new(0x0000), Byte::new(if self.ram_enabled { 0x0A } else { 0x00 })),
+            (Word::new(0x2000), Byte::new(self.rom_bank as u8)),
+            (Word::new(0x3000), Byte::new((self.rom_bank >> 8) as u8)),
+            (Word::new(0x4000), Byte::new(self.ram_bank)),
+        ]
+    }
 }
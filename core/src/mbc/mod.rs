@@ -1,19 +1,30 @@
 //! Memory bank controller trait and implementations.
 
 use crate::{
+    cartridge::{CartridgeError, RomSize},
+    env::GreyFrame,
     primitives::{Byte, Word},
+    save_state::{SaveStateError, StateReader, StateWriter},
 };
 pub(crate) use self::{
     no_mbc::NoMbc,
+    huc3::HuC3,
     mbc1::Mbc1,
     mbc3::Mbc3,
     mbc5::Mbc5,
+    pocket_camera::PocketCamera,
+    tama5::Tama5,
+    wisdom_tree::WisdomTree,
 };
 
 mod no_mbc;
 mod mbc1;
 mod mbc3;
 mod mbc5;
+mod pocket_camera;
+mod huc3;
+mod tama5;
+mod wisdom_tree;
 
 
 /// A memory bank controller.
@@ -21,7 +32,14 @@ mod mbc5;
 /// This part of the cartridge controls all writes and reads to and from ROM
 /// and external RAM. Usually, some kind of banking strategy is used to store
 /// more than `0x8000` bytes on the cartridge.
-pub(crate) trait Mbc {
+///
+/// The controllers of the supported cartridge types are created by
+/// `Cartridge::from_bytes`. Other hardware (e.g. flash carts or homebrew
+/// peripherals) can be emulated by implementing this trait and passing it to
+/// `Cartridge::with_mbc`.
+///
+/// `Send` is required to run machines on other threads (see `batch`).
+pub trait Mbc: Send {
     /// Loads one byte from the cartridge ROM. The `addr` has to be between `0`
     /// and `0x8000`.
     fn load_rom_byte(&self, addr: Word) -> Byte;
@@ -38,4 +56,213 @@ pub(crate) trait Mbc {
     /// Stores one byte to the external RAM. The `addr` is relative and has to
     /// be between `0` and `0x2000`.
     fn store_ram_byte(&mut self, addr: Word, byte: Byte);
+
+    /// Lets the hardware on the cartridge (e.g. a clock) run for `cycles`
+    /// machine cycles. This is called after every step. The default
+    /// implementation does nothing.
+    fn advance(&mut self, cycles: u8) {
+        let _ = cycles;
+    }
+
+    /// Returns the ROM bank currently mapped to `0x0000..0x4000`. The default
+    /// implementation returns 0, which is correct for all MBCs that do not
+    /// switch this area.
+    fn rom_bank0(&self) -> usize {
+        0
+    }
+
+    /// Returns the ROM bank currently mapped to `0x4000..0x8000`.
+    fn rom_bank(&self) -> usize;
+
+    /// Returns the RAM bank currently mapped to `0xA000..0xC000`. For MBC3,
+    /// this is the value of the RAM bank/RTC register select register.
+    fn ram_bank(&self) -> usize;
+
+    /// Returns the name of the mapper shown in debuggers, e.g. `"MBC1"`. The
+    /// default implementation returns `"custom"`.
+    fn name(&self) -> &'static str {
+        "custom"
+    }
+
+    /// Returns whether the external RAM is enabled or `None` if the mapper
+    /// has no RAM enable register. The default implementation returns `None`.
+    fn ram_enabled(&self) -> Option<bool> {
+        None
+    }
+
+    /// Returns a description of the current banking mode or `None` if the
+    /// mapper has only one. The default implementation returns `None`.
+    fn banking_mode(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Returns the full ROM (all banks).
+    fn rom(&self) -> &[Byte];
+
+    /// Returns the full ROM (all banks) for direct modification.
+    fn rom_mut(&mut self) -> &mut [Byte];
+
+    /// Returns the full external RAM (all banks). The default implementation
+    /// returns an empty slice.
+    fn ram(&self) -> &[Byte] {
+        &[]
+    }
+
+    /// Returns the full external RAM (all banks) for direct modification.
+    fn ram_mut(&mut self) -> &mut [Byte];
+
+    /// Clones this MBC. The ROM is shared between the clones until one of
+    /// them modifies it.
+    fn box_clone(&self) -> Box<dyn Mbc>;
+
+    /// Returns the size of the image to capture if the game started a
+    /// capture. The machine then requests the image from the peripherals
+    /// (see `Peripherals::capture_image`) and passes it to `finish_capture`.
+    fn capture_request(&self) -> Option<(usize, usize)> {
+        None
+    }
+
+    /// Finishes the capture requested by `capture_request`. `image` is
+    /// `None` if the peripherals have no image source.
+    fn finish_capture(&mut self, image: Option<GreyFrame>) {
+        let _ = image;
+    }
+
+    /// Returns whether the infrared LED of the cartridge is on or `None` if
+    /// the cartridge has no IR port. The default implementation returns
+    /// `None`.
+    fn ir_led(&self) -> Option<bool> {
+        None
+    }
+
+    /// Sets whether the IR sensor of the cartridge receives light. Without
+    /// calls to this, the cartridge never receives anything. The default
+    /// implementation does nothing.
+    fn set_ir_light(&mut self, received: bool) {
+        let _ = received;
+    }
+
+    /// Writes the RAM and the registers into a save state (see
+    /// `Machine::save_state`). The default implementation writes nothing, so
+    /// the state is not saved.
+    fn save_state(&self, w: &mut StateWriter) {
+        let _ = w;
+    }
+
+    /// Restores the state written by `save_state`.
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        let _ = r;
+        Ok(())
+    }
+
+    /// Returns register writes (address and value) that bring a freshly
+    /// created MBC into the current banking state. This is used by BESS save
+    /// states, which store MBCs this way. The default implementation returns
+    /// no writes.
+    fn register_writes(&self) -> Vec<(Word, Byte)> {
+        Vec::new()
+    }
+}
+
+impl Clone for Box<dyn Mbc> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+/// Makes sure that the length of the cartridge data matches the ROM size
+/// specified in the header. All MBC implementations rely on that.
+fn check_rom_len(data: &[u8], rom_size: RomSize) -> Result<(), CartridgeError> {
+    if rom_size.len() != data.len() {
+        return Err(CartridgeError::LengthMismatch { rom_size, actual: data.len() });
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{BiosKind, Emulator, HardwareModel, cartridge::Cartridge};
+    use super::*;
+
+    /// A mapper where every write to ROM selects the bank.
+    #[derive(Clone)]
+    struct AnyWriteMapper {
+        rom: Vec<Byte>,
+        bank: usize,
+    }
+
+    impl Mbc for AnyWriteMapper {
+        fn load_rom_byte(&self, addr: Word) -> Byte {
+            match addr.get() {
+                0x0000..=0x3FFF => self.rom[addr.get() as usize],
+                _ => self.rom[self.bank * 0x4000 + (addr.get() - 0x4000) as usize],
+            }
+        }
+        fn store_rom_byte(&mut self, _: Word, byte: Byte) {
+            self.bank = byte.get() as usize % (self.rom.len() / 0x4000);
+        }
+        fn load_ram_byte(&self, _: Word) -> Byte {
+            Byte::new(0xFF)
+        }
+        fn store_ram_byte(&mut self, _: Word, _: Byte) {}
+        fn rom_bank(&self) -> usize {
+            self.bank
+        }
+        fn ram_bank(&self) -> usize {
+            0
+        }
+        fn rom(&self) -> &[Byte] {
+            &self.rom
+        }
+        fn rom_mut(&mut self) -> &mut [Byte] {
+            &mut self.rom
+        }
+        fn ram_mut(&mut self) -> &mut [Byte] {
+            &mut []
+        }
+        fn box_clone(&self) -> Box<dyn Mbc> {
+            Box::new(self.clone())
+        }
+    }
+
+    #[test]
+    fn test_custom_mbc() {
+        // Four banks, each filled with its index.
+        let rom = (0..4 * 0x4000).map(|i| Byte::new((i / 0x4000) as u8)).collect::<Vec<_>>();
+        let mut mapper = AnyWriteMapper { rom, bank: 1 };
+        mapper.rom[0x0147] = Byte::new(0x00);
+        mapper.rom[0x0148] = Byte::new(0x01);
+        mapper.rom[0x0149] = Byte::new(0x00);
+
+        let cartridge = Cartridge::with_mbc(Box::new(mapper)).unwrap();
+        let mut emulator = Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        let machine = emulator.machine_mut();
+        assert_eq!(machine.load_byte(Word::new(0x4000)), Byte::new(1));
+        machine.store_byte(Word::new(0x7123), Byte::new(3));
+        assert_eq!(machine.load_byte(Word::new(0x4000)), Byte::new(3));
+        assert_eq!(machine.cartridge.rom_bank(), 3);
+        assert_eq!(machine.cartridge.mbc().name(), "custom");
+        assert_eq!(machine.cartridge.mbc().ram_enabled(), None);
+
+        let short = AnyWriteMapper { rom: vec![Byte::zero(); 0x100], bank: 1 };
+        assert!(Cartridge::with_mbc(Box::new(short)).is_err());
+    }
+
+    #[test]
+    fn test_mbc1_state() {
+        // MBC1 with 64KiB ROM and 8KiB RAM
+        let mut rom = vec![0; 0x10000];
+        rom[0x0147..0x014A].copy_from_slice(&[0x03, 0x01, 0x02]);
+        let mut cartridge = Cartridge::from_bytes(&rom).unwrap();
+        assert_eq!(cartridge.mbc().name(), "MBC1");
+        assert_eq!(cartridge.mbc().ram_enabled(), Some(false));
+        assert_eq!(cartridge.mbc().banking_mode(), Some("ROM banking"));
+
+        cartridge.mbc.store_rom_byte(Word::new(0x0000), Byte::new(0x0A));
+        cartridge.mbc.store_rom_byte(Word::new(0x6000), Byte::new(0x01));
+        assert_eq!(cartridge.mbc().ram_enabled(), Some(true));
+        assert_eq!(cartridge.mbc().banking_mode(), Some("RAM banking"));
+    }
 }
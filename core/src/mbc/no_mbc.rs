@@ -1,6 +1,9 @@
+use std::sync::Arc;
+
 use crate::{
-    cartridge::{RamSize, RomSize},
+    cartridge::{CartridgeError, RamSize, RomSize},
     primitives::{Byte, Word},
+    save_state::{SaveStateError, StateReader, StateWriter},
 };
 use super::Mbc;
 
@@ -11,28 +14,34 @@ use super::Mbc;
 /// are completely ignored.
 ///
 /// These cartridges might have extern RAM, though (however, at most 8KiB).
+#[derive(Clone)]
 pub(crate) struct NoMbc {
-    rom: Box<[Byte]>,
+    rom: Arc<[Byte]>,
     ram: Box<[Byte]>,
 }
 
 
 impl NoMbc {
-    pub(crate) fn new(data: &[u8], rom_size: RomSize, ram_size: RamSize) -> Self {
-        assert!(ram_size <= RamSize::Kb8, "More than 8KiB of RAM, but no MBC!");
-        assert!(rom_size == RomSize::NoBanking, "ROM banking, but no MBC!");
-        assert!(
-            rom_size.len() == data.len(),
-            "Length of cartridge doesn't match length specified in ROM size header",
-        );
-
-        let rom: Vec<_> = data.iter().cloned().map(Byte::new).collect();
+    pub(crate) fn new(
+        data: &[u8],
+        rom_size: RomSize,
+        ram_size: RamSize,
+    ) -> Result<Self, CartridgeError> {
+        if ram_size > RamSize::Kb8 {
+            return Err(CartridgeError::UnsupportedRamSize { ram_size, mbc: "no MBC" });
+        }
+        if rom_size != RomSize::NoBanking {
+            return Err(CartridgeError::UnsupportedRomSize { rom_size, mbc: "no MBC" });
+        }
+        super::check_rom_len(data, rom_size)?;
+
+        let rom = data.iter().cloned().map(Byte::new).collect();
         let ram = vec![Byte::zero(); ram_size.len()];
 
-        Self {
-            rom: rom.into_boxed_slice(),
+        Ok(Self {
+            rom,
             ram: ram.into_boxed_slice(),
-        }
+        })
     }
 }
 
@@ -64,4 +73,45 @@ impl Mbc for NoMbc {
             self.ram[idx] = byte;
         }
     }
+
+    fn rom_bank(&self) -> usize {
+        1
+    }
+
+    fn ram_bank(&self) -> usize {
+        0
+    }
+
+    fn name(&self) -> &'static str {
+        "none"
+    }
+
+    fn rom(&self) -> &[Byte] {
+        &self.rom
+    }
+
+    fn rom_mut(&mut self) -> &mut [Byte] {
+        Arc::make_mut(&mut self.rom)
+    }
+
+    fn ram(&self) -> &[Byte] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [Byte] {
+        &mut self.ram
+    }
+
+    fn box_clone(&self) -> Box<dyn Mbc> {
+        Box::new(self.clone())
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        r.bytes(&mut self.ram)?;
+        Ok(())
+    }
 }
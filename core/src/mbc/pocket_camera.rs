@@ -0,0 +1,347 @@
+use std::{
+    cmp::max,
+    sync::Arc,
+};
+
+use crate::{
+    log::*,
+    cartridge::{CartridgeError, RamSize, RomSize},
+    env::GreyFrame,
+    primitives::{Byte, Word},
+    save_state::{SaveStateError, StateReader, StateWriter},
+};
+use super::Mbc;
+
+/// Width of the images captured by the camera.
+const IMAGE_WIDTH: usize = 128;
+
+/// Height of the images captured by the camera.
+const IMAGE_HEIGHT: usize = 112;
+
+/// Offset of the captured image (as tile data) in RAM bank 0.
+const IMAGE_OFFSET: usize = 0x0100;
+
+/// Number of camera registers. They are mirrored every `0x80` bytes.
+const NUM_REGISTERS: usize = 0x36;
+
+/// Offset of the dithering matrix in the camera registers.
+const MATRIX_OFFSET: usize = 0x06;
+
+/// The exposure time for which the brightness of the image is not changed.
+const NEUTRAL_EXPOSURE: u32 = 0x0800;
+
+/// The controller of the Pocket Camera (Game Boy Camera).
+///
+/// Banking works like with MBC3 (without clock). Additionally, the registers
+/// of the camera sensor can be mapped to `0xA000..0xC000` by selecting RAM
+/// bank `0x10`. When a capture is started, the image is requested from the
+/// peripherals (see `Mbc::capture_request`) and written as tile data to RAM.
+///
+/// The analog processing of the sensor is only approximated: the exposure
+/// time scales the brightness linearly, the invert bit is respected and the
+/// image is converted to four shades with the dithering matrix. Gain and edge
+/// enhancement are ignored and captures finish immediately.
+#[derive(Clone)]
+pub(crate) struct PocketCamera {
+    rom: Arc<[Byte]>,
+    ram: Box<[Byte]>,
+
+    /// The ROM bank, 6 bits. Bank 0 is mapped as bank 1.
+    rom_bank: u8,
+
+    /// The RAM bank (values 0 to 0xF) or `0x10` if the camera registers are
+    /// mapped.
+    ram_bank: u8,
+
+    /// Whether or not the RAM is enabled for writing. Reading is always
+    /// possible.
+    ram_enabled: bool,
+
+    /// The camera registers. Bit 0 of the first one is set while a capture
+    /// is in progress.
+    registers: [Byte; NUM_REGISTERS],
+}
+
+
+impl PocketCamera {
+    pub(crate) fn new(
+        data: &[u8],
+        rom_size: RomSize,
+        ram_size: RamSize,
+    ) -> Result<Self, CartridgeError> {
+        if rom_size > RomSize::Banks64 {
+            return Err(CartridgeError::UnsupportedRomSize { rom_size, mbc: "Pocket Camera" });
+        }
+        if ram_size > RamSize::Kb128 {
+            return Err(CartridgeError::UnsupportedRamSize { ram_size, mbc: "Pocket Camera" });
+        }
+        super::check_rom_len(data, rom_size)?;
+
+        let rom = data.iter().cloned().map(Byte::new).collect();
+        let ram = vec![Byte::zero(); ram_size.len()];
+
+        Ok(Self {
+            rom,
+            ram: ram.into_boxed_slice(),
+            rom_bank: 0,
+            ram_bank: 0,
+            ram_enabled: false,
+            registers: [Byte::zero(); NUM_REGISTERS],
+        })
+    }
+
+    fn registers_mapped(&self) -> bool {
+        self.ram_bank & 0x10 != 0
+    }
+
+    /// Converts `image` to four shades and stores it as tile data at
+    /// `IMAGE_OFFSET`.
+    fn store_image(&mut self, image: &GreyFrame) {
+        let exposure = ((self.registers[2].get() as u32) << 8) | self.registers[3].get() as u32;
+        let invert = self.registers[4].get() & 0b1000 != 0;
+
+        for y in 0..IMAGE_HEIGHT {
+            for x in 0..IMAGE_WIDTH {
+                let brightness = image.pixels[y * IMAGE_WIDTH + x] as u32;
+                let mut value = (brightness * exposure / NEUTRAL_EXPOSURE).min(255) as u8;
+                if invert {
+                    value = 255 - value;
+                }
+
+                // Each entry of the 4x4 matrix has three thresholds.
+                let entry = MATRIX_OFFSET + ((y % 4) * 4 + x % 4) * 3;
+                let thresholds = &self.registers[entry..entry + 3];
+                let color = thresholds.iter().filter(|t| value < t.get()).count() as u8;
+
+                let tile = (y / 8) * (IMAGE_WIDTH / 8) + x / 8;
+                let idx = IMAGE_OFFSET + tile * 16 + (y % 8) * 2;
+                let mask = 0x80 >> (x % 8);
+                if let Some(bytes) = self.ram.get_mut(idx..idx + 2) {
+                    for (plane, byte) in bytes.iter_mut().enumerate() {
+                        *byte = byte.map(|b| if color & (1 << plane) != 0 {
+                            b | mask
+                        } else {
+                            b & !mask
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Mbc for PocketCamera {
+    fn load_rom_byte(&self, addr: Word) -> Byte {
+        match addr.get() {
+            // Always bank 0
+            0x0000..=0x3FFF => self.rom[addr.get() as usize],
+
+            // Bank 1 to N
+            0x4000..=0x7FFF => {
+                let bank_offset = max(self.rom_bank, 1) as usize * 0x4000;
+                let relative_addr = addr.get() as usize - 0x4000;
+
+                // Banks higher than specified in the header read as FF.
+                self.rom.get(bank_offset + relative_addr)
+                    .cloned()
+                    .unwrap_or(Byte::new(0xFF))
+            }
+
+            _ => unreachable!(),
+        }
+    }
+
+    fn store_rom_byte(&mut self, addr: Word, byte: Byte) {
+        match addr.get() {
+            // RAM enable
+            0x0000..=0x1FFF => self.ram_enabled = byte.get() & 0x0F == 0x0A,
+
+            // The ROM bank number
+            0x2000..=0x3FFF => self.rom_bank = byte.get() & 0b0011_1111,
+
+            // RAM bank or camera registers
+            0x4000..=0x5FFF => self.ram_bank = byte.get() & 0b0001_1111,
+
+            // This is unused; the write is ignored.
+            0x6000..=0x7FFF => {}
+
+            _ => unreachable!(),
+        }
+    }
+
+    fn load_ram_byte(&self, addr: Word) -> Byte {
+        if self.registers_mapped() {
+            // Only the first register can be read.
+            return match addr.get() % 0x80 {
+                0 => self.registers[0],
+                _ => Byte::zero(),
+            };
+        }
+
+        // If a value outside of the usable RAM is requested, we return FF.
+        self.ram.get((self.ram_bank & 0x0F) as usize * 0x2000 + addr.get() as usize)
+            .cloned()
+            .unwrap_or(Byte::new(0xFF))
+    }
+
+    fn store_ram_byte(&mut self, addr: Word, byte: Byte) {
+        if self.registers_mapped() {
+            match self.registers.get_mut((addr.get() % 0x80) as usize) {
+                Some(reg) => *reg = byte,
+                None => warn!("[camera] write to unknown camera register {}", addr),
+            }
+            return;
+        }
+
+        if !self.ram_enabled {
+            return;
+        }
+
+        // Writes outside of the valid RAM are ignored.
+        let idx = (self.ram_bank & 0x0F) as usize * 0x2000 + addr.get() as usize;
+        if idx < self.ram.len() {
+            self.ram[idx] = byte;
+        } else {
+            warn!(
+                "[camera] write outside of valid RAM (bank {}, address {})",
+                self.ram_bank,
+                addr,
+            );
+        }
+    }
+
+    fn rom_bank(&self) -> usize {
+        max(self.rom_bank, 1) as usize
+    }
+
+    fn ram_bank(&self) -> usize {
+        self.ram_bank as usize
+    }
+
+    fn name(&self) -> &'static str {
+        "Pocket Camera"
+    }
+
+    fn ram_enabled(&self) -> Option<bool> {
+        Some(self.ram_enabled)
+    }
+
+    fn rom(&self) -> &[Byte] {
+        &self.rom
+    }
+
+    fn rom_mut(&mut self) -> &mut [Byte] {
+        Arc::make_mut(&mut self.rom)
+    }
+
+    fn ram(&self) -> &[Byte] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [Byte] {
+        &mut self.ram
+    }
+
+    fn box_clone(&self) -> Box<dyn Mbc> {
+        Box::new(self.clone())
+    }
+
+    fn capture_request(&self) -> Option<(usize, usize)> {
+        if self.registers[0].get() & 1 != 0 {
+            Some((IMAGE_WIDTH, IMAGE_HEIGHT))
+        } else {
+            None
+        }
+    }
+
+    fn finish_capture(&mut self, image: Option<GreyFrame>) {
+        match image {
+            Some(image) if image.width == IMAGE_WIDTH && image.height == IMAGE_HEIGHT => {
+                self.store_image(&image);
+            }
+            Some(image) => warn!(
+                "[camera] captured image has size {}x{}, but {}x{} was requested",
+                image.width,
+                image.height,
+                IMAGE_WIDTH,
+                IMAGE_HEIGHT,
+            ),
+
+            // Without an image source, the previous image is kept.
+            None => {}
+        }
+
+        self.registers[0] = self.registers[0].map(|b| b & !1);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+        w.u8(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.bool(self.ram_enabled);
+        w.bytes(&self.registers);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        r.bytes(&mut self.ram)?;
+        self.rom_bank = r.u8()?;
+        self.ram_bank = r.u8()?;
+        self.ram_enabled = r.bool()?;
+        r.bytes(&mut self.registers)?;
+        Ok(())
+    }
+
+    fn register_writes(&self) -> Vec<(Word, Byte)> {
+        vec![
+            (Word::new(0x0000), Byte::new(if self.ram_enabled { 0x0A } else { 0x00 })),
+            (Word::new(0x2000), Byte::new(self.rom_bank)),
+            (Word::new(0x4000), Byte::new(self.ram_bank)),
+        ]
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_capture() {
+        let data = vec![0; RomSize::Banks64.len()];
+        let mut camera = PocketCamera::new(&data, RomSize::Banks64, RamSize::Kb128).unwrap();
+        let reg = |camera: &mut PocketCamera, idx: u16, value: u8| {
+            camera.store_ram_byte(Word::new(idx), Byte::new(value));
+        };
+
+        // Select the registers, set the neutral exposure and the same
+        // thresholds for all matrix entries.
+        camera.store_rom_byte(Word::new(0x4000), Byte::new(0x10));
+        reg(&mut camera, 0x02, 0x08);
+        reg(&mut camera, 0x03, 0x00);
+        for entry in 0..16 {
+            reg(&mut camera, 0x06 + entry * 3, 0x40);
+            reg(&mut camera, 0x07 + entry * 3, 0x80);
+            reg(&mut camera, 0x08 + entry * 3, 0xC0);
+        }
+        assert_eq!(camera.capture_request(), None);
+
+        reg(&mut camera, 0x00, 0x01);
+        assert_eq!(camera.load_ram_byte(Word::new(0x80)), Byte::new(0x01));
+        assert_eq!(camera.capture_request(), Some((IMAGE_WIDTH, IMAGE_HEIGHT)));
+
+        // Columns with increasing brightness: black, dark, light, white.
+        let pixels = (0..IMAGE_WIDTH * IMAGE_HEIGHT)
+            .map(|i| [0x00, 0x50, 0x90, 0xFF][i % 4])
+            .collect();
+        camera.finish_capture(Some(GreyFrame { width: IMAGE_WIDTH, height: IMAGE_HEIGHT, pixels }));
+        assert_eq!(camera.capture_request(), None);
+        assert_eq!(camera.load_ram_byte(Word::new(0x00)), Byte::zero());
+
+        // Colors 3, 2, 1, 0 repeated result in these bit planes.
+        camera.store_rom_byte(Word::new(0x4000), Byte::new(0x00));
+        for row in 0..8 {
+            assert_eq!(camera.load_ram_byte(Word::new(0x100 + row * 2)), Byte::new(0b1010_1010));
+            assert_eq!(camera.load_ram_byte(Word::new(0x101 + row * 2)), Byte::new(0b1100_1100));
+        }
+    }
+}
@@ -0,0 +1,400 @@
+use std::sync::Arc;
+
+use crate::{
+    MACHINE_CYCLES_PER_SECOND,
+    log::*,
+    cartridge::{CartridgeError, RomSize},
+    primitives::{Byte, Word},
+    save_state::{SaveStateError, StateReader, StateWriter},
+};
+use super::Mbc;
+
+/// Size of the EEPROM in bytes.
+const EEPROM_LEN: usize = 0x20;
+
+/// Number of nibbles in the timer page of the clock.
+const TIMER_PAGE_LEN: usize = 0x10;
+
+// The registers selected by writing to `0xA001`. All registers are 4 bits
+// wide.
+const REG_BANK_LO: usize = 0x0;
+const REG_BANK_HI: usize = 0x1;
+const REG_DATA_LO: usize = 0x4;
+const REG_DATA_HI: usize = 0x5;
+const REG_COMMAND: usize = 0x6;
+const REG_ADDR_LO: usize = 0x7;
+const REG_READY: usize = 0xA;
+const REG_OUT_LO: usize = 0xC;
+const REG_OUT_HI: usize = 0xD;
+
+// Positions of the values in the timer page. All but the weekday are stored
+// as two BCD digits (low digit first).
+const SECONDS: usize = 0x0;
+const MINUTES: usize = 0x2;
+const HOURS: usize = 0x4;
+const WEEKDAY: usize = 0x6;
+const DAY: usize = 0x7;
+const MONTH: usize = 0x9;
+const YEAR: usize = 0xB;
+
+/// The Bandai TAMA5 controller used by Tamagotchi 3.
+///
+/// All registers are accessed through two addresses: writing to `0xA001`
+/// selects a register, `0xA000` then reads or writes its 4 bit value. Writing
+/// the low address register executes the command in the command register
+/// with the address and the data from the other registers:
+///
+/// - `0`: write the data byte to the EEPROM
+/// - `1`: read a byte from the EEPROM into the output registers
+/// - `2`: clock control (stop, start, set or read minutes and hours)
+/// - `4`: write a nibble of the timer page of the clock
+///
+/// The clock runs with the emulated time. Like the EEPROM, its timer page is
+/// part of the external RAM (see `Mbc::ram`), so it is persisted with it.
+#[derive(Clone)]
+pub(crate) struct Tama5 {
+    rom: Arc<[Byte]>,
+
+    /// The EEPROM followed by the timer page of the clock (one nibble per
+    /// byte).
+    ram: Box<[Byte]>,
+
+    registers: [u8; 16],
+
+    /// The register accessed through `0xA000`.
+    selected: u8,
+
+    /// The result of the last read command, readable through the output
+    /// registers.
+    out: u8,
+
+    /// Whether or not the clock is running.
+    clock_running: bool,
+
+    /// Cycles since the clock last advanced by one second.
+    clock_cycles: u32,
+}
+
+
+impl Tama5 {
+    pub(crate) fn new(data: &[u8], rom_size: RomSize) -> Result<Self, CartridgeError> {
+        if rom_size > RomSize::Banks32 {
+            return Err(CartridgeError::UnsupportedRomSize { rom_size, mbc: "TAMA5" });
+        }
+        super::check_rom_len(data, rom_size)?;
+
+        let rom = data.iter().cloned().map(Byte::new).collect();
+        let mut out = Self {
+            rom,
+            ram: vec![Byte::zero(); EEPROM_LEN + TIMER_PAGE_LEN].into_boxed_slice(),
+            registers: [0; 16],
+            selected: 0,
+            out: 0,
+            clock_running: true,
+            clock_cycles: 0,
+        };
+        out.set_clock(DAY, 1);
+        out.set_clock(MONTH, 1);
+
+        Ok(out)
+    }
+
+    fn data(&self) -> u8 {
+        (self.registers[REG_DATA_HI] << 4) | self.registers[REG_DATA_LO]
+    }
+
+    /// Returns the two digit value at `pos` in the timer page. Each digit is
+    /// stored in the low nibble of its byte; the rest is ignored, as the RAM
+    /// might come from a corrupted save.
+    fn clock(&self, pos: usize) -> u8 {
+        let page = &self.ram[EEPROM_LEN..];
+        (page[pos].get() & 0xF) + 10 * (page[pos + 1].get() & 0xF)
+    }
+
+    fn set_clock(&mut self, pos: usize, value: u8) {
+        let page = &mut self.ram[EEPROM_LEN..];
+        page[pos] = Byte::new(value % 10);
+        page[pos + 1] = Byte::new(value / 10);
+    }
+
+    /// Advances the clock by one second.
+    fn tick(&mut self) {
+        let seconds = self.clock(SECONDS) + 1;
+        self.set_clock(SECONDS, seconds % 60);
+        if seconds < 60 {
+            return;
+        }
+
+        let minutes = self.clock(MINUTES) + 1;
+        self.set_clock(MINUTES, minutes % 60);
+        if minutes < 60 {
+            return;
+        }
+
+        let hours = self.clock(HOURS) + 1;
+        self.set_clock(HOURS, hours % 24);
+        if hours < 24 {
+            return;
+        }
+
+        let weekday = &mut self.ram[EEPROM_LEN + WEEKDAY];
+        *weekday = Byte::new((weekday.get() % 7 + 1) % 7);
+
+        let (month, year) = (self.clock(MONTH), self.clock(YEAR));
+        let days_in_month = match month {
+            2 if year % 4 == 0 => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        };
+        let day = self.clock(DAY) + 1;
+        if day <= days_in_month {
+            self.set_clock(DAY, day);
+            return;
+        }
+
+        self.set_clock(DAY, 1);
+        if month < 12 {
+            self.set_clock(MONTH, month + 1);
+        } else {
+            self.set_clock(MONTH, 1);
+            self.set_clock(YEAR, (year + 1) % 100);
+        }
+    }
+
+    /// Executes the command in the command register.
+    fn execute(&mut self) {
+        let command = self.registers[REG_COMMAND] >> 1;
+        let addr = ((self.registers[REG_COMMAND] & 1) << 4) | self.registers[REG_ADDR_LO];
+        let bcd = |v: u8| ((v / 10) << 4) | (v % 10);
+        let from_bcd = |v: u8| (v >> 4) * 10 + (v & 0xF);
+
+        match (command, addr) {
+            (0, _) => self.ram[addr as usize] = Byte::new(self.data()),
+            (1, _) => self.out = self.ram[addr as usize].get(),
+
+            // Clock control
+            (2, 0x00) => self.clock_running = false,
+            (2, 0x01) => self.clock_running = true,
+            (2, 0x04) => {
+                self.set_clock(MINUTES, from_bcd(self.data()) % 60);
+                self.set_clock(SECONDS, 0);
+                self.clock_cycles = 0;
+            }
+            (2, 0x05) => self.set_clock(HOURS, from_bcd(self.data()) % 24),
+            (2, 0x06) => self.out = bcd(self.clock(MINUTES)),
+            (2, 0x07) => self.out = bcd(self.clock(HOURS)),
+            (2, 0x10) | (2, 0x11) => debug!("[tama5] ignoring alarm command {:02x}", addr),
+
+            // Timer page write. The low address register selects the page,
+            // but only the timer page is emulated.
+            (4, 0) => {
+                let pos = self.registers[REG_DATA_LO] as usize;
+                self.ram[EEPROM_LEN + pos] = Byte::new(self.registers[REG_DATA_HI]);
+            }
+
+            _ => warn!("[tama5] unknown command {} with address {:02x}", command, addr),
+        }
+    }
+}
+
+impl Mbc for Tama5 {
+    fn load_rom_byte(&self, addr: Word) -> Byte {
+        match addr.get() {
+            // Always bank 0
+            0x0000..=0x3FFF => self.rom[addr.get() as usize],
+
+            // Bank 0 to N
+            0x4000..=0x7FFF => {
+                let bank_offset = self.rom_bank() * 0x4000;
+                let relative_addr = addr.get() as usize - 0x4000;
+
+                // The game might enable a bank higher than specified in the
+                // header. In that case we return FF.
+                self.rom.get(bank_offset + relative_addr)
+                    .cloned()
+                    .unwrap_or(Byte::new(0xFF))
+            }
+
+            _ => unreachable!(),
+        }
+    }
+
+    fn store_rom_byte(&mut self, addr: Word, byte: Byte) {
+        warn!("[tama5] write to ROM ({} <- {})", addr, byte);
+    }
+
+    fn load_ram_byte(&self, addr: Word) -> Byte {
+        if addr.get() & 1 != 0 {
+            return Byte::new(0xFF);
+        }
+
+        match self.selected as usize {
+            REG_OUT_LO => Byte::new(0xF0 | (self.out & 0xF)),
+            REG_OUT_HI => Byte::new(0xF0 | (self.out >> 4)),
+
+            // Bit 0 signals that the controller is ready.
+            REG_READY => Byte::new(0xF1),
+            _ => {
+                warn!("[tama5] read from register {:x}", self.selected);
+                Byte::new(0xF1)
+            }
+        }
+    }
+
+    fn store_ram_byte(&mut self, addr: Word, byte: Byte) {
+        if addr.get() & 1 != 0 {
+            self.selected = byte.get() & 0xF;
+            return;
+        }
+
+        let reg = self.selected as usize;
+        self.registers[reg] = byte.get() & 0xF;
+        if reg == REG_ADDR_LO {
+            self.execute();
+        }
+    }
+
+    fn advance(&mut self, cycles: u8) {
+        if !self.clock_running {
+            return;
+        }
+
+        self.clock_cycles += cycles as u32;
+        if self.clock_cycles >= MACHINE_CYCLES_PER_SECOND {
+            self.clock_cycles -= MACHINE_CYCLES_PER_SECOND;
+            self.tick();
+        }
+    }
+
+    fn rom_bank(&self) -> usize {
+        (self.registers[REG_BANK_LO] | ((self.registers[REG_BANK_HI] & 1) << 4)) as usize
+    }
+
+    fn ram_bank(&self) -> usize {
+        0
+    }
+
+    fn name(&self) -> &'static str {
+        "TAMA5"
+    }
+
+    fn rom(&self) -> &[Byte] {
+        &self.rom
+    }
+
+    fn rom_mut(&mut self) -> &mut [Byte] {
+        Arc::make_mut(&mut self.rom)
+    }
+
+    fn ram(&self) -> &[Byte] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [Byte] {
+        &mut self.ram
+    }
+
+    fn box_clone(&self) -> Box<dyn Mbc> {
+        Box::new(self.clone())
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+        for &reg in &self.registers {
+            w.u8(reg);
+        }
+        w.u8(self.selected);
+        w.u8(self.out);
+        w.bool(self.clock_running);
+        w.u32(self.clock_cycles);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        r.bytes(&mut self.ram)?;
+        for reg in &mut self.registers {
+            *reg = r.u8()? & 0xF;
+        }
+        self.selected = r.u8()? & 0xF;
+        self.out = r.u8()?;
+        self.clock_running = r.bool()?;
+        self.clock_cycles = r.u32()?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Writes `value` to the register `reg`.
+    fn write(tama: &mut Tama5, reg: usize, value: u8) {
+        tama.store_ram_byte(Word::new(0x0001), Byte::new(reg as u8));
+        tama.store_ram_byte(Word::new(0x0000), Byte::new(value));
+    }
+
+    /// Executes `command` with the given address and data and returns the
+    /// output registers.
+    fn command(tama: &mut Tama5, command: u8, addr: u8, data: u8) -> u8 {
+        write(tama, REG_DATA_LO, data & 0xF);
+        write(tama, REG_DATA_HI, data >> 4);
+        write(tama, REG_COMMAND, (command << 1) | (addr >> 4));
+        write(tama, REG_ADDR_LO, addr & 0xF);
+
+        let mut out = 0;
+        for &reg in &[REG_OUT_HI, REG_OUT_LO] {
+            tama.store_ram_byte(Word::new(0x0001), Byte::new(reg as u8));
+            out = (out << 4) | (tama.load_ram_byte(Word::new(0x0000)).get() & 0xF);
+        }
+        out
+    }
+
+    #[test]
+    fn test_tama5() {
+        // Each bank filled with its index.
+        let data = (0..RomSize::Banks32.len()).map(|i| (i / 0x4000) as u8).collect::<Vec<_>>();
+        let mut tama = Tama5::new(&data, RomSize::Banks32).unwrap();
+
+        write(&mut tama, REG_BANK_LO, 0x3);
+        write(&mut tama, REG_BANK_HI, 0x1);
+        assert_eq!(tama.rom_bank(), 0x13);
+        assert_eq!(tama.load_rom_byte(Word::new(0x4000)), Byte::new(0x13));
+
+        tama.store_ram_byte(Word::new(0x0001), Byte::new(REG_READY as u8));
+        assert_eq!(tama.load_ram_byte(Word::new(0x0000)), Byte::new(0xF1));
+
+        // EEPROM
+        command(&mut tama, 0, 0x1A, 0xC5);
+        assert_eq!(tama.ram()[0x1A], Byte::new(0xC5));
+        assert_eq!(command(&mut tama, 1, 0x1A, 0), 0xC5);
+
+        // Set the clock to 23:59 and let two minutes pass.
+        command(&mut tama, 2, 0x05, 0x23);
+        command(&mut tama, 2, 0x04, 0x59);
+        for _ in 0..120 * MACHINE_CYCLES_PER_SECOND / 16 {
+            tama.advance(16);
+        }
+        assert_eq!(command(&mut tama, 2, 0x07, 0), 0x00);
+        assert_eq!(command(&mut tama, 2, 0x06, 0), 0x01);
+        assert_eq!(tama.clock(DAY), 2);
+        assert_eq!(tama.ram()[EEPROM_LEN + WEEKDAY], Byte::new(1));
+
+        // A stopped clock does not advance.
+        command(&mut tama, 2, 0x00, 0);
+        for _ in 0..120 * MACHINE_CYCLES_PER_SECOND / 16 {
+            tama.advance(16);
+        }
+        assert_eq!(command(&mut tama, 2, 0x06, 0), 0x01);
+
+        // Garbage in the timer page (e.g. from a corrupted save) doesn't
+        // break the clock.
+        for b in &mut tama.ram_mut()[EEPROM_LEN..] {
+            *b = Byte::new(0xFF);
+        }
+        command(&mut tama, 2, 0x01, 0);
+        for _ in 0..2 * MACHINE_CYCLES_PER_SECOND / 16 {
+            tama.advance(16);
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::sync::Arc;
+
+use crate::{
+    cartridge::{CartridgeError, RomSize},
+    primitives::{Byte, Word},
+    save_state::{SaveStateError, StateReader, StateWriter},
+};
+use super::Mbc;
+
+/// Size of one bank of this controller.
+const BANK_SIZE: usize = 0x8000;
+
+/// The unlicensed mapper used by the games of Wisdom Tree.
+///
+/// The whole `0x0000..0x8000` range is switched at once: a write to any ROM
+/// address selects the 32KiB bank with the number in the lower byte of the
+/// address (the written value is ignored). There is no RAM.
+///
+/// The header of these games claims that there is no MBC and a ROM size of
+/// 32KiB, so the mapper is detected by the title instead (see `detect`).
+#[derive(Clone)]
+pub(crate) struct WisdomTree {
+    rom: Arc<[Byte]>,
+
+    /// The 32KiB bank mapped to `0x0000..0x8000`.
+    bank: u8,
+}
+
+
+impl WisdomTree {
+    /// Returns whether `data` is a game using this mapper.
+    pub(crate) fn detect(data: &[u8]) -> bool {
+        let title = &data[0x0134..0x013F];
+        data.len() > BANK_SIZE && (title == b"WISDOM TREE" || title == b"WISDOM\0TREE")
+    }
+
+    pub(crate) fn new(data: &[u8], rom_size: RomSize) -> Result<Self, CartridgeError> {
+        if !data.len().is_multiple_of(BANK_SIZE) || data.len() > 0x100 * BANK_SIZE {
+            return Err(CartridgeError::LengthMismatch { rom_size, actual: data.len() });
+        }
+
+        Ok(Self {
+            rom: data.iter().cloned().map(Byte::new).collect(),
+            bank: 0,
+        })
+    }
+}
+
+impl Mbc for WisdomTree {
+    fn load_rom_byte(&self, addr: Word) -> Byte {
+        // The game might select a bank higher than the ROM. In that case we
+        // return FF.
+        self.rom.get(self.bank as usize * BANK_SIZE + addr.get() as usize)
+            .cloned()
+            .unwrap_or(Byte::new(0xFF))
+    }
+
+    fn store_rom_byte(&mut self, addr: Word, _: Byte) {
+        self.bank = addr.get() as u8;
+    }
+
+    fn load_ram_byte(&self, _: Word) -> Byte {
+        Byte::new(0xFF)
+    }
+
+    fn store_ram_byte(&mut self, _: Word, _: Byte) {}
+
+    fn rom_bank0(&self) -> usize {
+        self.bank as usize * 2
+    }
+
+    fn rom_bank(&self) -> usize {
+        self.bank as usize * 2 + 1
+    }
+
+    fn ram_bank(&self) -> usize {
+        0
+    }
+
+    fn name(&self) -> &'static str {
+        "Wisdom Tree"
+    }
+
+    fn rom(&self) -> &[Byte] {
+        &self.rom
+    }
+
+    fn rom_mut(&mut self) -> &mut [Byte] {
+        Arc::make_mut(&mut self.rom)
+    }
+
+    fn ram_mut(&mut self) -> &mut [Byte] {
+        &mut []
+    }
+
+    fn box_clone(&self) -> Box<dyn Mbc> {
+        Box::new(self.clone())
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.bank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.bank = r.u8()?;
+        Ok(())
+    }
+
+    fn register_writes(&self) -> Vec<(Word, Byte)> {
+        vec![(Word::new(self.bank as u16), Byte::zero())]
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{BiosKind, HardwareModel, cartridge::Cartridge, machine::Machine};
+    use super::*;
+
+    #[test]
+    fn test_wisdom_tree() {
+        // Four banks, each filled with its index. The header claims 32KiB
+        // without MBC.
+        let mut rom = (0..4 * BANK_SIZE).map(|i| (i / BANK_SIZE) as u8).collect::<Vec<_>>();
+        rom[0x0134..0x013F].copy_from_slice(b"WISDOM TREE");
+        rom[0x0143..0x014A].copy_from_slice(&[0, 0, 0, 0, 0x00, 0x00, 0x00]);
+        let cartridge = Cartridge::from_bytes(&rom).unwrap();
+        assert_eq!(cartridge.mbc().name(), "Wisdom Tree");
+
+        let mut machine = Machine::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        machine.store_byte(Word::new(0x3F02), Byte::new(0xFF));
+        assert_eq!(machine.load_byte(Word::new(0x0200)), Byte::new(2));
+        assert_eq!(machine.load_byte(Word::new(0x7FFF)), Byte::new(2));
+        assert_eq!(machine.cartridge.rom_bank(), 5);
+        machine.store_byte(Word::new(0x7F03), Byte::zero());
+        assert_eq!(machine.load_byte(Word::new(0x4000)), Byte::new(3));
+        machine.store_byte(Word::new(0x2004), Byte::zero());
+        assert_eq!(machine.load_byte(Word::new(0x4000)), Byte::new(0xFF));
+
+        rom[0x0134..0x013F].copy_from_slice(b"OTHER GAME ");
+        assert!(Cartridge::from_bytes(&rom).is_err());
+    }
+}
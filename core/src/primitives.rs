@@ -478,52 +478,92 @@ impl Display for Word {
 }
 
 
-/// A chunk of Gameboy memory. Can be indexed by `Word`.
-pub struct Memory(Box<[Byte]>);
+/// A chunk of Gameboy memory of `N` bytes. Can be indexed by `Word`.
+///
+/// The bytes are stored inline, so accesses don't need to follow a pointer
+/// and copying a machine (e.g. for save states) is a single `memcpy`.
+#[derive(Clone)]
+pub struct Memory<const N: usize>([Byte; N]);
 
-impl Memory {
-    /// Returns a slice of memory with the specified length where all bytes are
-    /// set to 0.
-    pub fn zeroed(len: Word) -> Self {
-        Memory(vec![Byte::zero(); len.get() as usize].into_boxed_slice())
+impl<const N: usize> Memory<N> {
+    /// Returns memory where all bytes are set to 0.
+    pub fn zeroed() -> Self {
+        Memory([Byte::zero(); N])
     }
 
+    /// Returns memory with the given content. Panics if `bytes` is not
+    /// exactly `N` bytes long.
     pub fn from_bytes(bytes: &[u8]) -> Self {
-        let copy: Vec<_> = bytes.iter().cloned().map(Byte::new).collect();
-        Memory(copy.into_boxed_slice())
+        assert_eq!(bytes.len(), N, "memory has to be initialized with {} bytes", N);
+
+        let mut out = Self::zeroed();
+        for (dst, &src) in out.0.iter_mut().zip(bytes) {
+            *dst = Byte::new(src);
+        }
+        out
     }
 
     pub fn len(&self) -> Word {
-        Word::new(self.0.len() as u16)
+        Word::new(N as u16)
     }
 
     pub fn as_slice(&self) -> &[Byte] {
         &self.0
     }
+
+    pub fn as_mut_slice(&mut self) -> &mut [Byte] {
+        &mut self.0
+    }
+
+    /// Copies `src` into the memory, starting at `start`. Panics if `src`
+    /// does not fit.
+    pub fn copy_from_slice(&mut self, start: Word, src: &[Byte]) {
+        let start = start.0 as usize;
+        self.0[start..start + src.len()].copy_from_slice(src);
+    }
+
+    /// Copies the bytes starting at `start` into `dst`. Panics if there are
+    /// not enough bytes.
+    pub fn copy_to_slice(&self, start: Word, dst: &mut [Byte]) {
+        let start = start.0 as usize;
+        dst.copy_from_slice(&self.0[start..start + dst.len()]);
+    }
+
+    /// Sets all bytes to `value`.
+    pub fn fill(&mut self, value: Byte) {
+        self.0.fill(value);
+    }
 }
 
-impl Index<Word> for Memory {
+impl<const N: usize> Index<Word> for Memory<N> {
     type Output = Byte;
 
     #[inline(always)]
     fn index(&self, index: Word) -> &Self::Output {
-        &(*self.0)[index.0 as usize]
+        &self.0[index.0 as usize]
     }
 }
 
-impl Index<Range<Word>> for Memory {
+impl<const N: usize> Index<Range<Word>> for Memory<N> {
     type Output = [Byte];
 
     #[inline(always)]
     fn index(&self, index: Range<Word>) -> &Self::Output {
-        &(*self.0)[index.start.0 as usize..index.end.0 as usize]
+        &self.0[index.start.0 as usize..index.end.0 as usize]
     }
 }
 
-impl IndexMut<Word> for Memory {
+impl<const N: usize> IndexMut<Word> for Memory<N> {
     #[inline(always)]
     fn index_mut(&mut self, index: Word) -> &mut Self::Output {
-        &mut (*self.0)[index.0 as usize]
+        &mut self.0[index.0 as usize]
+    }
+}
+
+impl<const N: usize> IndexMut<Range<Word>> for Memory<N> {
+    #[inline(always)]
+    fn index_mut(&mut self, index: Range<Word>) -> &mut Self::Output {
+        &mut self.0[index.start.0 as usize..index.end.0 as usize]
     }
 }
 
@@ -698,4 +738,21 @@ mod test {
         assert_eq!(run(0b1111_0000, 4..=7), 0x0F);
         assert_eq!(run(0b1001_1010, 2..=4), 6);
     }
+
+    #[test]
+    fn test_memory_bulk() {
+        let mut mem = Memory::<0x10>::from_bytes(&[0xAA; 0x10]);
+        mem.copy_from_slice(Word::new(0x04), &[Byte::new(1), Byte::new(2), Byte::new(3)]);
+        assert_eq!(mem[Word::new(0x03)], Byte::new(0xAA));
+        assert_eq!(mem[Word::new(0x05)], Byte::new(2));
+        assert_eq!(mem[Word::new(0x07)], Byte::new(0xAA));
+
+        let mut dst = [Byte::zero(); 2];
+        mem.copy_to_slice(Word::new(0x05), &mut dst);
+        assert_eq!(dst, [Byte::new(2), Byte::new(3)]);
+
+        mem.fill(Byte::new(0x11));
+        assert!(mem.as_slice().iter().all(|&b| b == Byte::new(0x11)));
+        assert_eq!(mem.len(), Word::new(0x10));
+    }
 }
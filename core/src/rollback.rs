@@ -0,0 +1,351 @@
+//! Rollback netcode for two linked emulators (see `link`), to play link
+//! cable games over a network despite latency.
+//!
+//! Both sides execute both Game Boys. The local input of each frame is known
+//! immediately, while the remote input arrives later. Until then, the remote
+//! player is assumed to still press the keys of the last known frame. If the
+//! actual input differs from that prediction, the state before that frame is
+//! restored and all frames since then are executed again. For that, the
+//! states of the last frames are kept.
+//!
+//! The transport of the inputs is up to the frontend; see
+//! `Rollback::add_remote_input`.
+
+use std::collections::VecDeque;
+
+use crate::{
+    Disruption, Emulator, SCREEN_WIDTH,
+    env::{Frame, GreyFrame, Peripherals},
+    link::execute_linked_frame,
+    log::*,
+    machine::{Machine, input::Keys},
+    primitives::PixelColor,
+};
+
+
+/// How many frames beyond `max_rollback` a remote input may be ahead of the
+/// local side. The remote side can't get further ahead, so inputs for later
+/// frames are invalid.
+const INPUT_SLACK: u64 = 8;
+
+/// The two linked emulators of a rollback session.
+pub struct Rollback {
+    emulators: [Emulator; 2],
+
+    /// The index of the emulator controlled by the local player.
+    local: usize,
+
+    /// Number of executed frames.
+    frame: u64,
+
+    /// The maximum number of frames the local side can be ahead of the
+    /// confirmed remote input.
+    max_rollback: u64,
+
+    /// The local keys of all executed frames.
+    local_inputs: Vec<Keys>,
+
+    /// The remote keys of all frames received so far.
+    remote_inputs: Vec<Option<Keys>>,
+
+    /// The remote keys (received or predicted) each frame was executed with.
+    used_remote_inputs: Vec<Keys>,
+
+    /// Number of frames from the start for which the remote input is known.
+    confirmed: u64,
+
+    /// The states of both machines before the last executed frames, with
+    /// the frame number.
+    states: VecDeque<(u64, [Machine; 2])>,
+
+    /// The first frame which was executed with a wrong prediction.
+    rollback_to: Option<u64>,
+}
+
+impl Rollback {
+    /// Creates a session with both emulators at the same state on both sides.
+    /// `local` (0 or 1) is the index of the emulator of the local player. The
+    /// local side is never more than `max_rollback` frames ahead of the
+    /// remote side.
+    pub fn new(emulators: [Emulator; 2], local: usize, max_rollback: u64) -> Self {
+        assert!(local < 2, "the local player has to be 0 or 1");
+
+        Self {
+            emulators,
+            local,
+            frame: 0,
+            max_rollback: max_rollback.max(1),
+            local_inputs: Vec::new(),
+            remote_inputs: Vec::new(),
+            used_remote_inputs: Vec::new(),
+            confirmed: 0,
+            states: VecDeque::new(),
+            rollback_to: None,
+        }
+    }
+
+    /// Returns both emulators.
+    pub fn emulators(&self) -> &[Emulator; 2] {
+        &self.emulators
+    }
+
+    /// Returns the number of executed frames, which is also the number of
+    /// the next frame.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Returns the local keys of the given frame, e.g. to send them again if
+    /// they might have been lost.
+    pub fn local_input(&self, frame: u64) -> Option<Keys> {
+        self.local_inputs.get(frame as usize).copied()
+    }
+
+    /// Returns the number of frames from the start for which the remote input
+    /// was received.
+    pub fn confirmed_frames(&self) -> u64 {
+        self.confirmed
+    }
+
+    /// Adds the keys the remote player pressed in the given frame. Inputs
+    /// can arrive in any order and more than once. Inputs for frames the
+    /// remote side can't have reached yet are ignored.
+    pub fn add_remote_input(&mut self, frame: u64, keys: Keys) {
+        if frame >= self.frame.saturating_add(self.max_rollback + INPUT_SLACK) {
+            debug!("ignoring remote input for frame {} (at frame {})", frame, self.frame);
+            return;
+        }
+
+        let idx = frame as usize;
+        if self.remote_inputs.len() <= idx {
+            self.remote_inputs.resize(idx + 1, None);
+        }
+        if self.remote_inputs[idx].is_some() {
+            return;
+        }
+        self.remote_inputs[idx] = Some(keys);
+
+        while self.remote_inputs.get(self.confirmed as usize).is_some_and(|k| k.is_some()) {
+            self.confirmed += 1;
+        }
+
+        if frame < self.frame && self.used_remote_inputs[idx] != keys {
+            self.rollback_to = Some(self.rollback_to.map_or(frame, |f| f.min(frame)));
+        }
+    }
+
+    /// Returns `true` if the local side may execute the next frame. If not,
+    /// the remote input is too far behind.
+    pub fn can_advance(&self) -> bool {
+        self.frame < self.confirmed + self.max_rollback
+    }
+
+    /// Executes the next frame with `keys` as local input. Frames executed
+    /// with a wrong prediction are executed again before. The local screen
+    /// and sound are passed to `peripherals`. Returns `false` without doing
+    /// anything if the remote side is too far behind (see `can_advance`).
+    pub fn advance_frame(
+        &mut self,
+        keys: Keys,
+        peripherals: &mut impl Peripherals,
+    ) -> Result<bool, Disruption> {
+        if !self.can_advance() {
+            return Ok(false);
+        }
+
+        if let Some(frame) = self.rollback_to.take() {
+            let end = self.frame;
+            let (_, state) = self.states.iter()
+                .find(|(f, _)| *f == frame)
+                .expect("rollback frame is older than the kept states");
+            for (emulator, machine) in self.emulators.iter_mut().zip(state) {
+                emulator.machine_mut().restore(machine);
+            }
+
+            self.frame = frame;
+            while self.frame < end {
+                self.execute_frame(&mut Mute)?;
+            }
+        }
+
+        self.local_inputs.push(keys);
+        self.execute_frame(peripherals)?;
+        Ok(true)
+    }
+
+    /// Executes frame `self.frame`, whose local input has to be known.
+    fn execute_frame(&mut self, peripherals: &mut impl Peripherals) -> Result<(), Disruption> {
+        let idx = self.frame as usize;
+
+        // Save the state before this frame, possibly replacing the one saved
+        // before a rollback.
+        while self.states.back().is_some_and(|(f, _)| *f >= self.frame) {
+            self.states.pop_back();
+        }
+        let machines = [self.emulators[0].machine().clone(), self.emulators[1].machine().clone()];
+        self.states.push_back((self.frame, machines));
+        if self.states.len() as u64 > self.max_rollback + 1 {
+            self.states.pop_front();
+        }
+
+        // Predict that the remote keys did not change.
+        let remote = self.remote_inputs[..idx.min(self.remote_inputs.len())]
+            .iter()
+            .chain(self.remote_inputs.get(idx))
+            .rev()
+            .find_map(|k| *k)
+            .unwrap_or_else(Keys::none);
+        self.used_remote_inputs.truncate(idx);
+        self.used_remote_inputs.push(remote);
+
+        let local_keys = self.local_inputs[idx];
+        let [first, second] = &mut self.emulators;
+        let mut local = WithKeys { inner: peripherals, keys: local_keys };
+        let mut remote = WithKeys { inner: &mut Mute, keys: remote };
+        if self.local == 0 {
+            execute_linked_frame((first, &mut local), (second, &mut remote))?;
+        } else {
+            execute_linked_frame((first, &mut remote), (second, &mut local))?;
+        }
+
+        self.frame += 1;
+        Ok(())
+    }
+}
+
+/// Peripherals with the keys of the session instead of the ones of `inner`.
+struct WithKeys<'a, P> {
+    inner: &'a mut P,
+    keys: Keys,
+}
+
+impl<P: Peripherals> Peripherals for WithKeys<'_, P> {
+    const FULL_FRAMES: bool = P::FULL_FRAMES;
+
+    fn write_lcd_line(&mut self, line_idx: u8, pixels: &[PixelColor; SCREEN_WIDTH]) {
+        self.inner.write_lcd_line(line_idx, pixels);
+    }
+
+    fn present_frame(&mut self, frame: &Frame) {
+        self.inner.present_frame(frame);
+    }
+
+    fn get_pressed_keys(&self) -> Keys {
+        self.keys
+    }
+
+    fn offer_sound_sample(&mut self, f: impl FnOnce(f32) -> f32) {
+        self.inner.offer_sound_sample(f);
+    }
+
+    fn capture_image(&mut self, width: usize, height: usize) -> Option<GreyFrame> {
+        self.inner.capture_image(width, height)
+    }
+}
+
+/// Peripherals for the remote emulator and for executing frames again: the
+/// output is not shown.
+struct Mute;
+
+impl Peripherals for Mute {
+    fn write_lcd_line(&mut self, _: u8, _: &[PixelColor; SCREEN_WIDTH]) {}
+
+    fn get_pressed_keys(&self) -> Keys {
+        Keys::none()
+    }
+
+    fn offer_sound_sample(&mut self, _: impl FnOnce(f32) -> f32) {}
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        BiosKind, HardwareModel,
+        machine::input::JoypadKey,
+        primitives::{Byte, Word},
+        test_util::cartridge_with_code,
+    };
+    use super::*;
+
+    /// Sums up the values of the button keys at `$C000`.
+    ///
+    /// ```text
+    /// 0x0150: ld a, $10; ldh [$00], a; ldh a, [$00]; ld hl, $C000;
+    ///         add [hl]; ld [hl], a; jr -13
+    /// ```
+    fn emulator() -> Emulator {
+        let cartridge = cartridge_with_code(&[
+            0x3E, 0x10, 0xE0, 0x00, 0xF0, 0x00, 0x21, 0x00, 0xC0, 0x86, 0x77, 0x18, 0xF3,
+        ]);
+        Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg)
+    }
+
+    fn sums(emulators: &[Emulator; 2]) -> [Byte; 2] {
+        [0, 1].map(|i| emulators[i].machine().load_byte(Word::new(0xC000)))
+    }
+
+    #[test]
+    fn test_rollback() {
+        const FRAMES: u64 = 30;
+        const DELAY: u64 = 3;
+        let keys = |player: usize, frame: u64| match player {
+            0 => Keys::none().set_key(JoypadKey::A, frame % 3 == 1),
+            _ => Keys::none().set_key(JoypadKey::B, frame % 4 < 2),
+        };
+
+        // Both sides know all inputs immediately.
+        let mut expected = [emulator(), emulator()];
+        for frame in 0..FRAMES {
+            let [first, second] = &mut expected;
+            execute_linked_frame(
+                (first, &mut WithKeys { inner: &mut Mute, keys: keys(0, frame) }),
+                (second, &mut WithKeys { inner: &mut Mute, keys: keys(1, frame) }),
+            ).ok().unwrap();
+        }
+
+        // The inputs of the other side arrive `DELAY` frames late.
+        let mut sides = [0, 1].map(|local| Rollback::new([emulator(), emulator()], local, 8));
+        for frame in 0..FRAMES + DELAY {
+            for (local, side) in sides.iter_mut().enumerate() {
+                if frame >= DELAY {
+                    side.add_remote_input(frame - DELAY, keys(1 - local, frame - DELAY));
+                }
+                if frame < FRAMES {
+                    assert!(side.advance_frame(keys(local, frame), &mut Mute).ok().unwrap());
+                }
+            }
+        }
+
+        // The last mispredictions are only corrected when advancing again,
+        // so compare the states with one more frame.
+        for side in &mut sides {
+            side.add_remote_input(FRAMES, keys(1 - side.local, FRAMES));
+            side.advance_frame(keys(side.local, FRAMES), &mut Mute).ok().unwrap();
+        }
+        let [first, second] = &mut expected;
+        execute_linked_frame(
+            (first, &mut WithKeys { inner: &mut Mute, keys: keys(0, FRAMES) }),
+            (second, &mut WithKeys { inner: &mut Mute, keys: keys(1, FRAMES) }),
+        ).ok().unwrap();
+
+        assert_ne!(sums(&expected), [Byte::zero(); 2]);
+        for side in &sides {
+            assert_eq!(sums(side.emulators()), sums(&expected));
+        }
+
+        // Without remote input, the local side stalls.
+        let mut alone = Rollback::new([emulator(), emulator()], 0, 2);
+        assert!(alone.advance_frame(Keys::none(), &mut Mute).ok().unwrap());
+        assert!(alone.advance_frame(Keys::none(), &mut Mute).ok().unwrap());
+        assert!(!alone.advance_frame(Keys::none(), &mut Mute).ok().unwrap());
+        assert_eq!(alone.frame(), 2);
+
+        // Inputs for frames the remote side can't have reached are ignored.
+        alone.add_remote_input(1 << 40, Keys::none());
+        alone.add_remote_input(2 + 2 + INPUT_SLACK, Keys::none());
+        assert_eq!(alone.remote_inputs.len(), 0);
+        alone.add_remote_input(0, Keys::none());
+        assert_eq!(alone.confirmed_frames(), 1);
+    }
+}
@@ -0,0 +1,582 @@
+//! The file format of save states (see `Machine::save_state`).
+//!
+//! A save state starts with a header of 32 bytes (numbers are little endian):
+//!
+//! - `0..8`: the magic bytes `MAHBOIST`
+//! - `8..10`: the format version (`VERSION`)
+//! - `10`: the compression of the body (0 = none, 1 = zstd)
+//! - `11`: reserved, always 0
+//! - `12..16`: CRC32 of the ROM
+//! - `16..32`: the title of the ROM, padded with zeros
+//!
+//! The (possibly compressed) body is a sequence of chunks, one per component
+//! of the machine: a tag of 4 ASCII characters, the length of the data as
+//! `u32` and the data. To stay compatible with older versions, new fields are
+//! only appended to the end of a chunk and new components get new chunks:
+//! unknown chunks and additional bytes at the end of a chunk are ignored when
+//! loading. The version is only increased for incompatible changes.
+//!
+//! Besides the components, the `META` chunk stores information that helps
+//! users to pick the right state (see `Metadata` and `read_info`).
+
+use std::{
+    convert::TryInto,
+    error::Error,
+    fmt,
+    time::Duration,
+};
+
+use crate::{
+    MACHINE_CYCLES_PER_SECOND,
+    cartridge::Cartridge,
+    primitives::{Byte, Word},
+};
+
+
+/// The magic bytes every save state starts with.
+pub const MAGIC: &[u8; 8] = b"MAHBOIST";
+
+/// The current version of the format. States of newer versions cannot be
+/// loaded.
+pub const VERSION: u16 = 1;
+
+const HEADER_LEN: usize = 32;
+
+/// The compression of a save state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+
+    /// Compression with zstd. Only available with the `zstd` feature.
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+/// A downscaled screenshot stored in a save state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Thumbnail {
+    pub width: u16,
+    pub height: u16,
+
+    /// The RGB values of all pixels, row by row.
+    pub pixels: Vec<u8>,
+}
+
+/// Information stored in a save state that is not needed to restore the
+/// machine (see `Machine::save_state_with_metadata`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// When the state was saved, in seconds since the Unix epoch. 0 if
+    /// unknown.
+    pub timestamp: u64,
+
+    pub thumbnail: Option<Thumbnail>,
+}
+
+impl Metadata {
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.u64(self.timestamp);
+        w.bool(self.thumbnail.is_some());
+        if let Some(thumbnail) = &self.thumbnail {
+            w.u16(thumbnail.width);
+            w.u16(thumbnail.height);
+            w.u32(thumbnail.pixels.len() as u32);
+            w.buf.extend_from_slice(&thumbnail.pixels);
+        }
+    }
+
+    fn load_state(r: &mut StateReader) -> Result<Self, SaveStateError> {
+        let timestamp = r.u64()?;
+        let thumbnail = if r.bool()? {
+            let width = r.u16()?;
+            let height = r.u16()?;
+            let len = r.u32()? as usize;
+            if len != width as usize * height as usize * 3 || r.data.len() < len {
+                return Err(SaveStateError::Corrupted("invalid thumbnail".into()));
+            }
+            let (pixels, rest) = r.data.split_at(len);
+            r.data = rest;
+            Some(Thumbnail { width, height, pixels: pixels.to_vec() })
+        } else {
+            None
+        };
+
+        Ok(Self { timestamp, thumbnail })
+    }
+}
+
+/// The information about a save state returned by `read_info`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateInfo {
+    /// The title of the ROM the state belongs to.
+    pub title: String,
+
+    /// The emulated time since power on.
+    pub play_time: Duration,
+
+    /// The metadata of the state. States without metadata return the
+    /// default.
+    pub metadata: Metadata,
+}
+
+/// Reads the information about a save state without loading it. The state
+/// can belong to any ROM.
+pub fn read_info(data: &[u8]) -> Result<StateInfo, SaveStateError> {
+    let state = ParsedState::parse_any(data)?;
+
+    let mut r = state.chunk(b"MACH")?;
+    let _step_count = r.u64()?;
+    let cycle_count = r.u64()?;
+    let play_time = Duration::from_secs(cycle_count / MACHINE_CYCLES_PER_SECOND as u64);
+
+    let metadata = match state.chunk(b"META") {
+        Ok(mut r) => Metadata::load_state(&mut r)?,
+        Err(_) => Metadata::default(),
+    };
+
+    Ok(StateInfo {
+        title: state.title,
+        play_time,
+        metadata,
+    })
+}
+
+/// Reasons why a save state cannot be loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SaveStateError {
+    /// The data does not start with the magic bytes.
+    NotASaveState,
+
+    /// The state was saved with a newer, incompatible version of the format.
+    UnsupportedVersion(u16),
+
+    /// The body is compressed with an unknown method, or with zstd and the
+    /// `zstd` feature is disabled.
+    UnsupportedCompression(u8),
+
+    /// The state was saved with another ROM. Contains the title of that ROM.
+    RomMismatch(String),
+
+    /// A chunk required by this version is missing.
+    MissingChunk(String),
+
+    /// The data is truncated or invalid. Contains a description.
+    Corrupted(String),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::SaveStateError::*;
+
+        match self {
+            NotASaveState => write!(f, "not a save state"),
+            UnsupportedVersion(v) => write!(
+                f,
+                "save state has version {}, but only versions up to {} are supported",
+                v,
+                VERSION,
+            ),
+            UnsupportedCompression(c) => {
+                write!(f, "save state is compressed with unsupported method {}", c)
+            }
+            RomMismatch(title) => {
+                write!(f, "save state belongs to another ROM (with title '{}')", title)
+            }
+            MissingChunk(tag) => write!(f, "save state is missing chunk '{}'", tag),
+            Corrupted(msg) => write!(f, "save state is corrupted: {}", msg),
+        }
+    }
+}
+
+impl Error for SaveStateError {}
+
+/// Collects the chunks of a save state.
+pub(crate) struct StateBuilder {
+    body: Vec<u8>,
+}
+
+impl StateBuilder {
+    pub(crate) fn new() -> Self {
+        Self { body: Vec::new() }
+    }
+
+    /// Adds a chunk with the data written by `f`.
+    pub(crate) fn chunk(&mut self, tag: &[u8; 4], f: impl FnOnce(&mut StateWriter)) {
+        let mut writer = StateWriter { buf: Vec::new() };
+        f(&mut writer);
+
+        self.body.extend_from_slice(tag);
+        self.body.extend_from_slice(&(writer.buf.len() as u32).to_le_bytes());
+        self.body.extend_from_slice(&writer.buf);
+    }
+
+    /// Returns the complete save state for the given cartridge.
+    pub(crate) fn finish(self, cartridge: &Cartridge, compression: Compression) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + self.body.len());
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.push(compression as u8);
+        out.push(0);
+        out.extend_from_slice(&rom_checksum(cartridge).to_le_bytes());
+        let mut title = [0; 16];
+        let len = cartridge.title().len().min(16);
+        title[..len].copy_from_slice(&cartridge.title().as_bytes()[..len]);
+        out.extend_from_slice(&title);
+
+        match compression {
+            Compression::None => out.extend_from_slice(&self.body),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                let compressed = zstd::encode_all(&self.body[..], 0)
+                    .expect("compressing into memory failed");
+                out.extend_from_slice(&compressed);
+            }
+        }
+
+        out
+    }
+}
+
+/// The parsed chunks of a save state.
+pub(crate) struct ParsedState {
+    /// The title of the ROM stored in the header.
+    title: String,
+
+    body: Vec<u8>,
+
+    /// The tag and the range in `body` of each chunk.
+    chunks: Vec<([u8; 4], usize, usize)>,
+}
+
+impl ParsedState {
+    /// Checks the header and splits the body into chunks. The state has to
+    /// belong to the ROM of `cartridge`.
+    pub(crate) fn parse(data: &[u8], cartridge: &Cartridge) -> Result<Self, SaveStateError> {
+        let state = Self::parse_any(data)?;
+
+        let checksum = u32::from_le_bytes(data[12..16].try_into().unwrap());
+        if checksum != rom_checksum(cartridge) {
+            return Err(SaveStateError::RomMismatch(state.title));
+        }
+
+        Ok(state)
+    }
+
+    /// Like `parse`, but the state can belong to any ROM.
+    fn parse_any(data: &[u8]) -> Result<Self, SaveStateError> {
+        if data.len() < HEADER_LEN || &data[..8] != MAGIC {
+            return Err(SaveStateError::NotASaveState);
+        }
+
+        let version = u16::from_le_bytes([data[8], data[9]]);
+        if version > VERSION {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+
+        let title = String::from_utf8_lossy(&data[16..32]).trim_end_matches('\0').to_string();
+
+        let body = match data[10] {
+            0 => data[HEADER_LEN..].to_vec(),
+            #[cfg(feature = "zstd")]
+            1 => zstd::decode_all(&data[HEADER_LEN..])
+                .map_err(|e| SaveStateError::Corrupted(e.to_string()))?,
+            other => return Err(SaveStateError::UnsupportedCompression(other)),
+        };
+
+        let mut chunks = Vec::new();
+        let mut pos = 0;
+        while pos < body.len() {
+            if body.len() - pos < 8 {
+                return Err(SaveStateError::Corrupted("truncated chunk header".into()));
+            }
+            let tag = body[pos..pos + 4].try_into().unwrap();
+            let len = u32::from_le_bytes(body[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            let start = pos + 8;
+            if body.len() - start < len {
+                return Err(SaveStateError::Corrupted("truncated chunk".into()));
+            }
+
+            chunks.push((tag, start, start + len));
+            pos = start + len;
+        }
+
+        Ok(Self { title, body, chunks })
+    }
+
+    /// Returns a reader for the data of the given chunk.
+    pub(crate) fn chunk(&self, tag: &[u8; 4]) -> Result<StateReader<'_>, SaveStateError> {
+        self.chunks.iter()
+            .find(|(t, _, _)| t == tag)
+            .map(|&(_, start, end)| StateReader { data: &self.body[start..end] })
+            .ok_or_else(|| SaveStateError::MissingChunk(String::from_utf8_lossy(tag).into()))
+    }
+}
+
+/// Writes the fields of a component into its chunk.
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.u8(v as u8);
+    }
+
+    pub fn byte(&mut self, v: Byte) {
+        self.u8(v.get());
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn word(&mut self, v: Word) {
+        self.u16(v.get());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    /// Writes the bytes prefixed with their length.
+    pub fn bytes(&mut self, v: &[Byte]) {
+        self.u32(v.len() as u32);
+        self.buf.extend(v.iter().map(|b| b.get()));
+    }
+}
+
+/// Reads the fields of a component from its chunk, in the order they were
+/// written.
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl StateReader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], SaveStateError> {
+        if self.data.len() < N {
+            return Err(SaveStateError::Corrupted("chunk is too short".into()));
+        }
+
+        let (head, rest) = self.data.split_at(N);
+        self.data = rest;
+        Ok(head.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SaveStateError> {
+        self.take::<1>().map(|[b]| b)
+    }
+
+    pub fn bool(&mut self) -> Result<bool, SaveStateError> {
+        self.u8().map(|b| b != 0)
+    }
+
+    pub fn byte(&mut self) -> Result<Byte, SaveStateError> {
+        self.u8().map(Byte::new)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, SaveStateError> {
+        self.take().map(u16::from_le_bytes)
+    }
+
+    pub fn word(&mut self) -> Result<Word, SaveStateError> {
+        self.u16().map(Word::new)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SaveStateError> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SaveStateError> {
+        self.take().map(u64::from_le_bytes)
+    }
+
+    /// Reads bytes written by `StateWriter::bytes` into `dst`, which has to
+    /// have the same length.
+    pub fn bytes(&mut self, dst: &mut [Byte]) -> Result<(), SaveStateError> {
+        let len = self.u32()? as usize;
+        if len != dst.len() {
+            return Err(SaveStateError::Corrupted(
+                format!("expected {} bytes, found {}", dst.len(), len),
+            ));
+        }
+        if self.data.len() < len {
+            return Err(SaveStateError::Corrupted("chunk is too short".into()));
+        }
+
+        let (head, rest) = self.data.split_at(len);
+        for (dst, &src) in dst.iter_mut().zip(head) {
+            *dst = Byte::new(src);
+        }
+        self.data = rest;
+        Ok(())
+    }
+}
+
+/// Returns the CRC32 of the ROM of `cartridge`, which identifies the ROM.
+fn rom_checksum(cartridge: &Cartridge) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    !cartridge.rom().iter().fold(!0, |crc: u32, b| {
+        TABLE[((crc ^ b.get() as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        BiosKind, Emulator, HardwareModel,
+        test_util::{NullPeripherals, rom_with_code},
+    };
+    use super::*;
+
+    /// 0x0150: ld hl, $C000; inc [hl]; jr -3
+    fn emulator(title: &[u8]) -> Emulator {
+        let mut rom = rom_with_code(&[0x21, 0x00, 0xC0, 0x34, 0x18, 0xFD]);
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        let cartridge = Cartridge::from_bytes(&rom).unwrap();
+        Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg)
+    }
+
+    fn run_frames(emulator: &mut Emulator, frames: usize) {
+        for _ in 0..frames {
+            emulator.execute_frame(&mut NullPeripherals, |_| false).ok().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut emulator = emulator(b"COUNTER");
+        run_frames(&mut emulator, 5);
+        let state = emulator.machine().save_state(Compression::None);
+        let counter = emulator.machine().load_byte(Word::new(0xC000));
+        run_frames(&mut emulator, 5);
+        let expected = emulator.machine().save_state(Compression::None);
+        assert_ne!(emulator.machine().load_byte(Word::new(0xC000)), counter);
+
+        emulator.machine_mut().load_state(&state).unwrap();
+        assert_eq!(emulator.machine().load_byte(Word::new(0xC000)), counter);
+        run_frames(&mut emulator, 5);
+        assert_eq!(emulator.machine().save_state(Compression::None), expected);
+
+        // Unknown chunks are skipped.
+        let mut extended = state.clone();
+        extended.extend_from_slice(b"NEW \x02\x00\x00\x00ab");
+        emulator.machine_mut().load_state(&extended).unwrap();
+        assert_eq!(emulator.machine().save_state(Compression::None), state);
+
+        #[cfg(feature = "zstd")]
+        {
+            let compressed = emulator.machine().save_state(Compression::Zstd);
+            assert!(compressed.len() < state.len());
+            run_frames(&mut emulator, 1);
+            emulator.machine_mut().load_state(&compressed).unwrap();
+            assert_eq!(emulator.machine().save_state(Compression::None), state);
+        }
+    }
+
+    #[test]
+    fn test_metadata() {
+        let mut emulator = emulator(b"COUNTER");
+        run_frames(&mut emulator, 60);
+
+        let state = emulator.machine().save_state(Compression::None);
+        let info = read_info(&state).unwrap();
+        assert_eq!(info.title, "COUNTER");
+        assert_eq!(info.play_time, Duration::from_secs(1));
+        assert_eq!(info.metadata, Metadata::default());
+
+        let metadata = Metadata {
+            timestamp: 1_700_000_000,
+            thumbnail: Some(Thumbnail { width: 2, height: 1, pixels: vec![1, 2, 3, 4, 5, 6] }),
+        };
+        let state = emulator.machine().save_state_with_metadata(Compression::None, &metadata);
+        assert_eq!(read_info(&state).unwrap().metadata, metadata);
+        emulator.machine_mut().load_state(&state).unwrap();
+
+        assert_eq!(read_info(&state[..20]), Err(SaveStateError::NotASaveState));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut emulator = emulator(b"COUNTER");
+        let state = emulator.machine().save_state(Compression::None);
+        let machine = emulator.machine_mut();
+
+        assert_eq!(machine.load_state(&state[..20]), Err(SaveStateError::NotASaveState));
+        assert_eq!(machine.load_state(&[0; 64]), Err(SaveStateError::NotASaveState));
+
+        let mut newer = state.clone();
+        newer[8] = 2;
+        assert_eq!(machine.load_state(&newer), Err(SaveStateError::UnsupportedVersion(2)));
+
+        let mut compressed = state.clone();
+        compressed[10] = 7;
+        assert_eq!(machine.load_state(&compressed), Err(SaveStateError::UnsupportedCompression(7)));
+
+        assert!(matches!(
+            machine.load_state(&state[..state.len() - 3]),
+            Err(SaveStateError::Corrupted(_)),
+        ));
+
+        // The internal PPU state follows OAM, which is filled with a pattern
+        // to find it: the cycle in the line, the frame count, the H-Blank
+        // cycle, whether WY was reached, the window line and the sprites
+        // found on the line (y, x, tile, flags). The LCD has to be on for
+        // these to be checked.
+        let mut emulator = self::emulator(b"COUNTER");
+        let machine = emulator.machine_mut();
+        let pattern = (0..0xA0).map(|i| 0x20 + i as u8).collect::<Vec<_>>();
+        for (b, &v) in machine.ppu.oam.as_mut_slice().iter_mut().zip(&pattern) {
+            *b = Byte::new(v);
+        }
+        machine.store_byte(Word::new(0xFF40), Byte::new(0x91));
+        let ly = machine.ppu.regs().current_line.get();
+        let state = machine.save_state(Compression::None);
+        let oam = state.windows(0xA0).position(|w| w == pattern).unwrap();
+        let (cycle, window_line, sprite) = (oam + 0xA0, oam + 0xA0 + 11, oam + 0xA0 + 12);
+        let corrupt = |patches: &[(usize, u8)]| {
+            let mut corrupted = state.clone();
+            for &(i, b) in patches {
+                corrupted[i] = b;
+            }
+            machine.clone().load_state(&corrupted)
+        };
+
+        assert_eq!(corrupt(&[(window_line, ly + 1)]), Ok(()));
+        assert!(matches!(corrupt(&[(window_line, 255)]), Err(SaveStateError::Corrupted(_))));
+        assert_eq!(corrupt(&[(cycle, 5), (sprite, ly + 16), (sprite + 1, 8)]), Ok(()));
+        assert!(matches!(
+            corrupt(&[(cycle, 5), (sprite, ly + 17), (sprite + 1, 8)]),
+            Err(SaveStateError::Corrupted(_)),
+        ));
+
+        let other = self::emulator(b"OTHER").machine().save_state(Compression::None);
+        assert_eq!(
+            machine.load_state(&other),
+            Err(SaveStateError::RomMismatch("OTHER".into())),
+        );
+    }
+}
@@ -0,0 +1,38 @@
+//! Helpers shared by the tests of this crate and, with the `test-util`
+//! feature, by the tests of frontends.
+
+use crate::{
+    SCREEN_WIDTH,
+    cartridge::Cartridge,
+    env::Peripherals,
+    machine::input::Keys,
+    primitives::PixelColor,
+};
+
+
+/// Peripherals that ignore the screen and sound and never press any key.
+pub struct NullPeripherals;
+
+impl Peripherals for NullPeripherals {
+    fn write_lcd_line(&mut self, _: u8, _: &[PixelColor; SCREEN_WIDTH]) {}
+
+    fn get_pressed_keys(&self) -> Keys {
+        Keys::none()
+    }
+
+    fn offer_sound_sample(&mut self, _: impl FnOnce(f32) -> f32) {}
+}
+
+/// Returns a 32 KiB ROM without MBC with `code` at `0x0150`. The entry point
+/// at `0x0100` jumps there (`nop; jp $0150`).
+pub fn rom_with_code(code: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    rom[0x150..0x150 + code.len()].copy_from_slice(code);
+    rom
+}
+
+/// Returns a cartridge with the ROM of `rom_with_code`.
+pub fn cartridge_with_code(code: &[u8]) -> Cartridge {
+    Cartridge::from_bytes(&rom_with_code(code)).unwrap()
+}
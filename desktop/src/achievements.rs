@@ -0,0 +1,469 @@
+//! Achievements in the style of RetroAchievements: conditions on the emulated
+//! memory that are checked after each frame.
+//!
+//! The achievements of each ROM are read from the config directory (e.g.
+//! `~/.config/mahboi/achievements/<rom>.txt`). Each line contains a title and
+//! the conditions in the "memaddr" syntax of RetroAchievements (rcheevos),
+//! separated by `=`:
+//!
+//! ```text
+//! # Comment
+//! First Coin = 0xHc0a0=0_0xHc0a0>0
+//! Speedrun   = 0x c100<h0200_R:0xHff44>150.3.
+//! ```
+//!
+//! Only a subset of that syntax is supported: memory operands of all sizes
+//! (`0xH`, `0x `, `0xL`, `0xM` to `0xT`, ...), deltas (`d0xH...`), decimal
+//! and hex (`h...`) values, all comparisons, hit counts (`.N.`), the `R:`
+//! (reset if) and `P:` (pause if) flags and alternative groups (`S`).
+//!
+//! Logging in to RetroAchievements and downloading the official
+//! achievement sets is not supported.
+
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use mahboi::{
+    log::*,
+    machine::Machine,
+    primitives::Word,
+};
+use crate::input_macro::config_dir;
+
+
+/// How long the notification about an unlocked achievement is shown.
+const NOTIFICATION_DURATION: Duration = Duration::from_secs(5);
+
+/// The achievements of the running ROM.
+pub(crate) struct Achievements {
+    list: Vec<Achievement>,
+
+    /// The title of the last unlocked achievement and when it was unlocked.
+    last_unlocked: Option<(String, Instant)>,
+}
+
+#[derive(Debug)]
+struct Achievement {
+    title: String,
+
+    /// The first group has to be true, as well as one of the others (if there
+    /// are any).
+    groups: Vec<Vec<Condition>>,
+
+    state: State,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// The conditions have not been false yet. This prevents unlocking
+    /// achievements right away when loading a save state.
+    Waiting,
+    Active,
+    Unlocked,
+}
+
+#[derive(Debug)]
+struct Condition {
+    flag: Flag,
+    left: Operand,
+    cmp: Cmp,
+    right: Operand,
+
+    /// How often the condition has to be true. 0 means it just has to be true
+    /// in the current frame.
+    required_hits: u32,
+    hits: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flag {
+    None,
+    ResetIf,
+    PauseIf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug)]
+enum Operand {
+    Value(u32),
+    Memory {
+        addr: u16,
+        size: Size,
+
+        /// Whether the value of the previous frame is used.
+        delta: bool,
+
+        /// The value read in the previous frame.
+        prev: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Size {
+    Bit(u8),
+    Low4,
+    High4,
+    Bits8,
+    Bits16,
+    Bits24,
+    Bits32,
+}
+
+impl Achievements {
+    /// Loads the achievements of the given ROM. Returns `None` if there are
+    /// none.
+    pub(crate) fn for_rom(rom: &Path) -> Option<Self> {
+        let path = config_dir().and_then(|dir| {
+            let name = rom.file_stem()?;
+            Some(dir.join("mahboi").join("achievements").join(name).with_extension("txt"))
+        })?;
+        let content = fs::read_to_string(&path).ok()?;
+        match parse_file(&content) {
+            Ok(list) => {
+                let count = list.len();
+                info!("[achievements] loaded {} achievements from '{}'", count, path.display());
+                Some(Self { list, last_unlocked: None })
+            }
+            Err(e) => {
+                warn!("[achievements] ignoring invalid file '{}': {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Checks the conditions of all achievements. Has to be called once per
+    /// emulated frame.
+    pub(crate) fn evaluate(&mut self, machine: &Machine) {
+        for achievement in &mut self.list {
+            if achievement.state == State::Unlocked {
+                continue;
+            }
+
+            let triggered = achievement.evaluate(machine);
+            match (achievement.state, triggered) {
+                (State::Waiting, false) => achievement.state = State::Active,
+                (State::Active, true) => {
+                    info!("[achievements] unlocked '{}'", achievement.title);
+                    achievement.state = State::Unlocked;
+                    self.last_unlocked = Some((achievement.title.clone(), Instant::now()));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Returns the title of a recently unlocked achievement to show to the
+    /// user.
+    pub(crate) fn notification(&self) -> Option<&str> {
+        self.last_unlocked.as_ref()
+            .filter(|(_, time)| time.elapsed() < NOTIFICATION_DURATION)
+            .map(|(title, _)| title.as_str())
+    }
+}
+
+impl Achievement {
+    /// Returns whether all conditions are met in the current frame.
+    fn evaluate(&mut self, machine: &Machine) -> bool {
+        // All operands are read, even if the result is not needed, to keep
+        // the deltas up to date.
+        let results = self.groups.iter_mut()
+            .map(|group| group.iter_mut().map(|c| c.is_true(machine)).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        let reset = self.groups.iter()
+            .flatten()
+            .zip(results.iter().flatten())
+            .any(|(c, &true_now)| c.flag == Flag::ResetIf && true_now);
+        if reset {
+            self.groups.iter_mut().flatten().for_each(|c| c.hits = 0);
+            return false;
+        }
+
+        let mut group_results = self.groups.iter_mut().zip(&results).map(|(group, results)| {
+            let paused = group.iter()
+                .zip(results)
+                .any(|(c, &true_now)| c.flag == Flag::PauseIf && true_now);
+            if paused {
+                return false;
+            }
+
+            let mut all_true = true;
+            for (c, &true_now) in group.iter_mut().zip(results) {
+                if c.flag != Flag::None {
+                    continue;
+                }
+                if true_now && (c.required_hits == 0 || c.hits < c.required_hits) {
+                    c.hits += 1;
+                }
+                all_true &= if c.required_hits == 0 { true_now } else { c.hits >= c.required_hits };
+            }
+            all_true
+        });
+
+        let core = group_results.next().unwrap_or(false);
+        let mut alternatives = group_results.peekable();
+        let any_alternative = alternatives.peek().is_none()
+            || alternatives.fold(false, |acc, r| acc | r);
+        core && any_alternative
+    }
+}
+
+impl Condition {
+    fn is_true(&mut self, machine: &Machine) -> bool {
+        let left = self.left.read(machine);
+        let right = self.right.read(machine);
+        match self.cmp {
+            Cmp::Eq => left == right,
+            Cmp::Ne => left != right,
+            Cmp::Lt => left < right,
+            Cmp::Le => left <= right,
+            Cmp::Gt => left > right,
+            Cmp::Ge => left >= right,
+        }
+    }
+}
+
+impl Operand {
+    fn read(&mut self, machine: &Machine) -> u32 {
+        match self {
+            Operand::Value(v) => *v,
+            Operand::Memory { addr, size, delta, prev } => {
+                let byte = |offset: u16| {
+                    machine.load_byte(Word::new(addr.wrapping_add(offset))).get() as u32
+                };
+                let bytes = |n: u16| (0..n).rev().fold(0, |acc, i| (acc << 8) | byte(i));
+                let current = match *size {
+                    Size::Bit(bit) => (byte(0) >> bit) & 1,
+                    Size::Low4 => byte(0) & 0x0F,
+                    Size::High4 => byte(0) >> 4,
+                    Size::Bits8 => byte(0),
+                    Size::Bits16 => bytes(2),
+                    Size::Bits24 => bytes(3),
+                    Size::Bits32 => bytes(4),
+                };
+
+                let previous = std::mem::replace(prev, current);
+                if *delta { previous } else { current }
+            }
+        }
+    }
+}
+
+fn parse_file(src: &str) -> Result<Vec<Achievement>, String> {
+    src.lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|line| {
+            let (title, conditions) = line.split_once('=')
+                .ok_or_else(|| format!("missing '=' in line '{}'", line))?;
+            let groups = parse_conditions(conditions.trim())
+                .map_err(|e| format!("invalid conditions of '{}': {}", title.trim(), e))?;
+
+            Ok(Achievement { title: title.trim().to_string(), groups, state: State::Waiting })
+        })
+        .collect()
+}
+
+/// Parses the conditions in "memaddr" syntax into groups.
+fn parse_conditions(src: &str) -> Result<Vec<Vec<Condition>>, String> {
+    let mut parser = Parser { src };
+    let mut groups = vec![vec![]];
+    loop {
+        groups.last_mut().unwrap().push(parser.condition()?);
+        if parser.eat("_") {
+            continue;
+        } else if parser.eat("S") {
+            groups.push(vec![]);
+        } else if parser.src.is_empty() {
+            return Ok(groups);
+        } else {
+            return Err(format!("unexpected '{}'", parser.src));
+        }
+    }
+}
+
+struct Parser<'a> {
+    /// The rest of the input.
+    src: &'a str,
+}
+
+impl Parser<'_> {
+    /// Skips `prefix` if the input starts with it.
+    fn eat(&mut self, prefix: &str) -> bool {
+        match self.src.strip_prefix(prefix) {
+            Some(rest) => {
+                self.src = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Parses the longest prefix of digits in the given radix.
+    fn number(&mut self, radix: u32) -> Result<u32, String> {
+        let len = self.src.find(|c: char| !c.is_digit(radix)).unwrap_or(self.src.len());
+        let (digits, rest) = self.src.split_at(len);
+        let value = u32::from_str_radix(digits, radix)
+            .map_err(|_| format!("invalid number at '{}'", self.src))?;
+        self.src = rest;
+        Ok(value)
+    }
+
+    fn condition(&mut self) -> Result<Condition, String> {
+        let flag = if self.eat("R:") {
+            Flag::ResetIf
+        } else if self.eat("P:") {
+            Flag::PauseIf
+        } else if self.src.get(1..2) == Some(":") {
+            return Err(format!("unsupported flag '{}'", &self.src[..2]));
+        } else {
+            Flag::None
+        };
+
+        let left = self.operand()?;
+        let cmp = [
+            ("!=", Cmp::Ne),
+            ("<=", Cmp::Le),
+            (">=", Cmp::Ge),
+            ("=", Cmp::Eq),
+            ("<", Cmp::Lt),
+            (">", Cmp::Gt),
+        ].iter()
+            .find(|(s, _)| self.eat(s))
+            .map(|&(_, cmp)| cmp)
+            .ok_or_else(|| format!("expected comparison at '{}'", self.src))?;
+        let right = self.operand()?;
+
+        let required_hits = if self.eat(".") {
+            let hits = self.number(10)?;
+            if !self.eat(".") {
+                return Err(format!("expected '.' at '{}'", self.src));
+            }
+            hits
+        } else {
+            0
+        };
+
+        Ok(Condition { flag, left, cmp, right, required_hits, hits: 0 })
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        let delta = self.eat("d");
+        if !self.eat("0x") {
+            if delta {
+                return Err(format!("expected memory address at '{}'", self.src));
+            }
+
+            return if self.eat("h") {
+                self.number(16).map(Operand::Value)
+            } else {
+                self.number(10).map(Operand::Value)
+            };
+        }
+
+        let mut chars = self.src.chars();
+        let size = match chars.next() {
+            Some(c @ 'M'..='T') => Size::Bit(c as u8 - b'M'),
+            Some('L') => Size::Low4,
+            Some('U') => Size::High4,
+            Some('H') => Size::Bits8,
+            Some(' ') => Size::Bits16,
+            Some('W') => Size::Bits24,
+            Some('X') => Size::Bits32,
+            Some(c) if c.is_ascii_hexdigit() => {
+                let addr = self.number(16)?;
+                return address(addr).map(|addr| {
+                    Operand::Memory { addr, size: Size::Bits16, delta, prev: 0 }
+                });
+            }
+            _ => return Err(format!("invalid memory size at '{}'", self.src)),
+        };
+        self.src = chars.as_str();
+
+        let addr = address(self.number(16)?)?;
+        Ok(Operand::Memory { addr, size, delta, prev: 0 })
+    }
+}
+
+fn address(addr: u32) -> Result<u16, String> {
+    if addr > 0xFFFF {
+        Err(format!("address {:x} is out of range", addr))
+    } else {
+        Ok(addr as u16)
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use mahboi::{
+        BiosKind, Emulator, HardwareModel,
+        cartridge::Cartridge,
+        primitives::Byte,
+    };
+    use super::*;
+
+    #[test]
+    fn test_evaluate() {
+        let cartridge = Cartridge::from_bytes(&[0; 0x8000]).unwrap();
+        let mut emulator = Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        let mut achievements = Achievements {
+            list: parse_file("\
+                # Comment
+                Delta = d0xHc000=1_0xHc000=2
+                Hits = 0xMc001=1.3.
+                Alt = 0xUc005=h3S0xHc006=7S0xHc006=8
+            ").unwrap(),
+            last_unlocked: None,
+        };
+        let mut frame = |achievements: &mut Achievements, writes: &[(u16, u8)]| {
+            let machine = emulator.machine_mut();
+            for &(addr, value) in writes {
+                machine.store_byte(Word::new(addr), Byte::new(value));
+            }
+            achievements.evaluate(machine);
+            achievements.list.iter().map(|a| a.state).collect::<Vec<_>>()
+        };
+
+        use State::*;
+        let a = &mut achievements;
+        assert_eq!(frame(a, &[]), [Active, Active, Active]);
+        assert_eq!(frame(a, &[(0xc000, 1), (0xc001, 1)]), [Active, Active, Active]);
+        assert_eq!(frame(a, &[(0xc000, 2), (0xc005, 0x30)]), [Unlocked, Active, Active]);
+        assert_eq!(frame(a, &[]), [Unlocked, Unlocked, Active]);
+        assert_eq!(frame(a, &[(0xc006, 8)]), [Unlocked, Unlocked, Unlocked]);
+        assert_eq!(a.notification(), Some("Alt"));
+
+        // Hits are reset by `R:` and achievements that are true from the
+        // start wait until they were false.
+        a.list = parse_file("Hits = 0xMc001=1.3._R:0x c002>h1ff\nNow = 0xHc001=1").unwrap();
+        assert_eq!(frame(a, &[]), [Active, Waiting]);
+        frame(a, &[(0xc003, 0x02)]);
+        frame(a, &[(0xc003, 0x00)]);
+        frame(a, &[]);
+        assert_eq!(frame(a, &[(0xc001, 0)]), [Active, Active]);
+        assert_eq!(frame(a, &[(0xc001, 1)]), [Unlocked, Unlocked]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_conditions("0xHc000=1_0xHc001").is_err());
+        assert!(parse_conditions("A:0xHc000=1").is_err());
+        assert!(parse_conditions("0xHc000=1.3").is_err());
+        assert!(parse_conditions("0xH10000=1").is_err());
+        assert!(parse_file("no conditions").is_err());
+    }
+}
@@ -0,0 +1,144 @@
+//! Exporting the call graph (and optionally the control flow graphs of all
+//! functions) in the Graphviz DOT format.
+
+use std::{
+    fmt::Write as _,
+    fs,
+    path::Path,
+};
+
+use failure::{Error, ResultExt};
+
+use crate::{
+    disasm,
+    symbols::Symbols,
+};
+use super::{BANK_SIZE, CodeMap, RomAddr};
+
+
+/// Writes the DOT graph (see `to_dot`) to the file at `path`.
+pub(crate) fn export_to_file(
+    path: &Path,
+    map: &CodeMap,
+    symbols: &Symbols,
+    with_cfg: bool,
+) -> Result<(), Error> {
+    fs::write(path, to_dot(map, symbols, with_cfg))
+        .context(format!("failed to write '{}'", path.display()))?;
+
+    Ok(())
+}
+
+/// Returns the call graph of all functions as DOT graph. With `with_cfg`,
+/// every function is drawn as cluster containing its basic blocks (with
+/// disassembly) and calls are edges from the calling block to the entry
+/// block of the callee. Blocks shared by multiple functions are drawn once
+/// per function.
+pub(crate) fn to_dot(map: &CodeMap, symbols: &Symbols, with_cfg: bool) -> String {
+    let name = |entry: RomAddr| {
+        symbols.name_at(entry.addr, entry.bank).unwrap_or(&map.fns[&entry].name).to_string()
+    };
+
+    let mut out = String::new();
+    writeln!(out, "digraph program {{").unwrap();
+    writeln!(out, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+
+    for (&entry, f) in &map.fns {
+        if !with_cfg {
+            writeln!(out, "    \"{}\" [label=\"{}\"];", entry, escape(&name(entry))).unwrap();
+            for &callee in &f.callees {
+                writeln!(out, "    \"{}\" -> \"{}\";", entry, callee).unwrap();
+            }
+            continue;
+        }
+
+        writeln!(out).unwrap();
+        writeln!(out, "    subgraph \"cluster_{}\" {{", entry).unwrap();
+        writeln!(out, "        label=\"{}\";", escape(&name(entry))).unwrap();
+        for &start in &f.blocks {
+            let block = &map.blocks[&start];
+            let label = block_label(map, symbols, &block.instrs);
+            writeln!(out, "        \"{}/{}\" [label=\"{}\"];", entry, start, label).unwrap();
+            for succ in block.successors() {
+                writeln!(out, "        \"{}/{}\" -> \"{}/{}\";", entry, start, entry, succ)
+                    .unwrap();
+            }
+        }
+        writeln!(out, "    }}").unwrap();
+
+        for &start in &f.blocks {
+            for (_, target) in &map.blocks[&start].calls {
+                if let Some(callee) = target.rom() {
+                    writeln!(
+                        out,
+                        "    \"{}/{}\" -> \"{}/{}\" [style=dashed];",
+                        entry,
+                        start,
+                        callee,
+                        callee,
+                    ).unwrap();
+                }
+            }
+        }
+    }
+
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+/// Returns the (escaped) node label of a block: its address and the
+/// disassembly of all instructions, left-aligned.
+fn block_label(map: &CodeMap, symbols: &Symbols, instrs: &[RomAddr]) -> String {
+    let mut out = format!("{}\\l", instrs[0]);
+    for &addr in instrs {
+        let offset = addr.offset();
+        let bank_end = (offset / BANK_SIZE + 1) * BANK_SIZE;
+        let bytes = &map.rom[offset..bank_end.min(map.rom.len())];
+        let label = |target| symbols.name_at(target, addr.bank).map(|s| s.to_string());
+        let text = match disasm::disassemble(bytes, addr.addr, &label) {
+            Some((text, _)) => text,
+            None => format!("db ${:02x}", bytes[0].get()),
+        };
+        write!(out, "    {}\\l", escape(&text)).unwrap();
+    }
+
+    out
+}
+
+/// Escapes a string to be used inside a quoted DOT string.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+
+#[cfg(test)]
+mod test {
+    use mahboi::primitives::Byte;
+    use super::*;
+
+    #[test]
+    fn test_to_dot() {
+        // 0100: CALL 0150; RET
+        // 0150: JR NZ, 0150; RET
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0xCD, 0x50, 0x01, 0xC9]);
+        rom[0x150..0x153].copy_from_slice(&[0x20, 0xFE, 0xC9]);
+        let rom = rom.into_iter().map(Byte::new).collect::<Vec<_>>();
+        let map = CodeMap::new(&rom);
+        let symbols = Symbols::parse("00:0150 Wait").unwrap();
+
+        let dot = to_dot(&map, &symbols, false);
+        assert!(dot.starts_with("digraph program {\n"));
+        assert!(dot.contains("    \"00:0100\" [label=\"fn_00_0100\"];\n"));
+        assert!(dot.contains("    \"00:0150\" [label=\"Wait\"];\n"));
+        assert!(dot.contains("    \"00:0100\" -> \"00:0150\";\n"));
+
+        let dot = to_dot(&map, &symbols, true);
+        assert!(dot.contains("    subgraph \"cluster_00:0150\" {\n        label=\"Wait\";\n"));
+        assert!(dot.contains(
+            "        \"00:0150/00:0150\" [label=\"00:0150\\l    jr nz, Wait\\l\"];\n"
+        ));
+        assert!(dot.contains("        \"00:0150/00:0150\" -> \"00:0150/00:0152\";\n"));
+        assert!(dot.contains("    \"00:0100/00:0100\" -> \"00:0150/00:0150\" [style=dashed];\n"));
+    }
+}
@@ -0,0 +1,894 @@
+//! Control flow analysis of the ROM.
+//!
+//! Starting at entry points, instructions are decoded and split into basic
+//! blocks (sequences of instructions that are always executed from start to
+//! end). Jump, branch and call targets are followed, as long as they can be
+//! determined statically. All addresses are bank-aware: a jump from ROM bank
+//! 5 into the switchable area stays in bank 5. Jumps from bank 0 into the
+//! switchable area depend on the mapped bank at runtime and can't be
+//! followed (unless the ROM only has two banks). In the debugger, the
+//! analysis is extended with every executed address that wasn't known yet.
+//!
+//! Bytes of decoded (or executed) instructions are classified as code. ROM
+//! addresses loaded into a register pair (e.g. `LD HL, $4A30`) usually point
+//! to data like graphics or tables. Everything from such an address up to
+//! the next code byte is classified as data.
+//!
+//! Jump tables are recognized in two common forms: a table address loaded
+//! into `HL` in the same block as `LD A, [HL+]` and the final `JP HL`, and
+//! tables following an `RST` whose handler pops the return address (the
+//! table address) before jumping via `JP HL`. All table entries are added as
+//! entry points.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, btree_map::Entry},
+    fmt,
+    mem,
+};
+
+use mahboi::{
+    opcode,
+    instr::{INSTRUCTIONS, PREFIXED_INSTRUCTIONS},
+    primitives::{Byte, Word},
+};
+
+
+pub(crate) mod dot;
+
+/// Size of one ROM bank in bytes.
+pub(crate) const BANK_SIZE: usize = 0x4000;
+
+/// The addresses of the five interrupt handlers.
+const INTERRUPT_VECTORS: [u16; 5] = [0x40, 0x48, 0x50, 0x58, 0x60];
+
+/// Maximum number of entries read from a jump table.
+const MAX_TABLE_LEN: usize = 128;
+
+/// Maximum number of instructions decoded to check whether an `RST` handler
+/// is a jump table dispatcher.
+const MAX_DISPATCHER_LEN: usize = 32;
+
+/// The cartridge header (logo, title, checksums, ...), which is always data.
+const HEADER: std::ops::Range<usize> = 0x104..0x150;
+
+/// An address in the ROM, including the bank.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct RomAddr {
+    pub(crate) bank: usize,
+    pub(crate) addr: Word,
+}
+
+impl RomAddr {
+    /// Creates a ROM address. Addresses in the fixed bank always have bank
+    /// 0. `addr` must be below `0x8000`.
+    pub(crate) fn new(bank: usize, addr: Word) -> Self {
+        debug_assert!(addr.get() < 0x8000);
+        let bank = if addr.get() < 0x4000 { 0 } else { bank };
+        Self { bank, addr }
+    }
+
+    /// Returns the offset of this address in the ROM file.
+    pub(crate) fn offset(self) -> usize {
+        self.bank * BANK_SIZE + self.addr.get() as usize % BANK_SIZE
+    }
+
+    /// Returns the address `n` bytes after this one, or `None` if that is
+    /// not in the same bank anymore.
+    fn add(self, n: u16) -> Option<Self> {
+        if self.addr.get() as usize % BANK_SIZE + n as usize >= BANK_SIZE {
+            None
+        } else {
+            Some(Self { bank: self.bank, addr: self.addr + n })
+        }
+    }
+}
+
+impl fmt::Display for RomAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02X}:{:04X}", self.bank, self.addr.get())
+    }
+}
+
+/// The target of a jump, branch or call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Target {
+    Rom(RomAddr),
+
+    /// An address outside of ROM (e.g. code copied to HRAM).
+    Ram(Word),
+
+    /// An address in the switchable ROM area, but the bank is not known.
+    UnknownBank(Word),
+}
+
+impl Target {
+    pub(crate) fn rom(self) -> Option<RomAddr> {
+        match self {
+            Target::Rom(addr) => Some(addr),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Rom(addr) => write!(f, "{}", addr),
+            Target::Ram(addr) => write!(f, "{} (RAM)", addr),
+            Target::UnknownBank(addr) => write!(f, "??:{:04X}", addr.get()),
+        }
+    }
+}
+
+/// How a basic block is left.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Exit {
+    /// The next instruction starts another block.
+    Fallthrough(RomAddr),
+
+    /// Unconditional `JP` or `JR`.
+    Jump(Target),
+
+    /// Conditional `JP` or `JR`. `next` is the instruction after the branch.
+    Branch {
+        taken: Target,
+        next: RomAddr,
+    },
+
+    /// `RET` or `RETI`.
+    Return,
+
+    /// Conditional `RET`.
+    ConditionalReturn {
+        next: RomAddr,
+    },
+
+    /// `JP HL` or an `RST` to a jump table dispatcher (see
+    /// `CodeMap::jump_tables`).
+    IndirectJump,
+
+    /// An invalid opcode, an instruction reaching beyond the bank or the end
+    /// of the ROM.
+    Invalid,
+}
+
+/// A basic block.
+#[derive(Debug, Clone)]
+pub(crate) struct Block {
+    /// Start addresses of all instructions of this block. Never empty.
+    pub(crate) instrs: Vec<RomAddr>,
+
+    /// All `CALL` and `RST` instructions in this block with their targets.
+    pub(crate) calls: Vec<(RomAddr, Target)>,
+
+    pub(crate) exit: Exit,
+}
+
+impl Block {
+    /// Returns the blocks execution can continue with inside the same
+    /// function (i.e. not including calls).
+    pub(crate) fn successors(&self) -> Vec<RomAddr> {
+        match self.exit {
+            Exit::Fallthrough(next) => vec![next],
+            Exit::Jump(target) => target.rom().into_iter().collect(),
+            Exit::Branch { taken, next } => taken.rom().into_iter().chain(Some(next)).collect(),
+            Exit::ConditionalReturn { next } => vec![next],
+            Exit::Return | Exit::IndirectJump | Exit::Invalid => vec![],
+        }
+    }
+
+    /// Returns the address of the last instruction.
+    pub(crate) fn last_instr(&self) -> RomAddr {
+        *self.instrs.last().unwrap()
+    }
+}
+
+/// A function: an entry point (the target of a call, an interrupt vector or
+/// the ROM entry point) and all blocks reachable from it without calls.
+/// Blocks can be shared by multiple functions, e.g. if one function jumps
+/// into another one.
+#[derive(Debug, Clone)]
+pub(crate) struct Function {
+    /// Automatically generated name, e.g. `fn_0C_4A30` for a function at
+    /// `0x4A30` in bank `0x0C`. Only depends on the entry point.
+    pub(crate) name: String,
+
+    pub(crate) blocks: BTreeSet<RomAddr>,
+
+    /// Functions called by this function.
+    pub(crate) callees: BTreeSet<RomAddr>,
+}
+
+/// What a ROM byte is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ByteKind {
+    Code,
+    Data,
+
+    /// Neither reached by the analysis nor referenced as data.
+    Unknown,
+}
+
+/// The result of the analysis.
+pub(crate) struct CodeMap {
+    rom: Vec<Byte>,
+
+    /// All basic blocks by start address.
+    blocks: BTreeMap<RomAddr, Block>,
+
+    /// All functions by entry point.
+    fns: BTreeMap<RomAddr, Function>,
+
+    /// Start addresses of blocks that still have to be analyzed.
+    pending: Vec<RomAddr>,
+
+    /// All ROM offsets that are part of a decoded instruction.
+    code: Bitmap,
+
+    /// All ROM offsets where a decoded instruction starts.
+    instr_starts: Bitmap,
+
+    /// Executed instructions outside of ROM (e.g. in HRAM). As RAM can
+    /// change, these are not decoded by the analysis.
+    ram_instrs: BTreeSet<Word>,
+
+    /// Functions added since the last `run`.
+    new_fns: Vec<RomAddr>,
+
+    /// Start addresses of blocks that were split since the last `run`.
+    split_blocks: BTreeSet<RomAddr>,
+
+    /// All ROM offsets classified as data. Derived from `code` and
+    /// `data_refs` in `update_data`.
+    data: Bitmap,
+
+    /// ROM addresses loaded into register pairs by some instruction and the
+    /// start addresses of jump tables.
+    data_refs: BTreeSet<RomAddr>,
+
+    /// Entries of recognized jump tables by the address of the jumping
+    /// instruction (`JP HL` or `RST`).
+    jump_tables: BTreeMap<RomAddr, Vec<RomAddr>>,
+
+    /// Whether the `RST` vector at the given address is a jump table
+    /// dispatcher. Filled lazily.
+    dispatchers: BTreeMap<RomAddr, bool>,
+}
+
+impl CodeMap {
+    /// Analyzes the ROM, starting at the entry point `0x0100` and the
+    /// interrupt vectors.
+    pub(crate) fn new(rom: &[Byte]) -> Self {
+        let mut out = Self {
+            rom: rom.to_vec(),
+            blocks: BTreeMap::new(),
+            fns: BTreeMap::new(),
+            pending: Vec::new(),
+            code: Bitmap::new(rom.len()),
+            instr_starts: Bitmap::new(rom.len()),
+            ram_instrs: BTreeSet::new(),
+            new_fns: Vec::new(),
+            split_blocks: BTreeSet::new(),
+            data: Bitmap::new(rom.len()),
+            data_refs: BTreeSet::new(),
+            jump_tables: BTreeMap::new(),
+            dispatchers: BTreeMap::new(),
+        };
+
+        out.add_entry_point(RomAddr::new(0, Word::new(0x100)));
+        for &vector in &INTERRUPT_VECTORS {
+            out.add_entry_point(RomAddr::new(0, Word::new(vector)));
+        }
+        out
+    }
+
+    /// Adds a function entry point and analyzes all code reachable from it.
+    pub(crate) fn add_entry_point(&mut self, entry: RomAddr) {
+        self.add_function(entry);
+        self.run();
+    }
+
+    /// Extends the analysis with the instruction at `addr`, which is about
+    /// to be executed. Unknown ROM addresses are added as entry points.
+    pub(crate) fn observe_execution(&mut self, addr: Word, rom_bank: usize) {
+        if addr.get() >= 0x8000 {
+            self.ram_instrs.insert(addr);
+            return;
+        }
+
+        let addr = RomAddr::new(rom_bank, addr);
+        if addr.offset() < self.rom.len() && !self.instr_starts.get(addr.offset()) {
+            self.add_entry_point(addr);
+        }
+    }
+
+    /// Returns whether an instruction starts at `addr` (with `rom_bank`
+    /// mapped), as far as known.
+    pub(crate) fn is_instr_start(&self, addr: Word, rom_bank: usize) -> bool {
+        if addr.get() >= 0x8000 {
+            self.ram_instrs.contains(&addr)
+        } else {
+            self.instr_starts.get(RomAddr::new(rom_bank, addr).offset())
+        }
+    }
+
+    /// Returns what the byte at `addr` is used for.
+    pub(crate) fn kind_at(&self, addr: RomAddr) -> ByteKind {
+        let offset = addr.offset();
+        if self.code.get(offset) {
+            ByteKind::Code
+        } else if self.data.get(offset) {
+            ByteKind::Data
+        } else {
+            ByteKind::Unknown
+        }
+    }
+
+    pub(crate) fn blocks(&self) -> &BTreeMap<RomAddr, Block> {
+        &self.blocks
+    }
+
+    pub(crate) fn functions(&self) -> &BTreeMap<RomAddr, Function> {
+        &self.fns
+    }
+
+    pub(crate) fn function(&self, entry: RomAddr) -> Option<&Function> {
+        self.fns.get(&entry)
+    }
+
+    /// Returns the targets of all jumps and branches in ROM.
+    pub(crate) fn jump_targets(&self) -> BTreeSet<RomAddr> {
+        self.blocks.values()
+            .filter_map(|block| match block.exit {
+                Exit::Jump(target) | Exit::Branch { taken: target, .. } => target.rom(),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub(crate) fn jump_tables(&self) -> &BTreeMap<RomAddr, Vec<RomAddr>> {
+        &self.jump_tables
+    }
+
+    /// Returns all jumps and calls whose target is not known statically with
+    /// the target, if it is at least partially known. `JP HL` has no target.
+    pub(crate) fn unresolved(&self) -> Vec<(RomAddr, Option<Target>)> {
+        let mut out = Vec::new();
+        for block in self.blocks.values() {
+            for &(addr, target) in &block.calls {
+                if target.rom().is_none() {
+                    out.push((addr, Some(target)));
+                }
+            }
+
+            match block.exit {
+                Exit::Jump(target) | Exit::Branch { taken: target, .. }
+                    if target.rom().is_none() =>
+                {
+                    out.push((block.last_instr(), Some(target)));
+                }
+                Exit::IndirectJump if !self.jump_tables.contains_key(&block.last_instr()) => {
+                    out.push((block.last_instr(), None));
+                }
+                _ => {}
+            }
+        }
+
+        out
+    }
+
+    fn add_function(&mut self, entry: RomAddr) {
+        if let Entry::Vacant(e) = self.fns.entry(entry) {
+            e.insert(Function {
+                name: format!("fn_{:02X}_{:04X}", entry.bank, entry.addr.get()),
+                blocks: BTreeSet::new(),
+                callees: BTreeSet::new(),
+            });
+            self.pending.push(entry);
+            self.new_fns.push(entry);
+        }
+    }
+
+    /// Processes all pending blocks and then rebuilds all functions that
+    /// changed: new ones and those containing a block that was split.
+    fn run(&mut self) {
+        while let Some(start) = self.pending.pop() {
+            self.analyze_block(start);
+        }
+
+        let split = mem::take(&mut self.split_blocks);
+        let mut entries = mem::take(&mut self.new_fns);
+        entries.extend(self.fns.iter()
+            .filter(|(_, f)| split.iter().any(|b| f.blocks.contains(b)))
+            .map(|(&entry, _)| entry));
+        for entry in entries {
+            self.build_function(entry);
+        }
+        self.update_data();
+    }
+
+    /// Recomputes `data`: everything from a data reference up to the next
+    /// code byte (or the end of the bank) is data. So is the header.
+    fn update_data(&mut self) {
+        let len = self.rom.len();
+        let code = &self.code;
+        let mut data = Bitmap::new(len);
+        for offset in HEADER.take_while(|&o| o < len).filter(|&o| !code.get(o)) {
+            data.set(offset);
+        }
+
+        for addr in &self.data_refs {
+            let start = addr.offset();
+            let bank_end = (start / BANK_SIZE + 1) * BANK_SIZE;
+
+            // If this is set, the region was already marked via a previous
+            // reference.
+            if data.get(start) {
+                continue;
+            }
+            for offset in (start..bank_end.min(len)).take_while(|&o| !code.get(o)) {
+                data.set(offset);
+            }
+        }
+
+        self.data = data;
+    }
+
+    /// Decodes the block starting at `start`, if it doesn't exist yet.
+    fn analyze_block(&mut self, start: RomAddr) {
+        // If `start` is an instruction in the middle of an existing block,
+        // that block is split. Otherwise, a new block is decoded, which
+        // overlaps other blocks if it starts in the middle of an instruction.
+        if self.blocks.contains_key(&start) || self.split_block_at(start) {
+            return;
+        }
+
+        let mut block = Block {
+            instrs: Vec::new(),
+            calls: Vec::new(),
+            exit: Exit::Invalid,
+        };
+        let mut pos = start;
+        loop {
+            if pos != start && (self.blocks.contains_key(&pos) || self.split_block_at(pos)) {
+                block.exit = Exit::Fallthrough(pos);
+                break;
+            }
+
+            let len = match instr_len(&self.rom, pos) {
+                Some(len) => len,
+                None => break,
+            };
+            block.instrs.push(pos);
+
+            let offset = pos.offset();
+            self.instr_starts.set(offset);
+            for o in offset..offset + len as usize {
+                self.code.set(o);
+            }
+            let opcode = self.rom[offset].get();
+            let imm8 = self.rom.get(offset + 1).map_or(0, |b| b.get());
+            let imm16 = Word::new(
+                self.rom.get(offset + 2).map_or(0, |b| b.get()) as u16 * 0x100 + imm8 as u16
+            );
+            let next = pos.add(len as u16);
+            let relative = || self.target(pos, pos.addr + len + imm8 as i8);
+
+            let exit = match opcode {
+                opcode!("JR r8") => Some(Exit::Jump(relative())),
+                opcode!("JR NZ, r8")
+                | opcode!("JR Z, r8")
+                | opcode!("JR NC, r8")
+                | opcode!("JR C, r8") => {
+                    let taken = relative();
+                    Some(next.map_or(Exit::Invalid, |next| Exit::Branch { taken, next }))
+                }
+                opcode!("JP a16") => Some(Exit::Jump(self.target(pos, imm16))),
+                opcode!("JP NZ, a16")
+                | opcode!("JP Z, a16")
+                | opcode!("JP NC, a16")
+                | opcode!("JP C, a16") => {
+                    let taken = self.target(pos, imm16);
+                    Some(next.map_or(Exit::Invalid, |next| Exit::Branch { taken, next }))
+                }
+                opcode!("JP HL") => Some(Exit::IndirectJump),
+                opcode!("RET") | opcode!("RETI") => Some(Exit::Return),
+                opcode!("RET NZ")
+                | opcode!("RET Z")
+                | opcode!("RET NC")
+                | opcode!("RET C") => {
+                    Some(next.map_or(Exit::Invalid, |next| Exit::ConditionalReturn { next }))
+                }
+                opcode!("CALL a16")
+                | opcode!("CALL NZ, a16")
+                | opcode!("CALL Z, a16")
+                | opcode!("CALL NC, a16")
+                | opcode!("CALL C, a16") => {
+                    block.calls.push((pos, self.target(pos, imm16)));
+                    None
+                }
+                opcode!("LD BC, d16") | opcode!("LD DE, d16") | opcode!("LD HL, d16") => {
+                    if let Some(addr) = self.target(pos, imm16).rom() {
+                        self.data_refs.insert(addr);
+                    }
+                    None
+                }
+                op if op & 0b1100_0111 == 0b1100_0111 => {
+                    let vector = RomAddr::new(0, Word::new((op & 0b0011_1000) as u16));
+                    block.calls.push((pos, Target::Rom(vector)));
+                    if self.is_dispatcher(vector) {
+                        Some(Exit::IndirectJump)
+                    } else {
+                        None
+                    }
+                }
+                _ => None,
+            };
+
+            if let Some(exit) = exit {
+                block.exit = exit;
+                break;
+            }
+            match next {
+                Some(next) => pos = next,
+                None => break,
+            }
+        }
+
+        // A block starting with an invalid instruction still gets this one
+        // "instruction", so that the block is not empty.
+        if block.instrs.is_empty() {
+            block.instrs.push(start);
+        }
+
+        for &(_, target) in &block.calls {
+            if let Some(addr) = target.rom() {
+                self.add_function(addr);
+            }
+        }
+        if block.exit == Exit::IndirectJump {
+            let last = block.last_instr();
+            let table = if self.rom[last.offset()].get() == opcode!("JP HL") {
+                self.table_loaded_in(&block)
+            } else {
+                last.add(1)
+            };
+
+            if let Some(table) = table {
+                let entries = self.read_table(table);
+                for &entry in &entries {
+                    self.add_function(entry);
+                }
+                self.data_refs.insert(table);
+                self.jump_tables.insert(last, entries);
+            }
+        }
+        self.pending.extend(block.successors());
+        self.blocks.insert(start, block);
+    }
+
+    /// Returns the jump table address if the given block (ending with `JP
+    /// HL`) loads it with `LD HL, d16` and reads an entry with `LD A, [HL+]`.
+    fn table_loaded_in(&self, block: &Block) -> Option<RomAddr> {
+        let opcode_at = |addr: RomAddr| self.rom[addr.offset()].get();
+        let ld = block.instrs.iter().rposition(|&i| opcode_at(i) == opcode!("LD HL, d16"))?;
+        block.instrs[ld..].iter().find(|&&i| opcode_at(i) == opcode!("LD A, (HL+)"))?;
+
+        let offset = block.instrs[ld].offset();
+        let imm16 = Word::from_bytes(self.rom[offset + 1], self.rom[offset + 2]);
+        self.target(block.instrs[ld], imm16).rom()
+    }
+
+    /// Reads the entries of the jump table at `table`. The table ends before
+    /// the first entry that doesn't point to a valid instruction in ROM, that
+    /// overlaps code or that points into the table itself. Zero entries are
+    /// considered padding and end the table as well.
+    fn read_table(&self, table: RomAddr) -> Vec<RomAddr> {
+        let mut entries = Vec::new();
+        for i in 0..MAX_TABLE_LEN {
+            let pos = match table.add(2 * i as u16) {
+                Some(pos) if pos.add(1).is_some() => pos,
+                _ => break,
+            };
+            let offset = pos.offset();
+            if offset + 1 >= self.rom.len() || self.code.get(offset) || self.code.get(offset + 1) {
+                break;
+            }
+
+            let word = Word::from_bytes(self.rom[offset], self.rom[offset + 1]);
+            if word.get() == 0 {
+                break;
+            }
+            let entry = match self.target(pos, word).rom() {
+                Some(entry) if instr_len(&self.rom, entry).is_some() => entry,
+                _ => break,
+            };
+            if (table.offset()..=offset + 1).contains(&entry.offset()) {
+                break;
+            }
+            entries.push(entry);
+        }
+
+        entries
+    }
+
+    /// Returns whether the handler of the given `RST` vector is a jump table
+    /// dispatcher, i.e. pops the return address into `HL` and ends with `JP
+    /// HL`. Unconditional jumps are followed.
+    fn is_dispatcher(&mut self, vector: RomAddr) -> bool {
+        if let Some(&is_dispatcher) = self.dispatchers.get(&vector) {
+            return is_dispatcher;
+        }
+
+        let mut pos = vector;
+        let mut pops_hl = false;
+        let mut is_dispatcher = false;
+        for _ in 0..MAX_DISPATCHER_LEN {
+            let len = match instr_len(&self.rom, pos) {
+                Some(len) => len,
+                None => break,
+            };
+            let offset = pos.offset();
+            let next = match self.rom[offset].get() {
+                opcode!("POP HL") => {
+                    pops_hl = true;
+                    pos.add(len as u16)
+                }
+                opcode!("JP HL") => {
+                    is_dispatcher = pops_hl;
+                    break;
+                }
+                opcode!("JP a16") => {
+                    let imm16 = Word::from_bytes(self.rom[offset + 1], self.rom[offset + 2]);
+                    self.target(pos, imm16).rom()
+                }
+                opcode!("RET") | opcode!("RETI") => break,
+                _ => pos.add(len as u16),
+            };
+            match next {
+                Some(next) => pos = next,
+                None => break,
+            }
+        }
+
+        self.dispatchers.insert(vector, is_dispatcher);
+        is_dispatcher
+    }
+
+    /// If `addr` is the start of an instruction inside an existing block
+    /// (but not its first one), splits that block at `addr` and returns
+    /// `true`.
+    fn split_block_at(&mut self, addr: RomAddr) -> bool {
+        let (&start, block) = match self.blocks.range_mut(..addr).next_back() {
+            Some((start, block)) if start.bank == addr.bank => (start, block),
+            _ => return false,
+        };
+        let idx = match block.instrs.iter().position(|&i| i == addr) {
+            Some(idx) => idx,
+            None => return false,
+        };
+
+        let second_calls = block.calls.iter().position(|&(a, _)| a >= addr);
+        let second = Block {
+            instrs: block.instrs.split_off(idx),
+            calls: block.calls.split_off(second_calls.unwrap_or(block.calls.len())),
+            exit: block.exit,
+        };
+        block.exit = Exit::Fallthrough(addr);
+
+        self.split_blocks.insert(start);
+        self.blocks.insert(addr, second);
+        true
+    }
+
+    /// Collects all blocks of the function starting at `entry`. Jumps into
+    /// other functions are followed as well.
+    fn build_function(&mut self, entry: RomAddr) {
+        let mut blocks = BTreeSet::new();
+        let mut callees = BTreeSet::new();
+        let mut stack = vec![entry];
+        while let Some(addr) = stack.pop() {
+            if let Some(block) = self.blocks.get(&addr) {
+                if blocks.insert(addr) {
+                    callees.extend(block.calls.iter().filter_map(|(_, t)| t.rom()));
+                    stack.extend(block.successors());
+                }
+            }
+        }
+
+        let f = self.fns.get_mut(&entry).unwrap();
+        f.blocks = blocks;
+        f.callees = callees;
+    }
+
+    /// Resolves the target of a jump or call at `from`.
+    fn target(&self, from: RomAddr, target: Word) -> Target {
+        let num_banks = self.rom.len() / BANK_SIZE;
+        let addr = match target.get() {
+            0x0000..=0x3FFF => RomAddr::new(0, target),
+            0x4000..=0x7FFF if from.bank != 0 => RomAddr::new(from.bank, target),
+            0x4000..=0x7FFF if num_banks <= 2 => RomAddr::new(1, target),
+            0x4000..=0x7FFF => return Target::UnknownBank(target),
+            _ => return Target::Ram(target),
+        };
+
+        if addr.offset() < self.rom.len() {
+            Target::Rom(addr)
+        } else {
+            Target::UnknownBank(target)
+        }
+    }
+}
+
+/// A set of ROM offsets.
+struct Bitmap(Vec<u64>);
+
+impl Bitmap {
+    fn new(len: usize) -> Self {
+        Bitmap(vec![0; len.div_ceil(64)])
+    }
+
+    fn get(&self, offset: usize) -> bool {
+        self.0.get(offset / 64).is_some_and(|chunk| chunk & (1 << (offset % 64)) != 0)
+    }
+
+    fn set(&mut self, offset: usize) {
+        self.0[offset / 64] |= 1 << (offset % 64);
+    }
+}
+
+/// Returns the length of the instruction at `addr`, or `None` if it's
+/// invalid or reaches beyond the end of its bank or the ROM.
+fn instr_len(rom: &[Byte], addr: RomAddr) -> Option<u8> {
+    let offset = addr.offset();
+    let opcode = *rom.get(offset)?;
+    let len = match INSTRUCTIONS[opcode] {
+        Some(instr) if instr.mnemonic == "PREFIX CB" => {
+            PREFIXED_INSTRUCTIONS[*rom.get(offset + 1)?].len
+        }
+        Some(instr) => instr.len,
+        None => return None,
+    };
+
+    if addr.add(len as u16 - 1).is_none() || offset + len as usize > rom.len() {
+        return None;
+    }
+
+    Some(len)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_code_map() {
+        let mut rom = vec![0; 0x10000];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            rom[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+
+        // 0100: NOP; JP 0150
+        put(0x100, &[0x00, 0xC3, 0x50, 0x01]);
+        // 0150: CALL 0200; JR NZ, 0150; JP 4000
+        put(0x150, &[0xCD, 0x00, 0x02, 0x20, 0xFB, 0xC3, 0x00, 0x40]);
+        // 0200: LD A, 3; RET
+        put(0x200, &[0x3E, 0x03, 0xC9]);
+        // 0300: JP 0201 (into the immediate of `LD A, 3`, which is `INC BC`)
+        put(0x300, &[0xC3, 0x01, 0x02]);
+
+        let rom = rom.into_iter().map(Byte::new).collect::<Vec<_>>();
+        let mut map = CodeMap::new(&rom);
+        let addr = |a| RomAddr::new(0, Word::new(a));
+        let list = |set: &BTreeSet<RomAddr>| set.iter().copied().collect::<Vec<_>>();
+
+        let entries = map.functions().keys().copied().collect::<Vec<_>>();
+        assert_eq!(entries, [
+            addr(0x40), addr(0x48), addr(0x50), addr(0x58), addr(0x60), addr(0x100), addr(0x200),
+        ]);
+        assert_eq!(map.functions()[&addr(0x200)].name, "fn_00_0200");
+
+        // The jump to 0x4000 from bank 0 can't be resolved in a 4 bank ROM.
+        assert_eq!(
+            map.unresolved(),
+            [(addr(0x155), Some(Target::UnknownBank(Word::new(0x4000))))],
+        );
+
+        let main = &map.functions()[&addr(0x100)];
+        assert_eq!(list(&main.blocks), [addr(0x100), addr(0x150), addr(0x155)]);
+        assert_eq!(list(&main.callees), [addr(0x200)]);
+        assert_eq!(map.blocks()[&addr(0x150)].instrs, [addr(0x150), addr(0x153)]);
+
+        let f = &map.functions()[&addr(0x200)];
+        assert_eq!(list(&f.blocks), [addr(0x200)]);
+
+        // The block at 0x0201 overlaps the existing one, which is split at the
+        // shared `RET`.
+        map.add_entry_point(addr(0x300));
+        let f = &map.functions()[&addr(0x300)];
+        assert_eq!(list(&f.blocks), [addr(0x201), addr(0x202), addr(0x300)]);
+        assert_eq!(map.blocks()[&addr(0x201)].exit, Exit::Fallthrough(addr(0x202)));
+        assert_eq!(map.blocks()[&addr(0x200)].instrs, [addr(0x200)]);
+        let f = &map.functions()[&addr(0x200)];
+        assert_eq!(list(&f.blocks), [addr(0x200), addr(0x202)]);
+
+        map.add_entry_point(RomAddr::new(3, Word::new(0x4A30)));
+        assert_eq!(map.functions()[&RomAddr::new(3, Word::new(0x4A30))].name, "fn_03_4A30");
+    }
+
+    #[test]
+    fn test_classification() {
+        // 0100: LD HL, $0200; JP $0100
+        // 0300: NOP; RET (not reachable statically)
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x106].copy_from_slice(&[0x21, 0x00, 0x02, 0xC3, 0x00, 0x01]);
+        rom[0x301] = 0xC9;
+        let rom = rom.into_iter().map(Byte::new).collect::<Vec<_>>();
+        let mut map = CodeMap::new(&rom);
+        let kind = |map: &CodeMap, a| map.kind_at(RomAddr::new(1, Word::new(a)));
+
+        assert_eq!(kind(&map, 0x40), ByteKind::Code);
+        assert_eq!(kind(&map, 0x105), ByteKind::Code);
+        assert_eq!(kind(&map, 0x106), ByteKind::Data);
+        assert_eq!(kind(&map, 0x150), ByteKind::Unknown);
+        assert_eq!(kind(&map, 0x200), ByteKind::Data);
+        assert_eq!(kind(&map, 0x3FFF), ByteKind::Data);
+        assert_eq!(kind(&map, 0x4000), ByteKind::Unknown);
+
+        // Executed code ends the data region.
+        map.observe_execution(Word::new(0x300), 1);
+        assert_eq!(kind(&map, 0x2FF), ByteKind::Data);
+        assert_eq!(kind(&map, 0x300), ByteKind::Code);
+        assert_eq!(kind(&map, 0x302), ByteKind::Unknown);
+        assert!(map.function(RomAddr::new(0, Word::new(0x300))).is_some());
+        assert!(map.is_instr_start(Word::new(0x301), 1));
+        assert!(!map.is_instr_start(Word::new(0x302), 1));
+
+        assert!(!map.is_instr_start(Word::new(0xFF80), 1));
+        map.observe_execution(Word::new(0xFF80), 1);
+        assert!(map.is_instr_start(Word::new(0xFF80), 1));
+    }
+
+    #[test]
+    fn test_jump_tables() {
+        let mut rom = vec![0; 0x8000];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            rom[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+
+        // The RST $28 handler jumps to the dispatcher at 0200, which uses A
+        // as index into the table following the RST.
+        let dispatch = [0x5F, 0x16, 0x00, 0x19, 0x2A, 0x66, 0x6F, 0xE9];
+        put(0x28, &[0xC3, 0x00, 0x02]);
+        put(0x200, &[0x87, 0xE1]);
+        put(0x202, &dispatch);
+
+        // 0100: RST $28; dw 0300, 0310; dw $FFFF (not a ROM address)
+        put(0x100, &[0xEF, 0x00, 0x03, 0x10, 0x03, 0xFF, 0xFF]);
+        // 0300: LD HL, 0320; ADD A; (dispatch)
+        put(0x300, &[0x21, 0x20, 0x03, 0x87]);
+        put(0x304, &dispatch);
+        // 0310: RET
+        put(0x310, &[0xC9]);
+        // 0320: dw 0330, 0331; 0330: RET; RET
+        put(0x320, &[0x30, 0x03, 0x31, 0x03]);
+        put(0x330, &[0xC9, 0xC9]);
+
+        let rom = rom.into_iter().map(Byte::new).collect::<Vec<_>>();
+        let map = CodeMap::new(&rom);
+        let addr = |a| RomAddr::new(0, Word::new(a));
+
+        assert_eq!(map.jump_tables()[&addr(0x100)], [addr(0x300), addr(0x310)]);
+        assert_eq!(map.jump_tables()[&addr(0x30B)], [addr(0x330), addr(0x331)]);
+        for entry in &[0x300, 0x310, 0x330, 0x331] {
+            assert!(map.functions().contains_key(&addr(*entry)));
+        }
+        assert_eq!(map.blocks()[&addr(0x100)].exit, Exit::IndirectJump);
+        assert_eq!(map.kind_at(addr(0x101)), ByteKind::Data);
+        assert_eq!(map.kind_at(addr(0x320)), ByteKind::Data);
+
+        // Only the `JP HL` of the dispatcher itself stays unresolved.
+        assert_eq!(map.unresolved(), [(addr(0x209), None)]);
+    }
+}
@@ -3,51 +3,58 @@ use std::path::PathBuf;
 use log::LevelFilter;
 use structopt::StructOpt;
 
-use mahboi::{
-    BiosKind,
-    primitives::Word,
-};
+use mahboi::{BiosKind, HardwareModel, InputPolling, machine::trace_stream::TraceFormat};
+use crate::disasm::Selection;
 
 
+/// The scale factor of the window if `--scale` is not given.
+pub(crate) const DEFAULT_SCALE: u8 = 4;
+
 /// Gameboy Emulator.
 ///
 /// The keys WASD are mapped to the up, left, down and right button
 /// respectively. 'J' is mapped to the gameboy's A button, 'K' to the B button,
 /// 'N' to the Select button and 'M' to the Start button. The button 'Q' can be
-/// used to speed up the emulation.
+/// used to speed up the emulation. 'F5' starts and stops recording an input
+/// macro, which is replayed with 'F6'. 'F7' saves the state of the emulator
+/// and 'F8' loads it again. 'F10' opens the state manager to pick one of
+/// several state slots by thumbnail (arrow keys to select, enter to load,
+/// space to save, escape to close). 'F9' exports the state as BESS file,
+/// which other emulators can load. Macros, states and battery saves are
+/// stored per ROM in the config directory.
 #[derive(Debug, StructOpt)]
 #[structopt(author)]
 pub(crate) struct Args {
     /// Set the scale factor for the window. The native Gameboy resolution
     /// 144x160 multiplied with the scale factor is the size of the window in
-    /// physical pixels. Between 1 and 16.
-    #[structopt(
-        long,
-        default_value = "4",
-        validator(check_scale),
-    )]
-    pub(crate) scale: u8,
+    /// physical pixels. Between 1 and 16. [default: the size and position of
+    /// the window when it was closed last time, or 4]
+    #[structopt(long, validator(check_scale))]
+    pub(crate) scale: Option<u8>,
 
-    /// Start in debugging mode (a TUI debugger). Not usable on Windows!
+    /// Start in debugging mode (a TUI debugger).
     #[structopt(long)]
     pub(crate) debug: bool,
 
+    /// Open a graphical debugger in a second window. It shows the registers,
+    /// the memory, a disassembly and the contents of VRAM. Cannot be combined
+    /// with `--debug`.
+    #[structopt(long, conflicts_with_all = &["debug", "gdb", "link", "netplay"])]
+    pub(crate) gui_debug: bool,
+
     /// Path to the ROM that should be loaded into the emulator.
     #[structopt(parse(from_os_str))]
     pub(crate) path_to_rom: PathBuf,
 
     /// Breakpoint that is added to the debugger at the very beginning.
-    /// Breakpoints are specified in hexadecimal. To add multiple breakpoints,
-    /// you can either list them after one `--breakpoints` flag or specify
+    /// Breakpoints are specified as hexadecimal address, as `bank:address`
+    /// or as name of a symbol (see `--sym`). To add multiple breakpoints, you
+    /// can either list them after one `--breakpoints` flag or specify
     /// `--breakpoints` multiple times. Example: `--breakpoints 23 FF
-    /// --breakpoints 10B`.
-    #[structopt(
-        long,
-        parse(try_from_str = parse_breakpoint),
-        requires = "debug",
-    )]
-    #[cfg_attr(windows, allow(dead_code))]
-    pub(crate) breakpoints: Vec<Word>,
+    /// --breakpoints 5:4123 Main`. Breakpoints given as plain address trigger
+    /// in all ROM banks.
+    #[structopt(long, requires = "debug")]
+    pub(crate) breakpoints: Vec<String>,
 
     /// When starting in debugging mode, don't pause at the beginning, but
     /// start running right ahead (particularly useful in combination with
@@ -55,6 +62,124 @@ pub(crate) struct Args {
     #[structopt(long, requires = "debug")]
     pub(crate) instant_start: bool,
 
+    /// Symbol file (as generated by `rgblink -n`) to load in debugging mode
+    /// or for `--disassemble` and `--export-project`. Symbol names are shown
+    /// in the debugger and can be used instead of addresses. If not
+    /// specified, a `.sym` file next to the ROM with the same name is loaded,
+    /// if it exists.
+    #[structopt(long, parse(from_os_str))]
+    pub(crate) sym: Option<PathBuf>,
+
+    /// Instead of running the ROM, write a disassembly of it (in RGBDS
+    /// syntax) to the given file and exit. Symbols are used as labels.
+    /// Functions without a symbol get a generated name like `fn_0C_4A30`.
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["debug", "gdb"])]
+    pub(crate) disassemble: Option<PathBuf>,
+
+    /// Limits `--disassemble` to one bank (e.g. `1f`) or a range of addresses
+    /// (e.g. `0150-3fff` or `5:4000-4fff`). All values are hexadecimal.
+    #[structopt(
+        long,
+        default_value = "",
+        hide_default_value = true,
+        parse(try_from_str = Selection::parse),
+    )]
+    pub(crate) disassemble_range: Selection,
+
+    /// Instead of running the ROM, write its disassembly as an RGBDS project
+    /// (one file per bank, `main.asm` and a Makefile) to the given directory
+    /// and exit. Functions and jump targets without a symbol get generated
+    /// labels. The result assembles back to the original ROM.
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &["debug", "gdb", "disassemble"],
+    )]
+    pub(crate) export_project: Option<PathBuf>,
+
+    /// Instead of running the ROM, decode each ROM bank as tile data and
+    /// write it as PNG sprite sheet (`bank_XX.png`) to the given directory
+    /// and exit. Tiles overlapping code found by the analysis are shown in
+    /// dark red, banks consisting only of code are skipped.
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &["debug", "gdb", "disassemble", "export-project"],
+    )]
+    pub(crate) export_tiles: Option<PathBuf>,
+
+    /// Instead of running the ROM, write the background and window tile maps
+    /// of the state given with `--load-state` as PNG (`bg.png` and
+    /// `window.png`) to the given directory and exit.
+    #[structopt(long, parse(from_os_str), requires = "load-state")]
+    pub(crate) export_maps: Option<PathBuf>,
+
+    /// Outline the part of the maps that is visible on the screen in the
+    /// images written by `--export-maps`.
+    #[structopt(long, requires = "export-maps")]
+    pub(crate) map_viewport: bool,
+
+    /// Instead of running the ROM, compare the state given with
+    /// `--load-state` with the state in the given file, print all registers,
+    /// IO ports and memory ranges that differ and exit.
+    #[structopt(long, parse(from_os_str), requires = "load-state")]
+    pub(crate) diff_state: Option<PathBuf>,
+
+    /// File with debugger console commands (one per line) that are executed
+    /// at startup. Commands like `c` or `s` wait until the emulator is
+    /// paused, so this can be used to script debugging sessions. Lines
+    /// starting with `#` are ignored.
+    #[structopt(long, parse(from_os_str), requires = "debug")]
+    pub(crate) debug_script: Option<PathBuf>,
+
+    /// Don't restore and save the debugger session. By default, breakpoints,
+    /// watchpoints and watch expressions are stored in a `.mahboi-session`
+    /// file next to the ROM and restored at the next start.
+    #[structopt(long, requires = "debug")]
+    pub(crate) no_session: bool,
+
+    /// Number of executed instructions that are recorded. In debugging mode,
+    /// they can be inspected in the "Trace" tab of the debugger. They are
+    /// also written to the crash dump if the emulator panics. A value of `0`
+    /// disables tracing, which makes emulation slightly faster.
+    #[structopt(long, default_value = "1000")]
+    pub(crate) trace_len: usize,
+
+    /// Write one line per executed instruction to the given file, in the
+    /// format used by Game Boy Doctor: the registers and the four bytes at
+    /// PC before the instruction is executed. Instructions of the BIOS are
+    /// not logged, so compare with logs of emulators that skip the boot ROM.
+    /// Note that the reference logs of Game Boy Doctor expect `LY` to always
+    /// read `0x90`, which mahboi does not do.
+    #[structopt(long, parse(from_os_str))]
+    pub(crate) trace_log: Option<PathBuf>,
+
+    /// Instead of running the ROM in a window, run it as fast as possible
+    /// and compare each instruction with the given reference log in the
+    /// format of `--trace-log` (e.g. from Game Boy Doctor or SameBoy). Stops
+    /// at the first line that differs and prints it together with the lines
+    /// before it. Fields missing in the reference log are not compared.
+    #[structopt(long, parse(from_os_str))]
+    pub(crate) compare_log: Option<PathBuf>,
+
+    /// Write all executed instructions (including those of the BIOS) to the
+    /// given file, together with markers for interrupt dispatches and bank
+    /// switches. In contrast to `--trace-log`, this is meant for offline
+    /// analysis of long sessions and is fast enough to keep it enabled while
+    /// playing. See `--trace-stream-format`.
+    #[structopt(long, parse(from_os_str))]
+    pub(crate) trace_stream: Option<PathBuf>,
+
+    /// The format of the file written with `--trace-stream`: 'text' or the
+    /// about three times smaller 'binary'.
+    #[structopt(
+        long,
+        default_value = "text",
+        requires = "trace-stream",
+        parse(try_from_str = parse_trace_format),
+    )]
+    pub(crate) trace_stream_format: TraceFormat,
+
     /// Defines how much faster turbo mode (key Q) is than 100%. So, a value of
     /// `2` means double the speed, while `4` would mean 400% speed (= roughly
     /// 240FPS).
@@ -68,6 +193,27 @@ pub(crate) struct Args {
     #[structopt(long, default_value = "60")]
     pub(crate) fps: f64,
 
+    /// Minimize the time between pressing a key and seeing the result: the
+    /// emulation is synchronized with the display instead of running at
+    /// `--fps`, one frame per displayed frame (so slightly faster than the
+    /// original on 60Hz displays). The input is sampled right before each
+    /// frame. Turbo mode is not available in this mode.
+    #[structopt(long)]
+    pub(crate) low_latency: bool,
+
+    /// In low latency mode, wait this many milliseconds after a frame was
+    /// displayed before emulating the next one, so that it is finished just
+    /// before the display needs it and includes more recent input. If the
+    /// value is too high for your machine, frames are skipped. A value of
+    /// around `10` works well on most machines with a 60Hz display.
+    #[structopt(long, default_value = "0", requires = "low-latency")]
+    pub(crate) frame_delay: u64,
+
+    /// Show the input latency (in milliseconds) in the top left corner: the
+    /// one of the last key press and the average of the last 32 key presses.
+    #[structopt(long)]
+    pub(crate) latency_overlay: bool,
+
     /// Specifies which log messages to display and which to supress. The
     /// specified value will show all log messages with the same level or any
     /// higher level. So `-l warn` will print errors and warnings and `-l
@@ -89,16 +235,94 @@ pub(crate) struct Args {
         parse(try_from_str = parse_bios_kind),
     )]
     pub(crate) bios: BiosKind,
-}
 
-fn parse_breakpoint(src: &str) -> Result<Word, String> {
-    u16::from_str_radix(src, 16)
-        .map(Word::new)
-        .map_err(|e| format!(
-            "failed to parse breakpoint: {} (values like '1f' are valid -- no \
-                leading `0x`!)",
-            e,
-        ))
+    /// The Game Boy model to emulate: 'dmg0', 'dmg', 'mgb' (Game Boy Pocket),
+    /// 'sgb', 'cgb-dmg' (Game Boy Color running a DMG game) or 'cgb'. This
+    /// determines the register values after the boot ROM and some hardware
+    /// quirks. CGB features are not emulated.
+    #[structopt(
+        long,
+        default_value = "dmg",
+        parse(try_from_str = parse_model),
+    )]
+    pub(crate) model: HardwareModel,
+
+    /// How often the pressed keys are checked: once per 'frame' is enough for
+    /// normal playing, 'scanline' or 'instruction' allow inputs in the middle
+    /// of a frame (as used in speedruns), but are slower.
+    #[structopt(
+        long,
+        default_value = "frame",
+        parse(try_from_str = parse_input_polling),
+    )]
+    pub(crate) input_polling: InputPolling,
+
+    /// Let the game see opposing directions (Left and Right or Up and Down)
+    /// pressed at the same time, which is impossible on a real Game Boy. By
+    /// default, neither of them is pressed then. Useful to hunt for glitches,
+    /// but some games misbehave.
+    #[structopt(long)]
+    pub(crate) allow_opposing_directions: bool,
+
+    /// Cache decoded instructions of the ROM. This speeds up emulation a bit,
+    /// but needs four bytes of memory per ROM byte.
+    #[structopt(long)]
+    pub(crate) decode_cache: bool,
+
+    /// PNG image that is used as camera input for cartridges with a camera
+    /// (Pocket Camera). It is cropped and scaled to the resolution of the
+    /// camera. Without this, the camera does not capture anything.
+    #[structopt(long, parse(from_os_str))]
+    pub(crate) camera_image: Option<PathBuf>,
+
+    /// Load the given save state at startup. Both the own format of mahboi
+    /// (saved with 'F7') and BESS files (e.g. exported by other emulators)
+    /// are supported.
+    #[structopt(long, parse(from_os_str))]
+    pub(crate) load_state: Option<PathBuf>,
+
+    /// Start an HTTP server listening on the given TCP port on localhost to
+    /// control the emulator from other programs: read and write memory,
+    /// press keys, save and load states, take screenshots, pause and
+    /// continue. See `desktop/src/remote.rs` for the commands.
+    #[structopt(long)]
+    pub(crate) remote: Option<u16>,
+
+    /// Path to a second ROM (which can be the same file) that is run next to
+    /// the first one, with both Game Boys connected by a link cable. The
+    /// second one is controlled with the arrow keys, '.' (A), ',' (B), right
+    /// shift (Select) and enter (Start).
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["debug", "gdb"])]
+    pub(crate) link: Option<PathBuf>,
+
+    /// Play over the network with the given other side (`ip:port`). Both
+    /// sides run the same ROM on two Game Boys connected by a link cable, but
+    /// only show the one of the local player. Network latency is hidden by
+    /// predicting the input of the other side and correcting mispredictions
+    /// afterwards (rollback).
+    #[structopt(long, conflicts_with_all = &["debug", "gdb", "link"])]
+    pub(crate) netplay: Option<String>,
+
+    /// The local UDP port used for `--netplay`.
+    #[structopt(long, default_value = "7777", requires = "netplay")]
+    pub(crate) netplay_port: u16,
+
+    /// Which Game Boy the local player controls with `--netplay`: '1' or
+    /// '2'. The other side has to use the other one.
+    #[structopt(
+        long,
+        default_value = "1",
+        requires = "netplay",
+        parse(try_from_str = parse_netplay_player),
+    )]
+    pub(crate) netplay_player: u8,
+
+    /// Start a GDB server (remote serial protocol) listening on the given TCP
+    /// port on localhost. You can then attach with `target remote :<port>`.
+    /// As soon as a debugger connects, execution is paused. Cannot be
+    /// combined with `--debug`.
+    #[structopt(long, conflicts_with = "debug")]
+    pub(crate) gdb: Option<u16>,
 }
 
 fn parse_log_level(src: &str) -> Result<LevelFilter, &'static str> {
@@ -124,6 +348,45 @@ fn parse_bios_kind(src: &str) -> Result<BiosKind, &'static str> {
     }
 }
 
+fn parse_model(src: &str) -> Result<HardwareModel, &'static str> {
+    match src {
+        "dmg0" => Ok(HardwareModel::Dmg0),
+        "dmg" => Ok(HardwareModel::Dmg),
+        "mgb" => Ok(HardwareModel::Mgb),
+        "sgb" => Ok(HardwareModel::Sgb),
+        "cgb-dmg" => Ok(HardwareModel::CgbDmgMode),
+        "cgb" => Ok(HardwareModel::Cgb),
+        _ => Err(
+            "invalid model (valid values: 'dmg0', 'dmg', 'mgb', 'sgb', 'cgb-dmg' and 'cgb')"
+        ),
+    }
+}
+
+fn parse_input_polling(src: &str) -> Result<InputPolling, &'static str> {
+    match src {
+        "instruction" => Ok(InputPolling::PerInstruction),
+        "scanline" => Ok(InputPolling::PerScanline),
+        "frame" => Ok(InputPolling::PerFrame),
+        _ => Err("invalid input polling (valid values: 'instruction', 'scanline' and 'frame')"),
+    }
+}
+
+fn parse_trace_format(src: &str) -> Result<TraceFormat, &'static str> {
+    match src {
+        "text" => Ok(TraceFormat::Text),
+        "binary" => Ok(TraceFormat::Binary),
+        _ => Err("invalid trace format (valid values: 'text' and 'binary')"),
+    }
+}
+
+fn parse_netplay_player(src: &str) -> Result<u8, &'static str> {
+    match src {
+        "1" => Ok(1),
+        "2" => Ok(2),
+        _ => Err("invalid player (valid values: '1' and '2')"),
+    }
+}
+
 fn check_scale(src: String) -> Result<(), String> {
     match src.parse::<u8>() {
         Err(e) => Err(format!("failed to parse '{}' as `u8`: {}", src, e)),
@@ -0,0 +1,131 @@
+//! Battery backed cartridge RAM ("battery saves").
+//!
+//! The RAM of cartridges with a battery is stored per ROM in the config
+//! directory (e.g. `~/.config/mahboi/saves/<rom>.sav`) and loaded at
+//! startup. It is not only written on exit: while the game runs, the RAM is
+//! checked for changes every `FLUSH_INTERVAL` and written if it changed, so
+//! that at most a few seconds of progress are lost when the emulator crashes
+//! or is killed. Each write goes to a temporary file first, which then
+//! replaces the old file, so the file is never left half written.
+
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use mahboi::{
+    log::*,
+    machine::Machine,
+    primitives::Byte,
+};
+use crate::input_macro::config_dir;
+
+
+/// How often the RAM is checked for changes.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+
+pub(crate) struct BatterySave {
+    path: PathBuf,
+
+    /// The RAM as it was last written to (or read from) the file.
+    saved: Vec<Byte>,
+
+    last_check: Instant,
+}
+
+impl BatterySave {
+    /// Loads the save of the ROM into the cartridge RAM of `machine`, if
+    /// there is one. Returns `None` if the cartridge has no battery or there
+    /// is no config directory.
+    pub(crate) fn for_rom(rom: &Path, machine: &mut Machine) -> Option<Self> {
+        let cartridge = &mut machine.cartridge;
+        if !cartridge.cartridge_type().has_battery() || cartridge.mbc().ram().is_empty() {
+            return None;
+        }
+
+        let name = rom.file_stem()?;
+        let path = config_dir()?.join("mahboi").join("saves").join(name).with_extension("sav");
+        match fs::read(&path) {
+            Ok(data) => {
+                let ram = cartridge.ram_mut();
+                if data.len() != ram.len() {
+                    warn!(
+                        "[desktop] save '{}' has {} bytes, but the cartridge RAM has {}",
+                        path.display(),
+                        data.len(),
+                        ram.len(),
+                    );
+                }
+                for (dst, &src) in ram.iter_mut().zip(&data) {
+                    *dst = Byte::new(src);
+                }
+                info!("[desktop] loaded save '{}'", path.display());
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!("[desktop] failed to read save '{}': {}", path.display(), e),
+        }
+
+        Some(Self {
+            path,
+            saved: cartridge.mbc().ram().to_vec(),
+            last_check: Instant::now(),
+        })
+    }
+
+    /// Has to be called regularly. Writes the RAM to the file if it changed
+    /// and the last check is at least `FLUSH_INTERVAL` ago.
+    pub(crate) fn update(&mut self, machine: &Machine) {
+        if self.last_check.elapsed() >= FLUSH_INTERVAL {
+            self.flush(machine);
+        }
+    }
+
+    /// Writes the RAM to the file if it changed since the last write.
+    pub(crate) fn flush(&mut self, machine: &Machine) {
+        self.last_check = Instant::now();
+        let ram = machine.cartridge.mbc().ram();
+        if ram == &self.saved[..] {
+            return;
+        }
+
+        let data = ram.iter().map(|b| b.get()).collect::<Vec<_>>();
+        match write_atomically(&self.path, &data) {
+            Ok(()) => {
+                debug!("[desktop] wrote save '{}'", self.path.display());
+                self.saved = ram.to_vec();
+            }
+            Err(e) => warn!("[desktop] failed to write save '{}': {}", self.path.display(), e),
+        }
+    }
+}
+
+/// Writes `data` to a temporary file next to `path` and renames it to `path`
+/// afterwards. Renaming replaces the old file in one step, so `path`
+/// contains either the old or the new data, even if the process is killed.
+fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    fs::create_dir_all(path.parent().unwrap())?;
+    let tmp = path.with_extension("sav.tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_atomically() {
+        let dir = std::env::temp_dir().join(format!("mahboi-battery-{}", std::process::id()));
+        let path = dir.join("game.sav");
+        write_atomically(&path, &[1, 2, 3]).unwrap();
+        write_atomically(&path, &[4, 5]).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), [4, 5]);
+        assert!(!path.with_extension("sav.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
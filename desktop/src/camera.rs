@@ -0,0 +1,88 @@
+//! Image source for cartridges with a camera (see `Peripherals::capture_image`).
+//! Currently, only a static PNG image is supported.
+
+use std::{fs::File, path::Path};
+
+use failure::{bail, Error, ResultExt};
+use png::{ColorType, Decoder, Transformations};
+
+use mahboi::env::GreyFrame;
+
+
+/// Loads the PNG file at `path` and converts it to greyscale.
+pub(crate) fn load_png(path: &Path) -> Result<GreyFrame, Error> {
+    let file = File::open(path)
+        .context(format!("failed to open camera image '{}'", path.display()))?;
+    let mut decoder = Decoder::new(file);
+    decoder.set_transformations(Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().context("failed to decode camera image")?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).context("failed to decode camera image")?;
+
+    let channels = match info.color_type {
+        ColorType::Grayscale => 1,
+        ColorType::GrayscaleAlpha => 2,
+        ColorType::Rgb => 3,
+        ColorType::Rgba => 4,
+        ColorType::Indexed => bail!("indexed camera image was not expanded"),
+    };
+
+    // The alpha channel is ignored.
+    let pixels = buf[..info.buffer_size()]
+        .chunks(channels)
+        .map(|px| match channels {
+            1 | 2 => px[0],
+            _ => ((px[0] as u32 * 299 + px[1] as u32 * 587 + px[2] as u32 * 114) / 1000) as u8,
+        })
+        .collect();
+
+    Ok(GreyFrame {
+        width: info.width as usize,
+        height: info.height as usize,
+        pixels,
+    })
+}
+
+/// Scales `image` to `width` x `height` pixels. The image is cropped at the
+/// sides or at the top and bottom to keep its aspect ratio.
+pub(crate) fn scale(image: &GreyFrame, width: usize, height: usize) -> GreyFrame {
+    let (src_width, src_height) = if image.width * height > width * image.height {
+        (image.height * width / height, image.height)
+    } else {
+        (image.width, image.width * height / width)
+    };
+    let left = (image.width - src_width) / 2;
+    let top = (image.height - src_height) / 2;
+
+    let pixels = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let src_x = left + x * src_width / width;
+            let src_y = top + y * src_height / height;
+            image.pixels[src_y * image.width + src_x]
+        })
+        .collect();
+
+    GreyFrame { width, height, pixels }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scale() {
+        // A 4x2 image with a wider aspect ratio loses its outer columns.
+        let image = GreyFrame {
+            width: 4,
+            height: 2,
+            pixels: vec![0, 1, 2, 3, 4, 5, 6, 7],
+        };
+        let scaled = scale(&image, 4, 4);
+        assert_eq!(scaled.pixels, [1, 1, 2, 2, 1, 1, 2, 2, 5, 5, 6, 6, 5, 5, 6, 6]);
+
+        let same = scale(&image, 4, 2);
+        assert_eq!(same, image);
+    }
+}
@@ -0,0 +1,159 @@
+//! Writing a diagnostic dump when the emulator panics.
+//!
+//! The dump is a text file with the registers, the last executed
+//! instructions (see `--trace-len`), the IO registers, the mapped banks and
+//! hex dumps of WRAM and HRAM. Next to it, a save state of the machine is
+//! written, which can be loaded with `--load-state` to reproduce the crash.
+//! Dumps are stored in the config directory (e.g. `~/.config/mahboi/crashes`)
+//! or in the temporary directory if there is none.
+
+use std::{
+    env,
+    fmt::Write as _,
+    fs,
+    io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use mahboi::{
+    instr,
+    machine::Machine,
+    primitives::{Byte, Word},
+    save_state::Compression,
+};
+use crate::input_macro::config_dir;
+
+
+/// Writes a dump of `machine` after the panic with the given message and
+/// returns the path of the dump.
+pub(crate) fn write(rom: &Path, machine: &Machine, message: &str) -> io::Result<PathBuf> {
+    let dir = config_dir()
+        .map(|dir| dir.join("mahboi").join("crashes"))
+        .unwrap_or_else(env::temp_dir);
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let stem = rom.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+    let path = dir.join(format!("{}-{}.txt", stem, timestamp));
+
+    fs::create_dir_all(&dir)?;
+    fs::write(&path, format_dump(rom, machine, message))?;
+    fs::write(path.with_extension("state"), machine.save_state(Compression::Zstd))?;
+
+    Ok(path)
+}
+
+fn format_dump(rom: &Path, machine: &Machine, message: &str) -> String {
+    let mut out = String::new();
+
+    // Writing into a `String` never fails.
+    let _ = writeln!(out, "mahboi {} crash dump", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(out);
+    let _ = writeln!(out, "Panic:         {}", message);
+    let _ = writeln!(out, "ROM:           {} ('{}')", rom.display(), machine.cartridge.title());
+    let _ = writeln!(out, "Model:         {:?}", machine.model());
+    let _ = writeln!(out, "Steps:         {}", machine.step_count());
+    let _ = writeln!(out, "Cycles:        {}", machine.cycle_count());
+    let _ = writeln!(out, "BIOS mounted:  {}", machine.bios_mounted());
+    let _ = writeln!(out, "ROM bank:      {}", machine.cartridge.rom_bank());
+    let _ = writeln!(out, "RAM bank:      {}", machine.cartridge.ram_bank());
+
+    let cpu = &machine.cpu;
+    let interrupts = machine.interrupt_controller();
+    section(&mut out, "Registers");
+    let _ = writeln!(
+        out,
+        "A:{} F:{} B:{} C:{} D:{} E:{} H:{} L:{} SP:{} PC:{}",
+        cpu.a, cpu.f, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l, cpu.sp, cpu.pc,
+    );
+    let _ = writeln!(
+        out,
+        "IME:{} IE:{} IF:{}",
+        interrupts.ime as u8,
+        interrupts.interrupt_enable,
+        load(machine, 0xFF0F),
+    );
+
+    section(&mut out, "Last executed instructions (oldest first)");
+    if machine.trace().is_empty() {
+        let _ = writeln!(out, "none recorded (see `--trace-len`)");
+    }
+    for entry in machine.trace() {
+        let instr = match instr::decode(entry.pc, &entry.bytes) {
+            Some(instr) => instr.to_string(),
+            None => entry.bytes[0].to_string(),
+        };
+        let cpu = &entry.cpu;
+        let _ = writeln!(
+            out,
+            "{}  {:20} A:{} F:{} B:{} C:{} D:{} E:{} H:{} L:{} SP:{}",
+            entry.pc, instr, cpu.a, cpu.f, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l, cpu.sp,
+        );
+    }
+
+    section(&mut out, "IO registers");
+    hex_dump(&mut out, 0xFF00, &(0xFF00..0xFF80).map(|a| load(machine, a)).collect::<Vec<_>>());
+
+    section(&mut out, "HRAM");
+    hex_dump(&mut out, 0xFF80, machine.hram.as_slice());
+
+    section(&mut out, "WRAM");
+    hex_dump(&mut out, 0xC000, machine.wram.as_slice());
+
+    out
+}
+
+/// Loads a byte without side effects on the memory hooks.
+fn load(machine: &Machine, addr: u16) -> Byte {
+    machine.load_byte_bypass_dma(Word::new(addr))
+}
+
+fn section(out: &mut String, title: &str) {
+    let _ = writeln!(out);
+    let _ = writeln!(out, "{}", title);
+    let _ = writeln!(out, "{}", "-".repeat(title.len()));
+}
+
+/// Writes 16 bytes per line, each line starting with the address.
+fn hex_dump(out: &mut String, start: u16, bytes: &[Byte]) {
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let _ = write!(out, "{:04x}:", start as usize + i * 16);
+        for b in line {
+            let _ = write!(out, " {:02x}", b.get());
+        }
+        let _ = writeln!(out);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use mahboi::{
+        BiosKind, Emulator, HardwareModel,
+        cartridge::Cartridge,
+        test_util::NullPeripherals,
+    };
+    use super::*;
+
+    #[test]
+    fn test_format_dump() {
+        let cartridge = Cartridge::from_bytes(&[0; 0x8000]).unwrap();
+        let mut emulator = Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        emulator.machine_mut().set_trace_capacity(4);
+        emulator.machine_mut().store_byte(Word::new(0xC010), Byte::new(0xAB));
+        let mut steps = 0;
+        let _ = emulator.execute_frame(&mut NullPeripherals, |_| {
+            steps += 1;
+            steps > 10
+        });
+
+        let machine = emulator.machine();
+        let dump = format_dump(Path::new("test.gb"), machine, "oh no");
+        assert!(dump.contains("Panic:         oh no"));
+        assert!(dump.contains(&format!("PC:{}", machine.cpu.pc)));
+        assert!(dump.contains("c010: ab 00"));
+        assert!(dump.contains("ff80:"));
+
+        let trace = dump.split("(oldest first)").nth(1).unwrap();
+        assert_eq!(trace.lines().filter(|l| l.starts_with("0x")).count(), 4);
+    }
+}
@@ -0,0 +1,224 @@
+//! Translates winit events into the input of egui.
+
+use egui::{Event, Key, Modifiers, PointerButton, Pos2, RawInput, Rect, Vec2};
+use winit::{
+    dpi::PhysicalSize,
+    event::{
+        ElementState, ModifiersState, MouseButton, MouseScrollDelta, VirtualKeyCode, WindowEvent,
+    },
+};
+
+
+/// How many points one line of a mouse wheel scrolls.
+const POINTS_PER_LINE: f32 = 50.0;
+
+/// The input events since the last frame of the debugger window.
+pub(super) struct Input {
+    events: Vec<Event>,
+    modifiers: Modifiers,
+
+    /// The last position of the mouse in points.
+    pointer: Pos2,
+}
+
+impl Input {
+    pub(super) fn new() -> Self {
+        Self {
+            events: Vec::new(),
+            modifiers: Modifiers::default(),
+            pointer: Pos2::ZERO,
+        }
+    }
+
+    /// Records `event`. `scale_factor` is the number of physical pixels per
+    /// point.
+    pub(super) fn handle_event(&mut self, event: &WindowEvent<'_>, scale_factor: f64) {
+        match event {
+            WindowEvent::CursorMoved { position, .. } => {
+                let position = position.to_logical::<f32>(scale_factor);
+                self.pointer = Pos2::new(position.x, position.y);
+                self.events.push(Event::PointerMoved(self.pointer));
+            }
+            WindowEvent::CursorLeft { .. } => self.events.push(Event::PointerGone),
+            WindowEvent::MouseInput { state, button, .. } => {
+                let button = match button {
+                    MouseButton::Left => PointerButton::Primary,
+                    MouseButton::Right => PointerButton::Secondary,
+                    MouseButton::Middle => PointerButton::Middle,
+                    MouseButton::Other(_) => return,
+                };
+                self.events.push(Event::PointerButton {
+                    pos: self.pointer,
+                    button,
+                    pressed: *state == ElementState::Pressed,
+                    modifiers: self.modifiers,
+                });
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                let delta = match *delta {
+                    MouseScrollDelta::LineDelta(x, y) => Vec2::new(x, y) * POINTS_PER_LINE,
+                    MouseScrollDelta::PixelDelta(position) => {
+                        let position = position.to_logical::<f32>(scale_factor);
+                        Vec2::new(position.x, position.y)
+                    }
+                };
+                self.events.push(Event::Scroll(delta));
+            }
+            // Shortcuts and keys like backspace are handled as `Key` events.
+            WindowEvent::ReceivedCharacter(c)
+                if !c.is_control() && !self.modifiers.ctrl && !self.modifiers.mac_cmd =>
+            {
+                self.events.push(Event::Text(c.to_string()));
+            }
+            WindowEvent::KeyboardInput { input, .. } => {
+                if let Some(key) = input.virtual_keycode.and_then(key) {
+                    self.events.push(Event::Key {
+                        key,
+                        pressed: input.state == ElementState::Pressed,
+                        modifiers: self.modifiers,
+                    });
+                }
+            }
+            WindowEvent::ModifiersChanged(state) => self.modifiers = modifiers(*state),
+            _ => {}
+        }
+    }
+
+    /// Returns the input for the next frame of a window of the given size in
+    /// physical pixels.
+    pub(super) fn take(&mut self, size: PhysicalSize<u32>, scale_factor: f64) -> RawInput {
+        let size = size.to_logical::<f32>(scale_factor);
+        RawInput {
+            screen_rect: Some(Rect::from_min_size(Pos2::ZERO, Vec2::new(size.width, size.height))),
+            pixels_per_point: Some(scale_factor as f32),
+            modifiers: self.modifiers,
+            events: std::mem::take(&mut self.events),
+            ..RawInput::default()
+        }
+    }
+}
+
+fn modifiers(state: ModifiersState) -> Modifiers {
+    Modifiers {
+        alt: state.alt(),
+        ctrl: state.ctrl(),
+        shift: state.shift(),
+        mac_cmd: cfg!(target_os = "macos") && state.logo(),
+        command: if cfg!(target_os = "macos") { state.logo() } else { state.ctrl() },
+    }
+}
+
+/// Returns the egui key of `key` or `None` if egui doesn't know this key.
+fn key(key: VirtualKeyCode) -> Option<Key> {
+    use VirtualKeyCode as V;
+
+    let key = match key {
+        V::Down => Key::ArrowDown,
+        V::Left => Key::ArrowLeft,
+        V::Right => Key::ArrowRight,
+        V::Up => Key::ArrowUp,
+        V::Escape => Key::Escape,
+        V::Tab => Key::Tab,
+        V::Back => Key::Backspace,
+        V::Return | V::NumpadEnter => Key::Enter,
+        V::Space => Key::Space,
+        V::Insert => Key::Insert,
+        V::Delete => Key::Delete,
+        V::Home => Key::Home,
+        V::End => Key::End,
+        V::PageUp => Key::PageUp,
+        V::PageDown => Key::PageDown,
+        V::Key0 | V::Numpad0 => Key::Num0,
+        V::Key1 | V::Numpad1 => Key::Num1,
+        V::Key2 | V::Numpad2 => Key::Num2,
+        V::Key3 | V::Numpad3 => Key::Num3,
+        V::Key4 | V::Numpad4 => Key::Num4,
+        V::Key5 | V::Numpad5 => Key::Num5,
+        V::Key6 | V::Numpad6 => Key::Num6,
+        V::Key7 | V::Numpad7 => Key::Num7,
+        V::Key8 | V::Numpad8 => Key::Num8,
+        V::Key9 | V::Numpad9 => Key::Num9,
+        V::A => Key::A,
+        V::B => Key::B,
+        V::C => Key::C,
+        V::D => Key::D,
+        V::E => Key::E,
+        V::F => Key::F,
+        V::G => Key::G,
+        V::H => Key::H,
+        V::I => Key::I,
+        V::J => Key::J,
+        V::K => Key::K,
+        V::L => Key::L,
+        V::M => Key::M,
+        V::N => Key::N,
+        V::O => Key::O,
+        V::P => Key::P,
+        V::Q => Key::Q,
+        V::R => Key::R,
+        V::S => Key::S,
+        V::T => Key::T,
+        V::U => Key::U,
+        V::V => Key::V,
+        V::W => Key::W,
+        V::X => Key::X,
+        V::Y => Key::Y,
+        V::Z => Key::Z,
+        _ => return None,
+    };
+
+    Some(key)
+}
+
+
+#[cfg(test)]
+mod test {
+    use winit::{
+        dpi::PhysicalPosition,
+        event::{DeviceId, TouchPhase},
+    };
+    use super::*;
+
+    #[test]
+    fn test_pointer_in_points() {
+        let mut input = Input::new();
+        let device_id = unsafe { DeviceId::dummy() };
+        #[allow(deprecated)]
+        input.handle_event(&WindowEvent::CursorMoved {
+            device_id,
+            position: PhysicalPosition::new(40.0, 100.0),
+            modifiers: ModifiersState::empty(),
+        }, 2.0);
+        #[allow(deprecated)]
+        input.handle_event(&WindowEvent::MouseWheel {
+            device_id,
+            delta: MouseScrollDelta::LineDelta(0.0, -1.0),
+            phase: TouchPhase::Moved,
+            modifiers: ModifiersState::empty(),
+        }, 2.0);
+
+        let raw = input.take(PhysicalSize::new(800, 600), 2.0);
+        assert_eq!(raw.screen_rect, Some(Rect::from_min_max(Pos2::ZERO, Pos2::new(400.0, 300.0))));
+        assert_eq!(raw.pixels_per_point, Some(2.0));
+        assert_eq!(raw.events, vec![
+            Event::PointerMoved(Pos2::new(20.0, 50.0)),
+            Event::Scroll(Vec2::new(0.0, -POINTS_PER_LINE)),
+        ]);
+        assert!(input.take(PhysicalSize::new(800, 600), 2.0).events.is_empty());
+    }
+
+    #[test]
+    fn test_text_and_keys() {
+        let mut input = Input::new();
+        input.handle_event(&WindowEvent::ReceivedCharacter('a'), 1.0);
+        input.handle_event(&WindowEvent::ReceivedCharacter('\u{8}'), 1.0);
+        input.handle_event(&WindowEvent::ModifiersChanged(ModifiersState::CTRL), 1.0);
+        input.handle_event(&WindowEvent::ReceivedCharacter('c'), 1.0);
+
+        let events = input.take(PhysicalSize::new(1, 1), 1.0).events;
+        assert_eq!(events, vec![Event::Text("a".into())]);
+        assert_eq!(key(VirtualKeyCode::Back), Some(Key::Backspace));
+        assert_eq!(key(VirtualKeyCode::Numpad7), Some(Key::Num7));
+        assert_eq!(key(VirtualKeyCode::F1), None);
+    }
+}
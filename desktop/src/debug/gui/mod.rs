@@ -0,0 +1,307 @@
+//! A graphical debugger (built with egui) in a second window.
+//!
+//! The machine lives in the emulation thread, but the window has to live in
+//! the window thread. So the debugger is split in two halves: `GuiDebugger`
+//! in the emulation thread decides when to pause and regularly sends a
+//! `Snapshot` of the machine to the window thread. There, `GuiWindow` shows
+//! the last snapshot and sends `Request`s for the user's actions back.
+
+use std::{
+    collections::BTreeSet,
+    time::{Duration, Instant},
+};
+
+use mahboi::{
+    log::*,
+    machine::{Machine, cpu::Cpu},
+    primitives::{Byte, Word},
+};
+use crate::gfx::{self, Image};
+use super::Action;
+
+pub(crate) use self::window::GuiWindow;
+
+
+mod input;
+mod views;
+mod window;
+
+
+/// The minimum time between two snapshots, unless the user did something.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_millis(16);
+
+/// Actions of the user in the debugger window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Request {
+    Pause,
+    Continue,
+
+    /// Execute one instruction and pause again.
+    Step,
+
+    AddBreakpoint(Word),
+    RemoveBreakpoint(Word),
+
+    /// The debugger window was closed: remove all breakpoints, continue and
+    /// stop sending snapshots.
+    Detach,
+}
+
+/// The state of the machine shown in the debugger window.
+#[derive(Debug)]
+pub(crate) struct Snapshot {
+    pub(crate) paused: bool,
+    pub(crate) cpu: Cpu,
+    pub(crate) ime: bool,
+    pub(crate) rom_bank: usize,
+    pub(crate) ram_bank: usize,
+    pub(crate) cycle_count: u64,
+    pub(crate) frame_count: u64,
+    pub(crate) breakpoints: Vec<Word>,
+
+    /// The whole address space as seen by `Machine::peek_byte`, including
+    /// the IO registers.
+    pub(crate) memory: Vec<Byte>,
+
+    /// All tiles in VRAM and the background and window map with the
+    /// viewport outlined (see `gfx`).
+    pub(crate) tiles: Image,
+    pub(crate) bg_map: Image,
+    pub(crate) window_map: Image,
+}
+
+impl Snapshot {
+    fn new(machine: &Machine, paused: bool, breakpoints: &BTreeSet<Word>) -> Self {
+        let mut memory = machine.peek_range(Word::new(0x0000), 0x8000);
+        memory.extend(machine.peek_range(Word::new(0x8000), 0x8000));
+        let (bg_map, window_map) = gfx::maps(machine, true);
+
+        Self {
+            paused,
+            cpu: machine.cpu,
+            ime: machine.interrupt_controller().ime,
+            rom_bank: machine.cartridge.rom_bank(),
+            ram_bank: machine.cartridge.ram_bank(),
+            cycle_count: machine.cycle_count(),
+            frame_count: machine.ppu.frame_count(),
+            breakpoints: breakpoints.iter().copied().collect(),
+            memory,
+            tiles: gfx::vram_tiles(machine),
+            bg_map,
+            window_map,
+        }
+    }
+
+    pub(crate) fn byte(&self, addr: Word) -> Byte {
+        self.memory[addr.get() as usize]
+    }
+}
+
+/// The part of the graphical debugger in the emulation thread.
+pub(crate) struct GuiDebugger {
+    requests: Vec<Request>,
+    breakpoints: BTreeSet<Word>,
+
+    /// When continuing from a breakpoint, we would pause right away again.
+    /// So the emulator doesn't pause at this address once.
+    step_over: Option<Word>,
+
+    /// Pause before the next instruction (after `step_over`).
+    stepping: bool,
+
+    /// `false` once the window was closed.
+    attached: bool,
+
+    /// Whether requests were handled since the last snapshot.
+    changed: bool,
+
+    /// The time of the last snapshot and whether the emulation was paused
+    /// then.
+    last_snapshot: Option<(Instant, bool)>,
+}
+
+impl GuiDebugger {
+    pub(crate) fn new() -> Self {
+        Self {
+            requests: Vec::new(),
+            breakpoints: BTreeSet::new(),
+            step_over: None,
+            stepping: false,
+            attached: true,
+            changed: false,
+            last_snapshot: None,
+        }
+    }
+
+    /// Queues a request from the window. It is handled in the next `update`.
+    pub(crate) fn request(&mut self, request: Request) {
+        self.requests.push(request);
+    }
+
+    /// Handles the requests of the window.
+    pub(crate) fn update(&mut self, is_paused: bool, machine: &Machine) -> Action {
+        let pc = machine.cpu.pc;
+        let mut action = Action::Nothing;
+        let mut paused = is_paused;
+        self.changed |= !self.requests.is_empty();
+        for request in self.requests.drain(..) {
+            match request {
+                Request::Pause if !paused => {
+                    action = Action::Pause;
+                    paused = true;
+                }
+                Request::Continue if paused => {
+                    self.step_over = Some(pc);
+                    action = Action::Continue;
+                    paused = false;
+                }
+                Request::Step if paused => {
+                    self.step_over = Some(pc);
+                    self.stepping = true;
+                    action = Action::Continue;
+                }
+                Request::AddBreakpoint(addr) => {
+                    self.breakpoints.insert(addr);
+                }
+                Request::RemoveBreakpoint(addr) => {
+                    self.breakpoints.remove(&addr);
+                }
+                Request::Detach => {
+                    debug!("[debugger] window closed");
+                    self.attached = false;
+                    self.breakpoints.clear();
+                    if paused {
+                        self.step_over = Some(pc);
+                        action = Action::Continue;
+                        paused = false;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        action
+    }
+
+    /// Returns a new snapshot for the window if the machine changed since
+    /// the last one. While running, this happens at most every
+    /// `SNAPSHOT_INTERVAL`. While paused, only requests change the machine.
+    pub(crate) fn snapshot(&mut self, is_paused: bool, machine: &Machine) -> Option<Box<Snapshot>> {
+        let changed = match self.last_snapshot {
+            None => true,
+            Some((time, was_paused)) => {
+                self.changed
+                    || was_paused != is_paused
+                    || (!is_paused && time.elapsed() >= SNAPSHOT_INTERVAL)
+            }
+        };
+        if !self.attached || !changed {
+            return None;
+        }
+
+        self.changed = false;
+        self.last_snapshot = Some((Instant::now(), is_paused));
+        Some(Box::new(Snapshot::new(machine, is_paused, &self.breakpoints)))
+    }
+
+    pub(crate) fn should_pause(&mut self, machine: &Machine) -> bool {
+        if let Some(addr) = self.step_over {
+            if addr == machine.cpu.pc {
+                self.step_over = None;
+                return false;
+            }
+        }
+
+        if self.stepping {
+            self.stepping = false;
+            return true;
+        }
+
+        if self.breakpoints.contains(&machine.cpu.pc) {
+            debug!("[debugger] paused at breakpoint {}", machine.cpu.pc);
+            return true;
+        }
+
+        false
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use mahboi::{
+        BiosKind, Disruption, Emulator, HardwareModel,
+        test_util::{NullPeripherals, cartridge_with_code},
+    };
+    use super::*;
+
+    fn run(emulator: &mut Emulator, gui: &mut GuiDebugger) -> Result<(), Disruption> {
+        emulator.execute_frame(&mut NullPeripherals, |machine| gui.should_pause(machine))
+    }
+
+    #[test]
+    fn test_breakpoints_and_stepping() {
+        // 0150: NOP; NOP; NOP; JR -2 (to itself)
+        let cartridge = cartridge_with_code(&[0x00, 0x00, 0x00, 0x18, 0xFE]);
+        let mut emulator = Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        emulator.machine_mut().cpu.pc = Word::new(0x150);
+        let mut gui = GuiDebugger::new();
+
+        gui.request(Request::AddBreakpoint(Word::new(0x152)));
+        assert!(matches!(gui.update(false, emulator.machine()), Action::Nothing));
+        assert!(matches!(run(&mut emulator, &mut gui), Err(Disruption::Paused)));
+        assert_eq!(emulator.machine().cpu.pc, Word::new(0x152));
+
+        // A step executes the instruction at the breakpoint.
+        gui.request(Request::Step);
+        assert!(matches!(gui.update(true, emulator.machine()), Action::Continue));
+        assert!(matches!(run(&mut emulator, &mut gui), Err(Disruption::Paused)));
+        assert_eq!(emulator.machine().cpu.pc, Word::new(0x153));
+
+        // Continuing doesn't stop at the current breakpoint right away, but
+        // when it's reached again.
+        gui.request(Request::AddBreakpoint(Word::new(0x153)));
+        gui.request(Request::Continue);
+        assert!(matches!(gui.update(true, emulator.machine()), Action::Continue));
+        let cycles = emulator.machine().cycle_count();
+        assert!(matches!(run(&mut emulator, &mut gui), Err(Disruption::Paused)));
+        assert_eq!(emulator.machine().cpu.pc, Word::new(0x153));
+        assert!(emulator.machine().cycle_count() > cycles);
+
+        // Requests that don't fit the state are ignored.
+        gui.request(Request::Pause);
+        assert!(matches!(gui.update(true, emulator.machine()), Action::Nothing));
+
+        // Closing the window removes all breakpoints.
+        gui.request(Request::Detach);
+        assert!(matches!(gui.update(true, emulator.machine()), Action::Continue));
+        assert!(run(&mut emulator, &mut gui).is_ok());
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let cartridge = cartridge_with_code(&[0x18, 0xFE]);
+        let emulator = Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        let machine = emulator.machine();
+        let mut gui = GuiDebugger::new();
+
+        let snapshot = gui.snapshot(true, machine).unwrap();
+        assert!(snapshot.paused);
+        assert_eq!(snapshot.memory.len(), 0x10000);
+        assert_eq!(snapshot.byte(Word::new(0x0150)), Byte::new(0x18));
+
+        // While paused, only requests change the machine.
+        assert!(gui.snapshot(true, machine).is_none());
+        gui.request(Request::AddBreakpoint(Word::new(0x0150)));
+        assert!(matches!(gui.update(true, machine), Action::Nothing));
+        let snapshot = gui.snapshot(true, machine).unwrap();
+        assert_eq!(snapshot.breakpoints, vec![Word::new(0x0150)]);
+
+        // Pausing and continuing are sent right away.
+        assert!(!gui.snapshot(false, machine).unwrap().paused);
+
+        gui.request(Request::Detach);
+        let _ = gui.update(false, machine);
+        assert!(gui.snapshot(false, machine).is_none());
+    }
+}
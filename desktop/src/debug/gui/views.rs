@@ -0,0 +1,404 @@
+//! The panels of the debugger window.
+
+use egui::{
+    Color32, ColorImage, ComboBox, Context, Grid, Key, RichText, ScrollArea, TextStyle,
+    TextureHandle, Ui,
+};
+
+use mahboi::{
+    instr,
+    primitives::{Byte, Word},
+};
+use crate::gfx::{Image, Palette};
+use super::{Request, Snapshot};
+
+
+/// The number of instructions shown in the disassembly.
+const DISASM_LINES: usize = 64;
+
+/// The color of the line of the instruction at PC.
+const PC_COLOR: Color32 = Color32::from_rgb(0xF0, 0xC0, 0x40);
+const BREAKPOINT_COLOR: Color32 = Color32::from_rgb(0xF0, 0x40, 0x40);
+
+/// The VRAM images are shown at this integer scale.
+const VRAM_SCALE: f32 = 2.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tab {
+    Disassembly,
+    Memory,
+    Vram,
+}
+
+/// The VRAM images as egui textures.
+struct VramTextures {
+    tiles: TextureHandle,
+    bg_map: TextureHandle,
+    window_map: TextureHandle,
+}
+
+/// The state of the user interface, which is kept across snapshots.
+pub(super) struct Views {
+    tab: Tab,
+    breakpoint_input: String,
+
+    /// The disassembly starts at this address instead of PC.
+    disasm_start: Option<Word>,
+    disasm_input: String,
+
+    /// The memory view scrolls to this address in the next frame.
+    memory_scroll_to: Option<Word>,
+    memory_input: String,
+
+    palette: Palette,
+    vram: Option<VramTextures>,
+
+    /// Whether the VRAM textures are older than the snapshot.
+    vram_outdated: bool,
+}
+
+impl Views {
+    pub(super) fn new() -> Self {
+        Self {
+            tab: Tab::Disassembly,
+            breakpoint_input: String::new(),
+            disasm_start: None,
+            disasm_input: String::new(),
+            memory_scroll_to: None,
+            memory_input: String::new(),
+            palette: Palette::Bg,
+            vram: None,
+            vram_outdated: true,
+        }
+    }
+
+    /// Has to be called when a new snapshot arrived.
+    pub(super) fn snapshot_changed(&mut self) {
+        self.vram_outdated = true;
+    }
+
+    /// Draws the whole window. The actions of the user are added to
+    /// `requests`.
+    pub(super) fn show(
+        &mut self,
+        ctx: &Context,
+        snapshot: Option<&Snapshot>,
+        requests: &mut Vec<Request>,
+    ) {
+        let snapshot = match snapshot {
+            Some(snapshot) => snapshot,
+            None => {
+                egui::CentralPanel::default().show(ctx, |ui| {
+                    ui.label("Waiting for the emulator...");
+                });
+                return;
+            }
+        };
+
+        egui::TopBottomPanel::top("controls").show(ctx, |ui| {
+            ui.horizontal(|ui| controls(ui, snapshot, requests));
+        });
+        egui::SidePanel::left("state").resizable(false).show(ctx, |ui| {
+            registers(ui, snapshot);
+            ui.separator();
+            self.breakpoints(ui, snapshot, requests);
+        });
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.selectable_value(&mut self.tab, Tab::Disassembly, "Disassembly");
+                ui.selectable_value(&mut self.tab, Tab::Memory, "Memory");
+                ui.selectable_value(&mut self.tab, Tab::Vram, "VRAM");
+            });
+            ui.separator();
+
+            match self.tab {
+                Tab::Disassembly => self.disassembly(ui, snapshot, requests),
+                Tab::Memory => self.memory(ui, snapshot),
+                Tab::Vram => self.vram(ui, ctx, snapshot),
+            }
+        });
+    }
+
+    fn breakpoints(&mut self, ui: &mut Ui, snapshot: &Snapshot, requests: &mut Vec<Request>) {
+        ui.heading("Breakpoints");
+        ui.horizontal(|ui| {
+            let response = ui.add(
+                egui::TextEdit::singleline(&mut self.breakpoint_input)
+                    .desired_width(60.0)
+                    .hint_text("addr"),
+            );
+            let entered = response.lost_focus() && ui.input().key_pressed(Key::Enter);
+            if ui.button("Add").clicked() || entered {
+                if let Some(addr) = parse_addr(&self.breakpoint_input) {
+                    requests.push(Request::AddBreakpoint(addr));
+                    self.breakpoint_input.clear();
+                }
+            }
+        });
+
+        for &addr in &snapshot.breakpoints {
+            ui.horizontal(|ui| {
+                ui.monospace(addr.to_string());
+                if ui.small_button("Remove").clicked() {
+                    requests.push(Request::RemoveBreakpoint(addr));
+                }
+            });
+        }
+    }
+
+    /// Disassembles the memory linearly from `disasm_start` or PC. Clicking
+    /// an instruction toggles its breakpoint.
+    fn disassembly(&mut self, ui: &mut Ui, snapshot: &Snapshot, requests: &mut Vec<Request>) {
+        ui.horizontal(|ui| {
+            if let Some(addr) = go_to(ui, &mut self.disasm_input) {
+                self.disasm_start = Some(addr);
+            }
+            if ui.button("Follow PC").clicked() {
+                self.disasm_start = None;
+            }
+        });
+        ui.separator();
+
+        let mut addr = self.disasm_start.unwrap_or(snapshot.cpu.pc);
+        ScrollArea::vertical().show(ui, |ui| {
+            for _ in 0..DISASM_LINES {
+                let bytes = (0..3).map(|i| snapshot.byte(addr + i as u16)).collect::<Vec<_>>();
+                let (len, text) = match instr::decode(addr, &bytes) {
+                    Some(instr) => (instr.instr.len as usize, instr.to_string()),
+                    None => (1, format!("db {}", bytes[0])),
+                };
+                let raw = bytes[..len].iter()
+                    .map(|b| format!("{:02X}", b.get()))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+
+                let breakpoint = snapshot.breakpoints.contains(&addr);
+                let marker = match (addr == snapshot.cpu.pc, breakpoint) {
+                    (true, _) => "▶",
+                    (false, true) => "●",
+                    (false, false) => " ",
+                };
+                let line = format!("{} {}  {:<8}  {}", marker, addr, raw, text);
+                let mut line = RichText::new(line).monospace();
+                if addr == snapshot.cpu.pc {
+                    line = line.color(PC_COLOR);
+                } else if breakpoint {
+                    line = line.color(BREAKPOINT_COLOR);
+                }
+
+                if ui.selectable_label(false, line).clicked() {
+                    requests.push(if breakpoint {
+                        Request::RemoveBreakpoint(addr)
+                    } else {
+                        Request::AddBreakpoint(addr)
+                    });
+                }
+
+                // Don't wrap around at the end of the address space.
+                match addr.get().checked_add(len as u16) {
+                    Some(next) => addr = Word::new(next),
+                    None => break,
+                }
+            }
+        });
+    }
+
+    /// A hex dump of the whole address space.
+    fn memory(&mut self, ui: &mut Ui, snapshot: &Snapshot) {
+        if let Some(addr) = go_to(ui, &mut self.memory_input) {
+            self.memory_scroll_to = Some(addr);
+        }
+        ui.separator();
+
+        let row_height = ui.text_style_height(&TextStyle::Monospace);
+        let mut area = ScrollArea::vertical().auto_shrink([false; 2]);
+        if let Some(addr) = self.memory_scroll_to.take() {
+            let row = (addr.get() / 16) as f32;
+            area = area.vertical_scroll_offset(row * (row_height + ui.spacing().item_spacing.y));
+        }
+        area.show_rows(ui, row_height, 0x10000 / 16, |ui, rows| {
+            for row in rows {
+                let start = row * 16;
+                let bytes = &snapshot.memory[start..start + 16];
+                let hex = bytes.iter()
+                    .map(|b| format!("{:02X}", b.get()))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let ascii = bytes.iter()
+                    .map(|b| match b.get() {
+                        c @ 0x20..=0x7E => c as char,
+                        _ => '.',
+                    })
+                    .collect::<String>();
+                ui.monospace(format!("{}  {}  {}", Word::new(start as u16), hex, ascii));
+            }
+        });
+    }
+
+    /// The tiles in VRAM and both tile maps.
+    fn vram(&mut self, ui: &mut Ui, ctx: &Context, snapshot: &Snapshot) {
+        let palette = self.palette;
+        ComboBox::from_label("Tile palette")
+            .selected_text(palette_name(self.palette))
+            .show_ui(ui, |ui| {
+                for palette in [Palette::Bg, Palette::Obj0, Palette::Obj1] {
+                    ui.selectable_value(&mut self.palette, palette, palette_name(palette));
+                }
+            });
+        self.vram_outdated |= palette != self.palette;
+
+        // The textures are only updated while they are visible.
+        if self.vram_outdated || self.vram.is_none() {
+            let tiles_palette = snapshot.byte(self.palette.register());
+            let tiles = color_image(&snapshot.tiles, tiles_palette, self.palette.is_transparent());
+            let bgp = snapshot.byte(Palette::Bg.register());
+            let bg_map = color_image(&snapshot.bg_map, bgp, false);
+            let window_map = color_image(&snapshot.window_map, bgp, false);
+            match &mut self.vram {
+                Some(vram) => {
+                    vram.tiles.set(tiles);
+                    vram.bg_map.set(bg_map);
+                    vram.window_map.set(window_map);
+                }
+                None => {
+                    self.vram = Some(VramTextures {
+                        tiles: ctx.load_texture("vram_tiles", tiles),
+                        bg_map: ctx.load_texture("vram_bg_map", bg_map),
+                        window_map: ctx.load_texture("vram_window_map", window_map),
+                    });
+                }
+            }
+            self.vram_outdated = false;
+        }
+
+        let vram = self.vram.as_ref().unwrap();
+        ScrollArea::both().show(ui, |ui| {
+            ui.horizontal_top(|ui| {
+                for (title, texture) in [
+                    ("Tiles", &vram.tiles),
+                    ("Background map", &vram.bg_map),
+                    ("Window map", &vram.window_map),
+                ] {
+                    ui.vertical(|ui| {
+                        ui.label(title);
+                        ui.image(texture, texture.size_vec2() * VRAM_SCALE);
+                    });
+                }
+            });
+        });
+    }
+}
+
+/// The buttons to pause, continue and step, and the run state.
+fn controls(ui: &mut Ui, snapshot: &Snapshot, requests: &mut Vec<Request>) {
+    if snapshot.paused {
+        if ui.button("Continue").clicked() {
+            requests.push(Request::Continue);
+        }
+        if ui.button("Step").clicked() {
+            requests.push(Request::Step);
+        }
+        ui.label("Paused");
+    } else {
+        if ui.button("Pause").clicked() {
+            requests.push(Request::Pause);
+        }
+        ui.label("Running");
+    }
+}
+
+/// The CPU registers, the interrupt and LCD registers and the banks.
+fn registers(ui: &mut Ui, snapshot: &Snapshot) {
+    let cpu = &snapshot.cpu;
+    let io = |addr: u16| snapshot.byte(Word::new(addr)).to_string();
+    let flags = [
+        (cpu.zero(), 'Z'),
+        (cpu.subtract(), 'N'),
+        (cpu.half_carry(), 'H'),
+        (cpu.carry(), 'C'),
+    ];
+    let flags = flags.iter().map(|&(set, name)| if set { name } else { '-' }).collect::<String>();
+
+    ui.heading("CPU");
+    Grid::new("registers").num_columns(2).show(ui, |ui| {
+        let rows = [
+            ("AF", cpu.af().to_string()),
+            ("BC", cpu.bc().to_string()),
+            ("DE", cpu.de().to_string()),
+            ("HL", cpu.hl().to_string()),
+            ("SP", cpu.sp.to_string()),
+            ("PC", cpu.pc.to_string()),
+            ("Flags", flags),
+            ("IME", snapshot.ime.to_string()),
+            ("IE", io(0xFFFF)),
+            ("IF", io(0xFF0F)),
+            ("LCDC", io(0xFF40)),
+            ("STAT", io(0xFF41)),
+            ("LY", io(0xFF44)),
+            ("SCX", io(0xFF43)),
+            ("SCY", io(0xFF42)),
+            ("WX", io(0xFF4B)),
+            ("WY", io(0xFF4A)),
+            ("ROM bank", snapshot.rom_bank.to_string()),
+            ("RAM bank", snapshot.ram_bank.to_string()),
+            ("Cycle", snapshot.cycle_count.to_string()),
+            ("Frame", snapshot.frame_count.to_string()),
+        ];
+        for (name, value) in rows {
+            ui.label(name);
+            ui.monospace(value);
+            ui.end_row();
+        }
+    });
+}
+
+/// A text field for an address. Returns the address when the user pressed
+/// enter or the button.
+fn go_to(ui: &mut Ui, input: &mut String) -> Option<Word> {
+    ui.horizontal(|ui| {
+        let response = ui.add(
+            egui::TextEdit::singleline(input).desired_width(60.0).hint_text("addr"),
+        );
+        let entered = response.lost_focus() && ui.input().key_pressed(Key::Enter);
+        if ui.button("Go to").clicked() || entered {
+            parse_addr(input)
+        } else {
+            None
+        }
+    }).inner
+}
+
+/// Parses a hexadecimal address with an optional `$` or `0x` prefix.
+fn parse_addr(s: &str) -> Option<Word> {
+    let s = s.trim();
+    let digits = s.strip_prefix('$').or_else(|| s.strip_prefix("0x")).unwrap_or(s);
+    u16::from_str_radix(digits, 16).ok().map(Word::new)
+}
+
+fn palette_name(palette: Palette) -> &'static str {
+    match palette {
+        Palette::Bg => "BGP",
+        Palette::Obj0 => "OBP0",
+        Palette::Obj1 => "OBP1",
+    }
+}
+
+fn color_image(image: &Image, palette: Byte, transparent: bool) -> ColorImage {
+    let rgba = image.to_rgba(palette, transparent);
+    ColorImage::from_rgba_unmultiplied([image.width, image.height], &rgba)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_addr() {
+        assert_eq!(parse_addr("c000"), Some(Word::new(0xC000)));
+        assert_eq!(parse_addr(" $FF40 "), Some(Word::new(0xFF40)));
+        assert_eq!(parse_addr("0x150"), Some(Word::new(0x0150)));
+        assert_eq!(parse_addr("10000"), None);
+        assert_eq!(parse_addr("pc"), None);
+    }
+}
@@ -0,0 +1,127 @@
+//! The debugger window in the window thread.
+
+use egui_wgpu::renderer::{RenderPass, ScreenDescriptor};
+use failure::Error;
+use pixels::{Pixels, SurfaceTexture, wgpu};
+use winit::{
+    dpi::LogicalSize,
+    event::WindowEvent,
+    event_loop::EventLoop,
+    window::{Window, WindowBuilder, WindowId},
+};
+
+use crate::emulation::Update;
+use super::{
+    Request, Snapshot,
+    input::Input,
+    views::Views,
+};
+
+
+const WINDOW_TITLE: &str = "Mahboi Debugger";
+
+/// The part of the graphical debugger in the window thread.
+pub(crate) struct GuiWindow {
+    window: Window,
+
+    /// Only used for its wgpu device and surface. The pixel buffer is never
+    /// shown.
+    pixels: Pixels,
+    render_pass: RenderPass,
+    ctx: egui::Context,
+    input: Input,
+    views: Views,
+    snapshot: Option<Box<Snapshot>>,
+    scale_factor: f64,
+}
+
+impl GuiWindow {
+    pub(crate) fn new(event_loop: &EventLoop<Update>) -> Result<Self, Error> {
+        let window = WindowBuilder::new()
+            .with_title(WINDOW_TITLE)
+            .with_inner_size(LogicalSize::new(960.0, 640.0))
+            .build(event_loop)?;
+        let size = window.inner_size();
+        let pixels = Pixels::new(1, 1, SurfaceTexture::new(size.width, size.height, &window))?;
+        let render_pass = RenderPass::new(pixels.device(), pixels.render_texture_format(), 1);
+
+        Ok(Self {
+            scale_factor: window.scale_factor(),
+            window,
+            pixels,
+            render_pass,
+            ctx: egui::Context::default(),
+            input: Input::new(),
+            views: Views::new(),
+            snapshot: None,
+        })
+    }
+
+    pub(crate) fn id(&self) -> WindowId {
+        self.window.id()
+    }
+
+    /// Handles an event of this window. Returns `false` if the window was
+    /// closed.
+    pub(crate) fn handle_event(&mut self, event: &WindowEvent<'_>) -> bool {
+        match event {
+            WindowEvent::CloseRequested => return false,
+            WindowEvent::Resized(size) => self.pixels.resize_surface(size.width, size.height),
+            WindowEvent::ScaleFactorChanged { scale_factor, new_inner_size } => {
+                self.scale_factor = *scale_factor;
+                self.pixels.resize_surface(new_inner_size.width, new_inner_size.height);
+            }
+            _ => self.input.handle_event(event, self.scale_factor),
+        }
+
+        self.window.request_redraw();
+        true
+    }
+
+    /// Shows `snapshot` from now on.
+    pub(crate) fn set_snapshot(&mut self, snapshot: Box<Snapshot>) {
+        self.snapshot = Some(snapshot);
+        self.views.snapshot_changed();
+        self.window.request_redraw();
+    }
+
+    /// Draws the window and returns the actions of the user.
+    pub(crate) fn render(&mut self) -> Result<Vec<Request>, Error> {
+        // A minimized window has no surface to draw on.
+        let size = self.window.inner_size();
+        if size.width == 0 || size.height == 0 {
+            return Ok(Vec::new());
+        }
+
+        let raw_input = self.input.take(size, self.scale_factor);
+
+        let mut requests = Vec::new();
+        let (views, snapshot) = (&mut self.views, self.snapshot.as_deref());
+        let output = self.ctx.run(raw_input, |ctx| views.show(ctx, snapshot, &mut requests));
+        let primitives = self.ctx.tessellate(output.shapes);
+        let screen = ScreenDescriptor {
+            size_in_pixels: [size.width, size.height],
+            pixels_per_point: self.ctx.pixels_per_point(),
+        };
+
+        let render_pass = &mut self.render_pass;
+        let textures = &output.textures_delta;
+        self.pixels.render_with(|encoder, target, context| {
+            for (id, image_delta) in &textures.set {
+                render_pass.update_texture(&context.device, &context.queue, *id, image_delta);
+            }
+            render_pass.update_buffers(&context.device, &context.queue, &primitives, &screen);
+            render_pass.execute(encoder, target, &primitives, &screen, Some(wgpu::Color::BLACK));
+            Ok(())
+        })?;
+        for id in &textures.free {
+            self.render_pass.free_texture(id);
+        }
+
+        if output.needs_repaint {
+            self.window.request_redraw();
+        }
+
+        Ok(requests)
+    }
+}
@@ -1,9 +1,12 @@
 use crate::args::Args;
 
-pub(crate) use self::tui::TuiDebugger;
+pub(crate) use self::{
+    gui::{GuiDebugger, GuiWindow, Request, Snapshot},
+    tui::TuiDebugger,
+};
 
 
-#[cfg_attr(windows, path = "dummy_tui.rs")]
+mod gui;
 mod tui;
 mod simple;
 
@@ -29,9 +32,9 @@ pub(crate) fn init_logger(args: &Args) {
 }
 
 
-/// Returned from `TuiDebugger::update` to tell the main loop what to do.
+/// Returned from `TuiDebugger::update`, `GuiDebugger::update` and
+/// `GdbStub::update` to tell the main loop what to do.
 #[must_use]
-#[cfg_attr(windows, allow(dead_code))]
 pub(crate) enum Action {
     /// Quit the application
     Quit,
@@ -49,7 +52,6 @@ pub(crate) enum Action {
 pub(crate) struct WindowBuffer<'a>(pub(crate) &'a mut [u8]);
 
 impl WindowBuffer<'_> {
-    #[cfg_attr(windows, allow(dead_code))]
     fn paint_pink(&mut self) {
         for chunk in self.0.chunks_mut(4) {
             chunk[0] = 0xFF;
@@ -0,0 +1,37 @@
+//! The analysis of the ROM, extended while the emulator runs.
+
+use std::{
+    cell::{Ref, RefCell},
+    rc::Rc,
+};
+
+use mahboi::machine::Machine;
+use crate::analyze::CodeMap;
+
+
+/// The `CodeMap` of the ROM, extended with every executed instruction. Like
+/// `Coverage`, this is shared between the debugger and the ASM view: just
+/// `clone()` it.
+#[derive(Clone)]
+pub(crate) struct Analysis(Rc<RefCell<CodeMap>>);
+
+impl Analysis {
+    pub(crate) fn new(code_map: CodeMap) -> Self {
+        Analysis(Rc::new(RefCell::new(code_map)))
+    }
+
+    /// Has to be called before every step.
+    pub(crate) fn observe(&self, machine: &Machine) {
+        let pc = machine.cpu.pc;
+        let in_bios = machine.bios_mounted() && pc.get() < 0x100;
+        if in_bios || !machine.executes_instruction_next() {
+            return;
+        }
+
+        self.0.borrow_mut().observe_execution(pc, machine.cartridge.rom_bank());
+    }
+
+    pub(crate) fn get(&self) -> Ref<'_, CodeMap> {
+        self.0.borrow()
+    }
+}
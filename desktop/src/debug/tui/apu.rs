@@ -0,0 +1,129 @@
+//! Overview of the sound channels (APU) for the "APU" tab.
+
+use cursive::{
+    theme::{BaseColor, Color},
+    utils::markup::StyledString,
+};
+
+use mahboi::{
+    machine::Machine,
+    primitives::Word,
+};
+use super::io_regs::{decode_envelope, decode_nr10, decode_nr43, decode_nr52};
+
+
+/// Creates the content of the APU tab: the state of all four channels (as
+/// far as it is emulated) and the global sound registers.
+pub(crate) fn apu_text(machine: &Machine) -> StyledString {
+    let title_style = Color::Light(BaseColor::Green);
+    let value_style = Color::Light(BaseColor::Magenta);
+    let load = |addr: u16| machine.peek_byte(Word::new(addr)).get();
+    let sound = machine.sound_controller();
+    let nr51 = load(0xFF25);
+
+    let mut body = StyledString::new();
+    body.append_plain("NR52: ");
+    body.append_styled(decode_nr52(load(0xFF26)), value_style);
+    body.append_plain("   Master volume: ");
+    body.append_styled(
+        format!("left {}/7, right {}/7", (load(0xFF24) >> 4) & 0b111, load(0xFF24) & 0b111),
+        value_style,
+    );
+    body.append_plain("\n");
+
+    let channels = [
+        (1, "Square with sweep", 0xFF10),
+        (2, "Square", 0xFF15),
+        (3, "Wave", 0xFF1A),
+        (4, "Noise", 0xFF1F),
+    ];
+    for &(n, name, base) in &channels {
+        let status = sound.channel_status(n);
+        let nrx1 = load(base + 1);
+        let nrx4 = load(base + 4);
+        let optional = |v: Option<String>| v.unwrap_or_else(|| "not emulated".into());
+
+        body.append_styled(format!("\nChannel {} ({})\n", n, name), title_style);
+
+        // Whether the channel is powered and where it's output
+        let left = if nr51 & (1 << (n + 3)) != 0 { "L" } else { "-" };
+        let right = if nr51 & (1 << (n - 1)) != 0 { "R" } else { "-" };
+        body.append_plain("  DAC: ");
+        body.append_styled(if status.dac_enabled { "on" } else { "off" }, value_style);
+        body.append_plain("   Output: ");
+        body.append_styled(format!("{}{}", left, right), value_style);
+        body.append_plain("\n");
+
+        // Frequency
+        body.append_plain("  Frequency: ");
+        if n == 4 {
+            body.append_styled(decode_nr43(load(base + 3)), value_style);
+            body.append_plain(format!(" ({:.1} Hz)", noise_frequency(load(base + 3))));
+        } else {
+            let raw = load(base + 3) as u32 | (nrx4 as u32 & 0b111) << 8;
+            // The wave channel plays 32 samples per period, the square
+            // channels 8 steps.
+            let base_clock = if n == 3 { 65536 } else { 131072 };
+            body.append_styled(raw.to_string(), value_style);
+            body.append_plain(format!(" ({:.1} Hz)", base_clock as f64 / (2048 - raw) as f64));
+        }
+        body.append_plain("\n");
+
+        // Waveform
+        match n {
+            1 | 2 => {
+                body.append_plain("  Duty: ");
+                let duty = ["12.5%", "25%", "50%", "75%"][(nrx1 >> 6) as usize];
+                body.append_styled(duty, value_style);
+            }
+            3 => {
+                body.append_plain("  Level: ");
+                let level = ["0%", "100%", "50%", "25%"][((load(0xFF1C) >> 5) & 0b11) as usize];
+                body.append_styled(level, value_style);
+            }
+            _ => {
+                body.append_plain("  LFSR width: ");
+                let width = if load(base + 3) & 0b1000 != 0 { "7 bit" } else { "15 bit" };
+                body.append_styled(width, value_style);
+            }
+        }
+        body.append_plain("   Position: ");
+        body.append_styled(optional(status.position.map(|p| p.to_string())), value_style);
+        body.append_plain("\n");
+
+        // Volume envelope (the wave channel has none)
+        if n != 3 {
+            body.append_plain("  Envelope: ");
+            body.append_styled(decode_envelope(load(base + 2)), value_style);
+            body.append_plain("   Volume: ");
+            body.append_styled(optional(status.volume.map(|v| v.to_string())), value_style);
+            body.append_plain("\n");
+        }
+        if n == 1 {
+            body.append_plain("  Sweep: ");
+            body.append_styled(decode_nr10(load(0xFF10)), value_style);
+            body.append_plain("\n");
+        }
+
+        // Length
+        let length = if n == 3 { 256 - nrx1 as u16 } else { 64 - (nrx1 & 0x3F) as u16 };
+        body.append_plain("  Length: ");
+        body.append_styled(length.to_string(), value_style);
+        body.append_plain(format!(" ({})", if nrx4 & 0x40 != 0 { "enabled" } else { "disabled" }));
+        body.append_plain("   Counter: ");
+        body.append_styled(optional(status.length_counter.map(|c| c.to_string())), value_style);
+        body.append_plain("\n");
+    }
+
+    body
+}
+
+/// Returns the frequency (in Hz) at which the noise channel's LFSR is clocked
+/// for the given value of NR43.
+fn noise_frequency(nr43: u8) -> f64 {
+    let divisor = match nr43 & 0b111 {
+        0 => 0.5,
+        r => r as f64,
+    };
+    524288.0 / divisor / 2f64.powi((nr43 >> 4) as i32 + 1)
+}
@@ -1,7 +1,7 @@
 use std::{
     cmp,
-    collections::BTreeMap,
     ops::Range,
+    rc::Rc,
 };
 
 use cursive::{
@@ -14,122 +14,240 @@ use cursive::{
 };
 
 use mahboi::{
-    opcode,
-    instr::Instr,
+    instr::{self, Instr},
     machine::Machine,
     primitives::Word,
 };
+use crate::{
+    analyze::{ByteKind, RomAddr},
+    symbols::Symbols,
+};
 use super::{
-    Breakpoints,
+    Breakpoints, Location,
+    analysis::Analysis,
+    coverage::Coverage,
     util::{DecodedInstr, InstrArg},
 };
 
 /// How many bytes around PC should be showed in the view?
 const CONTEXT_SIZE: u16 = 100;
 
-/// When `update()` is called, how many instructions should be added to the
-/// cache (starting from the current instruction).
-const CACHE_LOOKAHEAD: u16 = 200;
-
 #[derive(Clone, Debug)]
 struct Line {
     current: bool,
     addr: Word,
     instr: DecodedInstr,
     comment: String,
+
+    /// The instruction in the syntax of `instr::assemble`. Empty for data
+    /// and invalid for unknown opcodes.
+    source: String,
+
+    /// If set, this line only shows the name of the symbol at `addr`. The
+    /// instruction is shown in the next line.
+    label: Option<String>,
 }
 
+/// Shows the code around PC. Instruction boundaries are taken from the
+/// analysis. Code unknown to it (e.g. the boot ROM) is decoded linearly,
+/// starting at PC or the focused address.
 pub struct AsmView {
     lines: Vec<Line>,
-    instr_cache: BTreeMap<Word, DecodedInstr>,
+
+    /// The ROM bank that was mapped in the last `update()` call.
+    rom_bank: usize,
     pc: Word,
     breakpoints: Breakpoints,
+    coverage: Coverage,
+    symbols: Rc<Symbols>,
+    analysis: Analysis,
+
+    /// If set, the view shows the code around this address instead of the
+    /// code around PC. Reset once PC changes.
+    focus: Option<Word>,
+
+    /// Set when `focus` was changed, but `update()` wasn't called yet.
+    needs_refresh: bool,
 }
 
 impl AsmView {
     /// Creates an empty AsmView.
-    pub(crate) fn new(breakpoints: Breakpoints) -> Self {
+    pub(crate) fn new(
+        breakpoints: Breakpoints,
+        coverage: Coverage,
+        symbols: Rc<Symbols>,
+        analysis: Analysis,
+    ) -> Self {
         Self {
             lines: vec![],
-            instr_cache: BTreeMap::new(),
+            rom_bank: 1,
             pc: Word::new(0),
             breakpoints,
+            coverage,
+            symbols,
+            analysis,
+            focus: None,
+            needs_refresh: false,
         }
     }
 
-    pub(crate) fn invalidate_cache(&mut self, range: Range<Word>) {
-        let keys = self.instr_cache.range(range)
-            .map(|(addr, _)| *addr)
-            .collect::<Vec<_>>();
+    /// Shows the code around `addr` (instead of around PC) until execution
+    /// continues. `update()` has to be called for this to take effect.
+    pub(crate) fn set_focus(&mut self, addr: Word) {
+        self.focus = Some(addr);
+        self.needs_refresh = true;
+    }
 
-        for key in keys {
-            self.instr_cache.remove(&key);
-        }
+    /// Returns `true` if `update()` needs to be called because the focus
+    /// changed.
+    pub(crate) fn needs_refresh(&self) -> bool {
+        self.needs_refresh
     }
 
     fn start_of_instr_at(&self, addr: Word) -> Option<Word> {
-        self.instr_cache
-            .range(addr..addr + 3u8)
-            .next()
-            .map(|(addr, _)| *addr)
+        let analysis = self.analysis.get();
+        (addr.get()..addr.get().saturating_add(3))
+            .map(Word::new)
+            .find(|&a| analysis.is_instr_start(a, self.rom_bank))
     }
 
     pub fn update(&mut self, machine: &Machine) {
+        if self.pc != machine.cpu.pc {
+            self.focus = None;
+        }
         self.pc = machine.cpu.pc;
+        self.rom_bank = machine.cartridge.rom_bank();
+        self.needs_refresh = false;
 
-        // Add new instructions to cache
-        let mut pos = machine.cpu.pc;
-        for _ in 0..CACHE_LOOKAHEAD {
-            let data = [
-                machine.load_byte(pos),
-                machine.load_byte(pos + 1u8),
-                machine.load_byte(pos + 2u8),
-            ];
-
-            // We can unwrap: `data` is always long enough
-            let instr = DecodedInstr::decode(&data).unwrap();
-
-            // If we encounter an unencodable instruction, we stop.
-            if instr.is_unknown() {
-                break;
+        // Construct the lines we want to show.
+        let rom_bank = self.rom_bank;
+        let analysis = self.analysis.get();
+        let symbols = self.symbols.clone();
+
+        // Functions found by the analysis have a generated name.
+        let name_of = |addr: Word| {
+            let in_bios = machine.bios_mounted() && addr.get() < 0x100;
+            match symbols.name_at(addr, rom_bank) {
+                Some(name) => Some(name.to_string()),
+                None if addr.get() < 0x8000 && !in_bios => {
+                    analysis.function(RomAddr::new(rom_bank, addr)).map(|f| f.name.clone())
+                }
+                None => None,
             }
+        };
 
-            let addr = pos;
-            pos += instr.len();
-
-            self.instr_cache.insert(addr, instr);
-        }
-
-        // Construct the lines we want to show.
         self.lines.clear();
         let curr_range = self.get_current_range();
         let mut addr = curr_range.start;
+        let mut sweep = false;
         while addr < curr_range.end {
             // Print arrow to show where we are
             let current = self.pc == addr;
+            if current || self.focus == Some(addr) {
+                sweep = true;
+            }
 
-            let instr = self.instr_cache.get(&addr)
-                .cloned()
-                .unwrap_or(DecodedInstr::Unknown(machine.load_byte(addr)));
+            // Data is shown byte by byte, unless it was executed.
+            let in_bios = machine.bios_mounted() && addr.get() < 0x100;
+            let in_rom = addr.get() < 0x8000 && !in_bios;
+            let is_data = !current
+                && in_rom
+                && analysis.kind_at(RomAddr::new(rom_bank, addr)) == ByteKind::Data
+                && self.coverage.is_executed(addr, rom_bank) != Some(true);
+            let is_known = !in_bios && analysis.is_instr_start(addr, rom_bank);
+            let (instr, source) = if !is_data && (is_known || sweep) {
+                decode_at(machine, addr)
+            } else {
+                (DecodedInstr::Unknown(machine.peek_byte(addr)), String::new())
+            };
+            if is_data || instr.is_unknown() {
+                sweep = false;
+            }
 
             let instr_len = instr.len();
 
+            let mut comment = if is_data {
+                "data".to_string()
+            } else {
+                comment_for(&instr, addr, &name_of)
+            };
+
+            // When paused on a conditional instruction, show whether the
+            // condition holds.
+            if let Some(taken) = branch_taken(&instr, machine).filter(|_| current) {
+                if !comment.is_empty() {
+                    comment += ", ";
+                }
+                comment += if taken { "taken" } else { "not taken" };
+            }
+
             let line = Line {
                 current,
                 addr,
-                comment: comment_for(&instr, addr),
+                comment,
                 instr,
+                source,
+                label: None,
             };
+
+            // Functions and jump targets get their own line with the name.
+            if let Some(name) = name_of(addr) {
+                self.lines.push(Line {
+                    current: false,
+                    label: Some(format!("{}:", name)),
+                    ..line.clone()
+                });
+            }
             self.lines.push(line);
 
             addr += instr_len;
         }
     }
 
+    /// Returns the line of PC or, if set, of the focused address.
     pub(crate) fn get_active_line(&self) -> usize {
-        self.lines.iter()
-            .position(|l| l.current)
-            .expect("internal asm_view error: no line is current")
+        match self.focus {
+            Some(focus) => self.lines.iter()
+                .position(|l| l.addr >= focus)
+                .unwrap_or(0),
+            None => self.lines.iter()
+                .position(|l| l.current)
+                .expect("internal asm_view error: no line is current"),
+        }
+    }
+
+    /// Returns the address and source of the instruction in the line of PC
+    /// or, if set, of the focused address. The source is empty for data and
+    /// unknown opcodes.
+    pub(crate) fn active_instr(&self) -> Option<(Word, String)> {
+        if self.lines.is_empty() {
+            return None;
+        }
+
+        let line = &self.lines[self.get_active_line()];
+        let source = if line.instr.is_unknown() { String::new() } else { line.source.clone() };
+        Some((line.addr, source))
+    }
+
+    /// Toggles the breakpoint at the line of PC or, if set, of the focused
+    /// address.
+    pub(crate) fn toggle_breakpoint_at_active_line(&mut self) {
+        if !self.lines.is_empty() {
+            let addr = self.lines[self.get_active_line()].addr;
+            self.toggle_breakpoint(addr);
+        }
+    }
+
+    /// Removes all breakpoints triggering at `addr` or, if there are none,
+    /// adds one for the current bank.
+    fn toggle_breakpoint(&mut self, addr: Word) {
+        let matching = self.breakpoints.matching(addr, self.rom_bank);
+        if matching.is_empty() {
+            self.breakpoints.add(Location::new(addr, self.rom_bank));
+        }
+        for loc in matching {
+            self.breakpoints.remove(loc);
+        }
     }
 
     fn get_current_range(&self) -> Range<Word> {
@@ -137,9 +255,10 @@ impl AsmView {
         // position is a bit tricky. It might be the case that it shows into
         // the middle of an cached instruction. If that's the case, we slightly
         // adjust the start value.
-        let start = self.pc.map(|w| w.saturating_sub(CONTEXT_SIZE));
+        let center = self.focus.unwrap_or(self.pc);
+        let start = center.map(|w| w.saturating_sub(CONTEXT_SIZE));
         let start = self.start_of_instr_at(start).unwrap_or(start);
-        let end = self.pc.map(|w| w.saturating_add(CONTEXT_SIZE));
+        let end = center.map(|w| w.saturating_add(CONTEXT_SIZE));
 
         start..end
     }
@@ -148,13 +267,21 @@ impl AsmView {
 impl View for AsmView {
     fn draw(&self, printer: &Printer) {
         for (i, line) in self.lines.iter().enumerate() {
+            // Labels are printed in the column of the address
+            if let Some(label) = &line.label {
+                printer.with_style(Color::Light(BaseColor::Green), |printer| {
+                    printer.print((7, i), label);
+                });
+                continue;
+            }
+
             // Print arrow to show where we are
             if line.current {
                 printer.print((0, i), "PC ➤ ");
             }
             let breakpoint_offset = 5;
 
-            if self.breakpoints.contains(line.addr) {
+            if self.breakpoints.is_set_at(line.addr, self.rom_bank) {
                 printer.with_style(Color::Light(BaseColor::Red), |printer| {
                     printer.print((breakpoint_offset, i), "⯃ ");
                 });
@@ -163,11 +290,17 @@ impl View for AsmView {
             }
             let addr_offset = breakpoint_offset + 2;
 
-            // Print address
-            printer.with_style(Color::Light(BaseColor::Blue), |printer| {
-                printer.print((addr_offset, i), &format!("{} │   ", line.addr));
+            // Print address (with bank in the switchable ROM area). ROM
+            // addresses that were never executed are grayed out.
+            let loc = Location::new(line.addr, self.rom_bank);
+            let addr_color = match self.coverage.is_executed(line.addr, self.rom_bank) {
+                Some(false) => Color::Light(BaseColor::Black),
+                _ => Color::Light(BaseColor::Blue),
+            };
+            printer.with_style(addr_color, |printer| {
+                printer.print((addr_offset, i), &format!("{: <7} │   ", loc.to_string()));
             });
-            let instr_offset = addr_offset + 11;
+            let instr_offset = addr_offset + 12;
 
             // Print instruction
             line.instr.print(&printer.offset((instr_offset, i)));
@@ -200,11 +333,7 @@ impl View for AsmView {
                     // If the left side of the line was clicked
                     if rel_pos.x < 14 {
                         let addr = self.lines[rel_pos.y].addr;
-                        if self.breakpoints.contains(addr) {
-                            self.breakpoints.remove(addr);
-                        } else {
-                            self.breakpoints.add(addr);
-                        }
+                        self.toggle_breakpoint(addr);
                         return EventResult::Consumed(None);
                     }
                 }
@@ -226,24 +355,71 @@ impl View for AsmView {
     }
 }
 
+/// Decodes the instruction at `addr`. Also returns its source for
+/// `instr::assemble`.
+fn decode_at(machine: &Machine, addr: Word) -> (DecodedInstr, String) {
+    let data = [
+        machine.peek_byte(addr),
+        machine.peek_byte(addr + 1u8),
+        machine.peek_byte(addr + 2u8),
+    ];
+
+    // We can unwrap: `data` is always long enough
+    (DecodedInstr::decode(&data).unwrap(), instr::disassemble(addr, &data))
+}
+
+/// Returns whether the condition of the conditional jump, call or return
+/// `instr` holds with the current flags, or `None` if `instr` has no
+/// condition.
+fn branch_taken(instr: &DecodedInstr, machine: &Machine) -> Option<bool> {
+    let opcode = instr.instr().filter(|_| !instr.prefixed())?.opcode.get();
+
+    // `JR cc`, `RET cc`, `JP cc` and `CALL cc` encode the condition in bits
+    // 3 and 4.
+    if ![0x20, 0xC0, 0xC2, 0xC4].contains(&(opcode & 0b1110_0111)) {
+        return None;
+    }
+
+    let cpu = &machine.cpu;
+    let taken = match (opcode >> 3) & 0b11 {
+        0 => !cpu.zero(),
+        1 => cpu.zero(),
+        2 => !cpu.carry(),
+        _ => cpu.carry(),
+    };
+    Some(taken)
+}
+
 /// Creates a comment string for the given instruction.
 ///
-/// The comment can hold any potentially useful informtion.
-fn comment_for(instr: &DecodedInstr, addr: Word) -> String {
+/// The comment can hold any potentially useful informtion, like the names of
+/// symbols that are referenced or the destination of jumps and calls.
+/// `name_of` returns the name of the symbol or function at an address.
+fn comment_for(
+    instr: &DecodedInstr,
+    addr: Word,
+    name_of: &dyn Fn(Word) -> Option<String>,
+) -> String {
     fn comment_sep(s: &mut String) {
         if !s.is_empty() {
             *s += ", ";
         }
     }
 
-    fn comment_for_arg(s: &mut String, arg: &InstrArg) {
+    fn comment_for_arg(s: &mut String, arg: &InstrArg, name_of: &dyn Fn(Word) -> Option<String>) {
         if let InstrArg::Dyn { raw, label, .. } = arg {
             let addr = match *label {
                 "(a8)" => Word::new(0xFF00) + raw[0],
-                "(a16)" | "d16" => Word::from_bytes(raw[0], raw[1]),
+                "(a16)" | "a16" | "d16" => Word::from_bytes(raw[0], raw[1]),
                 _ => return,
             };
 
+            if let Some(name) = name_of(addr) {
+                comment_sep(s);
+                *s += &name;
+                return;
+            }
+
             let comment = match addr.get() {
                 0xFF00 => "input",
                 0xFF01 => "serial transfer data",
@@ -272,39 +448,36 @@ fn comment_for(instr: &DecodedInstr, addr: Word) -> String {
         }
     }
 
+    // Show the destination of jumps and calls (with its name, if known)
+    if let Some(Instr { opcode, name, .. }) = instr.instr().filter(|_| !instr.prefixed()) {
+        let is_rst = opcode.get() & 0b1100_0111 == 0b1100_0111;
+        let dst = match instr.arg1().or_else(|| instr.arg0()) {
+            _ if is_rst => Some(Word::new((opcode.get() & 0b0011_1000) as u16)),
+            Some(InstrArg::Dyn { label: "r8", raw, .. }) if name == "JR" => {
+                Some(addr + raw[0].get() as i8 + 2u8)
+            }
+            Some(InstrArg::Dyn { label: "a16", raw, .. }) => Some(Word::from_bytes(raw[0], raw[1])),
+            _ => None,
+        };
+
+        if let Some(dst) = dst {
+            let verb = if is_rst || name == "CALL" { "calls" } else { "jumps to" };
+            return match name_of(dst) {
+                Some(name) => format!("{} {} ({})", verb, dst, name),
+                None => format!("{} {}", verb, dst),
+            };
+        }
+    }
+
     let mut out = String::new();
     match instr {
-        DecodedInstr::OneArg { arg, .. } => comment_for_arg(&mut out, arg),
+        DecodedInstr::OneArg { arg, .. } => comment_for_arg(&mut out, arg, name_of),
         DecodedInstr::TwoArgs { arg0, arg1, .. } => {
-            comment_for_arg(&mut out, arg0);
-            comment_for_arg(&mut out, arg1);
+            comment_for_arg(&mut out, arg0, name_of);
+            comment_for_arg(&mut out, arg1, name_of);
         }
         _ => {}
     };
 
-    if let Some(Instr { opcode, .. }) = instr.instr() {
-        match opcode.get() {
-            // Show destination address
-            opcode!("JR r8")
-            | opcode!("JR NZ, r8")
-            | opcode!("JR NC, r8")
-            | opcode!("JR Z, r8")
-            | opcode!("JR C, r8") if !instr.prefixed() => {
-                let arg = if opcode.get() == opcode!("JR r8") {
-                    instr.arg0().unwrap()
-                } else {
-                    instr.arg1().unwrap()
-                };
-                let raw = arg.raw_data().unwrap();
-                let r8 = raw[0].get() as i8;
-
-                let dst = addr + r8 + 2u8;
-                out.push_str(&format!("jumps to {}", dst));
-            }
-
-            _ => {}
-        }
-    }
-
     out
 }
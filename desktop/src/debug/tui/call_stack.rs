@@ -0,0 +1,173 @@
+//! A shadow call stack, reconstructed by observing execution.
+
+use mahboi::{
+    opcode,
+    instr::INSTRUCTIONS,
+    machine::Machine,
+    primitives::{Byte, Word},
+};
+use crate::symbols::Symbols;
+
+
+/// Addresses of the interrupt service routines.
+const INTERRUPT_VECTORS: [u16; 5] = [0x40, 0x48, 0x50, 0x58, 0x60];
+
+/// How a frame was entered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FrameKind {
+    Call,
+    Rst,
+    Interrupt,
+}
+
+/// One frame of the call stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Frame {
+    pub(crate) kind: FrameKind,
+
+    /// Address of the `CALL`/`RST` instruction or, for interrupts, of the
+    /// instruction that was interrupted.
+    pub(crate) call_site: Word,
+
+    /// Start address of the called function.
+    pub(crate) target: Word,
+
+    /// The address execution continues at when this frame returns.
+    pub(crate) return_addr: Word,
+
+    /// The ROM bank that was mapped when the frame was entered.
+    pub(crate) rom_bank: usize,
+}
+
+impl Frame {
+    /// Returns a short description of this frame, using the symbol name of
+    /// the called function if it exists.
+    pub(crate) fn label(&self, symbols: &Symbols) -> String {
+        let target = match symbols.name_at(self.target, self.rom_bank) {
+            Some(name) => name.to_string(),
+            None => self.target.to_string(),
+        };
+        let suffix = if self.kind == FrameKind::Interrupt { " (int)" } else { "" };
+
+        format!("{} ret {}{}", target, self.return_addr, suffix)
+    }
+}
+
+/// The state before the last instruction was executed.
+#[derive(Debug, Clone, Copy)]
+struct Before {
+    pc: Word,
+    sp: Word,
+    opcode: u8,
+}
+
+/// The call stack is reconstructed by looking at the machine before every
+/// instruction: if the stack pointer moved by two after a `CALL`, `RST` or
+/// `RET`-like instruction, the call was (or the return was) actually
+/// executed. Interrupt dispatches are detected by PC jumping to an interrupt
+/// vector while the old PC is pushed.
+#[derive(Clone)]
+pub(crate) struct CallStack {
+    frames: Vec<Frame>,
+    before: Option<Before>,
+
+    /// The stack pointer after the last push (by `CALL`, `RST`, `PUSH` or an
+    /// interrupt dispatch) and a description of what pushed.
+    last_push: Option<(Word, &'static str)>,
+}
+
+impl CallStack {
+    pub(crate) fn new() -> Self {
+        Self {
+            frames: Vec::new(),
+            before: None,
+            last_push: None,
+        }
+    }
+
+    /// All frames, the innermost last.
+    pub(crate) fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// Returns the stack pointer after the most recent push and a short
+    /// description of the instruction that pushed (e.g. `CALL` or `PUSH BC`).
+    pub(crate) fn last_push(&self) -> Option<(Word, &'static str)> {
+        self.last_push
+    }
+
+    /// Has to be called before every instruction is executed.
+    pub(crate) fn observe(&mut self, machine: &Machine) {
+        let pc = machine.cpu.pc;
+        let sp = machine.cpu.sp;
+
+        if let Some(before) = self.before {
+            if sp == before.sp - 2u16 {
+                // An interrupt dispatch pushes the address of the instruction
+                // that was about to be executed, calls push the address after
+                // the instruction.
+                let bytes = machine.peek_range(sp, 2);
+                let pushed = Word::from_bytes(bytes[0], bytes[1]);
+                let is_interrupt = pushed == before.pc && INTERRUPT_VECTORS.contains(&pc.get());
+                let kind = match before.opcode {
+                    _ if is_interrupt => Some(FrameKind::Interrupt),
+                    opcode!("CALL a16")
+                    | opcode!("CALL NZ, a16")
+                    | opcode!("CALL Z, a16")
+                    | opcode!("CALL NC, a16")
+                    | opcode!("CALL C, a16") => Some(FrameKind::Call),
+                    op if op & 0b1100_0111 == 0b1100_0111 => Some(FrameKind::Rst),
+                    _ => None,
+                };
+
+                let pushed_by = match kind {
+                    Some(FrameKind::Call) => Some("CALL"),
+                    Some(FrameKind::Rst) => Some("RST"),
+                    Some(FrameKind::Interrupt) => Some("interrupt"),
+                    None if before.opcode & 0b1100_1111 == 0b1100_0101 => {
+                        INSTRUCTIONS[Byte::new(before.opcode)].map(|instr| instr.mnemonic)
+                    }
+                    None => None,
+                };
+                if pushed_by.is_some() {
+                    self.last_push = pushed_by.map(|desc| (sp, desc));
+                }
+
+                if let Some(kind) = kind {
+                    self.frames.push(Frame {
+                        kind,
+                        call_site: before.pc,
+                        target: pc,
+                        return_addr: pushed,
+                        rom_bank: machine.cartridge.rom_bank(),
+                    });
+                }
+            } else if sp == before.sp + 2u16 {
+                let is_ret = matches!(
+                    before.opcode,
+                    opcode!("RET")
+                        | opcode!("RETI")
+                        | opcode!("RET NZ")
+                        | opcode!("RET Z")
+                        | opcode!("RET NC")
+                        | opcode!("RET C")
+                );
+
+                // Return to the innermost frame with a matching return
+                // address. If there is none, the program manipulated the
+                // stack and we keep our frames as they are.
+                if is_ret {
+                    if let Some(idx) = self.frames.iter().rposition(|f| f.return_addr == pc) {
+                        self.frames.truncate(idx);
+                    }
+                }
+            }
+        }
+
+        self.before = Some(Before {
+            pc,
+            sp,
+            opcode: machine.peek_byte(pc).get(),
+        });
+    }
+}
@@ -0,0 +1,539 @@
+//! The command console of the debugger: a prompt similar to the one of GDB.
+//!
+//! Addresses can be given as hexadecimal number (without prefix) or as symbol
+//! name. Values (for `set` and `p`) are expressions as described in the
+//! `expr` module.
+
+use std::path::PathBuf;
+
+use mahboi::{
+    machine::hooks::Watchpoint,
+    primitives::Word,
+};
+use crate::{
+    disasm::Selection,
+    gfx::Palette,
+    symbols::Symbols,
+};
+use super::{
+    Condition, Location, OpcodeBreak, parse_watchpoint,
+    expr::{Expr, Reg},
+    ram_search::Filter,
+};
+
+
+/// Shown for the `help` command.
+pub(crate) const HELP: &str = "\
+b <loc>          add breakpoint, e.g. `b 0150`, `b 05:4123` or `b Main`
+b <loc> if <expr>
+                 add conditional breakpoint, e.g. `b Main if a == 3`
+d <loc>          remove breakpoint
+bo <opcode>      pause before any instruction with this opcode, e.g. `bo ff`,
+                 `bo cb 37`, `bo rst 38h` or `bo invalid` (all invalid opcodes)
+do <opcode>      remove opcode breakpoint
+w <watchpoint>   add watchpoint, e.g. `w ff40 w` or `w c000-c0ff rw`
+x[/<n>] <addr>   show <n> bytes of memory (default 16)
+p <expr>         evaluate expression, e.g. `p [hl] + 1`
+display <expr>   add watch expression
+undisplay <n>    remove watch expression with index <n>
+set <reg>=<expr> set register or flag, e.g. `set a=0x10`, `set zf=1`
+set [<expr>]=<expr>
+                 write byte to memory
+c, s, u, f       continue, single step, step back, run to RET-like
+int [<kind>]     run until right before the next interrupt is dispatched,
+                 optionally only vblank, stat, timer, serial or joypad
+pause            pause execution
+source <file>    execute all commands in the given file
+disasm <file> [<bank> | [<bank>:]<start>-<end>]
+                 write disassembly of the ROM (RGBDS syntax) to the file
+project <dir>    write disassembly as RGBDS project to the directory
+tiles <file> [bg|obj0|obj1]
+                 write all tiles in VRAM as PNG with the palette applied
+tiles rom <dir>  write each ROM bank decoded as tiles as PNG to the directory
+maps <dir> [viewport]
+                 write the background and window maps as PNG to the directory,
+                 optionally with the visible part outlined
+coverage save <file>
+                 write all executed ROM ranges to the file
+coverage reset   forget all executed addresses
+profile on|off|reset
+                 start, stop or reset the profiler (see Profiler tab)
+heatmap on|off|reset
+                 start, stop or reset counting memory accesses (see Heatmap tab)
+heatmap window <frames>
+                 set the length of the time window of the heatmap
+heatmap save <file>
+                 write the heatmap of the last time window as PNG
+ramsearch reset  start a RAM search with all of WRAM, HRAM and cartridge RAM
+ramsearch eq|ne|inc|dec
+                 keep the candidates that are equal, not equal, larger or
+                 smaller than in the last snapshot (see RAM Search tab)
+ramsearch by <n> keep the candidates that changed by <n>, e.g. `ramsearch by -1`
+ramsearch watch <n>
+                 add the candidate with index <n> as watch expression
+ramsearch freeze <n>
+                 freeze the candidate with index <n> to its current value
+freeze <addr> [<expr>]
+                 write the value (default: the current one) to the address
+                 after every instruction
+unfreeze <addr>  stop writing a frozen address
+analyze          run the control flow analysis of the ROM and show a summary
+callgraph <file> [cfg]
+                 write the call graph (with `cfg`: the control flow graphs of
+                 all functions) as Graphviz DOT file
+help             show this help";
+
+/// A command entered in the console.
+#[derive(Debug, Clone)]
+pub(crate) enum Command {
+    Break(Location, Option<Condition>),
+    Delete(Location),
+    BreakOpcode(OpcodeBreak),
+    DeleteOpcode(OpcodeBreak),
+    Watch(Watchpoint),
+    Examine {
+        addr: Word,
+        len: u16,
+    },
+    Print(Expr),
+    Display {
+        source: String,
+        expr: Expr,
+    },
+    Undisplay(usize),
+    Set {
+        target: Target,
+        value: Expr,
+    },
+    Continue,
+    Step,
+    StepBack,
+    Finish,
+
+    /// Run until an interrupt is dispatched. If given, only the interrupt
+    /// with this vector address is considered.
+    RunToInterrupt(Option<Word>),
+    Pause,
+    Source(PathBuf),
+    Disassemble {
+        path: PathBuf,
+        selection: Selection,
+    },
+    ExportProject(PathBuf),
+    ExportTiles {
+        path: PathBuf,
+        palette: Palette,
+    },
+    ExportRomTiles(PathBuf),
+    ExportMaps {
+        dir: PathBuf,
+        viewport: bool,
+    },
+    Profile(ProfilerAction),
+    Heatmap(HeatmapAction),
+    RamSearch(RamSearchAction),
+    Freeze {
+        addr: Word,
+
+        /// The current value is used if this is `None`.
+        value: Option<Expr>,
+    },
+    Unfreeze(Word),
+    SaveCoverage(PathBuf),
+    ResetCoverage,
+    Analyze,
+    CallGraph {
+        path: PathBuf,
+        with_cfg: bool,
+    },
+    Help,
+}
+
+/// Argument of the `profile` command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ProfilerAction {
+    Start,
+    Stop,
+    Reset,
+}
+
+/// Argument of the `heatmap` command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum HeatmapAction {
+    Start,
+    Stop,
+    Reset,
+    Window(u64),
+    Save(PathBuf),
+}
+
+/// Argument of the `ramsearch` command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RamSearchAction {
+    Reset,
+    Filter(Filter),
+
+    /// Add the candidate with this index as watch expression.
+    Watch(usize),
+
+    /// Freeze the candidate with this index to its current value.
+    Freeze(usize),
+}
+
+/// Something that can be assigned with `set`.
+#[derive(Debug, Clone)]
+pub(crate) enum Target {
+    Reg(Reg),
+
+    /// The byte at the address the expression evaluates to.
+    Mem(Expr),
+}
+
+impl Command {
+    /// Parses one line of user input.
+    pub(crate) fn parse(input: &str, symbols: &Symbols) -> Result<Self, String> {
+        let input = input.trim();
+        let (cmd, rest) = match input.split_once(char::is_whitespace) {
+            Some((cmd, rest)) => (cmd, rest.trim()),
+            None => (input, ""),
+        };
+
+        let no_args = |cmd| if rest.is_empty() {
+            Ok(cmd)
+        } else {
+            Err(format!("'{}' does not take arguments", input))
+        };
+        let expr = |src: &str| Expr::parse(src, symbols).map_err(|e| e.to_string());
+
+        // `x` has its length attached to the command name
+        if let Some(len) = cmd.strip_prefix("x/") {
+            let len = len.parse().map_err(|e| format!("invalid length '{}': {}", len, e))?;
+            return Ok(Command::Examine { addr: symbols.resolve(rest)?, len });
+        }
+
+        match cmd {
+            "b" | "break" => {
+                let (loc, condition) = match rest.split_once(" if ") {
+                    Some((loc, cond)) => {
+                        let source = cond.trim().to_string();
+                        (loc, Some(Condition { source, expr: expr(cond)? }))
+                    }
+                    None => (rest, None),
+                };
+                Ok(Command::Break(Location::parse(loc, symbols)?, condition))
+            }
+            "d" | "delete" => Ok(Command::Delete(Location::parse(rest, symbols)?)),
+            "bo" => Ok(Command::BreakOpcode(OpcodeBreak::parse(rest)?)),
+            "do" => Ok(Command::DeleteOpcode(OpcodeBreak::parse(rest)?)),
+            "w" | "watch" => Ok(Command::Watch(parse_watchpoint(rest)?)),
+            "x" => Ok(Command::Examine { addr: symbols.resolve(rest)?, len: 16 }),
+            "p" | "print" => Ok(Command::Print(expr(rest)?)),
+            "display" => Ok(Command::Display { source: rest.to_string(), expr: expr(rest)? }),
+            "undisplay" => {
+                let idx = rest.parse().map_err(|e| format!("invalid index '{}': {}", rest, e))?;
+                Ok(Command::Undisplay(idx))
+            }
+            "set" => {
+                let (lhs, rhs) = rest.split_once('=').ok_or("expected `set <target>=<value>`")?;
+                let lhs = lhs.trim();
+                let target = match lhs.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                    Some(addr) => Target::Mem(expr(addr)?),
+                    None => {
+                        let reg = Reg::from_name(lhs)
+                            .ok_or_else(|| format!("'{}' is not a register", lhs))?;
+                        Target::Reg(reg)
+                    }
+                };
+
+                Ok(Command::Set { target, value: expr(rhs)? })
+            }
+            "c" | "continue" => no_args(Command::Continue),
+            "s" | "step" => no_args(Command::Step),
+            "u" | "back" => no_args(Command::StepBack),
+            "f" | "finish" => no_args(Command::Finish),
+            "int" | "interrupt" => {
+                let vector = match rest {
+                    "" => None,
+                    "vblank" => Some(0x40),
+                    "stat" => Some(0x48),
+                    "timer" => Some(0x50),
+                    "serial" => Some(0x58),
+                    "joypad" => Some(0x60),
+                    _ => return Err(format!("unknown interrupt '{}'", rest)),
+                };
+                Ok(Command::RunToInterrupt(vector.map(Word::new)))
+            }
+            "pause" => no_args(Command::Pause),
+            "source" if !rest.is_empty() => Ok(Command::Source(rest.into())),
+            "source" => Err("no file given".into()),
+            "disasm" if !rest.is_empty() => {
+                let (path, selection) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                Ok(Command::Disassemble {
+                    path: path.into(),
+                    selection: Selection::parse(selection)?,
+                })
+            }
+            "disasm" => Err("no file given".into()),
+            "project" if !rest.is_empty() => Ok(Command::ExportProject(rest.into())),
+            "project" => Err("no directory given".into()),
+            "tiles" => match rest.rsplit_once(char::is_whitespace) {
+                Some(("rom", dir)) => Ok(Command::ExportRomTiles(dir.trim().into())),
+                Some((path, palette)) => Ok(Command::ExportTiles {
+                    path: path.trim().into(),
+                    palette: Palette::parse(palette)?,
+                }),
+                None if rest.is_empty() => Err("no file given".into()),
+                None => Ok(Command::ExportTiles { path: rest.into(), palette: Palette::Bg }),
+            },
+            "coverage" => match rest.split_once(char::is_whitespace) {
+                Some(("save", path)) => Ok(Command::SaveCoverage(path.trim().into())),
+                None if rest == "reset" => Ok(Command::ResetCoverage),
+                _ => Err("expected `coverage save <file>` or `coverage reset`".into()),
+            },
+            "maps" => match rest.rsplit_once(char::is_whitespace) {
+                Some((dir, "viewport")) => {
+                    Ok(Command::ExportMaps { dir: dir.trim().into(), viewport: true })
+                }
+                _ if rest.is_empty() => Err("no directory given".into()),
+                _ => Ok(Command::ExportMaps { dir: rest.into(), viewport: false }),
+            },
+            "profile" => match rest {
+                "on" => Ok(Command::Profile(ProfilerAction::Start)),
+                "off" => Ok(Command::Profile(ProfilerAction::Stop)),
+                "reset" => Ok(Command::Profile(ProfilerAction::Reset)),
+                _ => Err("expected `profile on`, `profile off` or `profile reset`".into()),
+            },
+            "heatmap" => match rest.split_once(char::is_whitespace) {
+                None if rest == "on" => Ok(Command::Heatmap(HeatmapAction::Start)),
+                None if rest == "off" => Ok(Command::Heatmap(HeatmapAction::Stop)),
+                None if rest == "reset" => Ok(Command::Heatmap(HeatmapAction::Reset)),
+                Some(("window", frames)) => {
+                    let frames = frames.trim();
+                    match frames.parse() {
+                        Ok(frames) if frames > 0 => {
+                            Ok(Command::Heatmap(HeatmapAction::Window(frames)))
+                        }
+                        _ => Err(format!("invalid number of frames '{}'", frames)),
+                    }
+                }
+                Some(("save", path)) => {
+                    Ok(Command::Heatmap(HeatmapAction::Save(path.trim().into())))
+                }
+                _ => Err("expected `heatmap on|off|reset`, `heatmap window <frames>` or \
+                    `heatmap save <file>`".into()),
+            },
+            "ramsearch" => {
+                let action = match rest.split_once(char::is_whitespace) {
+                    None if rest == "reset" => RamSearchAction::Reset,
+                    None if rest == "eq" => RamSearchAction::Filter(Filter::Equal),
+                    None if rest == "ne" => RamSearchAction::Filter(Filter::NotEqual),
+                    None if rest == "inc" => RamSearchAction::Filter(Filter::Increased),
+                    None if rest == "dec" => RamSearchAction::Filter(Filter::Decreased),
+                    Some(("by", diff)) => {
+                        let diff = diff.trim();
+                        match diff.parse() {
+                            Ok(diff) => RamSearchAction::Filter(Filter::ChangedBy(diff)),
+                            _ => return Err(format!("invalid difference '{}'", diff)),
+                        }
+                    }
+                    Some((action @ ("watch" | "freeze"), idx)) => {
+                        let idx = idx.trim();
+                        match idx.parse() {
+                            Ok(idx) if action == "watch" => RamSearchAction::Watch(idx),
+                            Ok(idx) => RamSearchAction::Freeze(idx),
+                            _ => return Err(format!("invalid index '{}'", idx)),
+                        }
+                    }
+                    _ => return Err("expected `ramsearch reset|eq|ne|inc|dec`, \
+                        `ramsearch by <n>` or `ramsearch watch|freeze <n>`".into()),
+                };
+                Ok(Command::RamSearch(action))
+            }
+            "freeze" => match rest.split_once(char::is_whitespace) {
+                _ if rest.is_empty() => Err("no address given".into()),
+                Some((addr, value)) => Ok(Command::Freeze {
+                    addr: symbols.resolve(addr)?,
+                    value: Some(expr(value)?),
+                }),
+                None => Ok(Command::Freeze { addr: symbols.resolve(rest)?, value: None }),
+            },
+            "unfreeze" => Ok(Command::Unfreeze(symbols.resolve(rest)?)),
+            "analyze" => no_args(Command::Analyze),
+            "callgraph" => match rest.rsplit_once(char::is_whitespace) {
+                Some((path, "cfg")) => {
+                    Ok(Command::CallGraph { path: path.trim().into(), with_cfg: true })
+                }
+                _ if rest.is_empty() => Err("no file given".into()),
+                _ => Ok(Command::CallGraph { path: rest.into(), with_cfg: false }),
+            },
+            "help" => no_args(Command::Help),
+            _ => Err(format!("unknown command '{}' (try `help`)", cmd)),
+        }
+    }
+
+    /// Returns the event (as used for the global shortcuts) that performs the
+    /// same action as this command, if any. These commands can only be
+    /// executed while the emulator is paused.
+    pub(crate) fn as_event(&self) -> Option<char> {
+        match self {
+            Command::Continue => Some('r'),
+            Command::Step => Some('s'),
+            Command::StepBack => Some('u'),
+            Command::Finish => Some('f'),
+            Command::RunToInterrupt(None) => Some('i'),
+            Command::RunToInterrupt(Some(_)) => Some('I'),
+            _ => None,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let symbols = Symbols::parse("00:0150 Main\n02:4000 Loop").unwrap();
+        let parse = |input| Command::parse(input, &symbols);
+
+        let any = |addr| Location::any_bank(Word::new(addr));
+        assert!(matches!(parse("b 0150"), Ok(Command::Break(l, None)) if l == any(0x150)));
+        assert!(matches!(parse("break Main"), Ok(Command::Break(l, None)) if l == any(0x150)));
+        assert!(matches!(parse("d 1ab"), Ok(Command::Delete(l)) if l == any(0x1ab)));
+        assert!(matches!(
+            parse("b 5:4123"),
+            Ok(Command::Break(l, None)) if l == Location::new(Word::new(0x4123), 5)
+        ));
+        assert!(matches!(
+            parse("b Loop"),
+            Ok(Command::Break(l, None)) if l == Location::new(Word::new(0x4000), 2)
+        ));
+        assert!(matches!(
+            parse("b Main if a == 3"),
+            Ok(Command::Break(l, Some(c))) if l == any(0x150) && c.source == "a == 3"
+        ));
+        assert!(matches!(parse("bo ff"), Ok(Command::BreakOpcode(OpcodeBreak::Opcode(0xFF)))));
+        assert!(matches!(
+            parse("bo RST 38H"),
+            Ok(Command::BreakOpcode(OpcodeBreak::Opcode(0xFF)))
+        ));
+        assert!(matches!(parse("bo stop"), Ok(Command::BreakOpcode(OpcodeBreak::Opcode(0x10)))));
+        assert!(matches!(
+            parse("bo ld a,b"),
+            Ok(Command::BreakOpcode(OpcodeBreak::Opcode(0x78)))
+        ));
+        assert!(matches!(
+            parse("bo CB 37"),
+            Ok(Command::BreakOpcode(OpcodeBreak::Prefixed(0x37)))
+        ));
+        assert!(matches!(
+            parse("bo swap a"),
+            Ok(Command::BreakOpcode(OpcodeBreak::Prefixed(0x37)))
+        ));
+        assert!(matches!(parse("do invalid"), Ok(Command::DeleteOpcode(OpcodeBreak::Invalid))));
+        assert!(parse("bo").is_err());
+        assert!(parse("bo 100").is_err());
+        assert!(parse("bo frobnicate").is_err());
+        assert!(matches!(
+            parse("x/4 c000"),
+            Ok(Command::Examine { addr, len: 4 }) if addr == Word::new(0xC000)
+        ));
+        assert!(matches!(parse("x ff80"), Ok(Command::Examine { len: 16, .. })));
+        assert!(matches!(parse("w ff40 w"), Ok(Command::Watch(w)) if !w.on_read && w.on_write));
+        assert!(matches!(
+            parse("set a=0x10"),
+            Ok(Command::Set { target: Target::Reg(Reg::A), value: Expr::Num(0x10) })
+        ));
+        assert!(matches!(parse("set [hl] = 3"), Ok(Command::Set { target: Target::Mem(_), .. })));
+        assert!(matches!(parse("  continue "), Ok(Command::Continue)));
+        assert!(matches!(parse("int"), Ok(Command::RunToInterrupt(None))));
+        assert!(matches!(
+            parse("int timer"),
+            Ok(Command::RunToInterrupt(Some(v))) if v == Word::new(0x50)
+        ));
+        assert!(parse("int nmi").is_err());
+        assert!(matches!(parse("display [Main]"), Ok(Command::Display { .. })));
+        assert!(matches!(parse("undisplay 2"), Ok(Command::Undisplay(2))));
+        assert!(matches!(
+            parse("disasm out.asm 3"),
+            Ok(Command::Disassemble { selection: Selection::Bank(3), .. })
+        ));
+        assert!(matches!(parse("project out"), Ok(Command::ExportProject(_))));
+        assert!(parse("project").is_err());
+        assert!(matches!(
+            parse("tiles vram.png"),
+            Ok(Command::ExportTiles { palette: Palette::Bg, .. })
+        ));
+        assert!(matches!(
+            parse("tiles vram.png obj1"),
+            Ok(Command::ExportTiles { palette: Palette::Obj1, .. })
+        ));
+        assert!(matches!(parse("tiles rom out"), Ok(Command::ExportRomTiles(_))));
+        assert!(parse("tiles vram.png obj2").is_err());
+        assert!(parse("tiles").is_err());
+        assert!(matches!(
+            parse("maps out viewport"),
+            Ok(Command::ExportMaps { viewport: true, .. })
+        ));
+        assert!(matches!(parse("maps out"), Ok(Command::ExportMaps { viewport: false, .. })));
+        assert!(parse("maps").is_err());
+
+        assert!(matches!(parse("profile on"), Ok(Command::Profile(ProfilerAction::Start))));
+        assert!(matches!(parse("heatmap on"), Ok(Command::Heatmap(HeatmapAction::Start))));
+        assert!(matches!(
+            parse("heatmap window 30"),
+            Ok(Command::Heatmap(HeatmapAction::Window(30)))
+        ));
+        assert!(matches!(
+            parse("heatmap save h.png"),
+            Ok(Command::Heatmap(HeatmapAction::Save(_)))
+        ));
+        assert!(parse("heatmap window 0").is_err());
+        assert!(parse("heatmap").is_err());
+
+        assert!(matches!(
+            parse("ramsearch inc"),
+            Ok(Command::RamSearch(RamSearchAction::Filter(Filter::Increased))),
+        ));
+        assert!(matches!(
+            parse("ramsearch by -3"),
+            Ok(Command::RamSearch(RamSearchAction::Filter(Filter::ChangedBy(-3)))),
+        ));
+        assert!(matches!(
+            parse("ramsearch watch 2"),
+            Ok(Command::RamSearch(RamSearchAction::Watch(2))),
+        ));
+        assert!(matches!(
+            parse("ramsearch freeze 0"),
+            Ok(Command::RamSearch(RamSearchAction::Freeze(0))),
+        ));
+        assert!(parse("ramsearch by x").is_err());
+        assert!(matches!(
+            parse("freeze c000 3"),
+            Ok(Command::Freeze { addr, value: Some(Expr::Num(3)) }) if addr == Word::new(0xC000)
+        ));
+        assert!(matches!(parse("freeze c000"), Ok(Command::Freeze { value: None, .. })));
+        assert!(matches!(parse("unfreeze c000"), Ok(Command::Unfreeze(_))));
+        assert!(parse("freeze").is_err());
+        assert!(parse("ramsearch").is_err());
+
+        assert!(matches!(parse("coverage reset"), Ok(Command::ResetCoverage)));
+        assert!(matches!(
+            parse("callgraph calls.dot cfg"),
+            Ok(Command::CallGraph { with_cfg: true, .. })
+        ));
+        assert!(matches!(
+            parse("callgraph calls.dot"),
+            Ok(Command::CallGraph { with_cfg: false, .. })
+        ));
+        assert!(parse("callgraph").is_err());
+
+        assert!(parse("b").is_err());
+        assert!(parse("b 5:0150").is_err());
+        assert!(parse("s 3").is_err());
+        assert!(parse("set x=1").is_err());
+        assert!(parse("x/a c000").is_err());
+        assert!(parse("frobnicate").is_err());
+    }
+}
@@ -0,0 +1,170 @@
+//! Tracking which ROM addresses were executed.
+
+use std::{
+    cell::RefCell,
+    fmt::Write as _,
+    fs,
+    path::Path,
+    rc::Rc,
+};
+
+use mahboi::{
+    instr::{INSTRUCTIONS, PREFIXED_INSTRUCTIONS},
+    machine::Machine,
+    primitives::Word,
+};
+use super::is_banked;
+
+
+/// Number of bytes per ROM bank.
+const BANK_SIZE: usize = 0x4000;
+
+/// Bitmaps of executed bytes, one per ROM bank. Every byte of an executed
+/// instruction is marked. Like `Breakpoints`, this is shared between the
+/// debugger and the ASM view: just `clone()` it.
+#[derive(Clone)]
+pub(crate) struct Coverage(Rc<RefCell<Vec<Vec<u64>>>>);
+
+impl Coverage {
+    pub(crate) fn new() -> Self {
+        Coverage(Rc::new(RefCell::new(Vec::new())))
+    }
+
+    /// Has to be called before every step.
+    pub(crate) fn observe(&self, machine: &Machine) {
+        let pc = machine.cpu.pc;
+        let in_bios = machine.bios_mounted() && pc.get() < 0x100;
+        if pc.get() >= 0x8000 || in_bios || !machine.executes_instruction_next() {
+            return;
+        }
+
+        let opcode = machine.peek_byte(pc);
+        let len = match INSTRUCTIONS[opcode] {
+            Some(instr) if instr.mnemonic == "PREFIX CB" => {
+                PREFIXED_INSTRUCTIONS[machine.peek_byte(pc + 1u16)].len
+            }
+            Some(instr) => instr.len,
+            None => 1,
+        };
+
+        let bank = bank_of(pc, machine.cartridge.rom_bank());
+        let mut banks = self.0.borrow_mut();
+        if banks.len() <= bank {
+            banks.resize(bank + 1, Vec::new());
+        }
+        let bitmap = &mut banks[bank];
+        if bitmap.is_empty() {
+            bitmap.resize(BANK_SIZE / 64, 0);
+        }
+
+        // An instruction at the very end of a bank is cut off at the bank
+        // boundary.
+        let start = pc.get() as usize % BANK_SIZE;
+        for offset in (start..start + len as usize).take_while(|&o| o < BANK_SIZE) {
+            bitmap[offset / 64] |= 1 << (offset % 64);
+        }
+    }
+
+    /// Returns whether the byte at `addr` in the given ROM bank was executed.
+    /// Returns `None` if `addr` is not in ROM.
+    pub(crate) fn is_executed(&self, addr: Word, rom_bank: usize) -> Option<bool> {
+        if addr.get() >= 0x8000 {
+            return None;
+        }
+
+        let offset = addr.get() as usize % BANK_SIZE;
+        let banks = self.0.borrow();
+        let executed = banks.get(bank_of(addr, rom_bank))
+            .and_then(|bitmap| bitmap.get(offset / 64))
+            .is_some_and(|chunk| chunk & (1 << (offset % 64)) != 0);
+
+        Some(executed)
+    }
+
+    /// Forgets all executed addresses.
+    pub(crate) fn reset(&self) {
+        self.0.borrow_mut().clear();
+    }
+
+    /// Returns all ranges (inclusive) of executed bytes as `(bank, start,
+    /// end)`.
+    pub(crate) fn ranges(&self) -> Vec<(usize, Word, Word)> {
+        let banks = self.0.borrow();
+        let mut out = Vec::new();
+
+        for (bank, bitmap) in banks.iter().enumerate() {
+            let base = if bank == 0 { 0 } else { BANK_SIZE };
+            let addr = |offset: usize| Word::new((base + offset) as u16);
+            let is_set = |offset: usize| bitmap[offset / 64] & (1 << (offset % 64)) != 0;
+
+            let mut start = None;
+            for offset in 0..bitmap.len() * 64 {
+                match (start, is_set(offset)) {
+                    (None, true) => start = Some(offset),
+                    (Some(s), false) => {
+                        out.push((bank, addr(s), addr(offset - 1)));
+                        start = None;
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(s) = start {
+                out.push((bank, addr(s), addr(BANK_SIZE - 1)));
+            }
+        }
+
+        out
+    }
+
+    /// Writes all executed ranges to a text file, one `BB:AAAA-AAAA` range
+    /// per line.
+    pub(crate) fn export(&self, path: &Path) -> Result<usize, String> {
+        let ranges = self.ranges();
+        let mut out = String::from("; Executed ROM addresses (bank:start-end)\n");
+        for &(bank, start, end) in &ranges {
+            writeln!(out, "{:02X}:{:04X}-{:04X}", bank, start.get(), end.get()).unwrap();
+        }
+
+        fs::write(path, out).map_err(|e| format!("failed to write '{}': {}", path.display(), e))?;
+        Ok(ranges.len())
+    }
+}
+
+/// Returns the index of the bitmap for `addr`.
+fn bank_of(addr: Word, rom_bank: usize) -> usize {
+    if is_banked(addr) { rom_bank } else { 0 }
+}
+
+
+#[cfg(test)]
+mod test {
+    use mahboi::{BiosKind, Emulator, HardwareModel, cartridge::Cartridge};
+    use super::*;
+
+    #[test]
+    fn test_coverage() {
+        // `LD BC, d16` at 0x0150 and `NOP` at 0x4000
+        let mut rom = vec![0; 0x8000];
+        rom[0x150] = 0x01;
+        let cartridge = Cartridge::from_bytes(&rom).unwrap();
+        let mut emulator = Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        let machine = emulator.machine_mut();
+        let coverage = Coverage::new();
+
+        machine.cpu.pc = Word::new(0x150);
+        coverage.observe(machine);
+        machine.cpu.pc = Word::new(0x4000);
+        coverage.observe(machine);
+
+        assert_eq!(coverage.is_executed(Word::new(0x14f), 1), Some(false));
+        assert_eq!(coverage.is_executed(Word::new(0x152), 1), Some(true));
+        assert_eq!(coverage.is_executed(Word::new(0x153), 1), Some(false));
+        assert_eq!(coverage.is_executed(Word::new(0x4000), 1), Some(true));
+        assert_eq!(coverage.is_executed(Word::new(0x4000), 2), Some(false));
+        assert_eq!(coverage.is_executed(Word::new(0xC000), 1), None);
+        assert_eq!(coverage.ranges(), [
+            (0, Word::new(0x150), Word::new(0x152)),
+            (1, Word::new(0x4000), Word::new(0x4000)),
+        ]);
+    }
+}
@@ -0,0 +1,496 @@
+//! A tiny expression language used for breakpoint conditions and similar
+//! things.
+//!
+//! Expressions are evaluated against a `Machine` and always result in an
+//! integer. Like in C, `0` is false and everything else is true. Supported
+//! are:
+//!
+//! - Numbers: decimal (`42`) or hexadecimal (`0x2A` or `$2A`)
+//! - Registers: `A`, `F`, `B`, `C`, `D`, `E`, `H`, `L`, `AF`, `BC`, `DE`,
+//!   `HL`, `SP` and `PC` (case insensitive)
+//! - Flags: `zf`, `nf`, `hf` and `cf` (`0` or `1`)
+//! - Symbols (from a symbol file): evaluate to their address, so `[wFoo]`
+//!   loads the byte stored at `wFoo`
+//! - Memory: `[HL]` loads the byte at the address `HL`
+//! - Operators (from lowest to highest precedence): `||`, `&&`, `==` `!=`,
+//!   `<` `<=` `>` `>=`, `|`, `^`, `&`, `<<` `>>`, `+` `-`, `*` `/` `%` and the
+//!   unary `!`, `-` and `~`.
+
+use std::fmt;
+
+use mahboi::{
+    machine::{Machine, cpu::Cpu},
+    primitives::{Byte, Word},
+};
+use crate::symbols::Symbols;
+
+
+/// A parsed expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Expr {
+    Num(i64),
+    Reg(Reg),
+
+    /// A symbol, evaluating to its address.
+    Sym(String, Word),
+
+    /// Loads the byte at the address the inner expression evaluates to.
+    Mem(Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+/// Everything that can be referred to by name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Reg {
+    A, F, B, C, D, E, H, L,
+    Af, Bc, De, Hl, Sp, Pc,
+    FlagZ, FlagN, FlagH, FlagC,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UnaryOp {
+    Not,
+    Neg,
+    BitNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BinOp {
+    Or, And,
+    Eq, Ne,
+    Lt, Le, Gt, Ge,
+    BitOr, BitXor, BitAnd,
+    Shl, Shr,
+    Add, Sub,
+    Mul, Div, Rem,
+}
+
+impl BinOp {
+    /// All binary operators, grouped by precedence (lowest first).
+    const LEVELS: &'static [&'static [(&'static str, BinOp)]] = &[
+        &[("||", BinOp::Or)],
+        &[("&&", BinOp::And)],
+        &[("==", BinOp::Eq), ("!=", BinOp::Ne)],
+        &[("<=", BinOp::Le), (">=", BinOp::Ge), ("<", BinOp::Lt), (">", BinOp::Gt)],
+        &[("|", BinOp::BitOr)],
+        &[("^", BinOp::BitXor)],
+        &[("&", BinOp::BitAnd)],
+        &[("<<", BinOp::Shl), (">>", BinOp::Shr)],
+        &[("+", BinOp::Add), ("-", BinOp::Sub)],
+        &[("*", BinOp::Mul), ("/", BinOp::Div), ("%", BinOp::Rem)],
+    ];
+}
+
+impl Reg {
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        let reg = match &*name.to_ascii_lowercase() {
+            "a" => Reg::A,
+            "f" => Reg::F,
+            "b" => Reg::B,
+            "c" => Reg::C,
+            "d" => Reg::D,
+            "e" => Reg::E,
+            "h" => Reg::H,
+            "l" => Reg::L,
+            "af" => Reg::Af,
+            "bc" => Reg::Bc,
+            "de" => Reg::De,
+            "hl" => Reg::Hl,
+            "sp" => Reg::Sp,
+            "pc" => Reg::Pc,
+            "zf" => Reg::FlagZ,
+            "nf" => Reg::FlagN,
+            "hf" => Reg::FlagH,
+            "cf" => Reg::FlagC,
+            _ => return None,
+        };
+
+        Some(reg)
+    }
+
+    fn value(self, machine: &Machine) -> i64 {
+        let cpu = &machine.cpu;
+        let v = match self {
+            Reg::A => cpu.a.get() as u16,
+            Reg::F => cpu.f.get() as u16,
+            Reg::B => cpu.b.get() as u16,
+            Reg::C => cpu.c.get() as u16,
+            Reg::D => cpu.d.get() as u16,
+            Reg::E => cpu.e.get() as u16,
+            Reg::H => cpu.h.get() as u16,
+            Reg::L => cpu.l.get() as u16,
+            Reg::Af => cpu.af().get(),
+            Reg::Bc => cpu.bc().get(),
+            Reg::De => cpu.de().get(),
+            Reg::Hl => cpu.hl().get(),
+            Reg::Sp => cpu.sp.get(),
+            Reg::Pc => cpu.pc.get(),
+            Reg::FlagZ => cpu.zero() as u16,
+            Reg::FlagN => cpu.subtract() as u16,
+            Reg::FlagH => cpu.half_carry() as u16,
+            Reg::FlagC => cpu.carry() as u16,
+        };
+
+        v as i64
+    }
+
+    /// Sets the register (or flag) to `value`. The value is truncated to the
+    /// size of the register. For flags, every non-zero value sets the flag.
+    pub(crate) fn set(self, machine: &mut Machine, value: u16) {
+        let cpu = &mut machine.cpu;
+        let byte = Byte::new(value as u8);
+        let flag = |cpu: &mut Cpu, mask: u8| {
+            let f = if value != 0 { cpu.f.get() | mask } else { cpu.f.get() & !mask };
+            cpu.f = Byte::new(f);
+        };
+
+        match self {
+            Reg::A => cpu.a = byte,
+            Reg::F => cpu.f = Byte::new(value as u8 & 0xF0),
+            Reg::B => cpu.b = byte,
+            Reg::C => cpu.c = byte,
+            Reg::D => cpu.d = byte,
+            Reg::E => cpu.e = byte,
+            Reg::H => cpu.h = byte,
+            Reg::L => cpu.l = byte,
+            Reg::Af => cpu.set_af(Word::new(value)),
+            Reg::Bc => cpu.set_bc(Word::new(value)),
+            Reg::De => cpu.set_de(Word::new(value)),
+            Reg::Hl => cpu.set_hl(Word::new(value)),
+            Reg::Sp => cpu.sp = Word::new(value),
+            Reg::Pc => cpu.pc = Word::new(value),
+            Reg::FlagZ => flag(cpu, 0b1000_0000),
+            Reg::FlagN => flag(cpu, 0b0100_0000),
+            Reg::FlagH => flag(cpu, 0b0010_0000),
+            Reg::FlagC => flag(cpu, 0b0001_0000),
+        }
+    }
+}
+
+impl Expr {
+    /// Parses the given string as expression. Names that are not registers
+    /// are looked up in `symbols`.
+    pub(crate) fn parse(src: &str, symbols: &Symbols) -> Result<Self, ParseError> {
+        let tokens = tokenize(src)?;
+        let mut parser = Parser { tokens: &tokens, pos: 0, symbols };
+        let expr = parser.parse_binary(0)?;
+
+        match parser.peek() {
+            None => Ok(expr),
+            Some(t) => Err(ParseError(format!("unexpected '{}' after expression", t))),
+        }
+    }
+
+    /// Evaluates the expression. Memory is read via `Machine::peek_byte`.
+    pub(crate) fn eval(&self, machine: &Machine) -> Result<i64, EvalError> {
+        let v = match self {
+            Expr::Num(n) => *n,
+            Expr::Reg(r) => r.value(machine),
+            Expr::Sym(_, addr) => addr.get() as i64,
+            Expr::Mem(addr) => {
+                let addr = addr.eval(machine)?;
+                if !(0..=0xFFFF).contains(&addr) {
+                    return Err(EvalError(format!("address {:#x} out of range", addr)));
+                }
+                machine.peek_byte(Word::new(addr as u16)).get() as i64
+            }
+            Expr::Unary(op, inner) => {
+                let v = inner.eval(machine)?;
+                match op {
+                    UnaryOp::Not => (v == 0) as i64,
+                    UnaryOp::Neg => v.wrapping_neg(),
+                    UnaryOp::BitNot => !v,
+                }
+            }
+
+            // Short circuiting operators
+            Expr::Binary(BinOp::And, lhs, rhs) => {
+                (lhs.eval(machine)? != 0 && rhs.eval(machine)? != 0) as i64
+            }
+            Expr::Binary(BinOp::Or, lhs, rhs) => {
+                (lhs.eval(machine)? != 0 || rhs.eval(machine)? != 0) as i64
+            }
+
+            Expr::Binary(op, lhs, rhs) => {
+                let l = lhs.eval(machine)?;
+                let r = rhs.eval(machine)?;
+                match op {
+                    BinOp::Eq => (l == r) as i64,
+                    BinOp::Ne => (l != r) as i64,
+                    BinOp::Lt => (l < r) as i64,
+                    BinOp::Le => (l <= r) as i64,
+                    BinOp::Gt => (l > r) as i64,
+                    BinOp::Ge => (l >= r) as i64,
+                    BinOp::BitOr => l | r,
+                    BinOp::BitXor => l ^ r,
+                    BinOp::BitAnd => l & r,
+                    BinOp::Shl => l.wrapping_shl(r as u32),
+                    BinOp::Shr => l.wrapping_shr(r as u32),
+                    BinOp::Add => l.wrapping_add(r),
+                    BinOp::Sub => l.wrapping_sub(r),
+                    BinOp::Mul => l.wrapping_mul(r),
+                    BinOp::Div | BinOp::Rem if r == 0 => {
+                        return Err(EvalError("division by zero".into()));
+                    }
+                    BinOp::Div => l.wrapping_div(r),
+                    BinOp::Rem => l.wrapping_rem(r),
+                    BinOp::And | BinOp::Or => unreachable!(),
+                }
+            }
+        };
+
+        Ok(v)
+    }
+}
+
+/// Error while parsing an expression. Contains a human readable message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ParseError(String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Error while evaluating an expression. Contains a human readable message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct EvalError(String);
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+
+// ============================================================================
+// ===== Tokenizer and parser
+// ============================================================================
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Num(i64),
+    Ident(String),
+
+    /// Operators and brackets.
+    Punct(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Num(n) => n.fmt(f),
+            Token::Ident(s) => s.fmt(f),
+            Token::Punct(p) => p.fmt(f),
+        }
+    }
+}
+
+/// All punctuation tokens. Longer ones have to come first.
+const PUNCTS: &[&str] = &[
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>",
+    "<", ">", "|", "^", "&", "+", "-", "*", "/", "%", "!", "~",
+    "(", ")", "[", "]",
+];
+
+fn tokenize(src: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut rest = src.trim_start();
+
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+
+        if c.is_ascii_digit() || c == '$' {
+            // Numbers
+            let (digits, radix) = if let Some(hex) = rest.strip_prefix('$') {
+                (hex, 16)
+            } else if let Some(hex) = rest.strip_prefix("0x").or(rest.strip_prefix("0X")) {
+                (hex, 16)
+            } else {
+                (rest, 10)
+            };
+
+            let len = digits.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(digits.len());
+            let n = i64::from_str_radix(&digits[..len], radix)
+                .map_err(|_| ParseError(format!("invalid number '{}'", &digits[..len])))?;
+            tokens.push(Token::Num(n));
+            rest = &digits[len..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            // Identifiers. Symbol names can contain `.` and `:` (e.g.
+            // `Main.loop` or `Main::vblank`).
+            let len = rest.find(|c: char| !(c.is_ascii_alphanumeric() || "_.:".contains(c)))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..len].to_string()));
+            rest = &rest[len..];
+        } else {
+            // Operators
+            let p = PUNCTS.iter()
+                .find(|p| rest.starts_with(*p))
+                .ok_or_else(|| ParseError(format!("unexpected character '{}'", c)))?;
+            tokens.push(Token::Punct(p));
+            rest = &rest[p.len()..];
+        }
+
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    symbols: &'a Symbols,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        let t = self.tokens.get(self.pos);
+        self.pos += 1;
+        t
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Punct(p)) if *p == punct) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), ParseError> {
+        if self.eat(punct) {
+            Ok(())
+        } else {
+            Err(ParseError(format!("expected '{}'", punct)))
+        }
+    }
+
+    /// Parses a binary expression with operators of the given precedence level
+    /// or higher.
+    fn parse_binary(&mut self, level: usize) -> Result<Expr, ParseError> {
+        if level == BinOp::LEVELS.len() {
+            return self.parse_unary();
+        }
+
+        let mut lhs = self.parse_binary(level + 1)?;
+        'outer: loop {
+            for &(punct, op) in BinOp::LEVELS[level] {
+                if self.eat(punct) {
+                    let rhs = self.parse_binary(level + 1)?;
+                    lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+                    continue 'outer;
+                }
+            }
+
+            return Ok(lhs);
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ParseError> {
+        let op = match self.peek() {
+            Some(Token::Punct("!")) => UnaryOp::Not,
+            Some(Token::Punct("-")) => UnaryOp::Neg,
+            Some(Token::Punct("~")) => UnaryOp::BitNot,
+            _ => return self.parse_primary(),
+        };
+
+        self.pos += 1;
+        Ok(Expr::Unary(op, Box::new(self.parse_unary()?)))
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, ParseError> {
+        match self.next().cloned() {
+            Some(Token::Num(n)) => Ok(Expr::Num(n)),
+            Some(Token::Ident(name)) => {
+                if let Some(reg) = Reg::from_name(&name) {
+                    return Ok(Expr::Reg(reg));
+                }
+
+                self.symbols.get(&name)
+                    .map(|(_, addr)| Expr::Sym(name.clone(), addr))
+                    .ok_or_else(|| ParseError(format!("unknown name '{}'", name)))
+            }
+            Some(Token::Punct("(")) => {
+                let inner = self.parse_binary(0)?;
+                self.expect(")")?;
+                Ok(inner)
+            }
+            Some(Token::Punct("[")) => {
+                let inner = self.parse_binary(0)?;
+                self.expect("]")?;
+                Ok(Expr::Mem(Box::new(inner)))
+            }
+            Some(t) => Err(ParseError(format!("unexpected '{}'", t))),
+            None => Err(ParseError("unexpected end of expression".into())),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use mahboi::{
+        BiosKind, Emulator, HardwareModel,
+        cartridge::Cartridge,
+        primitives::Byte,
+    };
+    use super::*;
+
+    fn machine() -> Emulator {
+        let cartridge = Cartridge::from_bytes(&[0; 0x8000]).unwrap();
+        let mut emulator = Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        let cpu = &mut emulator.machine_mut().cpu;
+        cpu.a = Byte::new(0x3F);
+        cpu.set_hl(Word::new(0xC000));
+        emulator.machine_mut().store_byte(Word::new(0xC000), Byte::new(7));
+        emulator
+    }
+
+    #[test]
+    fn test_eval() {
+        let emulator = machine();
+        let symbols = Symbols::parse("00:C000 wFoo\n01:4000 Main::loop").unwrap();
+        let run = |src: &str| {
+            Expr::parse(src, &symbols).unwrap().eval(emulator.machine()).unwrap()
+        };
+
+        assert_eq!(run("42"), 42);
+        assert_eq!(run("0x2a + $2A"), 84);
+        assert_eq!(run("1 + 2 * 3"), 7);
+        assert_eq!(run("(1 + 2) * 3"), 9);
+        assert_eq!(run("a"), 0x3F);
+        assert_eq!(run("HL"), 0xC000);
+        assert_eq!(run("[HL]"), 7);
+        assert_eq!(run("[hl + 1 - 1] > 4"), 1);
+        assert_eq!(run("A == 0x3F && [HL] > 4"), 1);
+        assert_eq!(run("A != 0x3F || [HL] > 8"), 0);
+        assert_eq!(run("!A"), 0);
+        assert_eq!(run("-1 < 0"), 1);
+        assert_eq!(run("A & 0xF0 | 1 << 1"), 0x32);
+        assert_eq!(run("wFoo + 1"), 0xC001);
+        assert_eq!(run("[wFoo]"), 7);
+        assert_eq!(run("Main::loop"), 0x4000);
+    }
+
+    #[test]
+    fn test_errors() {
+        let emulator = machine();
+        let parse = |src| Expr::parse(src, &Symbols::default());
+
+        assert!(parse("").is_err());
+        assert!(parse("A ==").is_err());
+        assert!(parse("[HL").is_err());
+        assert!(parse("foo").is_err());
+        assert!(parse("1 2").is_err());
+        assert!(parse("0xZZ").is_err());
+        assert!(parse("1 / 0").unwrap().eval(emulator.machine()).is_err());
+    }
+}
@@ -0,0 +1,264 @@
+//! A heatmap of memory accesses, shown in the "Heatmap" tab.
+//!
+//! The machine counts the reads and writes of each address. Whenever a time
+//! window (60 frames by default, see `heatmap window`) is over, the counts are
+//! copied into the heatmap and reset, so the tab shows the last complete
+//! window. The tab shows one cell per 256 byte page and the most accessed
+//! addresses. In the image written by `heatmap save`, each address is one
+//! pixel (one row per page) with writes in red and reads in green.
+
+use std::{
+    cmp::Reverse,
+    fs::File,
+    io::BufWriter,
+    path::Path,
+};
+
+use cursive::{
+    theme::{BaseColor, Color},
+    utils::markup::StyledString,
+};
+use failure::{Error, ResultExt};
+
+use mahboi::{
+    machine::{Machine, hooks::AccessCounts},
+    primitives::{Word, CYCLES_PER_FRAME},
+};
+use crate::symbols::Symbols;
+
+
+/// Default length of a time window in frames.
+const DEFAULT_WINDOW: u64 = 60;
+
+/// Number of addresses in the list of the report.
+const REPORT_LEN: usize = 30;
+
+/// The characters used for the cells of the report, by increasing number of
+/// accesses.
+const SHADES: &[u8] = b" .:-=+*#%@";
+
+/// The counts of one time window, indexed by address.
+#[derive(Clone)]
+struct Window {
+    reads: Vec<u32>,
+    writes: Vec<u32>,
+}
+
+impl Window {
+    fn from_counts(counts: &AccessCounts) -> Self {
+        let addrs = || (0..=0xFFFF).map(Word::new);
+        Self {
+            reads: addrs().map(|a| counts.reads(a)).collect(),
+            writes: addrs().map(|a| counts.writes(a)).collect(),
+        }
+    }
+}
+
+pub(crate) struct Heatmap {
+    enabled: bool,
+
+    /// Length of a time window in frames.
+    frames: u64,
+
+    /// The cycle count at the start of the current window.
+    window_start: Option<u64>,
+
+    /// The last complete window.
+    last: Option<Window>,
+}
+
+impl Heatmap {
+    pub(crate) fn new() -> Self {
+        Self {
+            enabled: false,
+            frames: DEFAULT_WINDOW,
+            window_start: None,
+            last: None,
+        }
+    }
+
+    pub(crate) fn set_enabled(&mut self, machine: &mut Machine, enabled: bool) {
+        self.enabled = enabled;
+        self.window_start = None;
+        machine.set_access_counting(enabled);
+    }
+
+    /// Removes all collected data and starts a new window.
+    pub(crate) fn reset(&mut self, machine: &Machine) {
+        self.window_start = None;
+        self.last = None;
+        if let Some(counts) = machine.access_counts() {
+            counts.reset();
+        }
+    }
+
+    pub(crate) fn set_window(&mut self, frames: u64) {
+        self.frames = frames;
+    }
+
+    /// Has to be called before every step.
+    pub(crate) fn observe(&mut self, machine: &Machine) {
+        let counts = match machine.access_counts() {
+            Some(counts) if self.enabled => counts,
+            _ => return,
+        };
+
+        // When stepping back, the cycle count decreases. We just start a new
+        // window then.
+        let cycle_count = machine.cycle_count();
+        let start = *self.window_start.get_or_insert(cycle_count);
+        if cycle_count < start {
+            self.window_start = Some(cycle_count);
+        } else if cycle_count - start >= self.frames * CYCLES_PER_FRAME {
+            self.last = Some(Window::from_counts(counts));
+            counts.reset();
+            self.window_start = Some(cycle_count);
+        }
+    }
+
+    /// Returns the last complete window or, if there is none yet, the
+    /// current one.
+    fn window(&self, machine: &Machine) -> Option<Window> {
+        match &self.last {
+            Some(last) => Some(last.clone()),
+            None => machine.access_counts().map(Window::from_counts),
+        }
+    }
+
+    /// Returns the accesses per page and the most accessed addresses as text.
+    pub(crate) fn report(&self, machine: &Machine, symbols: &Symbols) -> StyledString {
+        let mut out = StyledString::new();
+        let window = match self.window(machine) {
+            Some(window) if self.enabled || self.last.is_some() => window,
+            _ => {
+                out.append_plain("The heatmap is disabled. Start it with the button above or \
+                    with `heatmap on` in the console.");
+                return out;
+            }
+        };
+
+        out.append_plain(format!(
+            "Window: {} frames{}\n",
+            self.frames,
+            if self.last.is_some() { "" } else { " (current window, incomplete)" },
+        ));
+
+        // One cell per page, 16 pages per row. Cells with more writes than
+        // reads are red.
+        let page = |p: usize, counts: &[u32]| counts[p * 256..][..256].iter().sum::<u32>();
+        let pages = (0..256)
+            .map(|p| (page(p, &window.reads), page(p, &window.writes)))
+            .collect::<Vec<_>>();
+        let max = pages.iter().map(|(r, w)| r + w).max().unwrap_or(0);
+
+        out.append_styled("\nPages (red: mostly writes)\n", Color::Light(BaseColor::Green));
+        for (row, pages) in pages.chunks(16).enumerate() {
+            out.append_styled(format!("{:04X} ", row * 0x1000), Color::Light(BaseColor::Blue));
+            for &(reads, writes) in pages {
+                let level = (intensity(reads + writes, max) * (SHADES.len() - 1) as f64).ceil();
+                let c = SHADES[level as usize] as char;
+                let color = if writes > reads { BaseColor::Red } else { BaseColor::Green };
+                out.append_styled(format!("{}{}", c, c), Color::Light(color));
+            }
+            out.append_plain("\n");
+        }
+
+        out.append_styled("\nAddresses\n", Color::Light(BaseColor::Green));
+        out.append_plain(format!("{:>10} {:>10}\n", "reads", "writes"));
+        let mut addrs = (0..0x10000usize)
+            .filter(|&a| window.reads[a] + window.writes[a] > 0)
+            .collect::<Vec<_>>();
+        addrs.sort_by_key(|&a| Reverse(window.reads[a] + window.writes[a]));
+
+        let rom_bank = machine.cartridge.rom_bank();
+        for a in addrs.into_iter().take(REPORT_LEN) {
+            let addr = Word::new(a as u16);
+            out.append_styled(format!("{:>10} ", window.reads[a]), Color::Light(BaseColor::Green));
+            out.append_styled(format!("{:>10}  ", window.writes[a]), Color::Light(BaseColor::Red));
+            out.append_plain(addr.to_string());
+            match symbols.nearest(addr, rom_bank) {
+                Some((name, 0)) => out.append_plain(format!(" ({})", name)),
+                Some((name, offset)) => out.append_plain(format!(" ({}+{})", name, offset)),
+                None => {}
+            }
+            out.append_plain("\n");
+        }
+
+        out
+    }
+
+    /// Writes the heatmap as 256x256 PNG image.
+    pub(crate) fn save(&self, path: &Path, machine: &Machine) -> Result<(), Error> {
+        let window = self.window(machine)
+            .ok_or_else(|| failure::err_msg("the heatmap is disabled"))?;
+
+        let max_reads = window.reads.iter().copied().max().unwrap_or(0);
+        let max_writes = window.writes.iter().copied().max().unwrap_or(0);
+        let mut pixels = Vec::with_capacity(0x10000 * 3);
+        for (&reads, &writes) in window.reads.iter().zip(&window.writes) {
+            pixels.push((intensity(writes, max_writes) * 255.0) as u8);
+            pixels.push((intensity(reads, max_reads) * 255.0) as u8);
+            pixels.push(0);
+        }
+
+        let file = File::create(path)
+            .context(format!("failed to create '{}'", path.display()))?;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), 256, 256);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&pixels)?;
+
+        Ok(())
+    }
+}
+
+/// Maps `count` to `0.0..=1.0` logarithmically, such that only 0 maps to 0.
+fn intensity(count: u32, max: u32) -> f64 {
+    if count == 0 {
+        0.0
+    } else {
+        (count as f64).ln_1p() / (max as f64).ln_1p()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use mahboi::{
+        BiosKind, Emulator, HardwareModel,
+        test_util::{NullPeripherals, cartridge_with_code},
+    };
+    use super::*;
+
+    #[test]
+    fn test_window() {
+        // 0150: LD (C000), A; JR -5
+        let cartridge = cartridge_with_code(&[0xEA, 0x00, 0xC0, 0x18, 0xFB]);
+        let mut emulator = Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        let machine = emulator.machine_mut();
+        machine.cpu.pc = Word::new(0x150);
+
+        let mut heatmap = Heatmap::new();
+        heatmap.set_enabled(machine, true);
+        heatmap.set_window(1);
+        loop {
+            heatmap.observe(machine);
+            if heatmap.last.is_some() {
+                break;
+            }
+            machine.execute_step(&mut NullPeripherals).ok().unwrap();
+        }
+
+        // Each loop iteration (7 cycles) writes once. Instruction fetches
+        // are not counted. The counts of the machine start over.
+        let last = heatmap.last.as_ref().unwrap();
+        let loops = CYCLES_PER_FRAME as u32 / 7;
+        assert!((loops..=loops + 1).contains(&last.writes[0xC000]));
+        assert_eq!(last.reads[0xC000], 0);
+        assert_eq!(last.reads[0x150], 0);
+        assert_eq!(machine.access_counts().unwrap().writes(Word::new(0xC000)), 0);
+
+        let report = heatmap.report(machine, &Symbols::parse("00:c000 wCounter").unwrap());
+        assert!(report.source().contains("(wCounter)"));
+    }
+}
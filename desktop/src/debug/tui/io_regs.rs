@@ -0,0 +1,273 @@
+//! Names and decoding of all IO registers (`0xFF00..=0xFF7F` and IE).
+
+use cursive::{
+    theme::{BaseColor, Color},
+    utils::markup::StyledString,
+};
+
+use mahboi::{
+    machine::Machine,
+    primitives::Word,
+};
+
+
+/// Description of one IO register.
+struct IoReg {
+    addr: u16,
+    name: &'static str,
+
+    /// Function to decode the value into a human readable description. `None`
+    /// if there is nothing to decode (e.g. for plain data registers).
+    decode: Option<fn(u8) -> String>,
+}
+
+macro_rules! regs {
+    ($($addr:literal $name:literal $($decode:expr)?;)*) => {
+        &[$(
+            IoReg {
+                addr: $addr,
+                name: $name,
+                decode: regs!(@decode $($decode)?),
+            },
+        )*]
+    };
+    (@decode) => { None };
+    (@decode $decode:expr) => { Some($decode) };
+}
+
+/// All IO registers, sorted by address. Registers that only exist on the CGB
+/// are listed, but not decoded.
+const IO_REGS: &[IoReg] = regs! {
+    0xFF00 "JOYP" decode_joyp;
+    0xFF01 "SB";
+    0xFF02 "SC" decode_sc;
+    0xFF04 "DIV" decimal;
+    0xFF05 "TIMA" decimal;
+    0xFF06 "TMA" decimal;
+    0xFF07 "TAC" decode_tac;
+    0xFF0F "IF" decode_interrupts;
+
+    0xFF10 "NR10" decode_nr10;
+    0xFF11 "NR11" decode_duty_length;
+    0xFF12 "NR12" decode_envelope;
+    0xFF13 "NR13";
+    0xFF14 "NR14" decode_freq_high;
+    0xFF16 "NR21" decode_duty_length;
+    0xFF17 "NR22" decode_envelope;
+    0xFF18 "NR23";
+    0xFF19 "NR24" decode_freq_high;
+    0xFF1A "NR30" decode_nr30;
+    0xFF1B "NR31" decimal;
+    0xFF1C "NR32" decode_nr32;
+    0xFF1D "NR33";
+    0xFF1E "NR34" decode_freq_high;
+    0xFF20 "NR41" decode_length;
+    0xFF21 "NR42" decode_envelope;
+    0xFF22 "NR43" decode_nr43;
+    0xFF23 "NR44" decode_freq_high;
+    0xFF24 "NR50" decode_nr50;
+    0xFF25 "NR51" decode_nr51;
+    0xFF26 "NR52" decode_nr52;
+
+    0xFF40 "LCDC" decode_lcdc;
+    0xFF41 "STAT" decode_stat;
+    0xFF42 "SCY" decimal;
+    0xFF43 "SCX" decimal;
+    0xFF44 "LY" decimal;
+    0xFF45 "LYC" decimal;
+    0xFF46 "DMA" decode_dma;
+    0xFF47 "BGP" decode_palette;
+    0xFF48 "OBP0" decode_palette;
+    0xFF49 "OBP1" decode_palette;
+    0xFF4A "WY" decimal;
+    0xFF4B "WX" decimal;
+
+    0xFF4D "KEY1";
+    0xFF4F "VBK";
+    0xFF50 "BOOT" decode_boot;
+    0xFF51 "HDMA1";
+    0xFF52 "HDMA2";
+    0xFF53 "HDMA3";
+    0xFF54 "HDMA4";
+    0xFF55 "HDMA5";
+    0xFF56 "RP";
+    0xFF68 "BCPS";
+    0xFF69 "BCPD";
+    0xFF6A "OCPS";
+    0xFF6B "OCPD";
+    0xFF70 "SVBK";
+
+    0xFFFF "IE" decode_interrupts;
+};
+
+/// Creates the content of the IO register view: one line per register with
+/// address, name, value and decoded meaning. The wave RAM is shown in one
+/// line.
+pub(crate) fn io_register_text(machine: &Machine) -> StyledString {
+    let value_style = Color::Light(BaseColor::Magenta);
+    let load = |addr: u16| machine.peek_byte(Word::new(addr)).get();
+
+    let mut body = StyledString::new();
+    for reg in IO_REGS {
+        // The wave RAM comes right after the sound registers
+        if reg.addr == 0xFF40 {
+            body.append_plain("FF30 WAVE  ");
+            let wave = (0xFF30..=0xFF3F).map(|a| format!("{:02x}", load(a))).collect::<String>();
+            body.append_styled(wave, value_style);
+            body.append_plain("\n");
+        }
+
+        let value = load(reg.addr);
+        body.append_plain(format!("{:04X} {: <5} ", reg.addr, reg.name));
+        body.append_styled(format!("{:02x}", value), value_style);
+        if let Some(decode) = reg.decode {
+            body.append_plain("  ");
+            body.append_plain(decode(value));
+        }
+        body.append_plain("\n");
+    }
+
+    body
+}
+
+
+// ===== Decoding functions ===================================================
+
+fn bit(value: u8, bit: u8) -> bool {
+    (value >> bit) & 1 == 1
+}
+
+fn on_off(b: bool) -> &'static str {
+    if b { "on" } else { "off" }
+}
+
+fn decimal(v: u8) -> String {
+    v.to_string()
+}
+
+fn decode_joyp(v: u8) -> String {
+    let select = match (bit(v, 5), bit(v, 4)) {
+        (false, false) => "btn+dir",
+        (false, true) => "btn",
+        (true, false) => "dir",
+        (true, true) => "none",
+    };
+
+    // A cleared bit means "pressed"
+    let pressed = (0..4).filter(|&i| !bit(v, i)).map(|i| i.to_string()).collect::<Vec<_>>();
+    format!("sel:{} pressed:[{}]", select, pressed.join(","))
+}
+
+fn decode_sc(v: u8) -> String {
+    let clock = if bit(v, 0) { "internal" } else { "external" };
+    format!("transfer:{} clock:{}", on_off(bit(v, 7)), clock)
+}
+
+fn decode_tac(v: u8) -> String {
+    let freq = match v & 0b11 {
+        0b00 => 4096,
+        0b01 => 262144,
+        0b10 => 65536,
+        _ => 16384,
+    };
+    format!("{} {}Hz", on_off(bit(v, 2)), freq)
+}
+
+fn decode_interrupts(v: u8) -> String {
+    let names = ["VBlank", "STAT", "Timer", "Serial", "Joypad"];
+    let set = (0..5).filter(|&i| bit(v, i)).map(|i| names[i as usize]).collect::<Vec<_>>();
+    if set.is_empty() {
+        "-".into()
+    } else {
+        set.join(" ")
+    }
+}
+
+pub(super) fn decode_nr10(v: u8) -> String {
+    let dir = if bit(v, 3) { "down" } else { "up" };
+    format!("sweep time:{} {} shift:{}", (v >> 4) & 0b111, dir, v & 0b111)
+}
+
+fn decode_duty_length(v: u8) -> String {
+    let duty = ["12.5%", "25%", "50%", "75%"][(v >> 6) as usize];
+    format!("duty:{} len:{}", duty, v & 0x3F)
+}
+
+fn decode_length(v: u8) -> String {
+    format!("len:{}", v & 0x3F)
+}
+
+pub(super) fn decode_envelope(v: u8) -> String {
+    let dir = if bit(v, 3) { "up" } else { "down" };
+    format!("vol:{} {} period:{}", v >> 4, dir, v & 0b111)
+}
+
+fn decode_freq_high(v: u8) -> String {
+    format!("trigger:{} len enable:{} freq hi:{}", bit(v, 7) as u8, bit(v, 6) as u8, v & 0b111)
+}
+
+fn decode_nr30(v: u8) -> String {
+    format!("DAC {}", on_off(bit(v, 7)))
+}
+
+fn decode_nr32(v: u8) -> String {
+    let vol = ["0%", "100%", "50%", "25%"][((v >> 5) & 0b11) as usize];
+    format!("vol:{}", vol)
+}
+
+pub(super) fn decode_nr43(v: u8) -> String {
+    let width = if bit(v, 3) { 7 } else { 15 };
+    format!("shift:{} width:{} divisor:{}", v >> 4, width, v & 0b111)
+}
+
+fn decode_nr50(v: u8) -> String {
+    format!("left:{} right:{}", (v >> 4) & 0b111, v & 0b111)
+}
+
+fn decode_nr51(v: u8) -> String {
+    let channels = |nibble: u8| {
+        (0..4).filter(|&i| bit(nibble, i)).map(|i| (i + 1).to_string()).collect::<String>()
+    };
+    format!("left:[{}] right:[{}]", channels(v >> 4), channels(v & 0xF))
+}
+
+pub(super) fn decode_nr52(v: u8) -> String {
+    let active = (0..4).filter(|&i| bit(v, i)).map(|i| (i + 1).to_string()).collect::<String>();
+    format!("{} active:[{}]", on_off(bit(v, 7)), active)
+}
+
+fn decode_lcdc(v: u8) -> String {
+    format!(
+        "LCD:{} win:{} win map:{} tiles:{} bg map:{} obj:{} {} bg:{}",
+        on_off(bit(v, 7)),
+        on_off(bit(v, 5)),
+        if bit(v, 6) { "9C00" } else { "9800" },
+        if bit(v, 4) { "8000" } else { "8800" },
+        if bit(v, 3) { "9C00" } else { "9800" },
+        on_off(bit(v, 1)),
+        if bit(v, 2) { "8x16" } else { "8x8" },
+        on_off(bit(v, 0)),
+    )
+}
+
+fn decode_stat(v: u8) -> String {
+    let mode = ["HBlank", "VBlank", "OAM", "transfer"][(v & 0b11) as usize];
+    let sources = [(6, "LYC"), (5, "OAM"), (4, "VBlank"), (3, "HBlank")]
+        .iter()
+        .filter(|(i, _)| bit(v, *i))
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>();
+    format!("mode:{} LY=LYC:{} int:[{}]", mode, bit(v, 2) as u8, sources.join(" "))
+}
+
+fn decode_dma(v: u8) -> String {
+    format!("src:{:02X}00", v)
+}
+
+fn decode_palette(v: u8) -> String {
+    format!("{} {} {} {}", v & 0b11, (v >> 2) & 0b11, (v >> 4) & 0b11, v >> 6)
+}
+
+fn decode_boot(v: u8) -> String {
+    if v == 0 { "boot ROM mapped" } else { "boot ROM unmapped" }.into()
+}
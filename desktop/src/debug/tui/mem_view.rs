@@ -1,5 +1,6 @@
 use std::{
     fmt::Write,
+    rc::Rc,
 };
 
 use cursive::{
@@ -15,7 +16,9 @@ use mahboi::{
     machine::Machine,
     primitives::{Byte, Word},
 };
+use crate::symbols::Symbols;
 use super::{
+    search::{Hit, Query},
     util::DecodedInstr,
 };
 
@@ -23,6 +26,79 @@ use super::{
 const DATA_OFFSET: usize = 9;
 const DATA_LEN: usize = 3 * 16 - 1;
 
+/// Width of the region labels right of the data area. Two labels (e.g. "OAM"
+/// and "unusable") can be in the same line.
+const REGION_LABEL_LEN: usize = 17;
+
+
+/// The regions of the address space, each shown in its own color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Region {
+    Rom0,
+    RomX,
+    Vram,
+    CartridgeRam,
+    Wram,
+    Echo,
+    Oam,
+    Unusable,
+    Io,
+    Hram,
+    Ie,
+}
+
+impl Region {
+    fn of(addr: Word) -> Self {
+        match addr.get() {
+            0x0000..=0x3FFF => Region::Rom0,
+            0x4000..=0x7FFF => Region::RomX,
+            0x8000..=0x9FFF => Region::Vram,
+            0xA000..=0xBFFF => Region::CartridgeRam,
+            0xC000..=0xDFFF => Region::Wram,
+            0xE000..=0xFDFF => Region::Echo,
+            0xFE00..=0xFE9F => Region::Oam,
+            0xFEA0..=0xFEFF => Region::Unusable,
+            0xFF00..=0xFF7F => Region::Io,
+            0xFF80..=0xFFFE => Region::Hram,
+            0xFFFF => Region::Ie,
+        }
+    }
+
+    fn color(self) -> Color {
+        match self {
+            Region::Rom0 => Color::Dark(BaseColor::Yellow),
+            Region::RomX => Color::Light(BaseColor::Yellow),
+            Region::Vram => Color::Light(BaseColor::Green),
+            Region::CartridgeRam => Color::Light(BaseColor::Red),
+            Region::Wram => Color::Light(BaseColor::Cyan),
+            Region::Echo => Color::Dark(BaseColor::Cyan),
+            Region::Oam => Color::Light(BaseColor::Magenta),
+            Region::Unusable => Color::Light(BaseColor::Black),
+            Region::Io => Color::Dark(BaseColor::Green),
+            Region::Hram => Color::Dark(BaseColor::Magenta),
+            Region::Ie => Color::Dark(BaseColor::Red),
+        }
+    }
+
+    /// Returns a short description, including the mapped bank for the
+    /// banked regions.
+    fn label(self, rom_bank: usize, ram_bank: usize) -> String {
+        match self {
+            Region::Rom0 => "ROM bank 00".into(),
+            Region::RomX => format!("ROM bank {:02X}", rom_bank),
+            Region::Vram => "VRAM".into(),
+            Region::CartridgeRam => format!("cart RAM bank {:02X}", ram_bank),
+            Region::Wram => "WRAM".into(),
+            Region::Echo => "echo RAM".into(),
+            Region::Oam => "OAM".into(),
+            Region::Unusable => "unusable".into(),
+            Region::Io => "IO registers".into(),
+            Region::Hram => "HRAM".into(),
+            Region::Ie => "IE register".into(),
+        }
+    }
+}
+
 
 pub struct MemView {
     /// Address of the first byte in the first line. Is always divisable by 16.
@@ -33,20 +109,113 @@ pub struct MemView {
 
     /// Position of the cursor
     pub(crate) cursor: Word,
+
+    /// The upper nibble of a byte that is currently typed in by the user.
+    pending_nibble: Option<u8>,
+
+    /// Edits done by the user that still have to be applied to the machine.
+    edits: Vec<MemEdit>,
+
+    /// If `true`, edits are written directly into the backing memory (see
+    /// `Machine::store_byte_raw`) instead of going through
+    /// `Machine::store_byte`.
+    pub(crate) raw_writes: bool,
+
+    /// A search that was started by the user, but not yet executed. This
+    /// happens in the next `update` call.
+    pending_search: Option<Query>,
+
+    /// Results of the last search. `None` if no search was done yet.
+    hits: Option<Vec<Hit>>,
+
+    /// Index into `hits` of the hit the cursor was last moved to.
+    current_hit: usize,
+
+    symbols: Rc<Symbols>,
+
+    /// The ROM bank mapped during the last `update` call. Used to look up
+    /// symbols.
+    rom_bank: usize,
+
+    /// The cartridge RAM bank mapped during the last `update` call.
+    ram_bank: usize,
+}
+
+/// A byte written by the user in the memory view.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct MemEdit {
+    pub(crate) addr: Word,
+    pub(crate) value: Byte,
+    pub(crate) raw: bool,
 }
 
 impl MemView {
     /// Creates an empty MemView.
-    pub fn new() -> Self {
+    pub fn new(symbols: Rc<Symbols>) -> Self {
         Self {
             first_line_addr: Word::new(0),
             data: vec![],
             cursor: Word::new(0),
+            pending_nibble: None,
+            edits: vec![],
+            raw_writes: false,
+            pending_search: None,
+            hits: None,
+            current_hit: 0,
+            symbols,
+            rom_bank: 1,
+            ram_bank: 0,
+        }
+    }
+
+    /// Starts a new search, which is executed in the next `update` call. The
+    /// cursor is then moved to the first hit.
+    pub(crate) fn search(&mut self, query: Query) {
+        self.pending_search = Some(query);
+    }
+
+    /// Moves the cursor to the next search hit (wrapping around).
+    pub(crate) fn next_hit(&mut self) {
+        if let Some(hits) = self.hits.as_ref().filter(|h| !h.is_empty()) {
+            self.current_hit = (self.current_hit + 1) % hits.len();
+            self.cursor = hits[self.current_hit].addr;
+        }
+    }
+
+    /// Moves the cursor to the previous search hit (wrapping around).
+    pub(crate) fn prev_hit(&mut self) {
+        if let Some(hits) = self.hits.as_ref().filter(|h| !h.is_empty()) {
+            self.current_hit = (self.current_hit + hits.len() - 1) % hits.len();
+            self.cursor = hits[self.current_hit].addr;
         }
     }
 
+    /// Returns all edits that were made since the last call. The caller has
+    /// to apply them to the machine. The view reloads its data in the next
+    /// `update` call.
+    pub(crate) fn take_edits(&mut self) -> Vec<MemEdit> {
+        if !self.edits.is_empty() {
+            self.data.clear();
+        }
+
+        std::mem::take(&mut self.edits)
+    }
+
     /// Updates the memory data and scrolling position.
     pub(crate) fn update(&mut self, machine: &Machine, state_changed: bool) {
+        self.rom_bank = machine.cartridge.rom_bank();
+        self.ram_bank = machine.cartridge.ram_bank();
+
+        // Execute a search requested by the user
+        if let Some(query) = self.pending_search.take() {
+            let hits = query.run(machine);
+            self.current_hit = 0;
+            if let Some(first) = hits.first() {
+                self.cursor = first.addr;
+            }
+            self.hits = Some(hits);
+        }
+
         // Check if we need to adjust our window
         let cursor_line = self.cursor.get() & 0xFFF0;
         let needs_update = if cursor_line <= self.first_line_addr.get() {
@@ -66,12 +235,7 @@ impl MemView {
 
 
         if state_changed || needs_update {
-            self.data.clear();
-
-            for i in 0u16..16 * 16 {
-                let addr = self.first_line_addr + i;
-                self.data.push(machine.load_byte(addr));
-            }
+            self.data = machine.peek_range(self.first_line_addr, 16 * 16);
         }
     }
 }
@@ -115,15 +279,40 @@ impl View for MemView {
                 buf.clear();
                 let _ = write!(buf, "{:02x}", b.get());
 
-                let effect = if self.cursor == addr + col as u8 {
+                let is_cursor = self.cursor == addr + col as u8;
+                if let (true, Some(nibble)) = (is_cursor, self.pending_nibble) {
+                    buf.clear();
+                    let _ = write!(buf, "{:x}_", nibble);
+                }
+
+                let effect = if is_cursor {
                     Effect::Reverse
                 } else {
                     Effect::Simple
                 };
-                printer.with_effect(effect, |printer| {
-                    printer.print((DATA_OFFSET + col * 3, row + 2), &buf);
+                let color = Region::of(addr + col as u8).color();
+                printer.with_style(color, |printer| {
+                    printer.with_effect(effect, |printer| {
+                        printer.print((DATA_OFFSET + col * 3, row + 2), &buf);
+                    });
                 });
             }
+
+            // Label all regions starting in this line. In the first line,
+            // the region of the first byte is labeled, too.
+            let mut x = DATA_OFFSET + DATA_LEN + 3;
+            for col in 0..16u8 {
+                let a = addr + col;
+                let starts = a.get() == 0 || Region::of(a) != Region::of(a - 1u16);
+                if starts || (row == 0 && col == 0) {
+                    let region = Region::of(a);
+                    let label = region.label(self.rom_bank, self.ram_bank);
+                    printer.with_style(region.color(), |printer| {
+                        printer.print((x, row + 2), &label);
+                    });
+                    x += label.len() + 1;
+                }
+            }
         }
 
         // Print remaining border
@@ -161,22 +350,79 @@ impl View for MemView {
             _ => printer.print((val_offset, info_offset + 1), "none"),
         }
 
+        // Symbol at the cursor
+        printer.print((DATA_OFFSET, info_offset + 2), "symbol:");
+        let name = self.symbols.name_at(self.cursor, self.rom_bank).unwrap_or("-");
+        printer.with_style(data_style, |printer| {
+            printer.print((val_offset, info_offset + 2), name);
+        });
+
+        // Region (and mapped bank) at the cursor
+        printer.print((DATA_OFFSET, info_offset + 3), "region:");
+        let region = Region::of(self.cursor);
+        printer.with_style(region.color(), |printer| {
+            printer.print(
+                (val_offset, info_offset + 3),
+                &region.label(self.rom_bank, self.ram_bank),
+            );
+        });
+
+        // Hint for editing
+        let mode = if self.raw_writes { "raw" } else { "via bus" };
+        printer.print(
+            (DATA_OFFSET, info_offset + 4),
+            &format!("type hex digits to edit ({})", mode),
+        );
+
+        // Search results
+        if let Some(hits) = &self.hits {
+            let s = match hits.get(self.current_hit) {
+                Some(hit) => format!("hit {}/{} at {}", self.current_hit + 1, hits.len(), hit),
+                None => "nothing found".to_string(),
+            };
+            printer.print((DATA_OFFSET, info_offset + 5), "search:");
+            printer.with_style(data_style, |printer| {
+                printer.print((val_offset, info_offset + 5), &s);
+            });
+        }
     }
 
     fn required_size(&mut self, _constraint: Vec2) -> Vec2 {
         Vec2::new(
-            // Width: offset + seperator + 16 * (byte + space) + seperator
-            DATA_OFFSET + DATA_LEN + 2,
+            // Width: offset + seperator + 16 * (byte + space) + seperator +
+            // region labels
+            DATA_OFFSET + DATA_LEN + 3 + REGION_LABEL_LEN,
 
             // Height: header + 16 lines + box border + info area
-            2 + 16 + 1 + 3,
+            2 + 16 + 1 + 6,
         )
     }
 
     /// Reacts to arrow keys, page up and down as well as mouse click inside
-    /// the data area.
+    /// the data area. Hex digits modify the selected byte.
     fn on_event(&mut self, event: Event) -> EventResult {
+        // Any other event than a hex digit aborts typing a byte.
+        let pending_nibble = self.pending_nibble.take();
+
         match event {
+            Event::Char(c) if c.is_ascii_hexdigit() => {
+                let nibble = c.to_digit(16).unwrap() as u8;
+                match pending_nibble {
+                    None => self.pending_nibble = Some(nibble),
+                    Some(upper) => {
+                        self.edits.push(MemEdit {
+                            addr: self.cursor,
+                            value: Byte::new((upper << 4) | nibble),
+                            raw: self.raw_writes,
+                        });
+                        self.cursor = self.cursor.map(|a| a.saturating_add(1));
+                    }
+                }
+                EventResult::Consumed(None)
+            }
+            Event::Key(Key::Esc) | Event::Key(Key::Backspace) if pending_nibble.is_some() => {
+                EventResult::Consumed(None)
+            }
             Event::Key(Key::Left) => {
                 self.cursor = self.cursor.map(|a| a.saturating_sub(1));
                 EventResult::Consumed(None)
@@ -1,6 +1,10 @@
 use std::{
-    cell::RefCell,
-    collections::BTreeSet,
+    cell::{Cell, RefCell},
+    cmp,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fmt,
+    fs,
+    io::{self, Write as _},
     panic,
     rc::Rc,
     sync::{
@@ -10,46 +14,87 @@ use std::{
     },
 };
 
+use crossterm::{
+    execute,
+    cursor::Show,
+    event::DisableMouseCapture,
+    terminal::{self, LeaveAlternateScreen},
+};
 use cursive::{
     Cursive, CursiveExt,
     theme::{Theme, BorderStyle, Effect, Color, BaseColor, Palette, PaletteColor, Style},
-    view::{Boxable, Identifiable, Scrollable},
+    view::{Boxable, Identifiable, Scrollable, ScrollStrategy},
     views::{
         OnEventView, ListView, ResizedView, EditView, DummyView, Button, TextView,
-        LinearLayout, Dialog, ScrollView, NamedView,
+        LinearLayout, Dialog, ScrollView, NamedView, Checkbox, RadioGroup, SelectView,
     },
     utils::markup::StyledString,
 };
-use failure::Error;
+use failure::{format_err, Error, ResultExt};
 use lazy_static::lazy_static;
 use log::{Log, Record, Level, Metadata};
 
 use mahboi::{
     opcode,
+    instr,
     log::*,
     machine::{
         Machine,
-        cpu::Cpu,
-        ppu::{Mode, Ppu},
+        hooks::{AccessKind, MemoryAccess, Watchpoint},
+        ppu::{Mode, CYCLES_PER_LINE},
     },
-    primitives::{Byte, Word},
+    primitives::{Byte, Word, CYCLES_PER_FRAME},
 };
 use crate::{
+    analyze::{CodeMap, dot},
     args::Args,
+    disasm,
+    gfx,
+    symbols::Symbols,
 };
 use super::{Action, WindowBuffer};
 use self::{
+    analysis::Analysis,
     asm_view::AsmView,
+    call_stack::CallStack,
+    coverage::Coverage,
+    console::{Command, HeatmapAction, ProfilerAction, RamSearchAction, Target},
+    rewind::History,
+    expr::Expr,
+    heatmap::Heatmap,
+    ram_search::RamSearch,
     log_view::LogView,
     mem_view::MemView,
+    patch::Patches,
+    profiler::Profiler,
+    search::{Query, SearchKind},
+    session::Session,
     tab_view::TabView,
+    util::styled_disassembly,
+    watch::Watches,
 };
 
+mod analysis;
+mod apu;
 mod asm_view;
+mod call_stack;
+mod console;
+mod coverage;
+mod expr;
+mod heatmap;
+mod ram_search;
+mod io_regs;
 mod log_view;
 mod mem_view;
+mod patch;
+mod profiler;
+mod rewind;
+mod search;
+mod session;
 mod tab_view;
+mod timeline;
 mod util;
+mod watch;
 
 
 // ============================================================================
@@ -124,6 +169,10 @@ const FPS_RUNNING: u32 = 1000;
 /// just for changes in the TUI that are not input triggered.
 const FPS_PAUSED: u32 = 2;
 
+/// Number of stack words shown above (already popped) and below SP.
+const STACK_WORDS_ABOVE_SP: u32 = 4;
+const STACK_WORDS_BELOW_SP: u32 = 48;
+
 /// A debugger that uses a terminal user interface. Used in `--debug` mode.
 pub(crate) struct TuiDebugger {
     /// Handle to the special TUI terminal
@@ -154,6 +203,22 @@ pub(crate) struct TuiDebugger {
     /// passed to Cursive event handlers.
     event_sink: Sender<char>,
 
+    /// Lines entered in the command console. They are moved to
+    /// `command_queue` in `update()`.
+    pending_commands: Receiver<String>,
+
+    /// Sender for `pending_commands`, passed to the console input view.
+    command_sink: Sender<String>,
+
+    /// Console commands waiting to be executed. Commands that continue
+    /// execution wait here until the emulator is paused again, which makes
+    /// scripts possible.
+    command_queue: VecDeque<String>,
+
+    /// Where breakpoints, watchpoints and watch expressions are persisted.
+    /// `None` if disabled via `--no-session`.
+    session: Option<Session>,
+
     // ===== Data to control when to stop execution ===========================
     /// This is an exception to the normal pause-rules. If this is
     /// `Some(addr)`, we will not pause execution for an instruction at `addr`.
@@ -163,10 +228,77 @@ pub(crate) struct TuiDebugger {
     /// A set of addresses at which we will pause execution
     breakpoints: Breakpoints,
 
+    /// Opcodes at which we will pause execution, regardless of the address.
+    opcode_breaks: BTreeSet<OpcodeBreak>,
+
+    /// Watchpoints as managed in the TUI. They are synchronized with the
+    /// watchpoints of the machine in `update()`.
+    watchpoints: Watchpoints,
+
+    /// If set, the watchpoint hit reported by the machine is ignored in the
+    /// next `should_pause` call. That hit was caused by the instruction
+    /// before we paused and was already reported.
+    ignore_watch_hit: bool,
+
+    /// The watchpoint hit that caused the last pause. Reported to the user in
+    /// `update()`.
+    watch_hit: Option<MemoryAccess>,
+
+    /// Whether to pause when the cartridge switches the ROM or RAM bank. Set
+    /// by a checkbox in the TUI.
+    break_on_bank_switch: Rc<Cell<bool>>,
+
+    /// The ROM and RAM bank seen in the last `should_pause` call.
+    last_banks: Option<Banks>,
+
+    /// The bank switch (old and new banks) that caused the last pause.
+    /// Reported to the user in `update()`.
+    bank_switch: Option<(Banks, Banks)>,
+
+    /// Shadow call stack, updated in every `should_pause` call.
+    call_stack: CallStack,
+
+    /// Snapshots and inputs to step backwards.
+    history: History,
+
+    /// Symbols loaded from a symbol file and generated names of functions
+    /// found by the analysis.
+    symbols: Rc<Symbols>,
+
+    /// The analysis of the ROM, extended by execution. Also used by the ASM
+    /// view.
+    analysis: Analysis,
+
+    /// Expressions shown in the watch panel.
+    watches: Watches,
+
+    /// Executed ROM addresses, also shown in the ASM view.
+    coverage: Coverage,
+
+    /// Code patches made in the ASM view.
+    patches: Patches,
+
+    /// Cycles per function and address, shown in the "Profiler" tab.
+    profiler: Profiler,
+
+    /// Memory accesses per address, shown in the "Heatmap" tab.
+    heatmap: Heatmap,
+
+    /// The state of the "RAM Search" tab.
+    ram_search: RamSearch,
+
     /// Flag that is set when the user requested to run until the next RET
     /// instruction.
     pause_on_ret: bool,
 
+    /// Flag that is set when the user requested to run until right before the
+    /// next interrupt is dispatched.
+    pause_before_interrupt: bool,
+
+    /// If set, `pause_before_interrupt` only considers the interrupt with this
+    /// vector address. Set by the `int` console command.
+    interrupt_filter: Option<Word>,
+
     /// This is set whenever the user runs the emulator until a new line or new
     /// frame is reached.
     pause_in_line: Option<u8>,
@@ -181,9 +313,6 @@ pub(crate) struct TuiDebugger {
     /// called and reset whenever all views are updated.
     update_needed: bool,
 
-    /// Was the boot ROM already disabled? This is used to do cache management.
-    boot_rom_disabled: bool,
-
     /// Sometimes the ASM view has to be scrolled to a specific position. This
     /// has to be done after `siv.step()`. That's why its stored here.
     scroll_asm_view: Option<usize>,
@@ -194,9 +323,9 @@ pub(crate) struct TuiDebugger {
 }
 
 impl TuiDebugger {
-    pub(crate) fn new(args: &Args) -> Result<Self, Error> {
+    pub(crate) fn new(args: &Args, rom: &[Byte]) -> Result<Self, Error> {
         // Create a handle to the terminal (with the correct backend).
-        let mut siv = Cursive::ncurses()?;
+        let mut siv = Cursive::crossterm()?;
 
         // To handle events, we use `Cursive::step`. Sadly, this function
         // blocks to wait on an event before it returns. This isn't good. We
@@ -216,38 +345,87 @@ impl TuiDebugger {
         // screen, before the message is printed.
         let previous_hook = panic::take_hook();
         panic::set_hook(Box::new(move |info| {
-            // So this is not entirely cool. These two lines are executed
-            // in the `drop()` function of `Cursive`. I have no idea if
-            // it's bad to call these twice. But so far, nothing bad has
-            // happend...
-            println!("\x1B[?1002l");
-            ncurses::endwin();
+            // This is what the crossterm backend does when `Cursive` is
+            // dropped. Doing it twice doesn't hurt. Errors are ignored as
+            // there is nothing we can do about them anyway.
+            let mut stdout = io::stdout();
+            let _ = execute!(stdout, DisableMouseCapture, LeaveAlternateScreen, Show);
+            let _ = terminal::disable_raw_mode();
 
             // Execute previous hook.
             previous_hook(info)
         }));
 
         let (event_sink, pending_events) = channel();
+        let (command_sink, pending_commands) = channel();
+
+        // The session of the last run is restored by executing its commands,
+        // followed by the ones of the startup script.
+        let mut command_queue = VecDeque::new();
+        let session = if args.no_session {
+            None
+        } else {
+            let (session, commands) = Session::load(Session::path_for_rom(&args.path_to_rom));
+            command_queue.extend(commands);
+            Some(session)
+        };
+        if let Some(path) = &args.debug_script {
+            let script = fs::read_to_string(path)
+                .context(format!("failed to read debugger script '{}'", path.display()))?;
+            command_queue.extend(script.lines().map(|l| l.to_string()));
+        }
+
+        let mut symbols = Symbols::for_rom(args)?;
+        if !symbols.is_empty() {
+            info!("[debugger] loaded symbol file");
+        }
+        let code_map = CodeMap::new(rom);
+        let count = symbols.add_function_names(&code_map);
+        info!("[debugger] generated names for {} functions without symbol", count);
 
         let mut out = Self {
             siv,
             pause_mode: false,
             pending_events,
             event_sink,
+            pending_commands,
+            command_sink,
+            command_queue,
+            session,
             step_over: None,
             breakpoints: Breakpoints::new(),
+            opcode_breaks: BTreeSet::new(),
+            watchpoints: Watchpoints::new(),
+            ignore_watch_hit: false,
+            watch_hit: None,
+            break_on_bank_switch: Rc::new(Cell::new(false)),
+            last_banks: None,
+            bank_switch: None,
+            call_stack: CallStack::new(),
+            history: History::new(),
+            symbols: Rc::new(symbols),
+            analysis: Analysis::new(code_map),
+            watches: Watches::new(),
+            profiler: Profiler::new(),
+            heatmap: Heatmap::new(),
+            ram_search: RamSearch::new(),
+            coverage: Coverage::new(),
+            patches: Patches::new(),
             pause_on_ret: false,
+            pause_before_interrupt: false,
+            interrupt_filter: None,
             pause_in_line: None,
             waiting_for_vblank: false,
-            boot_rom_disabled: false,
             update_needed: true,
             scroll_asm_view: None,
             update_counter: 0,
         };
 
         // Add all breakpoints specified by CLI
-        for &bp in &args.breakpoints {
-            out.breakpoints.add(bp);
+        for bp in &args.breakpoints {
+            let loc = Location::parse(bp, &out.symbols)
+                .map_err(|e| format_err!("invalid breakpoint '{}': {}", bp, e))?;
+            out.breakpoints.add(loc);
         }
 
         // Build the TUI view
@@ -263,7 +441,7 @@ impl TuiDebugger {
     pub(crate) fn update(
         &mut self,
         is_paused: bool,
-        machine: &Machine,
+        machine: &mut Machine,
         mut window: WindowBuffer,
     ) -> Action {
         if !self.siv.is_running() {
@@ -275,6 +453,76 @@ impl TuiDebugger {
             // Switch the debugger into pause mode.
             self.pause();
         }
+        if is_paused {
+            self.ignore_watch_hit = true;
+        }
+
+        // Apply changes to the watchpoints made in the TUI and report the
+        // watchpoint that caused a pause.
+        self.watchpoints.sync(machine);
+        if let Some(hit) = self.watch_hit.take() {
+            let msg = match hit.kind {
+                AccessKind::Read => format!(
+                    "Watchpoint hit: read {} from {} (instruction at {})",
+                    hit.value,
+                    hit.addr,
+                    hit.pc,
+                ),
+                AccessKind::Write => format!(
+                    "Watchpoint hit: wrote {} to {} (instruction at {})",
+                    hit.value,
+                    hit.addr,
+                    hit.pc,
+                ),
+            };
+            info!("[debugger] {}", msg);
+            self.siv.add_layer(Dialog::info(msg).title("Watchpoint"));
+        }
+        if let Some((old, new)) = self.bank_switch.take() {
+            let msg = format!(
+                "Bank switch: ROM bank {} -> {}, RAM bank {} -> {}",
+                old.rom,
+                new.rom,
+                old.ram,
+                new.ram,
+            );
+            info!("[debugger] {}", msg);
+            self.siv.add_layer(Dialog::info(msg).title("Bank switch"));
+        }
+
+        // Apply the edits made in the memory view.
+        if let Some(mut mem_view) = self.siv.find_name::<MemView>("mem_view") {
+            for edit in mem_view.take_edits() {
+                if edit.raw {
+                    machine.store_byte_raw(edit.addr, edit.value);
+                } else {
+                    machine.store_byte(edit.addr, edit.value);
+                }
+                self.update_needed = true;
+            }
+        }
+
+        // Apply the code patches requested in the patch dialog.
+        for result in self.patches.sync(machine) {
+            match result {
+                Ok(msg) => self.console_print(msg),
+                Err(e) => self.siv.add_layer(Dialog::info(format!("Cannot patch: {}", e))),
+            }
+            self.update_needed = true;
+        }
+
+        // Execute commands entered in the console.
+        while let Ok(line) = self.pending_commands.try_recv() {
+            self.command_queue.push_back(line);
+        }
+        self.run_commands(is_paused, machine);
+        self.save_session();
+
+        // A frame of the call stack might have been selected to be shown in
+        // the ASM view.
+        if is_paused && self.siv.find_name::<AsmView>("asm_view").unwrap().needs_refresh() {
+            self.update_needed = true;
+        }
 
         if self.update_needed {
             // We only update the ASM view if the emulator is paused
@@ -285,10 +533,24 @@ impl TuiDebugger {
                 self.scroll_asm_view = Some(line.saturating_sub(10));
             }
 
-            self.update_cpu_data(&machine.cpu);
+            self.update_timing_data(machine);
+            self.update_cpu_data(machine);
             self.update_stack_data(machine);
-            self.update_ppu_data(&machine.ppu);
+            self.update_call_stack_data();
+            self.update_io_data(machine);
+            self.update_timer_data(machine);
+            self.update_mbc_data(machine);
+            self.update_apu_data(machine);
             self.update_interrupt_data(machine);
+            if is_paused {
+                self.update_trace_data(machine);
+                self.update_watch_data(machine);
+                self.update_profiler_data();
+                self.update_heatmap_data(machine);
+                self.update_ram_search_data(machine);
+                self.update_frozen_list(machine);
+                self.update_timeline_data(machine);
+            }
 
             self.update_needed = false;
         }
@@ -347,6 +609,19 @@ impl TuiDebugger {
                         return Action::Continue;
                     }
                 }
+                'i' | 'I' => {
+                    if self.pause_mode {
+                        // `I` is only sent by the console, after setting the
+                        // filter.
+                        if c == 'i' {
+                            self.interrupt_filter = None;
+                        }
+                        self.step_over = Some(machine.cpu.pc);
+                        self.pause_before_interrupt = true;
+                        self.resume();
+                        return Action::Continue;
+                    }
+                }
                 'l' => {
                     if self.pause_mode {
                         let next_line = (machine.ppu.regs().current_line.get() + 1) % 144;
@@ -363,6 +638,17 @@ impl TuiDebugger {
                         return Action::Continue;
                     }
                 }
+                'u' => {
+                    if self.pause_mode {
+                        match self.history.step_back(machine, &mut self.call_stack) {
+                            Ok(()) => self.update_needed = true,
+                            Err(e) => {
+                                let msg = format!("Cannot step back: {}", e);
+                                self.siv.add_layer(Dialog::info(msg));
+                            }
+                        }
+                    }
+                }
                 'c' => {
                     window.paint_pink();
                 }
@@ -388,6 +674,339 @@ impl TuiDebugger {
         Action::Nothing
     }
 
+    /// Executes the commands in `command_queue`. Commands that continue
+    /// execution are forwarded as events. After such a command, we stop and
+    /// execute the remaining commands once the emulator is paused again.
+    fn run_commands(&mut self, is_paused: bool, machine: &mut Machine) {
+        while let Some(line) = self.command_queue.front().cloned() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                self.command_queue.pop_front();
+                continue;
+            }
+
+            let command = Command::parse(line, &self.symbols);
+            if let Ok(event) = command.as_ref().map(Command::as_event) {
+                if event.is_some() && !is_paused {
+                    break;
+                }
+            }
+
+            self.command_queue.pop_front();
+            self.console_print(StyledString::styled(
+                format!("> {}", line),
+                Color::Light(BaseColor::Blue),
+            ));
+
+            let result = command.and_then(|command| {
+                if let Some(event) = command.as_event() {
+                    if let Command::RunToInterrupt(vector) = command {
+                        self.interrupt_filter = vector;
+                    }
+                    self.event_sink.send(event).unwrap();
+                    return Ok(true);
+                }
+
+                self.run_command(command, machine).map(|_| false)
+            });
+
+            match result {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(e) => {
+                    self.console_print(StyledString::styled(
+                        format!("error: {}", e),
+                        Color::Light(BaseColor::Red),
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Executes a single command that does not continue execution.
+    fn run_command(&mut self, command: Command, machine: &mut Machine) -> Result<(), String> {
+        let eval = |expr: &Expr, machine: &Machine| expr.eval(machine).map_err(|e| e.to_string());
+
+        match command {
+            Command::Break(loc, condition) => {
+                self.breakpoints.add(loc);
+                match &condition {
+                    Some(c) => self.console_print(format!("breakpoint at {} if {}", loc, c.source)),
+                    None => self.console_print(format!("breakpoint at {}", loc)),
+                }
+                self.breakpoints.set_condition(loc, condition);
+            }
+            Command::Delete(loc) => {
+                if !self.breakpoints.contains(loc) {
+                    return Err(format!("there is no breakpoint at {}", loc));
+                }
+                self.breakpoints.remove(loc);
+                self.console_print(format!("removed breakpoint at {}", loc));
+            }
+            Command::BreakOpcode(b) => {
+                match b.mnemonic() {
+                    Some(mnemonic) => {
+                        self.console_print(format!("opcode breakpoint {} ({})", b, mnemonic))
+                    }
+                    None => self.console_print(format!("opcode breakpoint {}", b)),
+                }
+                self.opcode_breaks.insert(b);
+            }
+            Command::DeleteOpcode(b) => {
+                if !self.opcode_breaks.remove(&b) {
+                    return Err(format!("there is no opcode breakpoint {}", b));
+                }
+                self.console_print(format!("removed opcode breakpoint {}", b));
+            }
+            Command::Watch(w) => {
+                self.console_print(format!("watchpoint {}", format_watchpoint(&w)));
+                self.watchpoints.add(w);
+            }
+            Command::Examine { addr, len } => {
+                let end = addr.get() as u32 + len as u32;
+                let mut line_start = addr.get() as u32;
+                while line_start < end {
+                    let line_end = cmp::min(line_start + 16, end);
+                    let bytes = machine.peek_range(
+                        Word::new(line_start as u16),
+                        (line_end - line_start) as usize,
+                    );
+                    let bytes = bytes.iter().map(|b| b.to_string()).collect::<Vec<_>>();
+                    self.console_print(format!(
+                        "{} │ {}",
+                        Word::new(line_start as u16),
+                        bytes.join(" "),
+                    ));
+                    line_start = line_end;
+                }
+            }
+            Command::Print(expr) => {
+                let v = eval(&expr, machine)?;
+                self.console_print(format!("= {} ({:#x})", v, v));
+            }
+            Command::Display { source, expr } => {
+                self.watches.add(&source, expr);
+                self.watches.update(machine);
+                self.update_watch_list(machine);
+            }
+            Command::Undisplay(idx) => {
+                self.watches.remove(idx)?;
+                self.update_watch_list(machine);
+            }
+            Command::Set { target, value } => {
+                let value = eval(&value, machine)?;
+                match target {
+                    Target::Reg(reg) => reg.set(machine, value as u16),
+                    Target::Mem(addr) => {
+                        let addr = eval(&addr, machine)?;
+                        if !(0..=0xFFFF).contains(&addr) {
+                            return Err(format!("address {:#x} out of range", addr));
+                        }
+                        let addr = Word::new(addr as u16);
+                        machine.store_byte(addr, Byte::new(value as u8));
+                    }
+                }
+                self.update_needed = true;
+            }
+            Command::Pause => self.event_sink.send('p').unwrap(),
+            Command::Source(path) => {
+                let script = fs::read_to_string(&path)
+                    .map_err(|e| format!("failed to read '{}': {}", path.display(), e))?;
+
+                // The commands of the script are executed before all others
+                for line in script.lines().rev() {
+                    self.command_queue.push_front(line.to_string());
+                }
+            }
+            Command::Disassemble { path, selection } => {
+                let rom = machine.cartridge.rom();
+                disasm::export_to_file(&path, rom, selection, &self.symbols, &self.analysis.get())
+                    .map_err(|e| e.to_string())?;
+                self.console_print(format!("wrote disassembly to '{}'", path.display()));
+            }
+            Command::ExportProject(dir) => {
+                let rom = machine.cartridge.rom();
+                let count = disasm::export_project(&dir, rom, &self.symbols, &self.analysis.get())
+                    .map_err(|e| e.to_string())?;
+                self.console_print(format!("wrote {} files to '{}'", count, dir.display()));
+            }
+            Command::ExportTiles { path, palette } => {
+                gfx::export_vram_tiles(&path, machine, palette).map_err(|e| e.to_string())?;
+                self.console_print(format!("wrote tiles to '{}'", path.display()));
+            }
+            Command::ExportRomTiles(dir) => {
+                let rom = machine.cartridge.rom();
+                let count = gfx::export_rom_tiles(&dir, rom, &self.analysis.get())
+                    .map_err(|e| e.to_string())?;
+                self.console_print(format!("wrote {} tile sheets to '{}'", count, dir.display()));
+            }
+            Command::ExportMaps { dir, viewport } => {
+                gfx::export_maps(&dir, machine, viewport).map_err(|e| e.to_string())?;
+                self.console_print(format!("wrote maps to '{}'", dir.display()));
+            }
+            Command::Profile(action) => {
+                match action {
+                    ProfilerAction::Start => self.profiler.set_enabled(true),
+                    ProfilerAction::Stop => self.profiler.set_enabled(false),
+                    ProfilerAction::Reset => self.profiler.reset(),
+                }
+                self.update_profiler_data();
+            }
+            Command::Heatmap(action) => {
+                match action {
+                    HeatmapAction::Start => self.heatmap.set_enabled(machine, true),
+                    HeatmapAction::Stop => self.heatmap.set_enabled(machine, false),
+                    HeatmapAction::Reset => self.heatmap.reset(machine),
+                    HeatmapAction::Window(frames) => self.heatmap.set_window(frames),
+                    HeatmapAction::Save(path) => {
+                        self.heatmap.save(&path, machine).map_err(|e| e.to_string())?;
+                        self.console_print(format!("wrote heatmap to '{}'", path.display()));
+                    }
+                }
+                self.update_heatmap_data(machine);
+            }
+            Command::RamSearch(action) => {
+                match action {
+                    RamSearchAction::Reset => self.ram_search.reset(machine),
+                    RamSearchAction::Filter(filter) => {
+                        let left = self.ram_search.filter(machine, filter)?;
+                        self.console_print(format!("{} candidates left", left));
+                    }
+                    RamSearchAction::Watch(idx) => {
+                        let addr = self.ram_search.candidate(idx)?;
+                        let expr = Expr::Mem(Box::new(Expr::Num(addr.get() as i64)));
+                        self.watches.add(&format!("[{:#06x}]", addr.get()), expr);
+                        self.update_watch_data(machine);
+                    }
+                    RamSearchAction::Freeze(idx) => {
+                        let addr = self.ram_search.candidate(idx)?;
+                        machine.freeze(addr, machine.peek_byte(addr));
+                        self.update_frozen_list(machine);
+                    }
+                }
+                self.update_ram_search_data(machine);
+            }
+            Command::Freeze { addr, value } => {
+                let value = match value {
+                    Some(value) => Byte::new(eval(&value, machine)? as u8),
+                    None => machine.peek_byte(addr),
+                };
+                machine.freeze(addr, value);
+                self.update_frozen_list(machine);
+                self.update_needed = true;
+            }
+            Command::Unfreeze(addr) => {
+                if !machine.unfreeze(addr) {
+                    return Err(format!("{} is not frozen", addr));
+                }
+                self.update_frozen_list(machine);
+            }
+            Command::SaveCoverage(path) => {
+                let count = self.coverage.export(&path)?;
+                self.console_print(format!("wrote {} ranges to '{}'", count, path.display()));
+            }
+            Command::ResetCoverage => {
+                self.coverage.reset();
+                self.console_print("coverage data cleared");
+            }
+            Command::Analyze => {
+                let analysis = self.analysis.clone();
+                let map = analysis.get();
+                let unresolved = map.unresolved();
+                let calls = map.functions().values().map(|f| f.callees.len()).sum::<usize>();
+                self.console_print(format!(
+                    "found {} blocks in {} functions with {} call edges",
+                    map.blocks().len(),
+                    map.functions().len(),
+                    calls,
+                ));
+                let largest = map.functions().iter().max_by_key(|(_, f)| f.blocks.len());
+                if let Some((entry, f)) = largest {
+                    self.console_print(format!(
+                        "largest function: {} ({} blocks)",
+                        entry,
+                        f.blocks.len(),
+                    ));
+                }
+                for (addr, entries) in map.jump_tables() {
+                    let len = entries.len();
+                    self.console_print(format!("jump table at {}: {} entries", addr, len));
+                }
+                self.console_print(format!("{} unresolved jumps/calls:", unresolved.len()));
+                for (addr, target) in unresolved {
+                    match target {
+                        Some(target) => self.console_print(format!("  {} -> {}", addr, target)),
+                        None => self.console_print(format!("  {} -> (indirect)", addr)),
+                    }
+                }
+            }
+            Command::CallGraph { path, with_cfg } => {
+                dot::export_to_file(&path, &self.analysis.get(), &self.symbols, with_cfg)
+                    .map_err(|e| e.to_string())?;
+                self.console_print(format!("wrote call graph to '{}'", path.display()));
+            }
+            Command::Help => self.console_print(console::HELP),
+            Command::Continue
+            | Command::Step
+            | Command::StepBack
+            | Command::Finish
+            | Command::RunToInterrupt(_) => {
+                unreachable!("commands that continue execution are forwarded as events")
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the current breakpoints, watchpoints and watch expressions as
+    /// commands to the session file (if they changed). Nothing is saved
+    /// while the session is still being restored.
+    fn save_session(&mut self) {
+        if !self.command_queue.is_empty() {
+            return;
+        }
+        let session = match &mut self.session {
+            Some(session) => session,
+            None => return,
+        };
+
+        let mut commands = Vec::new();
+        for (loc, condition) in self.breakpoints.as_sorted_list() {
+            let loc = match loc.bank {
+                Some(bank) => format!("{:02X}:{:04X}", bank, loc.addr.get()),
+                None => format!("{:04X}", loc.addr.get()),
+            };
+            match condition {
+                Some(c) => commands.push(format!("b {} if {}", loc, c.source)),
+                None => commands.push(format!("b {}", loc)),
+            }
+        }
+        for b in &self.opcode_breaks {
+            commands.push(format!("bo {}", b));
+        }
+        for w in self.watchpoints.as_list() {
+            let kind = match (w.on_read, w.on_write) {
+                (true, false) => "r",
+                (false, true) => "w",
+                _ => "rw",
+            };
+            let (start, end) = (w.range.start().get(), w.range.end().get());
+            commands.push(format!("w {:04X}-{:04X} {}", start, end, kind));
+        }
+        for source in self.watches.sources() {
+            commands.push(format!("display {}", source));
+        }
+
+        session.save(&commands);
+    }
+
+    /// Appends a line to the output of the command console.
+    fn console_print(&mut self, line: impl Into<StyledString>) {
+        let mut view = self.siv.find_name::<TextView>("console_output").unwrap();
+        view.append(line);
+        view.append("\n");
+    }
+
     /// Switch to pause mode.
     fn pause(&mut self) {
         debug!("[debugger] enter pause mode");
@@ -432,18 +1051,14 @@ impl TuiDebugger {
         // Do internal updating unrelated to determining if the emulator should
         // stop.
         self.update_needed = true;
-        if machine.cpu.pc == 0x100 && !self.boot_rom_disabled {
-            self.boot_rom_disabled = true;
-
-            // The ASM view caches instructions and assumes the data in
-            // 0..0x4000 never changes... which is almost true. But if the boot
-            // ROM is disabled, we have to invalidate the cache for 0..0x100.
-            self.siv.find_name::<AsmView>("asm_view")
-                .unwrap()
-                .invalidate_cache(Word::new(0)..Word::new(0x100));
-
-        }
-
+        self.call_stack.observe(machine);
+        self.history.observe(machine, &self.call_stack);
+        self.profiler.observe(machine, &self.call_stack);
+        self.heatmap.observe(machine);
+        self.coverage.observe(machine);
+        self.analysis.observe(machine);
+        let ignore_watch_hit = self.ignore_watch_hit;
+        self.ignore_watch_hit = false;
         if let Some(line) = self.pause_in_line {
             // If we are supposed to wait for V-Blank, we just check if we are
             // in V-Blank. Otherwise, we check if we are in the line we want to
@@ -473,21 +1088,64 @@ impl TuiDebugger {
             }
         }
 
+        // Remember the current banks to detect bank switches.
+        let banks = Banks {
+            rom: machine.cartridge.rom_bank(),
+            ram: machine.cartridge.ram_bank(),
+        };
+        let last_banks = self.last_banks.replace(banks);
+
         // If we're in paused mode, the emulator should always pause.
         if self.pause_mode {
             return true;
         }
 
-        // We the current instruction is one of our breakpoints, we also pause.
-        if self.breakpoints.contains(machine.cpu.pc) {
+        // If the last instruction switched banks, we might pause.
+        if let Some(last) = last_banks {
+            if last != banks && self.break_on_bank_switch.get() {
+                self.bank_switch = Some((last, banks));
+                return true;
+            }
+        }
+
+        // If the last instruction triggered a watchpoint, we pause.
+        if let Some(hit) = machine.watchpoint_hit() {
+            if !ignore_watch_hit {
+                self.watch_hit = Some(hit);
+                return true;
+            }
+        }
+
+        // We the current instruction is one of our breakpoints (and its
+        // condition holds), we also pause.
+        if self.breakpoints.should_break(machine) {
             debug!("[debugger] paused at breakpoint {}", machine.cpu.pc);
             return true;
         }
 
+        // If the next instruction has an opcode we break on, we pause, too.
+        if machine.executes_instruction_next() {
+            if let Some(b) = self.opcode_breaks.iter().find(|b| b.matches(machine)) {
+                debug!("[debugger] paused at opcode breakpoint {} at {}", b, machine.cpu.pc);
+                return true;
+            }
+        }
+
+        // If we are supposed to pause before an interrupt is dispatched...
+        if self.pause_before_interrupt {
+            if let Some(vector) = machine.next_interrupt_vector() {
+                if self.interrupt_filter.is_none_or(|filter| filter == vector) {
+                    debug!("[debugger] paused before interrupt {}", vector);
+                    self.pause_before_interrupt = false;
+                    return true;
+                }
+            }
+        }
+
         // If we are supposed to pause on a RET instruction...
         if self.pause_on_ret {
             // ... check if the next instruction is an RET-like instruction
-            let opcode = machine.load_byte(machine.cpu.pc);
+            let opcode = machine.peek_byte(machine.cpu.pc);
             match opcode.get() {
                 opcode!("RET")
                 | opcode!("RETI")
@@ -514,7 +1172,7 @@ impl TuiDebugger {
 
         // Other global events are just forwarded to be handled in the next
         // `update()` call.
-        for &c in &['p', 'r', 's', 'f', 'l', 'k', 'c'] {
+        for &c in &['p', 'r', 's', 'u', 'f', 'i', 'l', 'k', 'c'] {
             let tx = self.event_sink.clone();
             self.siv.add_global_callback(c, move |_| tx.send(c).unwrap());
         }
@@ -545,13 +1203,43 @@ impl TuiDebugger {
             .no_wrap()
             .with_name("main_title");
 
+        // Create view for the instruction trace
+        let trace_tab = TextView::new("no data yet")
+            .with_name("trace_view")
+            .scrollable()
+            .scroll_strategy(ScrollStrategy::StickToBottom);
+
+        // Create view for the state of the sound channels
+        let apu_tab = TextView::new("no data yet")
+            .with_name("apu_data")
+            .scrollable();
+
+        // Create view for the timing of events in the last frame
+        let timeline_tab = TextView::new("no data yet")
+            .with_name("timeline_data")
+            .scrollable();
+
         let tabs = TabView::new()
             .tab("Event Log", log_tab)
             .tab("Debugger", self.debug_tab())
+            .tab("Trace", trace_tab)
+            .tab("Profiler", self.profiler_tab())
+            .tab("Heatmap", self.heatmap_tab())
+            .tab("RAM Search", self.ram_search_tab())
+            .tab("APU", apu_tab)
+            .tab("Timeline", timeline_tab)
             .with_name("tab_view");
 
+        // Cycle and frame counters, shown below the title. Cycles are 1MHz
+        // machine cycles.
+        let timing = TextView::new("")
+            .center()
+            .no_wrap()
+            .with_name("timing_data");
+
         let main_layout = LinearLayout::vertical()
             .child(main_title)
+            .child(timing)
             .child(tabs);
 
         self.siv.add_fullscreen_layer(main_layout);
@@ -564,23 +1252,73 @@ impl TuiDebugger {
         )
     }
 
-    fn update_stack_data(&mut self, machine: &Machine) {
+    fn update_timing_data(&mut self, machine: &Machine) {
+        let value_style = Color::Light(BaseColor::Magenta);
+        let ppu = &machine.ppu;
+
         let mut body = StyledString::new();
+        body.append_plain("Cycles: ");
+        body.append_styled(machine.cycle_count().to_string(), value_style);
+        body.append_plain("   Frame: ");
+        body.append_styled(ppu.frame_count().to_string(), value_style);
+        body.append_plain("   Cycle in frame: ");
+        body.append_styled(
+            format!("{}/{}", ppu.cycle_in_frame(), CYCLES_PER_FRAME),
+            value_style,
+        );
+        body.append_plain("   Line: ");
+        body.append_styled(ppu.regs().current_line.get().to_string(), value_style);
+        body.append_plain("   Dot: ");
+        body.append_styled(
+            format!("{}/{}", ppu.cycle_in_line() as u16 * 4, CYCLES_PER_LINE as u16 * 4),
+            value_style,
+        );
+
+        self.siv.find_name::<TextView>("timing_data").unwrap().set_content(body);
+    }
 
-        let start = machine.cpu.sp.get();
-        let end = start.saturating_add(20);
+    fn update_stack_data(&mut self, machine: &Machine) {
+        let mut body = StyledString::new();
 
-        for addr in start..end {
-            let addr = Word::new(addr);
-            body.append_styled(addr.to_string(), Color::Light(BaseColor::Blue));
-            body.append_styled(" │   ", Color::Light(BaseColor::Blue));
-            body.append_styled(
-                machine.load_byte(addr).to_string(),
-                Color::Dark(BaseColor::Yellow),
-            );
+        // A few (already popped) words above SP and many below it. Only the
+        // line of SP and a few below are visible without scrolling.
+        let sp = machine.cpu.sp.get() as u32;
+        let start = sp.saturating_sub(2 * STACK_WORDS_ABOVE_SP);
+        let end = (sp + 2 * STACK_WORDS_BELOW_SP).min(0xFFFF);
+        let last_push = self.call_stack.last_push().filter(|&(addr, _)| addr.get() as u32 >= sp);
+        let rom_bank = machine.cartridge.rom_bank();
+
+        for addr in (start..end).step_by(2) {
+            let addr = Word::new(addr as u16);
+            let bytes = machine.peek_range(addr, 2);
+            let value = Word::from_bytes(bytes[0], bytes[1]);
+            let popped = (addr.get() as u32) < sp;
+
+            body.append_styled(format!("{:04X}", addr.get()), Color::Light(BaseColor::Blue));
+            body.append_styled(" │ ", Color::Light(BaseColor::Blue));
+            let value_color = if popped {
+                Color::Light(BaseColor::Black)
+            } else {
+                Color::Dark(BaseColor::Yellow)
+            };
+            body.append_styled(format!("{:04X}", value.get()), value_color);
+            body.append_plain(if addr.get() as u32 == sp { " ◀ " } else { "   " });
+
+            if popped {
+                body.append_plain("\n");
+                continue;
+            }
 
-            if addr == start {
-                body.append_plain("   ← SP");
+            if let Some((_, pushed_by)) = last_push.filter(|&(a, _)| a == addr) {
+                body.append_styled(format!("[{}] ", pushed_by), Color::Light(BaseColor::Green));
+            }
+            if looks_like_return_addr(machine, value) {
+                let target = match self.symbols.nearest(value, rom_bank) {
+                    Some((name, 0)) => name.to_string(),
+                    Some((name, offset)) => format!("{}+{}", name, offset),
+                    None => "ret".to_string(),
+                };
+                body.append_styled(target, Color::Light(BaseColor::Magenta));
             }
 
             body.append_plain("\n");
@@ -589,132 +1327,186 @@ impl TuiDebugger {
         self.siv.find_name::<TextView>("stack_view").unwrap().set_content(body);
     }
 
-    fn update_ppu_data(&mut self, ppu: &Ppu) {
-        // TODO:
-        // - FF40 bit 0
-        // - FF41 bit 2-6
-
-        let reg_style = Color::Light(BaseColor::Magenta);
-
-        let mut body = StyledString::new();
-
-        let regs = ppu.regs();
-
-        // Phase/Status
-        if regs.is_lcd_enabled() {
-            body.append_plain("==> Phase: ");
-            body.append_plain(regs.mode().to_string());
-            body.append_plain("\n");
-        } else {
-            body.append_plain("  --- LCD disabled! ---\n");
+    fn update_call_stack_data(&mut self) {
+        let mut view = self.siv.find_name::<SelectView<Word>>("call_stack").unwrap();
+        view.clear();
+        for frame in self.call_stack.frames().iter().rev() {
+            view.add_item(frame.label(&self.symbols), frame.call_site);
         }
-        body.append_plain("\n");
-
-        // Tile data memory range for BG and window
-        body.append_plain("BG tile data: ");
-        body.append_styled(regs.bg_window_tile_data_address().to_string(), reg_style);
-        body.append_plain("\n");
-
-        // FF44 current line
-        body.append_plain("Current line: ");
-        body.append_styled(regs.current_line.get().to_string(), reg_style);
-        body.append_plain("\n");
-
-        // FF45 line compare
-        body.append_plain("Line compare: ");
-        body.append_styled(regs.lyc.get().to_string(), reg_style);
-        body.append_plain("\n");
-
-        body.append_plain("\n");
+    }
 
+    fn update_profiler_data(&mut self) {
+        let report = self.profiler.report(&self.symbols);
+        self.siv.find_name::<TextView>("profiler_data").unwrap().set_content(report);
+    }
 
-        // ===== Palette information =====
-        fn format_palette(b: Byte) -> String {
-            let b = b.get();
+    fn update_ram_search_data(&mut self, machine: &Machine) {
+        let report = self.ram_search.report(machine, &self.symbols);
+        self.siv.find_name::<TextView>("ram_search_data").unwrap().set_content(report);
+    }
 
-            format!(
-                "{:02b} {:02b} {:02b} {:02b}\n",
-                (b >> 6) & 0b11,
-                (b >> 4) & 0b11,
-                (b >> 2) & 0b11,
-                (b >> 0) & 0b11,
-            )
+    fn update_frozen_list(&mut self, machine: &Machine) {
+        let mut view = self.siv.find_name::<SelectView<Word>>("frozen_list").unwrap();
+        view.clear();
+        for &(addr, value) in machine.frozen() {
+            let mut label = StyledString::plain(format!("{} = ", addr));
+            label.append_styled(value.to_string(), Color::Light(BaseColor::Magenta));
+            if let Some((name, offset)) = self.symbols.nearest(addr, machine.cartridge.rom_bank()) {
+                match offset {
+                    0 => label.append_plain(format!(" ({})", name)),
+                    _ => label.append_plain(format!(" ({}+{})", name, offset)),
+                }
+            }
+            view.add_item(label, addr);
         }
-        body.append_plain("## Palettes: \n");
-
-        body.append_plain("- BG: ");
-        body.append_styled(format_palette(regs.background_palette), reg_style);
-        body.append_plain("- S0: ");
-        body.append_styled(format_palette(regs.sprite_palette_0), reg_style);
-        body.append_plain("- S1: ");
-        body.append_styled(format_palette(regs.sprite_palette_1), reg_style);
-
-        body.append_plain("\n");
+    }
 
+    fn update_heatmap_data(&mut self, machine: &Machine) {
+        let report = self.heatmap.report(machine, &self.symbols);
+        self.siv.find_name::<TextView>("heatmap_data").unwrap().set_content(report);
+    }
 
-        // ===== Background information =====
-        body.append_plain("## Background: \n");
+    fn update_trace_data(&mut self, machine: &Machine) {
+        let addr_style = Color::Light(BaseColor::Blue);
+        let reg_style = Color::Light(BaseColor::Magenta);
 
-        // Tile map memory region
-        body.append_plain("- tile map: ");
-        body.append_styled(regs.bg_tile_map_address().to_string(), reg_style);
-        body.append_plain("\n");
+        let mut body = StyledString::new();
+        if machine.trace().is_empty() {
+            body.append_plain("No instructions traced (see `--trace-len`)");
+        }
 
-        // Scroll
-        body.append_plain("- X: ");
-        body.append_styled(format!("{: >3}", regs.scroll_bg_x.get()), reg_style);
-        body.append_plain(",  Y: ");
-        body.append_styled(format!("{: >3}", regs.scroll_bg_y.get()), reg_style);
-        body.append_plain("\n");
+        for entry in machine.trace() {
+            body.append_styled(format!("{} │   ", entry.pc), addr_style);
+
+            let instr = match instr::decode(entry.pc, &entry.bytes) {
+                Some(instr) => styled_disassembly(&instr),
+                None => StyledString::plain(entry.bytes[0].to_string()),
+            };
+            let padding = 28usize.saturating_sub(instr.width());
+            body.append(instr);
+            body.append_plain(" ".repeat(padding));
+
+            let cpu = &entry.cpu;
+            let regs = [
+                ("A", cpu.a.get()),
+                ("F", cpu.f.get()),
+                ("B", cpu.b.get()),
+                ("C", cpu.c.get()),
+                ("D", cpu.d.get()),
+                ("E", cpu.e.get()),
+                ("H", cpu.h.get()),
+                ("L", cpu.l.get()),
+            ];
+            for &(name, value) in &regs {
+                body.append_plain(format!("{}:", name));
+                body.append_styled(format!("{:02x} ", value), reg_style);
+            }
+            body.append_plain("SP:");
+            body.append_styled(format!("{:04x}", cpu.sp.get()), reg_style);
+            body.append_plain("\n");
+        }
 
-        body.append_plain("\n");
+        self.siv.find_name::<TextView>("trace_view").unwrap().set_content(body);
+    }
 
+    fn update_watch_data(&mut self, machine: &Machine) {
+        self.watches.update(machine);
+        self.update_watch_list(machine);
+    }
 
-        // ===== Window information =====
-        body.append_plain("## Window: \n");
+    /// Shows the current values of the watch expressions without evaluating
+    /// them again.
+    fn update_watch_list(&mut self, machine: &Machine) {
+        let mut view = self.siv.find_name::<SelectView<usize>>("watch_list").unwrap();
+        view.clear();
+        for (i, label) in self.watches.labels(machine).into_iter().enumerate() {
+            view.add_item(label, i);
+        }
+    }
 
-        // Enabled?
-        body.append_plain("- ");
-        let win_status = if regs.is_window_enabled() { "enabled" } else { "disabled" };
-        body.append_styled(win_status, reg_style);
-        body.append_plain("\n");
+    fn update_io_data(&mut self, machine: &Machine) {
+        let body = io_regs::io_register_text(machine);
+        self.siv.find_name::<TextView>("io_data").unwrap().set_content(body);
+    }
 
-        // Tile map memory region
-        body.append_plain("- tile map: ");
-        body.append_styled(regs.window_tile_map_address().to_string(), reg_style);
-        body.append_plain("\n");
+    fn update_timer_data(&mut self, machine: &Machine) {
+        let reg_style = Color::Light(BaseColor::Magenta);
+        let timer = machine.timer();
 
-        // Scroll position
-        body.append_plain("- X: ");
-        body.append_styled(format!("{: >3}", regs.scroll_win_x.get()), reg_style);
-        body.append_plain(",  Y: ");
-        body.append_styled(format!("{: >3}", regs.scroll_win_y.get()), reg_style);
-        body.append_plain("\n");
+        let mut body = StyledString::new();
+        body.append_plain("DIV: ");
+        body.append_styled(timer.internal_counter().to_string(), reg_style);
+        body.append_plain("  TIMA: ");
+        body.append_styled(timer.counter().to_string(), reg_style);
+        body.append_plain("  TMA: ");
+        body.append_styled(timer.modulo().to_string(), reg_style);
+
+        body.append_plain("\nTAC: ");
+        body.append_styled(timer.control().to_string(), reg_style);
+        body.append_plain(format!(
+            " ({}, {}Hz)",
+            if timer.is_enabled() { "on" } else { "off" },
+            4_194_304 / timer.increment_period(),
+        ));
+
+        // Cycles are 1MHz cycles, like in the header
+        let cycles = |c: Option<u64>| c.map_or("-".to_string(), |c| c.to_string());
+        body.append_plain("\nNext increment in: ");
+        body.append_styled(cycles(timer.cycles_until_increment()), reg_style);
+        body.append_plain("\nNext interrupt in: ");
+        body.append_styled(cycles(timer.cycles_until_interrupt()), reg_style);
+
+        self.siv.find_name::<TextView>("timer_data").unwrap().set_content(body);
+    }
 
-        body.append_plain("\n");
+    fn update_mbc_data(&mut self, machine: &Machine) {
+        let reg_style = Color::Light(BaseColor::Magenta);
+        let cartridge = &machine.cartridge;
+        let mbc = cartridge.mbc();
 
+        let mut body = StyledString::new();
+        body.append_plain("Type: ");
+        body.append_styled(mbc.name(), reg_style);
+        body.append_plain(format!(" ({:?})", cartridge.cartridge_type()));
+
+        body.append_plain("\nROM bank: ");
+        body.append_styled(format!("{:02x}", mbc.rom_bank()), reg_style);
+        body.append_plain(format!(" of {}", mbc.rom().len() / 0x4000));
+
+        body.append_plain("\nRAM bank: ");
+        body.append_styled(format!("{:02x}", mbc.ram_bank()), reg_style);
+        match mbc.ram().len() {
+            0 => body.append_plain(" (no RAM)"),
+            len => body.append_plain(format!(" of {}", (len / 0x2000).max(1))),
+        }
 
-        // ===== Sprite information =====
-        body.append_plain("## Sprites: \n");
+        body.append_plain("\nRAM enabled: ");
+        let enabled = match mbc.ram_enabled() {
+            Some(true) => "yes",
+            Some(false) => "no",
+            None => "-",
+        };
+        body.append_styled(enabled, reg_style);
 
-        // Enabled?
-        body.append_plain("- ");
-        let sprite_status = if regs.are_sprites_enabled() { "enabled" } else { "disabled" };
-        body.append_styled(sprite_status, reg_style);
-        body.append_plain("\n");
+        body.append_plain("\nBanking mode: ");
+        body.append_styled(mbc.banking_mode().unwrap_or("-"), reg_style);
 
-        // Size
-        body.append_plain("- Size: ");
-        let sprite_size = if regs.large_sprites_enabled() { "8x16" } else { "8x8" };
-        body.append_styled(sprite_size, reg_style);
-        body.append_plain("\n");
+        self.siv.find_name::<TextView>("mbc_data").unwrap().set_content(body);
+    }
 
+    fn update_apu_data(&mut self, machine: &Machine) {
+        let body = apu::apu_text(machine);
+        self.siv.find_name::<TextView>("apu_data").unwrap().set_content(body);
+    }
 
-        self.siv.find_name::<TextView>("ppu_data").unwrap().set_content(body);
+    fn update_timeline_data(&mut self, machine: &Machine) {
+        let body = timeline::timeline_text(machine);
+        self.siv.find_name::<TextView>("timeline_data").unwrap().set_content(body);
     }
 
-    fn update_cpu_data(&mut self, cpu: &Cpu) {
+    fn update_cpu_data(&mut self, machine: &Machine) {
         let reg_style = Color::Light(BaseColor::Magenta);
+        let cpu = &machine.cpu;
 
         let mut body = StyledString::new();
 
@@ -768,6 +1560,13 @@ impl TuiDebugger {
         body.append_plain("  C: ");
         body.append_styled((cpu.carry() as u8).to_string(), reg_style);
 
+        // Currently mapped cartridge banks
+        body.append_plain("\n\n");
+        body.append_plain("ROM bank: ");
+        body.append_styled(machine.cartridge.rom_bank().to_string(), reg_style);
+        body.append_plain("  RAM bank: ");
+        body.append_styled(machine.cartridge.ram_bank().to_string(), reg_style);
+
         self.siv.find_name::<TextView>("cpu_data").unwrap().set_content(body);
     }
 
@@ -805,45 +1604,133 @@ impl TuiDebugger {
         body.append_plain("IF:   ");
         body.append_styled(bit_string(ints.interrupt_flag.get()), reg_style);
         body.append_plain("\n");
-        body.append_plain("\n");
-
-
-        // LCD stat interrupts
-        body.append_plain("            =  O  V  H\n");
-        body.append_plain("LCD stat:   ");
-        body.append_styled(
-            (machine.ppu.regs().coincidence_interrupt() as u8).to_string(),
-            reg_style,
-        );
-        body.append_plain("  ");
-        body.append_styled(
-            (machine.ppu.regs().oam_search_interrupt() as u8).to_string(),
-            reg_style,
-        );
-        body.append_plain("  ");
-        body.append_styled(
-            (machine.ppu.regs().vblank_interrupt() as u8).to_string(),
-            reg_style,
-        );
-        body.append_plain("  ");
-        body.append_styled(
-            (machine.ppu.regs().hblank_interrupt() as u8).to_string(),
-            reg_style,
-        );
-        body.append_plain("\n");
 
 
         self.siv.find_name::<TextView>("interrupt_view").unwrap().set_content(body);
     }
 
     /// Create the body of the debugging tab.
+    fn profiler_tab(&self) -> LinearLayout {
+        // The buttons just execute the corresponding console commands.
+        let button = |label, command: &'static str| {
+            let sink = self.command_sink.clone();
+            Button::new(label, move |_| sink.send(command.into()).unwrap())
+        };
+        let buttons = LinearLayout::horizontal()
+            .child(button("Start", "profile on"))
+            .child(DummyView)
+            .child(button("Stop", "profile off"))
+            .child(DummyView)
+            .child(button("Reset", "profile reset"));
+
+        let report = TextView::new(self.profiler.report(&self.symbols))
+            .with_name("profiler_data")
+            .scrollable();
+
+        LinearLayout::vertical()
+            .child(buttons)
+            .child(DummyView)
+            .child(report)
+    }
+
+    fn heatmap_tab(&self) -> LinearLayout {
+        // The buttons just execute the corresponding console commands.
+        let button = |label, command: &'static str| {
+            let sink = self.command_sink.clone();
+            Button::new(label, move |_| sink.send(command.into()).unwrap())
+        };
+        let buttons = LinearLayout::horizontal()
+            .child(button("Start", "heatmap on"))
+            .child(DummyView)
+            .child(button("Stop", "heatmap off"))
+            .child(DummyView)
+            .child(button("Reset", "heatmap reset"));
+
+        let report = TextView::new("The heatmap is disabled.")
+            .with_name("heatmap_data")
+            .scrollable();
+
+        LinearLayout::vertical()
+            .child(buttons)
+            .child(DummyView)
+            .child(report)
+    }
+
+    fn ram_search_tab(&self) -> LinearLayout {
+        // The buttons just execute the corresponding console commands.
+        let button = |label, command: &'static str| {
+            let sink = self.command_sink.clone();
+            Button::new(label, move |_| sink.send(command.into()).unwrap())
+        };
+        let buttons = LinearLayout::horizontal()
+            .child(button("Reset", "ramsearch reset"))
+            .child(DummyView)
+            .child(button("Equal", "ramsearch eq"))
+            .child(DummyView)
+            .child(button("Not equal", "ramsearch ne"))
+            .child(DummyView)
+            .child(button("Increased", "ramsearch inc"))
+            .child(DummyView)
+            .child(button("Decreased", "ramsearch dec"));
+
+        let report = TextView::new("no search started")
+            .with_name("ram_search_data")
+            .scrollable();
+
+        // Frozen addresses are added via console commands
+        let tx = self.command_sink.clone();
+        let frozen_list = SelectView::<Word>::new()
+            .on_submit(move |_, addr| tx.send(format!("unfreeze {:04x}", addr.get())).unwrap())
+            .with_name("frozen_list")
+            .scrollable();
+        let frozen_view = Dialog::around(frozen_list)
+            .title("Frozen (enter removes)")
+            .fixed_width(36);
+
+        LinearLayout::vertical()
+            .child(buttons)
+            .child(DummyView)
+            .child(LinearLayout::horizontal()
+                .child(report.full_width())
+                .child(frozen_view))
+    }
+
     fn debug_tab(&self) -> OnEventView<ResizedView<LinearLayout>> {
         // Main body (left)
-        let asm_view = AsmView::new(self.breakpoints.clone())
+        let asm_view = AsmView::new(
+            self.breakpoints.clone(),
+            self.coverage.clone(),
+            self.symbols.clone(),
+            self.analysis.clone(),
+        );
+        let asm_view = asm_view
             .with_name("asm_view")
             .scrollable()
             .with_name("asm_view_scroll");
 
+        // Command console (below the ASM view)
+        let console_output = TextView::new("Type `help` for a list of commands.\n")
+            .with_name("console_output")
+            .scrollable()
+            .scroll_strategy(ScrollStrategy::StickToBottom)
+            .fixed_height(6);
+        let tx = self.command_sink.clone();
+        let console_input = EditView::new()
+            .on_submit(move |s, input| {
+                tx.send(input.to_string()).unwrap();
+                s.call_on_name("console_input", |view: &mut EditView| view.set_content(""));
+            })
+            .with_name("console_input");
+        let console_view = Dialog::around(
+            LinearLayout::vertical()
+                .child(console_output)
+                .child(console_input)
+        ).title("Console [:]");
+
+        let left_panel = LinearLayout::vertical()
+            .child(asm_view)
+            .child(console_view);
+
         // First right column
         let cpu_body = TextView::new("no data yet").center().with_name("cpu_data");
         let cpu_view = Dialog::around(cpu_body).title("CPU registers");
@@ -854,6 +1741,15 @@ impl TuiDebugger {
             .fixed_height(8);
         let stack_view = Dialog::around(stack_body).title("Stack");
 
+        let call_stack_body = SelectView::<Word>::new()
+            .on_submit(|s, &call_site| {
+                s.call_on_name("asm_view", |view: &mut AsmView| view.set_focus(call_site));
+            })
+            .with_name("call_stack")
+            .scrollable()
+            .fixed_height(5);
+        let call_stack_view = Dialog::around(call_stack_body).title("Call stack");
+
         let interrupt_body = TextView::new("no data yet")
             .with_name("interrupt_view");
         let interrupt_view = Dialog::around(interrupt_body).title("Interrupts");
@@ -863,24 +1759,74 @@ impl TuiDebugger {
             .child(DummyView)
             .child(stack_view)
             .child(DummyView)
+            .child(call_stack_view)
+            .child(DummyView)
             .child(interrupt_view)
             .fixed_width(30);
 
         // Second right column
-        let ppu_body = TextView::new("not implemented yet").with_name("ppu_data");
-        let ppu_view = Dialog::around(ppu_body).title("PPU");
+        let io_body = TextView::new("no data yet")
+            .with_name("io_data")
+            .scrollable();
+        let io_view = Dialog::around(io_body).title("IO registers");
+
+        let timer_body = TextView::new("no data yet").with_name("timer_data");
+        let timer_view = Dialog::around(timer_body).title("Timer");
+
+        let mbc_body = TextView::new("no data yet").with_name("mbc_data");
+        let mbc_view = Dialog::around(mbc_body).title("Memory bank controller");
+
+        // Watch expressions are added and removed via console commands
+        let tx = self.command_sink.clone();
+        let watch_list = SelectView::<usize>::new()
+            .on_submit(move |_, idx| tx.send(format!("undisplay {}", idx)).unwrap())
+            .with_name("watch_list")
+            .scrollable()
+            .fixed_height(5);
+        let tx = self.command_sink.clone();
+        let watch_edit = EditView::new()
+            .on_submit(move |s, input| {
+                tx.send(format!("display {}", input)).unwrap();
+                s.call_on_name("watch_edit", |view: &mut EditView| view.set_content(""));
+            })
+            .with_name("watch_edit");
+        let watch_body = LinearLayout::vertical()
+            .child(watch_list)
+            .child(LinearLayout::horizontal()
+                .child(TextView::new("Add: "))
+                .child(watch_edit.full_width()));
+        let watch_view = Dialog::around(watch_body).title("Watch (enter removes)");
 
         // Setup Buttons
         let button_breakpoints = {
             let breakpoints = self.breakpoints.clone(); // clone for closure
+            let symbols = self.symbols.clone();
             Button::new("Manage Breakpoints [b]", move |s| {
-                Self::open_breakpoints_dialog(s, &breakpoints)
+                Self::open_breakpoints_dialog(s, &breakpoints, &symbols)
             })
         };
 
-        let mem_button = Button::new("View memory [m]", |s| {
-            Self::open_memory_dialog(s)
-        });
+        let button_watchpoints = {
+            let watchpoints = self.watchpoints.clone(); // clone for closure
+            Button::new("Manage Watchpoints [w]", move |s| {
+                Self::open_watchpoints_dialog(s, &watchpoints)
+            })
+        };
+
+        let toggle_bp_button = Button::new(
+            "Toggle breakpoint [B]",
+            Self::toggle_breakpoint_at_active_line,
+        );
+
+        let patch_button = {
+            let patches = self.patches.clone();
+            Button::new("Patch code [a]", move |s| Self::open_patch_dialog(s, &patches))
+        };
+
+        let mem_button = {
+            let symbols = self.symbols.clone();
+            Button::new("View memory [m]", move |s| Self::open_memory_dialog(s, &symbols))
+        };
 
         // Buttons for the 'r', 's' and 'f' actions
         let tx = self.event_sink.clone();
@@ -888,33 +1834,58 @@ impl TuiDebugger {
         let tx = self.event_sink.clone();
         let step_button = Button::new("Single step [s]", move |_| tx.send('s').unwrap());
         let tx = self.event_sink.clone();
+        let back_button = Button::new("Step back [u]", move |_| tx.send('u').unwrap());
+        let tx = self.event_sink.clone();
         let fun_end_button = Button::new("Run to RET-like [f]", move |_| tx.send('f').unwrap());
         let tx = self.event_sink.clone();
+        let interrupt_button = Button::new(
+            "Run to next interrupt [i]",
+            move |_| tx.send('i').unwrap(),
+        );
+        let tx = self.event_sink.clone();
         let line_button = Button::new("Run to next line [l]", move |_| tx.send('l').unwrap());
         let tx = self.event_sink.clone();
         let frame_button = Button::new("Run to next frame [k]", move |_| tx.send('k').unwrap());
 
+        let bank_switch_flag = self.break_on_bank_switch.clone();
+        let bank_switch_checkbox = LinearLayout::horizontal()
+            .child(Checkbox::new().on_change(move |_, checked| bank_switch_flag.set(checked)))
+            .child(TextView::new(" Break on bank switch"));
+
         // Wrap all buttons
         let debug_buttons = LinearLayout::vertical()
             .child(button_breakpoints)
+            .child(toggle_bp_button)
+            .child(button_watchpoints)
+            .child(patch_button)
             .child(mem_button)
             .child(run_button)
             .child(step_button)
+            .child(back_button)
             .child(fun_end_button)
+            .child(interrupt_button)
             .child(line_button)
-            .child(frame_button);
+            .child(frame_button)
+            .child(DummyView)
+            .child(bank_switch_checkbox);
         let debug_buttons = Dialog::around(debug_buttons).title("Actions");
 
         // Build the complete right side
         let second_right_panel = LinearLayout::vertical()
-            .child(ppu_view)
+            .child(io_view)
+            .child(DummyView)
+            .child(timer_view)
+            .child(DummyView)
+            .child(mbc_view)
+            .child(DummyView)
+            .child(watch_view)
             .child(DummyView)
             .child(debug_buttons)
-            .fixed_width(30);
+            .fixed_width(44);
 
         // Combine
         let view = LinearLayout::horizontal()
-            .child(asm_view)
+            .child(left_panel)
             .child(first_right_panel)
             .child(DummyView)
             .child(second_right_panel)
@@ -922,30 +1893,106 @@ impl TuiDebugger {
 
         // Add shortcuts for debug tab
         let breakpoints = self.breakpoints.clone();
+        let watchpoints = self.watchpoints.clone();
+        let symbols = self.symbols.clone();
+        let symbols_for_mem = self.symbols.clone();
+        let patches = self.patches.clone();
         OnEventView::new(view)
-            .on_event('b', move |s| Self::open_breakpoints_dialog(s, &breakpoints))
-            .on_event('m', |s| Self::open_memory_dialog(s))
+            .on_event('b', move |s| Self::open_breakpoints_dialog(s, &breakpoints, &symbols))
+            .on_event('w', move |s| Self::open_watchpoints_dialog(s, &watchpoints))
+            .on_event('m', move |s| Self::open_memory_dialog(s, &symbols_for_mem))
+            .on_event('B', Self::toggle_breakpoint_at_active_line)
+            .on_event('a', move |s| Self::open_patch_dialog(s, &patches))
+            .on_event(':', |s| {
+                let _ = s.focus_name("console_input");
+            })
+    }
+
+    /// Toggles the breakpoint at PC (or the focused address) in the ASM view.
+    fn toggle_breakpoint_at_active_line(siv: &mut Cursive) {
+        siv.call_on_name("asm_view", |view: &mut AsmView| {
+            view.toggle_breakpoint_at_active_line();
+        });
+    }
+
+    /// Opens the dialog to replace the instruction at PC (or the focused
+    /// address) by assembled code. It also lists the applied patches, the
+    /// last of which can be undone.
+    fn open_patch_dialog(siv: &mut Cursive, patches: &Patches) {
+        let active = siv.call_on_name("asm_view", |view: &mut AsmView| view.active_instr());
+        let (addr, source) = match active.flatten() {
+            Some(active) => active,
+            None => return,
+        };
+
+        let hex = |bytes: &[Byte]| {
+            bytes.iter().map(|b| format!("{:02x}", b.get())).collect::<Vec<_>>().join(" ")
+        };
+        let applied = patches.applied();
+        let patch_list = if applied.is_empty() {
+            "no patches applied".to_string()
+        } else {
+            applied.iter()
+                .map(|p| {
+                    format!("{}: {} ({} -> {})", p.addr, p.source, hex(&p.original), hex(&p.code))
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+
+        // The code is assembled in the next `update()` call, where the
+        // machine is available.
+        let patches_for_edit = patches.clone();
+        let edit = EditView::new()
+            .content(source)
+            .on_submit(move |s, input| {
+                patches_for_edit.request_patch(addr, input);
+                s.pop_layer();
+            })
+            .fixed_width(32);
+
+        let body = LinearLayout::vertical()
+            .child(TextView::new(patch_list).scrollable().max_height(10))
+            .child(DummyView)
+            .child(LinearLayout::horizontal()
+                .child(TextView::new(format!("Replace {} by:  ", addr)))
+                .child(edit));
+
+        let patches = patches.clone();
+        let dialog = Dialog::around(body)
+            .title("Patch code (padded with NOPs)")
+            .button("Undo last patch", move |s| {
+                patches.request_undo();
+                s.pop_layer();
+            })
+            .button("Cancel", |s| { s.pop_layer(); });
+
+        siv.add_layer(dialog);
     }
 
     /// Gets executed when the "Manage breakpoints" action button is pressed.
-    fn open_breakpoints_dialog(siv: &mut Cursive, breakpoints: &Breakpoints) {
+    fn open_breakpoints_dialog(
+        siv: &mut Cursive,
+        breakpoints: &Breakpoints,
+        symbols: &Rc<Symbols>,
+    ) {
         // Setup list showing all breakpoints
-        let bp_list = Self::create_breakpoint_list(breakpoints)
+        let bp_list = Self::create_breakpoint_list(breakpoints, symbols)
             .with_name("breakpoint_list");
 
         // Setup the field to add a breakpoint
         let breakpoints = breakpoints.clone(); // clone for closure
+        let symbols = symbols.clone();
         let add_breakpoint_edit = EditView::new()
-            .max_content_width(4)
             .on_submit(move |s, input| {
-                // Try to parse the input as hex value
-                match u16::from_str_radix(&input, 16) {
-                    Ok(addr) => {
+                // Try to parse the input as symbol, `bank:addr` or hex value
+                match Location::parse(input, &symbols) {
+                    Ok(loc) => {
                         // Add it to the breakpoints collection and update the
                         // list view.
-                        breakpoints.add(Word::new(addr));
+                        breakpoints.add(loc);
                         s.call_on_name("breakpoint_list", |list: &mut ListView| {
-                            *list = Self::create_breakpoint_list(&breakpoints);
+                            *list = Self::create_breakpoint_list(&breakpoints, &symbols);
                         });
                     },
                     Err(e) => {
@@ -954,7 +2001,7 @@ impl TuiDebugger {
                     }
                 }
             })
-            .fixed_width(7);
+            .fixed_width(24);
 
         let add_breakpoint = LinearLayout::horizontal()
             .child(TextView::new("Add breakpoint:  "))
@@ -976,38 +2023,170 @@ impl TuiDebugger {
     }
 
     /// Creates a list of all breakpoints in the given collection. For each
-    /// breakpoint, there is a button to remove the breakpoint. This function
-    /// assumes that the returned view is added to the Cursive instance with
-    /// the id "breakpoint_list"!
-    fn create_breakpoint_list(breakpoints: &Breakpoints) -> ListView {
+    /// breakpoint, there are buttons to edit its condition and to remove the
+    /// breakpoint. This function assumes that the returned view is added to
+    /// the Cursive instance with the id "breakpoint_list"!
+    fn create_breakpoint_list(breakpoints: &Breakpoints, symbols: &Rc<Symbols>) -> ListView {
         let mut out = ListView::new();
 
-        for bp in breakpoints.as_sorted_list() {
+        for (bp, condition) in breakpoints.as_sorted_list() {
+            let breakpoints_for_edit = breakpoints.clone();
+            let symbols_for_edit = symbols.clone();
+            let condition_button = Button::new("Condition", move |s| {
+                Self::open_condition_dialog(s, &breakpoints_for_edit, &symbols_for_edit, bp);
+            });
+
             let breakpoints = breakpoints.clone();
+            let symbols_for_remove = symbols.clone();
             let remove_button = Button::new("Remove", move |s| {
                 breakpoints.remove(bp);
                 s.call_on_name("breakpoint_list", |list: &mut ListView| {
-                    *list = Self::create_breakpoint_list(&breakpoints);
+                    *list = Self::create_breakpoint_list(&breakpoints, &symbols_for_remove);
+                });
+            });
+
+            let buttons = LinearLayout::horizontal()
+                .child(condition_button)
+                .child(DummyView)
+                .child(remove_button);
+
+            // For breakpoints in all banks, we just show the first symbol
+            // for the address.
+            let mut label = bp.to_string();
+            if let Some(name) = symbols.name_at(bp.addr, bp.bank.unwrap_or(1)) {
+                label += &format!(" ({})", name);
+            }
+            if let Some(c) = condition {
+                label += &format!(" if {}", c.source);
+            }
+            out.add_child(&label, buttons);
+        }
+
+        out
+    }
+
+    /// Opens a dialog to edit the condition of the breakpoint at `addr`. An
+    /// empty condition makes the breakpoint unconditional.
+    fn open_condition_dialog(
+        siv: &mut Cursive,
+        breakpoints: &Breakpoints,
+        symbols: &Rc<Symbols>,
+        loc: Location,
+    ) {
+        let current = breakpoints.condition(loc)
+            .map(|c| c.source)
+            .unwrap_or_default();
+
+        let breakpoints = breakpoints.clone();
+        let symbols = symbols.clone();
+        let edit = EditView::new()
+            .content(current)
+            .on_submit(move |s, input| {
+                let condition = if input.trim().is_empty() {
+                    None
+                } else {
+                    match Expr::parse(input, &symbols) {
+                        Ok(expr) => Some(Condition { source: input.trim().to_string(), expr }),
+                        Err(e) => {
+                            s.add_layer(Dialog::info(format!("invalid condition: {}", e)));
+                            return;
+                        }
+                    }
+                };
+
+                breakpoints.set_condition(loc, condition);
+                s.pop_layer();
+                s.call_on_name("breakpoint_list", |list: &mut ListView| {
+                    *list = Self::create_breakpoint_list(&breakpoints, &symbols);
+                });
+            })
+            .fixed_width(40);
+
+        let body = LinearLayout::vertical()
+            .child(TextView::new("Pause only if this expression is non-zero, e.g."))
+            .child(TextView::new("`A == 0x3F && [HL] > 4`. Leave empty to always pause."))
+            .child(DummyView)
+            .child(edit);
+
+        let dialog = Dialog::around(body)
+            .title(format!("Condition for breakpoint {}", loc))
+            .button("Cancel", |s| { s.pop_layer(); });
+
+        siv.add_layer(dialog);
+    }
+
+    /// Gets executed when the "Manage watchpoints" action button is pressed.
+    fn open_watchpoints_dialog(siv: &mut Cursive, watchpoints: &Watchpoints) {
+        let wp_list = Self::create_watchpoint_list(watchpoints)
+            .with_name("watchpoint_list");
+
+        // Setup the field to add a watchpoint
+        let watchpoints = watchpoints.clone(); // clone for closure
+        let add_watchpoint_edit = EditView::new()
+            .on_submit(move |s, input| {
+                match parse_watchpoint(input) {
+                    Ok(w) => {
+                        watchpoints.add(w);
+                        s.call_on_name("watchpoint_list", |list: &mut ListView| {
+                            *list = Self::create_watchpoint_list(&watchpoints);
+                        });
+                    }
+                    Err(e) => {
+                        s.add_layer(Dialog::info(format!("invalid watchpoint: {}", e)));
+                    }
+                }
+            })
+            .fixed_width(16);
+
+        let add_watchpoint = LinearLayout::horizontal()
+            .child(TextView::new("Add watchpoint:  "))
+            .child(add_watchpoint_edit);
+
+        let body = LinearLayout::vertical()
+            .child(wp_list)
+            .child(DummyView)
+            .child(add_watchpoint)
+            .child(TextView::new("Examples: `C000 w`, `FF80-FFFE rw`, `D000 r`"));
+
+        let dialog = Dialog::around(body)
+            .title("Watchpoints")
+            .button("Ok", |s| { s.pop_layer(); });
+
+        siv.add_layer(dialog);
+    }
+
+    /// Creates a list of all watchpoints with a button to remove each. The
+    /// returned view has to be named "watchpoint_list".
+    fn create_watchpoint_list(watchpoints: &Watchpoints) -> ListView {
+        let mut out = ListView::new();
+
+        for w in watchpoints.as_list() {
+            let label = format_watchpoint(&w);
+            let watchpoints = watchpoints.clone();
+            let remove_button = Button::new("Remove", move |s| {
+                watchpoints.remove(&w);
+                s.call_on_name("watchpoint_list", |list: &mut ListView| {
+                    *list = Self::create_watchpoint_list(&watchpoints);
                 });
             });
 
-            out.add_child(&bp.to_string(), remove_button);
+            out.add_child(&label, remove_button);
         }
 
         out
     }
 
     /// Gets executed when the "View memory" action button is pressed.
-    fn open_memory_dialog(siv: &mut Cursive) {
+    fn open_memory_dialog(siv: &mut Cursive, symbols: &Rc<Symbols>) {
+        let symbols_for_jump = symbols.clone();
         let jump_to_edit = EditView::new()
-            .max_content_width(4)
             .on_submit(move |s, input| {
-                // Try to parse the input as hex value
-                match u16::from_str_radix(&input, 16) {
+                // Try to parse the input as symbol or hex value
+                match symbols_for_jump.resolve(input) {
                     Ok(addr) => {
                         // Set cursor
                         let mut mem_view = s.find_name::<MemView>("mem_view").unwrap();
-                        mem_view.cursor = Word::new(addr);
+                        mem_view.cursor = addr;
                     },
                     Err(e) => {
                         let msg = format!("invalid addr: {}", e);
@@ -1015,20 +2194,71 @@ impl TuiDebugger {
                     }
                 }
             })
-            .fixed_width(7);
+            .fixed_width(24);
 
         let jump_to = LinearLayout::horizontal()
             .child(TextView::new("Jump to:  "))
             .child(jump_to_edit);
 
-        let mem_view = MemView::new()
+        let raw_checkbox = Checkbox::new().on_change(|s, checked| {
+            s.call_on_name("mem_view", |view: &mut MemView| view.raw_writes = checked);
+        });
+        let raw_writes = LinearLayout::horizontal()
+            .child(raw_checkbox)
+            .child(TextView::new(" Raw writes (bypass MBC and IO side effects)"));
+
+        // Setup the search
+        let mut kind_group = RadioGroup::new();
+        let search_kinds = LinearLayout::horizontal()
+            .child(kind_group.button(SearchKind::Bytes, "bytes"))
+            .child(DummyView)
+            .child(kind_group.button(SearchKind::U8, "8 bit"))
+            .child(DummyView)
+            .child(kind_group.button(SearchKind::U16, "16 bit"))
+            .child(DummyView)
+            .child(kind_group.button(SearchKind::Text, "text"))
+            .child(DummyView)
+            .child(Checkbox::new().with_name("search_all_banks"))
+            .child(TextView::new(" all ROM banks"));
+
+        let search_edit = EditView::new()
+            .on_submit(move |s, input| {
+                let all_rom_banks = s.find_name::<Checkbox>("search_all_banks")
+                    .unwrap()
+                    .is_checked();
+                match Query::parse(*kind_group.selection(), input, all_rom_banks) {
+                    Ok(query) => {
+                        s.call_on_name("mem_view", |view: &mut MemView| view.search(query));
+                    }
+                    Err(e) => s.add_layer(Dialog::info(format!("invalid search: {}", e))),
+                }
+            })
+            .fixed_width(24);
+
+        let search = LinearLayout::horizontal()
+            .child(TextView::new("Search:   "))
+            .child(search_edit)
+            .child(DummyView)
+            .child(Button::new("Previous", |s| {
+                s.call_on_name("mem_view", |view: &mut MemView| view.prev_hit());
+            }))
+            .child(DummyView)
+            .child(Button::new("Next", |s| {
+                s.call_on_name("mem_view", |view: &mut MemView| view.next_hit());
+            }));
+
+        let mem_view = MemView::new(symbols.clone())
             .with_name("mem_view");
 
         // Combine all elements
         let body = LinearLayout::vertical()
             .child(mem_view)
             .child(DummyView)
-            .child(jump_to);
+            .child(jump_to)
+            .child(raw_writes)
+            .child(DummyView)
+            .child(search)
+            .child(search_kinds);
 
         // Put into `Dialog` and show dialog
         let dialog = Dialog::around(body)
@@ -1040,36 +2270,405 @@ impl TuiDebugger {
 }
 
 
-/// A collection of breakpoints.
+/// An address in the address space, optionally with a ROM bank for
+/// addresses in the switchable ROM area (`0x4000..0x8000`).
+///
+/// Displayed as `bank:addr` (e.g. `05:4123`) if the bank is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Location {
+    pub(crate) addr: Word,
+
+    /// `None` means: any bank. Always `None` for addresses outside of the
+    /// switchable ROM area.
+    pub(crate) bank: Option<usize>,
+}
+
+impl Location {
+    /// Creates a location for `addr` in the given ROM bank. The bank is only
+    /// stored if `addr` is in the switchable ROM area.
+    pub(crate) fn new(addr: Word, rom_bank: usize) -> Self {
+        let bank = if is_banked(addr) { Some(rom_bank) } else { None };
+        Self { addr, bank }
+    }
+
+    /// Creates a location that matches `addr` in all banks.
+    pub(crate) fn any_bank(addr: Word) -> Self {
+        Self { addr, bank: None }
+    }
+
+    /// Parses `bank:addr` (both hexadecimal), a symbol name or a hexadecimal
+    /// address (which then matches all banks).
+    pub(crate) fn parse(input: &str, symbols: &Symbols) -> Result<Self, String> {
+        let input = input.trim();
+        if let Some((bank, addr)) = symbols.get(input) {
+            return Ok(Self::new(addr, bank));
+        }
+
+        if let Some((bank, addr)) = input.split_once(':') {
+            let bank = usize::from_str_radix(bank, 16)
+                .map_err(|e| format!("invalid bank '{}': {}", bank, e))?;
+            let addr = u16::from_str_radix(addr, 16)
+                .map(Word::new)
+                .map_err(|e| format!("invalid address '{}': {}", addr, e))?;
+            if !is_banked(addr) {
+                return Err(format!("{} is not in the switchable ROM area", addr));
+            }
+
+            return Ok(Self::new(addr, bank));
+        }
+
+        symbols.resolve(input).map(Self::any_bank)
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.bank {
+            Some(bank) => write!(f, "{:02X}:{:04X}", bank, self.addr.get()),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
+
+/// Returns `true` if `addr` is in the switchable ROM area.
+pub(crate) fn is_banked(addr: Word) -> bool {
+    (0x4000..0x8000).contains(&addr.get())
+}
+
+/// Returns `true` if `value` looks like a return address pushed by `CALL` or
+/// `RST`, i.e. points into ROM right after such an instruction.
+fn looks_like_return_addr(machine: &Machine, value: Word) -> bool {
+    if value.get() >= 0x8000 || value.get() < 3 {
+        return false;
+    }
+
+    let is_call = matches!(
+        machine.peek_byte(value - 3u16).get(),
+        opcode!("CALL a16")
+            | opcode!("CALL NZ, a16")
+            | opcode!("CALL Z, a16")
+            | opcode!("CALL NC, a16")
+            | opcode!("CALL C, a16")
+    );
+    let is_rst = machine.peek_byte(value - 1u16).get() & 0b1100_0111 == 0b1100_0111;
+
+    is_call || is_rst
+}
+
+/// A collection of breakpoints, each with an optional condition.
 ///
-/// This type uses reference counted pointer and interior mutability to be
-/// easily usable from everywhere. Just `clone()` this to get another owned
+/// Breakpoints in the switchable ROM area can be limited to one bank. This
+/// type uses reference counted pointer and interior mutability to be easily
+/// usable from everywhere. Just `clone()` this to get another owned
 /// reference.
 #[derive(Clone)]
-pub(crate) struct Breakpoints(Rc<RefCell<BTreeSet<Word>>>);
+pub(crate) struct Breakpoints(Rc<RefCell<BTreeMap<Location, Option<Condition>>>>);
+
+/// The condition of a breakpoint.
+#[derive(Debug, Clone)]
+pub(crate) struct Condition {
+    /// The condition as typed by the user.
+    source: String,
+    expr: Expr,
+}
 
 impl Breakpoints {
     fn new() -> Self {
-        Breakpoints(Rc::new(RefCell::new(BTreeSet::new())))
+        Breakpoints(Rc::new(RefCell::new(BTreeMap::new())))
     }
 
-    /// Add a breakpoint to the collection. If it's already inside, nothing
-    /// happens.
-    pub(crate) fn add(&self, addr: Word) {
-        self.0.borrow_mut().insert(addr);
+    /// Add an unconditional breakpoint to the collection. If it's already
+    /// inside, nothing happens.
+    pub(crate) fn add(&self, loc: Location) {
+        self.0.borrow_mut().entry(loc).or_insert(None);
     }
 
     /// Remove a breakpoint. If it's not present in the collection, nothing
     /// happens.
-    fn remove(&self, addr: Word) {
-        self.0.borrow_mut().remove(&addr);
+    fn remove(&self, loc: Location) {
+        self.0.borrow_mut().remove(&loc);
+    }
+
+    fn contains(&self, loc: Location) -> bool {
+        self.0.borrow().contains_key(&loc)
+    }
+
+    /// Returns all breakpoints that trigger at `addr` when `rom_bank` is
+    /// mapped: the one for this bank and the one for all banks.
+    fn matching(&self, addr: Word, rom_bank: usize) -> Vec<Location> {
+        let candidates = [Location::new(addr, rom_bank), Location::any_bank(addr)];
+        let inner = self.0.borrow();
+        let mut out = candidates.iter()
+            .copied()
+            .filter(|loc| inner.contains_key(loc))
+            .collect::<Vec<_>>();
+        out.dedup();
+        out
     }
 
-    fn contains(&self, addr: Word) -> bool {
-        self.0.borrow().contains(&addr)
+    /// Returns `true` if any breakpoint triggers at `addr` in the given bank.
+    fn is_set_at(&self, addr: Word, rom_bank: usize) -> bool {
+        !self.matching(addr, rom_bank).is_empty()
     }
 
-    fn as_sorted_list(&self) -> Vec<Word> {
-        self.0.borrow().iter().cloned().collect()
+    /// Returns the condition of the breakpoint at `loc`.
+    fn condition(&self, loc: Location) -> Option<Condition> {
+        self.0.borrow().get(&loc).cloned().flatten()
+    }
+
+    /// Sets the condition of the breakpoint at `loc`. If there is no
+    /// breakpoint at that location, nothing happens.
+    fn set_condition(&self, loc: Location, condition: Option<Condition>) {
+        if let Some(c) = self.0.borrow_mut().get_mut(&loc) {
+            *c = condition;
+        }
+    }
+
+    /// Returns `true` if there is a breakpoint at the current PC (in the
+    /// current bank) and its condition (if any) evaluates to a non-zero
+    /// value. If evaluating the condition fails, we pause as well.
+    fn should_break(&self, machine: &Machine) -> bool {
+        let inner = self.0.borrow();
+        self.matching(machine.cpu.pc, machine.cartridge.rom_bank())
+            .into_iter()
+            .any(|loc| match &inner[&loc] {
+                None => true,
+                Some(condition) => {
+                    match condition.expr.eval(machine) {
+                        Ok(v) => v != 0,
+                        Err(e) => {
+                            warn!(
+                                "[debugger] failed to evaluate breakpoint condition '{}': {}",
+                                condition.source,
+                                e,
+                            );
+                            true
+                        }
+                    }
+                }
+            })
+    }
+
+    fn as_sorted_list(&self) -> Vec<(Location, Option<Condition>)> {
+        self.0.borrow().iter().map(|(addr, c)| (*addr, c.clone())).collect()
+    }
+}
+
+/// A breakpoint that triggers whenever an instruction with a specific opcode
+/// is about to be executed, regardless of its address. Useful to catch jumps
+/// into garbage data (which often contains `RST 38H` or invalid opcodes).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum OpcodeBreak {
+    Opcode(u8),
+
+    /// The opcode following the `0xCB` prefix.
+    Prefixed(u8),
+
+    /// All opcodes that are not assigned to an instruction.
+    Invalid,
+}
+
+impl OpcodeBreak {
+    /// Parses an opcode breakpoint: a hexadecimal opcode (e.g. `ff`), `cb`
+    /// followed by a hexadecimal opcode (e.g. `cb 37`), a mnemonic (e.g.
+    /// `rst 38h` or `swap a`) or `invalid`.
+    pub(crate) fn parse(input: &str) -> Result<Self, String> {
+        let input = input.trim();
+        let lower = input.to_ascii_lowercase();
+        if input.is_empty() {
+            return Err("no opcode given".into());
+        }
+        if lower == "invalid" {
+            return Ok(OpcodeBreak::Invalid);
+        }
+
+        let hex = |s: &str| if s.len() <= 2 {
+            u8::from_str_radix(s, 16).ok()
+        } else {
+            None
+        };
+        if let Some(op) = hex(input) {
+            return Ok(OpcodeBreak::Opcode(op));
+        }
+        if let Some(op) = lower.strip_prefix("cb ").and_then(|op| hex(op.trim())) {
+            return Ok(OpcodeBreak::Prefixed(op));
+        }
+
+        // Compare mnemonics without whitespace, so that both `ld a,b` and
+        // `LD A, B` are accepted.
+        let normalize = |s: &str| {
+            s.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_ascii_uppercase()
+        };
+        let mnemonic = normalize(input);
+        let found = (0..=255u8).find_map(|op| {
+            let instr = instr::INSTRUCTIONS[Byte::new(op)].as_ref()?;
+            if normalize(instr.mnemonic) == mnemonic {
+                Some(OpcodeBreak::Opcode(op))
+            } else {
+                None
+            }
+        });
+        let found = found.or_else(|| (0..=255u8).find(|&op| {
+            normalize(instr::PREFIXED_INSTRUCTIONS[Byte::new(op)].mnemonic) == mnemonic
+        }).map(OpcodeBreak::Prefixed));
+
+        found.ok_or_else(|| format!("'{}' is neither an opcode nor a mnemonic", input))
+    }
+
+    /// Returns `true` if the instruction at the current PC matches.
+    fn matches(&self, machine: &Machine) -> bool {
+        let op = machine.peek_byte(machine.cpu.pc);
+        match *self {
+            OpcodeBreak::Opcode(expected) => op.get() == expected,
+            OpcodeBreak::Prefixed(expected) => {
+                op.get() == 0xCB && machine.peek_byte(machine.cpu.pc + 1u16).get() == expected
+            }
+            OpcodeBreak::Invalid => instr::INSTRUCTIONS[op].is_none(),
+        }
+    }
+
+    /// Returns the mnemonic of the instruction, if the opcode is valid.
+    fn mnemonic(&self) -> Option<&'static str> {
+        match *self {
+            OpcodeBreak::Opcode(op) => {
+                instr::INSTRUCTIONS[Byte::new(op)].as_ref().map(|i| i.mnemonic)
+            }
+            OpcodeBreak::Prefixed(op) => {
+                Some(instr::PREFIXED_INSTRUCTIONS[Byte::new(op)].mnemonic)
+            }
+            OpcodeBreak::Invalid => None,
+        }
+    }
+}
+
+/// Formats the breakpoint such that `OpcodeBreak::parse` accepts it again.
+impl fmt::Display for OpcodeBreak {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OpcodeBreak::Opcode(op) => write!(f, "{:02X}", op),
+            OpcodeBreak::Prefixed(op) => write!(f, "CB {:02X}", op),
+            OpcodeBreak::Invalid => write!(f, "invalid"),
+        }
+    }
+}
+
+
+/// The currently mapped ROM and RAM bank of the cartridge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Banks {
+    rom: usize,
+    ram: usize,
+}
+
+/// The watchpoints managed in the TUI.
+///
+/// Like `Breakpoints`, this can be cloned cheaply and shared between views.
+/// Changes are only applied to the machine in `Watchpoints::sync`.
+#[derive(Clone)]
+pub(crate) struct Watchpoints(Rc<RefCell<WatchpointsInner>>);
+
+struct WatchpointsInner {
+    list: Vec<Watchpoint>,
+
+    /// Whether `list` was changed since the last `sync`.
+    dirty: bool,
+}
+
+impl Watchpoints {
+    fn new() -> Self {
+        Watchpoints(Rc::new(RefCell::new(WatchpointsInner {
+            list: Vec::new(),
+            dirty: false,
+        })))
+    }
+
+    fn add(&self, w: Watchpoint) {
+        let mut inner = self.0.borrow_mut();
+        if !inner.list.contains(&w) {
+            inner.list.push(w);
+            inner.dirty = true;
+        }
+    }
+
+    fn remove(&self, w: &Watchpoint) {
+        let mut inner = self.0.borrow_mut();
+        inner.list.retain(|other| other != w);
+        inner.dirty = true;
+    }
+
+    fn as_list(&self) -> Vec<Watchpoint> {
+        self.0.borrow().list.clone()
+    }
+
+    /// Makes the watchpoints of the machine match ours (if anything changed).
+    fn sync(&self, machine: &mut Machine) {
+        let mut inner = self.0.borrow_mut();
+        if !inner.dirty {
+            return;
+        }
+
+        for w in machine.watchpoints().to_vec() {
+            machine.remove_watchpoint(&w);
+        }
+        for w in &inner.list {
+            machine.add_watchpoint(w.clone());
+        }
+        inner.dirty = false;
+    }
+}
+
+/// Parses a watchpoint of the form `<addr>[-<addr>] [r|w|rw]`. Addresses are
+/// hexadecimal. Without the second part, the watchpoint triggers on reads and
+/// writes.
+fn parse_watchpoint(input: &str) -> Result<Watchpoint, String> {
+    let mut parts = input.split_whitespace();
+    let range = parts.next().ok_or("no address given")?;
+    let kind = parts.next().unwrap_or("rw");
+    if parts.next().is_some() {
+        return Err("too many arguments".into());
+    }
+
+    let parse_addr = |s: &str| {
+        u16::from_str_radix(s, 16)
+            .map(Word::new)
+            .map_err(|e| format!("invalid address '{}': {}", s, e))
+    };
+    let (start, end) = match range.find('-') {
+        Some(pos) => (parse_addr(&range[..pos])?, parse_addr(&range[pos + 1..])?),
+        None => {
+            let addr = parse_addr(range)?;
+            (addr, addr)
+        }
+    };
+    if start > end {
+        return Err("start of range is after its end".into());
+    }
+
+    let (on_read, on_write) = match kind {
+        "r" => (true, false),
+        "w" => (false, true),
+        "rw" => (true, true),
+        _ => return Err(format!("invalid kind '{}' (valid: 'r', 'w' and 'rw')", kind)),
+    };
+
+    Ok(Watchpoint {
+        range: start..=end,
+        on_read,
+        on_write,
+    })
+}
+
+fn format_watchpoint(w: &Watchpoint) -> String {
+    let kind = match (w.on_read, w.on_write) {
+        (true, true) => "read/write",
+        (true, false) => "read",
+        (false, true) => "write",
+        (false, false) => "never",
+    };
+
+    if w.range.start() == w.range.end() {
+        format!("{} ({})", w.range.start(), kind)
+    } else {
+        format!("{}-{} ({})", w.range.start(), w.range.end(), kind)
     }
 }
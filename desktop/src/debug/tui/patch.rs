@@ -0,0 +1,203 @@
+//! Patching code in place: the instruction at an address is replaced by
+//! assembled code (see `instr::assemble`), e.g. to quickly try a fix without
+//! rebuilding the ROM. Patches can be undone in reverse order.
+
+use std::{
+    cell::RefCell,
+    rc::Rc,
+};
+
+use mahboi::{
+    instr,
+    machine::Machine,
+    primitives::{Byte, Word},
+};
+use super::is_banked;
+
+
+/// A patch that was written to memory.
+#[derive(Debug, Clone)]
+pub(crate) struct Patch {
+    pub(crate) addr: Word,
+
+    /// The ROM bank mapped when the patch was applied.
+    rom_bank: usize,
+
+    /// The code as entered by the user.
+    pub(crate) source: String,
+
+    /// The bytes that were overwritten.
+    pub(crate) original: Vec<Byte>,
+
+    /// The assembled code, padded with NOPs.
+    pub(crate) code: Vec<Byte>,
+}
+
+enum Request {
+    Patch { addr: Word, source: String },
+    Undo,
+}
+
+/// The patches made in the TUI.
+///
+/// Like `Watchpoints`, this can be cloned cheaply and shared between views.
+/// Requests made in the TUI are only executed in `Patches::sync`, as the
+/// views have no access to the machine.
+#[derive(Clone)]
+pub(crate) struct Patches(Rc<RefCell<PatchesInner>>);
+
+struct PatchesInner {
+    /// All applied patches, the most recent one last.
+    applied: Vec<Patch>,
+
+    /// Requests that were not executed yet.
+    pending: Vec<Request>,
+}
+
+impl Patches {
+    pub(crate) fn new() -> Self {
+        Patches(Rc::new(RefCell::new(PatchesInner {
+            applied: Vec::new(),
+            pending: Vec::new(),
+        })))
+    }
+
+    /// Requests to replace the instruction at `addr` by the assembled
+    /// `source`.
+    pub(crate) fn request_patch(&self, addr: Word, source: &str) {
+        let source = source.trim().to_string();
+        self.0.borrow_mut().pending.push(Request::Patch { addr, source });
+    }
+
+    /// Requests to undo the most recent patch.
+    pub(crate) fn request_undo(&self) {
+        self.0.borrow_mut().pending.push(Request::Undo);
+    }
+
+    /// Returns all applied patches, the most recent one last.
+    pub(crate) fn applied(&self) -> Vec<Patch> {
+        self.0.borrow().applied.clone()
+    }
+
+    /// Executes all pending requests. Returns a message describing the
+    /// result of each request.
+    pub(crate) fn sync(&self, machine: &mut Machine) -> Vec<Result<String, String>> {
+        let pending = std::mem::take(&mut self.0.borrow_mut().pending);
+        pending.into_iter()
+            .map(|request| match request {
+                Request::Patch { addr, source } => self.patch(machine, addr, source),
+                Request::Undo => self.undo(machine),
+            })
+            .collect()
+    }
+
+    /// Assembles `source` and writes it to `addr`. The patch always replaces
+    /// whole instructions: the remaining bytes of the last instruction that
+    /// is (partially) overwritten are filled with NOPs.
+    fn patch(&self, machine: &mut Machine, addr: Word, source: String) -> Result<String, String> {
+        let code = instr::assemble_at(addr, &source).map_err(|e| e.to_string())?;
+        if code.is_empty() {
+            return Err("no instruction entered".into());
+        }
+
+        let mut len = 0;
+        while len < code.len() {
+            let at = addr + len as u16;
+            let bytes = [
+                machine.peek_byte(at),
+                machine.peek_byte(at + 1u8),
+                machine.peek_byte(at + 2u8),
+            ];
+            len += instr::decode(at, &bytes).map(|d| d.instr.len as usize).unwrap_or(1);
+        }
+        if addr.get() as usize + len > 0x10000 {
+            return Err("patch does not fit into the address space".into());
+        }
+
+        let original = (0..len).map(|i| machine.peek_byte(addr + i as u16)).collect::<Vec<_>>();
+        let mut code = code.into_iter().map(Byte::new).collect::<Vec<_>>();
+        let nops = len - code.len();
+        code.resize(len, Byte::new(0x00));
+        for (i, &byte) in code.iter().enumerate() {
+            machine.store_byte_raw(addr + i as u16, byte);
+        }
+
+        let msg = format!("patched {} bytes at {}: {} (+{} NOPs)", len, addr, source, nops);
+        self.0.borrow_mut().applied.push(Patch {
+            addr,
+            rom_bank: machine.cartridge.rom_bank(),
+            source,
+            original,
+            code,
+        });
+
+        Ok(msg)
+    }
+
+    /// Restores the bytes overwritten by the most recent patch.
+    fn undo(&self, machine: &mut Machine) -> Result<String, String> {
+        let mut inner = self.0.borrow_mut();
+        let patch = inner.applied.last().ok_or("there is no patch to undo")?;
+
+        // Undoing in the wrong bank would overwrite unrelated code.
+        let rom_bank = machine.cartridge.rom_bank();
+        if is_banked(patch.addr) && patch.rom_bank != rom_bank {
+            return Err(format!(
+                "the patch at {} is in ROM bank {}, but bank {} is mapped",
+                patch.addr,
+                patch.rom_bank,
+                rom_bank,
+            ));
+        }
+
+        for (i, &byte) in patch.original.iter().enumerate() {
+            machine.store_byte_raw(patch.addr + i as u16, byte);
+        }
+
+        let patch = inner.applied.pop().unwrap();
+        Ok(format!("undid patch at {}: {}", patch.addr, patch.source))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use mahboi::{BiosKind, Emulator, HardwareModel, cartridge::Cartridge};
+    use super::*;
+
+    #[test]
+    fn test_patch_undo() {
+        // 0x0150: ld hl, $C000; inc [hl]
+        let mut rom = vec![0; 0x8000];
+        rom[0x150..0x154].copy_from_slice(&[0x21, 0x00, 0xC0, 0x34]);
+        let cartridge = Cartridge::from_bytes(&rom).unwrap();
+        let mut emulator = Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        let machine = emulator.machine_mut();
+        let bytes = |machine: &Machine| {
+            (0x150..0x155).map(|a| machine.load_byte(Word::new(a)).get()).collect::<Vec<_>>()
+        };
+
+        // The 3 byte instruction is replaced by a 2 byte one and a NOP.
+        let patches = Patches::new();
+        patches.request_patch(Word::new(0x0150), "ld a, $3f");
+        assert!(patches.sync(machine)[0].is_ok());
+        assert_eq!(bytes(machine), [0x3E, 0x3F, 0x00, 0x34, 0x00]);
+
+        // Overwriting parts of two instructions.
+        patches.request_patch(Word::new(0x0151), "ld hl, $1234; inc a");
+        assert!(patches.sync(machine)[0].is_ok());
+        assert_eq!(bytes(machine), [0x3E, 0x21, 0x34, 0x12, 0x3C]);
+        assert_eq!(patches.applied().len(), 2);
+
+        patches.request_patch(Word::new(0x0150), "foo");
+        assert!(patches.sync(machine)[0].is_err());
+
+        patches.request_undo();
+        patches.request_undo();
+        assert!(patches.sync(machine).iter().all(|r| r.is_ok()));
+        assert_eq!(bytes(machine), [0x21, 0x00, 0xC0, 0x34, 0x00]);
+
+        patches.request_undo();
+        assert!(patches.sync(machine)[0].is_err());
+    }
+}
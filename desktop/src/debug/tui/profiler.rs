@@ -0,0 +1,184 @@
+//! A simple profiler attributing executed cycles to addresses and functions.
+//!
+//! Before every step, the cycles spent since the last step are attributed to
+//! the address of the last step and to the function it belongs to. The
+//! function is the innermost frame of the shadow call stack.
+
+use std::collections::BTreeMap;
+
+use cursive::{
+    theme::{BaseColor, Color},
+    utils::markup::StyledString,
+};
+
+use mahboi::{
+    machine::Machine,
+    primitives::CYCLES_PER_FRAME,
+};
+use crate::symbols::Symbols;
+use super::{
+    Location,
+    call_stack::CallStack,
+};
+
+
+/// Number of entries shown in each list of the report.
+const REPORT_LEN: usize = 30;
+
+/// The step observed last.
+#[derive(Debug, Clone, Copy)]
+struct Last {
+    location: Location,
+    function: Option<Location>,
+    cycle_count: u64,
+}
+
+pub(crate) struct Profiler {
+    enabled: bool,
+    last: Option<Last>,
+
+    /// Cycles per address.
+    by_addr: BTreeMap<Location, u64>,
+
+    /// Cycles per function (identified by its start address). `None` is the
+    /// code outside of any detected function.
+    by_function: BTreeMap<Option<Location>, u64>,
+
+    total: u64,
+}
+
+impl Profiler {
+    pub(crate) fn new() -> Self {
+        Self {
+            enabled: false,
+            last: None,
+            by_addr: BTreeMap::new(),
+            by_function: BTreeMap::new(),
+            total: 0,
+        }
+    }
+
+    pub(crate) fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.last = None;
+    }
+
+    /// Removes all collected data.
+    pub(crate) fn reset(&mut self) {
+        self.last = None;
+        self.by_addr.clear();
+        self.by_function.clear();
+        self.total = 0;
+    }
+
+    /// Has to be called before every step, after the call stack was updated.
+    pub(crate) fn observe(&mut self, machine: &Machine, call_stack: &CallStack) {
+        if !self.enabled {
+            return;
+        }
+
+        // When stepping back, the cycle count decreases. We just skip that.
+        let cycle_count = machine.cycle_count();
+        if let Some(last) = self.last {
+            if let Some(cycles) = cycle_count.checked_sub(last.cycle_count) {
+                *self.by_addr.entry(last.location).or_default() += cycles;
+                *self.by_function.entry(last.function).or_default() += cycles;
+                self.total += cycles;
+            }
+        }
+
+        let rom_bank = machine.cartridge.rom_bank();
+        self.last = Some(Last {
+            location: Location::new(machine.cpu.pc, rom_bank),
+            function: call_stack.frames().last().map(|f| Location::new(f.target, f.rom_bank)),
+            cycle_count,
+        });
+    }
+
+    /// Returns the hot spots (functions and addresses with the most cycles)
+    /// as text.
+    pub(crate) fn report(&self, symbols: &Symbols) -> StyledString {
+        let mut out = StyledString::new();
+        if !self.enabled && self.total == 0 {
+            out.append_plain("The profiler is disabled. Start it with the button above or with \
+                `profile on` in the console.");
+            return out;
+        }
+
+        out.append_plain(format!(
+            "Total: {} cycles ({:.1} frames){}\n",
+            self.total,
+            self.total as f64 / CYCLES_PER_FRAME as f64,
+            if self.enabled { "" } else { " -- stopped" },
+        ));
+
+        let name = |loc: Location| match symbols.name_at(loc.addr, loc.bank.unwrap_or(0)) {
+            Some(name) => format!("{} ({})", name, loc),
+            None => loc.to_string(),
+        };
+
+        out.append_styled("\nFunctions\n", Color::Light(BaseColor::Green));
+        let functions = self.by_function.iter().map(|(&f, &c)| (f.map(name), c));
+        self.append_list(&mut out, functions.map(|(f, c)| {
+            (f.unwrap_or_else(|| "<outside of any call>".into()), c)
+        }));
+
+        out.append_styled("\nAddresses\n", Color::Light(BaseColor::Green));
+        self.append_list(&mut out, self.by_addr.iter().map(|(&loc, &c)| (name(loc), c)));
+
+        out
+    }
+
+    /// Appends the `REPORT_LEN` entries with the most cycles to `out`.
+    fn append_list(&self, out: &mut StyledString, entries: impl Iterator<Item = (String, u64)>) {
+        let mut entries = entries.collect::<Vec<_>>();
+        entries.sort_by_key(|&(_, cycles)| std::cmp::Reverse(cycles));
+
+        for (label, cycles) in entries.into_iter().take(REPORT_LEN) {
+            let percent = 100.0 * cycles as f64 / self.total.max(1) as f64;
+            out.append_styled(format!("{:>12} ", cycles), Color::Light(BaseColor::Magenta));
+            out.append_styled(format!("{:>5.1}%  ", percent), Color::Light(BaseColor::Yellow));
+            out.append_plain(format!("{}\n", label));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use mahboi::{
+        BiosKind, Emulator, HardwareModel,
+        cartridge::Cartridge,
+        primitives::Word,
+        test_util::{NullPeripherals, rom_with_code},
+    };
+    use super::*;
+
+    #[test]
+    fn test_attribution() {
+        // 0150: CALL 0160; JR -2 (to itself)
+        // 0160: NOP; RET
+        let mut rom = rom_with_code(&[0xCD, 0x60, 0x01, 0x18, 0xFE]);
+        rom[0x160..0x162].copy_from_slice(&[0x00, 0xC9]);
+        let cartridge = Cartridge::from_bytes(&rom).unwrap();
+        let mut emulator = Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        let machine = emulator.machine_mut();
+        machine.cpu.pc = Word::new(0x150);
+
+        let mut profiler = Profiler::new();
+        profiler.set_enabled(true);
+        let mut call_stack = CallStack::new();
+        for _ in 0..5 {
+            call_stack.observe(machine);
+            profiler.observe(machine, &call_stack);
+            machine.execute_step(&mut NullPeripherals).ok().unwrap();
+        }
+
+        // CALL (6), NOP (1), RET (4) and JR (3). The second JR is not yet
+        // attributed.
+        let function = Some(Location::any_bank(Word::new(0x160)));
+        assert_eq!(profiler.total, 14);
+        assert_eq!(profiler.by_function[&function], 5);
+        assert_eq!(profiler.by_function[&None], 9);
+    }
+}
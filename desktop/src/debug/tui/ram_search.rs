@@ -0,0 +1,201 @@
+//! RAM search ("cheat finder"), shown in the "RAM Search" tab.
+//!
+//! `ramsearch reset` takes a snapshot of WRAM, HRAM and the cartridge RAM and
+//! makes all of these addresses candidates. Each filter (e.g. `ramsearch inc`
+//! after the number of lives increased) compares the current value of each
+//! candidate with its value in the last snapshot, removes all candidates for
+//! which the comparison does not hold and takes a new snapshot. The remaining
+//! candidates can be turned into watch expressions.
+
+use std::ops::RangeInclusive;
+
+use cursive::{
+    theme::{BaseColor, Color},
+    utils::markup::StyledString,
+};
+
+use mahboi::{
+    machine::Machine,
+    primitives::Word,
+};
+use crate::symbols::Symbols;
+
+
+/// Maximum number of candidates shown in the tab.
+const REPORT_LEN: usize = 100;
+
+/// How the current value of a candidate has to relate to the value in the
+/// last snapshot to keep the candidate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Filter {
+    Equal,
+    NotEqual,
+    Increased,
+    Decreased,
+
+    /// The value changed by exactly this amount (without wrapping).
+    ChangedBy(i16),
+}
+
+impl Filter {
+    fn matches(self, previous: u8, current: u8) -> bool {
+        match self {
+            Filter::Equal => current == previous,
+            Filter::NotEqual => current != previous,
+            Filter::Increased => current > previous,
+            Filter::Decreased => current < previous,
+            Filter::ChangedBy(diff) => current as i16 - previous as i16 == diff,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Candidate {
+    addr: Word,
+
+    /// The value in the last snapshot.
+    previous: u8,
+}
+
+pub(crate) struct RamSearch {
+    /// `None` if no search was started yet.
+    candidates: Option<Vec<Candidate>>,
+
+    /// Number of filters applied since the last reset.
+    filters: usize,
+}
+
+impl RamSearch {
+    pub(crate) fn new() -> Self {
+        Self {
+            candidates: None,
+            filters: 0,
+        }
+    }
+
+    /// Starts a new search with all addresses as candidates.
+    pub(crate) fn reset(&mut self, machine: &Machine) {
+        let mut ranges: Vec<RangeInclusive<u16>> = vec![0xC000..=0xDFFF, 0xFF80..=0xFFFE];
+        if !machine.cartridge.mbc().ram().is_empty() {
+            ranges.insert(0, 0xA000..=0xBFFF);
+        }
+
+        let candidates = ranges.into_iter()
+            .flatten()
+            .map(Word::new)
+            .map(|addr| Candidate { addr, previous: load(machine, addr) })
+            .collect();
+        self.candidates = Some(candidates);
+        self.filters = 0;
+    }
+
+    /// Removes all candidates not matching `filter` and takes a new snapshot.
+    /// Returns the number of remaining candidates.
+    pub(crate) fn filter(&mut self, machine: &Machine, filter: Filter) -> Result<usize, String> {
+        let candidates = self.candidates.as_mut().ok_or_else(no_search)?;
+        candidates.retain_mut(|c| {
+            let current = load(machine, c.addr);
+            let keep = filter.matches(c.previous, current);
+            c.previous = current;
+            keep
+        });
+        self.filters += 1;
+
+        Ok(candidates.len())
+    }
+
+    /// Returns the address of the candidate with the given index (as shown
+    /// in the tab).
+    pub(crate) fn candidate(&self, idx: usize) -> Result<Word, String> {
+        self.candidates.as_ref()
+            .ok_or_else(no_search)?
+            .get(idx)
+            .map(|c| c.addr)
+            .ok_or_else(|| format!("there is no candidate {}", idx))
+    }
+
+    /// Returns the list of candidates with their values as text.
+    pub(crate) fn report(&self, machine: &Machine, symbols: &Symbols) -> StyledString {
+        let candidates = match &self.candidates {
+            Some(candidates) => candidates,
+            None => return no_search().into(),
+        };
+
+        let mut out = StyledString::new();
+        out.append_plain(format!(
+            "{} candidates after {} filters\n\n",
+            candidates.len(),
+            self.filters,
+        ));
+        out.append_plain(format!(
+            "{:>5}  {:<6}  {:>8}  {:>7}\n",
+            "#", "addr", "previous", "current",
+        ));
+
+        let rom_bank = machine.cartridge.rom_bank();
+        for (i, c) in candidates.iter().enumerate().take(REPORT_LEN) {
+            let current = load(machine, c.addr);
+            out.append_plain(format!("{:>5}  {}  ", i, c.addr));
+            out.append_styled(format!("{:>8}  ", c.previous), Color::Light(BaseColor::Blue));
+            let style = if current == c.previous { BaseColor::Magenta } else { BaseColor::Yellow };
+            out.append_styled(format!("{:>7}", current), Color::Light(style));
+            match symbols.nearest(c.addr, rom_bank) {
+                Some((name, 0)) => out.append_plain(format!("  ({})", name)),
+                Some((name, offset)) => out.append_plain(format!("  ({}+{})", name, offset)),
+                None => {}
+            }
+            out.append_plain("\n");
+        }
+        if candidates.len() > REPORT_LEN {
+            out.append_plain(format!("... and {} more\n", candidates.len() - REPORT_LEN));
+        }
+
+        out
+    }
+}
+
+/// Reads a byte without notifying watchpoints.
+fn load(machine: &Machine, addr: Word) -> u8 {
+    machine.peek_byte(addr).get()
+}
+
+fn no_search() -> String {
+    "no search started (use the Reset button or `ramsearch reset`)".into()
+}
+
+
+#[cfg(test)]
+mod test {
+    use mahboi::{
+        BiosKind, Emulator, HardwareModel,
+        cartridge::Cartridge,
+        primitives::Byte,
+    };
+    use super::*;
+
+    #[test]
+    fn test_filters() {
+        let cartridge = Cartridge::from_bytes(&vec![0; 0x8000]).unwrap();
+        let mut emulator = Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        let machine = emulator.machine_mut();
+        let mut search = RamSearch::new();
+        assert!(search.filter(machine, Filter::Equal).is_err());
+
+        search.reset(machine);
+        machine.store_byte(Word::new(0xC123), Byte::new(5));
+        machine.store_byte(Word::new(0xFF90), Byte::new(7));
+        assert_eq!(search.filter(machine, Filter::Increased), Ok(2));
+
+        machine.store_byte(Word::new(0xC123), Byte::new(3));
+        machine.store_byte(Word::new(0xFF90), Byte::new(6));
+        assert_eq!(search.filter(machine, Filter::ChangedBy(-2)), Ok(1));
+        assert_eq!(search.candidate(0), Ok(Word::new(0xC123)));
+        assert!(search.candidate(1).is_err());
+
+        assert_eq!(search.filter(machine, Filter::Equal), Ok(1));
+        assert_eq!(search.filter(machine, Filter::NotEqual), Ok(0));
+
+        let report = search.report(machine, &Symbols::default());
+        assert!(report.source().starts_with("0 candidates after 4 filters"));
+    }
+}
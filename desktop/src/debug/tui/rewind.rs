@@ -0,0 +1,173 @@
+//! Stepping backwards in time.
+//!
+//! Storing the full machine state before every instruction would be way too
+//! expensive. Instead, we take a snapshot every few thousand steps. To step
+//! back, we restore the last snapshot before the target step and re-execute
+//! from there. Emulation is deterministic, except for the input, so we also
+//! record every change of the pressed keys to replay them.
+
+use std::collections::VecDeque;
+
+use mahboi::{
+    SCREEN_WIDTH,
+    env::Peripherals,
+    machine::{Machine, input::Keys},
+    primitives::PixelColor,
+};
+use super::call_stack::CallStack;
+
+
+/// Number of steps between two snapshots.
+const SNAPSHOT_INTERVAL: u64 = 2_000;
+
+/// Maximum number of snapshots kept. Together with `SNAPSHOT_INTERVAL`, this
+/// determines how far we can step back.
+const MAX_SNAPSHOTS: usize = 50;
+
+struct Snapshot {
+    machine: Machine,
+    call_stack: CallStack,
+}
+
+/// The recorded history of the emulation.
+pub(crate) struct History {
+    /// Snapshots sorted by step count, oldest first.
+    snapshots: VecDeque<Snapshot>,
+
+    /// The keys pressed before a step with the given step count. Only changes
+    /// are stored.
+    key_log: Vec<(u64, Keys)>,
+}
+
+impl History {
+    pub(crate) fn new() -> Self {
+        Self {
+            snapshots: VecDeque::new(),
+            key_log: Vec::new(),
+        }
+    }
+
+    /// Has to be called before every step. Might be called multiple times
+    /// for the same step.
+    pub(crate) fn observe(&mut self, machine: &Machine, call_stack: &CallStack) {
+        let count = machine.step_count();
+
+        let keys = machine.pressed_keys();
+        if self.key_log.last().map(|&(_, k)| k) != Some(keys) {
+            self.key_log.push((count, keys));
+        }
+
+        let newest = self.snapshots.back().map(|s| s.machine.step_count());
+        if newest.is_none_or(|newest| count >= newest + SNAPSHOT_INTERVAL) {
+            self.snapshots.push_back(Snapshot {
+                machine: machine.clone(),
+                call_stack: call_stack.clone(),
+            });
+
+            if self.snapshots.len() > MAX_SNAPSHOTS {
+                self.snapshots.pop_front();
+
+                // Remove key changes we can't replay anymore, but keep the
+                // one that is active at the oldest snapshot.
+                let oldest = self.snapshots[0].machine.step_count();
+                let first_needed = self.key_log.iter()
+                    .rposition(|&(c, _)| c <= oldest)
+                    .unwrap_or(0);
+                self.key_log.drain(..first_needed);
+            }
+        }
+    }
+
+    /// Sets the machine (and call stack) back to the state before the
+    /// previous step was executed.
+    pub(crate) fn step_back(
+        &mut self,
+        machine: &mut Machine,
+        call_stack: &mut CallStack,
+    ) -> Result<(), String> {
+        let target = machine.step_count()
+            .checked_sub(1)
+            .ok_or("already at the first step")?;
+
+        // Snapshots after the target are useless now.
+        while self.snapshots.back().is_some_and(|s| s.machine.step_count() > target) {
+            self.snapshots.pop_back();
+        }
+        let snapshot = self.snapshots.back()
+            .ok_or("the history doesn't go back that far")?;
+
+        machine.restore(&snapshot.machine);
+        *call_stack = snapshot.call_stack.clone();
+
+        let mut replay = Replay { keys: Keys::none() };
+        while machine.step_count() < target {
+            replay.keys = self.keys_at(machine.step_count() + 1);
+            if machine.execute_step(&mut replay).is_err() {
+                return Err("emulation was disrupted while replaying".into());
+            }
+            call_stack.observe(machine);
+        }
+
+        self.key_log.retain(|&(c, _)| c <= target);
+        Ok(())
+    }
+
+    /// Returns the keys that were pressed before the step with the given
+    /// count.
+    fn keys_at(&self, count: u64) -> Keys {
+        self.key_log.iter()
+            .rev()
+            .find(|&&(c, _)| c <= count)
+            .map(|&(_, keys)| keys)
+            .unwrap_or_else(Keys::none)
+    }
+}
+
+/// Peripherals used while replaying: the output is discarded and the recorded
+/// keys are pressed.
+struct Replay {
+    keys: Keys,
+}
+
+impl Peripherals for Replay {
+    fn write_lcd_line(&mut self, _: u8, _: &[PixelColor; SCREEN_WIDTH]) {}
+
+    fn get_pressed_keys(&self) -> Keys {
+        self.keys
+    }
+
+    fn offer_sound_sample(&mut self, _: impl FnOnce(f32) -> f32) {}
+}
+
+
+#[cfg(test)]
+mod test {
+    use mahboi::{BiosKind, Emulator, HardwareModel, cartridge::Cartridge};
+    use super::*;
+
+    #[test]
+    fn test_step_back() {
+        let cartridge = Cartridge::from_bytes(&[0; 0x8000]).unwrap();
+        let mut emulator = Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        let machine = emulator.machine_mut();
+        let mut history = History::new();
+        let mut call_stack = CallStack::new();
+        let mut replay = Replay { keys: Keys::none() };
+
+        // Run for a while and remember PC and the value of `LY` before each
+        // step.
+        let mut states = Vec::new();
+        for _ in 0..5_000 {
+            call_stack.observe(machine);
+            history.observe(machine, &call_stack);
+            states.push((machine.step_count(), machine.cpu.pc, machine.ppu.regs().current_line));
+            let _ = machine.execute_step(&mut replay);
+        }
+
+        for expected in states.iter().rev().take(2_500) {
+            history.step_back(machine, &mut call_stack).unwrap();
+            let actual = (machine.step_count(), machine.cpu.pc, machine.ppu.regs().current_line);
+            assert_eq!(actual, *expected);
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//! Searching the memory of the emulated machine.
+
+use std::fmt;
+
+use mahboi::{
+    machine::Machine,
+    primitives::Word,
+};
+
+
+/// What kind of value the user searches for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SearchKind {
+    /// A sequence of bytes in hex, e.g. `3E 01 E0 40`.
+    Bytes,
+
+    /// A single 8 bit value.
+    U8,
+
+    /// A 16 bit value, stored in little endian.
+    U16,
+
+    /// ASCII text.
+    Text,
+}
+
+/// A parsed search query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Query {
+    /// The bytes to search for.
+    pub(crate) needle: Vec<u8>,
+
+    /// If `true`, all ROM banks are searched instead of only the currently
+    /// mapped ones.
+    pub(crate) all_rom_banks: bool,
+}
+
+impl Query {
+    /// Parses the user input as the given kind of value.
+    pub(crate) fn parse(
+        kind: SearchKind,
+        input: &str,
+        all_rom_banks: bool,
+    ) -> Result<Self, String> {
+        let needle = match kind {
+            SearchKind::Bytes => {
+                let digits = input.chars().filter(|c| !c.is_whitespace()).collect::<String>();
+                if !digits.is_ascii() {
+                    return Err("only hex digits are allowed".into());
+                }
+                if digits.len() % 2 == 1 {
+                    return Err("odd number of hex digits".into());
+                }
+
+                (0..digits.len()).step_by(2)
+                    .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| format!("invalid hex byte: {}", e))?
+            }
+            SearchKind::U8 => {
+                let v = parse_number(input)?;
+                if v > 0xFF {
+                    return Err(format!("{} does not fit into 8 bits", input.trim()));
+                }
+                vec![v as u8]
+            }
+            SearchKind::U16 => {
+                let v = parse_number(input)?;
+                if v > 0xFFFF {
+                    return Err(format!("{} does not fit into 16 bits", input.trim()));
+                }
+                vec![v as u8, (v >> 8) as u8]
+            }
+            SearchKind::Text => {
+                if !input.is_ascii() {
+                    return Err("only ASCII text can be searched".into());
+                }
+                input.as_bytes().to_vec()
+            }
+        };
+
+        if needle.is_empty() {
+            return Err("empty search".into());
+        }
+
+        Ok(Self { needle, all_rom_banks })
+    }
+
+    /// Executes the search and returns all hits in memory order.
+    pub(crate) fn run(&self, machine: &Machine) -> Vec<Hit> {
+        let mut hits = Vec::new();
+
+        // When searching all ROM banks, the ROM area of the address space is
+        // replaced by the full ROM.
+        let start = if self.all_rom_banks {
+            let rom = machine.cartridge.rom();
+            let hits_in_rom = find_all(rom.len(), &self.needle, |i| rom[i].get());
+            hits.extend(hits_in_rom.into_iter().map(|offset| {
+                let bank = offset / 0x4000;
+                let addr = if bank == 0 { offset } else { 0x4000 + offset % 0x4000 };
+                Hit { addr: Word::new(addr as u16), rom_bank: Some(bank) }
+            }));
+
+            0x8000
+        } else {
+            0
+        };
+
+        let len = 0x10000 - start;
+        let load = |i| machine.peek_byte(Word::new((start + i) as u16)).get();
+        let hits_in_memory = find_all(len, &self.needle, load);
+        hits.extend(hits_in_memory.into_iter().map(|offset| {
+            Hit { addr: Word::new((start + offset) as u16), rom_bank: None }
+        }));
+
+        hits
+    }
+}
+
+/// A position where the searched value was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Hit {
+    pub(crate) addr: Word,
+
+    /// Only set when searching all ROM banks: the bank in which the value was
+    /// found.
+    pub(crate) rom_bank: Option<usize>,
+}
+
+impl fmt::Display for Hit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.rom_bank {
+            Some(bank) => write!(f, "{} (ROM bank {})", self.addr, bank),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
+
+/// Parses a number that is either decimal or hexadecimal (prefixed with `0x`
+/// or `$`).
+fn parse_number(input: &str) -> Result<u32, String> {
+    let input = input.trim();
+    let res = if let Some(hex) = input.strip_prefix("0x").or_else(|| input.strip_prefix('$')) {
+        u32::from_str_radix(hex, 16)
+    } else {
+        input.parse()
+    };
+
+    res.map_err(|e| format!("invalid number '{}': {}", input, e))
+}
+
+/// Returns the start offsets of all occurrences of `needle` in the haystack of
+/// length `len`. Bytes of the haystack are obtained via `get`.
+fn find_all(len: usize, needle: &[u8], get: impl Fn(usize) -> u8) -> Vec<usize> {
+    let haystack = (0..len).map(get).collect::<Vec<_>>();
+    haystack.windows(needle.len())
+        .enumerate()
+        .filter(|(_, window)| *window == needle)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let parse = |kind, input| Query::parse(kind, input, false).map(|q| q.needle);
+
+        assert_eq!(parse(SearchKind::Bytes, "3E 01 e040"), Ok(vec![0x3E, 0x01, 0xE0, 0x40]));
+        assert_eq!(parse(SearchKind::U8, "0x3f"), Ok(vec![0x3F]));
+        assert_eq!(parse(SearchKind::U8, "200"), Ok(vec![200]));
+        assert_eq!(parse(SearchKind::U16, "$1234"), Ok(vec![0x34, 0x12]));
+        assert_eq!(parse(SearchKind::Text, "HI"), Ok(vec![b'H', b'I']));
+
+        assert!(parse(SearchKind::Bytes, "3E 0").is_err());
+        assert!(parse(SearchKind::U8, "256").is_err());
+        assert!(parse(SearchKind::U16, "0x10000").is_err());
+        assert!(parse(SearchKind::Text, "").is_err());
+    }
+
+    #[test]
+    fn test_find_all() {
+        let data = [1, 2, 1, 2, 1, 3];
+        assert_eq!(find_all(data.len(), &[1, 2, 1], |i| data[i]), vec![0, 2]);
+        assert_eq!(find_all(data.len(), &[3, 4], |i| data[i]), Vec::<usize>::new());
+    }
+}
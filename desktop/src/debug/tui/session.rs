@@ -0,0 +1,70 @@
+//! Persisting breakpoints, watchpoints and watch expressions across runs.
+//!
+//! The session file is just a list of console commands that recreate the
+//! state (e.g. `b 05:4123 if a == 3`). It is executed at startup like a
+//! debugger script and rewritten whenever the state changes.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use mahboi::log::*;
+
+
+pub(crate) struct Session {
+    path: PathBuf,
+
+    /// The content last written to (or read from) the file. Used to only
+    /// write the file if something changed.
+    last_saved: String,
+}
+
+impl Session {
+    /// Returns the session file belonging to the given ROM.
+    pub(crate) fn path_for_rom(rom: &Path) -> PathBuf {
+        rom.with_extension("mahboi-session")
+    }
+
+    /// Loads the session file at `path` (if it exists) and returns its
+    /// commands.
+    pub(crate) fn load(path: PathBuf) -> (Self, Vec<String>) {
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => {
+                info!("[debugger] restoring session from '{}'", path.display());
+                content
+            }
+            Err(_) => String::new(),
+        };
+
+        let commands = content.lines().map(|l| l.to_string()).collect();
+        let session = Self { path, last_saved: content };
+        (session, commands)
+    }
+
+    /// Writes the given commands to the session file, if they differ from
+    /// the ones written last time.
+    pub(crate) fn save(&mut self, commands: &[String]) {
+        let mut content = commands.join("\n");
+        if !content.is_empty() {
+            content.push('\n');
+        }
+
+        if content == self.last_saved {
+            return;
+        }
+
+        // Don't leave an empty file if there never was one.
+        let result = if content.is_empty() && !self.path.exists() {
+            Ok(())
+        } else {
+            fs::write(&self.path, &content)
+        };
+        if let Err(e) = result {
+            warn!("[debugger] failed to write session file '{}': {}", self.path.display(), e);
+        }
+
+        // Even on error, to not retry (and warn) all the time.
+        self.last_saved = content;
+    }
+}
@@ -0,0 +1,120 @@
+//! Timing of the events in the last frame for the "Timeline" tab.
+//!
+//! The tab lists all LYC matches, interrupt dispatches and DMA starts with
+//! the line and the cycle in the line they happened in. Below that, each line
+//! of the frame is drawn as one row of cells (two cycles per cell) showing
+//! the PPU mode, with the events marked at their position.
+
+use cursive::{
+    theme::{BaseColor, Color},
+    utils::markup::StyledString,
+};
+
+use mahboi::{
+    machine::{
+        Machine,
+        ppu::{Mode, CYCLES_PER_LINE},
+        timeline::{Event, EventKind},
+    },
+    primitives::Word,
+};
+
+
+/// Number of lines per frame, including the V-Blank lines.
+const LINES: u32 = 154;
+
+/// Number of cycles shown in one cell.
+const CYCLES_PER_CELL: u32 = 2;
+
+/// Creates the content of the Timeline tab.
+pub(crate) fn timeline_text(machine: &Machine) -> StyledString {
+    let events = match machine.timeline() {
+        Some(timeline) if !timeline.last_frame().is_empty() => timeline.last_frame(),
+        _ => return "no complete frame recorded yet (is the LCD enabled?)".into(),
+    };
+
+    let title_style = Color::Light(BaseColor::Green);
+    let pos_style = Color::Light(BaseColor::Blue);
+
+    let mut body = StyledString::new();
+    body.append_styled("Events in the last frame\n", title_style);
+    for event in events.iter().filter(|e| !matches!(e.kind, EventKind::Mode(_))) {
+        body.append_styled(
+            format!("LY {:3} cycle {:3}  ", event.line(), event.cycle_in_line()),
+            pos_style,
+        );
+        body.append_plain(format!("{}\n", describe(event.kind)));
+    }
+
+    body.append_styled("\nPPU modes per line ", title_style);
+    body.append_plain(format!(
+        "({} cycles per cell: 2 OAM search, 3 pixel transfer, 0 H-Blank, 1 V-Blank, \
+            L LYC match, I interrupt, D DMA start)\n",
+        CYCLES_PER_CELL,
+    ));
+    append_cells(&mut body, events);
+
+    body
+}
+
+/// Appends one row of cells per line.
+fn append_cells(body: &mut StyledString, events: &[Event]) {
+    let cells_per_line = (CYCLES_PER_LINE as u32).div_ceil(CYCLES_PER_CELL);
+
+    // The frame starts in the V-Blank of the previous frame.
+    let mut mode = Mode::VBlank;
+    let mut events = events.iter().peekable();
+    for line in 0..LINES {
+        body.append_styled(format!("{:3} ", line), Color::Light(BaseColor::Blue));
+
+        let line_start = line * CYCLES_PER_LINE as u32;
+        for cell in 0..cells_per_line {
+            let end = (line_start + (cell + 1) * CYCLES_PER_CELL)
+                .min(line_start + CYCLES_PER_LINE as u32);
+            let mut marker = None;
+            while let Some(event) = events.next_if(|e| e.cycle < end) {
+                match event.kind {
+                    EventKind::Mode(m) => mode = m,
+                    EventKind::LycMatch => marker = marker.or(Some('L')),
+                    EventKind::Interrupt(_) => marker = marker.or(Some('I')),
+                    EventKind::Dma(_) => marker = marker.or(Some('D')),
+                }
+            }
+
+            let (c, color) = match mode {
+                Mode::OamSearch => ('2', BaseColor::Yellow),
+                Mode::PixelTransfer => ('3', BaseColor::Red),
+                Mode::HBlank => ('0', BaseColor::Blue),
+                Mode::VBlank => ('1', BaseColor::Magenta),
+            };
+            match marker {
+                Some(marker) => {
+                    body.append_styled(marker.to_string(), Color::Light(BaseColor::White));
+                }
+                None => body.append_styled(c.to_string(), Color::Dark(color)),
+            }
+        }
+        body.append_plain("\n");
+    }
+}
+
+fn describe(kind: EventKind) -> String {
+    match kind {
+        EventKind::Mode(mode) => format!("{} started", mode),
+        EventKind::LycMatch => "LY = LYC".into(),
+        EventKind::Interrupt(vector) => {
+            let name = match vector.get() {
+                0x40 => "V-Blank",
+                0x48 => "LCD STAT",
+                0x50 => "timer",
+                0x58 => "serial",
+                0x60 => "joypad",
+                _ => "unknown",
+            };
+            format!("{} interrupt dispatched (to {})", name, vector)
+        }
+        EventKind::Dma(page) => {
+            format!("OAM DMA started from {}", Word::new(page.get() as u16 * 0x100))
+        }
+    }
+}
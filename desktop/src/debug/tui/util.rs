@@ -5,7 +5,7 @@ use cursive::{
 };
 
 use mahboi::{
-    instr::{Instr, INSTRUCTIONS, PREFIXED_INSTRUCTIONS},
+    instr::{Disassembled, Instr, INSTRUCTIONS, PREFIXED_INSTRUCTIONS},
     primitives::{Byte, Word},
 };
 
@@ -54,13 +54,6 @@ impl InstrArg {
             raw:data.to_vec(),
         })
     }
-
-    pub(crate) fn raw_data(&self) -> Option<Vec<Byte>> {
-        match self {
-            InstrArg::Static(..) => None,
-            InstrArg::Dyn { raw, .. } => Some(raw.clone()),
-        }
-    }
 }
 
 /// A decoded instruction
@@ -232,6 +225,34 @@ impl DecodedInstr {
     }
 }
 
+/// Creates a styled string of an instruction disassembled by the core, with
+/// the same formatting as `DecodedInstr::to_styled_string` uses. Immediates
+/// are shown resolved, e.g. jump targets of `JR` as absolute addresses.
+pub(crate) fn styled_disassembly(instr: &Disassembled) -> StyledString {
+    let name_style = Style::from(Color::Light(BaseColor::White))
+        .combine(Effect::Bold);
+
+    let mut out = StyledString::new();
+    if instr.operands[0].is_none() {
+        out.append_styled(instr.instr.name, name_style);
+    } else {
+        out.append_styled(format!("{:5}", instr.instr.name), name_style);
+    }
+
+    for (i, operand) in instr.operands.iter().flatten().enumerate() {
+        if i > 0 {
+            out.append_plain(", ");
+        }
+        let color = match operand.value {
+            Some(_) => Color::Dark(BaseColor::Yellow),
+            None => Color::Light(BaseColor::White),
+        };
+        out.append_styled(operand.to_string(), color);
+    }
+
+    out
+}
+
 /// Takes a styled string and prints it to the given printer.
 pub(crate) fn print_styled_string(printer: &Printer, ss: &StyledString) {
     let mut offset = 0;
@@ -0,0 +1,107 @@
+//! Watch expressions: expressions that are re-evaluated whenever the debugger
+//! view is updated.
+
+use cursive::{
+    theme::{BaseColor, Color},
+    utils::markup::StyledString,
+};
+
+use mahboi::machine::Machine;
+use super::expr::Expr;
+
+
+/// One watch expression.
+struct Watch {
+    /// The expression as typed by the user.
+    source: String,
+    expr: Expr,
+
+    /// The result of the last evaluation.
+    value: Option<Result<i64, String>>,
+
+    /// The value at the previous pause, i.e. before the emulator continued
+    /// the last time. Used to highlight changes.
+    previous: Option<i64>,
+
+    /// The step count of the machine when `value` was evaluated.
+    evaluated_at: u64,
+}
+
+/// All watch expressions.
+pub(crate) struct Watches {
+    list: Vec<Watch>,
+}
+
+impl Watches {
+    pub(crate) fn new() -> Self {
+        Self { list: Vec::new() }
+    }
+
+    pub(crate) fn add(&mut self, source: &str, expr: Expr) {
+        self.list.push(Watch {
+            source: source.trim().to_string(),
+            expr,
+            value: None,
+            previous: None,
+            evaluated_at: 0,
+        });
+    }
+
+    /// Removes the watch with the given index (as shown in the view).
+    pub(crate) fn remove(&mut self, idx: usize) -> Result<(), String> {
+        if idx >= self.list.len() {
+            return Err(format!("there is no watch expression {}", idx));
+        }
+
+        self.list.remove(idx);
+        Ok(())
+    }
+
+    /// Returns the expressions as typed by the user.
+    pub(crate) fn sources(&self) -> impl Iterator<Item = &str> {
+        self.list.iter().map(|w| &*w.source)
+    }
+
+    /// Evaluates all expressions. If the machine executed instructions since
+    /// the last call, the old values are remembered to highlight changes.
+    pub(crate) fn update(&mut self, machine: &Machine) {
+        let step_count = machine.step_count();
+        for watch in &mut self.list {
+            let value = watch.expr.eval(machine).map_err(|e| e.to_string());
+            if watch.evaluated_at != step_count {
+                watch.previous = watch.value.take().and_then(Result::ok);
+                watch.evaluated_at = step_count;
+            }
+            watch.value = Some(value);
+        }
+    }
+
+    /// Returns the label for each watch expression. Changed values are
+    /// highlighted.
+    pub(crate) fn labels(&self, machine: &Machine) -> Vec<StyledString> {
+        self.list.iter().enumerate().map(|(i, watch)| {
+            let mut out = StyledString::plain(format!("{}: {} = ", i, watch.source));
+            match &watch.value {
+                None => out.append_plain("?"),
+                Some(Err(e)) => out.append_styled(e, Color::Light(BaseColor::Red)),
+                Some(Ok(v)) => {
+                    let changed = watch.previous.is_some_and(|p| p != *v);
+                    let style = if changed {
+                        Color::Light(BaseColor::Yellow)
+                    } else {
+                        Color::Light(BaseColor::Magenta)
+                    };
+                    out.append_styled(format!("{:#x} ({})", v, v), style);
+
+                    // For a symbol, the value stored there is more
+                    // interesting than its address.
+                    if let Expr::Sym(_, addr) = watch.expr {
+                        out.append_plain(format!(" → {}", machine.peek_byte(addr)));
+                    }
+                }
+            }
+
+            out
+        }).collect()
+    }
+}
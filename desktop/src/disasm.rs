@@ -0,0 +1,502 @@
+//! Exporting a disassembly of the ROM as RGBDS source code.
+//!
+//! The disassembly is a simple linear sweep: everything is decoded as code,
+//! except for bytes that the analysis (see `analyze`) classified as data.
+//! Those are emitted as `db`. This is not pretty, but the output assembles
+//! back to the very same bytes with `rgbasm`. Symbols from a symbol file are
+//! emitted as labels and used for operands if they are defined in the output.
+//!
+//! `export_project` writes a whole RGBDS project instead, with generated labels
+//! for all functions and jump targets found by the analysis.
+
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use failure::{Error, ResultExt, bail};
+
+use mahboi::{
+    instr::{self, Immediate, Operand},
+    primitives::{Byte, Word},
+};
+use crate::{
+    analyze::{ByteKind, CodeMap, RomAddr},
+    symbols::Symbols,
+};
+
+
+/// Size of one ROM bank in bytes.
+const BANK_SIZE: usize = 0x4000;
+
+/// Maximum number of bytes per `db` line for data.
+const DATA_PER_LINE: usize = 8;
+
+/// The first line of all generated files.
+const HEADER: &str = "; Disassembly generated by mahboi";
+
+/// The Makefile of exported projects.
+const MAKEFILE: &str = "\
+# Generated by mahboi. Builds the ROM with RGBDS.
+game.gb: main.o
+\trgblink -n game.sym -o $@ $<
+
+main.o: $(wildcard *.asm)
+\trgbasm -o $@ main.asm
+";
+
+/// The part of the ROM to disassemble.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Selection {
+    All,
+    Bank(usize),
+
+    /// The addresses `start..=end` in the given bank.
+    Range {
+        bank: usize,
+        start: Word,
+        end: Word,
+    },
+}
+
+impl Selection {
+    /// Parses a selection of the form `<bank>`, `<bank>:<start>-<end>` or
+    /// `<start>-<end>` (all hexadecimal). An empty string selects everything.
+    pub(crate) fn parse(input: &str) -> Result<Self, String> {
+        let input = input.trim();
+        if input.is_empty() {
+            return Ok(Selection::All);
+        }
+
+        let parse_num = |s: &str| {
+            usize::from_str_radix(s.trim(), 16)
+                .map_err(|e| format!("invalid hex number '{}': {}", s, e))
+        };
+
+        let (bank, range) = match input.split_once(':') {
+            Some((bank, range)) => (Some(parse_num(bank)?), range),
+            None if !input.contains('-') => return Ok(Selection::Bank(parse_num(input)?)),
+            None => (None, input),
+        };
+
+        let (start, end) = range.split_once('-')
+            .ok_or_else(|| format!("expected range `<start>-<end>`, found '{}'", range))?;
+        let (start, end) = (parse_num(start)?, parse_num(end)?);
+        if start > end || end >= 0x8000 || (start < 0x4000) != (end < 0x4000) {
+            return Err(format!(
+                "{:04x}-{:04x} is not a range within 0000-3fff or 4000-7fff",
+                start,
+                end,
+            ));
+        }
+
+        let bank = bank.unwrap_or(if start < 0x4000 { 0 } else { 1 });
+        if (bank == 0) != (start < 0x4000) {
+            return Err(format!("{:04x}-{:04x} is not in bank {}", start, end, bank));
+        }
+
+        Ok(Selection::Range {
+            bank,
+            start: Word::new(start as u16),
+            end: Word::new(end as u16),
+        })
+    }
+
+    /// Returns the selected parts of the ROM as `(bank, start, end)` triples
+    /// with inclusive bounds.
+    fn parts(&self, num_banks: usize) -> Vec<(usize, u16, u16)> {
+        let whole_bank = |bank| {
+            let start = if bank == 0 { 0 } else { 0x4000 };
+            (bank, start, start + 0x3FFF)
+        };
+
+        match *self {
+            Selection::All => (0..num_banks).map(whole_bank).collect(),
+            Selection::Bank(bank) => vec![whole_bank(bank)],
+            Selection::Range { bank, start, end } => vec![(bank, start.get(), end.get())],
+        }
+    }
+}
+
+/// Writes the disassembly of the selected part of `rom` to the file at
+/// `path`.
+pub(crate) fn export_to_file(
+    path: &Path,
+    rom: &[Byte],
+    selection: Selection,
+    symbols: &Symbols,
+    code_map: &CodeMap,
+) -> Result<(), Error> {
+    let file = File::create(path)
+        .context(format!("failed to create '{}'", path.display()))?;
+    let mut out = BufWriter::new(file);
+    export(&mut out, rom, selection, symbols, code_map)?;
+    out.flush()?;
+
+    Ok(())
+}
+
+/// Writes the disassembly of the selected part of `rom` to `out`.
+pub(crate) fn export(
+    out: &mut impl Write,
+    rom: &[Byte],
+    selection: Selection,
+    symbols: &Symbols,
+    code_map: &CodeMap,
+) -> Result<(), Error> {
+    let num_banks = rom.len() / BANK_SIZE;
+    let parts = selection.parts(num_banks);
+    if let Some(&(bank, _, _)) = parts.iter().find(|&&(bank, _, _)| bank >= num_banks) {
+        bail!("bank {} does not exist (the ROM has {} banks)", bank, num_banks);
+    }
+
+    // Symbols can only be used as operand if they are defined somewhere in
+    // the output.
+    let is_defined = |bank: usize, addr: Word| {
+        addr.get() >= 0x8000 || parts.iter().any(|&(b, start, end)| {
+            (b == bank || addr.get() < 0x4000) && (start..=end).contains(&addr.get())
+        })
+    };
+
+    writeln!(out, "{}", HEADER)?;
+    writeln!(out)?;
+    write_constants(out, symbols)?;
+    for &part in &parts {
+        write_section(out, rom, part, symbols, code_map, &is_defined)?;
+    }
+
+    Ok(())
+}
+
+/// Writes an RGBDS project to the directory `dir`: one file per bank, a file
+/// with constants, `main.asm` including all of them and a Makefile. All
+/// functions and jump targets get labels (unless they have a symbol already).
+/// Returns the number of written files.
+pub(crate) fn export_project(
+    dir: &Path,
+    rom: &[Byte],
+    symbols: &Symbols,
+    code_map: &CodeMap,
+) -> Result<usize, Error> {
+    let files = project_files(rom, symbols, code_map)?;
+    fs::create_dir_all(dir).context(format!("failed to create '{}'", dir.display()))?;
+    for (name, content) in &files {
+        let path = dir.join(name);
+        fs::write(&path, content).context(format!("failed to write '{}'", path.display()))?;
+    }
+
+    Ok(files.len())
+}
+
+/// Returns the names and contents of all files of the RGBDS project (see
+/// `export_project`).
+fn project_files(
+    rom: &[Byte],
+    symbols: &Symbols,
+    code_map: &CodeMap,
+) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let mut symbols = symbols.clone();
+    symbols.add_function_names(code_map);
+    symbols.add_jump_labels(code_map);
+
+    let mut constants = Vec::new();
+    writeln!(constants, "{}", HEADER)?;
+    writeln!(constants)?;
+    write_constants(&mut constants, &symbols)?;
+    let mut files = vec![("constants.asm".to_string(), constants)];
+
+    // Everything is defined somewhere in the project.
+    let mut main = format!("{}\n\nINCLUDE \"constants.asm\"\n", HEADER);
+    for part in Selection::All.parts(rom.len() / BANK_SIZE) {
+        let name = format!("bank_{:02x}.asm", part.0);
+        let mut out = Vec::new();
+        writeln!(out, "{}", HEADER)?;
+        writeln!(out)?;
+        write_section(&mut out, rom, part, &symbols, code_map, &|_, _| true)?;
+
+        main.push_str(&format!("INCLUDE \"{}\"\n", name));
+        files.push((name, out));
+    }
+
+    files.push(("main.asm".into(), main.into_bytes()));
+    files.push(("Makefile".into(), MAKEFILE.into()));
+    Ok(files)
+}
+
+/// Writes `DEF` directives for all symbols outside of the ROM.
+fn write_constants(out: &mut impl Write, symbols: &Symbols) -> Result<(), Error> {
+    let mut has_constants = false;
+    for (_, addr, name) in symbols.iter().filter(|(_, addr, _)| addr.get() >= 0x8000) {
+        writeln!(out, "DEF {} EQU ${:04x}", name, addr.get())?;
+        has_constants = true;
+    }
+    if has_constants {
+        writeln!(out)?;
+    }
+
+    Ok(())
+}
+
+/// Writes a `SECTION` with the disassembly of `start..=end` in `bank`.
+/// Symbols are only used as operands if `is_defined` returns `true` for them.
+fn write_section(
+    out: &mut impl Write,
+    rom: &[Byte],
+    (bank, start, end): (usize, u16, u16),
+    symbols: &Symbols,
+    code_map: &CodeMap,
+    is_defined: &dyn Fn(usize, Word) -> bool,
+) -> Result<(), Error> {
+    if bank == 0 {
+        writeln!(out, "SECTION \"ROM Bank $00\", ROM0[${:04x}]", start)?;
+    } else {
+        writeln!(
+            out,
+            "SECTION \"ROM Bank ${:02x}\", ROMX[${:04x}], BANK[${:02x}]",
+            bank,
+            start,
+            bank,
+        )?;
+    }
+    writeln!(out)?;
+
+    let bank_start = if bank == 0 { 0 } else { 0x4000 };
+    let bank_data = &rom[bank * BANK_SIZE..(bank + 1) * BANK_SIZE];
+    let end_offset = (end - bank_start) as usize;
+    let label_at = |addr: u16| symbols.name_at(Word::new(addr), bank);
+    let is_data = |addr: u16| {
+        code_map.kind_at(RomAddr::new(bank, Word::new(addr))) == ByteKind::Data
+    };
+    // Without an MBC, bank 1 is always mapped, even from bank 0.
+    let mapped_bank = if rom.len() <= 2 * BANK_SIZE { 1 } else { bank };
+    let operand_label = |target: Word| {
+        let name = symbols.name_at(target, mapped_bank)?;
+        if is_defined(mapped_bank, target) { Some(name.to_string()) } else { None }
+    };
+
+    let mut addr = start;
+    loop {
+        let bytes = &bank_data[(addr - bank_start) as usize..=end_offset];
+        if let Some(name) = label_at(addr) {
+            writeln!(out, "{}:", name)?;
+        }
+
+        // Data is emitted in lines of up to `DATA_PER_LINE` bytes, up to
+        // the next label. If a label or data starts in the middle of an
+        // instruction, we emit the bytes before it as data to be able to
+        // define the label.
+        let (text, len) = if is_data(addr) {
+            let max = bytes.len().min(DATA_PER_LINE);
+            let len = (1..max)
+                .find(|&i| label_at(addr + i as u16).is_some() || !is_data(addr + i as u16))
+                .unwrap_or(max);
+            (data_directive(&bytes[..len]), len)
+        } else {
+            let is_boundary = |a: u16| label_at(a).is_some() || is_data(a);
+            match disassemble(bytes, Word::new(addr), &operand_label) {
+                Some((text, len)) => match (1..len).find(|&i| is_boundary(addr + i as u16)) {
+                    None => (text, len),
+                    Some(i) => (data_directive(&bytes[..i]), i),
+                },
+                None => (data_directive(&bytes[..1]), 1),
+            }
+        };
+
+        writeln!(out, "    {: <32}; ${:04x}", text, addr)?;
+        match addr.checked_add(len as u16) {
+            Some(next) if next <= end => addr = next,
+            _ => break,
+        }
+    }
+
+    writeln!(out)?;
+
+    Ok(())
+}
+
+/// Returns a `db` directive for the given bytes.
+fn data_directive(bytes: &[Byte]) -> String {
+    let bytes = bytes.iter().map(|b| format!("${:02x}", b.get())).collect::<Vec<_>>();
+    format!("db {}", bytes.join(", "))
+}
+
+/// Disassembles the instruction at the start of `bytes` (which is located at
+/// `addr`) into RGBDS syntax. Returns the text and the length of the
+/// instruction, or `None` if the opcode is invalid, the instruction is
+/// truncated or it wouldn't assemble to the same bytes.
+///
+/// `label` returns the name that should be used for an address operand.
+pub(crate) fn disassemble(
+    bytes: &[Byte],
+    addr: Word,
+    label: &dyn Fn(Word) -> Option<String>,
+) -> Option<(String, usize)> {
+    let instr = instr::decode(addr, bytes)?;
+    let len = instr.instr.len as usize;
+    let addr_or_label = |target: Word| {
+        label(target).unwrap_or_else(|| format!("${:04x}", target.get()))
+    };
+
+    // `rgbasm` always emits `STOP` followed by a zero byte.
+    if instr.instr.name == "STOP" && bytes[1].get() != 0 {
+        return None;
+    }
+
+    let operands = instr.operands.iter().flatten().map(|op| match (op.operand, op.value) {
+        (Operand::RstTarget(target), _) => format!("${:02x}", target),
+        (Operand::SpRel8, Some(Immediate::Signed(v))) => format!("sp{:+}", v),
+        (_, Some(Immediate::Signed(v))) => v.to_string(),
+        (Operand::MemAddr16, Some(Immediate::Addr(target)))
+        | (Operand::MemHighAddr8, Some(Immediate::Addr(target))) => {
+            format!("[{}]", addr_or_label(target))
+        }
+        (_, Some(Immediate::Addr(target))) | (_, Some(Immediate::Data16(target))) => {
+            addr_or_label(target)
+        }
+        (_, Some(Immediate::Data8(b))) => format!("${:02x}", b.get()),
+        (operand, None) => operand.to_string().to_lowercase().replace('(', "[").replace(')', "]"),
+    }).collect::<Vec<_>>();
+
+    let name = instr.instr.name.to_lowercase();
+    let text = if operands.is_empty() {
+        name
+    } else {
+        format!("{} {}", name, operands.join(", "))
+    };
+
+    Some((text, len))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn export_lines(rom: &[Byte], selection: Selection, symbols: &Symbols) -> Vec<String> {
+        let mut out = Vec::new();
+        export(&mut out, rom, selection, symbols, &CodeMap::new(rom)).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        out.lines()
+            .map(|l| l.split(';').next().unwrap().trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect()
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let symbols = Symbols::parse("00:0150 Main\n00:C000 wBuffer\n00:FF80 hFoo").unwrap();
+        let rom = [
+            0x00,                   // nop
+            0x18, 0xfd,             // jr Main
+            0xfa, 0x00, 0xc0,       // ld a, [wBuffer]
+            0xe0, 0x80,             // ldh [hFoo], a
+            0xf8, 0xfe,             // ld hl, sp-2
+            0xcb, 0x7c,             // bit 7, h
+            0x3e, 0x12,             // ld a, $12
+            0xd3,                   // invalid
+            0x21, 0x34, 0x12,       // ld hl, $1234
+            0xff,                   // rst $38
+            0x10, 0x01,             // stop with non-zero byte
+        ];
+        let mut full = vec![Byte::new(0); 2 * BANK_SIZE];
+        for (i, b) in rom.iter().enumerate() {
+            full[0x150 + i] = Byte::new(*b);
+        }
+
+        let selection = Selection::parse("0150-0164").unwrap();
+        let lines = export_lines(&full, selection, &symbols);
+
+        assert_eq!(lines, [
+            "DEF wBuffer EQU $c000",
+            "DEF hFoo EQU $ff80",
+            "SECTION \"ROM Bank $00\", ROM0[$0150]",
+            "Main:",
+            "nop",
+            "jr Main",
+            "ld a, [wBuffer]",
+            "ldh [hFoo], a",
+            "ld hl, sp-2",
+            "bit 7, h",
+            "ld a, $12",
+            "db $d3",
+            "ld hl, $1234",
+            "rst $38",
+            "db $10",
+            "db $01",
+        ]);
+    }
+
+    #[test]
+    fn test_data() {
+        let rom = [
+            0x21, 0x58, 0x01,       // ld hl, $0158
+            0xc3, 0x50, 0x01,       // jp $0150
+            0x00, 0x3e,             // not referenced: decoded as code
+            0xd3, 0x3e, 0x12,       // data
+        ];
+        let mut full = vec![Byte::new(0); 2 * BANK_SIZE];
+        for (i, b) in rom.iter().enumerate() {
+            full[0x150 + i] = Byte::new(*b);
+        }
+
+        let selection = Selection::parse("0150-015a").unwrap();
+        assert_eq!(export_lines(&full, selection, &Symbols::default()), [
+            "SECTION \"ROM Bank $00\", ROM0[$0150]",
+            "ld hl, $0158",
+            "jp $0150",
+            "nop",
+            "db $3e",
+            "db $d3, $3e, $12",
+        ]);
+    }
+
+    #[test]
+    fn test_project_files() {
+        let mut rom = vec![Byte::new(0); 2 * BANK_SIZE];
+        let code: &[(usize, &[u8])] = &[
+            (0x100, &[0x00, 0xc3, 0x50, 0x01]),         // nop; jp $0150
+            (0x150, &[0xcd, 0x00, 0x40, 0x18, 0xfb]),   // call $4000; jr $0150
+            (0x4000, &[0xc9]),                          // ret
+        ];
+        for &(offset, bytes) in code {
+            for (i, b) in bytes.iter().enumerate() {
+                rom[offset + i] = Byte::new(*b);
+            }
+        }
+
+        let symbols = Symbols::parse("00:C000 wBuffer").unwrap();
+        let files = project_files(&rom, &symbols, &CodeMap::new(&rom)).unwrap();
+        let names = files.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["constants.asm", "bank_00.asm", "bank_01.asm", "main.asm", "Makefile"]);
+
+        let file = |i: usize| String::from_utf8(files[i].1.clone()).unwrap();
+        assert!(file(0).contains("DEF wBuffer EQU $c000\n"));
+        assert!(file(1).contains("SECTION \"ROM Bank $00\", ROM0[$0000]\n"));
+        assert!(file(1).contains("jr_00_0150:\n    call fn_01_4000 "));
+        assert!(file(1).contains("    jr jr_00_0150 "));
+        assert!(file(2).contains("fn_01_4000:\n    ret "));
+        assert!(file(3).ends_with(
+            "INCLUDE \"constants.asm\"\nINCLUDE \"bank_00.asm\"\nINCLUDE \"bank_01.asm\"\n"
+        ));
+    }
+
+    #[test]
+    fn test_parse_selection() {
+        let range = |bank, start, end| Selection::Range {
+            bank,
+            start: Word::new(start),
+            end: Word::new(end),
+        };
+
+        assert_eq!(Selection::parse(""), Ok(Selection::All));
+        assert_eq!(Selection::parse("1f"), Ok(Selection::Bank(0x1f)));
+        assert_eq!(Selection::parse("0150-3fff"), Ok(range(0, 0x150, 0x3fff)));
+        assert_eq!(Selection::parse("4000-4fff"), Ok(range(1, 0x4000, 0x4fff)));
+        assert_eq!(Selection::parse("5:4000-4fff"), Ok(range(5, 0x4000, 0x4fff)));
+        assert!(Selection::parse("0:4000-4fff").is_err());
+        assert!(Selection::parse("3000-4fff").is_err());
+        assert!(Selection::parse("4fff-4000").is_err());
+        assert!(Selection::parse("xyz").is_err());
+    }
+}
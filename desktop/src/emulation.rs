@@ -0,0 +1,640 @@
+//! The emulation thread.
+//!
+//! The emulator runs in its own thread, so that the pacing of the emulated
+//! frames does not depend on when winit delivers events. The window thread
+//! only renders and forwards keyboard events to the emulation thread (see
+//! `Command`), which sends finished frames and window titles back as winit
+//! user events (see `Update`). Everything accessing the machine (the
+//! debugger, the GDB stub, the remote control, the state manager, ...) lives
+//! in the emulation thread.
+
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use failure::{format_err, Error, ResultExt};
+use winit::{event::VirtualKeyCode, event_loop::EventLoopProxy};
+
+use mahboi::{
+    Emulator, Disruption,
+    cartridge::Cartridge,
+    log::*,
+    machine::input::OpposingDirections,
+};
+use crate::{
+    Outcome, WINDOW_TITLE,
+    achievements::Achievements,
+    args::Args,
+    battery::BatterySave,
+    crash_dump,
+    debug::{Action, GuiDebugger, Request, Snapshot, TuiDebugger, WindowBuffer},
+    env::Env,
+    gdb::GdbStub,
+    remote::RemoteServer,
+    state_file,
+    state_manager::StateManager,
+    timer::LoopTimer,
+    trace_log::TraceLog,
+};
+
+
+/// How often the debugger, the GDB stub and the remote control are updated
+/// while the emulation is paused.
+const PAUSED_INTERVAL: Duration = Duration::from_millis(16);
+
+/// In low latency mode, the emulation does not wait longer than this for the
+/// presentation of the last frame (e.g. while the window is minimized).
+const PRESENT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Messages from the window thread to the emulation thread.
+pub(crate) enum Command {
+    /// A key was pressed (`true`) or released (`false`) at the given time.
+    Key(VirtualKeyCode, bool, Instant),
+
+    /// The last frame was handed to the GPU.
+    Presented,
+
+    /// An action of the user in the graphical debugger window.
+    Debugger(Request),
+
+    /// The window was closed.
+    Quit,
+}
+
+/// Messages from the emulation thread to the window thread.
+#[derive(Debug)]
+pub(crate) enum Update {
+    /// A new frame (RGBA) to present, with the time of the first key press
+    /// it is the first frame emulated after (see `latency`).
+    Frame(Vec<u8>, Option<Instant>),
+
+    /// A new window title.
+    Title(String),
+
+    /// The state of the machine for the graphical debugger window.
+    Debugger(Box<Snapshot>),
+
+    /// The emulation thread stopped, so the window should be closed.
+    Exit,
+}
+
+/// The host keys as seen by the emulation thread.
+#[derive(Default)]
+pub(crate) struct Input {
+    held: HashSet<VirtualKeyCode>,
+
+    /// Keys pressed since the last iteration of the emulation loop.
+    pressed: HashSet<VirtualKeyCode>,
+
+    /// The time of the first key press since the last emulated frame.
+    pressed_at: Option<Instant>,
+}
+
+impl Input {
+    pub(crate) fn key_held(&self, key: VirtualKeyCode) -> bool {
+        self.held.contains(&key)
+    }
+
+    pub(crate) fn key_pressed(&self, key: VirtualKeyCode) -> bool {
+        self.pressed.contains(&key)
+    }
+
+    fn update(&mut self, key: VirtualKeyCode, down: bool, time: Instant) {
+        // Key repeats of the OS are no new presses.
+        if down {
+            if self.held.insert(key) {
+                self.pressed.insert(key);
+                self.pressed_at = self.pressed_at.or(Some(time));
+            }
+        } else {
+            self.held.remove(&key);
+        }
+    }
+}
+
+/// Handle to the emulation thread.
+pub(crate) struct EmulationThread {
+    commands: Sender<Command>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl EmulationThread {
+    /// Starts the emulation thread. Returns once the emulator is created or
+    /// with the error why it could not be created.
+    pub(crate) fn spawn(args: Args, updates: EventLoopProxy<Update>) -> Result<Self, Error> {
+        let (commands, command_rx) = mpsc::channel();
+        let (started_tx, started) = mpsc::channel();
+        let handle = thread::Builder::new()
+            .name("emulation".into())
+            .spawn(move || {
+                let emulation = match Emulation::new(args, command_rx, updates.clone()) {
+                    Ok(emulation) => emulation,
+                    Err(e) => {
+                        let _ = started_tx.send(Err(e));
+                        return;
+                    }
+                };
+                let _ = started_tx.send(Ok(()));
+
+                // The window has to be closed even if the emulator panicked.
+                let result = panic::catch_unwind(AssertUnwindSafe(|| emulation.run()));
+                let _ = updates.send_event(Update::Exit);
+                if let Err(e) = result {
+                    panic::resume_unwind(e);
+                }
+            })
+            .context("failed to spawn emulation thread")?;
+
+        started.recv()
+            .unwrap_or_else(|_| Err(format_err!("emulation thread panicked during startup")))?;
+
+        Ok(Self {
+            commands,
+            handle: Some(handle),
+        })
+    }
+
+    pub(crate) fn send_key(&self, key: VirtualKeyCode, down: bool) {
+        let _ = self.commands.send(Command::Key(key, down, Instant::now()));
+    }
+
+    pub(crate) fn send_presented(&self) {
+        let _ = self.commands.send(Command::Presented);
+    }
+
+    pub(crate) fn send_request(&self, request: Request) {
+        let _ = self.commands.send(Command::Debugger(request));
+    }
+
+    /// Stops the emulation thread and waits until it finished (e.g. writing
+    /// the trace stream). Returns `Err` if the thread panicked.
+    pub(crate) fn join(&mut self) -> thread::Result<()> {
+        let _ = self.commands.send(Command::Quit);
+        match self.handle.take() {
+            Some(handle) => handle.join(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The state of the emulation thread.
+struct Emulation {
+    args: Args,
+    emulator: Emulator,
+    env: Env,
+    is_paused: bool,
+    timer: LoopTimer,
+
+    debugger: Option<TuiDebugger>,
+    gui: Option<GuiDebugger>,
+    gdb: Option<GdbStub>,
+    remote: Option<RemoteServer>,
+    trace_log: Option<TraceLog>,
+    achievements: Option<Achievements>,
+    battery: Option<BatterySave>,
+    state_manager: StateManager,
+
+    input: Input,
+    commands: Receiver<Command>,
+    updates: EventLoopProxy<Update>,
+}
+
+impl Emulation {
+    fn new(
+        args: Args,
+        commands: Receiver<Command>,
+        updates: EventLoopProxy<Update>,
+    ) -> Result<Self, Error> {
+        // Start the GDB server if requested.
+        let gdb = args.gdb.map(GdbStub::new).transpose()?;
+
+        // Start the remote control server if requested.
+        let remote = args.remote.map(RemoteServer::new).transpose()?;
+
+        // Open the instruction log if requested.
+        let trace_log = args.trace_log.as_deref().map(TraceLog::new).transpose()?;
+
+        // Load the ROM from disk and create the emulator.
+        let battery;
+        let emulator = {
+            // Load ROM
+            let rom = fs::read(&args.path_to_rom).context("failed to load ROM file")?;
+            let cartridge = Cartridge::from_bytes(&rom).context("invalid ROM file")?;
+            info!("[desktop] Loaded: {:#?}", cartridge);
+
+            // Create emulator
+            let mut emulator = Emulator::new(cartridge, args.bios, args.model);
+            emulator.set_input_polling(args.input_polling);
+            if args.allow_opposing_directions {
+                emulator.machine_mut().set_opposing_directions(OpposingDirections::Allow);
+            }
+            emulator.machine_mut().set_decode_cache(args.decode_cache);
+
+            // Record the last executed instructions for the debugger and crash
+            // dumps.
+            emulator.machine_mut().set_trace_capacity(args.trace_len);
+
+            // The debugger shows the timing of events in the last frame.
+            emulator.machine_mut().set_timeline_enabled(args.debug);
+
+            // Load the battery save before a save state, which replaces it.
+            battery = BatterySave::for_rom(&args.path_to_rom, emulator.machine_mut());
+
+            if let Some(path) = &args.load_state {
+                state_file::load_file(path, emulator.machine_mut());
+            }
+
+            if let Some(path) = &args.trace_stream {
+                let file = File::create(path)
+                    .context(format!("failed to create trace stream '{}'", path.display()))?;
+                emulator.start_trace(file, args.trace_stream_format);
+            }
+
+            emulator
+        };
+
+        // Create the TUI debugger if we're in debug mode.
+        let debugger = if args.debug {
+            Some(TuiDebugger::new(&args, emulator.machine().cartridge.rom())?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            env: Env::new(&args)?,
+            is_paused: args.debug && !args.instant_start,
+            timer: LoopTimer::new(&args),
+            debugger,
+            gui: if args.gui_debug { Some(GuiDebugger::new()) } else { None },
+            gdb,
+            remote,
+            trace_log,
+
+            // Load the achievements of the ROM, if there are any.
+            achievements: Achievements::for_rom(&args.path_to_rom),
+            battery,
+            state_manager: StateManager::new(&args.path_to_rom),
+            emulator,
+            args,
+            input: Input::default(),
+            commands,
+            updates,
+        })
+    }
+
+    /// Runs until the window is closed or the emulator terminates.
+    fn run(mut self) {
+        while self.update() {}
+
+        // Write the rest of the trace stream before exiting.
+        self.emulator.stop_trace();
+    }
+
+    /// Emulates one frame (unless the emulation is paused), handles the input
+    /// and updates the debugging tools. Returns `false` if the emulation
+    /// should stop.
+    fn update(&mut self) -> bool {
+        let mut running = !self.is_paused && !self.state_manager.is_open();
+        self.input.pressed.clear();
+        if !running {
+            thread::sleep(PAUSED_INTERVAL);
+        } else if self.args.low_latency {
+            if !self.wait_for_presentation() {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(self.args.frame_delay));
+            self.timer.count_frame();
+        } else {
+            self.timer.wait_for_frame();
+        }
+
+        // Handle the input since the last iteration. This happens right
+        // before emulating the frame to include the most recent input.
+        while let Ok(command) = self.commands.try_recv() {
+            if !self.handle_command(command) {
+                return false;
+            }
+        }
+
+        // Handle non-Gameboy input events.
+        let input = &self.input;
+        let machine = self.emulator.machine_mut();
+        self.timer.set_turbo_mode(input.key_held(VirtualKeyCode::Q));
+        if input.key_pressed(VirtualKeyCode::F5) {
+            self.env.input_macro.toggle_recording();
+        }
+        if input.key_pressed(VirtualKeyCode::F6) {
+            self.env.input_macro.play();
+        }
+        if input.key_pressed(VirtualKeyCode::F7) {
+            self.state_manager.quick_save(machine, &self.env.frame);
+        }
+        if input.key_pressed(VirtualKeyCode::F8) {
+            if let Some(battery) = &mut self.battery {
+                battery.flush(machine);
+            }
+            self.state_manager.quick_load(machine);
+        }
+        if input.key_pressed(VirtualKeyCode::F9) {
+            state_file::export_bess(&self.args.path_to_rom, machine);
+        }
+
+        // The state manager overlay replaces the screen and pauses the
+        // emulation while it is open.
+        if input.key_pressed(VirtualKeyCode::F10) {
+            if self.state_manager.is_open() {
+                self.state_manager.close(&mut self.env.frame);
+                self.timer.unpause();
+            } else {
+                // The emulation is paused while the overlay is open, so this
+                // is the last chance to save the game before a state is
+                // loaded.
+                if let Some(battery) = &mut self.battery {
+                    battery.flush(machine);
+                }
+                self.state_manager.open(&mut self.env.frame);
+            }
+        }
+        if self.state_manager.is_open() {
+            let closed = self.state_manager.update(input, machine, &mut self.env.frame);
+            if closed {
+                self.timer.unpause();
+            } else {
+                let description = self.state_manager.description();
+                self.set_title(format!("{} - {}", WINDOW_TITLE, description));
+            }
+        }
+
+        // Run the emulator.
+        running &= !self.state_manager.is_open();
+        let mut input_time = None;
+        if running {
+            input_time = self.input.pressed_at.take();
+            let input = &self.input;
+            self.env.update_keys(|key| input.key_held(key));
+
+            // Actually emulate!
+            let outcome = emulate_frame(
+                &self.args.path_to_rom,
+                &mut self.emulator,
+                &mut self.env,
+                self.debugger.as_mut(),
+                self.gui.as_mut(),
+                self.gdb.as_mut(),
+                self.trace_log.as_mut(),
+            );
+            if let Some(achievements) = &mut self.achievements {
+                achievements.evaluate(self.emulator.machine());
+            }
+
+            match outcome {
+                Outcome::Continue => {}
+                Outcome::Pause => self.is_paused = true,
+                Outcome::Terminate => return false,
+            }
+        }
+
+        // Write the battery save if the game changed it.
+        if let Some(battery) = &mut self.battery {
+            battery.update(self.emulator.machine());
+        }
+
+        // Handle requests from a connected GDB.
+        if let Some(gdb) = &mut self.gdb {
+            let action = gdb.update(self.is_paused, self.emulator.machine_mut());
+            if !self.handle_action(action) {
+                return false;
+            }
+        }
+
+        // Handle requests of remote control clients.
+        if let Some(remote) = &mut self.remote {
+            let battery = &mut self.battery;
+            let action = remote.update(self.emulator.machine_mut(), &self.env.frame, |m| {
+                if let Some(battery) = battery {
+                    battery.flush(m);
+                }
+            });
+            if !self.handle_action(action) {
+                return false;
+            }
+        }
+
+        // If we're in debug mode (and have a TUI debugger), let's update it.
+        if let Some(debugger) = &mut self.debugger {
+            let action = debugger.update(
+                self.is_paused,
+                self.emulator.machine_mut(),
+                WindowBuffer(&mut self.env.frame),
+            );
+            if !self.handle_action(action) {
+                return false;
+            }
+        }
+
+        // Handle the requests of the graphical debugger and send it the new
+        // state of the machine.
+        if let Some(gui) = &mut self.gui {
+            let action = gui.update(self.is_paused, self.emulator.machine());
+            if !self.handle_action(action) {
+                return false;
+            }
+        }
+        if let Some(gui) = &mut self.gui {
+            if let Some(snapshot) = gui.snapshot(self.is_paused, self.emulator.machine()) {
+                let _ = self.updates.send_event(Update::Debugger(snapshot));
+            }
+        }
+
+        // Write FPS and recently unlocked achievements into window title
+        if let Some(fps) = self.timer.report_fps() {
+            let mut title = format!("{} - {:.1} FPS", WINDOW_TITLE, fps);
+            let notification = self.achievements.as_ref().and_then(|a| a.notification());
+            if let Some(unlocked) = notification {
+                title += &format!(" - Achievement unlocked: {}", unlocked);
+            }
+            self.set_title(title);
+        }
+
+        let _ = self.updates.send_event(Update::Frame(self.env.frame.clone(), input_time));
+        true
+    }
+
+    /// Returns `false` if the emulation should stop.
+    fn handle_command(&mut self, command: Command) -> bool {
+        match command {
+            Command::Key(key, down, time) => self.input.update(key, down, time),
+            Command::Presented => {}
+            Command::Debugger(request) => {
+                if let Some(gui) = &mut self.gui {
+                    gui.request(request);
+                }
+            }
+            Command::Quit => return false,
+        }
+
+        true
+    }
+
+    /// Waits until the window thread presented the last frame. Returns `false`
+    /// if the emulation should stop.
+    fn wait_for_presentation(&mut self) -> bool {
+        let deadline = Instant::now() + PRESENT_TIMEOUT;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.commands.recv_timeout(timeout) {
+                Ok(Command::Presented) | Err(RecvTimeoutError::Timeout) => return true,
+                Ok(command) => {
+                    if !self.handle_command(command) {
+                        return false;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return false,
+            }
+        }
+    }
+
+    /// Reacts to the action requested by a debugging tool. Returns `false` if
+    /// the emulation should stop.
+    fn handle_action(&mut self, action: Action) -> bool {
+        match action {
+            Action::Quit => return false,
+            Action::Pause => self.is_paused = true,
+            Action::Continue => {
+                self.is_paused = false;
+                self.timer.unpause();
+            }
+            Action::Nothing => {}
+        }
+
+        true
+    }
+
+    fn set_title(&self, title: String) {
+        let _ = self.updates.send_event(Update::Title(title));
+    }
+}
+
+impl Drop for Emulation {
+    fn drop(&mut self) {
+        // Write the battery save one last time. This also happens when the
+        // emulator panicked, as `run` is unwound then.
+        if let Some(battery) = &mut self.battery {
+            battery.flush(self.emulator.machine());
+        }
+    }
+}
+
+// Emulates one frame of the emulator and correctly handles the debugger and the
+// result of the emulation.
+fn emulate_frame(
+    rom: &Path,
+    emulator: &mut Emulator,
+    env: &mut Env,
+    mut debugger: Option<&mut TuiDebugger>,
+    mut gui: Option<&mut GuiDebugger>,
+    mut gdb: Option<&mut GdbStub>,
+    mut trace_log: Option<&mut TraceLog>,
+) -> Outcome {
+    let debugging = debugger.is_some() || gui.is_some() || gdb.is_some();
+    env.begin_frame();
+    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+        emulator.execute_frame(env, |machine| {
+            if let Some(trace_log) = &mut trace_log {
+                trace_log.observe(machine);
+            }
+
+            // If we have a debugger or GDB stub, we ask it when to pause.
+            // Otherwise, we never stop.
+            if let Some(debugger) = &mut debugger {
+                debugger.should_pause(machine)
+            } else if let Some(gui) = &mut gui {
+                gui.should_pause(machine)
+            } else if let Some(gdb) = &mut gdb {
+                gdb.should_pause(machine)
+            } else {
+                false
+            }
+        })
+    }));
+
+    if let Some(trace_log) = trace_log {
+        trace_log.flush();
+    }
+
+    match res {
+        Err(e) => {
+            let message = e.downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| e.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".into());
+            warn!("Emulator panicked: {}", message);
+
+            // Write a dump of the machine for bug reports.
+            match crash_dump::write(rom, emulator.machine(), &message) {
+                Ok(path) => {
+                    warn!("[desktop] wrote crash dump to '{}'", path.display());
+                    eprintln!("A crash dump was written to '{}'", path.display());
+                }
+                Err(e) => warn!("[desktop] failed to write crash dump: {}", e),
+            }
+
+            if !debugging {
+                panic::resume_unwind(e);
+            }
+
+            Outcome::Pause
+        }
+        Ok(disruption) => {
+            // React to abnormal disruptions
+            match disruption {
+                Ok(_) => Outcome::Continue,
+                Err(Disruption::Paused) => Outcome::Pause,
+                Err(Disruption::Terminated) => {
+                    // If we are not in debug mode, we stop the program, as it
+                    // doesn't make much sense to keep running. In debug mode,
+                    // we just pause execution.
+                    warn!("[desktop] Emulator was terminated");
+                    if debugging {
+                        Outcome::Pause
+                    } else {
+                        Outcome::Terminate
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_input() {
+        let mut input = Input::default();
+        let now = Instant::now();
+        input.update(VirtualKeyCode::J, true, now);
+        assert!(input.key_pressed(VirtualKeyCode::J));
+        assert!(input.key_held(VirtualKeyCode::J));
+        assert_eq!(input.pressed_at, Some(now));
+
+        // Repeats of a held key are no new presses.
+        input.pressed.clear();
+        input.pressed_at = None;
+        input.update(VirtualKeyCode::J, true, now);
+        assert!(!input.key_pressed(VirtualKeyCode::J));
+        assert!(input.key_held(VirtualKeyCode::J));
+        assert_eq!(input.pressed_at, None);
+
+        input.update(VirtualKeyCode::J, false, now);
+        assert!(!input.key_held(VirtualKeyCode::J));
+    }
+}
@@ -4,16 +4,15 @@ use cpal::{Sample, SampleFormat, SampleRate, traits::{DeviceTrait, HostTrait, St
 use failure::{bail, format_err, Error, ResultExt};
 use pixels::{Pixels, SurfaceTexture};
 use winit::{event::VirtualKeyCode, window::Window};
-use winit_input_helper::WinitInputHelper;
 
 use mahboi::{
     SCREEN_WIDTH, SCREEN_HEIGHT, FRAME_RATE, MACHINE_CYCLES_PER_SECOND,
-    env::Peripherals,
+    env::{Frame, GreyFrame, Peripherals},
     primitives::PixelColor,
     machine::input::{Keys, JoypadKey},
     log::*,
 };
-use crate::args::Args;
+use crate::{args::Args, camera, input_macro::InputMacro};
 
 
 type AudioBuffer = Arc<Mutex<Vec<f32>>>;
@@ -30,10 +29,31 @@ const SOURCE_BUFFER_READY_ABOVE: u32 = 5;
 /// enough for the host buffe every second callback or so.
 const SOURCE_BUFFER_TOO_SHORT_BELOW: u32 = 2;
 
+/// Creates the pixel buffer the screen is rendered into.
+pub(crate) fn create_pixels(window: &Window) -> Result<Pixels, Error> {
+    let window_size = window.inner_size();
+    let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, window);
+    let mut pixels = Pixels::new(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32, surface_texture)?;
+
+    // Write 255 to all alpha channels here (well, to all channels for
+    // simplicity). Since pixels 0.9 we need to explicitly set the alpha
+    // values.
+    pixels.get_frame().fill(255);
+
+    Ok(pixels)
+}
+
 /// The environment of the Gameboy. Implements `Peripherals`.
 pub(crate) struct Env {
-    pub(crate) pixels: Pixels,
+    /// The screen as RGBA, which is copied into the pixel buffer of the
+    /// window.
+    pub(crate) frame: Vec<u8>,
+
+    /// The keys pressed by the user and the keys the emulator sees, which
+    /// differ while the input macro is replayed.
+    live_keys: Keys,
     keys: Keys,
+    pub(crate) input_macro: InputMacro,
 
     // Sound system
     audio_buffer: AudioBuffer,
@@ -44,26 +64,13 @@ pub(crate) struct Env {
     /// A fixed (set in `new`) value determining how many emulation cycles pass
     /// per host audio sample (without turbo mode).
     cycles_per_host_sample: f64,
+
+    /// The image passed via `--camera-image`, in its original size.
+    camera_image: Option<GreyFrame>,
 }
 
 impl Env {
-    pub(crate) fn new(args: &Args, window: &Window) -> Result<Self, Error> {
-        // Pixelbuffer for the Gameboy to render into
-        let pixels = {
-            let window_size = window.inner_size();
-            let surface_texture
-                = SurfaceTexture::new(window_size.width, window_size.height, window);
-            let mut pixels =
-                Pixels::new(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32, surface_texture)?;
-
-            // Write 255 to all alpha channels here (well, to all channels for
-            // simplicity). Since pixels 0.9 we need to explicitly set the
-            // alpha values.
-            pixels.get_frame().fill(255);
-
-            pixels
-        };
-
+    pub(crate) fn new(args: &Args) -> Result<Self, Error> {
         // Audio stream for emulated audio
         let audio_buffer = Arc::new(Mutex::new(Vec::new()));
         let cycles_till_next_sample = 0.0;
@@ -75,37 +82,58 @@ impl Env {
         let cycles_per_host_second = (args.fps / FRAME_RATE) * MACHINE_CYCLES_PER_SECOND as f64;
         let cycles_per_host_sample = cycles_per_host_second / stream_config.sample_rate.0 as f64;
 
+        let camera_image = args.camera_image.as_deref().map(camera::load_png).transpose()?;
+
         Ok(Self {
+            live_keys: Keys::none(),
             keys: Keys::none(),
-            pixels,
+            input_macro: InputMacro::for_rom(&args.path_to_rom),
+            frame: vec![255; SCREEN_WIDTH * SCREEN_HEIGHT * 4],
             audio_buffer,
             _stream: stream,
             sample_rate: stream_config.sample_rate.0 as f32,
             cycles_till_next_sample,
             cycles_per_host_sample,
+            camera_image,
         })
     }
 
-    pub(crate) fn update_keys(&mut self, input: &WinitInputHelper) {
-        self.keys = Keys::none()
-            .set_key(JoypadKey::Up, input.key_held(VirtualKeyCode::W))
-            .set_key(JoypadKey::Left, input.key_held(VirtualKeyCode::A))
-            .set_key(JoypadKey::Down, input.key_held(VirtualKeyCode::S))
-            .set_key(JoypadKey::Right, input.key_held(VirtualKeyCode::D))
-            .set_key(JoypadKey::A, input.key_held(VirtualKeyCode::J))
-            .set_key(JoypadKey::B, input.key_held(VirtualKeyCode::K))
-            .set_key(JoypadKey::Select, input.key_held(VirtualKeyCode::N))
-            .set_key(JoypadKey::Start, input.key_held(VirtualKeyCode::M));
+    /// Updates the pressed keys. `key_held` returns whether the given host
+    /// key is currently held down.
+    pub(crate) fn update_keys(&mut self, key_held: impl Fn(VirtualKeyCode) -> bool) {
+        self.live_keys = Keys::none()
+            .set_key(JoypadKey::Up, key_held(VirtualKeyCode::W))
+            .set_key(JoypadKey::Left, key_held(VirtualKeyCode::A))
+            .set_key(JoypadKey::Down, key_held(VirtualKeyCode::S))
+            .set_key(JoypadKey::Right, key_held(VirtualKeyCode::D))
+            .set_key(JoypadKey::A, key_held(VirtualKeyCode::J))
+            .set_key(JoypadKey::B, key_held(VirtualKeyCode::K))
+            .set_key(JoypadKey::Select, key_held(VirtualKeyCode::N))
+            .set_key(JoypadKey::Start, key_held(VirtualKeyCode::M));
+    }
+
+    /// Is called before each emulated frame to advance the input macro.
+    pub(crate) fn begin_frame(&mut self) {
+        self.keys = self.input_macro.next_frame(self.live_keys);
     }
 }
 
 impl Peripherals for Env {
+    // The window only shows complete frames anyway.
+    const FULL_FRAMES: bool = true;
+
     fn get_pressed_keys(&self) -> Keys {
         self.keys
     }
 
+    fn present_frame(&mut self, frame: &Frame) {
+        for (line_idx, pixels) in frame.iter().enumerate() {
+            self.write_lcd_line(line_idx as u8, pixels);
+        }
+    }
+
     fn write_lcd_line(&mut self, line_idx: u8, pixels: &[PixelColor; SCREEN_WIDTH]) {
-        let buffer = self.pixels.get_frame();
+        let buffer = &mut self.frame;
         let offset = line_idx as usize * SCREEN_WIDTH * 4;
 
         // TODO: use zip
@@ -125,6 +153,10 @@ impl Peripherals for Env {
         }
         self.cycles_till_next_sample -= 1.0;
     }
+
+    fn capture_image(&mut self, width: usize, height: usize) -> Option<GreyFrame> {
+        self.camera_image.as_ref().map(|image| camera::scale(image, width, height))
+    }
 }
 
 fn find_best_stream_config(device: &cpal::Device) -> Result<cpal::SupportedStreamConfig, Error> {
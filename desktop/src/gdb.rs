@@ -0,0 +1,855 @@
+//! A stub implementing the GDB remote serial protocol (RSP) over TCP.
+//!
+//! This allows debugging ROMs with `gdb` or any IDE that speaks this protocol.
+//! Only a small subset of the protocol is implemented: reading and writing
+//! registers and memory, breakpoints (`Z0`/`Z1`), watchpoints (`Z2` to `Z4`),
+//! single stepping and continuing.
+//!
+//! GDB has no built-in knowledge about the SM83, so we send a target
+//! description containing six 16 bit registers: AF, BC, DE, HL, SP and PC (in
+//! that order). All values are transferred in little endian, as usual.
+
+use std::{
+    collections::BTreeSet,
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use failure::{Error, ResultExt};
+
+use mahboi::{
+    log::*,
+    machine::{
+        Machine,
+        hooks::{AccessKind, MemoryAccess, Watchpoint},
+    },
+    primitives::{Byte, Word},
+};
+use crate::debug::Action;
+
+
+/// The target description sent to GDB.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.mahboi.sm83">
+    <reg name="af" bitsize="16" type="int"/>
+    <reg name="bc" bitsize="16" type="int"/>
+    <reg name="de" bitsize="16" type="int"/>
+    <reg name="hl" bitsize="16" type="int"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// Signal numbers used in stop replies.
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// Byte sent by GDB to interrupt the running target (Ctrl+C).
+const INTERRUPT: u8 = 0x03;
+
+/// Listens for GDB connections and talks to the connected debugger.
+pub(crate) struct GdbStub {
+    listener: TcpListener,
+    conn: Option<Connection>,
+
+    /// Addresses at which execution is stopped.
+    breakpoints: BTreeSet<Word>,
+
+    /// Watchpoints that were added by GDB. We remember them to be able to
+    /// remove them from the machine once GDB detaches.
+    watchpoints: Vec<Watchpoint>,
+
+    /// Is the emulator running on behalf of GDB (after `c` or `s`)? If this
+    /// is `true` and the emulator is paused, we still owe GDB a stop reply.
+    running: bool,
+
+    /// Are we executing a single instruction (`s` packet)?
+    single_step: bool,
+
+    /// Set when resuming. The next call to `should_pause` will never pause,
+    /// as we otherwise would stop at the same breakpoint/watchpoint again.
+    skip_next_check: bool,
+
+    /// Why we stopped last time.
+    stop_reason: StopReason,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum StopReason {
+    Signal(u8),
+    Watchpoint(MemoryAccess),
+}
+
+struct Connection {
+    stream: TcpStream,
+
+    /// Received bytes that were not handled yet.
+    buffer: Vec<u8>,
+
+    /// Whether GDB asked us to stop sending `+` acknowledgements.
+    no_ack: bool,
+}
+
+impl GdbStub {
+    /// Creates a new stub listening on `localhost` at the given port.
+    pub(crate) fn new(port: u16) -> Result<Self, Error> {
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .context("failed to start GDB server")?;
+        listener.set_nonblocking(true)?;
+        info!("[gdb] waiting for connection on port {}", port);
+
+        Ok(Self {
+            listener,
+            conn: None,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            running: false,
+            single_step: false,
+            skip_next_check: false,
+            stop_reason: StopReason::Signal(SIGTRAP),
+        })
+    }
+
+    /// Accepts new connections and handles all pending packets. Should be
+    /// called regularly.
+    ///
+    /// Returns a requested action.
+    pub(crate) fn update(&mut self, is_paused: bool, machine: &mut Machine) -> Action {
+        if self.conn.is_none() {
+            return self.accept();
+        }
+
+        // If the emulator stopped while running on behalf of GDB, we have to
+        // report that.
+        if self.running && is_paused {
+            self.running = false;
+            self.single_step = false;
+            let reply = self.stop_reply();
+            self.send(&reply);
+        }
+
+        // Read everything available from the socket.
+        let mut closed = false;
+        if let Some(conn) = &mut self.conn {
+            let mut buf = [0; 1024];
+            loop {
+                match conn.stream.read(&mut buf) {
+                    Ok(0) => {
+                        closed = true;
+                        break;
+                    }
+                    Ok(n) => conn.buffer.extend_from_slice(&buf[..n]),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        warn!("[gdb] failed to read from connection: {}", e);
+                        closed = true;
+                        break;
+                    }
+                }
+            }
+        }
+
+        if closed {
+            info!("[gdb] connection closed");
+            return self.detach(machine);
+        }
+
+        // Handle all complete packets.
+        let mut action = Action::Nothing;
+        while let Some(packet) = self.next_packet() {
+            match packet {
+                Packet::Interrupt => {
+                    if self.running {
+                        self.stop_reason = StopReason::Signal(SIGINT);
+                        action = Action::Pause;
+                    }
+                }
+                Packet::Command(cmd) => {
+                    match self.handle_command(&cmd, is_paused, machine) {
+                        Action::Nothing => {}
+                        other => action = other,
+                    }
+                }
+            }
+        }
+
+        action
+    }
+
+    /// Returns `true` if the emulator should pause before executing the next
+    /// instruction.
+    pub(crate) fn should_pause(&mut self, machine: &Machine) -> bool {
+        if self.conn.is_none() {
+            return false;
+        }
+
+        if self.skip_next_check {
+            self.skip_next_check = false;
+            return false;
+        }
+
+        if let Some(access) = machine.watchpoint_hit() {
+            self.stop_reason = StopReason::Watchpoint(access);
+            return true;
+        }
+
+        if self.single_step || self.breakpoints.contains(&machine.cpu.pc) {
+            self.stop_reason = StopReason::Signal(SIGTRAP);
+            return true;
+        }
+
+        false
+    }
+
+    /// Checks for a new connection.
+    fn accept(&mut self) -> Action {
+        match self.listener.accept() {
+            Ok((stream, addr)) => {
+                if let Err(e) = stream.set_nonblocking(true) {
+                    warn!("[gdb] failed to configure connection: {}", e);
+                    return Action::Nothing;
+                }
+
+                info!("[gdb] debugger connected from {}", addr);
+                self.conn = Some(Connection {
+                    stream,
+                    buffer: Vec::new(),
+                    no_ack: false,
+                });
+
+                // GDB expects the target to be stopped once it's attached.
+                self.running = false;
+                self.stop_reason = StopReason::Signal(SIGTRAP);
+                Action::Pause
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Action::Nothing,
+            Err(e) => {
+                warn!("[gdb] failed to accept connection: {}", e);
+                Action::Nothing
+            }
+        }
+    }
+
+    /// Closes the connection and removes all breakpoints and watchpoints.
+    fn detach(&mut self, machine: &mut Machine) -> Action {
+        self.conn = None;
+        self.breakpoints.clear();
+        for w in self.watchpoints.drain(..) {
+            machine.remove_watchpoint(&w);
+        }
+        self.single_step = false;
+        self.running = false;
+
+        Action::Continue
+    }
+
+    /// Extracts the next packet from the receive buffer. Also sends
+    /// acknowledgements.
+    fn next_packet(&mut self) -> Option<Packet> {
+        let conn = self.conn.as_mut()?;
+
+        loop {
+            match conn.buffer.first() {
+                None => return None,
+
+                // Acknowledgements from GDB. We never resend packets, so we
+                // can ignore those.
+                Some(b'+') | Some(b'-') => {
+                    conn.buffer.remove(0);
+                }
+                Some(&INTERRUPT) => {
+                    conn.buffer.remove(0);
+                    return Some(Packet::Interrupt);
+                }
+                Some(b'$') => break,
+                Some(&b) => {
+                    warn!("[gdb] unexpected byte {:#04x} outside of packet", b);
+                    conn.buffer.remove(0);
+                }
+            }
+        }
+
+        // The packet is complete once we have the `#` and both checksum
+        // digits.
+        let end = conn.buffer.iter().position(|&b| b == b'#')?;
+        if conn.buffer.len() < end + 3 {
+            return None;
+        }
+
+        let data = conn.buffer[1..end].to_vec();
+        let checksum = std::str::from_utf8(&conn.buffer[end + 1..end + 3])
+            .ok()
+            .and_then(|s| u8::from_str_radix(s, 16).ok());
+        conn.buffer.drain(..end + 3);
+
+        let valid = checksum == Some(Self::checksum(&data));
+        if !conn.no_ack {
+            let ack: &[u8] = if valid { b"+" } else { b"-" };
+            if let Err(e) = conn.stream.write_all(ack) {
+                warn!("[gdb] failed to send acknowledgement: {}", e);
+            }
+        }
+
+        if valid {
+            Some(Packet::Command(String::from_utf8_lossy(&data).into_owned()))
+        } else {
+            warn!("[gdb] received packet with invalid checksum");
+            self.next_packet()
+        }
+    }
+
+    /// Handles one command packet and sends the reply.
+    fn handle_command(&mut self, cmd: &str, is_paused: bool, machine: &mut Machine) -> Action {
+        trace!("[gdb] <- {}", cmd);
+
+        let (reply, action) = match cmd.as_bytes().first() {
+            Some(b'?') => (self.stop_reply(), Action::Nothing),
+            Some(b'g') => (Self::read_registers(machine), Action::Nothing),
+            Some(b'G') => (Self::write_registers(&cmd[1..], machine), Action::Nothing),
+            Some(b'p') => (Self::read_register(&cmd[1..], machine), Action::Nothing),
+            Some(b'P') => (Self::write_register(&cmd[1..], machine), Action::Nothing),
+            Some(b'm') => (Self::read_memory(&cmd[1..], machine), Action::Nothing),
+            Some(b'M') => (Self::write_memory(&cmd[1..], machine), Action::Nothing),
+            Some(b'Z') => (self.add_break(&cmd[1..], machine), Action::Nothing),
+            Some(b'z') => (self.remove_break(&cmd[1..], machine), Action::Nothing),
+            Some(b'c') | Some(b's') => {
+                // Continuing at another address is not supported.
+                if cmd.len() > 1 {
+                    ("E01".into(), Action::Nothing)
+                } else {
+                    self.single_step = cmd == "s";
+                    self.skip_next_check = true;
+                    self.running = true;
+
+                    // The stop reply is sent once the emulator pauses again.
+                    return if is_paused { Action::Continue } else { Action::Nothing };
+                }
+            }
+            Some(b'k') => {
+                info!("[gdb] received kill request");
+                self.send("OK");
+                return Action::Quit;
+            }
+            Some(b'D') => {
+                info!("[gdb] debugger detached");
+                self.send("OK");
+                return self.detach(machine);
+            }
+            Some(b'H') | Some(b'T') => ("OK".into(), Action::Nothing),
+            Some(b'q') | Some(b'Q') => (self.handle_query(cmd), Action::Nothing),
+
+            // Everything else is not supported. An empty reply tells GDB so.
+            _ => (String::new(), Action::Nothing),
+        };
+
+        self.send(&reply);
+        action
+    }
+
+    /// Handles general query packets (`q...` and `Q...`).
+    fn handle_query(&mut self, cmd: &str) -> String {
+        if cmd.starts_with("qSupported") {
+            "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+".into()
+        } else if cmd == "QStartNoAckMode" {
+            // This packet was already acknowledged. GDB still acknowledges
+            // our `OK`, but that doesn't matter as we ignore those anyway.
+            if let Some(conn) = &mut self.conn {
+                conn.no_ack = true;
+            }
+            "OK".into()
+        } else if cmd == "qAttached" {
+            "1".into()
+        } else if cmd == "qC" {
+            "QC1".into()
+        } else if cmd == "qfThreadInfo" {
+            "m1".into()
+        } else if cmd == "qsThreadInfo" {
+            "l".into()
+        } else if let Some(rest) = cmd.strip_prefix("qXfer:features:read:target.xml:") {
+            match parse_pair(rest) {
+                Some((offset, len)) => {
+                    let offset = (offset as usize).min(TARGET_XML.len());
+                    let end = (offset + len as usize).min(TARGET_XML.len());
+                    let prefix = if end == TARGET_XML.len() { 'l' } else { 'm' };
+                    format!("{}{}", prefix, &TARGET_XML[offset..end])
+                }
+                None => "E01".into(),
+            }
+        } else {
+            String::new()
+        }
+    }
+
+    fn stop_reply(&self) -> String {
+        match self.stop_reason {
+            StopReason::Signal(sig) => format!("S{:02x}", sig),
+            StopReason::Watchpoint(access) => {
+                let kind = match access.kind {
+                    AccessKind::Read => "rwatch",
+                    AccessKind::Write => "watch",
+                };
+                format!("T{:02x}{}:{:04x};", SIGTRAP, kind, access.addr.get())
+            }
+        }
+    }
+
+    fn read_registers(machine: &Machine) -> String {
+        let cpu = &machine.cpu;
+        [cpu.af(), cpu.bc(), cpu.de(), cpu.hl(), cpu.sp, cpu.pc]
+            .iter()
+            .map(|w| encode_word(*w))
+            .collect()
+    }
+
+    fn write_registers(data: &str, machine: &mut Machine) -> String {
+        let words: Option<Vec<_>> = (0..6)
+            .map(|i| data.get(i * 4..i * 4 + 4).and_then(decode_word))
+            .collect();
+
+        match words {
+            Some(words) => {
+                for (i, &w) in words.iter().enumerate() {
+                    Self::set_register(machine, i, w);
+                }
+                "OK".into()
+            }
+            None => "E01".into(),
+        }
+    }
+
+    fn read_register(data: &str, machine: &Machine) -> String {
+        let cpu = &machine.cpu;
+        let value = match usize::from_str_radix(data, 16) {
+            Ok(0) => cpu.af(),
+            Ok(1) => cpu.bc(),
+            Ok(2) => cpu.de(),
+            Ok(3) => cpu.hl(),
+            Ok(4) => cpu.sp,
+            Ok(5) => cpu.pc,
+            _ => return "E01".into(),
+        };
+
+        encode_word(value)
+    }
+
+    fn write_register(data: &str, machine: &mut Machine) -> String {
+        let mut parts = data.splitn(2, '=');
+        let idx = parts.next().and_then(|s| usize::from_str_radix(s, 16).ok());
+        let value = parts.next().and_then(decode_word);
+
+        match (idx, value) {
+            (Some(idx), Some(value)) if idx < 6 => {
+                Self::set_register(machine, idx, value);
+                "OK".into()
+            }
+            _ => "E01".into(),
+        }
+    }
+
+    fn set_register(machine: &mut Machine, idx: usize, value: Word) {
+        let cpu = &mut machine.cpu;
+        match idx {
+            0 => cpu.set_af(value),
+            1 => cpu.set_bc(value),
+            2 => cpu.set_de(value),
+            3 => cpu.set_hl(value),
+            4 => cpu.sp = value,
+            5 => cpu.pc = value,
+            _ => unreachable!(),
+        }
+    }
+
+    fn read_memory(data: &str, machine: &Machine) -> String {
+        let (addr, len) = match parse_pair(data) {
+            Some(v) => v,
+            None => return "E01".into(),
+        };
+
+        // Reads beyond the address space are cut off.
+        let end = (addr as usize + len as usize).min(0x10000);
+        (addr as usize..end)
+            .map(|a| format!("{:02x}", machine.load_byte(Word::new(a as u16)).get()))
+            .collect()
+    }
+
+    fn write_memory(data: &str, machine: &mut Machine) -> String {
+        let mut parts = data.splitn(2, ':');
+        let range = parts.next().and_then(parse_pair);
+        let bytes = parts.next().and_then(decode_bytes);
+
+        match (range, bytes) {
+            (Some((addr, len)), Some(bytes)) if bytes.len() == len as usize => {
+                if addr as usize + bytes.len() > 0x10000 {
+                    return "E02".into();
+                }
+
+                for (i, b) in bytes.into_iter().enumerate() {
+                    machine.store_byte(Word::new(addr as u16 + i as u16), Byte::new(b));
+                }
+                "OK".into()
+            }
+            _ => "E01".into(),
+        }
+    }
+
+    /// Handles `Z` packets (with the `Z` already removed).
+    fn add_break(&mut self, data: &str, machine: &mut Machine) -> String {
+        match parse_break(data) {
+            Some(Break::Code(addr)) => {
+                self.breakpoints.insert(addr);
+                "OK".into()
+            }
+            Some(Break::Watch(w)) => {
+                machine.add_watchpoint(w.clone());
+                self.watchpoints.push(w);
+                "OK".into()
+            }
+            None => String::new(),
+        }
+    }
+
+    /// Handles `z` packets (with the `z` already removed).
+    fn remove_break(&mut self, data: &str, machine: &mut Machine) -> String {
+        match parse_break(data) {
+            Some(Break::Code(addr)) => {
+                self.breakpoints.remove(&addr);
+                "OK".into()
+            }
+            Some(Break::Watch(w)) => {
+                machine.remove_watchpoint(&w);
+                self.watchpoints.retain(|other| *other != w);
+                "OK".into()
+            }
+            None => String::new(),
+        }
+    }
+
+    /// Sends a packet with the given data.
+    fn send(&mut self, data: &str) {
+        trace!("[gdb] -> {}", data);
+
+        if let Some(conn) = &mut self.conn {
+            let packet = format!("${}#{:02x}", data, Self::checksum(data.as_bytes()));
+            if let Err(e) = conn.stream.write_all(packet.as_bytes()) {
+                warn!("[gdb] failed to send packet: {}", e);
+            }
+        }
+    }
+
+    fn checksum(data: &[u8]) -> u8 {
+        data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+    }
+}
+
+enum Packet {
+    Interrupt,
+    Command(String),
+}
+
+enum Break {
+    Code(Word),
+    Watch(Watchpoint),
+}
+
+/// Parses the arguments of `Z` and `z` packets: `type,addr,kind`.
+fn parse_break(data: &str) -> Option<Break> {
+    let mut parts = data.split(',');
+    let ty = parts.next()?;
+    let addr = u16::from_str_radix(parts.next()?, 16).ok()?;
+    let len = u16::from_str_radix(parts.next()?, 16).ok()?.max(1);
+    let end = addr.checked_add(len - 1)?;
+
+    let watchpoint = |on_read, on_write| Watchpoint {
+        range: Word::new(addr)..=Word::new(end),
+        on_read,
+        on_write,
+    };
+
+    match ty {
+        // We don't distinguish between software and hardware breakpoints.
+        "0" | "1" => Some(Break::Code(Word::new(addr))),
+        "2" => Some(Break::Watch(watchpoint(false, true))),
+        "3" => Some(Break::Watch(watchpoint(true, false))),
+        "4" => Some(Break::Watch(watchpoint(true, true))),
+        _ => None,
+    }
+}
+
+/// Parses `a,b` where both are hex numbers.
+fn parse_pair(data: &str) -> Option<(u32, u32)> {
+    let mut parts = data.splitn(2, ',');
+    let a = u32::from_str_radix(parts.next()?, 16).ok()?;
+    let b = u32::from_str_radix(parts.next()?, 16).ok()?;
+    Some((a, b))
+}
+
+/// Encodes a word as little endian hex string.
+fn encode_word(w: Word) -> String {
+    let (lsb, msb) = w.into_bytes();
+    format!("{:02x}{:02x}", lsb.get(), msb.get())
+}
+
+/// Decodes a little endian hex string with 4 digits into a word.
+fn decode_word(s: &str) -> Option<Word> {
+    match decode_bytes(s)?.as_slice() {
+        &[lsb, msb] => Some(Word::from_bytes(Byte::new(lsb), Byte::new(msb))),
+        _ => None,
+    }
+}
+
+fn decode_bytes(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 == 1 {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use mahboi::{
+        BiosKind, Emulator, HardwareModel,
+        test_util::cartridge_with_code,
+    };
+    use super::*;
+
+    /// Returns a stub and the socket of the connected debugger.
+    fn connect() -> (GdbStub, TcpStream) {
+        let mut stub = GdbStub::new(0).unwrap();
+        let client = TcpStream::connect(stub.listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        while stub.conn.is_none() {
+            assert!(matches!(stub.accept(), Action::Nothing | Action::Pause));
+        }
+
+        (stub, client)
+    }
+
+    fn emulator() -> Emulator {
+        Emulator::new(cartridge_with_code(&[]), BiosKind::Minimal, HardwareModel::Dmg)
+    }
+
+    /// Simulates receiving `data` from the debugger.
+    fn receive(stub: &mut GdbStub, data: &[u8]) {
+        stub.conn.as_mut().unwrap().buffer.extend_from_slice(data);
+    }
+
+    /// Reads `len` bytes sent by the stub.
+    fn read(client: &mut TcpStream, len: usize) -> String {
+        let mut buf = vec![0; len];
+        client.read_exact(&mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    /// Reads one packet sent by the stub and returns its data. The checksum
+    /// has to be correct.
+    fn read_packet(client: &mut TcpStream) -> String {
+        assert_eq!(read(client, 1), "$");
+        let mut data = String::new();
+        loop {
+            match read(client, 1).as_str() {
+                "#" => break,
+                c => data.push_str(c),
+            }
+        }
+        let checksum = u8::from_str_radix(&read(client, 2), 16).unwrap();
+        assert_eq!(checksum, GdbStub::checksum(data.as_bytes()));
+        data
+    }
+
+    /// Handles `cmd` and returns the reply.
+    fn command(
+        stub: &mut GdbStub,
+        client: &mut TcpStream,
+        machine: &mut Machine,
+        cmd: &str,
+    ) -> String {
+        assert!(matches!(stub.handle_command(cmd, true, machine), Action::Nothing));
+        read_packet(client)
+    }
+
+    fn next_command(stub: &mut GdbStub) -> Option<String> {
+        match stub.next_packet() {
+            Some(Packet::Command(cmd)) => Some(cmd),
+            Some(Packet::Interrupt) => panic!("unexpected interrupt"),
+            None => None,
+        }
+    }
+
+    #[test]
+    fn test_framing() {
+        let (mut stub, mut client) = connect();
+
+        // Packets are only complete with both checksum digits.
+        receive(&mut stub, b"+$q");
+        assert!(stub.next_packet().is_none());
+        receive(&mut stub, b"C#b");
+        assert!(stub.next_packet().is_none());
+        receive(&mut stub, b"4");
+        assert_eq!(next_command(&mut stub).as_deref(), Some("qC"));
+        assert_eq!(read(&mut client, 1), "+");
+        assert!(stub.conn.as_ref().unwrap().buffer.is_empty());
+
+        // Acknowledgements and garbage between packets are skipped.
+        receive(&mut stub, b"-x+$?#3f+");
+        assert_eq!(next_command(&mut stub).as_deref(), Some("?"));
+        assert!(stub.next_packet().is_none());
+        assert_eq!(read(&mut client, 1), "+");
+
+        receive(&mut stub, &[INTERRUPT]);
+        assert!(matches!(stub.next_packet(), Some(Packet::Interrupt)));
+        assert!(stub.next_packet().is_none());
+    }
+
+    #[test]
+    fn test_bad_checksum() {
+        let (mut stub, mut client) = connect();
+
+        // Invalid packets are rejected with a `-` and skipped.
+        receive(&mut stub, b"$qC#00$qC#zz$qC#B4");
+        assert_eq!(next_command(&mut stub).as_deref(), Some("qC"));
+        assert_eq!(read(&mut client, 3), "--+");
+
+        receive(&mut stub, b"$qC#00");
+        assert!(stub.next_packet().is_none());
+        assert_eq!(read(&mut client, 1), "-");
+    }
+
+    #[test]
+    fn test_no_ack_mode() {
+        let (mut stub, mut client) = connect();
+        let mut emulator = emulator();
+        let machine = emulator.machine_mut();
+
+        // The `OK` is still acknowledged by GDB, but we don't send
+        // acknowledgements anymore.
+        receive(&mut stub, b"$QStartNoAckMode#b0");
+        let cmd = next_command(&mut stub).unwrap();
+        assert_eq!(read(&mut client, 1), "+");
+        assert_eq!(command(&mut stub, &mut client, machine, &cmd), "OK");
+        assert!(stub.conn.as_ref().unwrap().no_ack);
+
+        receive(&mut stub, b"$qC#b4$qC#00");
+        assert_eq!(next_command(&mut stub).as_deref(), Some("qC"));
+        assert!(stub.next_packet().is_none());
+
+        // Nothing was sent in between.
+        assert_eq!(command(&mut stub, &mut client, machine, "qC"), "QC1");
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let (mut stub, mut client) = connect();
+        let mut emulator = emulator();
+        let machine = emulator.machine_mut();
+
+        assert_eq!(command(&mut stub, &mut client, machine, "Z0,150,1"), "OK");
+        assert_eq!(command(&mut stub, &mut client, machine, "Z1,c3,1"), "OK");
+        assert!(stub.breakpoints.iter().eq(&[Word::new(0xC3), Word::new(0x150)]));
+        assert_eq!(command(&mut stub, &mut client, machine, "z0,150,1"), "OK");
+        assert!(stub.breakpoints.iter().eq(&[Word::new(0xC3)]));
+
+        assert_eq!(command(&mut stub, &mut client, machine, "Z2,c000,2"), "OK");
+        assert_eq!(stub.watchpoints, vec![Watchpoint {
+            range: Word::new(0xC000)..=Word::new(0xC001),
+            on_read: false,
+            on_write: true,
+        }]);
+        assert_eq!(command(&mut stub, &mut client, machine, "z2,c000,2"), "OK");
+        assert!(stub.watchpoints.is_empty());
+
+        // Unsupported or invalid breakpoints get an empty reply.
+        assert_eq!(command(&mut stub, &mut client, machine, "Z5,150,1"), "");
+        assert_eq!(command(&mut stub, &mut client, machine, "Z0,xyz,1"), "");
+        assert_eq!(command(&mut stub, &mut client, machine, "Z4,ffff,2"), "");
+    }
+
+    #[test]
+    fn test_parse_break() {
+        assert!(matches!(parse_break("0,150,1"), Some(Break::Code(a)) if a == Word::new(0x150)));
+        assert!(matches!(parse_break("1,0,0"), Some(Break::Code(a)) if a == Word::new(0)));
+        assert!(matches!(parse_break("3,ff80,1"), Some(Break::Watch(w)) if w == Watchpoint {
+            range: Word::new(0xFF80)..=Word::new(0xFF80),
+            on_read: true,
+            on_write: false,
+        }));
+        assert!(matches!(parse_break("4,fffe,2"), Some(Break::Watch(w)) if w == Watchpoint {
+            range: Word::new(0xFFFE)..=Word::new(0xFFFF),
+            on_read: true,
+            on_write: true,
+        }));
+        assert!(parse_break("4,ffff,2").is_none());
+        assert!(parse_break("0,10000,1").is_none());
+        assert!(parse_break("0,150").is_none());
+    }
+
+    #[test]
+    fn test_memory() {
+        let (mut stub, mut client) = connect();
+        let mut emulator = emulator();
+        let machine = emulator.machine_mut();
+
+        assert_eq!(command(&mut stub, &mut client, machine, "Mc000,3:0aBcff"), "OK");
+        assert_eq!(command(&mut stub, &mut client, machine, "mc000,3"), "0abcff");
+        assert_eq!(command(&mut stub, &mut client, machine, "mc001,0"), "");
+        assert_eq!(command(&mut stub, &mut client, machine, "m150,2"), "0000");
+
+        // Reads are cut off at the end of the address space, writes fail.
+        assert_eq!(command(&mut stub, &mut client, machine, "mfffe,4").len(), 4);
+        assert_eq!(command(&mut stub, &mut client, machine, "Mffff,2:0102"), "E02");
+
+        assert_eq!(command(&mut stub, &mut client, machine, "Mc000,2:01"), "E01");
+        assert_eq!(command(&mut stub, &mut client, machine, "Mc000,1:0"), "E01");
+        assert_eq!(command(&mut stub, &mut client, machine, "mc000"), "E01");
+        assert_eq!(command(&mut stub, &mut client, machine, "mc000,3"), "0abcff");
+    }
+
+    #[test]
+    fn test_registers() {
+        let (mut stub, mut client) = connect();
+        let mut emulator = emulator();
+        let machine = emulator.machine_mut();
+
+        let cpu = &mut machine.cpu;
+        cpu.set_af(Word::new(0x12B0));
+        cpu.set_bc(Word::new(0x3456));
+        cpu.set_de(Word::new(0x789A));
+        cpu.set_hl(Word::new(0xBCDE));
+        cpu.sp = Word::new(0xFFFE);
+        cpu.pc = Word::new(0x0150);
+        let regs = "b01256349a78debcfeff5001";
+        assert_eq!(command(&mut stub, &mut client, machine, "g"), regs);
+        assert_eq!(command(&mut stub, &mut client, machine, "p5"), "5001");
+        assert_eq!(command(&mut stub, &mut client, machine, "p6"), "E01");
+
+        assert_eq!(command(&mut stub, &mut client, machine, "P5=0002"), "OK");
+        assert_eq!(machine.cpu.pc, Word::new(0x0200));
+        assert_eq!(command(&mut stub, &mut client, machine, "G0000"), "E01");
+        assert_eq!(command(&mut stub, &mut client, machine, &format!("G{}", regs)), "OK");
+        assert_eq!(command(&mut stub, &mut client, machine, "g"), regs);
+    }
+
+    #[test]
+    fn test_encoding() {
+        assert_eq!(parse_pair("c000,10"), Some((0xC000, 0x10)));
+        assert_eq!(parse_pair("c000"), None);
+        assert_eq!(parse_pair("c000,"), None);
+
+        assert_eq!(encode_word(Word::new(0x1234)), "3412");
+        assert_eq!(decode_word("3412"), Some(Word::new(0x1234)));
+        assert_eq!(decode_word("341"), None);
+        assert_eq!(decode_word("341256"), None);
+        assert_eq!(decode_word("zz12"), None);
+        assert_eq!(decode_bytes("0aFf"), Some(vec![0x0A, 0xFF]));
+        assert_eq!(decode_bytes("é0"), None);
+    }
+}
@@ -0,0 +1,378 @@
+//! Exporting graphics as indexed PNG images: tile sheets and tile maps.
+//!
+//! Tiles are 8x8 pixels with 2 bits per pixel, stored in 16 bytes: two bytes
+//! per row, the first one holding the low bits and the second one the high
+//! bits of the color numbers. The leftmost pixel is in bit 7. Sheets are
+//! `SHEET_COLUMNS` tiles wide and contain all tiles in the order they are
+//! stored in memory, so tile `n` of VRAM is at column `n % 16` and row
+//! `n / 16`. Tile maps are exported as 256x256 images with all 32x32 tiles,
+//! regardless of which part is visible.
+
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+    path::Path,
+};
+
+use failure::{Error, ResultExt};
+use mahboi::{
+    SCREEN_HEIGHT, SCREEN_WIDTH,
+    machine::{
+        Machine,
+        ppu::TileMapArea,
+    },
+    primitives::{Byte, PixelColor, Word},
+};
+use crate::analyze::{ByteKind, CodeMap, RomAddr, BANK_SIZE};
+
+
+/// The number of tiles per row in a sheet.
+const SHEET_COLUMNS: usize = 16;
+
+/// The number of bytes of one tile.
+const TILE_SIZE: usize = 16;
+
+/// The number of tiles in VRAM (`0x8000..0x9800`).
+const VRAM_TILES: usize = 384;
+
+/// The palette used for tiles from ROM: the identity mapping.
+const DEFAULT_PALETTE: u8 = 0b11_10_01_00;
+
+/// The number of tiles per row and column of a tile map.
+const MAP_TILES: usize = 32;
+
+/// The color index of tiles in ROM that overlap code.
+const CODE_INDEX: u8 = 4;
+
+/// The color index of the viewport rectangle in tile maps.
+const VIEWPORT_INDEX: u8 = 5;
+
+/// The colors of the indices after the four shades.
+const EXTRA_COLORS: [[u8; 3]; 2] = [
+    [0x60, 0x10, 0x10],
+    [0xF0, 0x20, 0x20],
+];
+
+/// The palette register that is applied to the color numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Palette {
+    Bg,
+    Obj0,
+    Obj1,
+}
+
+impl Palette {
+    pub(crate) fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "bg" => Ok(Palette::Bg),
+            "obj0" => Ok(Palette::Obj0),
+            "obj1" => Ok(Palette::Obj1),
+            _ => Err(format!("unknown palette '{}' (expected bg, obj0 or obj1)", s)),
+        }
+    }
+
+    pub(crate) fn register(self) -> Word {
+        match self {
+            Palette::Bg => Word::new(0xFF47),
+            Palette::Obj0 => Word::new(0xFF48),
+            Palette::Obj1 => Word::new(0xFF49),
+        }
+    }
+
+    /// For sprites, color number 0 is transparent.
+    pub(crate) fn is_transparent(self) -> bool {
+        self != Palette::Bg
+    }
+}
+
+/// An image of color numbers (0 to 3, `CODE_INDEX` or `VIEWPORT_INDEX`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Image {
+    pub(crate) width: usize,
+    pub(crate) height: usize,
+    pub(crate) pixels: Vec<u8>,
+}
+
+impl Image {
+    pub(crate) fn new(width: usize, height: usize) -> Self {
+        Self { width, height, pixels: vec![0; width * height] }
+    }
+
+    /// Returns a sheet with space for `count` tiles.
+    fn sheet(count: usize) -> Self {
+        let rows = count.div_ceil(SHEET_COLUMNS);
+        Self::new(SHEET_COLUMNS * 8, rows * 8)
+    }
+
+    /// Draws the tile encoded in the 16 bytes `data` with its top left
+    /// corner at `x` and `y`.
+    pub(crate) fn draw_tile(&mut self, x: usize, y: usize, data: &[Byte]) {
+        for (row, bytes) in data.chunks(2).enumerate() {
+            let (lo, hi) = (bytes[0].get(), bytes[1].get());
+            for col in 0..8 {
+                let bit = 7 - col;
+                let color = ((hi >> bit) & 1) << 1 | ((lo >> bit) & 1);
+                self.pixels[(y + row) * self.width + x + col] = color;
+            }
+        }
+    }
+
+    /// Draws tile `n` of a sheet.
+    fn draw_sheet_tile(&mut self, n: usize, data: &[Byte]) {
+        self.draw_tile(n % SHEET_COLUMNS * 8, n / SHEET_COLUMNS * 8, data);
+    }
+
+    fn fill_sheet_tile(&mut self, n: usize, color: u8) {
+        let (x, y) = (n % SHEET_COLUMNS * 8, n / SHEET_COLUMNS * 8);
+        for row in y..y + 8 {
+            self.pixels[row * self.width + x..][..8].iter_mut().for_each(|p| *p = color);
+        }
+    }
+
+    /// Draws the outline of a rectangle with its top left corner at `x` and
+    /// `y`. Parts outside of the image wrap around, like the viewport does.
+    fn draw_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: u8) {
+        let mut set = |x: usize, y: usize| {
+            let (x, y) = (x % self.width, y % self.height);
+            self.pixels[y * self.width + x] = color;
+        };
+        for dx in 0..width {
+            set(x + dx, y);
+            set(x + dx, y + height - 1);
+        }
+        for dy in 0..height {
+            set(x, y + dy);
+            set(x + width - 1, y + dy);
+        }
+    }
+
+    /// Writes the image as indexed PNG. The color numbers are mapped to
+    /// shades with `palette` (encoded like BGP). If `transparent` is set,
+    /// color number 0 is transparent.
+    pub(crate) fn write_png(
+        &self,
+        path: &Path,
+        palette: Byte,
+        transparent: bool,
+    ) -> Result<(), Error> {
+        let file = File::create(path)
+            .context(format!("failed to create '{}'", path.display()))?;
+
+        let colors: Vec<u8> = colors(palette).iter().flatten().copied().collect();
+        let mut encoder = png::Encoder::new(
+            BufWriter::new(file),
+            self.width as u32,
+            self.height as u32,
+        );
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(colors);
+        if transparent {
+            encoder.set_trns(vec![0]);
+        }
+        encoder.write_header()?.write_image_data(&self.pixels)?;
+
+        Ok(())
+    }
+
+    /// Returns the image as RGBA with the same colors as `write_png`.
+    pub(crate) fn to_rgba(&self, palette: Byte, transparent: bool) -> Vec<u8> {
+        let colors = colors(palette);
+        let mut out = Vec::with_capacity(self.pixels.len() * 4);
+        for &index in &self.pixels {
+            let alpha = if transparent && index == 0 { 0 } else { 0xFF };
+            out.extend_from_slice(&colors[index as usize]);
+            out.push(alpha);
+        }
+        out
+    }
+}
+
+/// Returns the colors of all color indices: the four shades mapped with
+/// `palette` (encoded like BGP), followed by `EXTRA_COLORS`.
+fn colors(palette: Byte) -> Vec<[u8; 3]> {
+    let shades = (0..4).map(|i| {
+        let shade = (palette.get() >> (i * 2)) & 0b11;
+        PixelColor::from_greyscale(shade).to_srgb()
+    });
+    shades.chain(EXTRA_COLORS.iter().copied()).collect()
+}
+
+/// Returns a sheet of all tiles in VRAM.
+pub(crate) fn vram_tiles(machine: &Machine) -> Image {
+    let vram = machine.ppu.vram.as_slice();
+    let mut out = Image::sheet(VRAM_TILES);
+    for (n, data) in vram[..VRAM_TILES * TILE_SIZE].chunks(TILE_SIZE).enumerate() {
+        out.draw_sheet_tile(n, data);
+    }
+    out
+}
+
+/// Writes all tiles in VRAM with the current value of the given palette
+/// register applied.
+pub(crate) fn export_vram_tiles(
+    path: &Path,
+    machine: &Machine,
+    palette: Palette,
+) -> Result<(), Error> {
+    let value = machine.load_byte_bypass_dma(palette.register());
+    vram_tiles(machine).write_png(path, value, palette.is_transparent())
+}
+
+/// Returns a sheet of the given ROM bank, decoded as if it only contained
+/// tiles. Tiles overlapping code found by the analysis are filled with
+/// `CODE_INDEX`. Returns `None` if the whole bank is code.
+pub(crate) fn rom_tiles(rom: &[Byte], bank: usize, code_map: &CodeMap) -> Option<Image> {
+    let data = &rom[bank * BANK_SIZE..][..BANK_SIZE];
+    let base = if bank == 0 { 0 } else { 0x4000 };
+    let is_code = |offset: usize| {
+        let addr = RomAddr::new(bank, Word::new((base + offset) as u16));
+        code_map.kind_at(addr) == ByteKind::Code
+    };
+
+    let mut out = Image::sheet(BANK_SIZE / TILE_SIZE);
+    let mut any_data = false;
+    for (n, tile) in data.chunks(TILE_SIZE).enumerate() {
+        if (n * TILE_SIZE..(n + 1) * TILE_SIZE).any(is_code) {
+            out.fill_sheet_tile(n, CODE_INDEX);
+        } else {
+            out.draw_sheet_tile(n, tile);
+            any_data = true;
+        }
+    }
+
+    if any_data { Some(out) } else { None }
+}
+
+/// Writes one sheet per ROM bank (`bank_XX.png`) to `dir`, see `rom_tiles`.
+/// Returns the number of written files.
+pub(crate) fn export_rom_tiles(
+    dir: &Path,
+    rom: &[Byte],
+    code_map: &CodeMap,
+) -> Result<usize, Error> {
+    fs::create_dir_all(dir).context(format!("failed to create '{}'", dir.display()))?;
+
+    let mut count = 0;
+    for bank in 0..rom.len() / BANK_SIZE {
+        if let Some(sheet) = rom_tiles(rom, bank, code_map) {
+            let path = dir.join(format!("bank_{:02X}.png", bank));
+            sheet.write_png(&path, Byte::new(DEFAULT_PALETTE), false)?;
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+/// Returns the tile map in the given area as 256x256 image, using the tile
+/// data area currently selected in LCD control.
+pub(crate) fn tile_map(machine: &Machine, area: TileMapArea) -> Image {
+    let vram = machine.ppu.vram.as_slice();
+    let data_area = machine.ppu.regs().bg_window_tile_data_address();
+    let start = area.absolute().start.get() as usize - 0x8000;
+
+    let mut out = Image::new(MAP_TILES * 8, MAP_TILES * 8);
+    for (i, &idx) in vram[start..][..MAP_TILES * MAP_TILES].iter().enumerate() {
+        let offset = data_area.index(idx).get() as usize;
+        out.draw_tile(i % MAP_TILES * 8, i / MAP_TILES * 8, &vram[offset..][..TILE_SIZE]);
+    }
+    out
+}
+
+/// Returns the background map and the window map. If `viewport` is set, the
+/// part of each map that is visible on the screen is outlined.
+pub(crate) fn maps(machine: &Machine, viewport: bool) -> (Image, Image) {
+    let regs = machine.ppu.regs();
+    let mut bg = tile_map(machine, regs.bg_tile_map_address());
+    let mut window = tile_map(machine, regs.window_tile_map_address());
+
+    if viewport {
+        let (x, y) = (regs.scroll_bg_x.get() as usize, regs.scroll_bg_y.get() as usize);
+        bg.draw_rect(x, y, SCREEN_WIDTH, SCREEN_HEIGHT, VIEWPORT_INDEX);
+
+        // The top left corner of the window is shown at `(WX - 7, WY)`. With
+        // `WX < 7`, the left part of the window is cut off.
+        let (wx, wy) = (regs.scroll_win_x.get() as usize, regs.scroll_win_y.get() as usize);
+        if regs.is_window_enabled() && wx < SCREEN_WIDTH + 7 && wy < SCREEN_HEIGHT {
+            let left = 7usize.saturating_sub(wx);
+            let width = SCREEN_WIDTH + 7 - wx.max(7);
+            window.draw_rect(left, 0, width, SCREEN_HEIGHT - wy, VIEWPORT_INDEX);
+        }
+    }
+
+    (bg, window)
+}
+
+/// Writes the background map (`bg.png`) and the window map (`window.png`) to
+/// `dir`, with the background palette applied. If `viewport` is set, the part
+/// of each map that is visible on the screen is outlined.
+pub(crate) fn export_maps(dir: &Path, machine: &Machine, viewport: bool) -> Result<(), Error> {
+    let (bg, window) = maps(machine, viewport);
+    fs::create_dir_all(dir).context(format!("failed to create '{}'", dir.display()))?;
+    let palette = machine.ppu.regs().background_palette;
+    bg.write_png(&dir.join("bg.png"), palette, false)?;
+    window.write_png(&dir.join("window.png"), palette, false)?;
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use mahboi::{BiosKind, Emulator, HardwareModel, cartridge::Cartridge};
+    use super::*;
+
+    #[test]
+    fn test_draw_tile() {
+        // The first row of the example tile from the Pan Docs.
+        let mut data = vec![Byte::new(0x3C), Byte::new(0x7E)];
+        data.extend(vec![Byte::zero(); TILE_SIZE - 2]);
+
+        let mut sheet = Image::sheet(20);
+        assert_eq!((sheet.width, sheet.height), (128, 16));
+        sheet.draw_sheet_tile(17, &data);
+        assert_eq!(&sheet.pixels[8 * 128 + 8..][..8], &[0, 2, 3, 3, 3, 3, 2, 0]);
+        assert!(sheet.pixels[9 * 128..].iter().all(|&p| p == 0));
+
+        sheet.fill_sheet_tile(0, CODE_INDEX);
+        assert_eq!(sheet.pixels[7 * 128 + 7], CODE_INDEX);
+        assert_eq!(sheet.pixels[7 * 128 + 8], 0);
+    }
+
+    #[test]
+    fn test_to_rgba() {
+        let mut image = Image::new(3, 1);
+        image.pixels.copy_from_slice(&[0, 1, VIEWPORT_INDEX]);
+
+        // Color number 1 is mapped to black, 0 to white.
+        let white = PixelColor::from_greyscale(0).to_srgb();
+        let black = PixelColor::from_greyscale(3).to_srgb();
+        let rgba = image.to_rgba(Byte::new(0b0000_1100), true);
+        assert_eq!(&rgba[..4], &[white[0], white[1], white[2], 0]);
+        assert_eq!(&rgba[4..8], &[black[0], black[1], black[2], 0xFF]);
+        assert_eq!(&rgba[8..], &[0xF0, 0x20, 0x20, 0xFF]);
+    }
+
+    #[test]
+    fn test_tile_map() {
+        let cartridge = Cartridge::from_bytes(&[0; 0x8000]).unwrap();
+        let mut emulator = Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        let machine = emulator.machine_mut();
+        machine.store_byte(Word::new(0xFF40), Byte::new(0x91));
+
+        // The second map entry shows tile 1, whose first row has color 1.
+        machine.ppu.vram[Word::new(0x1801)] = Byte::new(1);
+        machine.ppu.vram[Word::new(0x0010)] = Byte::new(0xFF);
+
+        let mut map = tile_map(machine, TileMapArea::Low);
+        assert_eq!((map.width, map.height), (256, 256));
+        assert_eq!(&map.pixels[6..10], &[0, 0, 1, 1]);
+        assert_eq!(map.pixels[256 + 8], 0);
+
+        // The viewport wraps around.
+        map.draw_rect(200, 0, SCREEN_WIDTH, SCREEN_HEIGHT, VIEWPORT_INDEX);
+        assert_eq!(map.pixels[143 * 256 + 103], VIEWPORT_INDEX);
+        assert_eq!(map.pixels[143 * 256 + 104], 0);
+    }
+}
@@ -0,0 +1,214 @@
+//! Recording a short input sequence (a macro) and replaying it on a hotkey.
+//!
+//! In contrast to a full recording of all inputs, a macro is relative to the
+//! moment of playback: it is replayed from the frame in which the hotkey is
+//! pressed. The macro of each ROM is stored in the config directory (e.g.
+//! `~/.config/mahboi/macros`) and loaded at startup.
+//!
+//! The file contains one line per run of frames with the same keys: the
+//! number of frames and the pressed keys, e.g. `12 A+Right` (or `12 -` if no
+//! key is pressed).
+
+use std::{
+    env,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use mahboi::{
+    log::*,
+    machine::input::{JoypadKey, Keys},
+};
+
+
+/// The names of all keys, as used in the macro file.
+const KEY_NAMES: [(JoypadKey, &str); 8] = [
+    (JoypadKey::A, "A"),
+    (JoypadKey::B, "B"),
+    (JoypadKey::Select, "Select"),
+    (JoypadKey::Start, "Start"),
+    (JoypadKey::Right, "Right"),
+    (JoypadKey::Left, "Left"),
+    (JoypadKey::Up, "Up"),
+    (JoypadKey::Down, "Down"),
+];
+
+/// The macro of the running ROM.
+pub(crate) struct InputMacro {
+    /// The file the macro is stored in. `None` if there is no config
+    /// directory.
+    path: Option<PathBuf>,
+
+    /// The pressed keys of each frame.
+    frames: Vec<Keys>,
+
+    state: State,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    Recording,
+
+    /// Contains the index of the next frame to replay.
+    Playing(usize),
+}
+
+impl InputMacro {
+    /// Loads the macro of the given ROM, if one was stored.
+    pub(crate) fn for_rom(rom: &Path) -> Self {
+        let path = config_dir().and_then(|dir| {
+            let name = rom.file_stem()?;
+            Some(dir.join("mahboi").join("macros").join(name).with_extension("macro"))
+        });
+
+        let frames = match path.as_ref().map(fs::read_to_string) {
+            Some(Ok(content)) => parse(&content).unwrap_or_else(|e| {
+                warn!("[desktop] ignoring invalid input macro: {}", e);
+                Vec::new()
+            }),
+            _ => Vec::new(),
+        };
+
+        Self { path, frames, state: State::Idle }
+    }
+
+    /// Starts recording a new macro or stops the recording and stores the
+    /// macro.
+    pub(crate) fn toggle_recording(&mut self) {
+        if self.state != State::Recording {
+            info!("[desktop] recording input macro");
+            self.frames.clear();
+            self.state = State::Recording;
+            return;
+        }
+
+        self.state = State::Idle;
+        info!("[desktop] recorded input macro of {} frames", self.frames.len());
+        if let Some(path) = &self.path {
+            let result = fs::create_dir_all(path.parent().unwrap())
+                .and_then(|_| fs::write(path, serialize(&self.frames)));
+            if let Err(e) = result {
+                warn!("[desktop] failed to write input macro '{}': {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Starts replaying the macro from the beginning.
+    pub(crate) fn play(&mut self) {
+        if self.state == State::Recording {
+            return;
+        }
+
+        if self.frames.is_empty() {
+            info!("[desktop] no input macro recorded");
+        } else {
+            self.state = State::Playing(0);
+        }
+    }
+
+    /// Is called once per emulated frame with the keys pressed by the user.
+    /// Returns the keys the emulator should see.
+    pub(crate) fn next_frame(&mut self, live: Keys) -> Keys {
+        match self.state {
+            State::Idle => live,
+            State::Recording => {
+                self.frames.push(live);
+                live
+            }
+            State::Playing(idx) => {
+                self.state = if idx + 1 < self.frames.len() {
+                    State::Playing(idx + 1)
+                } else {
+                    State::Idle
+                };
+                self.frames[idx]
+            }
+        }
+    }
+}
+
+/// Returns the platform specific directory for configuration files.
+pub(crate) fn config_dir() -> Option<PathBuf> {
+    if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        env::var_os("HOME").map(|home| Path::new(&home).join("Library/Application Support"))
+    } else {
+        env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+    }
+}
+
+fn serialize(frames: &[Keys]) -> String {
+    let mut out = String::new();
+    let mut rest = frames;
+    while let Some(&keys) = rest.first() {
+        let count = rest.iter().take_while(|&&k| k == keys).count();
+        let names = KEY_NAMES.iter()
+            .filter(|(key, _)| keys.is_pressed(*key))
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>();
+        let names = if names.is_empty() { "-".to_string() } else { names.join("+") };
+
+        out += &format!("{} {}\n", count, names);
+        rest = &rest[count..];
+    }
+
+    out
+}
+
+fn parse(src: &str) -> Result<Vec<Keys>, String> {
+    let mut frames = Vec::new();
+    for line in src.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+        let (count, names) = line.split_once(' ')
+            .ok_or_else(|| format!("invalid line '{}'", line))?;
+        let count = count.parse::<usize>()
+            .map_err(|_| format!("invalid frame count '{}'", count))?;
+
+        let mut keys = Keys::none();
+        for name in names.split('+').filter(|&n| n != "-") {
+            let key = KEY_NAMES.iter()
+                .find(|(_, n)| *n == name)
+                .ok_or_else(|| format!("unknown key '{}'", name))?
+                .0;
+            keys = keys.set_key(key, true);
+        }
+
+        frames.resize(frames.len() + count, keys);
+    }
+
+    Ok(frames)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record_play() {
+        let a = Keys::none().set_key(JoypadKey::A, true);
+        let a_right = a.set_key(JoypadKey::Right, true);
+        let mut input_macro = InputMacro { path: None, frames: vec![], state: State::Idle };
+        input_macro.play();
+
+        input_macro.toggle_recording();
+        for &keys in &[a, a, Keys::none(), a_right] {
+            assert_eq!(input_macro.next_frame(keys), keys);
+        }
+        input_macro.toggle_recording();
+
+        let serialized = serialize(&input_macro.frames);
+        assert_eq!(serialized, "2 A\n1 -\n1 A+Right\n");
+        assert_eq!(parse(&serialized), Ok(input_macro.frames.clone()));
+        assert!(parse("3 A+Foo").is_err());
+
+        // The macro replaces the live keys until it is finished.
+        assert_eq!(input_macro.next_frame(Keys::none()), Keys::none());
+        input_macro.play();
+        let replayed = (0..5).map(|_| input_macro.next_frame(Keys::none())).collect::<Vec<_>>();
+        assert_eq!(replayed, [a, a, Keys::none(), a_right, Keys::none()]);
+    }
+}
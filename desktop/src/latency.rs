@@ -0,0 +1,132 @@
+//! Measuring the input latency, shown in an overlay (`--latency-overlay`).
+//!
+//! The latency of a key press is the time from winit delivering the key event
+//! until the first frame emulated with that input is handed to the GPU. It
+//! does not include the latency of the input device and of the display. The
+//! overlay shows the latency of the last key press in the first row and the
+//! average over the last `NUM_SAMPLES` key presses in the second row, both in
+//! milliseconds.
+
+use std::{collections::VecDeque, time::Duration};
+
+use mahboi::SCREEN_WIDTH;
+
+
+/// The number of key presses the average is calculated over.
+const NUM_SAMPLES: usize = 32;
+
+/// The digits 0 to 9, each 3x5 pixels. Each byte is one row, the lowest three
+/// bits are the pixels from right to left.
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b001, 0b001, 0b001],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+/// The overlay is a box in the top left corner with this size.
+const BOX_WIDTH: usize = 15;
+const BOX_HEIGHT: usize = 16;
+
+const BACKGROUND_COLOR: [u8; 3] = [0x00, 0x00, 0x00];
+const TEXT_COLOR: [u8; 3] = [0xF8, 0xD0, 0x30];
+
+
+pub(crate) struct LatencyMeter {
+    /// The latencies of the last key presses, the most recent last.
+    samples: VecDeque<Duration>,
+}
+
+impl LatencyMeter {
+    pub(crate) fn new() -> Self {
+        Self {
+            samples: VecDeque::with_capacity(NUM_SAMPLES),
+        }
+    }
+
+    pub(crate) fn record(&mut self, latency: Duration) {
+        if self.samples.len() == NUM_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(latency);
+    }
+
+    pub(crate) fn last(&self) -> Option<Duration> {
+        self.samples.back().copied()
+    }
+
+    pub(crate) fn average(&self) -> Option<Duration> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        Some(self.samples.iter().sum::<Duration>() / self.samples.len() as u32)
+    }
+
+    /// Draws the overlay into the top left corner of `frame` (RGBA).
+    pub(crate) fn draw(&self, frame: &mut [u8]) {
+        for y in 0..BOX_HEIGHT {
+            for x in 0..BOX_WIDTH {
+                set_pixel(frame, x, y, BACKGROUND_COLOR);
+            }
+        }
+
+        for (row, latency) in [self.last(), self.average()].iter().enumerate() {
+            if let Some(latency) = latency {
+                draw_number(frame, 2, 2 + row * 7, latency.as_millis().min(999) as usize);
+            }
+        }
+    }
+}
+
+/// Draws `n` with its top left corner at `x` and `y`.
+fn draw_number(frame: &mut [u8], x: usize, y: usize, n: usize) {
+    let digits = n.to_string();
+    for (i, digit) in digits.bytes().enumerate() {
+        let glyph = DIGITS[(digit - b'0') as usize];
+        for (dy, row) in glyph.iter().enumerate() {
+            for dx in 0..3 {
+                if row & (0b100 >> dx) != 0 {
+                    set_pixel(frame, x + i * 4 + dx, y + dy, TEXT_COLOR);
+                }
+            }
+        }
+    }
+}
+
+fn set_pixel(frame: &mut [u8], x: usize, y: usize, color: [u8; 3]) {
+    let idx = (y * SCREEN_WIDTH + x) * 4;
+    frame[idx..idx + 3].copy_from_slice(&color);
+}
+
+
+#[cfg(test)]
+mod test {
+    use mahboi::SCREEN_HEIGHT;
+    use super::*;
+
+    #[test]
+    fn test_meter() {
+        let mut meter = LatencyMeter::new();
+        assert_eq!(meter.average(), None);
+
+        for ms in 0..NUM_SAMPLES as u64 + 10 {
+            meter.record(Duration::from_millis(ms));
+        }
+        assert_eq!(meter.last(), Some(Duration::from_millis(41)));
+        assert_eq!(meter.average(), Some(Duration::from_micros(25_500)));
+
+        // The last latency "41" is drawn in the first row.
+        let mut frame = vec![0xFF; SCREEN_WIDTH * SCREEN_HEIGHT * 4];
+        meter.draw(&mut frame);
+        let lit = |x: usize, y: usize| frame[(y * SCREEN_WIDTH + x) * 4..][..3] == TEXT_COLOR;
+        assert!(lit(2, 2) && !lit(3, 2) && lit(4, 2));
+        assert!(!lit(6, 2) && lit(7, 2));
+        assert_eq!(frame[BOX_HEIGHT * SCREEN_WIDTH * 4], 0xFF);
+    }
+}
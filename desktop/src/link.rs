@@ -0,0 +1,210 @@
+//! Local two player mode: two emulators side by side in one window, with
+//! their serial ports connected by a link cable (see `mahboi::link`).
+//!
+//! The left Game Boy is controlled as usual (WASD, J, K, N, M). The right one
+//! uses the arrow keys, '.' for A, ',' for B, right shift for Select and enter
+//! for Start. Sound is not played in this mode.
+
+use std::{fs, path::Path};
+
+use failure::{Error, ResultExt};
+use pixels::{Pixels, SurfaceTexture};
+use winit::{
+    dpi::PhysicalSize,
+    event::{Event, VirtualKeyCode},
+    event_loop::{ControlFlow, EventLoop},
+    window::WindowBuilder,
+};
+use winit_input_helper::WinitInputHelper;
+
+use mahboi::{
+    SCREEN_WIDTH, SCREEN_HEIGHT, Disruption, Emulator,
+    cartridge::Cartridge,
+    env::Peripherals,
+    link::execute_linked_frame,
+    log::*,
+    machine::input::{JoypadKey, Keys},
+    primitives::PixelColor,
+};
+use crate::{Outcome, WINDOW_TITLE, args::{Args, DEFAULT_SCALE}, timer::LoopTimer};
+
+
+/// The keys of both players: up, left, down, right, A, B, Select, Start.
+const KEY_MAPS: [[VirtualKeyCode; 8]; 2] = [
+    [
+        VirtualKeyCode::W,
+        VirtualKeyCode::A,
+        VirtualKeyCode::S,
+        VirtualKeyCode::D,
+        VirtualKeyCode::J,
+        VirtualKeyCode::K,
+        VirtualKeyCode::N,
+        VirtualKeyCode::M,
+    ],
+    [
+        VirtualKeyCode::Up,
+        VirtualKeyCode::Left,
+        VirtualKeyCode::Down,
+        VirtualKeyCode::Right,
+        VirtualKeyCode::Period,
+        VirtualKeyCode::Comma,
+        VirtualKeyCode::RShift,
+        VirtualKeyCode::Return,
+    ],
+];
+
+/// The screen and keys of one of the Game Boys.
+struct Player {
+    emulator: Emulator,
+    framebuffer: Vec<[u8; 3]>,
+    keys: Keys,
+}
+
+impl Player {
+    fn new(path: &Path, args: &Args) -> Result<Self, Error> {
+        let rom = fs::read(path).context("failed to load ROM file")?;
+        let cartridge = Cartridge::from_bytes(&rom).context("invalid ROM file")?;
+        info!("[desktop] Loaded: {:#?}", cartridge);
+
+        let mut emulator = Emulator::new(cartridge, args.bios, args.model);
+        emulator.set_input_polling(args.input_polling);
+
+        Ok(Self {
+            emulator,
+            framebuffer: vec![[0; 3]; SCREEN_WIDTH * SCREEN_HEIGHT],
+            keys: Keys::none(),
+        })
+    }
+
+    fn update_keys(&mut self, input: &WinitInputHelper, map: &[VirtualKeyCode; 8]) {
+        let keys = [
+            JoypadKey::Up,
+            JoypadKey::Left,
+            JoypadKey::Down,
+            JoypadKey::Right,
+            JoypadKey::A,
+            JoypadKey::B,
+            JoypadKey::Select,
+            JoypadKey::Start,
+        ];
+        self.keys = keys.iter().zip(map)
+            .fold(Keys::none(), |acc, (&key, &code)| acc.set_key(key, input.key_held(code)));
+    }
+}
+
+/// The peripherals of one player. The emulator is borrowed separately.
+struct Screen<'a> {
+    framebuffer: &'a mut [[u8; 3]],
+    keys: Keys,
+}
+
+impl Peripherals for Screen<'_> {
+    fn write_lcd_line(&mut self, line_idx: u8, pixels: &[PixelColor; SCREEN_WIDTH]) {
+        let start = line_idx as usize * SCREEN_WIDTH;
+        for (out, pixel) in self.framebuffer[start..start + SCREEN_WIDTH].iter_mut().zip(pixels) {
+            *out = pixel.to_srgb();
+        }
+    }
+
+    fn get_pressed_keys(&self) -> Keys {
+        self.keys
+    }
+
+    fn offer_sound_sample(&mut self, _: impl FnOnce(f32) -> f32) {}
+}
+
+/// Runs the ROM of `args` and the one at `other_rom` side by side until the
+/// window is closed.
+pub(crate) fn run(args: Args, other_rom: &Path) -> Result<(), Error> {
+    let mut players = [Player::new(&args.path_to_rom, &args)?, Player::new(other_rom, &args)?];
+
+    let event_loop = EventLoop::new();
+    let mut input = WinitInputHelper::new();
+    let window = {
+        let factor = args.scale.unwrap_or(DEFAULT_SCALE) as u32;
+        let initial_size = PhysicalSize::new(
+            2 * SCREEN_WIDTH as u32 * factor,
+            SCREEN_HEIGHT as u32 * factor,
+        );
+        WindowBuilder::new()
+            .with_title(WINDOW_TITLE)
+            .with_inner_size(initial_size)
+            .build(&event_loop)?
+    };
+    let mut pixels = {
+        let window_size = window.inner_size();
+        let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, &window);
+        let mut pixels =
+            Pixels::new(2 * SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32, surface_texture)?;
+        pixels.get_frame().fill(255);
+        pixels
+    };
+
+    let mut timer = LoopTimer::new(&args);
+    event_loop.run(move |event, _, control_flow| {
+        if let Event::RedrawRequested(_) = event {
+            if let Err(e) = pixels.render() {
+                eprintln!("pixels.render() failed: {}", e);
+                *control_flow = ControlFlow::Exit;
+                return;
+            }
+        }
+
+        if input.update(&event) {
+            if input.quit() || (input.key_pressed(VirtualKeyCode::Q) && input.held_control()) {
+                *control_flow = ControlFlow::Exit;
+                return;
+            }
+
+            timer.set_turbo_mode(input.key_held(VirtualKeyCode::Q));
+            if let Some(size) = input.window_resized() {
+                pixels.resize_surface(size.width, size.height);
+            }
+
+            for (player, map) in players.iter_mut().zip(&KEY_MAPS) {
+                player.update_keys(&input, map);
+            }
+
+            let outcome = timer.drive_emulation(|| emulate_frame(&mut players));
+            if outcome == Outcome::Terminate {
+                *control_flow = ControlFlow::Exit;
+                return;
+            }
+
+            // Copy both screens next to each other into the window buffer.
+            let frame = pixels.get_frame();
+            for (side, player) in players.iter().enumerate() {
+                for (i, color) in player.framebuffer.iter().enumerate() {
+                    let (x, y) = (i % SCREEN_WIDTH, i / SCREEN_WIDTH);
+                    let offset = 4 * (y * 2 * SCREEN_WIDTH + side * SCREEN_WIDTH + x);
+                    frame[offset..offset + 3].copy_from_slice(color);
+                }
+            }
+
+            if let Some(fps) = timer.report_fps() {
+                window.set_title(&format!("{} - {:.1} FPS", WINDOW_TITLE, fps));
+            }
+
+            window.request_redraw();
+        }
+    });
+}
+
+fn emulate_frame(players: &mut [Player; 2]) -> Outcome {
+    let [left, right] = players;
+    let mut left_screen = Screen { framebuffer: &mut left.framebuffer, keys: left.keys };
+    let mut right_screen = Screen { framebuffer: &mut right.framebuffer, keys: right.keys };
+    let res = execute_linked_frame(
+        (&mut left.emulator, &mut left_screen),
+        (&mut right.emulator, &mut right_screen),
+    );
+
+    match res {
+        Ok(()) => Outcome::Continue,
+        Err(Disruption::Paused) => Outcome::Continue,
+        Err(Disruption::Terminated) => {
+            warn!("[desktop] Emulator was terminated");
+            Outcome::Terminate
+        }
+    }
+}
@@ -1,35 +1,53 @@
-use std::{
-    fs,
-    panic::{self, AssertUnwindSafe},
-};
+use std::fs;
 
-use failure::{Error, ResultExt};
+use failure::{format_err, Error, ResultExt};
 use structopt::StructOpt;
 use winit::{
     dpi::PhysicalSize,
-    event::{Event, VirtualKeyCode},
-    event_loop::{ControlFlow, EventLoop},
-    window::WindowBuilder,
+    event::{ElementState, Event, VirtualKeyCode, WindowEvent},
+    event_loop::{ControlFlow, EventLoopBuilder},
+    window::{WindowBuilder, WindowId},
 };
 use winit_input_helper::WinitInputHelper;
 
 use mahboi::{
-    SCREEN_WIDTH, SCREEN_HEIGHT, Emulator, Disruption,
+    Emulator, SCREEN_WIDTH, SCREEN_HEIGHT,
     cartridge::Cartridge,
-    log::*,
 };
 use crate::{
-    args::Args,
-    debug::{Action, TuiDebugger, WindowBuffer},
-    env::Env,
-    timer::LoopTimer,
+    analyze::CodeMap,
+    args::{Args, DEFAULT_SCALE},
+    debug::{GuiWindow, Request},
+    emulation::{EmulationThread, Update},
+    latency::LatencyMeter,
+    symbols::Symbols,
+    window_geometry::Geometry,
 };
 
 
+mod achievements;
+mod analyze;
 mod args;
+mod battery;
+mod camera;
+mod crash_dump;
 mod debug;
+mod disasm;
+mod emulation;
 mod env;
+mod gfx;
+mod gdb;
+mod input_macro;
+mod latency;
+mod link;
+mod netplay;
+mod remote;
+mod state_file;
+mod state_manager;
+mod symbols;
 mod timer;
+mod trace_log;
+mod window_geometry;
 
 
 const WINDOW_TITLE: &str = "Mahboi";
@@ -52,66 +70,182 @@ fn run() -> Result<(), Error> {
     // Parse CLI arguments
     let args = Args::from_args();
 
-    // Initialize Debugger.
-    let mut is_paused = args.debug && !args.instant_start;
-    let mut debugger = {
-        // Initialize global logger.
-        debug::init_logger(&args);
-
-        // Create the TUI debugger if we're in debug mode.
-        if args.debug {
-            Some(TuiDebugger::new(&args)?)
-        } else {
-            None
+    // Only write the tile maps of a save state if requested.
+    if let Some(dir) = &args.export_maps {
+        let rom = fs::read(&args.path_to_rom).context("failed to load ROM file")?;
+        let cartridge = Cartridge::from_bytes(&rom).context("invalid ROM file")?;
+        let mut emulator = Emulator::new(cartridge, args.bios, args.model);
+        if let Some(path) = &args.load_state {
+            state_file::read_file(path, emulator.machine_mut())
+                .map_err(|e| format_err!("failed to load state '{}': {}", path.display(), e))?;
         }
-    };
+        gfx::export_maps(dir, emulator.machine(), args.map_viewport)?;
+        return Ok(());
+    }
 
-    // Load the ROM from disk and create the emulator.
-    let mut emulator = {
-        // Load ROM
+    if let Some(other) = &args.diff_state {
         let rom = fs::read(&args.path_to_rom).context("failed to load ROM file")?;
-        let cartridge = Cartridge::from_bytes(&rom);
-        info!("[desktop] Loaded: {:#?}", cartridge);
+        let cartridge = Cartridge::from_bytes(&rom).context("invalid ROM file")?;
+        let mut left = Emulator::new(cartridge, args.bios, args.model);
+        let mut right = left.fork();
+        let load = |emulator: &mut Emulator, path: &std::path::Path| {
+            state_file::read_file(path, emulator.machine_mut())
+                .map_err(|e| format_err!("failed to load state '{}': {}", path.display(), e))
+        };
+        load(&mut left, args.load_state.as_ref().unwrap())?;
+        load(&mut right, other)?;
+        print!("{}", left.machine().diff(right.machine()));
+        return Ok(());
+    }
 
-        // Create emulator
-        Emulator::new(cartridge, args.bios)
-    };
+    // Only compare with a reference log if requested.
+    if let Some(path) = &args.compare_log {
+        return trace_log::compare(&args, path);
+    }
+
+    // Only write the disassembly or tiles if requested.
+    let export = args.disassemble.is_some()
+        || args.export_project.is_some()
+        || args.export_tiles.is_some();
+    if export {
+        let rom = fs::read(&args.path_to_rom).context("failed to load ROM file")?;
+        let cartridge = Cartridge::from_bytes(&rom).context("invalid ROM file")?;
+        let mut symbols = Symbols::for_rom(&args)?;
+        let code_map = CodeMap::new(cartridge.rom());
+
+        if let Some(dir) = &args.export_project {
+            disasm::export_project(dir, cartridge.rom(), &symbols, &code_map)?;
+        }
+        if let Some(dir) = &args.export_tiles {
+            gfx::export_rom_tiles(dir, cartridge.rom(), &code_map)?;
+        }
+        if let Some(path) = &args.disassemble {
+            symbols.add_function_names(&code_map);
+            let range = args.disassemble_range;
+            disasm::export_to_file(path, cartridge.rom(), range, &symbols, &code_map)?;
+        }
+        return Ok(());
+    }
+
+    // Initialize global logger.
+    debug::init_logger(&args);
 
-    // Initialize the events loop, the window and the pixels buffer.
-    let event_loop = EventLoop::new();
+    // The two player mode has its own main loop.
+    if let Some(other_rom) = args.link.clone() {
+        return link::run(args, &other_rom);
+    }
+    if let Some(peer) = args.netplay.clone() {
+        return netplay::run(args, &peer);
+    }
+
+    // Start the emulator in its own thread.
+    let event_loop = EventLoopBuilder::with_user_event().build();
+    let scale = args.scale;
+    let gui_debug = args.gui_debug;
+    let mut latency = if args.latency_overlay { Some(LatencyMeter::new()) } else { None };
+    let mut emulation = EmulationThread::spawn(args, event_loop.create_proxy())?;
+
+    // Initialize the window and the pixels buffer.
     let mut input = WinitInputHelper::new();
     let window = {
-        let factor = args.scale as u32;
-        let initial_size = PhysicalSize::new(
-            SCREEN_WIDTH as u32 * factor,
-            SCREEN_HEIGHT as u32* factor,
-        );
-        WindowBuilder::new()
-            .with_title(WINDOW_TITLE)
-            .with_inner_size(initial_size)
-            .build(&event_loop)?
+        let builder = WindowBuilder::new().with_title(WINDOW_TITLE);
+        let builder = match (scale, Geometry::load()) {
+            (None, Some(geometry)) => geometry.apply(builder, &event_loop),
+            _ => {
+                let scale = scale.unwrap_or(DEFAULT_SCALE) as u32;
+                builder.with_inner_size(PhysicalSize::new(
+                    SCREEN_WIDTH as u32 * scale,
+                    SCREEN_HEIGHT as u32 * scale,
+                ))
+            }
+        };
+        builder.build(&event_loop)?
     };
+    let mut scale_factor = window.scale_factor();
+
+    let mut pixels = env::create_pixels(&window)?;
 
-    let mut env = Env::new(&args, &window)?;
+    // Open the graphical debugger if requested. Its events are handled
+    // before the events of the main window.
+    let mut gui = if gui_debug { Some(GuiWindow::new(&event_loop)?) } else { None };
+    let gui_id = gui.as_ref().map(GuiWindow::id);
+
+    // The time of the key press the next presented frame is the first
+    // reaction to.
+    let mut input_time = None;
 
     // ============================================================================================
     // ===== Main loop
     // ============================================================================================
-    // Setup loop timing.
-    let mut timer = LoopTimer::new(&args);
-
-    // Start everything and run until the window is closed.
+    // Render the frames of the emulation thread and forward keyboard events to
+    // it until the window is closed.
     event_loop.run(move |event, _, control_flow| {
-        // Draw the current frame.
-        if let Event::RedrawRequested(_) = event {
-            if let Err(e) = env.pixels.render() {
-                eprintln!("pixels.render() failed: {}", e);
+        control_flow.set_wait();
+
+        let mut event = match handle_gui_event(&mut gui, gui_id, event, &emulation) {
+            Some(event) => event,
+            None => return,
+        };
+
+        // When the window is moved to a monitor with a different scale
+        // factor, keep the screen at the same integer scale in logical
+        // pixels instead of the size the OS suggests.
+        if let Event::WindowEvent {
+            event: WindowEvent::ScaleFactorChanged { scale_factor: new, new_inner_size },
+            ..
+        } = &mut event {
+            **new_inner_size = window_geometry::rescale(window.inner_size(), scale_factor, *new);
+            pixels.resize_surface(new_inner_size.width, new_inner_size.height);
+            scale_factor = *new;
+        }
+
+        match &event {
+            Event::UserEvent(Update::Frame(frame, time)) => {
+                pixels.get_frame().copy_from_slice(frame);
+                if let Some(latency) = &latency {
+                    latency.draw(pixels.get_frame());
+                }
+                input_time = input_time.or(*time);
+                window.request_redraw();
+            }
+            Event::UserEvent(Update::Title(title)) => window.set_title(title),
+            Event::UserEvent(Update::Exit) => {
                 *control_flow = ControlFlow::Exit;
                 return;
             }
+            Event::WindowEvent { event: WindowEvent::KeyboardInput { input, .. }, .. } => {
+                if let Some(key) = input.virtual_keycode {
+                    emulation.send_key(key, input.state == ElementState::Pressed);
+                }
+            }
+
+            // Draw the current frame.
+            Event::RedrawRequested(_) => {
+                if let Err(e) = pixels.render() {
+                    eprintln!("pixels.render() failed: {}", e);
+                    *control_flow = ControlFlow::Exit;
+                    return;
+                }
+
+                emulation.send_presented();
+                if let (Some(latency), Some(time)) = (&mut latency, input_time.take()) {
+                    latency.record(time.elapsed());
+                }
+            }
+
+            // Wait until the emulation thread is done (e.g. writing the trace
+            // stream). If it panicked, we exit with the same code as a panic
+            // in the main thread would.
+            Event::LoopDestroyed => {
+                Geometry::of(&window).save();
+                if emulation.join().is_err() {
+                    std::process::exit(101);
+                }
+                return;
+            }
+            _ => {}
         }
 
-        // Application logic.
         if input.update(&event) {
             // Events to close the window.
             if input.quit() || (input.key_pressed(VirtualKeyCode::Q) && input.held_control()) {
@@ -119,60 +253,51 @@ fn run() -> Result<(), Error> {
                 return;
             }
 
-            // Handle other non-Gameboy input events.
-            timer.set_turbo_mode(input.key_held(VirtualKeyCode::Q));
             if let Some(size) = input.window_resized() {
-                env.pixels.resize_surface(size.width, size.height);
+                pixels.resize_surface(size.width, size.height);
             }
+        }
+    });
+}
 
-            // Run the emulator.
-            if !is_paused {
-                env.update_keys(&input);
-
-                // Actually emulate!
-                let outcome = timer.drive_emulation(|| {
-                    emulate_frame(&mut emulator, &mut env, debugger.as_mut())
-                });
-
-                match outcome {
-                    Outcome::Continue => {}
-                    Outcome::Pause => is_paused = true,
-                    Outcome::Terminate => {
-                        *control_flow = ControlFlow::Exit;
-                        return;
-                    }
-                }
+/// Passes the events of the debugger window and the snapshots for it to
+/// `gui`. Returns all other events.
+fn handle_gui_event<'a>(
+    gui: &mut Option<GuiWindow>,
+    gui_id: Option<WindowId>,
+    event: Event<'a, Update>,
+    emulation: &EmulationThread,
+) -> Option<Event<'a, Update>> {
+    match event {
+        // Snapshots may still arrive after the window was closed.
+        Event::UserEvent(Update::Debugger(snapshot)) => {
+            if let Some(window) = gui {
+                window.set_snapshot(snapshot);
             }
+        }
 
-            // If we're in debug mode (and have a TUI debugger), let's update it.
-            if let Some(debugger) = &mut debugger {
-                let action = debugger.update(
-                    is_paused,
-                    emulator.machine(),
-                    WindowBuffer(env.pixels.get_frame()),
-                );
-                match action {
-                    Action::Quit => {
-                        *control_flow = ControlFlow::Exit;
-                        return;
-                    }
-                    Action::Pause => is_paused = true,
-                    Action::Continue => {
-                        is_paused = false;
-                        timer.unpause();
-                    }
-                    Action::Nothing => {}
+        // Events of the closed window are ignored as well, as the main
+        // window would treat `Destroyed` as its own.
+        Event::WindowEvent { window_id, event } if Some(window_id) == gui_id => {
+            if let Some(window) = gui {
+                if !window.handle_event(&event) {
+                    emulation.send_request(Request::Detach);
+                    *gui = None;
                 }
             }
-
-            // Write FPS into window title
-            if let Some(fps) = timer.report_fps() {
-                window.set_title(&format!("{} - {:.1} FPS", WINDOW_TITLE, fps));
+        }
+        Event::RedrawRequested(window_id) if Some(window_id) == gui_id => {
+            if let Some(window) = gui {
+                match window.render() {
+                    Ok(requests) => requests.into_iter().for_each(|r| emulation.send_request(r)),
+                    Err(e) => eprintln!("failed to draw the debugger window: {}", e),
+                }
             }
-
-            window.request_redraw();
         }
-    });
+        event => return Some(event),
+    }
+
+    None
 }
 
 
@@ -182,57 +307,3 @@ enum Outcome {
     Pause,
     Terminate,
 }
-
-// Emulates one frame of the emulator and correctly handles the debugger and the
-// result of the emulation.
-fn emulate_frame(
-    emulator: &mut Emulator,
-    env: &mut Env,
-    mut debugger: Option<&mut TuiDebugger>,
-) -> Outcome {
-    let res = panic::catch_unwind(AssertUnwindSafe(|| {
-        emulator.execute_frame(env, |machine| {
-            // If we have a TUI debugger, we ask it when to pause.
-            // Otherwise, we never stop.
-            if let Some(debugger) = &mut debugger {
-                debugger.should_pause(machine)
-            } else {
-                false
-            }
-        })
-    }));
-
-    match res {
-        Err(e) => {
-            if let Some(s) = e.downcast_ref::<&str>() {
-                warn!("Emulator panicked: {}", s);
-            } else {
-                warn!("Emulator panicked!");
-            };
-
-            if debugger.is_none() {
-                panic::resume_unwind(e);
-            }
-
-            Outcome::Pause
-        }
-        Ok(disruption) => {
-            // React to abnormal disruptions
-            match disruption {
-                Ok(_) => Outcome::Continue,
-                Err(Disruption::Paused) => Outcome::Pause,
-                Err(Disruption::Terminated) => {
-                    // If we are not in debug mode, we stop the program, as it
-                    // doesn't make much sense to keep running. In debug mode,
-                    // we just pause execution.
-                    warn!("[desktop] Emulator was terminated");
-                    if debugger.is_some() {
-                        Outcome::Pause
-                    } else {
-                        Outcome::Terminate
-                    }
-                }
-            }
-        }
-    }
-}
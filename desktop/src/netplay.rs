@@ -0,0 +1,275 @@
+//! Playing link cable games over the network with rollback (see
+//! `mahboi::rollback`).
+//!
+//! Both sides run both Game Boys with the same ROM, but only show the one of
+//! the local player. The inputs are exchanged over UDP: every packet contains
+//! all local inputs the other side has not confirmed yet, so lost packets do
+//! not need to be detected.
+
+use std::{
+    convert::TryInto,
+    fs,
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+};
+
+use failure::{bail, Error, ResultExt};
+use winit::{
+    dpi::PhysicalSize,
+    event::{Event, VirtualKeyCode},
+    event_loop::{ControlFlow, EventLoop},
+    window::WindowBuilder,
+};
+use winit_input_helper::WinitInputHelper;
+
+use mahboi::{
+    SCREEN_WIDTH, SCREEN_HEIGHT, Disruption, Emulator,
+    cartridge::Cartridge,
+    env::Peripherals,
+    log::*,
+    machine::input::Keys,
+    rollback::Rollback,
+};
+use crate::{
+    Outcome, WINDOW_TITLE,
+    args::{Args, DEFAULT_SCALE},
+    env::{self, Env},
+    timer::LoopTimer,
+};
+
+
+/// Maximum number of frames executed with predicted remote input.
+const MAX_ROLLBACK: u64 = 12;
+
+/// Maximum number of inputs per packet.
+const MAX_INPUTS_PER_PACKET: usize = 128;
+
+/// A packet as sent over UDP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Packet {
+    /// Number of frames from the start for which the sender received the
+    /// input of the receiver.
+    ack: u64,
+
+    /// The frame of the first input.
+    start: u64,
+
+    /// The keys of the sender in consecutive frames.
+    inputs: Vec<Keys>,
+}
+
+impl Packet {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(16 + self.inputs.len());
+        out.extend_from_slice(&self.ack.to_be_bytes());
+        out.extend_from_slice(&self.start.to_be_bytes());
+        out.extend(self.inputs.iter().map(|keys| keys.bits()));
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 16 {
+            return None;
+        }
+
+        let (ack, rest) = bytes.split_at(8);
+        let (start, inputs) = rest.split_at(8);
+        Some(Self {
+            ack: u64::from_be_bytes(ack.try_into().unwrap()),
+            start: u64::from_be_bytes(start.try_into().unwrap()),
+            inputs: inputs.iter().map(|&b| Keys::from_bits(b)).collect(),
+        })
+    }
+}
+
+/// A rollback session with its connection to the other side.
+struct Netplay {
+    session: Rollback,
+    socket: UdpSocket,
+    peer: SocketAddr,
+
+    /// Number of frames from the start for which the other side confirmed
+    /// our input.
+    remote_ack: u64,
+}
+
+impl Netplay {
+    fn new(args: &Args, peer: SocketAddr, player: usize) -> Result<Self, Error> {
+        let rom = fs::read(&args.path_to_rom).context("failed to load ROM file")?;
+        let cartridge = Cartridge::from_bytes(&rom).context("invalid ROM file")?;
+        info!("[desktop] Loaded: {:#?}", cartridge);
+        let emulators = [(); 2].map(|_| {
+            let mut emulator = Emulator::new(cartridge.clone(), args.bios, args.model);
+            emulator.set_input_polling(args.input_polling);
+            emulator
+        });
+
+        let socket = UdpSocket::bind(("0.0.0.0", args.netplay_port))
+            .context("failed to bind netplay socket")?;
+        socket.set_nonblocking(true)?;
+        info!("[netplay] playing as player {} with {}", player + 1, peer);
+
+        Ok(Self {
+            session: Rollback::new(emulators, player, MAX_ROLLBACK),
+            socket,
+            peer,
+            remote_ack: 0,
+        })
+    }
+
+    /// Sends all local inputs not confirmed by the other side.
+    fn send(&self) {
+        let start = self.remote_ack;
+        let end = self.session.frame().min(start + MAX_INPUTS_PER_PACKET as u64);
+        let packet = Packet {
+            ack: self.session.confirmed_frames(),
+            start,
+            inputs: (start..end).filter_map(|f| self.session.local_input(f)).collect(),
+        };
+
+        if let Err(e) = self.socket.send_to(&packet.encode(), self.peer) {
+            // E.g. if the other side is not started yet.
+            debug!("[netplay] failed to send packet: {}", e);
+        }
+    }
+
+    /// Handles all received packets.
+    fn receive(&mut self) {
+        let mut buf = [0; 16 + MAX_INPUTS_PER_PACKET];
+        loop {
+            let packet = match self.socket.recv_from(&mut buf) {
+                Ok((len, from)) if from == self.peer => Packet::decode(&buf[..len]),
+                Ok((_, from)) => {
+                    warn!("[netplay] ignoring packet from unknown address {}", from);
+                    continue;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    debug!("[netplay] failed to receive packet: {}", e);
+                    continue;
+                }
+            };
+
+            let packet = match packet {
+                Some(packet) => packet,
+                None => continue,
+            };
+            // The other side can't confirm frames we didn't execute yet.
+            let ack = packet.ack.min(self.session.frame());
+            self.remote_ack = self.remote_ack.max(ack);
+            for (i, &keys) in packet.inputs.iter().enumerate() {
+                match packet.start.checked_add(i as u64) {
+                    Some(frame) => self.session.add_remote_input(frame, keys),
+                    None => break,
+                }
+            }
+        }
+    }
+
+    /// Exchanges inputs and executes the next frame, if the other side is
+    /// not too far behind.
+    fn emulate_frame(&mut self, env: &mut Env) -> Outcome {
+        self.receive();
+        env.begin_frame();
+        let keys = env.get_pressed_keys();
+
+        let outcome = match self.session.advance_frame(keys, env) {
+            Ok(true) => Outcome::Continue,
+            Ok(false) => {
+                debug!("[netplay] waiting for the other side");
+                Outcome::Continue
+            }
+            Err(Disruption::Paused) => Outcome::Continue,
+            Err(Disruption::Terminated) => {
+                warn!("[desktop] Emulator was terminated");
+                Outcome::Terminate
+            }
+        };
+
+        self.send();
+        outcome
+    }
+}
+
+/// Runs a netplay session with `peer` until the window is closed.
+pub(crate) fn run(args: Args, peer: &str) -> Result<(), Error> {
+    let peer = match peer.parse() {
+        Ok(peer) => peer,
+        Err(_) => bail!("invalid netplay address '{}' (expected 'ip:port')", peer),
+    };
+    let player = args.netplay_player as usize - 1;
+    let mut netplay = Netplay::new(&args, peer, player)?;
+
+    let event_loop = EventLoop::new();
+    let mut input = WinitInputHelper::new();
+    let window = {
+        let factor = args.scale.unwrap_or(DEFAULT_SCALE) as u32;
+        let initial_size = PhysicalSize::new(
+            SCREEN_WIDTH as u32 * factor,
+            SCREEN_HEIGHT as u32 * factor,
+        );
+        WindowBuilder::new()
+            .with_title(WINDOW_TITLE)
+            .with_inner_size(initial_size)
+            .build(&event_loop)?
+    };
+    let mut pixels = env::create_pixels(&window)?;
+    let mut env = Env::new(&args)?;
+
+    let mut timer = LoopTimer::new(&args);
+    event_loop.run(move |event, _, control_flow| {
+        if let Event::RedrawRequested(_) = event {
+            pixels.get_frame().copy_from_slice(&env.frame);
+            if let Err(e) = pixels.render() {
+                eprintln!("pixels.render() failed: {}", e);
+                *control_flow = ControlFlow::Exit;
+                return;
+            }
+        }
+
+        if input.update(&event) {
+            if input.quit() || (input.key_pressed(VirtualKeyCode::Q) && input.held_control()) {
+                *control_flow = ControlFlow::Exit;
+                return;
+            }
+
+            if let Some(size) = input.window_resized() {
+                pixels.resize_surface(size.width, size.height);
+            }
+            env.update_keys(|key| input.key_held(key));
+
+            // Turbo mode is not available, as the other side could not keep up.
+            let outcome = timer.drive_emulation(|| netplay.emulate_frame(&mut env));
+            if outcome == Outcome::Terminate {
+                *control_flow = ControlFlow::Exit;
+                return;
+            }
+
+            if let Some(fps) = timer.report_fps() {
+                window.set_title(&format!("{} - {:.1} FPS", WINDOW_TITLE, fps));
+            }
+
+            window.request_redraw();
+        }
+    });
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_packet() {
+        let packet = Packet {
+            ack: 3,
+            start: 0x1234,
+            inputs: vec![Keys::none(), Keys::from_bits(0x81)],
+        };
+        let bytes = packet.encode();
+        assert_eq!(bytes.len(), 18);
+        assert_eq!(bytes[16..], [0x00, 0x81]);
+        assert_eq!(Packet::decode(&bytes), Some(packet));
+        assert_eq!(Packet::decode(&bytes[..10]), None);
+    }
+}
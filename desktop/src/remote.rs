@@ -0,0 +1,316 @@
+//! A small HTTP server to control the running emulator from other programs,
+//! e.g. stream overlays, CI scripts or bots.
+//!
+//! Only one request per connection is handled. All addresses and values are
+//! hexadecimal. The server understands:
+//!
+//! - `GET /memory?addr=c000&len=10`: reads memory, as hex bytes separated by
+//!   spaces.
+//! - `POST /memory?addr=c000&value=3f`: writes one byte.
+//! - `POST /keys?press=a,start&release=b`: presses or releases keys until
+//!   they are changed again.
+//! - `POST /state/save?slot=name` and `POST /state/load?slot=name`: saves or
+//!   loads a state (in memory).
+//! - `GET /screenshot`: the current screen as PNG.
+//! - `POST /pause` and `POST /continue`.
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    time::Duration,
+};
+
+use failure::{Error, ResultExt};
+
+use mahboi::{
+    SCREEN_WIDTH, SCREEN_HEIGHT,
+    log::*,
+    machine::{Machine, input::JoypadKey},
+    primitives::{Byte, Word},
+};
+use crate::debug::Action;
+
+
+/// How long we wait for the request of a client.
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Maximum size of a request head.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+/// Listens for HTTP requests.
+pub(crate) struct RemoteServer {
+    listener: TcpListener,
+
+    /// The states saved by `/state/save`, by slot name.
+    slots: HashMap<String, Machine>,
+}
+
+#[derive(Debug)]
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn ok(body: impl Into<String>) -> Self {
+        Self { status: "200 OK", content_type: "text/plain", body: body.into().into_bytes() }
+    }
+
+    fn error(status: &'static str, msg: impl Into<String>) -> Self {
+        Self { status, content_type: "text/plain", body: msg.into().into_bytes() }
+    }
+}
+
+impl RemoteServer {
+    pub(crate) fn new(port: u16) -> Result<Self, Error> {
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .context("failed to start remote control server")?;
+        listener.set_nonblocking(true)?;
+        info!("[remote] listening on port {}", port);
+
+        Ok(Self {
+            listener,
+            slots: HashMap::new(),
+        })
+    }
+
+    /// Handles all pending requests. Should be called regularly. `frame` is
+    /// the RGBA buffer of the screen. `before_load` is called right before a
+    /// state is loaded into `machine`.
+    ///
+    /// Returns a requested action.
+    pub(crate) fn update(
+        &mut self,
+        machine: &mut Machine,
+        frame: &[u8],
+        mut before_load: impl FnMut(&Machine),
+    ) -> Action {
+        let mut action = Action::Nothing;
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("[remote] failed to accept connection: {}", e);
+                    break;
+                }
+            };
+
+            if let Err(e) = self.serve(stream, machine, frame, &mut action, &mut before_load) {
+                warn!("[remote] failed to handle request: {}", e);
+            }
+        }
+
+        action
+    }
+
+    /// Reads one request from `stream` and writes the response.
+    fn serve(
+        &mut self,
+        mut stream: TcpStream,
+        machine: &mut Machine,
+        frame: &[u8],
+        action: &mut Action,
+        before_load: &mut dyn FnMut(&Machine),
+    ) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+
+        // We only need the request line, but read the whole head to not
+        // reset the connection while the client is still sending.
+        let mut head = Vec::new();
+        let mut buf = [0; 1024];
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_REQUEST_LEN {
+            match stream.read(&mut buf)? {
+                0 => break,
+                n => head.extend_from_slice(&buf[..n]),
+            }
+        }
+
+        let head = String::from_utf8_lossy(&head);
+        let request_line = head.lines().next().unwrap_or("");
+        let mut parts = request_line.split(' ');
+        let response = match (parts.next(), parts.next()) {
+            (Some(method), Some(target)) => {
+                self.handle(method, target, machine, frame, action, before_load)
+            }
+            _ => Response::error("400 Bad Request", "invalid request"),
+        };
+
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            response.status,
+            response.content_type,
+            response.body.len(),
+        )?;
+        stream.write_all(&response.body)
+    }
+
+    /// Executes the request and returns the response.
+    fn handle(
+        &mut self,
+        method: &str,
+        target: &str,
+        machine: &mut Machine,
+        frame: &[u8],
+        action: &mut Action,
+        before_load: &mut dyn FnMut(&Machine),
+    ) -> Response {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let params = query.split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .collect::<HashMap<_, _>>();
+        let hex = |name: &str| -> Result<u16, Response> {
+            let value = params.get(name)
+                .ok_or_else(|| Response::error("400 Bad Request", format!("missing '{}'", name)))?;
+            u16::from_str_radix(value, 16).map_err(|_| {
+                Response::error("400 Bad Request", format!("invalid hex number '{}'", value))
+            })
+        };
+
+        let result = match (method, path) {
+            ("GET", "/memory") => hex("addr").and_then(|addr| {
+                let len = if params.contains_key("len") { hex("len")? } else { 1 };
+                let bytes = (0..len)
+                    .map(|i| machine.load_byte(Word::new(addr.wrapping_add(i))).get())
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<Vec<_>>();
+                Ok(Response::ok(bytes.join(" ")))
+            }),
+            ("POST", "/memory") => hex("addr").and_then(|addr| {
+                let value = hex("value")?;
+                machine.store_byte(Word::new(addr), Byte::new(value as u8));
+                Ok(Response::ok(""))
+            }),
+            ("POST", "/keys") => {
+                let mut changes = Vec::new();
+                for &(name, pressed) in &[("press", true), ("release", false)] {
+                    for key in params.get(name).into_iter().flat_map(|v| v.split(',')) {
+                        match parse_key(key) {
+                            Some(key) => changes.push((key, pressed)),
+                            None => return Response::error(
+                                "400 Bad Request",
+                                format!("unknown key '{}'", key),
+                            ),
+                        }
+                    }
+                }
+                for (key, pressed) in changes {
+                    machine.set_key(key, pressed);
+                }
+                Ok(Response::ok(""))
+            }
+            ("POST", "/state/save") => {
+                let slot = params.get("slot").copied().unwrap_or("default");
+                self.slots.insert(slot.to_string(), machine.clone());
+                Ok(Response::ok(""))
+            }
+            ("POST", "/state/load") => {
+                let slot = params.get("slot").copied().unwrap_or("default");
+                match self.slots.get(slot) {
+                    Some(state) => {
+                        before_load(machine);
+                        machine.restore(state);
+                        Ok(Response::ok(""))
+                    }
+                    None => Err(Response::error(
+                        "404 Not Found",
+                        format!("no state in slot '{}'", slot),
+                    )),
+                }
+            }
+            ("GET", "/screenshot") => screenshot(frame).map_err(|e| {
+                Response::error("500 Internal Server Error", e.to_string())
+            }),
+            ("POST", "/pause") => {
+                *action = Action::Pause;
+                Ok(Response::ok(""))
+            }
+            ("POST", "/continue") => {
+                *action = Action::Continue;
+                Ok(Response::ok(""))
+            }
+            _ => Err(Response::error("404 Not Found", format!("unknown command {}", path))),
+        };
+
+        result.unwrap_or_else(|e| e)
+    }
+}
+
+fn parse_key(name: &str) -> Option<JoypadKey> {
+    match name.to_ascii_lowercase().as_str() {
+        "a" => Some(JoypadKey::A),
+        "b" => Some(JoypadKey::B),
+        "select" => Some(JoypadKey::Select),
+        "start" => Some(JoypadKey::Start),
+        "right" => Some(JoypadKey::Right),
+        "left" => Some(JoypadKey::Left),
+        "up" => Some(JoypadKey::Up),
+        "down" => Some(JoypadKey::Down),
+        _ => None,
+    }
+}
+
+/// Encodes the RGBA `frame` as PNG.
+fn screenshot(frame: &[u8]) -> Result<Response, png::EncodingError> {
+    let mut body = Vec::new();
+    let mut encoder = png::Encoder::new(&mut body, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(frame)?;
+
+    Ok(Response { status: "200 OK", content_type: "image/png", body })
+}
+
+
+#[cfg(test)]
+mod test {
+    use mahboi::{BiosKind, Emulator, HardwareModel, cartridge::Cartridge};
+    use super::*;
+
+    #[test]
+    fn test_commands() {
+        let cartridge = Cartridge::from_bytes(&[0; 0x8000]).unwrap();
+        let mut emulator = Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        let machine = emulator.machine_mut();
+        let frame = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 4];
+        let mut server = RemoteServer::new(0).unwrap();
+        let mut action = Action::Nothing;
+        let mut loads = 0;
+        let mut request = |method, target, machine: &mut Machine| {
+            let response = server.handle(
+                method,
+                target,
+                machine,
+                &frame,
+                &mut action,
+                &mut |_| loads += 1,
+            );
+            (response.status, String::from_utf8_lossy(&response.body).into_owned())
+        };
+
+        request("POST", "/memory?addr=c000&value=3f", machine);
+        request("POST", "/memory?addr=c001&value=40", machine);
+        assert_eq!(request("GET", "/memory?addr=c000&len=2", machine).1, "3f 40");
+
+        request("POST", "/state/save?slot=x", machine);
+        request("POST", "/memory?addr=c000&value=00", machine);
+        assert_eq!(request("POST", "/state/load?slot=x", machine).0, "200 OK");
+        assert_eq!(request("GET", "/memory?addr=c000", machine).1, "3f");
+        assert_eq!(request("POST", "/state/load?slot=y", machine).0, "404 Not Found");
+
+        assert_eq!(request("POST", "/keys?press=a,Start", machine).0, "200 OK");
+        assert_eq!(request("POST", "/keys?press=x", machine).0, "400 Bad Request");
+        assert_eq!(request("GET", "/memory?addr=zz", machine).0, "400 Bad Request");
+        assert_eq!(request("GET", "/foo", machine).0, "404 Not Found");
+
+        let (status, _) = request("GET", "/screenshot", machine);
+        assert_eq!(status, "200 OK");
+        request("POST", "/pause", machine);
+        assert!(matches!(action, Action::Pause));
+        assert_eq!(loads, 1);
+    }
+}
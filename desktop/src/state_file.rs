@@ -0,0 +1,153 @@
+//! Saving and loading the state of the emulator to a file (see
+//! `mahboi::save_state`). There are `NUM_SLOTS` states per ROM, stored
+//! compressed in the config directory (e.g. `~/.config/mahboi/states`). Each
+//! state contains a thumbnail of the screen, which is shown by the state
+//! manager. States can also be exported to BESS files for other emulators.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use mahboi::{
+    SCREEN_WIDTH, SCREEN_HEIGHT,
+    log::*,
+    machine::Machine,
+    save_state::{self, Compression, Metadata, StateInfo, Thumbnail, MAGIC},
+};
+use crate::input_macro::config_dir;
+
+
+/// The number of save state slots per ROM.
+pub(crate) const NUM_SLOTS: usize = 4;
+
+/// Thumbnails are the screen downscaled by this factor.
+pub(crate) const THUMBNAIL_FACTOR: usize = 2;
+
+/// Returns the path of the file in the state directory. The first slot uses
+/// the name `<rom>.state`, the others `<rom>.<slot>.state`.
+fn path(rom: &Path, slot: usize, extension: &str) -> Option<PathBuf> {
+    let stem = rom.file_stem()?.to_string_lossy();
+    let name = match slot {
+        0 => format!("{}.{}", stem, extension),
+        _ => format!("{}.{}.{}", stem, slot, extension),
+    };
+    Some(config_dir()?.join("mahboi").join("states").join(name))
+}
+
+/// Saves the state of `machine` to the given slot of the ROM. `frame` is the
+/// current screen (RGBA), which is stored as thumbnail.
+pub(crate) fn save(rom: &Path, slot: usize, machine: &Machine, frame: &[u8]) {
+    let path = match path(rom, slot, "state") {
+        Some(path) => path,
+        None => {
+            warn!("[desktop] cannot save state: no config directory");
+            return;
+        }
+    };
+
+    let metadata = Metadata {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        thumbnail: Some(thumbnail(frame)),
+    };
+    let state = machine.save_state_with_metadata(Compression::Zstd, &metadata);
+    let result = fs::create_dir_all(path.parent().unwrap())
+        .and_then(|_| fs::write(&path, state));
+    match result {
+        Ok(()) => info!("[desktop] saved state to '{}'", path.display()),
+        Err(e) => warn!("[desktop] failed to write state '{}': {}", path.display(), e),
+    }
+}
+
+/// Loads the state in the given slot of the ROM into `machine`, if one was
+/// saved.
+pub(crate) fn load(rom: &Path, slot: usize, machine: &mut Machine) {
+    if let Some(path) = path(rom, slot, "state") {
+        load_file(&path, machine);
+    }
+}
+
+/// Returns information about the state in the given slot of the ROM, or
+/// `None` if the slot is empty or the state cannot be read.
+pub(crate) fn info(rom: &Path, slot: usize) -> Option<StateInfo> {
+    let path = path(rom, slot, "state")?;
+    let data = fs::read(&path).ok()?;
+    match save_state::read_info(&data) {
+        Ok(info) => Some(info),
+        Err(e) => {
+            warn!("[desktop] failed to read state '{}': {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Exports the state of `machine` as BESS file next to the states of the
+/// given ROM.
+pub(crate) fn export_bess(rom: &Path, machine: &Machine) {
+    let path = match path(rom, 0, "bess") {
+        Some(path) => path,
+        None => {
+            warn!("[desktop] cannot export state: no config directory");
+            return;
+        }
+    };
+
+    let result = fs::create_dir_all(path.parent().unwrap())
+        .and_then(|_| fs::write(&path, machine.export_bess()));
+    match result {
+        Ok(()) => info!("[desktop] exported state to '{}'", path.display()),
+        Err(e) => warn!("[desktop] failed to write state '{}': {}", path.display(), e),
+    }
+}
+
+/// Loads the state in the given file into `machine`. The file can either be a
+/// state saved by `save` or a BESS file.
+pub(crate) fn load_file(path: &Path, machine: &mut Machine) {
+    match read_file(path, machine) {
+        Ok(()) => info!("[desktop] loaded state from '{}'", path.display()),
+        Err(e) => warn!("[desktop] failed to load state '{}': {}", path.display(), e),
+    }
+}
+
+/// Like `load_file`, but returns errors instead of logging them.
+pub(crate) fn read_file(path: &Path, machine: &mut Machine) -> Result<(), String> {
+    let data = fs::read(path).map_err(|e| e.to_string())?;
+    let result = if data.starts_with(MAGIC) {
+        machine.load_state(&data)
+    } else {
+        machine.import_bess(&data)
+    };
+    result.map_err(|e| e.to_string())
+}
+
+/// Downscales the screen (RGBA) by `THUMBNAIL_FACTOR`, averaging the pixels.
+fn thumbnail(frame: &[u8]) -> Thumbnail {
+    let width = SCREEN_WIDTH / THUMBNAIL_FACTOR;
+    let height = SCREEN_HEIGHT / THUMBNAIL_FACTOR;
+    let mut pixels = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            for channel in 0..3 {
+                let mut sum = 0;
+                for dy in 0..THUMBNAIL_FACTOR {
+                    for dx in 0..THUMBNAIL_FACTOR {
+                        let sx = x * THUMBNAIL_FACTOR + dx;
+                        let sy = y * THUMBNAIL_FACTOR + dy;
+                        sum += frame[(sy * SCREEN_WIDTH + sx) * 4 + channel] as usize;
+                    }
+                }
+                pixels.push((sum / (THUMBNAIL_FACTOR * THUMBNAIL_FACTOR)) as u8);
+            }
+        }
+    }
+
+    Thumbnail {
+        width: width as u16,
+        height: height as u16,
+        pixels,
+    }
+}
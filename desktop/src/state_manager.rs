@@ -0,0 +1,236 @@
+//! The state manager: an overlay showing the thumbnails of all save state
+//! slots of the ROM, to pick the right state visually. It is opened with
+//! 'F10'. The arrow keys select a slot, enter loads it, space saves the
+//! current state into it and escape closes the overlay. Details about the
+//! selected slot (when it was saved and the play time) are shown in the
+//! window title.
+//!
+//! The selected slot is also used by quick save and load ('F7' and 'F8').
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use winit::event::VirtualKeyCode;
+
+use mahboi::{
+    SCREEN_WIDTH, SCREEN_HEIGHT,
+    machine::Machine,
+    save_state::StateInfo,
+};
+use crate::{
+    emulation::Input,
+    state_file::{self, NUM_SLOTS, THUMBNAIL_FACTOR},
+};
+
+
+/// The slots are shown in a grid with this many columns.
+const COLUMNS: usize = 2;
+
+const CELL_WIDTH: usize = SCREEN_WIDTH / THUMBNAIL_FACTOR;
+const CELL_HEIGHT: usize = SCREEN_HEIGHT / THUMBNAIL_FACTOR;
+
+const BORDER_COLOR: [u8; 3] = [0xF8, 0xD0, 0x30];
+const EMPTY_COLOR: [u8; 3] = [0x30, 0x30, 0x30];
+
+
+pub(crate) struct StateManager {
+    rom: PathBuf,
+
+    /// The selected slot.
+    slot: usize,
+
+    /// The state of the overlay, if it is open.
+    overlay: Option<Overlay>,
+}
+
+struct Overlay {
+    /// Information about each slot, `None` for empty slots.
+    slots: Vec<Option<StateInfo>>,
+
+    /// The screen (RGBA) before the overlay was drawn over it. It is restored
+    /// when the overlay is closed and used as thumbnail when saving.
+    screen: Vec<u8>,
+}
+
+impl StateManager {
+    pub(crate) fn new(rom: &Path) -> Self {
+        Self {
+            rom: rom.to_owned(),
+            slot: 0,
+            overlay: None,
+        }
+    }
+
+    pub(crate) fn is_open(&self) -> bool {
+        self.overlay.is_some()
+    }
+
+    /// Opens the overlay and draws it into `frame`.
+    pub(crate) fn open(&mut self, frame: &mut [u8]) {
+        self.overlay = Some(Overlay {
+            slots: (0..NUM_SLOTS).map(|slot| state_file::info(&self.rom, slot)).collect(),
+            screen: frame.to_vec(),
+        });
+        self.draw(frame);
+    }
+
+    /// Closes the overlay and restores the screen.
+    pub(crate) fn close(&mut self, frame: &mut [u8]) {
+        if let Some(overlay) = self.overlay.take() {
+            frame.copy_from_slice(&overlay.screen);
+        }
+    }
+
+    /// Saves the state into the selected slot.
+    pub(crate) fn quick_save(&mut self, machine: &Machine, frame: &[u8]) {
+        let screen = self.overlay.as_ref().map(|o| &o.screen[..]).unwrap_or(frame);
+        state_file::save(&self.rom, self.slot, machine, screen);
+    }
+
+    /// Loads the state in the selected slot.
+    pub(crate) fn quick_load(&mut self, machine: &mut Machine) {
+        state_file::load(&self.rom, self.slot, machine);
+    }
+
+    /// Handles the input while the overlay is open. Returns `true` if the
+    /// overlay was closed.
+    pub(crate) fn update(
+        &mut self,
+        input: &Input,
+        machine: &mut Machine,
+        frame: &mut [u8],
+    ) -> bool {
+        let rows = NUM_SLOTS.div_ceil(COLUMNS);
+        let (mut col, mut row) = (self.slot % COLUMNS, self.slot / COLUMNS);
+        if input.key_pressed(VirtualKeyCode::Left) {
+            col = (col + COLUMNS - 1) % COLUMNS;
+        }
+        if input.key_pressed(VirtualKeyCode::Right) {
+            col = (col + 1) % COLUMNS;
+        }
+        if input.key_pressed(VirtualKeyCode::Up) {
+            row = (row + rows - 1) % rows;
+        }
+        if input.key_pressed(VirtualKeyCode::Down) {
+            row = (row + 1) % rows;
+        }
+        self.slot = (row * COLUMNS + col).min(NUM_SLOTS - 1);
+
+        if input.key_pressed(VirtualKeyCode::Space) {
+            self.quick_save(machine, frame);
+            if let Some(overlay) = &mut self.overlay {
+                overlay.slots[self.slot] = state_file::info(&self.rom, self.slot);
+            }
+        }
+
+        let has_state = self.overlay.as_ref().is_some_and(|o| o.slots[self.slot].is_some());
+        if input.key_pressed(VirtualKeyCode::Return) && has_state {
+            self.quick_load(machine);
+            self.close(frame);
+            return true;
+        }
+        if input.key_pressed(VirtualKeyCode::Escape) {
+            self.close(frame);
+            return true;
+        }
+
+        self.draw(frame);
+        false
+    }
+
+    /// Returns a description of the selected slot for the window title.
+    pub(crate) fn description(&self) -> String {
+        let info = self.overlay.as_ref().and_then(|o| o.slots[self.slot].as_ref());
+        let info = match info {
+            Some(info) => info,
+            None => return format!("Slot {} (empty)", self.slot + 1),
+        };
+
+        let saved = match info.metadata.timestamp {
+            0 => "unknown".into(),
+            timestamp => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                format_age(now.as_secs().saturating_sub(timestamp))
+            }
+        };
+        format!(
+            "Slot {} - saved {} - play time {}",
+            self.slot + 1,
+            saved,
+            format_play_time(info.play_time),
+        )
+    }
+
+    /// Draws the thumbnails of all slots into `frame`. The selected slot is
+    /// framed, the others are dimmed.
+    fn draw(&self, frame: &mut [u8]) {
+        let overlay = match &self.overlay {
+            Some(overlay) => overlay,
+            None => return,
+        };
+
+        for (slot, info) in overlay.slots.iter().enumerate() {
+            let x0 = (slot % COLUMNS) * CELL_WIDTH;
+            let y0 = (slot / COLUMNS) * CELL_HEIGHT;
+            let thumbnail = info.as_ref()
+                .and_then(|info| info.metadata.thumbnail.as_ref())
+                .filter(|t| t.width as usize == CELL_WIDTH && t.height as usize == CELL_HEIGHT);
+
+            for y in 0..CELL_HEIGHT {
+                for x in 0..CELL_WIDTH {
+                    let on_border = x < 2 || y < 2 || x >= CELL_WIDTH - 2 || y >= CELL_HEIGHT - 2;
+                    let color = if slot == self.slot && on_border {
+                        BORDER_COLOR
+                    } else if let Some(thumbnail) = thumbnail {
+                        let idx = (y * CELL_WIDTH + x) * 3;
+                        let mut color = [0; 3];
+                        color.copy_from_slice(&thumbnail.pixels[idx..idx + 3]);
+                        if slot != self.slot {
+                            color.iter_mut().for_each(|c| *c /= 2);
+                        }
+                        color
+                    } else {
+                        EMPTY_COLOR
+                    };
+
+                    let idx = ((y0 + y) * SCREEN_WIDTH + x0 + x) * 4;
+                    frame[idx..idx + 3].copy_from_slice(&color);
+                    frame[idx + 3] = 0xFF;
+                }
+            }
+        }
+    }
+}
+
+/// Formats the age of a state, e.g. "5 min ago".
+fn format_age(secs: u64) -> String {
+    match secs {
+        0..=59 => "just now".into(),
+        60..=3599 => format!("{} min ago", secs / 60),
+        3600..=86399 => format!("{} h ago", secs / 3600),
+        _ => format!("{} days ago", secs / 86400),
+    }
+}
+
+/// Formats the play time as `h:mm:ss`.
+fn format_play_time(time: Duration) -> String {
+    let secs = time.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format_age(12), "just now");
+        assert_eq!(format_age(125), "2 min ago");
+        assert_eq!(format_age(7300), "2 h ago");
+        assert_eq!(format_age(3 * 86400 + 5), "3 days ago");
+        assert_eq!(format_play_time(Duration::from_secs(3 * 3600 + 7 * 60 + 9)), "3:07:09");
+    }
+}
@@ -0,0 +1,216 @@
+//! Symbol files (`.sym`) as produced by RGBDS (`rgblink -n`).
+//!
+//! Each line has the form `BB:AAAA Name` where `BB` is the bank and `AAAA`
+//! the address (both hexadecimal). Everything after a `;` is a comment.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::Path,
+};
+
+use failure::{Error, ResultExt, bail};
+
+use mahboi::{
+    log::*,
+    primitives::Word,
+};
+use crate::{
+    analyze::{CodeMap, RomAddr},
+    args::Args,
+};
+
+
+/// All symbols loaded from a symbol file. Might be empty.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Symbols {
+    /// All symbols at an address, with the bank they are in.
+    by_addr: BTreeMap<Word, Vec<(usize, String)>>,
+
+    by_name: HashMap<String, (usize, Word)>,
+}
+
+impl Symbols {
+    /// Loads the symbol file at the given path.
+    pub(crate) fn load(path: &Path) -> Result<Self, Error> {
+        let src = fs::read_to_string(path)
+            .context(format!("failed to read symbol file '{}'", path.display()))?;
+        let out = Self::parse(&src)
+            .context(format!("invalid symbol file '{}'", path.display()))?;
+
+        Ok(out)
+    }
+
+    /// Loads the symbol file specified with `--sym` or, if there is none, the
+    /// one next to the ROM (if it exists). Errors in the latter are only
+    /// logged.
+    pub(crate) fn for_rom(args: &Args) -> Result<Self, Error> {
+        if let Some(path) = &args.sym {
+            return Self::load(path);
+        }
+
+        let path = args.path_to_rom.with_extension("sym");
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        Ok(Self::load(&path).unwrap_or_else(|e| {
+            warn!("[desktop] ignoring symbol file: {}", e);
+            Self::default()
+        }))
+    }
+
+    /// Parses the contents of a symbol file.
+    pub(crate) fn parse(src: &str) -> Result<Self, Error> {
+        let mut out = Self::default();
+
+        for (i, line) in src.lines().enumerate() {
+            let line = line.split(';').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let parsed = line.split_once(' ').and_then(|(location, name)| {
+                let (bank, addr) = location.split_once(':')?;
+                let bank = usize::from_str_radix(bank, 16).ok()?;
+                let addr = u16::from_str_radix(addr, 16).ok()?;
+                Some((bank, Word::new(addr), name.trim()))
+            });
+
+            let (bank, addr, name) = match parsed {
+                Some(v) => v,
+                None => bail!("line {} is not of the form `BB:AAAA Name`: '{}'", i + 1, line),
+            };
+
+            out.add(bank, addr, name);
+        }
+
+        Ok(out)
+    }
+
+    fn add(&mut self, bank: usize, addr: Word, name: &str) {
+        self.by_addr.entry(addr).or_default().push((bank, name.to_string()));
+        self.by_name.insert(name.to_string(), (bank, addr));
+    }
+
+    /// Adds the generated names (e.g. `fn_0C_4A30`) of all functions found by
+    /// the analysis that don't have a symbol yet. Returns the number of added
+    /// names.
+    pub(crate) fn add_function_names(&mut self, code_map: &CodeMap) -> usize {
+        code_map.functions()
+            .iter()
+            .filter(|(&entry, f)| self.add_missing(entry, &f.name))
+            .count()
+    }
+
+    /// Adds labels like `jr_01_4A35` for all jump targets found by the
+    /// analysis that don't have a symbol yet.
+    pub(crate) fn add_jump_labels(&mut self, code_map: &CodeMap) {
+        for target in code_map.jump_targets() {
+            self.add_missing(target, &format!("jr_{:02X}_{:04X}", target.bank, target.addr.get()));
+        }
+    }
+
+    /// Adds the symbol if there is none at `addr` yet. Returns whether it was
+    /// added.
+    fn add_missing(&mut self, addr: RomAddr, name: &str) -> bool {
+        let missing = self.name_at(addr.addr, addr.bank).is_none();
+        if missing {
+            self.add(addr.bank, addr.addr, name);
+        }
+
+        missing
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// Returns the name of the symbol at `addr`. For addresses in the
+    /// switchable ROM area, only symbols in `rom_bank` are considered.
+    pub(crate) fn name_at(&self, addr: Word, rom_bank: usize) -> Option<&str> {
+        let candidates = self.by_addr.get(&addr)?;
+        let symbol = if (0x4000..0x8000).contains(&addr.get()) {
+            candidates.iter().find(|(bank, _)| *bank == rom_bank)
+        } else {
+            candidates.first()
+        };
+
+        symbol.map(|(_, name)| &**name)
+    }
+
+    /// Returns the symbol at or before `addr` (in the same memory area) and
+    /// the offset of `addr` from it. In the switchable ROM area, only symbols
+    /// in `rom_bank` are considered.
+    pub(crate) fn nearest(&self, addr: Word, rom_bank: usize) -> Option<(&str, u16)> {
+        // 0: fixed ROM bank, 1: switchable ROM bank, 2: everything else
+        let area = |a: Word| (a.get() >= 0x4000) as u8 + (a.get() >= 0x8000) as u8;
+        self.by_addr.range(..=addr)
+            .rev()
+            .take_while(|(&a, _)| area(a) == area(addr))
+            .find_map(|(&a, candidates)| {
+                let symbol = if area(a) == 1 {
+                    candidates.iter().find(|(bank, _)| *bank == rom_bank)
+                } else {
+                    candidates.first()
+                };
+                symbol.map(|(_, name)| (&**name, addr.get() - a.get()))
+            })
+    }
+
+    /// Iterates over all symbols, sorted by address.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (usize, Word, &str)> {
+        self.by_addr.iter().flat_map(|(&addr, symbols)| {
+            symbols.iter().map(move |(bank, name)| (*bank, addr, &**name))
+        })
+    }
+
+    /// Returns the bank and address of the symbol with the given name.
+    pub(crate) fn get(&self, name: &str) -> Option<(usize, Word)> {
+        self.by_name.get(name).copied()
+    }
+
+    /// Interprets user input as address: either as symbol name or as
+    /// hexadecimal number.
+    pub(crate) fn resolve(&self, input: &str) -> Result<Word, String> {
+        let input = input.trim();
+        if let Some((_, addr)) = self.get(input) {
+            return Ok(addr);
+        }
+
+        u16::from_str_radix(input, 16)
+            .map(Word::new)
+            .map_err(|e| format!("'{}' is neither a symbol nor a hex address: {}", input, e))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let src = "; File generated by rgblink\n\
+            00:0150 Main\n\
+            01:4000 Main::vblank_handler ; comment\n\
+            02:4000 OtherBank\n\
+            00:C000 wBuffer\n";
+        let symbols = Symbols::parse(src).unwrap();
+
+        assert_eq!(symbols.name_at(Word::new(0x150), 1), Some("Main"));
+        assert_eq!(symbols.name_at(Word::new(0x4000), 1), Some("Main::vblank_handler"));
+        assert_eq!(symbols.name_at(Word::new(0x4000), 2), Some("OtherBank"));
+        assert_eq!(symbols.name_at(Word::new(0x4000), 3), None);
+        assert_eq!(symbols.get("wBuffer"), Some((0, Word::new(0xC000))));
+        assert_eq!(symbols.resolve("Main"), Ok(Word::new(0x150)));
+        assert_eq!(symbols.resolve("1ab"), Ok(Word::new(0x1ab)));
+        assert!(symbols.resolve("Nope").is_err());
+        assert_eq!(symbols.nearest(Word::new(0x153), 1), Some(("Main", 3)));
+        assert_eq!(symbols.nearest(Word::new(0x4010), 2), Some(("OtherBank", 0x10)));
+        assert_eq!(symbols.nearest(Word::new(0x4010), 3), None);
+        assert_eq!(symbols.nearest(Word::new(0x100), 1), None);
+
+        assert!(Symbols::parse("0150 Main").is_err());
+    }
+}
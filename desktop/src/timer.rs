@@ -1,7 +1,10 @@
 //! Timing the host loop (usually fixed to the screen's refresh rate) with the
 //! Gameboy emulation speed.
 
-use std::time::{Duration, Instant};
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
 
 use crate::{
     Outcome,
@@ -16,6 +19,11 @@ const REPORT_INTERVAL: Duration = Duration::from_millis(250);
 /// Check `drive_emulation` for more details.
 const SLACK_MULTIPLIER: f32 = 1.3;
 
+/// If the emulation thread falls behind by more than this many frames (e.g.
+/// because the machine was suspended), `wait_for_frame` does not try to catch
+/// up anymore.
+const MAX_FRAMES_BEHIND: u32 = 5;
+
 pub(crate)  struct LoopTimer {
     /// The time an emulated frame should last. (This stays constant.)
     ideal_frame_time: Duration,
@@ -31,6 +39,9 @@ pub(crate)  struct LoopTimer {
     /// method should be called once every frame on the host machine.
     last_host_frame: Option<Instant>,
 
+    /// The point in time at which `wait_for_frame` should return next.
+    next_frame: Option<Instant>,
+
     /// Whether the turbo mode is enabled.
     turbo: bool,
 
@@ -56,6 +67,7 @@ impl LoopTimer {
             turbo_mode_factor: args.turbo_mode_factor,
             turbo: false,
             last_host_frame: None,
+            next_frame: None,
             behind,
             last_report: Instant::now(),
             frames_since_last_report: 0,
@@ -73,6 +85,7 @@ impl LoopTimer {
     pub(crate) fn unpause(&mut self) {
         self.behind = self.ideal_frame_time.mul_f32(1.5);
         self.last_host_frame = None;
+        self.next_frame = None;
     }
 
     /// Call once per host frame and pass a closure that emulates one frame of
@@ -123,6 +136,31 @@ impl LoopTimer {
         Outcome::Continue
     }
 
+    /// The counterpart of `drive_emulation` for the emulation thread, which is
+    /// not driven by host frames: call before emulating each frame. Sleeps
+    /// until that frame is due.
+    pub(crate) fn wait_for_frame(&mut self) {
+        let target_frame_time = self.target_frame_time();
+        let now = Instant::now();
+        let due = match self.next_frame {
+            Some(due) if due > now => {
+                thread::sleep(due - now);
+                due
+            }
+            Some(due) if now - due < target_frame_time * MAX_FRAMES_BEHIND => due,
+            _ => now,
+        };
+
+        self.next_frame = Some(due + target_frame_time);
+        self.count_frame();
+    }
+
+    /// Counts an emulated frame for the FPS report. Only necessary if neither
+    /// `drive_emulation` nor `wait_for_frame` is used.
+    pub(crate) fn count_frame(&mut self) {
+        self.frames_since_last_report += 1;
+    }
+
     /// Returns `Some(fps)` every `REPORT_INTERVAL`.
     pub(crate) fn report_fps(&mut self) -> Option<f64> {
         let elapsed = self.last_report.elapsed();
@@ -0,0 +1,382 @@
+//! Logging every executed instruction in the format used by Game Boy Doctor.
+//!
+//! Each line contains the registers and the four bytes at PC before the
+//! instruction is executed, e.g.:
+//!
+//! ```text
+//! A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+//! ```
+//!
+//! Such logs can be compared line by line with logs of other emulators to
+//! find the first instruction mahboi executes differently. `compare` does
+//! that automatically: it runs the ROM without window and stops at the first
+//! line that differs from a reference log.
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use failure::{bail, format_err, Error, ResultExt};
+
+use mahboi::{
+    Disruption, Emulator, SCREEN_WIDTH,
+    cartridge::Cartridge,
+    env::Peripherals,
+    log::*,
+    machine::{Machine, input::Keys},
+    primitives::PixelColor,
+};
+use crate::args::Args;
+
+
+pub(crate) struct TraceLog {
+    /// `None` if writing failed before.
+    out: Option<BufWriter<File>>,
+
+    /// The step count of the machine when the last line was written. The
+    /// same state can be observed multiple times (e.g. when pausing).
+    last_step: Option<u64>,
+}
+
+impl TraceLog {
+    /// Creates the log file at `path`.
+    pub(crate) fn new(path: &Path) -> Result<Self, Error> {
+        let file = File::create(path)
+            .context(format!("failed to create trace log '{}'", path.display()))?;
+
+        Ok(Self {
+            out: Some(BufWriter::new(file)),
+            last_step: None,
+        })
+    }
+
+    /// Has to be called before every step. See `is_logged` for which steps
+    /// are logged.
+    pub(crate) fn observe(&mut self, machine: &Machine) {
+        let step = machine.step_count();
+        if self.last_step == Some(step) || !is_logged(machine) {
+            return;
+        }
+        self.last_step = Some(step);
+
+        if let Some(out) = &mut self.out {
+            if let Err(e) = writeln!(out, "{}", format_line(machine)) {
+                error!("[desktop] failed to write trace log, stopping: {}", e);
+                self.out = None;
+            }
+        }
+    }
+
+    /// Writes all buffered lines to the file.
+    pub(crate) fn flush(&mut self) {
+        if let Some(out) = &mut self.out {
+            if let Err(e) = out.flush() {
+                error!("[desktop] failed to write trace log, stopping: {}", e);
+                self.out = None;
+            }
+        }
+    }
+}
+
+/// Number of matching lines printed before the first differing one.
+const CONTEXT_LINES: usize = 8;
+
+/// The comparison is aborted if no instruction was logged for this many
+/// frames (e.g. because the CPU is stuck in HALT).
+const STALL_FRAMES: u32 = 600;
+
+/// Runs the ROM without window and compares each line that would be written
+/// to the trace log with the reference log at `path`. Stops at the first
+/// differing line and prints it together with the lines before it.
+pub(crate) fn compare(args: &Args, path: &Path) -> Result<(), Error> {
+    let rom = fs::read(&args.path_to_rom).context("failed to load ROM file")?;
+    let cartridge = Cartridge::from_bytes(&rom).context("invalid ROM file")?;
+    let mut emulator = Emulator::new(cartridge, args.bios, args.model);
+    let file = File::open(path)
+        .context(format!("failed to open reference log '{}'", path.display()))?;
+
+    match find_divergence(&mut emulator, BufReader::new(file).lines())? {
+        Comparison::Match(lines) => {
+            println!("All {} lines of the reference log match", lines);
+            Ok(())
+        }
+        Comparison::Stalled(lines) => {
+            bail!(
+                "no instruction was executed for {} frames after line {} of the reference log",
+                STALL_FRAMES,
+                lines,
+            );
+        }
+        Comparison::Diverged(d) => {
+            let machine = emulator.machine();
+            println!(
+                "Divergence at line {} of the reference log (step {}, cycle {}):\n",
+                d.line,
+                machine.step_count(),
+                machine.cycle_count(),
+            );
+            for (line, text) in &d.context {
+                println!("  {:>9}  {}", line, text);
+            }
+            println!("> expected   {}", d.expected);
+            println!("> actual     {}", d.actual);
+            println!("\nDiffering fields: {}", d.fields.join(", "));
+
+            Err(format_err!("diverged from the reference log at line {}", d.line))
+        }
+    }
+}
+
+/// The result of `find_divergence`.
+#[derive(Debug)]
+enum Comparison {
+    /// The reference log ended without difference, after the given number
+    /// of lines.
+    Match(usize),
+
+    /// No instruction was logged for `STALL_FRAMES` frames, after the given
+    /// number of matching lines.
+    Stalled(usize),
+
+    Diverged(Divergence),
+}
+
+/// The first line of the reference log that differs.
+#[derive(Debug)]
+struct Divergence {
+    /// The line number (starting at 1) in the reference log.
+    line: usize,
+    expected: String,
+    actual: String,
+
+    /// The names of the fields with different values, e.g. `PC`.
+    fields: Vec<String>,
+
+    /// The last matching lines with their line numbers, oldest first.
+    context: VecDeque<(usize, String)>,
+}
+
+/// Runs `emulator` until the logged state differs from the next line of
+/// `reference`. Empty lines in the reference log are ignored.
+fn find_divergence(
+    emulator: &mut Emulator,
+    reference: impl Iterator<Item = io::Result<String>>,
+) -> Result<Comparison, Error> {
+    let mut reference = reference.enumerate()
+        .map(|(i, line)| line.map(|line| (i + 1, line)))
+        .filter(|line| line.as_ref().map_or(true, |(_, line)| !line.trim().is_empty()));
+    let mut context = VecDeque::new();
+    let mut matched = 0;
+    let mut result = None;
+
+    let mut idle_frames = 0;
+    while result.is_none() {
+        let matched_before = matched;
+        let res = emulator.execute_frame(&mut Headless, |machine| {
+            if !is_logged(machine) {
+                return false;
+            }
+
+            let (line, expected) = match reference.next() {
+                None => {
+                    result = Some(Ok(Comparison::Match(matched)));
+                    return true;
+                }
+                Some(Err(e)) => {
+                    let e = Error::from(e).context("failed to read reference log");
+                    result = Some(Err(e.into()));
+                    return true;
+                }
+                Some(Ok(line)) => line,
+            };
+
+            let actual = format_line(machine);
+            let fields = match differing_fields(&expected, &actual) {
+                Some(fields) => fields,
+                None => {
+                    let e = format_err!("line {} of the reference log has no fields", line);
+                    result = Some(Err(e));
+                    return true;
+                }
+            };
+            if fields.is_empty() {
+                matched += 1;
+                context.push_back((line, expected));
+                if context.len() > CONTEXT_LINES {
+                    context.pop_front();
+                }
+                false
+            } else {
+                let context = std::mem::take(&mut context);
+                let divergence = Divergence { line, expected, actual, fields, context };
+                result = Some(Ok(Comparison::Diverged(divergence)));
+                true
+            }
+        });
+
+        if let Err(Disruption::Terminated) = res {
+            bail!("emulator terminated after line {} of the reference log", matched);
+        }
+
+        idle_frames = if matched == matched_before { idle_frames + 1 } else { 0 };
+        if result.is_none() && idle_frames == STALL_FRAMES {
+            return Ok(Comparison::Stalled(matched));
+        }
+    }
+
+    result.unwrap()
+}
+
+/// Returns the names of all fields (`NAME:VALUE`) of the `expected` line
+/// that are missing or have a different value in `actual`. Fields only in
+/// `actual` are ignored, so reference logs may omit some (e.g. `PCMEM`).
+/// Returns `None` if `expected` has no fields at all.
+fn differing_fields(expected: &str, actual: &str) -> Option<Vec<String>> {
+    fn fields(line: &str) -> Vec<(&str, &str)> {
+        line.split_whitespace().filter_map(|field| field.split_once(':')).collect()
+    }
+
+    let expected = fields(expected);
+    let actual = fields(actual);
+    if expected.is_empty() {
+        return None;
+    }
+
+    let differing = expected.into_iter()
+        .filter(|(name, value)| {
+            !actual.iter().any(|(n, v)| n == name && v.eq_ignore_ascii_case(value))
+        })
+        .map(|(name, _)| name.to_string())
+        .collect();
+    Some(differing)
+}
+
+/// Peripherals without screen and input, for `compare`.
+struct Headless;
+
+impl Peripherals for Headless {
+    fn write_lcd_line(&mut self, _: u8, _: &[PixelColor; SCREEN_WIDTH]) {}
+
+    fn get_pressed_keys(&self) -> Keys {
+        Keys::none()
+    }
+
+    fn offer_sound_sample(&mut self, _: impl FnOnce(f32) -> f32) {}
+}
+
+/// Returns whether a line is logged for the next step. Only instructions of
+/// the cartridge are logged: the BIOS, interrupt dispatches and cycles in
+/// HALT mode are skipped.
+fn is_logged(machine: &Machine) -> bool {
+    !machine.bios_mounted() && machine.executes_instruction_next()
+}
+
+/// Formats the current state of the machine as one line of the log.
+fn format_line(machine: &Machine) -> String {
+    let cpu = &machine.cpu;
+    let pc_mem = (0..4u16)
+        .map(|i| format!("{:02X}", machine.load_byte(cpu.pc + i).get()))
+        .collect::<Vec<_>>();
+
+    format!(
+        "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} \
+            SP:{:04X} PC:{:04X} PCMEM:{}",
+        cpu.a.get(),
+        cpu.f.get(),
+        cpu.b.get(),
+        cpu.c.get(),
+        cpu.d.get(),
+        cpu.e.get(),
+        cpu.h.get(),
+        cpu.l.get(),
+        cpu.sp.get(),
+        cpu.pc.get(),
+        pc_mem.join(","),
+    )
+}
+
+
+#[cfg(test)]
+mod test {
+    use mahboi::{
+        BiosKind, Emulator, HardwareModel,
+        cartridge::Cartridge,
+        primitives::{Byte, Word},
+        test_util::cartridge_with_code,
+    };
+    use super::*;
+
+    #[test]
+    fn test_format_line() {
+        let cartridge = cartridge_with_code(&[]);
+        let mut emulator = Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+        let machine = emulator.machine_mut();
+
+        machine.cpu.a = Byte::new(0x01);
+        machine.cpu.f = Byte::new(0xB0);
+        machine.cpu.set_bc(Word::new(0x0013));
+        machine.cpu.set_de(Word::new(0x00D8));
+        machine.cpu.set_hl(Word::new(0x014D));
+        machine.cpu.sp = Word::new(0xFFFE);
+        machine.cpu.pc = Word::new(0x0100);
+
+        assert_eq!(
+            format_line(machine),
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,50,01",
+        );
+    }
+
+    #[test]
+    fn test_find_divergence() {
+        // 0x0100: inc a; jr -3
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x103].copy_from_slice(&[0x3C, 0x18, 0xFD]);
+        let cartridge = Cartridge::from_bytes(&rom).unwrap();
+        let emulator = Emulator::new(cartridge, BiosKind::Minimal, HardwareModel::Dmg);
+
+        let mut reference = Vec::new();
+        let _ = emulator.fork().execute_frame(&mut Headless, |machine| {
+            if reference.len() == 20 {
+                return true;
+            }
+            if is_logged(machine) {
+                reference.push(format_line(machine));
+            }
+            false
+        });
+        assert_eq!(reference.len(), 20);
+        let compare = |reference: &[String]| {
+            find_divergence(&mut emulator.fork(), reference.iter().cloned().map(Ok))
+        };
+        assert!(matches!(compare(&reference), Ok(Comparison::Match(20))));
+
+        // Change the value of A in one line and add an empty line before.
+        let a = u8::from_str_radix(&reference[12][2..4], 16).unwrap();
+        reference[12] = format!("A:{:02X}{}", !a, &reference[12][4..]);
+        reference.insert(5, String::new());
+        let d = match compare(&reference) {
+            Ok(Comparison::Diverged(d)) => d,
+            other => panic!("unexpected result: {:?}", other.map_err(|e| e.to_string())),
+        };
+        assert_eq!(d.line, 14);
+        assert_eq!(d.fields, ["A"]);
+        assert_eq!(d.context.len(), CONTEXT_LINES);
+        assert_eq!(d.context.back().unwrap().0, 13);
+
+        assert!(compare(&["garbage".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_differing_fields() {
+        let actual = "A:01 F:B0 SP:FFFE PC:0100 PCMEM:00,C3,50,01";
+        assert_eq!(differing_fields("A:01 F:b0 PC:0100", actual), Some(vec![]));
+        assert_eq!(
+            differing_fields("A:02 F:B0 LY:90 PC:0100", actual),
+            Some(vec!["A".to_string(), "LY".to_string()]),
+        );
+        assert_eq!(differing_fields("foo bar", actual), None);
+    }
+}
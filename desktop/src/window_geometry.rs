@@ -0,0 +1,192 @@
+//! Remembering the size and position of the main window between runs.
+//!
+//! When the window is closed, its geometry is written to `window.txt` in the
+//! config directory (e.g. `~/.config/mahboi/window.txt`), one property per
+//! line:
+//!
+//! ```text
+//! size 640 576
+//! position 100 80
+//! monitor DP-1
+//! ```
+//!
+//! All values are in physical pixels. The position is only restored if the
+//! monitor the window was on is still connected, so that the window does not
+//! end up outside of all screens.
+
+use std::{fs, path::PathBuf, str::FromStr};
+
+use winit::{
+    dpi::{PhysicalPosition, PhysicalSize},
+    event_loop::EventLoopWindowTarget,
+    window::{Window, WindowBuilder},
+};
+
+use mahboi::{SCREEN_HEIGHT, SCREEN_WIDTH, log::*};
+use crate::input_macro::config_dir;
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Geometry {
+    size: PhysicalSize<u32>,
+
+    /// The position of the outer window. `None` on platforms that don't
+    /// support it (e.g. Wayland).
+    position: Option<PhysicalPosition<i32>>,
+
+    /// The name of the monitor the window is on.
+    monitor: Option<String>,
+}
+
+impl Geometry {
+    /// Returns the current geometry of `window`.
+    pub(crate) fn of(window: &Window) -> Self {
+        Self {
+            size: window.inner_size(),
+            position: window.outer_position().ok(),
+            monitor: window.current_monitor().and_then(|m| m.name()),
+        }
+    }
+
+    /// Loads the geometry stored when the window was closed last time.
+    pub(crate) fn load() -> Option<Self> {
+        let path = path()?;
+        let content = fs::read_to_string(&path).ok()?;
+        let geometry = parse(&content);
+        if geometry.is_none() {
+            warn!("[desktop] ignoring invalid window geometry '{}'", path.display());
+        }
+        geometry
+    }
+
+    /// Stores the geometry in the config directory. Errors are only logged.
+    pub(crate) fn save(&self) {
+        if let Some(path) = path() {
+            let result = fs::create_dir_all(path.parent().unwrap())
+                .and_then(|_| fs::write(&path, self.serialize()));
+            if let Err(e) = result {
+                warn!("[desktop] failed to write window geometry '{}': {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Applies the geometry to `builder`. The position is only applied if the
+    /// monitor is still connected and contains it.
+    pub(crate) fn apply<T>(
+        &self,
+        builder: WindowBuilder,
+        event_loop: &EventLoopWindowTarget<T>,
+    ) -> WindowBuilder {
+        let builder = builder.with_inner_size(self.size);
+        let (position, name) = match (self.position, &self.monitor) {
+            (Some(position), Some(name)) => (position, name),
+            _ => return builder,
+        };
+
+        let on_monitor = event_loop.available_monitors()
+            .filter(|m| m.name().as_ref() == Some(name))
+            .any(|m| {
+                let (origin, size) = (m.position(), m.size());
+                (origin.x..origin.x + size.width as i32).contains(&position.x)
+                    && (origin.y..origin.y + size.height as i32).contains(&position.y)
+            });
+        if on_monitor {
+            builder.with_position(position)
+        } else {
+            builder
+        }
+    }
+
+    fn serialize(&self) -> String {
+        let mut out = format!("size {} {}\n", self.size.width, self.size.height);
+        if let Some(position) = self.position {
+            out += &format!("position {} {}\n", position.x, position.y);
+        }
+        if let Some(monitor) = &self.monitor {
+            out += &format!("monitor {}\n", monitor);
+        }
+        out
+    }
+}
+
+/// Returns the size the window should have after the scale factor changed
+/// from `old` to `new` (e.g. because it was moved to another monitor), so
+/// that the screen keeps the same size in logical pixels and is still scaled
+/// by an integer factor.
+pub(crate) fn rescale(size: PhysicalSize<u32>, old: f64, new: f64) -> PhysicalSize<u32> {
+    let scale = (size.width / SCREEN_WIDTH as u32).min(size.height / SCREEN_HEIGHT as u32);
+    let scale = ((scale.max(1) as f64 * new / old).round() as u32).max(1);
+    PhysicalSize::new(SCREEN_WIDTH as u32 * scale, SCREEN_HEIGHT as u32 * scale)
+}
+
+fn path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("mahboi").join("window.txt"))
+}
+
+/// Parses the file written by `Geometry::save`. Unknown lines are ignored.
+/// Returns `None` if the size is missing or any value is invalid.
+fn parse(content: &str) -> Option<Geometry> {
+    let mut size = None;
+    let mut position = None;
+    let mut monitor = None;
+    for line in content.lines() {
+        let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+        match key {
+            "size" => {
+                let (width, height): (u32, u32) = pair(value)?;
+                if width < SCREEN_WIDTH as u32 || height < SCREEN_HEIGHT as u32 {
+                    return None;
+                }
+                size = Some(PhysicalSize::new(width, height));
+            }
+            "position" => {
+                let (x, y) = pair(value)?;
+                position = Some(PhysicalPosition::new(x, y));
+            }
+            "monitor" if !value.is_empty() => monitor = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    Some(Geometry { size: size?, position, monitor })
+}
+
+/// Parses two numbers separated by a space.
+fn pair<T: FromStr>(s: &str) -> Option<(T, T)> {
+    let (a, b) = s.split_once(' ')?;
+    Some((a.trim().parse().ok()?, b.trim().parse().ok()?))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let geometry = Geometry {
+            size: PhysicalSize::new(640, 576),
+            position: Some(PhysicalPosition::new(-20, 80)),
+            monitor: Some("Built-in Retina Display".into()),
+        };
+        assert_eq!(parse(&geometry.serialize()), Some(geometry));
+
+        let minimal = parse("size 160 144\nfoo bar\n").unwrap();
+        assert_eq!(minimal.position, None);
+        assert_eq!(minimal.monitor, None);
+
+        assert_eq!(parse("position 1 2\n"), None);
+        assert_eq!(parse("size 100 100\n"), None);
+        assert_eq!(parse("size 640\n"), None);
+    }
+
+    #[test]
+    fn test_rescale() {
+        let size = PhysicalSize::new(640, 576);
+        assert_eq!(rescale(size, 1.0, 2.0), PhysicalSize::new(1280, 1152));
+        assert_eq!(rescale(size, 2.0, 1.0), PhysicalSize::new(320, 288));
+        assert_eq!(rescale(size, 1.0, 1.25), PhysicalSize::new(800, 720));
+        assert_eq!(rescale(PhysicalSize::new(700, 600), 1.0, 1.0), size);
+        assert_eq!(rescale(PhysicalSize::new(160, 144), 2.0, 1.0), PhysicalSize::new(160, 144));
+    }
+}
@@ -41,3 +41,13 @@ cd gekkio
 wget -nv https://gekkio.fi/files/mooneye-gb/latest/mooneye-gb_hwtests.zip
 unzip -qo mooneye-gb_hwtests.zip
 rm mooneye-gb_hwtests.zip
+
+cd ..
+
+
+# Download dmg-acid2 (tests sprite priority, the 10 sprite limit and the window
+# line counter)
+mkdir -p acid2
+cd acid2
+
+wget -nv https://github.com/mattcurrie/dmg-acid2/releases/download/v1.0/dmg-acid2.gb